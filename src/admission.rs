@@ -0,0 +1,162 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Instant;
+
+use crate::db_structure::{ColumnTable, DbColumn};
+use crate::utilities::{ksf, ErrorTag, EzError, KeyString};
+
+
+/// The retry hint given before recovery has made any measurable progress.
+pub const DEFAULT_RETRY_AFTER_SECS: u64 = 5;
+
+/// Never tell a client to wait longer than this.
+pub const MAX_RETRY_AFTER_SECS: u64 = 60;
+
+/// Keeps non admin clients out and refuses every change while the server is still loading its data after startup.
+/// Recovery progress is counted in files loaded from the data directory.
+pub struct AdmissionController {
+    recovering: AtomicBool,
+    phase: RwLock<KeyString>,
+    loaded: AtomicU64,
+    total: AtomicU64,
+    started: Instant,
+}
+
+impl Default for AdmissionController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AdmissionController {
+    /// A new controller starts out recovering. Call finish() when the server is fully loaded.
+    pub fn new() -> AdmissionController {
+        AdmissionController {
+            recovering: AtomicBool::new(true),
+            phase: RwLock::new(ksf("starting")),
+            loaded: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// A controller that admits everyone. Used for tests and for databases that don't load anything from disk.
+    pub fn ready() -> AdmissionController {
+        let controller = AdmissionController::new();
+        controller.finish();
+        controller
+    }
+
+    pub fn is_recovering(&self) -> bool {
+        self.recovering.load(Ordering::Acquire)
+    }
+
+    pub fn set_phase(&self, phase: &str, total: u64) {
+        *self.phase.write().unwrap() = ksf(phase);
+        self.total.fetch_add(total, Ordering::Relaxed);
+    }
+
+    pub fn advance(&self, loaded: u64) {
+        self.loaded.fetch_add(loaded, Ordering::Relaxed);
+    }
+
+    pub fn finish(&self) {
+        *self.phase.write().unwrap() = ksf("ready");
+        self.loaded.store(self.total.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.recovering.store(false, Ordering::Release);
+    }
+
+    /// Fraction of recovery completed, between 0 and 1.
+    pub fn progress(&self) -> f64 {
+        if !self.is_recovering() {
+            return 1.0
+        }
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.0
+        }
+        (self.loaded.load(Ordering::Relaxed) as f64 / total as f64).min(1.0)
+    }
+
+    /// How long a rejected client should wait before trying again, extrapolated from the progress so far.
+    pub fn retry_after(&self) -> u64 {
+        if !self.is_recovering() {
+            return 0
+        }
+        let progress = self.progress();
+        if progress <= 0.0 {
+            return DEFAULT_RETRY_AFTER_SECS
+        }
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let remaining = elapsed / progress - elapsed;
+        (remaining.ceil() as u64).clamp(1, MAX_RETRY_AFTER_SECS)
+    }
+
+    /// Admins are let in to read so they can watch recovery, but nothing may change until it is done since
+    /// the change could be overwritten or lost by the data still being loaded. Everyone else waits.
+    pub fn admit(&self, is_admin: bool, read_only: bool) -> Result<(), EzError> {
+        if (is_admin && read_only) || !self.is_recovering() {
+            return Ok(())
+        }
+
+        let refused = match is_admin {
+            true => "Changes are refused until it is done. ",
+            false => "",
+        };
+        Err(EzError{
+            tag: ErrorTag::Unavailable,
+            text: format!(
+                "Server is recovering ({}: {:.0}% done). {}Retry after {} seconds",
+                self.phase.read().unwrap(),
+                self.progress() * 100.0,
+                refused,
+                self.retry_after()
+            ),
+        })
+    }
+
+    /// The ez_health system table. A single row describing whether the server is accepting queries.
+    pub fn to_table(&self) -> Result<ColumnTable, EzError> {
+
+        let status = if self.is_recovering() { ksf("recovering") } else { ksf("ready") };
+
+        let mut table = ColumnTable::create_empty("ez_health", "system");
+        table.add_column(ksf("status"), DbColumn::Texts(vec![status]))?;
+        table.add_column(ksf("phase"), DbColumn::Texts(vec![*self.phase.read().unwrap()]))?;
+        table.add_column(ksf("progress_percent"), DbColumn::Floats(vec![(self.progress() * 100.0) as f32]))?;
+        table.add_column(ksf("retry_after_secs"), DbColumn::Ints(vec![self.retry_after() as i32]))?;
+        table.add_column(ksf("uptime_secs"), DbColumn::Ints(vec![self.started.elapsed().as_secs() as i32]))?;
+
+        Ok(table)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admission() {
+        let controller = AdmissionController::new();
+        assert!(controller.admit(true, true).is_ok());
+        let e = controller.admit(false, true).unwrap_err();
+        assert_eq!(e.tag, ErrorTag::Unavailable);
+        // Not even admins can change anything yet
+        let e = controller.admit(true, false).unwrap_err();
+        assert_eq!(e.tag, ErrorTag::Unavailable);
+        assert!(e.text.contains("Changes are refused"));
+        assert_eq!(controller.retry_after(), DEFAULT_RETRY_AFTER_SECS);
+
+        controller.set_phase("loading tables", 4);
+        controller.advance(2);
+        assert_eq!(controller.progress(), 0.5);
+        assert!(controller.retry_after() >= 1);
+
+        controller.finish();
+        assert!(controller.admit(false, false).is_ok());
+        assert!(controller.admit(true, false).is_ok());
+        assert_eq!(controller.progress(), 1.0);
+        assert_eq!(controller.retry_after(), 0);
+    }
+}
@@ -0,0 +1,192 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::db_structure::{ColumnTable, DbColumn};
+use crate::utilities::{ksf, ErrorTag, EzError};
+
+
+/// The part of query execution an allocation happened in. Phases nest: an allocation is counted
+/// towards the innermost phase the thread is in when it allocates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocPhase {
+    /// Anything outside an instrumented phase.
+    Other,
+    Parse,
+    Filter,
+    Subtable,
+    Join,
+    Summary,
+    Sort,
+}
+
+const PHASE_COUNT: usize = 7;
+
+impl AllocPhase {
+    pub const ALL: [AllocPhase; PHASE_COUNT] = [
+        AllocPhase::Other,
+        AllocPhase::Parse,
+        AllocPhase::Filter,
+        AllocPhase::Subtable,
+        AllocPhase::Join,
+        AllocPhase::Summary,
+        AllocPhase::Sort,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AllocPhase::Other => "other",
+            AllocPhase::Parse => "parse",
+            AllocPhase::Filter => "filter",
+            AllocPhase::Subtable => "subtable",
+            AllocPhase::Join => "join",
+            AllocPhase::Summary => "summary",
+            AllocPhase::Sort => "sort",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Allocation counts of one phase since the server started or the stats were last reset.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PhaseAllocations {
+    pub allocations: u64,
+    pub bytes: u64,
+}
+
+static ALLOCATIONS: [AtomicU64; PHASE_COUNT] = [const { AtomicU64::new(0) }; PHASE_COUNT];
+static BYTES: [AtomicU64; PHASE_COUNT] = [const { AtomicU64::new(0) }; PHASE_COUNT];
+
+thread_local! {
+    static PHASE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Whether this build counts allocations. Needs the alloc-stats feature.
+pub const fn enabled() -> bool {
+    cfg!(feature = "alloc-stats")
+}
+
+#[cfg_attr(not(feature = "alloc-stats"), allow(dead_code))]
+fn record(bytes: usize) {
+    // The thread local may already be gone while the thread shuts down
+    let phase = PHASE.try_with(|phase| phase.get()).unwrap_or(0);
+    ALLOCATIONS[phase].fetch_add(1, Ordering::Relaxed);
+    BYTES[phase].fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Restores the phase the thread was in before when it goes out of scope.
+pub struct PhaseGuard {
+    previous: usize,
+}
+
+impl Drop for PhaseGuard {
+    fn drop(&mut self) {
+        let _ = PHASE.try_with(|phase| phase.set(self.previous));
+    }
+}
+
+/// Counts the allocations of this thread towards `phase` until the guard is dropped.
+/// Costs a thread local write without the alloc-stats feature.
+pub fn enter(phase: AllocPhase) -> PhaseGuard {
+    let previous = PHASE.try_with(|current| current.replace(phase.index())).unwrap_or(0);
+    PhaseGuard{previous}
+}
+
+pub fn phase_allocations(phase: AllocPhase) -> PhaseAllocations {
+    PhaseAllocations {
+        allocations: ALLOCATIONS[phase.index()].load(Ordering::Relaxed),
+        bytes: BYTES[phase.index()].load(Ordering::Relaxed),
+    }
+}
+
+pub fn reset() {
+    for phase in AllocPhase::ALL {
+        ALLOCATIONS[phase.index()].store(0, Ordering::Relaxed);
+        BYTES[phase.index()].store(0, Ordering::Relaxed);
+    }
+}
+
+/// The answer to the ALLOC_STATS admin command. One row per phase, the busiest first.
+pub fn alloc_stats_table() -> Result<ColumnTable, EzError> {
+    if !enabled() {
+        return Err(EzError{tag: ErrorTag::Unimplemented, text: "This server was built without allocation stats. Build with --features alloc-stats".to_owned()})
+    }
+    let mut rows: Vec<(AllocPhase, PhaseAllocations)> = AllocPhase::ALL.iter().map(|phase| (*phase, phase_allocations(*phase))).collect();
+    rows.sort_by_key(|row| std::cmp::Reverse(row.1.bytes));
+
+    let clamp = |x: u64| x.min(i32::MAX as u64) as i32;
+    let mut table = ColumnTable::create_empty("ez_alloc_stats", "system");
+    table.add_column(ksf("phase"), DbColumn::Texts(rows.iter().map(|(phase, _)| ksf(phase.name())).collect()))?;
+    table.add_column(ksf("allocations"), DbColumn::Ints(rows.iter().map(|(_, stats)| clamp(stats.allocations)).collect()))?;
+    table.add_column(ksf("bytes"), DbColumn::Ints(rows.iter().map(|(_, stats)| clamp(stats.bytes)).collect()))?;
+    table.add_column(ksf("mean_bytes"), DbColumn::Floats(rows.iter().map(|(_, stats)| {
+        if stats.allocations == 0 {0.0} else {stats.bytes as f32 / stats.allocations as f32}
+    }).collect()))?;
+    Ok(table)
+}
+
+
+#[cfg(feature = "alloc-stats")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    use super::record;
+
+    /// The system allocator with a count of every allocation. Growing reallocations count the bytes added.
+    pub struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            record(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            record(layout.size());
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            record(new_size.saturating_sub(layout.size()));
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAllocator = CountingAllocator;
+}
+
+
+#[cfg(all(test, feature = "alloc-stats"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_phases() {
+        let before = phase_allocations(AllocPhase::Join);
+        {
+            let _join = enter(AllocPhase::Join);
+            let v: Vec<u8> = Vec::with_capacity(1000);
+            std::hint::black_box(&v);
+            {
+                let _sort = enter(AllocPhase::Sort);
+                std::hint::black_box(Vec::<u8>::with_capacity(10));
+            }
+            std::hint::black_box(Vec::<u8>::with_capacity(500));
+        }
+        let after = phase_allocations(AllocPhase::Join);
+        // Tests on other threads can only add to the counts
+        assert!(after.allocations - before.allocations >= 2);
+        assert!(after.bytes - before.bytes >= 1500);
+        assert_eq!(PHASE.with(|phase| phase.get()), 0);
+
+        let table = alloc_stats_table().unwrap();
+        assert_eq!(table.len(), AllocPhase::ALL.len());
+    }
+}
@@ -1,13 +1,16 @@
 use std::{
     collections::{BTreeMap, HashSet},
     fmt::{self, Display},
-    sync::{Arc, RwLock},
+    sync::{atomic::{AtomicU32, Ordering}, Arc, RwLock},
 };
 
 use ezcbor::cbor::{self, byteslice_from_cbor, Cbor};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 // use serde::{Deserialize, Serialize};
 
-use crate::{utilities::KeyString, ezql::{KvQuery, Query}, utilities::{encode_hex, ez_hash}};
+use crate::{utilities::KeyString, ezql::{IntoTarget, KvQuery, Query}, utilities::{encode_hex, ez_hash, ErrorTag, EzError}};
+use crate::system_tables::is_public_system_table;
 
 /// Defines a permission a user has to interact with a given table
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -42,14 +45,122 @@ impl Permission {
     }
 }
 
+pub const DEFAULT_PASSWORD_ITERATIONS: u32 = 100_000;
+
+static PASSWORD_ITERATIONS: AtomicU32 = AtomicU32::new(DEFAULT_PASSWORD_ITERATIONS);
+
+/// How many PBKDF2 rounds new password hashes get. Existing hashes keep the count they were made with.
+pub fn set_password_iterations(iterations: u32) {
+    PASSWORD_ITERATIONS.store(iterations.max(1), Ordering::Relaxed);
+}
+
+pub fn password_iterations() -> u32 {
+    PASSWORD_ITERATIONS.load(Ordering::Relaxed)
+}
+
+/// A password hashed with PBKDF2-HMAC-SHA256 and a random salt.
+/// Users stored before passwords were salted have 0 iterations and a bare ez_hash of the password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordHash {
+    pub hash: [u8; 32],
+    pub salt: [u8; 16],
+    pub iterations: u32,
+}
+
+impl PasswordHash {
+    /// Hashes the password with a fresh salt and the configured number of iterations.
+    pub fn new(password: &str) -> PasswordHash {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        PasswordHash::with_salt(password, salt, password_iterations())
+    }
+
+    pub fn with_salt(password: &str, salt: [u8; 16], iterations: u32) -> PasswordHash {
+        PasswordHash {
+            hash: pbkdf2_sha256(password.as_bytes(), &salt, iterations),
+            salt,
+            iterations,
+        }
+    }
+
+    /// Whether this hash was stored before salting and should be replaced on the next successful login.
+    pub fn is_legacy(&self) -> bool {
+        self.iterations == 0
+    }
+
+    /// Checks the password in constant time.
+    pub fn verify(&self, password: &str) -> bool {
+        let candidate = match self.is_legacy() {
+            true => ez_hash(password.as_bytes()),
+            false => pbkdf2_sha256(password.as_bytes(), &self.salt, self.iterations),
+        };
+        candidate.iter().zip(self.hash.iter()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    /// [hash: 32][salt: 16][iterations: u32]. Legacy hashes are just the 32 byte hash.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.is_legacy() {
+            return self.hash.to_vec()
+        }
+        let mut bytes = Vec::with_capacity(52);
+        bytes.extend_from_slice(&self.hash);
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.iterations.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<PasswordHash> {
+        match bytes.len() {
+            32 => Some(PasswordHash{hash: bytes.try_into().ok()?, salt: [0u8; 16], iterations: 0}),
+            52 => Some(PasswordHash{
+                hash: bytes[0..32].try_into().ok()?,
+                salt: bytes[32..48].try_into().ok()?,
+                iterations: u32::from_le_bytes(bytes[48..52].try_into().ok()?),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        block[0..32].copy_from_slice(&ez_hash(key));
+    } else {
+        block[0..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+/// PBKDF2 with HMAC-SHA256 and a single 32 byte output block.
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut message = salt.to_vec();
+    message.extend_from_slice(&1u32.to_be_bytes());
+    let mut block = hmac_sha256(password, &message);
+    let mut output = block;
+    for _ in 1..iterations {
+        block = hmac_sha256(password, &block);
+        for (out, b) in output.iter_mut().zip(block.iter()) {
+            *out ^= b;
+        }
+    }
+    output
+}
+
 /// The struct that represents a user.
-/// The password field is a blake3 hash of the users password
+/// The password field is a salted PBKDF2 hash of the users password
 /// the can_upload field tracks whether the user should be allowed to upload tables or binary blobs
 /// the can_X fields are lists of tables / values on which X operation is allowed.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct User {
     pub username: String,
-    pub password: [u8; 32],
+    pub password: PasswordHash,
     pub admin: bool,
     pub can_upload: bool,
     pub can_read: HashSet<String>,
@@ -77,7 +188,7 @@ impl Display for User {
         if can_write.len() > 0 {can_write.pop();}
 
         let printer = format!("username\n\t{}\npassword\n\t{}\nadmin\n\t{}\ncan_upload\n\t{}\ncan_read\n{}\ncan_write\n{}",
-            self.username, encode_hex(&self.password), self.admin, self.can_upload, can_read, can_write
+            self.username, encode_hex(&self.password.hash), self.admin, self.can_upload, can_read, can_write
         );
         write!(f, "{}", printer)
     }
@@ -89,7 +200,7 @@ impl Cbor for User {
 
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.username.to_cbor_bytes());
-        bytes.extend_from_slice(&cbor::byteslice_to_cbor(&self.password.as_slice()));
+        bytes.extend_from_slice(&cbor::byteslice_to_cbor(&self.password.to_bytes()));
         bytes.extend_from_slice(&self.admin.to_cbor_bytes());
         bytes.extend_from_slice(&self.can_upload.to_cbor_bytes());
         bytes.extend_from_slice(&self.can_read.to_cbor_bytes());
@@ -118,12 +229,12 @@ impl Cbor for User {
         let (can_write, bytes_read) = <HashSet<String> as Cbor>::from_cbor_bytes(&bytes[i..])?;
         i += bytes_read;
 
-        let mut password = [0u8;32];
-        password.copy_from_slice(&temp_password[0..32]);
+        // A malformed hash locks the user out rather than failing to load every user
+        let password = PasswordHash::from_bytes(&temp_password).unwrap_or(PasswordHash{hash: [0u8; 32], salt: [0u8; 16], iterations: 1});
         Ok((
             User {
                 username,
-                password: password.into(),
+                password,
                 admin,
                 can_upload,
                 can_read,
@@ -141,7 +252,7 @@ impl User {
 
         User {
             username: String::from(username),
-            password: ez_hash(password.as_bytes()),
+            password: PasswordHash::new(password),
             admin: false,
             can_upload: false,
             can_read: HashSet::new(),
@@ -155,7 +266,7 @@ impl User {
 
         User {
             username: String::from(username),
-            password: ez_hash(password.as_bytes()),
+            password: PasswordHash::new(password),
             admin: true,
             can_upload: true,
             can_read: HashSet::new(),
@@ -166,6 +277,70 @@ impl User {
     
 }
 
+/// Checks the password of a user. Users stored before passwords were salted get a salted hash on their
+/// first successful login. Returns true when that happened and the users should be saved.
+pub fn authenticate_user(users: &RwLock<BTreeMap<KeyString, RwLock<User>>>, username: &str, password: &str) -> Result<bool, EzError> {
+    // The same error and the same work whether or not the user exists, so logins can't be used to find usernames
+    let refused = || EzError{tag: ErrorTag::Authentication, text: "Wrong username or password".to_owned()};
+    let users = users.read().unwrap();
+    let mut user = match users.get(&KeyString::from(username)) {
+        Some(user) => user.write().unwrap(),
+        None => {
+            std::hint::black_box(PasswordHash::with_salt(password, [0u8; 16], password_iterations()));
+            return Err(refused())
+        },
+    };
+    if !user.password.verify(password) {
+        return Err(refused())
+    }
+    if user.password.is_legacy() {
+        user.password = PasswordHash::new(password);
+        return Ok(true)
+    }
+    Ok(false)
+}
+
+/// Adds a user. Fails if the username is taken.
+pub fn add_user(users: &RwLock<BTreeMap<KeyString, RwLock<User>>>, user: User) -> Result<(), EzError> {
+    if user.username.is_empty() || user.username.len() > 64 {
+        return Err(EzError{tag: ErrorTag::Query, text: format!("Usernames are 1 to 64 bytes. '{}' is {}", user.username, user.username.len())})
+    }
+    let mut users = users.write().unwrap();
+    let username = KeyString::from(user.username.as_str());
+    if users.contains_key(&username) {
+        return Err(EzError{tag: ErrorTag::Query, text: format!("User '{}' already exists", username)})
+    }
+    users.insert(username, RwLock::new(user));
+    Ok(())
+}
+
+/// Removes a user. The last admin can't be removed so the server can always be administered.
+pub fn remove_user(users: &RwLock<BTreeMap<KeyString, RwLock<User>>>, username: &str) -> Result<User, EzError> {
+    let mut users = users.write().unwrap();
+    let key = KeyString::from(username);
+    let is_admin = match users.get(&key) {
+        Some(user) => user.read().unwrap().admin,
+        None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No user named '{}'", username)}),
+    };
+    let admins = users.values().filter(|user| user.read().unwrap().admin).count();
+    if is_admin && admins == 1 {
+        return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is the last admin and can't be removed", username)})
+    }
+    Ok(users.remove(&key).expect("checked above").into_inner().unwrap())
+}
+
+/// Gives a user a new password, hashed with a fresh salt.
+pub fn change_password(users: &RwLock<BTreeMap<KeyString, RwLock<User>>>, username: &str, password: &str) -> Result<(), EzError> {
+    let users = users.read().unwrap();
+    match users.get(&KeyString::from(username)) {
+        Some(user) => {
+            user.write().unwrap().password = PasswordHash::new(password);
+            Ok(())
+        },
+        None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No user named '{}'", username)}),
+    }
+}
+
 pub fn check_permission(
     queries: &[Query],
     username: &str,
@@ -186,12 +361,35 @@ pub fn check_permission(
 
     for query in queries {
         match query {
-            Query::SELECT{table_name, primary_keys: _, columns: _, conditions: _ } => if user.can_read.contains(&table_name.to_string()) {continue},
+            Query::SELECT{table_name, primary_keys: _, columns: _, conditions: _, distinct: _, limit: _ } => if user.can_read.contains(&table_name.to_string()) || is_public_system_table(table_name) {continue},
             Query::LEFT_JOIN{left_table_name, right_table_name, match_columns: _, primary_keys: _ } => if user.can_read.contains(&left_table_name.to_string()) && user.can_read.contains(&right_table_name.to_string()) {continue},
-            Query::UPDATE{table_name, primary_keys: _, conditions: _, updates: _ } => if user.can_write.contains(&table_name.to_string()) {continue},
-            Query::INSERT{table_name, inserts: _ } => if user.can_write.contains(&table_name.to_string()) {continue},
+            Query::UPDATE{table_name, primary_keys: _, conditions: _, updates: _, version: _ } => if user.can_write.contains(&table_name.to_string()) {continue},
+            Query::INSERT{table_name, ..} => if user.can_write.contains(&table_name.to_string()) {continue},
             Query::DELETE{table_name, primary_keys: _, conditions: _ } => if user.can_write.contains(&table_name.to_string()) {continue},
-            Query::SUMMARY{table_name, columns: _ } => if user.can_read.contains(&table_name.to_string()) {continue},
+            Query::SUMMARY{table_name, columns: _ } => if user.can_read.contains(&table_name.to_string()) || is_public_system_table(table_name) {continue},
+            // Globs are expanded to table names before this check. See expand_table_globs()
+            Query::MULTI_SUMMARY{tables, columns: _ } => if tables.iter().all(|table_name| user.can_read.contains(&table_name.to_string()) || is_public_system_table(table_name)) {continue},
+            Query::DEDUPLICATE{table_name} => if user.can_write.contains(&table_name.to_string()) {continue},
+            Query::CREATE{..} | Query::CREATE_FROM_SCHEMA{..} => if user.can_upload {continue},
+            // Only the owner may drop or alter a table. See check_ownership()
+            Query::DROP{..} | Query::ALTER_TABLE{..} => continue,
+            // Only looks at the sample it was sent so any user may ask
+            Query::INFER_SCHEMA{table_name: _, sample: _} => continue,
+            Query::DESCRIBE{table_name} => if user.can_read.contains(&table_name.to_string()) || is_public_system_table(table_name) {continue},
+            // Checked again as the query it runs once it is bound
+            Query::PREPARE{query} => if check_permission(&[(**query).clone()], username, users.clone()).is_ok() {continue},
+            Query::EXECUTE{..} => continue,
+            Query::HELP => continue,
+            // Shows row counts and keys of the table so it needs what the query needs
+            Query::EXPLAIN{query} => if check_permission(&[(**query).clone()], username, users.clone()).is_ok() {continue},
+            // Needs to read the source and to create the target
+            Query::INTO{query, target} => {
+                let can_store = match target {
+                    IntoTarget::Table(_) => user.can_upload,
+                    IntoTarget::Value(key) => user.can_upload && user.can_write.contains(key.as_str()),
+                };
+                if can_store && check_permission(&[(**query).clone()], username, users.clone()).is_ok() {continue}
+            },
             _ => unimplemented!()
         }
         return Err(AuthenticationError::Permission)
@@ -201,6 +399,45 @@ pub fn check_permission(
 }
 
 
+/// Checks that the user owns every table the batch would drop or alter. Admins may drop or alter any table.
+/// owner_of looks up the owner of a table, see BufferPool::table_owner(). A table without an owner doesn't exist
+/// and is left for the query to fail on.
+pub fn check_ownership(
+    queries: &[Query],
+    username: &str,
+    users: Arc<RwLock<BTreeMap<KeyString, RwLock<User>>>>,
+    owner_of: impl Fn(&KeyString) -> Option<KeyString>,
+) -> Result<(), AuthenticationError> {
+
+    let user = users.read().unwrap();
+    let user = match user.get(&KeyString::from(username)) {
+        Some(u) => u.read().unwrap(),
+        None => return Err(AuthenticationError::Permission),
+    };
+
+    if user.admin {
+        return Ok(())
+    }
+
+    for query in queries {
+        let table_name = match query {
+            Query::DROP{table_name} | Query::ALTER_TABLE{table_name, ..} => table_name,
+            Query::PREPARE{query} => match &**query {
+                Query::DROP{table_name} | Query::ALTER_TABLE{table_name, ..} => table_name,
+                _ => continue,
+            },
+            _ => continue,
+        };
+        match owner_of(table_name) {
+            Some(owner) if owner.as_str() != username => return Err(AuthenticationError::NotOwner),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+
 pub fn check_kv_permission(
     queries: &[KvQuery],
     username: &str,
@@ -221,10 +458,17 @@ pub fn check_kv_permission(
 
     for query in queries {
         match query {
-            KvQuery::Create(_key_string, _) => if user.can_upload {continue},
+            KvQuery::Create(_key_string, _) | KvQuery::CreateWithTtl(_key_string, _, _) => if user.can_upload {continue},
             KvQuery::Read(key_string) => if user.can_read.contains(key_string.as_str()) {continue},
-            KvQuery::Update(key_string, _) => if user.can_write.contains(key_string.as_str()) {continue},
+            KvQuery::Update(key_string, _) | KvQuery::UpdateWithTtl(key_string, _, _) => if user.can_write.contains(key_string.as_str()) {continue},
             KvQuery::Delete(key_string) => if user.can_write.contains(key_string.as_str()) {continue},
+            KvQuery::ReadVersion(key_string, _) => if user.can_read.contains(key_string.as_str()) {continue},
+            KvQuery::Rollback(key_string, _) => if user.can_write.contains(key_string.as_str()) {continue},
+            KvQuery::ReadMany(keys) => if keys.iter().all(|key| user.can_read.contains(key.as_str())) {continue},
+            // A write can create the value so it needs both
+            KvQuery::WriteMany(pairs) => if user.can_upload && pairs.iter().all(|(key, _)| user.can_write.contains(key.as_str())) {continue},
+            // Reading the prefix itself grants scanning the keys under it
+            KvQuery::Scan(prefix, _) => if user.can_read.contains(prefix.as_str()) {continue},
         }
         return Err(AuthenticationError::Permission)
     }
@@ -268,6 +512,8 @@ pub enum AuthenticationError {
     WrongPassword,
     TooLong,
     Permission,
+    /// Only the owner of a table or an admin may drop it.
+    NotOwner,
     WrongStringFormat,
 }
 
@@ -282,6 +528,7 @@ impl fmt::Display for AuthenticationError {
             AuthenticationError::WrongPassword => write!(f, "IP"),
             AuthenticationError::TooLong => write!(f, "LA"),
             AuthenticationError::Permission => write!(f, "NP"),
+            AuthenticationError::NotOwner => write!(f, "NO"),
             AuthenticationError::WrongStringFormat => write!(f, "WF"),
         }
     }
@@ -333,5 +580,84 @@ mod tests {
         assert_eq!(user, decoded_user);
     }
 
+    #[test]
+    fn test_password_hashing() {
+        let first = PasswordHash::new("correct horse");
+        let second = PasswordHash::new("correct horse");
+        assert_ne!(first.salt, second.salt);
+        assert_ne!(first.hash, second.hash);
+        assert!(first.verify("correct horse"));
+        assert!(!first.verify("correct horse "));
+        assert_eq!(PasswordHash::from_bytes(&first.to_bytes()), Some(first));
+
+        // RFC 7914 test vector for PBKDF2-HMAC-SHA256 with one iteration
+        assert_eq!(&encode_hex(&pbkdf2_sha256(b"passwd", b"salt", 1))[0..16], "55ac046e56e3089f");
+
+        let legacy = PasswordHash::from_bytes(&ez_hash(b"old")).unwrap();
+        assert!(legacy.is_legacy() && legacy.verify("old"));
+        assert_eq!(PasswordHash::from_bytes(&[0u8; 7]), None);
+    }
+
+    #[test]
+    fn test_user_management() {
+        let mut legacy = User::new("dave", "pass");
+        legacy.password = PasswordHash::from_bytes(&ez_hash(b"pass")).unwrap();
+        let users = RwLock::new(BTreeMap::from([
+            (KeyString::from("admin"), RwLock::new(User::admin("admin", "admin"))),
+            (KeyString::from("dave"), RwLock::new(legacy)),
+        ]));
+
+        // The first login replaces the unsalted hash
+        assert!(authenticate_user(&users, "dave", "pass").unwrap());
+        assert!(!authenticate_user(&users, "dave", "pass").unwrap());
+        assert!(!users.read().unwrap()[&KeyString::from("dave")].read().unwrap().password.is_legacy());
+        // Wrong passwords and unknown users look the same
+        let wrong = authenticate_user(&users, "dave", "wrong").unwrap_err();
+        assert_eq!(authenticate_user(&users, "nobody", "pass").unwrap_err(), wrong);
+
+        change_password(&users, "dave", "new pass").unwrap();
+        assert!(authenticate_user(&users, "dave", "pass").is_err());
+        assert!(authenticate_user(&users, "dave", "new pass").is_ok());
+        assert!(change_password(&users, "nobody", "x").is_err());
+
+        assert!(add_user(&users, User::new("dave", "again")).is_err());
+        add_user(&users, User::new("erin", "erin")).unwrap();
+        assert_eq!(remove_user(&users, "erin").unwrap().username, "erin");
+        assert!(remove_user(&users, "erin").is_err());
+        assert!(remove_user(&users, "admin").is_err());
+    }
+
+    #[test]
+    fn test_table_ownership() {
+        let mut alice = User::new("alice", "alice");
+        alice.can_upload = true;
+        let mut bob = User::new("bob", "bob");
+        bob.can_write.insert("alices_table".to_owned());
+        let mut map = BTreeMap::new();
+        map.insert(KeyString::from("alice"), RwLock::new(alice));
+        map.insert(KeyString::from("bob"), RwLock::new(bob));
+        map.insert(KeyString::from("admin"), RwLock::new(User::admin("admin", "admin")));
+        let users = Arc::new(RwLock::new(map));
+        let owner_of = |table_name: &KeyString| match table_name.as_str() {
+            "alices_table" => Some(KeyString::from("alice")),
+            _ => None,
+        };
+
+        let drop = [Query::DROP{table_name: KeyString::from("alices_table")}];
+        assert!(check_permission(&drop, "bob", users.clone()).is_ok());
+        assert!(check_ownership(&drop, "alice", users.clone(), owner_of).is_ok());
+        assert!(check_ownership(&drop, "admin", users.clone(), owner_of).is_ok());
+        // Being allowed to write to the table is not enough to drop it
+        assert!(matches!(check_ownership(&drop, "bob", users.clone(), owner_of), Err(AuthenticationError::NotOwner)));
+        let prepared = [Query::PREPARE{query: Box::new(drop[0].clone())}];
+        assert!(check_ownership(&prepared, "bob", users.clone(), owner_of).is_err());
+        assert!(check_ownership(&[Query::DROP{table_name: KeyString::from("missing")}], "bob", users.clone(), owner_of).is_ok());
+        assert!(check_ownership(&drop, "nobody", users.clone(), owner_of).is_err());
+
+        let create = [Query::CREATE{table: crate::testing_tools::create_fixed_table(2), engine: crate::row_table::TableEngine::Column}];
+        assert!(check_permission(&create, "alice", users.clone()).is_ok());
+        assert!(check_permission(&create, "bob", users.clone()).is_err());
+    }
+
     
 }
@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+
+use ezcbor::cbor::{decode_cbor, Cbor};
+
+use crate::auth::User;
+use crate::database::Database;
+use crate::db_structure::{ColumnTable, Value};
+use crate::disk_utilities::{read_table_file, ValueExpiry, USERS_FILE, VALUE_EXPIRY_FILE, VALUE_HISTORY_FILE};
+use crate::maintenance::{TASKS_FILE, TASK_BINARY_SIZE};
+use crate::namespaces::{QUOTAS_FILE, QUOTA_BINARY_SIZE};
+use crate::partitions::{partitions_from_binary, partitions_to_binary, PARTITIONS_FILE};
+use crate::paths::{path_to_string, value_file, KEY_FILTERS_DIR, RAW_TABLES_DIR, RAW_VALUES_DIR, TABLE_CHUNKS_DIR};
+use crate::utilities::{get_current_time, ksf, u64_from_le_slice, ErrorTag, EzError, KeyString};
+
+/// The first 64 bytes of every backup archive.
+pub const BACKUP_MAGIC: &str = "EZDB_BACKUP";
+/// The version of the archive layout this build writes. Archives of a newer version are refused.
+pub const BACKUP_FORMAT_VERSION: u64 = 2;
+/// What a restore moves aside goes in a directory with this prefix and the time of the restore.
+pub const PRE_RESTORE_PREFIX: &str = "pre_restore_";
+
+/// [magic: 64][version: u64][taken_at: u64][sections: u64]
+const BACKUP_HEADER_SIZE: usize = 64 + 8 + 8 + 8;
+
+/// The kind of each section of an archive. Every section is [kind: u8][name: 64][length: u64][bytes].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+enum SectionKind {
+    /// A table in the layout of a table file.
+    Table = 1,
+    /// A value in the layout of a value file.
+    Value = 2,
+    /// The users file.
+    Users = 3,
+    /// The value expiry file.
+    ValueExpiry = 4,
+    /// The tasks file.
+    Tasks = 5,
+    /// The quotas file.
+    Quotas = 6,
+    /// The partitions file. Archives of version 1 have none.
+    Partitions = 7,
+}
+
+impl SectionKind {
+    fn from_byte(byte: u8) -> Result<SectionKind, EzError> {
+        match byte {
+            1 => Ok(SectionKind::Table),
+            2 => Ok(SectionKind::Value),
+            3 => Ok(SectionKind::Users),
+            4 => Ok(SectionKind::ValueExpiry),
+            5 => Ok(SectionKind::Tasks),
+            6 => Ok(SectionKind::Quotas),
+            7 => Ok(SectionKind::Partitions),
+            other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unknown backup section kind: {}", other)}),
+        }
+    }
+}
+
+/// Everything a backup archive holds. Tables and values are kept in the layout of their files
+/// so a restore only has to write them out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Backup {
+    pub version: u64,
+    /// Seconds since the epoch when the copy was taken.
+    pub taken_at: u64,
+    pub tables: BTreeMap<KeyString, Vec<u8>>,
+    pub values: BTreeMap<KeyString, Vec<u8>>,
+    pub users: Vec<u8>,
+    pub value_expiry: Vec<u8>,
+    pub tasks: Vec<u8>,
+    pub quotas: Vec<u8>,
+    pub partitions: Vec<u8>,
+}
+
+/// What an archive holds, as answered to a backup and printed after a restore.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackupSummary {
+    pub version: u64,
+    pub taken_at: u64,
+    pub tables: usize,
+    pub values: usize,
+    pub users: usize,
+    pub bytes: usize,
+    /// Where a restore moved the files it replaced. None for a backup or if there was nothing to move.
+    pub moved_to: Option<PathBuf>,
+}
+
+impl Display for BackupSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Backup version {} taken at {}: {} table(s), {} value(s), {} user(s), {} bytes", self.version, self.taken_at, self.tables, self.values, self.users, self.bytes)?;
+        if let Some(dir) = &self.moved_to {
+            writeln!(f, "The files it replaced were moved to {}", path_to_string(dir))?;
+        }
+        Ok(())
+    }
+}
+
+impl Backup {
+    /// Copies every table, value, user and the metadata files while the server keeps running.
+    /// The tables are read locked together in name order, the same way a Snapshot locks them, and the values
+    /// are read locked alongside, so the archive holds the state between two writes and never half of one.
+    /// Writers are only held up for the copy. Encoding the tables happens after the locks are released.
+    /// Tables that were unloaded for being idle and values that did not fit in the buffer pool are read from disk.
+    /// Attachments in the blob store are not part of the archive.
+    pub fn take(database: &Database) -> Result<Backup, EzError> {
+        println!("calling: Backup::take()");
+
+        let mut loaded = Vec::new();
+        let mut unloaded = Vec::new();
+        let (values, value_expiry, unloaded_values, partitions) = {
+            let tables = database.buffer_pool.tables.read().unwrap();
+            let mut guards = Vec::with_capacity(tables.len());
+            for (name, table) in tables.iter() {
+                guards.push(database.locks.read_table(*name, table)?);
+            }
+            let values = database.buffer_pool.values.read().unwrap();
+
+            for guard in guards {
+                loaded.push(guard.clone());
+            }
+            // Unloaded tables can't be reloaded or changed while we hold the table map
+            for name in database.buffer_pool.unloaded_tables.read().unwrap().keys() {
+                unloaded.push(read_table_file(name.as_str())?);
+            }
+            let mut unloaded_values = Vec::new();
+            for key in database.buffer_pool.unloaded_values.read().unwrap().iter() {
+                if !values.contains_key(key) {
+                    unloaded_values.push((*key, std::fs::read(value_file(key.as_str()))?));
+                }
+            }
+            let partitions = partitions_to_binary(&database.buffer_pool.partitions.read().unwrap());
+            (values.clone(), database.buffer_pool.value_expiry.read().unwrap().to_binary(), unloaded_values, partitions)
+        };
+
+        let users = {
+            let users = database.users.read().unwrap();
+            let mut plain = BTreeMap::new();
+            for (username, user) in users.iter() {
+                plain.insert(*username, user.read().unwrap().clone());
+            }
+            plain.to_cbor_bytes()
+        };
+
+        let mut tables = BTreeMap::new();
+        for table in loaded.iter().chain(unloaded.iter()) {
+            tables.insert(table.name, table.to_disk_binary()?);
+        }
+        let mut value_files: BTreeMap<KeyString, Vec<u8>> = values.into_iter().map(|(key, value)| (key, value.write_to_binary())).collect();
+        value_files.extend(unloaded_values);
+
+        Ok(Backup {
+            version: BACKUP_FORMAT_VERSION,
+            taken_at: get_current_time(),
+            tables,
+            values: value_files,
+            users,
+            value_expiry,
+            tasks: database.tasks.to_binary(),
+            quotas: database.namespaces.to_binary(),
+            partitions,
+        })
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        let sections = self.tables.len() + self.values.len() + 5;
+        let mut binary = Vec::new();
+        binary.extend_from_slice(ksf(BACKUP_MAGIC).raw());
+        binary.extend_from_slice(&self.version.to_le_bytes());
+        binary.extend_from_slice(&self.taken_at.to_le_bytes());
+        binary.extend_from_slice(&(sections as u64).to_le_bytes());
+
+        let mut write_section = |kind: SectionKind, name: &KeyString, bytes: &[u8]| {
+            binary.push(kind as u8);
+            binary.extend_from_slice(name.raw());
+            binary.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+            binary.extend_from_slice(bytes);
+        };
+        for (name, table) in &self.tables {
+            write_section(SectionKind::Table, name, table);
+        }
+        for (key, value) in &self.values {
+            write_section(SectionKind::Value, key, value);
+        }
+        write_section(SectionKind::Users, &ksf(USERS_FILE), &self.users);
+        write_section(SectionKind::ValueExpiry, &ksf(VALUE_EXPIRY_FILE), &self.value_expiry);
+        write_section(SectionKind::Tasks, &ksf(TASKS_FILE), &self.tasks);
+        write_section(SectionKind::Quotas, &ksf(QUOTAS_FILE), &self.quotas);
+        write_section(SectionKind::Partitions, &ksf(PARTITIONS_FILE), &self.partitions);
+
+        binary
+    }
+
+    /// Reads an archive and checks every table, value and metadata file in it, so a restore never
+    /// replaces the data directory with something the server can't load.
+    pub fn from_binary(binary: &[u8]) -> Result<Backup, EzError> {
+        if binary.len() < BACKUP_HEADER_SIZE || KeyString::try_from(&binary[0..64])?.as_str() != BACKUP_MAGIC {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: "Not an EZDB backup archive".to_owned()})
+        }
+        let version = u64_from_le_slice(&binary[64..72]);
+        if version > BACKUP_FORMAT_VERSION {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Backup archive version {} is newer than this server understands ({})", version, BACKUP_FORMAT_VERSION)})
+        }
+        let taken_at = u64_from_le_slice(&binary[72..80]);
+        let sections = u64_from_le_slice(&binary[80..88]);
+
+        let mut backup = Backup{version, taken_at, ..Default::default()};
+        let mut pointer = BACKUP_HEADER_SIZE;
+        for _ in 0..sections {
+            if binary.len() < pointer + 73 {
+                return Err(EzError{tag: ErrorTag::Deserialization, text: "Backup archive is truncated".to_owned()})
+            }
+            let kind = SectionKind::from_byte(binary[pointer])?;
+            let name = KeyString::try_from(&binary[pointer + 1..pointer + 65])?;
+            let len = u64_from_le_slice(&binary[pointer + 65..pointer + 73]) as usize;
+            pointer += 73;
+            if binary.len() - pointer < len {
+                return Err(EzError{tag: ErrorTag::Deserialization, text: "Backup archive is truncated".to_owned()})
+            }
+            let bytes = binary[pointer..pointer + len].to_vec();
+            pointer += len;
+
+            match kind {
+                SectionKind::Table => {
+                    ColumnTable::from_binary(Some(name.as_str()), &bytes)?;
+                    backup.tables.insert(name, bytes);
+                },
+                SectionKind::Value => {
+                    Value::from_binary(name.as_str(), &bytes)?;
+                    backup.values.insert(name, bytes);
+                },
+                SectionKind::Users => {
+                    decode_cbor::<BTreeMap<KeyString, User>>(&bytes)?;
+                    backup.users = bytes;
+                },
+                SectionKind::ValueExpiry => {
+                    ValueExpiry::from_binary(&bytes)?;
+                    backup.value_expiry = bytes;
+                },
+                SectionKind::Tasks => {
+                    if !bytes.len().is_multiple_of(TASK_BINARY_SIZE) {
+                        return Err(EzError{tag: ErrorTag::Deserialization, text: "The tasks in the backup archive are corrupted".to_owned()})
+                    }
+                    backup.tasks = bytes;
+                },
+                SectionKind::Quotas => {
+                    if !bytes.len().is_multiple_of(QUOTA_BINARY_SIZE) {
+                        return Err(EzError{tag: ErrorTag::Deserialization, text: "The quotas in the backup archive are corrupted".to_owned()})
+                    }
+                    backup.quotas = bytes;
+                },
+                SectionKind::Partitions => {
+                    partitions_from_binary(&bytes)?;
+                    backup.partitions = bytes;
+                },
+            }
+        }
+        if pointer != binary.len() {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("{} bytes after the last section of the backup archive", binary.len() - pointer)})
+        }
+
+        Ok(backup)
+    }
+
+    pub fn summary(&self) -> Result<BackupSummary, EzError> {
+        Ok(BackupSummary {
+            version: self.version,
+            taken_at: self.taken_at,
+            tables: self.tables.len(),
+            values: self.values.len(),
+            users: decode_cbor::<BTreeMap<KeyString, User>>(&self.users)?.len(),
+            bytes: self.tables.values().chain(self.values.values()).map(|bytes| bytes.len()).sum(),
+            moved_to: None,
+        })
+    }
+}
+
+/// Replaces the tables, values, users and metadata files of a data directory with the contents of an archive.
+/// Runs at startup, before the server loads anything. The whole archive is checked before anything is touched,
+/// and the files it replaces are moved to a pre_restore_<time> directory inside the data directory rather than deleted.
+pub fn restore_backup(archive: &Path, data_dir: &Path) -> Result<BackupSummary, EzError> {
+    println!("calling: restore_backup()");
+
+    let backup = Backup::from_binary(&std::fs::read(archive)?)?;
+    let mut summary = backup.summary()?;
+
+    std::fs::create_dir_all(data_dir)?;
+    let aside = data_dir.join(format!("{}{}", PRE_RESTORE_PREFIX, get_current_time()));
+    // The key filters describe the tables being replaced so they go too and are rebuilt from the restored tables.
+    // So does the value history since it belongs to the values being replaced. Archives don't hold history.
+    for name in [RAW_TABLES_DIR, TABLE_CHUNKS_DIR, KEY_FILTERS_DIR, RAW_VALUES_DIR, USERS_FILE, VALUE_EXPIRY_FILE, VALUE_HISTORY_FILE, TASKS_FILE, QUOTAS_FILE, PARTITIONS_FILE] {
+        let path = data_dir.join(name);
+        if path.exists() {
+            std::fs::create_dir_all(&aside)?;
+            std::fs::rename(&path, aside.join(name))?;
+            summary.moved_to = Some(aside.clone());
+        }
+    }
+
+    let tables_dir = data_dir.join(RAW_TABLES_DIR);
+    std::fs::create_dir_all(&tables_dir)?;
+    for (name, table) in &backup.tables {
+        std::fs::write(tables_dir.join(name.as_str()), table)?;
+    }
+    let values_dir = data_dir.join(RAW_VALUES_DIR);
+    std::fs::create_dir_all(&values_dir)?;
+    for (key, value) in &backup.values {
+        std::fs::write(values_dir.join(key.as_str()), value)?;
+    }
+    std::fs::write(data_dir.join(USERS_FILE), &backup.users)?;
+    std::fs::write(data_dir.join(VALUE_EXPIRY_FILE), &backup.value_expiry)?;
+    std::fs::write(data_dir.join(TASKS_FILE), &backup.tasks)?;
+    std::fs::write(data_dir.join(QUOTAS_FILE), &backup.quotas)?;
+    std::fs::write(data_dir.join(PARTITIONS_FILE), &backup.partitions)?;
+
+    Ok(summary)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing_tools::create_fixed_table;
+
+    fn test_backup() -> Backup {
+        let table = create_fixed_table(10);
+        let users = BTreeMap::from([(ksf("admin"), User::admin("admin", "admin"))]);
+        let mut expiry = ValueExpiry::default();
+        expiry.set(ksf("core1"), 1_000);
+        Backup {
+            version: BACKUP_FORMAT_VERSION,
+            taken_at: 1234,
+            tables: BTreeMap::from([(table.name, table.to_disk_binary().unwrap())]),
+            values: BTreeMap::from([(ksf("core1"), Value{name: ksf("core1"), body: vec![1, 2, 3]}.write_to_binary())]),
+            users: users.to_cbor_bytes(),
+            value_expiry: expiry.to_binary(),
+            tasks: Vec::new(),
+            quotas: Vec::new(),
+            partitions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_backup_binary() {
+        let backup = test_backup();
+        let binary = backup.to_binary();
+        assert_eq!(Backup::from_binary(&binary).unwrap(), backup);
+
+        assert!(Backup::from_binary(&binary[..binary.len() - 1]).is_err());
+        let mut newer = binary.clone();
+        newer[64..72].copy_from_slice(&(BACKUP_FORMAT_VERSION + 1).to_le_bytes());
+        assert!(Backup::from_binary(&newer).unwrap_err().text.contains("newer"));
+        let mut garbage = binary.clone();
+        garbage.push(0);
+        assert!(Backup::from_binary(&garbage).is_err());
+    }
+
+    #[test]
+    fn test_restore_backup() {
+        let dir = PathBuf::from("test_files").join("restore_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let data_dir = dir.join("EZconfig");
+        std::fs::create_dir_all(data_dir.join(RAW_TABLES_DIR)).unwrap();
+        std::fs::write(data_dir.join(RAW_TABLES_DIR).join("old_table"), b"old").unwrap();
+
+        let backup = test_backup();
+        let archive = dir.join("archive");
+        std::fs::write(&archive, backup.to_binary()).unwrap();
+
+        let summary = restore_backup(&archive, &data_dir).unwrap();
+        assert_eq!((summary.tables, summary.values, summary.users), (1, 1, 1));
+        let moved_to = summary.moved_to.unwrap();
+        assert_eq!(std::fs::read(moved_to.join(RAW_TABLES_DIR).join("old_table")).unwrap(), b"old");
+        assert!(!data_dir.join(RAW_TABLES_DIR).join("old_table").exists());
+
+        let table = ColumnTable::from_binary(Some("fixed_table"), &std::fs::read(data_dir.join(RAW_TABLES_DIR).join("fixed_table")).unwrap()).unwrap();
+        assert_eq!(table, create_fixed_table(10));
+        let value = Value::from_binary("core1", &std::fs::read(data_dir.join(RAW_VALUES_DIR).join("core1")).unwrap()).unwrap();
+        assert_eq!(value.body, vec![1, 2, 3]);
+        assert_eq!(ValueExpiry::from_binary(&std::fs::read(data_dir.join(VALUE_EXPIRY_FILE)).unwrap()).unwrap().get(&ksf("core1")), Some(1_000));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+//! Talks to a running EZDB server. Without a command it starts an interactive prompt.
+//!
+//!     ezdb-cli [--address=127.0.0.1:3004] [--user=admin] [--password=...] [command]
+//!
+//! The password can also be given in EZDB_PASSWORD. It is asked for when it is neither.
+//! A command is either EZQL text or one of the dot commands listed by .help. See EZDB::cli.
+
+use std::io::{BufRead, Write};
+
+use EZDB::cli::{is_complete, run_command, CliCommand, CLI_HELP};
+use EZDB::client_networking::make_connection;
+use EZDB::config::DEFAULT_LISTEN_ADDRESS;
+use EZDB::utilities::EzError;
+
+fn main() -> Result<(), EzError> {
+    let mut address = DEFAULT_LISTEN_ADDRESS.to_owned();
+    let mut username = "admin".to_owned();
+    let mut password = std::env::var("EZDB_PASSWORD").ok();
+    let mut words = Vec::new();
+    for arg in std::env::args().skip(1) {
+        if let Some(value) = arg.strip_prefix("--address=") {
+            address = value.to_owned();
+        } else if let Some(value) = arg.strip_prefix("--user=") {
+            username = value.to_owned();
+        } else if let Some(value) = arg.strip_prefix("--password=") {
+            password = Some(value.to_owned());
+        } else if arg == "--help" || arg == "-h" {
+            println!("Usage: ezdb-cli [--address={}] [--user=admin] [--password=...] [command]\n{}", DEFAULT_LISTEN_ADDRESS, CLI_HELP);
+            return Ok(())
+        } else {
+            words.push(arg);
+        }
+    }
+
+    let stdin = std::io::stdin();
+    let password = match password {
+        Some(password) => password,
+        None => {
+            print!("Password for {}: ", username);
+            std::io::stdout().flush()?;
+            let mut line = String::new();
+            stdin.lock().read_line(&mut line)?;
+            line.trim_end().to_owned()
+        },
+    };
+    let mut connection = make_connection(&address, &username, &password)?;
+
+    // One shot. The exit code tells scripts whether it worked
+    if !words.is_empty() {
+        let command = CliCommand::parse(&words.join(" "))?;
+        match run_command(&mut connection, &command, &username) {
+            Ok(output) => println!("{}", output),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            },
+        }
+        return Ok(())
+    }
+
+    println!("Connected to {} as {}. Type .help for the commands", address, username);
+    let mut pending = String::new();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", if pending.is_empty() { "ezdb> " } else { "  ... " });
+        std::io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        pending.push_str(&line);
+        pending.push('\n');
+        if !is_complete(&pending) {
+            continue
+        }
+        let text = std::mem::take(&mut pending);
+        if text.trim().is_empty() {
+            continue
+        }
+        match CliCommand::parse(&text) {
+            Ok(CliCommand::Quit) => break,
+            Ok(command) => match run_command(&mut connection, &command, &username) {
+                Ok(output) => println!("{}", output),
+                Err(e) => eprintln!("{}", e),
+            },
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    Ok(())
+}
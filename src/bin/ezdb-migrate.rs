@@ -0,0 +1,34 @@
+//! Upgrades an EZDB data directory to the format of this build. Stop the server before running it.
+//!
+//!     ezdb-migrate [data directory] [--dry-run]
+//!
+//! The data directory defaults to EZconfig in the working directory, the same place the server uses.
+
+use std::path::PathBuf;
+
+use EZDB::migrate::migrate_data_dir;
+use EZDB::paths::CONFIG_DIR;
+use EZDB::utilities::EzError;
+
+fn main() -> Result<(), EzError> {
+    let mut data_dir = PathBuf::from(CONFIG_DIR);
+    let mut dry_run = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--help" | "-h" => {
+                println!("Usage: ezdb-migrate [data directory, default {}] [--dry-run]", CONFIG_DIR);
+                return Ok(())
+            },
+            path => data_dir = PathBuf::from(path),
+        }
+    }
+
+    let report = migrate_data_dir(&data_dir, dry_run)?;
+    print!("{}", report);
+    if !report.failed.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,394 @@
+use std::collections::{BTreeSet, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::db_structure::{ColumnTable, DbColumn};
+use crate::disk_utilities::{read_table_file, CHUNK_SIZE};
+use crate::database::Database;
+use crate::partitions::partitioned_table_of;
+use crate::trash::{list_trash, read_trashed_table};
+use crate::utilities::{encode_hex, ez_hash, ErrorTag, EzError, KeyString};
+use crate::paths::config_file;
+
+
+/// Text cells starting with this prefix reference a blob. "blob:<id>"
+pub const BLOB_REF_PREFIX: &str = "blob:";
+
+/// Blob ids are the first 29 bytes of the sha256 of the contents, hex encoded.
+/// That is the most that fits in a Text cell together with the prefix.
+pub const BLOB_ID_LEN: usize = 58;
+
+/// Blobs stored, or stored again, less than this many seconds ago are never collected. Clients upload a blob
+/// before they insert the row that references it, so a fresh blob with no references is not garbage yet.
+pub const BLOB_GRACE_SECS: u64 = 60 * 60;
+
+/// A reference from a table cell to a blob in the BlobStore.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlobRef {
+    pub id: KeyString,
+}
+
+impl BlobRef {
+    pub fn for_content(content: &[u8]) -> BlobRef {
+        let hash = encode_hex(&ez_hash(content));
+        BlobRef{id: KeyString::from(&hash[0..BLOB_ID_LEN])}
+    }
+
+    /// The value to store in a Text column to link a row to this blob.
+    pub fn to_cell(&self) -> KeyString {
+        KeyString::from(format!("{}{}", BLOB_REF_PREFIX, self.id).as_str())
+    }
+
+    /// Returns None if the cell is not a blob reference.
+    pub fn from_cell(cell: &KeyString) -> Option<BlobRef> {
+        BlobRef::from_text(cell.as_str())
+    }
+
+    /// Like from_cell() for the cells of a LongText column.
+    pub fn from_text(text: &str) -> Option<BlobRef> {
+        let id = text.strip_prefix(BLOB_REF_PREFIX)?;
+        if id.len() == BLOB_ID_LEN && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            Some(BlobRef{id: KeyString::from(id)})
+        } else {
+            None
+        }
+    }
+}
+
+/// Stores binary attachments keyed by their hash.
+/// Each blob is split into CHUNK_SIZE chunks which are themselves stored by hash, so identical blobs and
+/// identical chunks are only written once. A manifest per blob lists its chunks in order.
+///     root/manifests/<blob id>
+///     root/chunks/<chunk hash>
+pub struct BlobStore {
+    root: PathBuf,
+    /// Held while writing or collecting so garbage collection never deletes chunks of a half written blob.
+    lock: Mutex<()>,
+}
+
+impl BlobStore {
+    /// Nothing is touched on disk until the first blob is stored.
+    pub fn new(root: impl AsRef<Path>) -> BlobStore {
+        BlobStore{root: root.as_ref().to_path_buf(), lock: Mutex::new(())}
+    }
+
+    pub fn default_path() -> PathBuf {
+        config_file("blobs")
+    }
+
+    fn manifest_path(&self, blob: &BlobRef) -> PathBuf {
+        self.root.join("manifests").join(blob.id.as_str())
+    }
+
+    fn chunk_path(&self, chunk_id: &str) -> PathBuf {
+        self.root.join("chunks").join(chunk_id)
+    }
+
+    /// Stores the content and returns its reference. Storing the same content twice only restarts its grace period.
+    pub fn put(&self, content: &[u8]) -> Result<BlobRef, EzError> {
+        println!("calling: BlobStore::put()");
+
+        let blob = BlobRef::for_content(content);
+        let _guard = self.lock.lock().unwrap();
+        if self.contains(&blob) {
+            std::fs::File::options().write(true).open(self.manifest_path(&blob))?.set_modified(SystemTime::now())?;
+            return Ok(blob)
+        }
+
+        std::fs::create_dir_all(self.root.join("manifests"))?;
+        std::fs::create_dir_all(self.root.join("chunks"))?;
+
+        let mut manifest = String::new();
+        for chunk in content.chunks(CHUNK_SIZE) {
+            let chunk_id = encode_hex(&ez_hash(chunk));
+            let path = self.chunk_path(&chunk_id);
+            if !path.exists() {
+                write_atomically(&path, chunk)?;
+            }
+            manifest.push_str(&chunk_id);
+            manifest.push('\n');
+        }
+        // The manifest goes last so a blob is never visible before all its chunks are written.
+        write_atomically(&self.manifest_path(&blob), manifest.as_bytes())?;
+
+        Ok(blob)
+    }
+
+    pub fn contains(&self, blob: &BlobRef) -> bool {
+        self.manifest_path(blob).exists()
+    }
+
+    /// Seconds since the epoch when the blob was last stored.
+    fn stored_at(&self, blob: &BlobRef) -> Result<u64, EzError> {
+        let modified = std::fs::metadata(self.manifest_path(blob))?.modified()?;
+        Ok(modified.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()))
+    }
+
+    /// Reassembles a blob from its chunks and checks it against its id.
+    pub fn get(&self, blob: &BlobRef) -> Result<Vec<u8>, EzError> {
+        println!("calling: BlobStore::get()");
+
+        let manifest = match std::fs::read_to_string(self.manifest_path(blob)) {
+            Ok(manifest) => manifest,
+            Err(_) => return Err(EzError{tag: ErrorTag::Query, text: format!("No blob with id: '{}'", blob.id)}),
+        };
+
+        let mut content = Vec::new();
+        for chunk_id in manifest.lines() {
+            content.extend_from_slice(&std::fs::read(self.chunk_path(chunk_id))?);
+        }
+
+        if BlobRef::for_content(&content) != *blob {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Blob '{}' is corrupted", blob.id)})
+        }
+
+        Ok(content)
+    }
+
+    /// All blobs currently in the store.
+    pub fn list(&self) -> Result<Vec<BlobRef>, EzError> {
+        let dir = self.root.join("manifests");
+        if !dir.exists() {
+            return Ok(Vec::new())
+        }
+        let mut blobs = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let id = name.to_string_lossy();
+            // Skips leftover temp files from interrupted writes
+            if id.len() == BLOB_ID_LEN && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+                blobs.push(BlobRef{id: KeyString::from(id.as_ref())});
+            }
+        }
+        blobs.sort();
+        Ok(blobs)
+    }
+
+    /// Deletes every blob that is not in the set `referenced` returns and is older than BLOB_GRACE_SECS,
+    /// and then every chunk no remaining blob uses. `referenced` runs with the store locked, so no blob
+    /// can be stored between the scan for references and the deletes. Returns the number of blobs deleted.
+    pub fn collect_garbage(&self, now: u64, referenced: impl FnOnce() -> Result<HashSet<BlobRef>, EzError>) -> Result<usize, EzError> {
+        println!("calling: BlobStore::collect_garbage()");
+
+        let _guard = self.lock.lock().unwrap();
+        let referenced = referenced()?;
+        let mut removed = 0;
+        let mut live_chunks = HashSet::new();
+        for blob in self.list()? {
+            if referenced.contains(&blob) || self.stored_at(&blob)?.saturating_add(BLOB_GRACE_SECS) > now {
+                for chunk_id in std::fs::read_to_string(self.manifest_path(&blob))?.lines() {
+                    live_chunks.insert(chunk_id.to_owned());
+                }
+            } else {
+                std::fs::remove_file(self.manifest_path(&blob))?;
+                removed += 1;
+            }
+        }
+
+        let chunk_dir = self.root.join("chunks");
+        if chunk_dir.exists() {
+            for entry in std::fs::read_dir(chunk_dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !live_chunks.contains(&name) {
+                    std::fs::remove_file(entry.path())?;
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+fn write_atomically(path: &Path, content: &[u8]) -> Result<(), EzError> {
+    let temp_path = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&temp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+/// Every blob referenced from a Text or LongText cell of any table. That includes the rows waiting in row buffers,
+/// tables that were unloaded for being idle and dropped tables in the trash, which can still be restored.
+pub fn referenced_blobs(database: &Database) -> Result<HashSet<BlobRef>, EzError> {
+
+    let mut referenced = HashSet::new();
+    visit_tables(database, true, |_, table| add_references(table, &mut referenced))?;
+
+    Ok(referenced)
+}
+
+/// The tables with a cell referencing the blob, partitioned tables under their own name. Dropped tables don't count.
+pub fn tables_referencing(blob: &BlobRef, database: &Database) -> Result<BTreeSet<KeyString>, EzError> {
+
+    let mut tables = BTreeSet::new();
+    visit_tables(database, false, |table_name, table| {
+        let mut referenced = HashSet::new();
+        add_references(table, &mut referenced);
+        if referenced.contains(blob) {
+            tables.insert(*table_name);
+        }
+    })?;
+
+    Ok(tables)
+}
+
+fn add_references(table: &ColumnTable, referenced: &mut HashSet<BlobRef>) {
+    for column in table.columns.values() {
+        match column {
+            DbColumn::Texts(col) => referenced.extend(col.iter().filter_map(BlobRef::from_cell)),
+            DbColumn::LongTexts(col) => referenced.extend(col.iter().filter_map(BlobRef::from_text)),
+            _ => (),
+        }
+    }
+}
+
+/// Calls `visit` with every table, loaded or unloaded, the rows waiting in every row buffer and, if `include_trash`,
+/// every dropped table. Partitions are passed under the name of their table.
+/// Every loaded table stays read locked until all of them have been visited, so a reference can't move from a table
+/// that has not been visited yet to one that has.
+fn visit_tables(database: &Database, include_trash: bool, mut visit: impl FnMut(&KeyString, &ColumnTable)) -> Result<(), EzError> {
+    let pool = &database.buffer_pool;
+    let partitioned = pool.partitions.read().unwrap().clone();
+    let table_name = |name: &KeyString| partitioned_table_of(name, &partitioned).unwrap_or(*name);
+
+    // Row buffers are always locked before the table map. See BufferPool::row_buffers
+    let buffers = pool.row_buffers.read().unwrap();
+    let tables = pool.tables.read().unwrap();
+    let mut guards = Vec::with_capacity(tables.len());
+    for (name, table) in tables.iter() {
+        guards.push((*name, database.locks.read_table(*name, table)?));
+    }
+
+    for (name, guard) in &guards {
+        visit(&table_name(name), guard);
+    }
+    for (name, buffer) in buffers.iter() {
+        visit(&table_name(name), &buffer.to_column_table());
+    }
+    // Unloaded tables can't be reloaded or changed while we hold the table map
+    for name in pool.unloaded_tables.read().unwrap().keys() {
+        visit(&table_name(name), &read_table_file(name.as_str())?);
+    }
+    if include_trash {
+        for trashed in list_trash()? {
+            if let Some(table) = read_trashed_table(trashed.name.as_str())? {
+                visit(&trashed.name, &table);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::disk_utilities::write_table_chunks;
+    use crate::paths::test_file;
+    use crate::trash::{move_to_trash, purge_trash};
+    use crate::utilities::{get_current_time, ksf};
+
+    use super::*;
+
+    #[test]
+    fn test_blob_store() {
+        let root = test_file("blob_store_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let store = BlobStore::new(&root);
+
+        let big: Vec<u8> = (0..CHUNK_SIZE * 2 + 17).map(|i| (i % 251) as u8).collect();
+        let small = b"a tiny attachment".to_vec();
+
+        let big_ref = store.put(&big).unwrap();
+        let small_ref = store.put(&small).unwrap();
+        assert_eq!(store.put(&big).unwrap(), big_ref);
+        assert_eq!(store.get(&big_ref).unwrap(), big);
+        assert_eq!(store.get(&small_ref).unwrap(), small);
+        assert_eq!(BlobRef::from_cell(&big_ref.to_cell()), Some(big_ref));
+        assert_eq!(BlobRef::from_cell(&KeyString::from("not a blob")), None);
+        assert_eq!(store.list().unwrap().len(), 2);
+
+        // Freshly stored blobs are kept even though nothing references them yet
+        let now = get_current_time();
+        let referenced = HashSet::from([small_ref]);
+        assert_eq!(store.collect_garbage(now, || Ok(referenced.clone())).unwrap(), 0);
+        assert_eq!(store.get(&big_ref).unwrap(), big);
+
+        let later = now + BLOB_GRACE_SECS + 1;
+        assert_eq!(store.collect_garbage(later, || Ok(referenced.clone())).unwrap(), 1);
+        assert!(store.get(&big_ref).is_err());
+        assert_eq!(store.get(&small_ref).unwrap(), small);
+        assert_eq!(std::fs::read_dir(root.join("chunks")).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_put_restarts_grace_period() {
+        let root = test_file("blob_store_grace_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let store = BlobStore::new(&root);
+
+        let blob = store.put(b"uploaded twice").unwrap();
+        let long_ago = SystemTime::now() - Duration::from_secs(BLOB_GRACE_SECS * 2);
+        std::fs::File::options().write(true).open(store.manifest_path(&blob)).unwrap().set_modified(long_ago).unwrap();
+        assert!(store.stored_at(&blob).unwrap() + BLOB_GRACE_SECS < get_current_time());
+
+        store.put(b"uploaded twice").unwrap();
+        assert_eq!(store.collect_garbage(get_current_time(), || Ok(HashSet::new())).unwrap(), 0);
+        assert!(store.contains(&blob));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_references_are_scanned_under_the_store_lock() {
+        let root = test_file("blob_store_lock_test");
+        let _ = std::fs::remove_dir_all(&root);
+        let store = BlobStore::new(&root);
+
+        store.collect_garbage(get_current_time(), || {
+            assert!(store.lock.try_lock().is_err());
+            Ok(HashSet::new())
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_referenced_blobs_sources() {
+        let database = Database::init().unwrap();
+        let blobs: Vec<BlobRef> = (0..4u8).map(|i| BlobRef::for_content(&[i; 8])).collect();
+
+        // A LongText cell of a loaded table
+        let notes = ColumnTable::from_csv_string(&format!("id,i-P;note,l-N\n1;{}", blobs[0].to_cell()), "blob_refs_notes", "test").unwrap();
+        database.buffer_pool.add_table(notes).unwrap();
+
+        // A row still waiting in the row buffer of its table
+        let files = ColumnTable::from_csv_string("id,i-P;file,t-N\n1;none", "blob_refs_files", "test").unwrap();
+        database.buffer_pool.add_table(files.clone()).unwrap();
+        let waiting = ColumnTable::from_csv_string(&format!("id,i-P;file,t-N\n2;{}", blobs[1].to_cell()), "blob_refs_files", "test").unwrap();
+        database.buffer_pool.buffer_rows(files.name, &files.header, &waiting).unwrap();
+
+        // A dropped table that can still be restored
+        let dropped = ColumnTable::from_csv_string(&format!("id,i-P;file,t-N\n1;{}", blobs[2].to_cell()), "blob_refs_dropped", "test").unwrap();
+        write_table_chunks("blob_refs_dropped", &dropped, None, 2).unwrap();
+        move_to_trash("blob_refs_dropped", get_current_time()).unwrap();
+
+        let referenced = referenced_blobs(&database).unwrap();
+        assert!(referenced.contains(&blobs[0]));
+        assert!(referenced.contains(&blobs[1]));
+        assert!(referenced.contains(&blobs[2]));
+        assert!(!referenced.contains(&blobs[3]));
+
+        assert_eq!(tables_referencing(&blobs[0], &database).unwrap(), BTreeSet::from([ksf("blob_refs_notes")]));
+        assert_eq!(tables_referencing(&blobs[1], &database).unwrap(), BTreeSet::from([ksf("blob_refs_files")]));
+        assert!(tables_referencing(&blobs[2], &database).unwrap().is_empty());
+
+        purge_trash(Some(&ksf("blob_refs_dropped"))).unwrap();
+    }
+}
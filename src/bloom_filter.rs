@@ -22,6 +22,9 @@ use getrandom::getrandom;
 use siphasher::reexports::serde;
 use siphasher::sip::SipHasher13;
 
+use crate::db_structure::DbColumn;
+use crate::utilities::{checked_slice, u64_from_le_slice, ErrorTag, EzError, KeyString};
+
 pub mod reexports {
     #[cfg(feature = "random")]
     pub use ::getrandom;
@@ -262,6 +265,136 @@ impl<T: ?Sized> Bloom<T> {
 }
 
 
+/// How many keys a new KeyFilter makes room for per row of the table, so a table can grow for a while before
+/// its filter has to be rebuilt.
+pub const KEY_FILTER_HEADROOM: usize = 2;
+/// Rows a KeyFilter makes room for however small the table is.
+pub const KEY_FILTER_MIN_KEYS: usize = 1024;
+/// The share of lookups of missing keys a KeyFilter lets through.
+pub const KEY_FILTER_FALSE_POSITIVES: f64 = 0.01;
+/// [bitmap_bits: 8][hash functions: 8][sip keys: 32][capacity: 8][keys: 8][removed: 8][table rows: 8][int keys: 8]
+const KEY_FILTER_HEADER_SIZE: usize = 88;
+
+/// A bloom filter over the primary keys of a table. When it says a key is missing the key is not in the table,
+/// so lookups of missing keys need neither the table nor its file. Keys can't be taken out of a bloom filter,
+/// so deleted keys are only counted and the filter is rebuilt from the table once too many of its keys are
+/// gone or it holds more keys than it was made for. See BufferPool::key_filters.
+#[derive(Debug)]
+pub struct KeyFilter {
+    bloom: Bloom<[u8]>,
+    int_keys: bool,
+    capacity: usize,
+    keys: usize,
+    removed: usize,
+}
+
+impl KeyFilter {
+    /// A filter holding every key of a primary key column. None for columns that can't be primary keys.
+    pub fn from_keys(keys: &DbColumn) -> Option<KeyFilter> {
+        let int_keys = match keys {
+            DbColumn::Ints(_) => true,
+            DbColumn::Texts(_) => false,
+            _ => return None,
+        };
+        let capacity = cmp::max(keys.len() * KEY_FILTER_HEADROOM, KEY_FILTER_MIN_KEYS);
+        let bloom = Bloom::new_for_fp_rate_with_seed(capacity, KEY_FILTER_FALSE_POSITIVES, &rand::random::<[u8; 32]>());
+        let mut filter = KeyFilter{bloom, int_keys, capacity, keys: 0, removed: 0};
+        filter.add_keys(keys);
+        Some(filter)
+    }
+
+    /// Records new keys. Keys of the wrong type are ignored since they can't be in the table either.
+    pub fn add_keys(&mut self, keys: &DbColumn) {
+        match keys {
+            DbColumn::Ints(keys) if self.int_keys => for key in keys {
+                self.bloom.set(&key.to_le_bytes()[..]);
+            },
+            DbColumn::Texts(keys) if !self.int_keys => for key in keys {
+                self.bloom.set(key.raw());
+            },
+            _ => return,
+        }
+        self.keys += keys.len();
+    }
+
+    /// Records that rows were deleted. Their keys stay in the filter until it is rebuilt.
+    pub fn note_removed(&mut self, rows: usize) {
+        self.removed += rows;
+    }
+
+    /// Whether the filter should be built again from the table. Either it holds more keys than it was sized
+    /// for, so it lets too many missing keys through, or most of what it holds was deleted.
+    pub fn needs_rebuild(&self) -> bool {
+        self.keys > self.capacity || self.removed * 2 > self.keys
+    }
+
+    pub fn has_int_keys(&self) -> bool {
+        self.int_keys
+    }
+
+    pub fn might_contain_int(&self, key: i32) -> bool {
+        self.int_keys && self.bloom.check(&key.to_le_bytes()[..])
+    }
+
+    pub fn might_contain_text(&self, key: &KeyString) -> bool {
+        !self.int_keys && self.bloom.check(key.raw())
+    }
+
+    /// Tests a key written as text, the way queries list keys. A key that is not a valid int for a table with
+    /// int keys might still be there as far as the filter knows, so the query reports the bad key itself.
+    pub fn might_contain(&self, key: &KeyString) -> bool {
+        match self.int_keys {
+            true => match key.to_i32_checked() {
+                Ok(key) => self.might_contain_int(key),
+                Err(_) => true,
+            },
+            false => self.might_contain_text(key),
+        }
+    }
+
+    /// The filter as written to its file. The row count of the table is kept with it so a filter that does not
+    /// belong to the table on disk is noticed when it is read back.
+    pub fn to_binary(&self, table_rows: usize) -> Vec<u8> {
+        let bitmap = self.bloom.bitmap();
+        let mut binary = Vec::with_capacity(KEY_FILTER_HEADER_SIZE + bitmap.len());
+        binary.extend_from_slice(&self.bloom.number_of_bits().to_le_bytes());
+        binary.extend_from_slice(&(self.bloom.number_of_hash_functions() as u64).to_le_bytes());
+        for (k0, k1) in self.bloom.sip_keys() {
+            binary.extend_from_slice(&k0.to_le_bytes());
+            binary.extend_from_slice(&k1.to_le_bytes());
+        }
+        for number in [self.capacity, self.keys, self.removed, table_rows, self.int_keys as usize] {
+            binary.extend_from_slice(&(number as u64).to_le_bytes());
+        }
+        binary.extend_from_slice(&bitmap);
+        binary
+    }
+
+    /// Reads a filter written by to_binary() and the row count of the table it was written with.
+    pub fn from_binary(binary: &[u8]) -> Result<(KeyFilter, usize), EzError> {
+        let number = |i: usize| checked_slice(binary, i * 8, 8, "Key filter").map(u64_from_le_slice);
+        let bitmap_bits = number(0)?;
+        let hash_functions = number(1)?;
+        let sip_keys = [(number(2)?, number(3)?), (number(4)?, number(5)?)];
+        let (capacity, keys, removed, table_rows, int_keys) = (number(6)?, number(7)?, number(8)?, number(9)?, number(10)?);
+
+        let bitmap = &binary[KEY_FILTER_HEADER_SIZE..];
+        if bitmap_bits == 0 || hash_functions == 0 || hash_functions > u32::MAX as u64 || (bitmap.len() as u64) * 8 < bitmap_bits || int_keys > 1 {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: "Key filter is corrupted".to_owned()})
+        }
+        let bloom = Bloom::from_existing(bitmap, bitmap_bits, hash_functions as u32, sip_keys);
+        let filter = KeyFilter {
+            bloom,
+            int_keys: int_keys == 1,
+            capacity: capacity as usize,
+            keys: keys as usize,
+            removed: removed as usize,
+        };
+        Ok((filter, table_rows as usize))
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -286,4 +419,27 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_key_filter() {
+        let keys: Vec<i32> = (0..5000).map(|x| x * 2).collect();
+        let mut filter = KeyFilter::from_keys(&DbColumn::Ints(keys.clone())).unwrap();
+        assert!(keys.iter().all(|key| filter.might_contain_int(*key)));
+        let missing = (0..5000).filter(|x| filter.might_contain_int(x * 2 + 1)).count();
+        assert!(missing < 250, "{} of 5000 missing keys got through", missing);
+        assert!(!filter.might_contain_text(&KeyString::from("0")));
+        assert!(filter.might_contain(&KeyString::from("not a number")));
+
+        filter.add_keys(&DbColumn::Ints(vec![7]));
+        let (read, rows) = KeyFilter::from_binary(&filter.to_binary(5001)).unwrap();
+        assert_eq!(rows, 5001);
+        assert!(read.might_contain(&KeyString::from("7")));
+        assert_eq!(read.bloom.bitmap(), filter.bloom.bitmap());
+        assert!(KeyFilter::from_binary(&filter.to_binary(5001)[..40]).is_err());
+
+        assert!(!filter.needs_rebuild());
+        filter.note_removed(4000);
+        assert!(filter.needs_rebuild());
+        assert!(KeyFilter::from_keys(&DbColumn::Floats(vec![1.0])).is_none());
+    }
+
 }
\ No newline at end of file
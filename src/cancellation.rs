@@ -0,0 +1,104 @@
+//! Lets a worker notice that the client of the request it is running went away. The worker watches the
+//! socket of the job while it runs and query execution calls check_cancelled() between queries, so the rest
+//! of the batch is skipped and its locks released instead of being carried out for nobody.
+
+use std::cell::Cell;
+#[cfg(unix)]
+use std::os::fd::RawFd;
+
+// Nothing is ever watched off unix, see peer_disconnected()
+#[cfg(not(unix))]
+pub type RawFd = i32;
+
+use crate::utilities::{ErrorTag, EzError};
+
+
+thread_local! {
+    static WATCHED: Cell<Option<RawFd>> = const { Cell::new(None) };
+}
+
+/// Whether the other end of the socket hung up or the socket failed. Never blocks.
+/// POLLRDHUP is set once the peer shut down its end, even if it sent data first that nobody has read yet.
+/// nix 0.29 has no name for it so this polls through the libc nix exports.
+#[cfg(all(feature = "server", target_os = "linux"))]
+pub fn peer_disconnected(fd: RawFd) -> bool {
+    use nix::libc::{poll, pollfd, POLLERR, POLLHUP, POLLIN, POLLRDHUP};
+
+    let mut watched = pollfd{fd, events: POLLIN | POLLRDHUP, revents: 0};
+    // The fd stays open for as long as the job holds the connection
+    match unsafe { poll(&mut watched, 1, 0) } {
+        1 => watched.revents & (POLLRDHUP | POLLHUP | POLLERR) != 0,
+        _ => false,
+    }
+}
+
+/// Only the server on Linux watches connections, so elsewhere no peer is ever seen to leave.
+#[cfg(not(all(feature = "server", target_os = "linux")))]
+pub fn peer_disconnected(_fd: RawFd) -> bool {
+    false
+}
+
+/// Watches a connection for the current thread until dropped. See check_cancelled().
+pub struct DisconnectWatch {
+    previous: Option<RawFd>,
+}
+
+pub fn watch_connection(fd: RawFd) -> DisconnectWatch {
+    DisconnectWatch{previous: WATCHED.replace(Some(fd))}
+}
+
+impl Drop for DisconnectWatch {
+    fn drop(&mut self) {
+        WATCHED.set(self.previous);
+    }
+}
+
+/// Fails with ErrorTag::Cancelled if the connection watched by this thread has disconnected.
+/// Always passes when nothing is watched, as in tests and embedded use.
+pub fn check_cancelled() -> Result<(), EzError> {
+    match WATCHED.get() {
+        Some(fd) if peer_disconnected(fd) => Err(EzError{tag: ErrorTag::Cancelled, text: "The client disconnected. The rest of the request was cancelled".to_owned()}),
+        _ => Ok(()),
+    }
+}
+
+
+#[cfg(all(test, feature = "server", target_os = "linux"))]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::fd::AsRawFd;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn test_disconnect_detection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_side, _) = listener.accept().unwrap();
+        let fd = server_side.as_raw_fd();
+
+        assert!(check_cancelled().is_ok());
+        let watch = watch_connection(fd);
+        assert!(!peer_disconnected(fd));
+        assert!(check_cancelled().is_ok());
+        // A client that sent more than the job has read is still there
+        client.write_all(b"next request").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!peer_disconnected(fd));
+
+        drop(client);
+        let start = Instant::now();
+        while !peer_disconnected(fd) {
+            assert!(start.elapsed() < Duration::from_secs(5), "The disconnect was never seen");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(check_cancelled().unwrap_err().tag, ErrorTag::Cancelled);
+
+        // Only the thread that set up the watch is affected, and only until it is dropped
+        assert!(std::thread::spawn(check_cancelled).join().unwrap().is_ok());
+        drop(watch);
+        assert!(check_cancelled().is_ok());
+    }
+}
@@ -0,0 +1,212 @@
+//! The commands of ezdb-cli, the admin tool that talks to a running server with the client protocol.
+//! Lines starting with a dot are tool commands. Everything else is EZQL text and is sent as one batch:
+//!     .tables                                 the ez_tables system table
+//!     .describe <table>                       the columns of a table
+//!     .import <table> <file.csv>              creates the table from a CSV file. See ColumnTable::from_csv_string()
+//!     .export <table> <file.csv>              writes the whole table to a CSV file that .import reads back
+//!     .users                                  the ez_users system table
+//!     .user add <username> <password> [admin]
+//!     .user remove <username>
+//!     .user password <username> <password>
+//!     .stats                                  the server metrics
+//!     .health                                 whether the server is ready
+//!     .help                                   this list
+//!     .quit
+//! The user commands and .stats need an admin. See server_networking::perform_administration().
+
+use crate::client_networking::{check_health, describe_table, fetch_metrics, send_admin_command, send_bulk_load, send_queries_outcome, send_query, send_write_queries};
+use crate::db_structure::ColumnTable;
+use crate::ezql::{is_write_batch, parse_EZQL, Query};
+use crate::protocol::QueryOutcome;
+use crate::transport::Transport;
+use crate::utilities::{ErrorTag, EzError, KeyString};
+
+
+pub const CLI_HELP: &str = "\
+.tables                                  list the tables
+.describe <table>                        show the columns of a table
+.import <table> <file.csv>               create a table from a CSV file
+.export <table> <file.csv>               write a table to a CSV file
+.users                                   list the users
+.user add <username> <password> [admin]  add a user
+.user remove <username>                  remove a user
+.user password <username> <password>     change the password of a user
+.stats                                   show the server metrics
+.health                                  show whether the server is ready
+.help                                    show this list
+.quit                                    leave
+Anything else is sent as EZQL. A query can span several lines.";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CliCommand {
+    Ezql(String),
+    Tables,
+    Describe{table_name: String},
+    Import{table_name: String, path: String},
+    Export{table_name: String, path: String},
+    Users,
+    UserAdd{username: String, password: String, admin: bool},
+    UserRemove{username: String},
+    UserPassword{username: String, password: String},
+    Stats,
+    Health,
+    Help,
+    Quit,
+}
+
+impl CliCommand {
+    pub fn parse(line: &str) -> Result<CliCommand, EzError> {
+        let line = line.trim();
+        if !line.starts_with('.') {
+            return Ok(CliCommand::Ezql(line.to_owned()))
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words.as_slice() {
+            [".tables"] => CliCommand::Tables,
+            [".describe", table_name] => CliCommand::Describe{table_name: table_name.to_string()},
+            [".import", table_name, path] => CliCommand::Import{table_name: table_name.to_string(), path: path.to_string()},
+            [".export", table_name, path] => CliCommand::Export{table_name: table_name.to_string(), path: path.to_string()},
+            [".users"] => CliCommand::Users,
+            [".user", "add", username, password] => CliCommand::UserAdd{username: username.to_string(), password: password.to_string(), admin: false},
+            [".user", "add", username, password, "admin"] => CliCommand::UserAdd{username: username.to_string(), password: password.to_string(), admin: true},
+            [".user", "remove", username] => CliCommand::UserRemove{username: username.to_string()},
+            [".user", "password", username, password] => CliCommand::UserPassword{username: username.to_string(), password: password.to_string()},
+            [".stats"] => CliCommand::Stats,
+            [".health"] => CliCommand::Health,
+            [".help"] => CliCommand::Help,
+            [".quit"] | [".exit"] => CliCommand::Quit,
+            _ => return Err(EzError{tag: ErrorTag::Instruction, text: format!("'{}' is not a command. Type .help for the list", line)}),
+        };
+        Ok(command)
+    }
+}
+
+/// Whether the text is a whole command. EZQL is complete once its parentheses are closed, so a query can be
+/// typed over several lines.
+pub fn is_complete(text: &str) -> bool {
+    let mut depth = 0i64;
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            _ => (),
+        }
+    }
+    depth <= 0 && !quoted
+}
+
+/// Runs the command and returns what to print. `username` is recorded as the creator of imported tables.
+pub fn run_command(connection: &mut Transport, command: &CliCommand, username: &str) -> Result<String, EzError> {
+    match command {
+        CliCommand::Ezql(text) => run_ezql(connection, text),
+        CliCommand::Tables => Ok(format_table(&send_query(connection, &Query::new_select("ez_tables"))?)),
+        CliCommand::Describe{table_name} => Ok(format_table(&describe_table(connection, table_name)?.to_table()?)),
+        CliCommand::Import{table_name, path} => {
+            let text = std::fs::read_to_string(path)?;
+            let table = ColumnTable::from_csv_string(&text, table_name, username)?;
+            let rows = table.len();
+            send_bulk_load(connection, table_name, &table)?;
+            Ok(format!("Imported {} rows into '{}'", rows, table_name))
+        },
+        CliCommand::Export{table_name, path} => {
+            let table = send_query(connection, &Query::new_select(table_name))?;
+            std::fs::write(path, table.to_string())?;
+            Ok(format!("Exported {} rows of '{}' to {}", table.len(), table_name, path))
+        },
+        CliCommand::Users => Ok(format_table(&send_query(connection, &Query::new_select("ez_users"))?)),
+        CliCommand::UserAdd{username, password, admin} => {
+            let mut args = KeyString::from_input(username)?.raw().to_vec();
+            args.push(*admin as u8);
+            args.extend_from_slice(password.as_bytes());
+            Ok(format_table(&send_admin_command(connection, "USER_ADD", &args)?))
+        },
+        CliCommand::UserRemove{username} => {
+            let args = KeyString::from_input(username)?.raw().to_vec();
+            Ok(format_table(&send_admin_command(connection, "USER_REMOVE", &args)?))
+        },
+        CliCommand::UserPassword{username, password} => {
+            let mut args = KeyString::from_input(username)?.raw().to_vec();
+            args.extend_from_slice(password.as_bytes());
+            Ok(format_table(&send_admin_command(connection, "USER_PASSWORD", &args)?))
+        },
+        CliCommand::Stats => fetch_metrics(connection),
+        CliCommand::Health => Ok(format_table(&check_health(connection)?)),
+        CliCommand::Help => Ok(CLI_HELP.to_owned()),
+        CliCommand::Quit => Ok(String::new()),
+    }
+}
+
+/// Write batches are answered with how many rows each query changed, everything else with the last result.
+fn run_ezql(connection: &mut Transport, text: &str) -> Result<String, EzError> {
+    let queries = parse_EZQL(text)?;
+    if is_write_batch(&queries) {
+        let ack = send_write_queries(connection, &queries)?.into_result()?;
+        return Ok(format!("{} rows affected", ack.total_affected()))
+    }
+    match send_queries_outcome(connection, &queries)? {
+        QueryOutcome::Rows(table) | QueryOutcome::Empty(table) => Ok(format_table(&table)),
+        QueryOutcome::Done => Ok("Done".to_owned()),
+        QueryOutcome::NotFound(e) => Err(e),
+    }
+}
+
+/// The table as aligned columns under their names, followed by the row count.
+pub fn format_table(table: &ColumnTable) -> String {
+    let text = table.to_string();
+    let mut rows: Vec<Vec<String>> = vec![table.header.iter().map(|item| item.name.to_string()).collect()];
+    for line in text.lines().skip(1) {
+        rows.push(line.split(';').map(|cell| cell.to_owned()).collect());
+    }
+    let mut widths = vec![0; rows[0].len()];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut printer = String::new();
+    for (index, row) in rows.iter().enumerate() {
+        let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+        printer.push_str(cells.join(" | ").trim_end());
+        printer.push('\n');
+        if index == 0 {
+            let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+            printer.push_str(&rule.join("-+-"));
+            printer.push('\n');
+        }
+    }
+    printer.push_str(&format!("({} rows)", table.len()));
+    printer
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::testing_tools::create_fixed_table;
+
+    use super::*;
+
+    #[test]
+    fn test_cli_commands() {
+        assert_eq!(CliCommand::parse("  .tables ").unwrap(), CliCommand::Tables);
+        assert_eq!(
+            CliCommand::parse(".user add bob secret admin").unwrap(),
+            CliCommand::UserAdd{username: "bob".to_owned(), password: "secret".to_owned(), admin: true},
+        );
+        assert_eq!(
+            CliCommand::parse(".import products products.csv").unwrap(),
+            CliCommand::Import{table_name: "products".to_owned(), path: "products.csv".to_owned()},
+        );
+        assert!(matches!(CliCommand::parse("SELECT(table_name: products)").unwrap(), CliCommand::Ezql(_)));
+        assert_eq!(CliCommand::parse(".user add bob").unwrap_err().tag, ErrorTag::Instruction);
+
+        assert!(!is_complete("SELECT(table_name: products,"));
+        assert!(is_complete("SELECT(table_name: products,\n conditions: ((name equals \")\")))"));
+
+        let formatted = format_table(&create_fixed_table(3));
+        assert_eq!(formatted.lines().count(), 6);
+        assert!(formatted.ends_with("(3 rows)"));
+    }
+}
@@ -1,10 +1,23 @@
+use std::ops::{Deref, DerefMut};
 use std::str::{self};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-use eznoise::{initiate_connection, Connection};
+use eznoise::initiate_connection;
+
+use crate::backup::{Backup, BackupSummary};
+use crate::db_structure::{ColumnTable, DbType, DbValue, TableSchema, Value};
+use crate::frame_checksum::{client_checksum, open_frame, seal_frame, set_client_checksum, FrameChecksum};
+use crate::schema_file::parse_schema;
+use crate::ezql::{Alteration, KeyList, KvQuery, Query, RangeOrListOrAll, ValueFilter, WriteAck};
+use crate::row_mapping::Row;
+use crate::protocol::{decode_ack, decode_blob_ref, decode_cursor_opened, decode_error, decode_kv_results, decode_page, decode_query_outcome, decode_table, Credentials, QueryOutcome, Request};
+use crate::transport::Transport;
+use crate::utilities::{ksf, KeyString, ErrorTag, EzError};
+
+/// Builds the queries the functions below send. See query_builder.rs.
+pub use crate::query_builder::{col, Delete, Filter, Select};
 
-use crate::db_structure::{ColumnTable, Metadata, Value};
-use crate::ezql::{KvQuery, Query};
-use crate::utilities::{ksf, kv_query_results_from_binary, KeyString, u64_from_le_slice, ErrorTag, EzError};
 // use crate::PATH_SEP;
 
 
@@ -14,21 +27,51 @@ pub enum Response {
 }
 
 
-pub fn make_connection(address: &str, username: &str, password: &str) -> Result<Connection, EzError> {
-    let mut connection = initiate_connection(address)?;
-    let mut auth_buffer = [0u8;1024];
-    if username.len() > 512 || password.len() > 512 {
-        return Err(EzError{ tag: ErrorTag::Authentication, text: "Username and password must each be less than 512 bytes".to_owned()})
-    }
-    auth_buffer[0..username.len()].copy_from_slice(username.as_bytes());
-    auth_buffer[512..512+password.len()].copy_from_slice(username.as_bytes());
-    
-    connection.SEND_C1(&auth_buffer)?;
-    println!("HERE!!!");
+pub fn make_connection(address: &str, username: &str, password: &str) -> Result<Transport, EzError> {
+    make_connection_with_checksum(address, username, password, FrameChecksum::None)
+}
+
+/// Connect and ask the server to put a checksum on every frame in both directions.
+/// Frames that fail the check are rejected before they are parsed and counted in the ez_health table.
+pub fn make_connection_with_checksum(address: &str, username: &str, password: &str, checksum: FrameChecksum) -> Result<Transport, EzError> {
+    authenticate(Transport::Noise(initiate_connection(address)?), username, password, checksum)
+}
+
+/// Connect to a server that was started with a TLS certificate. The certificate must be valid for server_name
+/// and signed by an authority in the config, see transport::load_client_config().
+#[cfg(feature = "tls")]
+pub fn make_tls_connection(
+    address: &str,
+    server_name: &str,
+    config: std::sync::Arc<rustls::ClientConfig>,
+    username: &str,
+    password: &str,
+) -> Result<Transport, EzError> {
+    let connection = crate::transport::TlsConnection::connect(address, server_name, config)?;
+    authenticate(Transport::Tls(Box::new(connection)), username, password, FrameChecksum::None)
+}
+
+/// Sends the credentials. The protocol is the same whatever the transport.
+fn authenticate(mut connection: Transport, username: &str, password: &str, checksum: FrameChecksum) -> Result<Transport, EzError> {
+    let credentials = Credentials{username: username.to_owned(), password: password.to_owned(), checksum};
+
+    connection.send_to_server(&credentials.encode()?)?;
+    set_client_checksum(connection.connection_id(), checksum);
 
     Ok(connection)
 }
 
+/// Sends one request and waits for its response. Error responses come back as the EzError the server sent.
+fn send_request(connection: &mut Transport, request: &Request) -> Result<Vec<u8>, EzError> {
+    let checksum = client_checksum(connection.connection_id());
+    connection.send_to_server(&seal_frame(request.encode(), checksum))?;
+    let response = open_frame(connection.receive_from_server()?, checksum)?;
+    match decode_error(&response) {
+        Some(e) => Err(e),
+        None => Ok(response),
+    }
+}
+
 /// Send an EZQL query to the database server
 pub fn oneshot_query(
     address: &str,
@@ -42,39 +85,625 @@ pub fn oneshot_query(
     send_query(&mut connection, query)
 }
 
-pub fn send_query(connection: &mut Connection, query: &Query) -> Result<ColumnTable, EzError> {
+pub fn send_query(connection: &mut Transport, query: &Query) -> Result<ColumnTable, EzError> {
+
+    let response = send_request(connection, &Request::Query(vec![query.clone()]))?;
+
+    decode_table(&response)
+}
+
+/// Like send_query() but tells a query that matched no rows apart from one that names a table, column or key
+/// that doesn't exist. Other failures are still errors.
+pub fn send_query_outcome(connection: &mut Transport, query: &Query) -> Result<QueryOutcome, EzError> {
+
+    match send_request(connection, &Request::Query(vec![query.clone()])) {
+        Ok(response) => decode_query_outcome(&response),
+        Err(e) => QueryOutcome::from_result(Err(e)),
+    }
+}
+
+/// Sends a chain of queries as one batch, as parse_EZQL() returns them. The outcome is that of the last query.
+/// Batches of only writes are answered with a WriteAck so send those with send_write_queries() instead.
+pub fn send_queries_outcome(connection: &mut Transport, queries: &[Query]) -> Result<QueryOutcome, EzError> {
+
+    match send_request(connection, &Request::Query(queries.to_vec())) {
+        Ok(response) => decode_query_outcome(&response),
+        Err(e) => QueryOutcome::from_result(Err(e)),
+    }
+}
+
+/// Keeps the query on the server and returns its handle for execute_prepared(). Write the parameters as $1, $2, ...
+/// in place of primary keys and condition or update values. The handle only works on this connection.
+pub fn prepare_query(connection: &mut Transport, query: &Query) -> Result<u64, EzError> {
+
+    let response = send_query(connection, &Query::PREPARE{query: Box::new(query.clone())})?;
+    match response.get_column_int(&ksf("handle"))?.first() {
+        Some(handle) => Ok(*handle as u64),
+        None => Err(EzError{tag: ErrorTag::ParseResponse, text: "The server did not answer with a handle".to_owned()}),
+    }
+}
+
+/// Runs a query prepared with prepare_query() with the given parameter values.
+/// Prepared writes are answered with a WriteAck so send their Query::EXECUTE with send_write_queries() instead.
+pub fn execute_prepared(connection: &mut Transport, handle: u64, params: &[DbValue]) -> Result<ColumnTable, EzError> {
+    send_query(connection, &Query::EXECUTE{handle, params: params.to_vec()})
+}
+
+/// Send a batch of write queries. The server answers with a status and an affected row count per query.
+/// A failed query doesn't make this return an error. Check the WriteAck or call WriteAck::into_result().
+pub fn send_write_queries(connection: &mut Transport, queries: &[Query]) -> Result<WriteAck, EzError> {
+
+    let response = send_request(connection, &Request::Query(queries.to_vec()))?;
+
+    WriteAck::from_binary(&response)
+}
+
+/// Selects the rows with the given primary keys. The keys go over the wire packed in the type of the primary key
+/// column and are looked up with binary searches, so a long key list costs no string conversions on either end.
+pub fn query_keys(connection: &mut Transport, table_name: &str, keys: KeyList) -> Result<ColumnTable, EzError> {
+
+    let query = Query::SELECT {
+        table_name: KeyString::from_input(table_name)?,
+        primary_keys: RangeOrListOrAll::Keys(keys),
+        columns: vec![ksf("*")],
+        conditions: Vec::new(),
+        distinct: false,
+        limit: None,
+    };
+
+    send_query(connection, &query)
+}
+
+/// Deletes the rows with the given primary keys. Returns how many rows were deleted. See query_keys().
+pub fn delete_keys(connection: &mut Transport, table_name: &str, keys: KeyList) -> Result<u64, EzError> {
+
+    let query = Query::DELETE {
+        table_name: KeyString::from_input(table_name)?,
+        primary_keys: RangeOrListOrAll::Keys(keys),
+        conditions: Vec::new(),
+    };
+
+    Ok(send_write_queries(connection, &[query])?.into_result()?.total_affected())
+}
+
+/// Changes the columns of a table. Returns how many rows the table has. See Alteration.
+pub fn alter_table(connection: &mut Transport, table_name: &str, alteration: Alteration) -> Result<u64, EzError> {
+
+    let query = Query::ALTER_TABLE { table_name: KeyString::from_input(table_name)?, alteration };
+
+    Ok(send_write_queries(connection, &[query])?.into_result()?.total_affected())
+}
+
+/// Creates every table of the schema that the server does not have yet. Returns how many were created.
+/// See schema_file.rs for the format.
+pub fn create_from_schema(connection: &mut Transport, schema: &str) -> Result<u64, EzError> {
+
+    let query = Query::CREATE_FROM_SCHEMA { tables: parse_schema(schema)? };
+
+    Ok(send_write_queries(connection, &[query])?.into_result()?.total_affected())
+}
+
+/// Send a query attributed to the given tag, such as a job id, instead of the connection tag.
+pub fn send_tagged_query(connection: &mut Transport, tag: &str, query: &Query) -> Result<ColumnTable, EzError> {
+
+    let request = Request::TaggedQuery{tag: KeyString::from_input(tag)?, queries: vec![query.clone()]};
+    let response = send_request(connection, &request)?;
+
+    decode_table(&response)
+}
+
+/// Send a query that is only answered once the server has applied the given write version, the version in the
+/// WriteAck of an earlier write. Servers that don't get there in time answer with ErrorTag::Unavailable.
+pub fn send_query_at_version(connection: &mut Transport, query: &Query, version: u64) -> Result<ColumnTable, EzError> {
+
+    let response = send_request(connection, &Request::AtVersion{version, queries: vec![query.clone()]})?;
+
+    decode_table(&response)
+}
+
+/// Tag everything sent on this connection from now on, for example with the name of the calling service.
+/// Operators can see the load per tag in the ez_tags system table. An empty tag clears it.
+pub fn set_session_tag(connection: &mut Transport, tag: &str) -> Result<(), EzError> {
 
-    let query = query.to_binary();
-    let mut packet = Vec::new();
-    packet.extend_from_slice(KeyString::from("QUERY").raw());
-    packet.extend_from_slice(&query);
-    connection.SEND_C1(&packet)?;
-    
-    let response = connection.RECEIVE_C2()?;
+    let response = send_request(connection, &Request::Tag(KeyString::from_input(tag)?))?;
 
-    match ColumnTable::from_binary(Some("RESULT"), &response) {
-        Ok(table) => Ok(table),
+    decode_ack(&response)
+}
+
+pub fn send_kv_queries(connection: &mut Transport, queries: &[KvQuery]) -> Result<Vec<Result<Option<Value>, EzError>>, EzError> {
+
+    let response = send_request(connection, &Request::KvQuery(queries.to_vec()))?;
+
+    decode_kv_results(&response)
+}
+
+/// Sends KV creates, updates and deletes that are applied all together or not at all.
+/// There is one result per query. If any of them failed, none of them were applied.
+pub fn send_kv_batch(connection: &mut Transport, queries: &[KvQuery]) -> Result<Vec<Result<Option<Value>, EzError>>, EzError> {
+
+    let response = send_request(connection, &Request::KvBatch(queries.to_vec()))?;
+
+    decode_kv_results(&response)
+}
+
+/// Reads many values in one round trip. One result per key, in order.
+pub fn read_many(connection: &mut Transport, keys: &[KeyString]) -> Result<Vec<Result<Value, EzError>>, EzError> {
+
+    let results = send_kv_queries(connection, &[KvQuery::ReadMany(keys.to_vec())])?;
+
+    Ok(results.into_iter().map(|result| match result {
+        Ok(Some(value)) => Ok(value),
+        Ok(None) => Err(EzError{tag: ErrorTag::Query, text: "The server answered a read without a value".to_owned()}),
         Err(e) => Err(e),
+    }).collect())
+}
+
+/// Creates or replaces many values in one round trip. One result per pair, in order.
+pub fn write_many(connection: &mut Transport, pairs: &[(KeyString, Vec<u8>)]) -> Result<Vec<Result<(), EzError>>, EzError> {
+
+    let results = send_kv_queries(connection, &[KvQuery::WriteMany(pairs.to_vec())])?;
+
+    Ok(results.into_iter().map(|result| result.map(|_| ())).collect())
+}
+
+/// The values whose keys start with the prefix and that pass the filter, in key order. The filter runs on the
+/// server so values that don't match are never sent.
+pub fn scan_values(connection: &mut Transport, prefix: &str, filter: &ValueFilter) -> Result<Vec<Value>, EzError> {
+
+    let results = send_kv_queries(connection, &[KvQuery::Scan(KeyString::from_str_checked(prefix)?, filter.clone())])?;
+
+    results.into_iter().filter_map(|result| result.transpose()).collect()
+}
+
+/// Upload a table in the EZ binary column layout without going through csv.
+/// The table is validated and sorted on the server so the columns can be in any order.
+pub fn send_bulk_load(connection: &mut Transport, table_name: &str, table: &ColumnTable) -> Result<(), EzError> {
+
+    send_bulk_load_binary(connection, table_name, &table.to_binary())
+}
+
+/// Upload a table that is already in the EZ binary column layout. See ColumnTable::to_binary() for the layout.
+pub fn send_bulk_load_binary(connection: &mut Transport, table_name: &str, binary: &[u8]) -> Result<(), EzError> {
+
+    let request = Request::BulkLoad{table_name: KeyString::from_input(table_name)?, table: binary.to_vec()};
+    let response = send_request(connection, &request)?;
+
+    decode_ack(&response)
+}
+
+/// Upload an attachment. Returns the value to put in a Text cell to link a row to it.
+/// Uploading the same content twice returns the same reference and stores it only once.
+pub fn upload_blob(connection: &mut Transport, content: &[u8]) -> Result<KeyString, EzError> {
+
+    let response = send_request(connection, &Request::BlobPut(content.to_vec()))?;
+
+    decode_blob_ref(&response)
+}
+
+/// Download the attachment a Text cell refers to.
+pub fn download_blob(connection: &mut Transport, reference: &KeyString) -> Result<Vec<u8>, EzError> {
+
+    send_request(connection, &Request::BlobGet(*reference))
+}
+
+/// Ask the server whether it is ready. Answers even while the server is still recovering after a restart.
+/// Returns the single row ez_health table with the recovery progress and a retry hint in seconds.
+pub fn check_health(connection: &mut Transport) -> Result<ColumnTable, EzError> {
+
+    let response = send_request(connection, &Request::Health)?;
+
+    decode_table(&response)
+}
+
+/// Send an administrative command to the server. See server_networking::perform_administration() for the available commands.
+/// The task commands all respond with the current list of background tasks.
+pub fn send_admin_command(connection: &mut Transport, command: &str, args: &[u8]) -> Result<ColumnTable, EzError> {
+
+    let response = send_request(connection, &Request::Admin{command: ksf(command), args: args.to_vec()})?;
+
+    decode_table(&response)
+}
+
+/// The server's query counts, latencies, buffer pool and connection metrics in the Prometheus text format.
+/// Admins only.
+pub fn fetch_metrics(connection: &mut Transport) -> Result<String, EzError> {
+
+    let response = send_request(connection, &Request::Admin{command: ksf("METRICS"), args: Vec::new()})?;
+
+    Ok(String::from_utf8(response)?)
+}
+
+/// Takes a consistent backup of every table, value, user and the metadata while the server keeps
+/// accepting writes, and writes the archive to `path` on this machine. Admins only.
+/// Start the server with --restore=<archive> to load it.
+pub fn backup_database(connection: &mut Transport, path: &std::path::Path) -> Result<BackupSummary, EzError> {
+
+    let response = send_request(connection, &Request::Admin{command: ksf("BACKUP"), args: Vec::new()})?;
+    let summary = Backup::from_binary(&response)?.summary()?;
+    std::fs::write(path, &response)?;
+
+    Ok(summary)
+}
+
+
+/// Runs the batch on the server and keeps the result there to be fetched a page at a time.
+/// Returns the cursor id and the number of rows in the result. See query_cursor() for an iterator over the pages.
+pub fn open_cursor(connection: &mut Transport, queries: &[Query]) -> Result<(u64, u64), EzError> {
+
+    let response = send_request(connection, &Request::OpenCursor(queries.to_vec()))?;
+
+    decode_cursor_opened(&response)
+}
+
+/// The next page of at most max_rows rows and how many rows are left after it. The server closes the cursor
+/// after its last page. A max_rows of 0 uses the server's default page size.
+pub fn fetch_page(connection: &mut Transport, cursor_id: u64, max_rows: u64) -> Result<(ColumnTable, u64), EzError> {
+
+    let response = send_request(connection, &Request::FetchPage{cursor_id, max_rows})?;
+    match decode_page(&response) {
+        Ok(page) => Ok(page),
+        Err(_) => Err(EzError{tag: ErrorTag::Query, text: String::from_utf8_lossy(&response).to_string()}),
+    }
+}
+
+pub fn close_cursor(connection: &mut Transport, cursor_id: u64) -> Result<(), EzError> {
+
+    let response = send_request(connection, &Request::CloseCursor{cursor_id})?;
+
+    decode_ack(&response)
+}
+
+/// The pages of a query result held on the server. Each item is a table of at most page_rows rows.
+/// Dropping the iterator before the last page closes the cursor on the server.
+pub struct QueryCursor<'a> {
+    connection: &'a mut Transport,
+    pub cursor_id: u64,
+    /// Rows in the whole result.
+    pub rows: u64,
+    page_rows: u64,
+    finished: bool,
+}
+
+impl Iterator for QueryCursor<'_> {
+    type Item = Result<ColumnTable, EzError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None
+        }
+        match fetch_page(self.connection, self.cursor_id, self.page_rows) {
+            Ok((page, remaining)) => {
+                self.finished = remaining == 0;
+                Some(Ok(page))
+            },
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+impl Drop for QueryCursor<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = close_cursor(self.connection, self.cursor_id);
+        }
     }
 }
 
-pub fn send_kv_queries(connection: &mut Connection, queries: &[KvQuery]) -> Result<Vec<Result<Option<Value>, EzError>>, EzError> {
+/// Runs the query and iterates over its result in pages of page_rows rows instead of receiving it in one message.
+pub fn query_cursor<'a>(connection: &'a mut Transport, query: &Query, page_rows: u64) -> Result<QueryCursor<'a>, EzError> {
+
+    let (cursor_id, rows) = open_cursor(connection, std::slice::from_ref(query))?;
+
+    Ok(QueryCursor{connection, cursor_id, rows, page_rows, finished: false})
+}
 
-    let mut packet = Vec::new();
-    packet.extend_from_slice(ksf("KVQUERY").raw());
-    for query in queries {
-        packet.extend_from_slice(&query.to_binary());
+/// A session that writes to one server and reads from another without missing its own writes. Every write
+/// raises the session's write version to the one in its WriteAck and every read asks for at least that
+/// version. Reads the other server can't answer in time go to the server written to. See write_versions.rs
+pub struct ReadYourWrites {
+    pub primary: Transport,
+    pub replica: Option<Transport>,
+    /// The highest write version this session was given.
+    pub version: u64,
+}
+
+impl ReadYourWrites {
+    pub fn new(primary: Transport, replica: Option<Transport>) -> ReadYourWrites {
+        ReadYourWrites{primary, replica, version: 0}
     }
 
-    connection.SEND_C1(&packet)?;
+    /// Sends the batch to the primary. See send_write_queries().
+    pub fn write(&mut self, queries: &[Query]) -> Result<WriteAck, EzError> {
+        let ack = send_write_queries(&mut self.primary, queries)?;
+        self.version = self.version.max(ack.version);
+        Ok(ack)
+    }
 
-    let response = connection.RECEIVE_C2()?;
+    /// Sends the query to the replica if there is one, and to the primary if there isn't or it is behind.
+    pub fn read(&mut self, query: &Query) -> Result<ColumnTable, EzError> {
+        if let Some(replica) = self.replica.as_mut() {
+            match send_query_at_version(replica, query, self.version) {
+                Err(e) if e.tag == ErrorTag::Unavailable => (),
+                result => return result,
+            }
+        }
+        send_query_at_version(&mut self.primary, query, self.version)
+    }
+}
 
-    let results = kv_query_results_from_binary(&response)?;
 
-    
+/// Settings for a ClientPool.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// The most connections the pool keeps open at once.
+    pub size: usize,
+    pub checksum: FrameChecksum,
+    /// How long a single read or write on a connection may block. None blocks until the server answers.
+    pub io_timeout: Option<Duration>,
+    /// How long ClientPool::get() waits for a connection to be returned when all of them are in use.
+    pub checkout_timeout: Duration,
+    /// How many times ClientPool::run() retries on a fresh connection after an io error.
+    pub retries: usize,
+}
 
-    Ok(results)
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 4,
+            checksum: FrameChecksum::None,
+            io_timeout: Some(Duration::from_secs(30)),
+            checkout_timeout: Duration::from_secs(10),
+            retries: 1,
+        }
+    }
+}
+
+struct PoolState {
+    idle: Vec<Transport>,
+    /// Idle connections plus the ones handed out.
+    open: usize,
+}
+
+/// A fixed number of authenticated connections to one server that are shared between threads.
+/// The handshake is done once per connection instead of once per call. Connections the server has closed
+/// are noticed when they are handed out and replaced with new ones.
+pub struct ClientPool {
+    address: String,
+    username: String,
+    password: String,
+    config: PoolConfig,
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+impl ClientPool {
+    /// Opens every connection up front so a wrong address or password fails here and not on first use.
+    pub fn new(address: &str, username: &str, password: &str, config: PoolConfig) -> Result<ClientPool, EzError> {
+        if config.size == 0 {
+            return Err(EzError{tag: ErrorTag::Structure, text: "A connection pool needs room for at least one connection".to_owned()})
+        }
+        let pool = ClientPool {
+            address: address.to_owned(),
+            username: username.to_owned(),
+            password: password.to_owned(),
+            config,
+            state: Mutex::new(PoolState{idle: Vec::new(), open: 0}),
+            returned: Condvar::new(),
+        };
+
+        let mut idle = Vec::with_capacity(pool.config.size);
+        for _ in 0..pool.config.size {
+            idle.push(pool.connect()?);
+        }
+        let open = idle.len();
+        *pool.state.lock().unwrap() = PoolState{idle, open};
+
+        Ok(pool)
+    }
+
+    fn connect(&self) -> Result<Transport, EzError> {
+        let connection = make_connection_with_checksum(&self.address, &self.username, &self.password, self.config.checksum)?;
+        connection.stream().set_read_timeout(self.config.io_timeout)?;
+        connection.stream().set_write_timeout(self.config.io_timeout)?;
+        Ok(connection)
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    /// How many connections are waiting to be handed out.
+    pub fn idle_count(&self) -> usize {
+        self.state.lock().unwrap().idle.len()
+    }
+
+    /// Hands out a connection, waiting up to the checkout timeout for one to be returned if they are all in use.
+    /// The connection goes back to the pool when the PooledConnection is dropped.
+    pub fn get(&self) -> Result<PooledConnection<'_>, EzError> {
+        let deadline = Instant::now() + self.config.checkout_timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(connection) = state.idle.pop() {
+                drop(state);
+                if connection_is_closed(&connection) {
+                    // The server hung up while the connection was idle
+                    state = self.state.lock().unwrap();
+                    state.open -= 1;
+                    continue
+                }
+                return Ok(PooledConnection{pool: self, connection: Some(connection), broken: false})
+            }
+            if state.open < self.config.size {
+                state.open += 1;
+                drop(state);
+                return match self.connect() {
+                    Ok(connection) => Ok(PooledConnection{pool: self, connection: Some(connection), broken: false}),
+                    Err(e) => {
+                        self.state.lock().unwrap().open -= 1;
+                        self.returned.notify_one();
+                        Err(e)
+                    },
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(EzError{tag: ErrorTag::Unavailable, text: format!("All {} connections to {} were busy for {:?}", self.config.size, self.address, self.config.checkout_timeout)})
+            }
+            state = self.returned.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Runs the call on a pooled connection. If it fails with an io error, such as a broken pipe or a timeout,
+    /// the connection is thrown away and the call is retried on a new one up to PoolConfig::retries times.
+    /// A write that reached the server before the connection broke may be applied twice, so set retries to 0
+    /// for pools that send writes which can't be repeated.
+    pub fn run<T, F>(&self, mut call: F) -> Result<T, EzError>
+    where F: FnMut(&mut Transport) -> Result<T, EzError> {
+        let mut attempts = 0;
+        loop {
+            let mut connection = self.get()?;
+            match call(&mut connection) {
+                Err(e) if e.tag == ErrorTag::Io => {
+                    connection.mark_broken();
+                    if attempts >= self.config.retries {
+                        return Err(e)
+                    }
+                    attempts += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    pub fn send_query(&self, query: &Query) -> Result<ColumnTable, EzError> {
+        self.run(|connection| send_query(connection, query))
+    }
+
+    pub fn send_write_queries(&self, queries: &[Query]) -> Result<WriteAck, EzError> {
+        self.run(|connection| send_write_queries(connection, queries))
+    }
+
+    pub fn send_kv_queries(&self, queries: &[KvQuery]) -> Result<Vec<Result<Option<Value>, EzError>>, EzError> {
+        self.run(|connection| send_kv_queries(connection, queries))
+    }
+
+    pub fn send_kv_batch(&self, queries: &[KvQuery]) -> Result<Vec<Result<Option<Value>, EzError>>, EzError> {
+        self.run(|connection| send_kv_batch(connection, queries))
+    }
+
+    pub fn read_many(&self, keys: &[KeyString]) -> Result<Vec<Result<Value, EzError>>, EzError> {
+        self.run(|connection| read_many(connection, keys))
+    }
+
+    pub fn write_many(&self, pairs: &[(KeyString, Vec<u8>)]) -> Result<Vec<Result<(), EzError>>, EzError> {
+        self.run(|connection| write_many(connection, pairs))
+    }
+}
+
+/// Whether the server has closed the connection. Only valid between requests when no answer is expected.
+fn connection_is_closed(connection: &Transport) -> bool {
+    if connection.stream().set_nonblocking(true).is_err() {
+        return true
+    }
+    let mut byte = [0u8];
+    let closed = match connection.stream().peek(&mut byte) {
+        // Either the server hung up or it sent something nobody asked for. Neither connection can be used.
+        Ok(_) => true,
+        Err(e) => e.kind() != std::io::ErrorKind::WouldBlock,
+    };
+    closed || connection.stream().set_nonblocking(false).is_err()
+}
+
+/// A connection borrowed from a ClientPool. Derefs to the Transport so the functions in this module take it directly.
+pub struct PooledConnection<'a> {
+    pool: &'a ClientPool,
+    connection: Option<Transport>,
+    broken: bool,
+}
+
+impl PooledConnection<'_> {
+    /// Closes the connection instead of returning it to the pool. Call this after an error that may have
+    /// left half a message on the stream.
+    pub fn mark_broken(&mut self) {
+        self.broken = true;
+    }
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Transport;
+
+    fn deref(&self) -> &Transport {
+        self.connection.as_ref().expect("The connection is only taken when dropped")
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Transport {
+        self.connection.as_mut().expect("The connection is only taken when dropped")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        let mut state = self.pool.state.lock().unwrap();
+        match self.connection.take() {
+            Some(connection) if !self.broken => state.idle.push(connection),
+            _ => state.open -= 1,
+        }
+        drop(state);
+        self.pool.returned.notify_one();
+    }
+}
+
+
+/// A struct that rows of a table can be read into, one field per column.
+pub trait FromRow: Sized {
+    /// The column each field is read from and the type the field expects.
+    fn fields() -> Vec<(KeyString, DbType)>;
+
+    /// Reads row `index`. Only called on tables whose schema passed TableSchema::check_mapping(Self::fields()).
+    fn from_row(table: &ColumnTable, index: usize) -> Result<Self, EzError>;
+}
+
+/// Ask the server for the schema of a table without fetching any of its rows.
+pub fn describe_table(connection: &mut Transport, table_name: &str) -> Result<TableSchema, EzError> {
+
+    let table_name = KeyString::from_input(table_name)?;
+    let description = send_query(connection, &Query::DESCRIBE{table_name})?;
+
+    TableSchema::from_table(table_name, &description)
+}
+
+/// What the server supports: query types, condition tests, update operators, statistics and limits. See help::help_table().
+pub fn server_help(connection: &mut Transport) -> Result<ColumnTable, EzError> {
+    send_query(connection, &Query::HELP)
+}
+
+/// Converts every row of a result table into T after checking that T fits the schema.
+pub fn rows_from_table<T: FromRow>(schema: &TableSchema, table: &ColumnTable) -> Result<Vec<T>, EzError> {
+
+    schema.check_mapping(&T::fields())?;
+    (0..table.len()).map(|index| T::from_row(table, index)).collect()
+}
+
+/// Runs a query and reads the result into T. The mapping is checked against the DESCRIBE result of the
+/// queried table first so a struct that doesn't fit fails before any data is pulled.
+pub fn query_typed<T: FromRow>(connection: &mut Transport, query: &Query) -> Result<Vec<T>, EzError> {
+
+    let schema = describe_table(connection, query.get_table_name().as_str())?;
+    schema.check_mapping(&T::fields())?;
+    let table = send_query(connection, query)?;
+
+    rows_from_table(&table.schema(), &table)
+}
+
+/// Runs a query and reads the result into a Row type. Unlike query_typed() the mapping is only checked
+/// against the result, which saves the DESCRIBE round trip.
+pub fn query_rows<T: Row>(connection: &mut Transport, query: &Query) -> Result<Vec<T>, EzError> {
+    send_query(connection, query)?.rows_as()
 }
 
 
@@ -83,7 +712,7 @@ mod tests {
     #![allow(unused, non_snake_case)]
     use std::{fs::remove_file, path::Path, time::Duration};
 
-    use crate::{db_structure::{ColumnTable, DbValue}, ezql::{Condition, RangeOrListOrAll, Test, TestOp}, testing_tools::random_column_table, utilities::ksf};
+    use crate::{db_structure::{ColumnTable, DbValue}, ezql::{Condition, RangeOrListOrAll, Test, TestOp}, row_table::TableEngine, testing_tools::random_column_table, utilities::ksf};
 
     use super::*;
 
@@ -109,7 +738,9 @@ mod tests {
             table_name: ksf("good_table"),
             primary_keys: RangeOrListOrAll::All,
             columns: vec![ksf("id"), ksf("name"), ksf("price")],
-            conditions: Vec::new() 
+            conditions: Vec::new(),
+            distinct: false,
+            limit: None, 
         };
 
         let response = oneshot_query(address, username, password, &query).unwrap();
@@ -123,6 +754,7 @@ mod tests {
         let password = "admin";
         let query = Query::CREATE {
             table: random_column_table(5, 1000),
+            engine: TableEngine::Column,
         };
 
         let response = oneshot_query(address, username, password, &query).unwrap();
@@ -138,7 +770,9 @@ mod tests {
             table_name: ksf("good_table"),
             primary_keys: RangeOrListOrAll::All,
             columns: vec![ksf("id"), ksf("name"), ksf("price")],
-            conditions: Vec::new() 
+            conditions: Vec::new(),
+            distinct: false,
+            limit: None, 
         };
 
         let mut connection = make_connection(address, username, password).unwrap();
@@ -152,6 +786,53 @@ mod tests {
         assert_eq!(response1, response2);
     }
 
+    #[test]
+    fn test_query_cursor() {
+        let address = "127.0.0.1:3004";
+        let mut connection = make_connection(address, "admin", "admin").unwrap();
+        let query = Query::SELECT {
+            table_name: ksf("good_table"),
+            primary_keys: RangeOrListOrAll::All,
+            columns: vec![ksf("*")],
+            conditions: Vec::new(),
+            distinct: false,
+            limit: None,
+        };
+
+        let whole = send_query(&mut connection, &query).unwrap();
+        let cursor = query_cursor(&mut connection, &query, 2).unwrap();
+        assert_eq!(cursor.rows as usize, whole.len());
+        let mut rows = 0;
+        for page in cursor {
+            let page = page.unwrap();
+            assert!(page.len() <= 2);
+            rows += page.len();
+        }
+        assert_eq!(rows, whole.len());
+    }
+
+    #[test]
+    fn test_client_pool() {
+        let address = "127.0.0.1:3004";
+        let config = PoolConfig{size: 2, checkout_timeout: Duration::from_millis(100), ..Default::default()};
+        let pool = ClientPool::new(address, "admin", "admin", config).unwrap();
+        assert_eq!(pool.idle_count(), 2);
+
+        let first = pool.get().unwrap();
+        let mut second = pool.get().unwrap();
+        assert_eq!(pool.get().err().unwrap().tag, ErrorTag::Unavailable);
+
+        // A broken connection is closed and replaced on the next checkout
+        second.mark_broken();
+        drop(second);
+        drop(first);
+        assert_eq!(pool.idle_count(), 1);
+        let _third = pool.get().unwrap();
+        let _fourth = pool.get().unwrap();
+
+        assert!(ClientPool::new("127.0.0.1:1", "admin", "admin", PoolConfig::default()).is_err());
+    }
+
     #[test]
     fn test_kv_query() {
         let address = "127.0.0.1:3004";
@@ -172,5 +853,34 @@ mod tests {
         }
     }
 
+    #[derive(Debug, PartialEq)]
+    struct Tool {
+        id: i32,
+        name: KeyString,
+    }
+
+    impl FromRow for Tool {
+        fn fields() -> Vec<(KeyString, DbType)> {
+            vec![(ksf("id"), DbType::Int), (ksf("name"), DbType::Text)]
+        }
+
+        fn from_row(table: &ColumnTable, index: usize) -> Result<Self, EzError> {
+            Ok(Tool {
+                id: table.get_column_int(&ksf("id"))?[index],
+                name: table.get_column_text(&ksf("name"))?[index],
+            })
+        }
+    }
+
+    #[test]
+    fn test_rows_from_table() {
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;saw\n2;drill", "tools", "test").unwrap();
+        let tools: Vec<Tool> = rows_from_table(&table.schema(), &table).unwrap();
+        assert_eq!(tools, vec![Tool{id: 1, name: ksf("saw")}, Tool{id: 2, name: ksf("drill")}]);
+
+        let wrong = ColumnTable::from_csv_string("id,t-P;name,t-N\na;saw", "tools", "test").unwrap();
+        assert!(rows_from_table::<Tool>(&wrong.schema(), &wrong).is_err());
+    }
+
 
 }
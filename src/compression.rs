@@ -1,10 +1,12 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use miniz_oxide::deflate::compress_to_vec;
 use miniz_oxide::inflate::decompress_to_vec;
 // use brotli::{CompressorReader, Decompressor};
 
-use crate::utilities::{usize_from_le_slice, ErrorTag, EzError};
-#[allow(unused)]
-use crate::PATH_SEP;
+use crate::db_structure::{DbColumn, DbType, LongTexts};
+use crate::utilities::{f32_from_le_slice, i32_from_le_slice, i64_from_le_slice, u64_from_le_slice, usize_from_le_slice, ErrorTag, EzError, KeyString};
 
 
 // // Function to compress data
@@ -78,19 +80,285 @@ pub fn miniz_decompress(data: &[u8]) -> Result<Vec<u8>, EzError> {
 }
 
 
+/// Whether table files are written with compressed columns. On by default. Compressed files can still be
+/// mapped by MappedTable but are decoded into memory first instead of being read in place.
+static TABLE_COMPRESSION: AtomicBool = AtomicBool::new(true);
+
+pub fn set_table_compression(on: bool) {
+    TABLE_COMPRESSION.store(on, Ordering::Relaxed);
+}
+
+pub fn table_compression() -> bool {
+    TABLE_COMPRESSION.load(Ordering::Relaxed)
+}
+
+/// How a column is stored in a compressed table file. The writer tries every encoding that fits the
+/// column type and keeps the smallest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColumnEncoding {
+    /// The same bytes as an uncompressed table.
+    Raw,
+    /// Texts only. Each distinct text once, then a u32 index per row.
+    Dictionary,
+    /// The raw bytes compressed with miniz.
+    Lz,
+    /// Ints and durations only. The difference to the previous value as a zigzag varint. Sorted columns
+    /// like primary keys and row ids shrink to about a byte per row.
+    Delta,
+}
+
+impl ColumnEncoding {
+    fn tag(&self) -> u8 {
+        match self {
+            ColumnEncoding::Raw => 0,
+            ColumnEncoding::Dictionary => 1,
+            ColumnEncoding::Lz => 2,
+            ColumnEncoding::Delta => 3,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<ColumnEncoding, EzError> {
+        match tag {
+            0 => Ok(ColumnEncoding::Raw),
+            1 => Ok(ColumnEncoding::Dictionary),
+            2 => Ok(ColumnEncoding::Lz),
+            3 => Ok(ColumnEncoding::Delta),
+            other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unknown column encoding: {}", other)}),
+        }
+    }
+}
+
+fn column_corrupt(text: &str) -> EzError {
+    EzError{tag: ErrorTag::Deserialization, text: format!("Compressed column is corrupt: {}", text)}
+}
+
+/// The column as it is laid out in an uncompressed table binary.
+pub fn raw_column_bytes(column: &DbColumn) -> Vec<u8> {
+    let mut binary = Vec::new();
+    match column {
+        DbColumn::Ints(col) => col.iter().for_each(|item| binary.extend_from_slice(&item.to_le_bytes())),
+        DbColumn::Floats(col) => col.iter().for_each(|item| binary.extend_from_slice(&item.to_le_bytes())),
+        DbColumn::Texts(col) => col.iter().for_each(|item| binary.extend_from_slice(item.raw())),
+        DbColumn::Durations(col) => col.iter().for_each(|item| binary.extend_from_slice(&item.to_le_bytes())),
+        DbColumn::LongTexts(col) => col.write_binary(&mut binary),
+    }
+    binary
+}
+
+fn raw_column_from_bytes(kind: DbType, rows: usize, binary: &[u8]) -> Result<DbColumn, EzError> {
+    let size = match kind {
+        DbType::Int | DbType::Float | DbType::Enum => 4,
+        DbType::Duration => 8,
+        DbType::Text => 64,
+        DbType::LongText => {
+            let (column, len) = LongTexts::from_binary(binary, rows)?;
+            if len != binary.len() {
+                return Err(column_corrupt(&format!("expected a long text column of {} bytes but found {} bytes", len, binary.len())))
+            }
+            return Ok(DbColumn::LongTexts(column))
+        },
+    };
+    if rows.checked_mul(size) != Some(binary.len()) {
+        return Err(column_corrupt(&format!("expected {} rows of {} bytes but found {} bytes", rows, size, binary.len())))
+    }
+    Ok(match kind {
+        DbType::Int | DbType::Enum => DbColumn::Ints(binary.chunks(4).map(i32_from_le_slice).collect()),
+        DbType::Float => DbColumn::Floats(binary.chunks(4).map(f32_from_le_slice).collect()),
+        DbType::Duration => DbColumn::Durations(binary.chunks(8).map(i64_from_le_slice).collect()),
+        DbType::Text => DbColumn::Texts(binary.chunks(64).map(KeyString::try_from).collect::<Result<Vec<_>, _>>()?),
+        DbType::LongText => unreachable!("Returned above"),
+    })
+}
+
+fn write_varint(binary: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        binary.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    binary.push(x as u8);
+}
+
+fn read_varint(binary: &[u8], pointer: &mut usize) -> Result<u64, EzError> {
+    let mut x = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = match binary.get(*pointer) {
+            Some(byte) => *byte,
+            None => return Err(column_corrupt("a varint is cut short")),
+        };
+        *pointer += 1;
+        x |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(x)
+        }
+    }
+    Err(column_corrupt("a varint is too long"))
+}
+
+fn encode_delta(values: impl Iterator<Item = i64>) -> Vec<u8> {
+    let mut binary = Vec::new();
+    let mut previous = 0i64;
+    for value in values {
+        let delta = value.wrapping_sub(previous);
+        write_varint(&mut binary, ((delta << 1) ^ (delta >> 63)) as u64);
+        previous = value;
+    }
+    binary
+}
+
+fn decode_delta(rows: usize, binary: &[u8]) -> Result<Vec<i64>, EzError> {
+    let mut values = Vec::with_capacity(rows.min(binary.len()));
+    let mut pointer = 0;
+    let mut previous = 0i64;
+    for _ in 0..rows {
+        let zigzag = read_varint(binary, &mut pointer)?;
+        let delta = ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64);
+        previous = previous.wrapping_add(delta);
+        values.push(previous);
+    }
+    if pointer != binary.len() {
+        return Err(column_corrupt("trailing bytes after the deltas"))
+    }
+    Ok(values)
+}
+
+fn encode_dictionary(col: &[KeyString]) -> Option<Vec<u8>> {
+    let mut dictionary: BTreeMap<KeyString, u32> = BTreeMap::new();
+    for item in col {
+        let next = dictionary.len();
+        if next > u32::MAX as usize {
+            return None
+        }
+        dictionary.entry(*item).or_insert(next as u32);
+    }
+    let mut entries = vec![KeyString::new(); dictionary.len()];
+    for (item, index) in &dictionary {
+        entries[*index as usize] = *item;
+    }
+
+    let mut binary = Vec::new();
+    binary.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    for entry in &entries {
+        let raw = entry.raw();
+        let len = raw.iter().rposition(|byte| *byte != 0).map_or(0, |i| i + 1);
+        binary.push(len as u8);
+        binary.extend_from_slice(&raw[..len]);
+    }
+    for item in col {
+        write_varint(&mut binary, dictionary[item] as u64);
+    }
+    Some(binary)
+}
+
+fn decode_dictionary(rows: usize, binary: &[u8]) -> Result<Vec<KeyString>, EzError> {
+    if binary.len() < 8 {
+        return Err(column_corrupt("the dictionary is cut short"))
+    }
+    let count = u64_from_le_slice(&binary[0..8]) as usize;
+    let mut pointer = 8;
+    let mut entries = Vec::with_capacity(count.min(binary.len()));
+    for _ in 0..count {
+        let len = match binary.get(pointer) {
+            Some(len) if *len <= 64 => *len as usize,
+            _ => return Err(column_corrupt("the dictionary is cut short")),
+        };
+        pointer += 1;
+        if binary.len() - pointer < len {
+            return Err(column_corrupt("the dictionary is cut short"))
+        }
+        entries.push(KeyString::try_from(&binary[pointer..pointer + len])?);
+        pointer += len;
+    }
+    let mut values = Vec::with_capacity(rows.min(binary.len() - pointer));
+    for _ in 0..rows {
+        match entries.get(read_varint(binary, &mut pointer)? as usize) {
+            Some(entry) => values.push(*entry),
+            None => return Err(column_corrupt("a dictionary index is out of range")),
+        }
+    }
+    if pointer != binary.len() {
+        return Err(column_corrupt("the dictionary indexes don't match the row count"))
+    }
+    Ok(values)
+}
+
+/// Writes the column as [encoding: u8][length: u64][payload] using the smallest encoding that fits it.
+pub fn compress_column(column: &DbColumn, binary: &mut Vec<u8>) -> Result<ColumnEncoding, EzError> {
+    let raw = raw_column_bytes(column);
+    let mut candidates = Vec::new();
+    match column {
+        DbColumn::Ints(col) => candidates.push((ColumnEncoding::Delta, encode_delta(col.iter().map(|x| *x as i64)))),
+        DbColumn::Durations(col) => candidates.push((ColumnEncoding::Delta, encode_delta(col.iter().copied()))),
+        DbColumn::Texts(col) => if let Some(dictionary) = encode_dictionary(col) {
+            candidates.push((ColumnEncoding::Dictionary, dictionary))
+        },
+        DbColumn::Floats(_) | DbColumn::LongTexts(_) => (),
+    }
+    if !raw.is_empty() {
+        candidates.push((ColumnEncoding::Lz, miniz_compress(&raw)?));
+    }
+
+    let (encoding, payload) = candidates.into_iter()
+        .filter(|(_, payload)| payload.len() < raw.len())
+        .min_by_key(|(_, payload)| payload.len())
+        .unwrap_or((ColumnEncoding::Raw, raw));
+    binary.push(encoding.tag());
+    binary.extend_from_slice(&payload.len().to_le_bytes());
+    binary.extend_from_slice(&payload);
+    Ok(encoding)
+}
+
+/// Reads a column written by compress_column(). Returns the column and how many bytes it took up.
+pub fn decompress_column(kind: DbType, rows: usize, binary: &[u8]) -> Result<(DbColumn, usize), EzError> {
+    if binary.len() < 9 {
+        return Err(column_corrupt("the column header is cut short"))
+    }
+    let encoding = ColumnEncoding::from_tag(binary[0])?;
+    let len = u64_from_le_slice(&binary[1..9]) as usize;
+    if binary.len() - 9 < len {
+        return Err(column_corrupt("the column is cut short"))
+    }
+    let payload = &binary[9..9 + len];
+
+    let column = match (encoding, kind) {
+        (ColumnEncoding::Raw, kind) => raw_column_from_bytes(kind, rows, payload)?,
+        (ColumnEncoding::Lz, kind) => raw_column_from_bytes(kind, rows, &miniz_decompress(payload)?)?,
+        (ColumnEncoding::Delta, DbType::Int | DbType::Enum) => {
+            let values = decode_delta(rows, payload)?;
+            DbColumn::Ints(values.into_iter().map(|x| x as i32).collect())
+        },
+        (ColumnEncoding::Delta, DbType::Duration) => DbColumn::Durations(decode_delta(rows, payload)?),
+        (ColumnEncoding::Dictionary, DbType::Text) => DbColumn::Texts(decode_dictionary(rows, payload)?),
+        (encoding, kind) => return Err(column_corrupt(&format!("{:?} can't hold {} values", encoding, kind.name()))),
+    };
+    Ok((column, 9 + len))
+}
+
+/// How many bytes the compressed column at the start of the binary takes up, without decoding it.
+pub fn compressed_column_len(binary: &[u8]) -> Result<usize, EzError> {
+    if binary.len() < 9 {
+        return Err(column_corrupt("the column header is cut short"))
+    }
+    ColumnEncoding::from_tag(binary[0])?;
+    match (u64_from_le_slice(&binary[1..9]) as usize).checked_add(9) {
+        Some(len) => Ok(len),
+        None => Err(column_corrupt("impossible column length")),
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     #![allow(unused)]
 
     use rand::Rng;
 
-    use crate::{db_structure::ColumnTable, utilities::ez_hash};
+    use crate::{db_structure::ColumnTable, paths::test_file, utilities::ez_hash};
 
     use super::*;
 
     #[test]
     fn test_brotli() {
-        let table_string = std::fs::read_to_string(&format!("test_files{PATH_SEP}test_csv_from_google_sheets_combined_sorted.csv")).unwrap();
+        let table_string = std::fs::read_to_string(test_file("test_csv_from_google_sheets_combined_sorted.csv")).unwrap();
         let table = ColumnTable::from_csv_string(&table_string, "basic_test", "test").unwrap();
         let binary = table.to_binary();
         // let brotli_compressed_table = brotli_compress(&binary).unwrap();
@@ -107,4 +375,51 @@ mod tests {
         assert_eq!(table, miniz_recovered_table);
     }
 
+    #[test]
+    fn test_column_compression() {
+        let table_string = std::fs::read_to_string(test_file("test_csv_from_google_sheets_combined_sorted.csv")).unwrap();
+        let table = ColumnTable::from_csv_string(&table_string, "basic_test", "test").unwrap();
+        let plain = table.to_binary();
+        let compressed = table.to_compressed_binary().unwrap();
+        assert!(compressed.len() < plain.len());
+        assert_eq!(ColumnTable::from_binary(None, &compressed).unwrap(), table);
+        assert_eq!(crate::db_structure::column_table_binary_len(&compressed).unwrap(), compressed.len());
+        // Old files still load
+        assert_eq!(ColumnTable::from_binary(None, &plain).unwrap(), table);
+        assert!(ColumnTable::from_binary(None, &compressed[..compressed.len() - 1]).is_err());
+
+        let mut binary = Vec::new();
+        let sorted = DbColumn::Ints((0..1000).collect());
+        assert_eq!(compress_column(&sorted, &mut binary).unwrap(), ColumnEncoding::Delta);
+        assert_eq!(decompress_column(DbType::Int, 1000, &binary).unwrap(), (sorted, binary.len()));
+
+        let mut binary = Vec::new();
+        let extremes = DbColumn::Durations(vec![i64::MIN, i64::MAX, 0, -1, i64::MIN]);
+        compress_column(&extremes, &mut binary).unwrap();
+        assert_eq!(decompress_column(DbType::Duration, 5, &binary).unwrap().0, extremes);
+
+        let mut binary = Vec::new();
+        let texts: Vec<KeyString> = (0..1000).map(|i| KeyString::from(["north", "south", "east"][i % 3])).collect();
+        let repeated = DbColumn::Texts(texts.clone());
+        compress_column(&repeated, &mut binary).unwrap();
+        assert_eq!(decompress_column(DbType::Text, 1000, &binary).unwrap().0, repeated);
+        assert!(decompress_column(DbType::Text, 999, &binary).is_err());
+        assert!(decompress_column(DbType::Int, 1000, &binary).is_err());
+        // Lz beats the dictionary on a column this regular, so the dictionary is checked on its own
+        let dictionary = encode_dictionary(&texts).unwrap();
+        assert_eq!(dictionary.len(), 8 + 3 + "northsoutheast".len() + 1000);
+        assert_eq!(decode_dictionary(1000, &dictionary).unwrap(), texts);
+        assert!(decode_dictionary(999, &dictionary).is_err());
+        assert!(decode_dictionary(1000, &dictionary[..dictionary.len() - 1]).is_err());
+
+        let mut binary = Vec::new();
+        let long = DbColumn::LongTexts((0..100).map(|i| ["a", "", "a much longer value that would never fit in a KeyString, repeated until the compressor notices"][i % 3]).collect());
+        assert_eq!(compress_column(&long, &mut binary).unwrap(), ColumnEncoding::Lz);
+        assert_eq!(decompress_column(DbType::LongText, 100, &binary).unwrap().0, long);
+
+        let mut binary = Vec::new();
+        assert_eq!(compress_column(&DbColumn::Floats(vec![]), &mut binary).unwrap(), ColumnEncoding::Raw);
+        assert_eq!(decompress_column(DbType::Float, 0, &binary).unwrap().0, DbColumn::Floats(vec![]));
+    }
+
 }
\ No newline at end of file
@@ -0,0 +1,526 @@
+//! Server settings read from a key=value file, with environment variables on top:
+//!     # Lines starting with # are comments
+//!     listen_address = 0.0.0.0:3004
+//!     data_dir = /var/lib/ezdb
+//!     buffer_pool_bytes = 2000000000
+//!     flush_interval_secs = 5
+//!     thread_pool_size = 16
+//!     log_level = warn
+//! Every setting can be overridden by EZDB_ followed by its name in capitals, as in EZDB_LISTEN_ADDRESS, and
+//! then by a command line flag of the same name, as in --lock-timeout-ms=200. See flag_setting().
+//! Settings that are left out keep their defaults. The settings in RUNTIME_SETTINGS can also be changed while
+//! the server runs with the CONFIG_SET admin command. The rest take effect on the next start.
+//! The flags that do something rather than set something, --config=, --self-test, --daemon and --restore=,
+//! are only flags.
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::RwLock;
+
+use crate::auth::{password_iterations, set_password_iterations, DEFAULT_PASSWORD_ITERATIONS};
+use crate::compression::{set_table_compression, table_compression};
+use crate::database::{flush_interval_secs, set_flush_interval_secs, DEFAULT_FLUSH_INTERVAL_SECS};
+use crate::db_structure::{reject_nan, set_reject_nan, ColumnTable, DbColumn};
+use crate::disk_monitor::{disk_thresholds, set_disk_thresholds, DiskThresholds, DEFAULT_DISK_THRESHOLDS};
+use crate::disk_utilities::{
+    buffer_pool_cap, set_buffer_pool_cap, set_table_chunk_rows, set_table_idle_secs, set_value_compaction_percent,
+    set_value_history_depth, table_chunk_rows, table_idle_secs, value_compaction_percent, value_history_depth,
+    DEFAULT_TABLE_CHUNK_ROWS, DEFAULT_TABLE_IDLE_SECS, DEFAULT_VALUE_COMPACTION_PERCENT, DEFAULT_VALUE_HISTORY_DEPTH, MAX_BUFFERPOOL_SIZE,
+};
+use crate::external_sort::{set_sort_spill_threshold, sort_spill_threshold, DEFAULT_SORT_SPILL_THRESHOLD};
+use crate::lock_monitor::{lock_timeout_ms, set_lock_timeout_ms, DEFAULT_LOCK_TIMEOUT_MS};
+use crate::paths::{config_dir, set_data_dir, CONFIG_DIR};
+use crate::schema_file::{schema_file, set_schema_file};
+use crate::thread_pool::{
+    parallel_scan_rows, queue_warning_depth, set_parallel_scan_rows, set_queue_warning_depth, set_thread_pool_size, thread_pool_size,
+    DEFAULT_PARALLEL_SCAN_ROWS, DEFAULT_QUEUE_WARNING_DEPTH, DEFAULT_THREAD_POOL_SIZE,
+};
+use crate::trash::{set_trash_retention_secs, trash_retention_secs, DEFAULT_TRASH_RETENTION_SECS};
+use crate::utilities::{ksf, set_strict_keystrings, strict_keystrings, ErrorTag, EzError, KeyString};
+use crate::write_versions::{set_version_wait_ms, version_wait_ms, DEFAULT_VERSION_WAIT_MS};
+
+
+/// Read from the working directory unless the server is given --config=.
+pub const CONFIG_FILE: &str = "ezdb.conf";
+pub const ENV_PREFIX: &str = "EZDB_";
+pub const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:3004";
+
+pub const SETTINGS: [&str; 27] = [
+    "listen_address", "data_dir", "buffer_pool_bytes", "flush_interval_secs", "thread_pool_size", "log_level",
+    "strict_keystrings", "reject_nan", "table_compression", "password_iterations",
+    "kv_history_depth", "kv_compaction_percent", "table_idle_secs", "table_chunk_rows",
+    "lock_timeout_ms", "queue_warning_depth", "parallel_scan_rows", "trash_retention_secs", "sort_spill_threshold",
+    "version_wait_ms", "disk_warn_days_until_full", "disk_warn_used_percent",
+    "schema_file", "tls_cert", "tls_key", "pid_file", "log_file",
+];
+/// The settings CONFIG_SET can change without a restart.
+pub const RUNTIME_SETTINGS: [&str; 15] = [
+    "buffer_pool_bytes", "flush_interval_secs", "log_level", "strict_keystrings", "reject_nan", "table_compression",
+    "password_iterations", "kv_compaction_percent", "table_idle_secs", "lock_timeout_ms", "queue_warning_depth",
+    "parallel_scan_rows", "trash_retention_secs", "sort_spill_threshold", "version_wait_ms",
+];
+
+/// How much the server prints about its own work. Errors are always printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl LogLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<LogLevel, EzError> {
+        match name.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            other => Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is not a log level. Use error, warn, info or debug", other)}),
+        }
+    }
+
+    fn from_u8(level: u8) -> LogLevel {
+        match level {
+            0 => LogLevel::Error,
+            1 => LogLevel::Warn,
+            2 => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_level() -> LogLevel {
+    LogLevel::from_u8(LOG_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Whether messages of the given level are printed.
+pub fn log_enabled(level: LogLevel) -> bool {
+    level <= log_level()
+}
+
+/// Prints the message if messages of its level are printed.
+pub fn log(level: LogLevel, message: impl Display) {
+    if log_enabled(level) {
+        println!("{}", message);
+    }
+}
+
+static LISTEN_ADDRESS: RwLock<Option<String>> = RwLock::new(None);
+
+/// Records the address the server listens on so CONFIG reports it.
+pub fn set_listen_address(address: &str) {
+    *LISTEN_ADDRESS.write().unwrap() = Some(address.to_owned());
+}
+
+pub fn listen_address() -> String {
+    LISTEN_ADDRESS.read().unwrap().clone().unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.to_owned())
+}
+
+/// The settings last applied, for the ones that have no setter of their own such as the TLS files.
+static APPLIED: RwLock<Option<ServerConfig>> = RwLock::new(None);
+
+/// The setting a command line flag sets and the value it sets it to. --name=value sets the setting of the same
+/// name with dashes for underscores. The switches --strict-keystrings, --reject-nan and --uncompressed-tables
+/// turn their setting on or off and --schema= is short for --schema-file=. None for flags that are not settings.
+pub fn flag_setting(arg: &str) -> Option<(String, String)> {
+    match arg {
+        "--strict-keystrings" => return Some(("strict_keystrings".to_owned(), "true".to_owned())),
+        "--reject-nan" => return Some(("reject_nan".to_owned(), "true".to_owned())),
+        "--uncompressed-tables" => return Some(("table_compression".to_owned(), "false".to_owned())),
+        _ => (),
+    }
+    let (name, value) = arg.strip_prefix("--")?.split_once('=')?;
+    let setting = match name {
+        "schema" => "schema_file".to_owned(),
+        name => name.replace('-', "_"),
+    };
+    SETTINGS.contains(&setting.as_str()).then(|| (setting, value.to_owned()))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerConfig {
+    pub listen_address: String,
+    pub data_dir: PathBuf,
+    /// The most bytes the buffer pool holds. See BufferPool::max_size().
+    pub buffer_pool_bytes: u64,
+    /// The least time between two writes of changed tables and values to disk. 0 writes them on every
+    /// maintenance pass. See perform_maintenance().
+    pub flush_interval_secs: u64,
+    /// Workers answering requests, not counting the priority workers. See initialize_thread_pool().
+    pub thread_pool_size: usize,
+    pub log_level: LogLevel,
+    /// See set_strict_keystrings().
+    pub strict_keystrings: bool,
+    /// See set_reject_nan().
+    pub reject_nan: bool,
+    /// Off keeps table files in the plain layout so sidecars can map them without decoding.
+    pub table_compression: bool,
+    /// See set_password_iterations().
+    pub password_iterations: u32,
+    /// See set_value_history_depth().
+    pub kv_history_depth: u64,
+    /// See set_value_compaction_percent().
+    pub kv_compaction_percent: u64,
+    /// See set_table_idle_secs().
+    pub table_idle_secs: u64,
+    /// See set_table_chunk_rows().
+    pub table_chunk_rows: u64,
+    /// See set_lock_timeout_ms().
+    pub lock_timeout_ms: u64,
+    /// See set_queue_warning_depth().
+    pub queue_warning_depth: u64,
+    /// Tables with fewer rows than this are filtered and summarized on one thread. See set_parallel_scan_rows().
+    pub parallel_scan_rows: u64,
+    /// See set_trash_retention_secs().
+    pub trash_retention_secs: u64,
+    /// See set_sort_spill_threshold().
+    pub sort_spill_threshold: u64,
+    /// See set_version_wait_ms().
+    pub version_wait_ms: u64,
+    /// When the disk monitor warns. See DiskThresholds.
+    pub disk_thresholds: DiskThresholds,
+    /// Tables of the schema file missing once recovery has loaded the rest are created. See schema_file.rs
+    pub schema_file: Option<PathBuf>,
+    /// Clients connect over TLS instead of the Noise handshake if both are given.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Written by --daemon, and without it if given.
+    pub pid_file: Option<PathBuf>,
+    /// Where the output goes. --daemon writes to server.log in the data directory without it.
+    pub log_file: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen_address: DEFAULT_LISTEN_ADDRESS.to_owned(),
+            data_dir: PathBuf::from(CONFIG_DIR),
+            buffer_pool_bytes: MAX_BUFFERPOOL_SIZE,
+            flush_interval_secs: DEFAULT_FLUSH_INTERVAL_SECS,
+            thread_pool_size: DEFAULT_THREAD_POOL_SIZE,
+            log_level: LogLevel::Info,
+            strict_keystrings: false,
+            reject_nan: false,
+            table_compression: true,
+            password_iterations: DEFAULT_PASSWORD_ITERATIONS,
+            kv_history_depth: DEFAULT_VALUE_HISTORY_DEPTH,
+            kv_compaction_percent: DEFAULT_VALUE_COMPACTION_PERCENT,
+            table_idle_secs: DEFAULT_TABLE_IDLE_SECS,
+            table_chunk_rows: DEFAULT_TABLE_CHUNK_ROWS,
+            lock_timeout_ms: DEFAULT_LOCK_TIMEOUT_MS,
+            queue_warning_depth: DEFAULT_QUEUE_WARNING_DEPTH,
+            parallel_scan_rows: DEFAULT_PARALLEL_SCAN_ROWS,
+            trash_retention_secs: DEFAULT_TRASH_RETENTION_SECS,
+            sort_spill_threshold: DEFAULT_SORT_SPILL_THRESHOLD,
+            version_wait_ms: DEFAULT_VERSION_WAIT_MS,
+            disk_thresholds: DEFAULT_DISK_THRESHOLDS,
+            schema_file: None,
+            tls_cert: None,
+            tls_key: None,
+            pid_file: None,
+            log_file: None,
+        }
+    }
+}
+
+impl Display for ServerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for setting in SETTINGS {
+            writeln!(f, "{} = {}", setting, self.get(setting).unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
+impl ServerConfig {
+    /// The defaults, then the file if it exists, then the EZDB_ environment variables.
+    pub fn load(path: &Path) -> Result<ServerConfig, EzError> {
+        let mut config = match std::fs::read_to_string(path) {
+            Ok(text) => ServerConfig::parse(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ServerConfig::default(),
+            Err(e) => return Err(e.into()),
+        };
+        config.apply_env(std::env::vars())?;
+        Ok(config)
+    }
+
+    /// Reads key=value lines. Blank lines and lines starting with # are skipped.
+    pub fn parse(text: &str) -> Result<ServerConfig, EzError> {
+        let mut config = ServerConfig::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let (setting, value) = match line.split_once('=') {
+                Some(pair) => pair,
+                None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Line {} of the config file is not setting = value", number + 1)}),
+            };
+            config.set(setting.trim(), value.trim()).map_err(|e| EzError{tag: e.tag, text: format!("Line {} of the config file: {}", number + 1, e.text)})?;
+        }
+        Ok(config)
+    }
+
+    /// Overrides settings with the EZDB_ variables among `vars`. Other variables are ignored, unknown EZDB_
+    /// ones are not, since they are most likely a misspelled setting.
+    pub fn apply_env(&mut self, vars: impl IntoIterator<Item = (String, String)>) -> Result<(), EzError> {
+        for (name, value) in vars {
+            if let Some(setting) = name.strip_prefix(ENV_PREFIX) {
+                self.set(&setting.to_ascii_lowercase(), &value).map_err(|e| EzError{tag: e.tag, text: format!("{}: {}", name, e.text)})?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Overrides settings with the command line flags among `args` that set one. See flag_setting().
+    pub fn apply_flags(&mut self, args: &[String]) -> Result<(), EzError> {
+        for arg in args {
+            if let Some((setting, value)) = flag_setting(arg) {
+                self.set(&setting, &value).map_err(|e| EzError{tag: e.tag, text: format!("{}: {}", arg, e.text)})?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, setting: &str, value: &str) -> Result<(), EzError> {
+        let number = |value: &str| value.parse::<u64>().map_err(|_| EzError{tag: ErrorTag::ParseInt, text: format!("'{}' is not a valid {}", value, setting)});
+        let float = |value: &str| value.parse::<f64>().map_err(|_| EzError{tag: ErrorTag::ParseFloat, text: format!("'{}' is not a valid {}", value, setting)});
+        let switch = |value: &str| match value.to_ascii_lowercase().as_str() {
+            "true" | "on" | "1" => Ok(true),
+            "false" | "off" | "0" => Ok(false),
+            _ => Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is not a valid {}. Use true or false", value, setting)}),
+        };
+        // An empty path turns the setting off
+        let path = |value: &str| (!value.is_empty()).then(|| PathBuf::from(value));
+        match setting {
+            "listen_address" => self.listen_address = value.to_owned(),
+            "data_dir" => self.data_dir = PathBuf::from(value),
+            "buffer_pool_bytes" => self.buffer_pool_bytes = number(value)?,
+            "flush_interval_secs" => self.flush_interval_secs = number(value)?,
+            "thread_pool_size" => match number(value)? {
+                0 => return Err(EzError{tag: ErrorTag::Query, text: "thread_pool_size has to be at least 1".to_owned()}),
+                size => self.thread_pool_size = size as usize,
+            },
+            "log_level" => self.log_level = LogLevel::from_name(value)?,
+            "strict_keystrings" => self.strict_keystrings = switch(value)?,
+            "reject_nan" => self.reject_nan = switch(value)?,
+            "table_compression" => self.table_compression = switch(value)?,
+            "password_iterations" => match number(value)? {
+                0 => return Err(EzError{tag: ErrorTag::Query, text: "password_iterations has to be at least 1".to_owned()}),
+                iterations => self.password_iterations = iterations.min(u32::MAX as u64) as u32,
+            },
+            "kv_history_depth" => self.kv_history_depth = number(value)?,
+            "kv_compaction_percent" => self.kv_compaction_percent = number(value)?,
+            "table_idle_secs" => self.table_idle_secs = number(value)?,
+            "table_chunk_rows" => self.table_chunk_rows = number(value)?,
+            "lock_timeout_ms" => self.lock_timeout_ms = number(value)?,
+            "queue_warning_depth" => self.queue_warning_depth = number(value)?,
+            "parallel_scan_rows" => self.parallel_scan_rows = number(value)?,
+            "trash_retention_secs" => self.trash_retention_secs = number(value)?,
+            "sort_spill_threshold" => self.sort_spill_threshold = number(value)?,
+            "version_wait_ms" => self.version_wait_ms = number(value)?,
+            "disk_warn_days_until_full" => self.disk_thresholds.warn_days_until_full = float(value)?,
+            "disk_warn_used_percent" => self.disk_thresholds.warn_used_percent = float(value)?,
+            "schema_file" => self.schema_file = path(value),
+            "tls_cert" => self.tls_cert = path(value),
+            "tls_key" => self.tls_key = path(value),
+            "pid_file" => self.pid_file = path(value),
+            "log_file" => self.log_file = path(value),
+            other => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No setting named '{}'. The settings are: {}", other, SETTINGS.join(", "))}),
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, setting: &str) -> Option<String> {
+        let path = |path: &Option<PathBuf>| path.as_ref().map_or_else(String::new, |path| path.display().to_string());
+        match setting {
+            "listen_address" => Some(self.listen_address.clone()),
+            "data_dir" => Some(self.data_dir.display().to_string()),
+            "buffer_pool_bytes" => Some(self.buffer_pool_bytes.to_string()),
+            "flush_interval_secs" => Some(self.flush_interval_secs.to_string()),
+            "thread_pool_size" => Some(self.thread_pool_size.to_string()),
+            "log_level" => Some(self.log_level.name().to_owned()),
+            "strict_keystrings" => Some(self.strict_keystrings.to_string()),
+            "reject_nan" => Some(self.reject_nan.to_string()),
+            "table_compression" => Some(self.table_compression.to_string()),
+            "password_iterations" => Some(self.password_iterations.to_string()),
+            "kv_history_depth" => Some(self.kv_history_depth.to_string()),
+            "kv_compaction_percent" => Some(self.kv_compaction_percent.to_string()),
+            "table_idle_secs" => Some(self.table_idle_secs.to_string()),
+            "table_chunk_rows" => Some(self.table_chunk_rows.to_string()),
+            "lock_timeout_ms" => Some(self.lock_timeout_ms.to_string()),
+            "queue_warning_depth" => Some(self.queue_warning_depth.to_string()),
+            "parallel_scan_rows" => Some(self.parallel_scan_rows.to_string()),
+            "trash_retention_secs" => Some(self.trash_retention_secs.to_string()),
+            "sort_spill_threshold" => Some(self.sort_spill_threshold.to_string()),
+            "version_wait_ms" => Some(self.version_wait_ms.to_string()),
+            "disk_warn_days_until_full" => Some(self.disk_thresholds.warn_days_until_full.to_string()),
+            "disk_warn_used_percent" => Some(self.disk_thresholds.warn_used_percent.to_string()),
+            "schema_file" => Some(path(&self.schema_file)),
+            "tls_cert" => Some(path(&self.tls_cert)),
+            "tls_key" => Some(path(&self.tls_key)),
+            "pid_file" => Some(path(&self.pid_file)),
+            "log_file" => Some(path(&self.log_file)),
+            _ => None,
+        }
+    }
+
+    /// Makes these the settings of the process. Call before the database is initialized, since the data
+    /// directory, the thread pool size and the disk thresholds are only read then. The TLS, pid and log
+    /// files are only read by main() when the server starts.
+    pub fn apply(&self) {
+        set_listen_address(&self.listen_address);
+        set_data_dir(self.data_dir.clone());
+        set_buffer_pool_cap(self.buffer_pool_bytes);
+        set_flush_interval_secs(self.flush_interval_secs);
+        set_thread_pool_size(self.thread_pool_size);
+        set_log_level(self.log_level);
+        set_strict_keystrings(self.strict_keystrings);
+        set_reject_nan(self.reject_nan);
+        set_table_compression(self.table_compression);
+        set_password_iterations(self.password_iterations);
+        set_value_history_depth(self.kv_history_depth);
+        set_value_compaction_percent(self.kv_compaction_percent);
+        set_table_idle_secs(self.table_idle_secs);
+        set_table_chunk_rows(self.table_chunk_rows);
+        set_lock_timeout_ms(self.lock_timeout_ms);
+        set_queue_warning_depth(self.queue_warning_depth);
+        set_parallel_scan_rows(self.parallel_scan_rows);
+        set_trash_retention_secs(self.trash_retention_secs);
+        set_sort_spill_threshold(self.sort_spill_threshold);
+        set_version_wait_ms(self.version_wait_ms);
+        set_disk_thresholds(self.disk_thresholds);
+        set_schema_file(self.schema_file.clone());
+        *APPLIED.write().unwrap() = Some(self.clone());
+    }
+
+    /// The settings the process runs with right now, including changes made with CONFIG_SET.
+    pub fn current() -> ServerConfig {
+        let applied = APPLIED.read().unwrap().clone().unwrap_or_default();
+        ServerConfig {
+            listen_address: listen_address(),
+            data_dir: config_dir(),
+            buffer_pool_bytes: buffer_pool_cap(),
+            flush_interval_secs: flush_interval_secs(),
+            thread_pool_size: thread_pool_size(),
+            log_level: log_level(),
+            strict_keystrings: strict_keystrings(),
+            reject_nan: reject_nan(),
+            table_compression: table_compression(),
+            password_iterations: password_iterations(),
+            kv_history_depth: value_history_depth(),
+            kv_compaction_percent: value_compaction_percent(),
+            table_idle_secs: table_idle_secs(),
+            table_chunk_rows: table_chunk_rows(),
+            lock_timeout_ms: lock_timeout_ms(),
+            queue_warning_depth: queue_warning_depth(),
+            parallel_scan_rows: parallel_scan_rows(),
+            trash_retention_secs: trash_retention_secs(),
+            sort_spill_threshold: sort_spill_threshold(),
+            version_wait_ms: version_wait_ms(),
+            disk_thresholds: disk_thresholds(),
+            schema_file: schema_file(),
+            ..applied
+        }
+    }
+
+    /// The settings as a table of their names and values, for the CONFIG admin command.
+    pub fn to_table(&self) -> Result<ColumnTable, EzError> {
+        let mut table = ColumnTable::create_empty("ez_config", "system");
+        table.add_column(ksf("setting"), DbColumn::Texts(SETTINGS.iter().map(|setting| ksf(setting)).collect()))?;
+        table.add_column(ksf("value"), DbColumn::Texts(SETTINGS.iter().map(|setting| KeyString::from_str_lossy(&self.get(setting).unwrap_or_default())).collect()))?;
+        table.add_column(ksf("runtime"), DbColumn::Ints(SETTINGS.iter().map(|setting| RUNTIME_SETTINGS.contains(setting) as i32).collect()))?;
+        Ok(table)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_file_and_env() {
+        let text = "# A comment\nlisten_address = 0.0.0.0:4000\n\nbuffer_pool_bytes=1000\nlog_level = WARN\n";
+        let mut config = ServerConfig::parse(text).unwrap();
+        assert_eq!(config.listen_address, "0.0.0.0:4000");
+        assert_eq!(config.buffer_pool_bytes, 1000);
+        assert_eq!(config.log_level, LogLevel::Warn);
+        assert_eq!(config.thread_pool_size, DEFAULT_THREAD_POOL_SIZE);
+
+        let env = vec![
+            ("EZDB_THREAD_POOL_SIZE".to_owned(), "3".to_owned()),
+            ("EZDB_LOG_LEVEL".to_owned(), "debug".to_owned()),
+            ("HOME".to_owned(), "/root".to_owned()),
+        ];
+        config.apply_env(env).unwrap();
+        assert_eq!(config.thread_pool_size, 3);
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(ServerConfig::parse(&config.to_string()).unwrap(), config);
+
+        assert!(ServerConfig::parse("buffer_pool_bytes = lots").is_err());
+        assert!(ServerConfig::parse("thread_pool_size = 0").is_err());
+        assert_eq!(ServerConfig::parse("listen_adress = x").unwrap_err().tag, ErrorTag::NotFound);
+        assert!(config.apply_env(vec![("EZDB_BUFER_POOL_BYTES".to_owned(), "1".to_owned())]).is_err());
+        assert_eq!(config.to_table().unwrap().len(), SETTINGS.len());
+    }
+
+    #[test]
+    fn test_every_setting_round_trips() {
+        let mut config = ServerConfig::default();
+        let text = "strict_keystrings = true\ntable_compression = off\nlock_timeout_ms = 250\ndisk_warn_used_percent = 75.5\ntls_cert = /etc/ezdb/cert.pem\n";
+        config = ServerConfig::parse(&format!("{}{}", config, text)).unwrap();
+        assert!(config.strict_keystrings);
+        assert!(!config.table_compression);
+        assert_eq!(config.lock_timeout_ms, 250);
+        assert_eq!(config.disk_thresholds.warn_used_percent, 75.5);
+        assert_eq!(config.tls_cert, Some(PathBuf::from("/etc/ezdb/cert.pem")));
+        assert_eq!(config.tls_key, None);
+        for setting in SETTINGS {
+            assert!(config.get(setting).is_some(), "{} can't be read", setting);
+        }
+        for setting in RUNTIME_SETTINGS {
+            assert!(SETTINGS.contains(&setting));
+        }
+        assert_eq!(ServerConfig::parse(&config.to_string()).unwrap(), config);
+        assert!(ServerConfig::parse("reject_nan = maybe").is_err());
+        assert!(ServerConfig::parse("disk_warn_days_until_full = soon").is_err());
+    }
+
+    #[test]
+    fn test_flags_set_settings() {
+        let flag = |arg: &str| flag_setting(arg).map(|(setting, value)| format!("{}={}", setting, value));
+        assert_eq!(flag("--kv-history-depth=3"), Some("kv_history_depth=3".to_owned()));
+        assert_eq!(flag("--uncompressed-tables"), Some("table_compression=false".to_owned()));
+        assert_eq!(flag("--schema=tables.schema"), Some("schema_file=tables.schema".to_owned()));
+        assert_eq!(flag("--pid-file=/run/ezdb.pid"), Some("pid_file=/run/ezdb.pid".to_owned()));
+        // Actions are not settings
+        assert_eq!(flag("--daemon"), None);
+        assert_eq!(flag("--restore=backup.tar"), None);
+        assert_eq!(flag("--config=ezdb.conf"), None);
+
+        let mut config = ServerConfig::parse("lock_timeout_ms = 100\nreject_nan = false").unwrap();
+        let args: Vec<String> = ["EZDB", "--lock-timeout-ms=200", "--reject-nan", "--daemon"].iter().map(|arg| arg.to_string()).collect();
+        config.apply_flags(&args).unwrap();
+        assert_eq!(config.lock_timeout_ms, 200);
+        assert!(config.reject_nan);
+        assert!(config.apply_flags(&["--table-idle-secs=soon".to_owned()]).is_err());
+    }
+}
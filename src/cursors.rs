@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+
+use crate::db_structure::ColumnTable;
+use crate::utilities::{get_current_time, ksf, ErrorTag, EzError};
+
+
+/// How many rows a page holds when the client doesn't say.
+pub const DEFAULT_PAGE_ROWS: u64 = 10_000;
+/// A connection can't keep more results than this open at once.
+pub const MAX_CURSORS_PER_CONNECTION: usize = 16;
+/// Cursors that haven't been fetched from for this long are dropped by maintenance.
+pub const CURSOR_IDLE_SECS: u64 = 600;
+
+struct Cursor {
+    table: ColumnTable,
+    position: usize,
+    last_used: u64,
+}
+
+/// Results that are held on the server and fetched by the client a page at a time so a large SELECT
+/// doesn't have to fit in a single message. The cursors of a connection live in its Session, so only that
+/// connection can read them. A cursor is dropped when its last page has been fetched, when it is closed,
+/// or when its connection goes away.
+#[derive(Default)]
+pub struct Cursors {
+    next_id: u64,
+    cursors: BTreeMap<u64, Cursor>,
+}
+
+impl Cursors {
+    pub fn new() -> Cursors {
+        Cursors::default()
+    }
+
+    /// Holds the result. Returns the cursor id and the number of rows in the result.
+    pub fn open(&mut self, table: ColumnTable) -> Result<(u64, u64), EzError> {
+        if self.cursors.len() >= MAX_CURSORS_PER_CONNECTION {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("A connection can have at most {} open cursors. Close or finish one first", MAX_CURSORS_PER_CONNECTION)})
+        }
+        self.next_id += 1;
+        let rows = table.len() as u64;
+        self.cursors.insert(self.next_id, Cursor{table, position: 0, last_used: get_current_time()});
+        Ok((self.next_id, rows))
+    }
+
+    /// The next page of at most max_rows rows and how many rows are left after it.
+    /// The cursor is dropped with its last page.
+    pub fn fetch(&mut self, cursor_id: u64, max_rows: u64) -> Result<(ColumnTable, u64), EzError> {
+        let cursor = match self.cursors.get_mut(&cursor_id) {
+            Some(cursor) => cursor,
+            None => return Err(EzError{tag: ErrorTag::Query, text: format!("No open cursor with id {}", cursor_id)}),
+        };
+        let max_rows = if max_rows == 0 { DEFAULT_PAGE_ROWS } else { max_rows };
+        let stop = cursor.position.saturating_add(max_rows as usize).min(cursor.table.len());
+        let mut page = cursor.table.create_subtable_from_index_range(cursor.position, stop);
+        page.name = ksf("RESULT");
+        cursor.position = stop;
+        cursor.last_used = get_current_time();
+        let remaining = (cursor.table.len() - stop) as u64;
+        if remaining == 0 {
+            self.cursors.remove(&cursor_id);
+        }
+        Ok((page, remaining))
+    }
+
+    pub fn close(&mut self, cursor_id: u64) -> Result<(), EzError> {
+        match self.cursors.remove(&cursor_id) {
+            Some(_) => Ok(()),
+            None => Err(EzError{tag: ErrorTag::Query, text: format!("No open cursor with id {}", cursor_id)}),
+        }
+    }
+
+    /// Drops cursors that haven't been used for CURSOR_IDLE_SECS. Returns how many were dropped.
+    pub fn expire_idle(&mut self, now: u64) -> usize {
+        let before = self.cursors.len();
+        self.cursors.retain(|_, cursor| now.saturating_sub(cursor.last_used) < CURSOR_IDLE_SECS);
+        before - self.cursors.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cursors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cursors.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{decode_page, encode_page};
+    use crate::testing_tools::create_fixed_table;
+
+    use super::*;
+
+    #[test]
+    fn test_cursor_pages() {
+        let mut cursors = Cursors::new();
+        let (id, rows) = cursors.open(create_fixed_table(25)).unwrap();
+        assert_eq!(rows, 25);
+
+        let mut fetched = Vec::new();
+        loop {
+            let (page, remaining) = cursors.fetch(id, 10).unwrap();
+            let binary = encode_page(&page, remaining);
+            let (page, remaining) = decode_page(&binary).unwrap();
+            fetched.extend_from_slice(page.get_column_int(&ksf("ints")).unwrap());
+            if remaining == 0 {
+                break
+            }
+        }
+        assert_eq!(fetched, (0..25).collect::<Vec<i32>>());
+        assert!(cursors.is_empty());
+        assert!(cursors.fetch(id, 10).is_err());
+
+        let (id, _) = cursors.open(create_fixed_table(5)).unwrap();
+        cursors.close(id).unwrap();
+        assert!(cursors.close(id).is_err());
+
+        for _ in 0..MAX_CURSORS_PER_CONNECTION {
+            cursors.open(create_fixed_table(5)).unwrap();
+        }
+        assert!(cursors.open(create_fixed_table(5)).is_err());
+        assert_eq!(cursors.expire_idle(get_current_time() + CURSOR_IDLE_SECS), MAX_CURSORS_PER_CONNECTION);
+    }
+}
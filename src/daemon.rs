@@ -0,0 +1,134 @@
+//! Running the server under an init system. With --daemon the server detaches from the terminal, writes
+//! its pid file and sends everything it prints to the log file. Under systemd run it in the foreground
+//! instead and let the journal collect the output. See ezdb.service in the repository root.
+//! There is no Windows service wrapper and none is planned. The server is built on epoll and only runs on
+//! Linux, so this module is only built there. See the Platforms section of the README.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::{dup2, fork, getpid, setsid, ForkResult, Pid};
+
+use crate::utilities::{ErrorTag, EzError};
+
+
+fn daemon_error(what: &str, e: Errno) -> EzError {
+    EzError{tag: ErrorTag::Io, text: format!("Could not {}: {}", what, e)}
+}
+
+/// Whether a process with this pid exists. A process we may not signal still exists.
+pub fn process_is_running(pid: i32) -> bool {
+    if pid <= 0 {
+        return false
+    }
+    match kill(Pid::from_raw(pid), None) {
+        Ok(()) => true,
+        Err(Errno::EPERM) => true,
+        Err(_) => false,
+    }
+}
+
+/// The pid written in a pid file, if there is one and it holds a number.
+pub fn read_pid_file(path: &Path) -> Option<i32> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Holds the pid file of the running server and removes it when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    pub path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the pid of this process to the file. Fails if the file names another process that is still
+    /// running. A pid file left behind by a server that died is overwritten.
+    pub fn create(path: &Path) -> Result<PidFile, EzError> {
+        if let Some(pid) = read_pid_file(path) {
+            if pid != getpid().as_raw() && process_is_running(pid) {
+                return Err(EzError{tag: ErrorTag::Io, text: format!("The pid file '{}' belongs to running process {}", path.display(), pid)})
+            }
+        }
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", getpid())?;
+        file.sync_all()?;
+
+        Ok(PidFile{path: path.to_path_buf()})
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if read_pid_file(&self.path) == Some(getpid().as_raw()) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Points stdout and stderr at the end of the log file and stdin at /dev/null.
+pub fn redirect_output(log_file: &Path) -> Result<(), EzError> {
+    let log = OpenOptions::new().create(true).append(true).open(log_file)?;
+    let null = File::open("/dev/null")?;
+    dup2(null.as_raw_fd(), 0).map_err(|e| daemon_error("redirect stdin", e))?;
+    dup2(log.as_raw_fd(), 1).map_err(|e| daemon_error("redirect stdout", e))?;
+    dup2(log.as_raw_fd(), 2).map_err(|e| daemon_error("redirect stderr", e))?;
+
+    Ok(())
+}
+
+/// Detaches the process from its terminal with the usual double fork. Only the grandchild returns, the other
+/// two exit. Has to be called before any threads are started. The working directory is kept because the
+/// data directory is relative to it.
+pub fn daemonize(log_file: &Path) -> Result<(), EzError> {
+    // Safe while the process has a single thread
+    match unsafe { fork() }.map_err(|e| daemon_error("fork", e))? {
+        ForkResult::Parent{..} => std::process::exit(0),
+        ForkResult::Child => (),
+    }
+    setsid().map_err(|e| daemon_error("start a new session", e))?;
+    // The second fork means the daemon is not a session leader and can never get a terminal again
+    match unsafe { fork() }.map_err(|e| daemon_error("fork", e))? {
+        ForkResult::Parent{..} => std::process::exit(0),
+        ForkResult::Child => (),
+    }
+    redirect_output(log_file)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("ezdb_test_{}.pid", getpid()));
+        let _ = std::fs::remove_file(&path);
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid_file(&path), Some(getpid().as_raw()));
+        assert!(process_is_running(getpid().as_raw()));
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // Left behind by a process that is gone
+        std::fs::write(&path, format!("{}\n", i32::MAX)).unwrap();
+        assert!(!process_is_running(i32::MAX));
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid_file(&path), Some(getpid().as_raw()));
+        drop(pid_file);
+
+        // Belongs to a running process. Pid 1 always runs
+        std::fs::write(&path, "1\n").unwrap();
+        let e = PidFile::create(&path).unwrap_err();
+        assert_eq!(e.tag, ErrorTag::Io);
+        assert_eq!(read_pid_file(&path), Some(1));
+        std::fs::remove_file(&path).unwrap();
+
+        std::fs::write(&path, "garbage").unwrap();
+        assert_eq!(read_pid_file(&path), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
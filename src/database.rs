@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::admission::AdmissionController;
+use crate::auth::User;
+use crate::blob_store::BlobStore;
+use crate::disk_monitor::DiskMonitor;
+use crate::config::{log, log_enabled, LogLevel};
+use crate::disk_utilities::{buffer_pool_cap, load_users, remove_table_files, save_users, table_idle_secs, value_compaction_percent, BufferPool, USERS_FILE, VALUE_EXPIRY_FILE, VALUE_HISTORY_FILE};
+use crate::external_sort::clear_sort_spill_dir;
+use crate::frame_checksum::FrameChecks;
+use crate::lock_monitor::LockMonitor;
+use crate::logging::Logger;
+use crate::maintenance::TaskManager;
+use crate::metrics::Metrics;
+use crate::namespaces::NamespaceRegistry;
+use crate::partitions::PARTITIONS_FILE;
+use crate::paths::{config_dir, config_file, create_data_dirs, raw_tables_dir, raw_values_dir, value_file};
+use crate::protocol::Request;
+use crate::row_table::ROW_ENGINE_FILE;
+use crate::schema_file::{read_schema_file, schema_file};
+use crate::sessions::Sessions;
+use crate::tagging::TagRegistry;
+use crate::thread_pool::PoolStats;
+use crate::trash::{purge_expired_trash, trash_retention_secs};
+use crate::utilities::{get_current_time, EzError, KeyString};
+use crate::write_versions::WriteVersions;
+
+pub const DEFAULT_FLUSH_INTERVAL_SECS: u64 = 0;
+
+static FLUSH_INTERVAL_SECS: AtomicU64 = AtomicU64::new(DEFAULT_FLUSH_INTERVAL_SECS);
+static LAST_FLUSH: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the least time between two writes of changed tables and values by maintenance. 0 writes them on every
+/// pass. Tables are still written before they are unloaded or dropped however recently the last write was.
+pub fn set_flush_interval_secs(secs: u64) {
+    FLUSH_INTERVAL_SECS.store(secs, Ordering::Relaxed);
+}
+
+pub fn flush_interval_secs() -> u64 {
+    FLUSH_INTERVAL_SECS.load(Ordering::Relaxed)
+}
+
+/// Whether this maintenance pass writes changed tables and values, and if so notes that it did.
+fn flush_due(now: u64) -> bool {
+    if flush_interval_secs() == 0 {
+        return true
+    }
+    let last = LAST_FLUSH.load(Ordering::Relaxed);
+    if now.saturating_sub(last) < flush_interval_secs() {
+        return false
+    }
+    LAST_FLUSH.compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+}
+
+/// Everything the engine keeps in memory. The server shares one behind an Arc between its threads
+/// and embedders can hold one without any of the networking.
+pub struct Database {
+    pub buffer_pool: BufferPool,
+    pub users: Arc<RwLock<BTreeMap<KeyString, RwLock<User>>>>,
+    pub logger: Logger,
+    pub tasks: TaskManager,
+    pub namespaces: NamespaceRegistry,
+    pub disk: DiskMonitor,
+    pub admission: AdmissionController,
+    pub blobs: BlobStore,
+    pub tags: TagRegistry,
+    pub frames: FrameChecks,
+    pub locks: LockMonitor,
+    pub sessions: Sessions,
+    pub pool: PoolStats,
+    pub metrics: Metrics,
+    pub versions: WriteVersions,
+}
+
+impl Database {
+    pub fn init() -> Result<Database, EzError> {
+        println!("calling: Database::init()");
+
+        if !config_dir().is_dir() {
+            println!("config does not exist");
+            create_data_dirs().expect("Need IO access to initialize database");
+        } else {
+            println!("config folder exists");
+        }
+        clear_sort_spill_dir()?;
+
+        let buffer_pool = BufferPool::empty(AtomicU64::new(buffer_pool_cap()));
+        let users = load_users(&config_file(USERS_FILE))?;
+        
+        let database = Database {
+            buffer_pool,
+            users: Arc::new(RwLock::new(users)),
+            logger: Logger::init(),
+            tasks: TaskManager::load(&TaskManager::default_path())?,
+            namespaces: NamespaceRegistry::load(&NamespaceRegistry::default_path())?,
+            disk: DiskMonitor::default(),
+            admission: AdmissionController::new(),
+            blobs: BlobStore::new(BlobStore::default_path()),
+            tags: TagRegistry::new(),
+            frames: FrameChecks::new(),
+            locks: LockMonitor::new(),
+            sessions: Sessions::new(),
+            pool: PoolStats::new(),
+            metrics: Metrics::new(),
+            versions: WriteVersions::load(&WriteVersions::default_path())?,
+        };
+
+        Ok(database)
+    }
+
+    /// Loads the tables and values from disk. Runs in the background after the server starts listening
+    /// so that admins can connect and follow the progress. Everyone else is turned away until it finishes.
+    pub fn recover(&self) -> Result<(), EzError> {
+        println!("calling: Database::recover()");
+
+        let tables_path = raw_tables_dir();
+        let values_path = raw_values_dir();
+        let table_files = std::fs::read_dir(&tables_path)?.count() as u64;
+        let value_files = std::fs::read_dir(&values_path)?.count() as u64;
+
+        self.admission.set_phase("loading tables", table_files + value_files);
+        self.buffer_pool.init_tables(&tables_path)?;
+        self.buffer_pool.load_partitions(&config_file(PARTITIONS_FILE))?;
+        self.buffer_pool.load_row_engine_tables(&config_file(ROW_ENGINE_FILE))?;
+        self.admission.advance(table_files);
+
+        if let Some(path) = schema_file() {
+            let created = self.buffer_pool.create_missing_tables(read_schema_file(&path)?)?;
+            log(LogLevel::Info, format_args!("Created {} missing tables from schema file {}", created.len(), path.display()));
+        }
+
+        self.admission.set_phase("loading values", 0);
+        self.buffer_pool.init_values(&values_path)?;
+        self.buffer_pool.load_value_expiry(&config_file(VALUE_EXPIRY_FILE))?;
+        self.buffer_pool.load_value_history(&config_file(VALUE_HISTORY_FILE))?;
+        self.admission.advance(value_files);
+
+        self.admission.finish();
+        log(LogLevel::Info, "Recovery finished. Admitting all clients");
+
+        Ok(())
+    }
+
+    /// Checks whether the given user may send the request right now. While recovering only admins get in
+    /// and only with requests that change nothing.
+    pub fn admit(&self, username: &str, request: &Request) -> Result<(), EzError> {
+        if !self.admission.is_recovering() {
+            return Ok(())
+        }
+        self.admission.admit(self.is_admin(username), request.is_read_only())
+    }
+
+    pub fn is_admin(&self, username: &str) -> bool {
+        match self.users.read().unwrap().get(&KeyString::from(username)) {
+            Some(user) => user.read().unwrap().admin,
+            None => false,
+        }
+    }
+
+    /// Writes the users to disk. Call after any change to them so it survives a restart.
+    pub fn save_users(&self) -> Result<(), EzError> {
+        save_users(&self.users.read().unwrap(), &config_file(USERS_FILE))
+    }
+
+    pub fn contains_table(&self, table_name: KeyString) -> bool {
+        self.buffer_pool.tables.read().unwrap().contains_key(&table_name)
+    }
+}
+
+
+pub fn perform_maintenance(db_ref: Arc<Database>) -> Result<(), EzError> {
+
+    // Everything in the buffer pool is being loaded from disk. Writing it straight back is wasted work.
+    if db_ref.admission.is_recovering() {
+        return Ok(())
+    }
+
+    let expired = db_ref.sessions.expire_idle_cursors(get_current_time());
+    if expired > 0 {
+        log(LogLevel::Info, format_args!("Dropped {} idle cursors", expired));
+    }
+
+    // Background tasks advance one step at a time. Admin commands that pause or cancel them
+    // are handled by other threads in between steps.
+    loop {
+        match db_ref.tasks.run_step(&db_ref) {
+            Ok(true) => continue,
+            Ok(false) => break,
+            Err(e) => interior_log(e),
+        }
+    }
+
+    match db_ref.disk.sample(&config_dir()) {
+        Ok(_) => (),
+        Err(e) => interior_log(e),
+    }
+
+    if log_enabled(LogLevel::Debug) {
+        println!("Current tables:");
+        for table in db_ref.buffer_pool.tables.read().unwrap().keys() {
+            println!("{}", table);
+        }
+        println!("Background thread still running");
+        println!("{:?}", db_ref.buffer_pool.table_delete_list.read().unwrap());
+    }
+    for key in db_ref.buffer_pool.table_delete_list.read().unwrap().iter() {
+        match remove_table_files(key.as_str()) {
+            Ok(_) => (),
+            Err(e) => println!("LINE: {} - ERROR: {}", line!(), e),
+        }
+        
+    }
+    db_ref.buffer_pool.table_delete_list.write().unwrap().clear();
+
+    match purge_expired_trash(get_current_time(), trash_retention_secs()) {
+        Ok(purged) => if !purged.is_empty() {
+            log(LogLevel::Info, format_args!("Purged {} dropped tables from the trash", purged.len()));
+        },
+        Err(e) => interior_log(e),
+    }
+
+
+    let expired = db_ref.buffer_pool.expire_values(get_current_time());
+    if !expired.is_empty() {
+        log(LogLevel::Info, format_args!("Expired {} values", expired.len()));
+    }
+
+    for key in db_ref.buffer_pool.value_delete_list.write().unwrap().iter() {
+        match std::fs::remove_file(value_file(key.as_str())) {
+            Ok(_) => (),
+            Err(e) => println!("LINE: {} - ERROR: {}", line!(), e),
+        }
+    }
+    db_ref.buffer_pool.value_delete_list.write().unwrap().clear();
+
+    match db_ref.buffer_pool.write_value_expiry(&config_file(VALUE_EXPIRY_FILE)) {
+        Ok(_) => (),
+        Err(e) => interior_log(e),
+    }
+
+    match db_ref.buffer_pool.write_value_history(&config_file(VALUE_HISTORY_FILE)) {
+        Ok(_) => (),
+        Err(e) => interior_log(e),
+    }
+
+    // Rows waiting in row buffers only reach the disk through their table
+    if let Err(e) = db_ref.buffer_pool.merge_row_buffers() {
+        interior_log(e);
+    }
+
+    // The flush policy. Changes wait in memory until flush_interval_secs() has passed since the last write
+    if flush_due(get_current_time()) {
+        for (key, table_lock) in db_ref.buffer_pool.tables.read().unwrap().iter() {
+            if db_ref.buffer_pool.table_naughty_list.read().unwrap().contains(key) {
+                match db_ref.buffer_pool.flush_table(key, &table_lock.read().unwrap(), false) {
+                    Ok(_) => (),
+                    Err(e) => println!("LINE: {} - ERROR: {}", line!(), e),
+                };
+            }
+        }
+
+        for (key, value) in db_ref.buffer_pool.values.read().unwrap().iter() {
+            let mut value_naughty_list = db_ref.buffer_pool.value_naughty_list.write().unwrap();
+            if value_naughty_list.contains(key) {
+                let mut file = std::fs::File::create(value_file(key.as_str())).unwrap_or_else(|_| panic!("Panic of line: {} of database. The backup file could not be created.", line!()));
+                file.write_all(&value.write_to_binary()).unwrap_or_else(|_| panic!("Panic of line: {} of database. The backup file could not be written.", line!()));
+                value_naughty_list.remove(key);
+            }
+        }
+    }
+
+    let percent = value_compaction_percent();
+    if percent > 0 {
+        match db_ref.buffer_pool.value_storage_stats(&raw_values_dir()) {
+            Ok(stats) if stats.dead_files > 0 && stats.fragmentation() * 100.0 >= percent as f32 => {
+                match db_ref.buffer_pool.compact_values(&raw_values_dir()) {
+                    Ok((before, _)) => log(LogLevel::Info, format_args!("Compacted the value files. Removed or rewrote {} files holding {} dead bytes", before.dead_files, before.dead_bytes)),
+                    Err(e) => interior_log(e),
+                }
+            },
+            Ok(_) => (),
+            Err(e) => interior_log(e),
+        }
+    }
+
+    match db_ref.buffer_pool.unload_idle_tables(get_current_time(), table_idle_secs()) {
+        Ok(unloaded) => for name in unloaded {
+            log(LogLevel::Info, format_args!("Unloaded idle table: {}", name));
+        },
+        Err(e) => interior_log(e),
+    }
+
+    Ok(())
+}
+
+pub fn interior_log(e: EzError) {
+    println!("{}", e);
+}
+
+
+
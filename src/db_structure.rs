@@ -1,19 +1,29 @@
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap, HashSet}, fmt::{self, Debug, Display}, num::{ParseFloatError, ParseIntError}, sync::atomic::{AtomicU64, Ordering}
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet}, fmt::{self, Debug, Display}, io::BufRead, mem::size_of, sync::atomic::{AtomicBool, AtomicU64, Ordering}
 };
 
 // use smartstring::{LazyCompact, SmartString, };
 
 use ezcbor::cbor::{byteslice_from_cbor, byteslice_to_cbor, expected_data_item, Cbor, CborError, DataItem};
 
+use crate::alloc_stats::{self, AllocPhase};
+use crate::compression::{compress_column, compressed_column_len, decompress_column, table_compression};
 use crate::utilities::*;
-#[allow(unused)]
-use crate::PATH_SEP;
+use crate::ezql::KeyList;
+use crate::query_execution::db_slice_from_column;
+use crate::frame_checksum::crc32;
+use crate::row_mapping::Row;
 
 /// Alias for SmartString
 // pub type KeyString = SmartString<LazyCompact>;
 
 
+// Cells copied into query results on this thread, so tests can check how much a query copied
+#[cfg(test)]
+thread_local! {
+    pub(crate) static CELLS_COPIED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 /// The struct that carries metadata relevant to a given table. More metadata will probably be added later.
 #[derive(Debug)]
 pub struct Metadata {
@@ -84,6 +94,123 @@ impl Metadata {
             created_by: KeyString::from(client),
         }
     }
+
+    /// Fresh metadata with the same creator. Used for tables that are derived from another table.
+    pub fn inherit(&self) -> Metadata {
+        Metadata::new(self.created_by.as_str())
+    }
+
+    /// Records an access. Only needs a read lock on the table since the counters are atomic.
+    pub fn touch(&self) {
+        self.last_access.store(get_current_time(), Ordering::Relaxed);
+        self.times_accessed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// [last_access: 8][times_accessed: 8][created_by: 64]
+    pub fn to_binary(&self) -> [u8; METADATA_BINARY_SIZE] {
+        let mut binary = [0u8; METADATA_BINARY_SIZE];
+        binary[0..8].copy_from_slice(&self.last_access.load(Ordering::Relaxed).to_le_bytes());
+        binary[8..16].copy_from_slice(&self.times_accessed.load(Ordering::Relaxed).to_le_bytes());
+        binary[16..80].copy_from_slice(self.created_by.raw());
+        binary
+    }
+
+    pub fn from_binary(binary: &[u8]) -> Result<Metadata, EzError> {
+        if binary.len() != METADATA_BINARY_SIZE {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Metadata should be {} bytes but is {}", METADATA_BINARY_SIZE, binary.len())})
+        }
+        Ok(Metadata {
+            last_access: AtomicU64::new(u64_from_le_slice(&binary[0..8])),
+            times_accessed: AtomicU64::new(u64_from_le_slice(&binary[8..16])),
+            created_by: KeyString::try_from(&binary[16..80])?,
+        })
+    }
+}
+
+/// The size of serialized Metadata in a table header. last_access, times_accessed, created_by
+pub const METADATA_BINARY_SIZE: usize = 8 + 8 + 64;
+
+/// Marks a binary table whose header carries Metadata right after the column names.
+pub const COLUMN_TABLE_MAGIC: &str = "EZDB_COLUMNTABLE_M";
+
+/// Tables written before Metadata was stored. They are still readable and get a creator of "unknown".
+pub const LEGACY_COLUMN_TABLE_MAGIC: &str = "EZDB_COLUMNTABLE";
+
+/// Same header as COLUMN_TABLE_MAGIC but every column is written by compression::compress_column().
+/// Only used for table files. Tables sent over the network are never compressed this way.
+pub const COMPRESSED_COLUMN_TABLE_MAGIC: &str = "EZDB_COLUMNTABLE_Z";
+
+/// The newest table format this build can read. Version 0 is LEGACY_COLUMN_TABLE_MAGIC. Version 1 added the
+/// Metadata and is what COLUMN_TABLE_MAGIC and COMPRESSED_COLUMN_TABLE_MAGIC are. Version 2 added enum columns.
+/// Version 3 added the CHECKSUMS_SECTION. Version 4 added Duration columns and version 5 LongText columns,
+/// neither of which older servers can read.
+pub const TABLE_FORMAT_VERSION: u64 = 5;
+
+/// Tables with enum columns. Their values are in the ENUM_VALUES_SECTION, which older servers can't do without.
+/// Tables without enum columns are still sent in version 1 so older clients can read them.
+pub const ENUM_COLUMN_TABLE_MAGIC: &str = "EZDB_COLUMNTABLE_V2_R2";
+pub const COMPRESSED_ENUM_COLUMN_TABLE_MAGIC: &str = "EZDB_COLUMNTABLE_V2_R2_Z";
+
+/// Table files. They carry the CHECKSUMS_SECTION, which servers reading version 2 skip.
+pub const CHECKED_COLUMN_TABLE_MAGIC: &str = "EZDB_COLUMNTABLE_V3_R2";
+pub const COMPRESSED_CHECKED_COLUMN_TABLE_MAGIC: &str = "EZDB_COLUMNTABLE_V3_R2_Z";
+
+/// The optional section holding the values of every enum column. For each column: [name: 64][count: u64][value: 64]...
+pub const ENUM_VALUES_SECTION: &str = "enum_values";
+
+/// The optional section holding the CRC-32 of the header, metadata included, followed by the CRC-32 of each
+/// column as it is stored, compressed or not: [header: u32][column: u32]... in the order of the columns.
+pub const CHECKSUMS_SECTION: &str = "checksums";
+
+/// Formats after version 1 are marked "EZDB_COLUMNTABLE_V<version>_R<oldest reader>", with "_Z" at the end
+/// when the columns are compressed. A server can read the file if its TABLE_FORMAT_VERSION is at least the
+/// oldest reader version. Such files start with the version 1 layout. Whatever a newer version adds goes after
+/// the columns as optional sections that older servers skip: [count: u64] then [name: 64][length: u64][body]
+/// for each section. A change that older servers can't skip must raise the oldest reader version.
+pub const VERSIONED_COLUMN_TABLE_PREFIX: &str = "EZDB_COLUMNTABLE_V";
+
+/// What the magic at the start of a table binary says about the rest of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TableFormat {
+    pub version: u64,
+    pub has_metadata: bool,
+    pub compressed: bool,
+    /// Optional sections follow the columns.
+    pub has_sections: bool,
+}
+
+/// Reads the magic of a table binary. Files from newer formats that this server can't read are refused
+/// with both version numbers so the operator knows which server version the file needs.
+pub fn table_format(magic: &[u8]) -> Result<TableFormat, EzError> {
+    let not_table = || EzError{tag: ErrorTag::Deserialization, text: "Not ColumnTable".to_owned()};
+    let magic = KeyString::try_from(magic).map_err(|_| not_table())?;
+    let format = |version, has_metadata, compressed, has_sections| TableFormat{version, has_metadata, compressed, has_sections};
+    match magic.as_str() {
+        LEGACY_COLUMN_TABLE_MAGIC => return Ok(format(0, false, false, false)),
+        COLUMN_TABLE_MAGIC => return Ok(format(1, true, false, false)),
+        COMPRESSED_COLUMN_TABLE_MAGIC => return Ok(format(1, true, true, false)),
+        _ => (),
+    }
+
+    let rest = magic.as_str().strip_prefix(VERSIONED_COLUMN_TABLE_PREFIX).ok_or_else(not_table)?;
+    let mut parts = rest.split('_');
+    let version = parts.next().and_then(|x| x.parse::<u64>().ok()).ok_or_else(not_table)?;
+    let reader = parts.next().and_then(|x| x.strip_prefix('R')).and_then(|x| x.parse::<u64>().ok()).ok_or_else(not_table)?;
+    if reader > TABLE_FORMAT_VERSION {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: format!(
+            "The table was written in format version {} which needs a server that reads version {} or newer. This server reads up to version {}",
+            version, reader, TABLE_FORMAT_VERSION
+        )})
+    }
+    let compressed = match parts.next() {
+        None => false,
+        Some("Z") => true,
+        Some(other) => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unknown table format flag '{}' in '{}'", other, magic)}),
+    };
+    if parts.next().is_some() {
+        return Err(not_table())
+    }
+    Ok(format(version, true, compressed, true))
 }
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
@@ -91,6 +218,8 @@ pub enum DbValue {
     Int(i32),
     Float(f32),
     Text(KeyString),
+    /// A span of time in nanoseconds
+    Duration(i64),
 }
 
 impl Display for DbValue {
@@ -99,6 +228,7 @@ impl Display for DbValue {
             DbValue::Int(x) => write!(f,"Value: '{}'", x),
             DbValue::Float(x) => write!(f,"Value: '{}'", x),
             DbValue::Text(x) => write!(f,"Value: '{}'", x),
+            DbValue::Duration(x) => write!(f,"Value: '{}'", format_duration(*x)),
         }
     }
 }
@@ -144,6 +274,23 @@ impl DbValue {
         }
     }
 
+    /// Returns the duration in nanoseconds
+    pub fn to_duration(&self) -> i64 {
+        match self {
+            DbValue::Duration(d) => *d,
+            x => panic!("A call to DbValue.to_duration() failed. Actual value: '{}'", x)
+        }
+    }
+
+    /// Like to_duration() but also accepts text like "150ms". Queries written as text carry their values as text.
+    pub fn as_duration(&self) -> Result<i64, EzError> {
+        match self {
+            DbValue::Duration(d) => Ok(*d),
+            DbValue::Text(t) => parse_duration(t.as_str()),
+            x => Err(EzError{tag: ErrorTag::Query, text: format!("{} is not a duration. Durations need a unit like 150ms or 2s", x)}),
+        }
+    }
+
 
     pub fn to_binary(&self) -> [u8;72] {
         let mut binary = [0u8;72];
@@ -164,6 +311,11 @@ impl DbValue {
                 binary[1..8].copy_from_slice(&[0u8;7]);
                 binary[8..72].copy_from_slice(key_string.raw());
             }
+            DbValue::Duration(d) => {
+                binary[0] = b'd';
+                binary[1..8].copy_from_slice(&[0u8;7]);
+                binary[8..16].copy_from_slice(&d.to_le_bytes());
+            }
         };
 
         binary
@@ -185,20 +337,113 @@ impl DbValue {
                 Ok(DbValue::Float(f))
             }
             b't' => {
-                let ks = KeyString::try_from(&binary[8..72])?;
+                let ks = KeyString::try_from(checked_slice(binary, 8, 64, "Text value")?)?;
                 Ok(DbValue::Text(ks))
             }
+            b'd' => {
+                if binary.len() < 16 {
+                    return Err(EzError { tag: ErrorTag::Deserialization, text: format!("cannot deserialize a duration from less than 16 bytes. Was passed: '{}' bytes", binary.len()) })
+                }
+                let d = i64_from_le_slice(&binary[8..16]);
+                Ok(DbValue::Duration(d))
+            }
             other => return Err(EzError { tag: ErrorTag::Deserialization, text: format!("Unsupported data type: '{}'", other) })
         }
     }
 }
 
+const DURATION_UNITS: [(&str, i64); 8] = [
+    ("d", 86_400_000_000_000),
+    ("h", 3_600_000_000_000),
+    ("m", 60_000_000_000),
+    ("s", 1_000_000_000),
+    ("ms", 1_000_000),
+    ("us", 1_000),
+    ("µs", 1_000),
+    ("ns", 1),
+];
+
+/// Parses a duration like "150ms", "2s", "1.5h" or "1h30m" to nanoseconds.
+/// Every number needs a unit. Supported units are d, h, m, s, ms, us (or µs) and ns.
+pub fn parse_duration(s: &str) -> Result<i64, EzError> {
+
+    let trimmed = s.trim();
+    let (negative, mut rest) = match trimmed.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, trimmed),
+    };
+    if rest.is_empty() {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Could not parse '{}' as a duration", s)})
+    }
+
+    let mut total: i64 = 0;
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(rest.len());
+        let unit_end = rest[number_end..].find(|c: char| c.is_ascii_digit() || c == '.').map(|i| i + number_end).unwrap_or(rest.len());
+        let (number, unit) = (&rest[..number_end], &rest[number_end..unit_end]);
+        let factor = match DURATION_UNITS.iter().find(|(name, _)| *name == unit) {
+            Some((_, factor)) => *factor,
+            None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Could not parse '{}' as a duration. '{}' is not a unit. Use d, h, m, s, ms, us or ns", s, unit)}),
+        };
+        let nanos = if number.contains('.') {
+            match number.parse::<f64>() {
+                Ok(x) => (x * factor as f64).round() as i64,
+                Err(_) => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Could not parse '{}' as a duration", s)}),
+            }
+        } else {
+            match number.parse::<i64>().ok().and_then(|x| x.checked_mul(factor)) {
+                Some(x) => x,
+                None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Could not parse '{}' as a duration", s)}),
+            }
+        };
+        total = match total.checked_add(nanos) {
+            Some(x) => x,
+            None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Duration '{}' is too large", s)}),
+        };
+        rest = &rest[unit_end..];
+    }
+
+    Ok(if negative { -total } else { total })
+}
+
+/// Formats nanoseconds in the largest unit that represents them exactly, so parse_duration() gives back the same value.
+pub fn format_duration(nanos: i64) -> String {
+
+    if nanos == 0 {
+        return "0s".to_owned()
+    }
+    for (unit, factor) in DURATION_UNITS {
+        if unit != "µs" && nanos % factor == 0 {
+            return format!("{}{}", nanos / factor, unit)
+        }
+    }
+    unreachable!("Every i64 is a whole number of nanoseconds")
+}
+
+/// Formats nanoseconds for people, in the largest unit the duration reaches with up to 3 decimals. Used for aggregates.
+pub fn humanize_duration(nanos: f64) -> String {
+
+    for (unit, factor) in DURATION_UNITS {
+        if unit != "µs" && nanos.abs() >= factor as f64 {
+            let value = format!("{:.3}", nanos / factor as f64);
+            let value = value.trim_end_matches('0').trim_end_matches('.');
+            return format!("{}{}", value, unit)
+        }
+    }
+    format!("{}ns", nanos)
+}
+
 /// Identifies a type of a DbVec
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum DbType {
     Int,
     Float,
     Text,
+    Duration,
+    /// Text of any length. See LongTexts.
+    LongText,
+    /// One of a fixed set of values, stored in an Ints column as the position of the value in HeaderItem::values.
+    Enum,
 }
 
 impl Cbor for DbType {
@@ -210,6 +455,9 @@ impl Cbor for DbType {
             DbType::Int => bytes.push(0xc6),
             DbType::Float => bytes.push(0xc6+1),
             DbType::Text => bytes.push(0xc6+2),
+            DbType::Duration => bytes.push(0xc6+3),
+            DbType::LongText => bytes.push(0xc6+4),
+            DbType::Enum => bytes.push(0xc6+5),
         };
         bytes
     }
@@ -225,7 +473,10 @@ impl Cbor for DbType {
                 0 => Ok((DbType::Int, 1)),
                 1 => Ok((DbType::Float, 1)),
                 2 => Ok((DbType::Text, 1)),
-                _ => return Err(CborError::Unexpected(format!("Unexpected byte encountered while decoding a DbType. Should only allow 0x0, 0x1, or 0x2 but encounterd '{:x}'", byte))),
+                3 => Ok((DbType::Duration, 1)),
+                4 => Ok((DbType::LongText, 1)),
+                5 => Ok((DbType::Enum, 1)),
+                _ => Err(CborError::Unexpected(format!("Unexpected byte encountered while decoding a DbType. Should only allow 0x0 to 0x5 but encounterd '{:x}'", byte))),
 
             },
             _ => return Err(CborError::Unexpected("Error originated from TableKey implementation".to_owned())),
@@ -233,12 +484,252 @@ impl Cbor for DbType {
     }
 }
 
+impl DbType {
+    /// The lowercase name used in system tables and DESCRIBE results.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DbType::Int => "int",
+            DbType::Float => "float",
+            DbType::Text => "text",
+            DbType::Duration => "duration",
+            DbType::LongText => "longtext",
+            DbType::Enum => "enum",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<DbType, EzError> {
+        match name {
+            "int" => Ok(DbType::Int),
+            "float" => Ok(DbType::Float),
+            "text" => Ok(DbType::Text),
+            "duration" => Ok(DbType::Duration),
+            "longtext" => Ok(DbType::LongText),
+            "enum" => Ok(DbType::Enum),
+            other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("'{}' is not a column type", other)}),
+        }
+    }
+}
+
+/// Engine maintained duration columns holding when each row was inserted and when it last changed, as time since the Unix epoch.
+/// Like every duration they are stored in nanoseconds, but always hold whole seconds, so a row changed at epoch second s
+/// holds s * 1_000_000_000 and compares against duration literals like `1700000000s` and dates parsed with
+/// CsvColumnFormat::date_format. They aren't u64 seconds since no column type holds a u64 and Ints run out in 2038.
+/// Only tables created with them have them. See ColumnTable::add_row_timestamps().
+pub const CREATED_AT_COLUMN: &str = "__created_at";
+pub const UPDATED_AT_COLUMN: &str = "__updated_at";
+
+pub fn is_row_timestamp_column(name: &KeyString) -> bool {
+    name.as_str() == CREATED_AT_COLUMN || name.as_str() == UPDATED_AT_COLUMN
+}
+
+/// Engine maintained column holding a stable id for each row. Ids are handed out on insert and never change, so they
+/// keep pointing at the same row when rows move on sorting, inserts and deletes. They are plain counts kept in a
+/// duration column since that is the only 64 bit integer column. SELECT only returns them when asked for by name.
+/// Only tables created with them have them. See ColumnTable::add_row_ids().
+pub const ROW_ID_COLUMN: &str = "__row_id";
+
+pub fn is_row_id_column(name: &KeyString) -> bool {
+    name.as_str() == ROW_ID_COLUMN
+}
+
+/// Engine maintained column counting the writes to each row. New rows start at 1 and every UPDATE or insert over an
+/// existing key adds one, so a client can send back the version it read and have the update refused if the row changed
+/// since. Counts are kept in a duration column like row ids. Only tables created with them have them.
+/// See ColumnTable::add_row_versions().
+pub const ROW_VERSION_COLUMN: &str = "__version";
+
+pub fn is_row_version_column(name: &KeyString) -> bool {
+    name.as_str() == ROW_VERSION_COLUMN
+}
+
+/// Engine columns holding plain counts in a duration column. Conditions on them take plain numbers.
+pub fn is_count_column(name: &KeyString) -> bool {
+    is_row_id_column(name) || is_row_version_column(name)
+}
+
+/// Columns the engine keeps up to date itself. Queries can't change them.
+pub fn is_engine_column(name: &KeyString) -> bool {
+    is_row_timestamp_column(name) || is_row_id_column(name) || is_row_version_column(name)
+}
+
+/// Reads a row id out of a query value. Ids are written as plain numbers.
+pub fn row_id_from_value(value: &DbValue) -> Result<i64, EzError> {
+    match value {
+        DbValue::Int(x) if *x >= 0 => Ok(*x as i64),
+        DbValue::Text(t) => match t.as_str().parse::<i64>() {
+            Ok(x) if x >= 0 => Ok(x),
+            _ => Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is not a row id", t)}),
+        },
+        DbValue::Duration(x) => Ok(*x),
+        x => Err(EzError{tag: ErrorTag::Query, text: format!("{} is not a row id", x)}),
+    }
+}
+
+/// The row timestamp stored for epoch second `secs`. See CREATED_AT_COLUMN.
+fn epoch_nanos(secs: u64) -> i64 {
+    secs as i64 * 1_000_000_000
+}
+
+/// A column of text values of any length. The values sit back to back in one buffer and value i is
+/// bytes[offsets[i]..offsets[i+1]], so the column takes two allocations however many rows it has and
+/// doesn't pad short values the way KeyString does.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct LongTexts {
+    bytes: String,
+    offsets: Vec<usize>,
+}
+
+impl Default for LongTexts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> FromIterator<&'a str> for LongTexts {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        let mut column = LongTexts::new();
+        for value in iter {
+            column.push(value);
+        }
+        column
+    }
+}
+
+impl LongTexts {
+    pub fn new() -> LongTexts {
+        LongTexts{bytes: String::new(), offsets: vec![0]}
+    }
+
+    pub fn with_capacity(rows: usize) -> LongTexts {
+        let mut offsets = Vec::with_capacity(rows + 1);
+        offsets.push(0);
+        LongTexts{bytes: String::new(), offsets}
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes held in memory: the text and one offset per value plus the leading 0.
+    pub fn byte_size(&self) -> usize {
+        self.bytes.len() + self.offsets.len() * size_of::<usize>()
+    }
+
+    /// Bytes write_binary() writes: the text length, one offset per value and the text.
+    pub fn binary_size(&self) -> usize {
+        8 + self.len() * 8 + self.bytes.len()
+    }
+
+    /// Total length of the values in bytes.
+    pub fn byte_len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Panics if the index is out of bounds, like indexing a Vec.
+    pub fn get(&self, index: usize) -> &str {
+        &self.bytes[self.offsets[index]..self.offsets[index + 1]]
+    }
+
+    pub fn push(&mut self, value: &str) {
+        self.bytes.push_str(value);
+        self.offsets.push(self.bytes.len());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> + '_ {
+        (0..self.len()).map(|i| self.get(i))
+    }
+
+    /// The values at the given indexes, in that order.
+    pub fn select(&self, indexes: &[usize]) -> LongTexts {
+        let mut column = LongTexts::with_capacity(indexes.len());
+        for index in indexes {
+            column.push(self.get(*index));
+        }
+        column
+    }
+
+    /// The values from start up to but not including stop.
+    pub fn slice(&self, start: usize, stop: usize) -> LongTexts {
+        (start..stop).map(|i| self.get(i)).collect()
+    }
+
+    /// Keeps the values whose bit is set in `keep`, like the free retain_by_mask().
+    pub fn retain_by_mask(&mut self, keep: &[u64]) {
+        *self = self.iter().enumerate().filter(|(i, _)| keep[i / 64] & (1 << (i % 64)) != 0).map(|(_, value)| value).collect();
+    }
+
+    /// Removes the values at the given indexes, like the free remove_indices().
+    pub fn remove_indices(&mut self, indices: &[usize]) {
+        let indices_set: HashSet<usize> = indices.iter().cloned().collect();
+        *self = self.iter().enumerate().filter(|(i, _)| !indices_set.contains(i)).map(|(_, value)| value).collect();
+    }
+
+    /// Merges in the values of `other` the way merge_in_order() does.
+    fn merged_in_order(&self, other: &LongTexts, record_vec: &[u8]) -> LongTexts {
+        let one: Vec<&str> = self.iter().collect();
+        let two: Vec<&str> = other.iter().collect();
+        merge_in_order(&one, &two, record_vec).into_iter().collect()
+    }
+
+    /// [data length: u64][end offset of each value: u64][data]
+    pub fn write_binary(&self, binary: &mut Vec<u8>) {
+        binary.extend_from_slice(&self.bytes.len().to_le_bytes());
+        for offset in &self.offsets[1..] {
+            binary.extend_from_slice(&offset.to_le_bytes());
+        }
+        binary.extend_from_slice(self.bytes.as_bytes());
+    }
+
+    /// How many bytes write_binary() wrote for `rows` values at the start of the binary.
+    pub fn binary_len(binary: &[u8], rows: usize) -> Result<usize, EzError> {
+        let too_short = || EzError{tag: ErrorTag::Deserialization, text: "Binary is too short to contain its long text column".to_owned()};
+        if binary.len() < 8 {
+            return Err(too_short())
+        }
+        let data_len = u64_from_le_slice(&binary[0..8]) as usize;
+        rows.checked_mul(8).and_then(|x| x.checked_add(8)).and_then(|x| x.checked_add(data_len)).ok_or_else(too_short)
+    }
+
+    /// Reads what write_binary() wrote. Returns the column and how many bytes it took up.
+    pub fn from_binary(binary: &[u8], rows: usize) -> Result<(LongTexts, usize), EzError> {
+        let len = LongTexts::binary_len(binary, rows)?;
+        if binary.len() < len {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: "Binary is too short to contain its long text column".to_owned()})
+        }
+        let data_start = 8 + rows * 8;
+        let bytes = match std::str::from_utf8(&binary[data_start..len]) {
+            Ok(bytes) => bytes.to_owned(),
+            Err(e) => return Err(EzError{tag: ErrorTag::Utf8, text: e.to_string()}),
+        };
+        let mut offsets = Vec::with_capacity(rows + 1);
+        offsets.push(0);
+        for chunk in binary[8..data_start].chunks_exact(8) {
+            let offset = u64_from_le_slice(chunk) as usize;
+            if offset < offsets[offsets.len() - 1] || offset > bytes.len() || !bytes.is_char_boundary(offset) {
+                return Err(EzError{tag: ErrorTag::Deserialization, text: "Long text column has a bad offset".to_owned()})
+            }
+            offsets.push(offset);
+        }
+        if offsets[rows] != bytes.len() {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: "Long text column offsets don't cover its data".to_owned()})
+        }
+        Ok((LongTexts{bytes, offsets}, len))
+    }
+}
+
 /// A single column in a database table.
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum DbColumn {
     Ints(Vec<i32>),
     Texts(Vec<KeyString>),
     Floats(Vec<f32>),
+    /// Nanoseconds
+    Durations(Vec<i64>),
+    LongTexts(LongTexts),
 }
 
 impl Display for DbColumn {
@@ -249,6 +740,8 @@ impl Display for DbColumn {
             DbColumn::Ints(v) => write!(f, "{:?}", v),
             DbColumn::Floats(v) => write!(f, "{:?}", v),
             DbColumn::Texts(v) => write!(f, "{:?}", v),
+            DbColumn::Durations(v) => write!(f, "{:?}", v.iter().map(|d| format_duration(*d)).collect::<Vec<String>>()),
+            DbColumn::LongTexts(v) => write!(f, "{:?}", v.iter().collect::<Vec<&str>>()),
         }
     }
 }
@@ -265,6 +758,12 @@ impl From<Vec<f32>> for DbColumn {
     }
 }
 
+impl From<Vec<i64>> for DbColumn {
+    fn from(value: Vec<i64>) -> Self {
+        DbColumn::Durations(value)
+    }
+}
+
 impl From<Vec<KeyString>> for DbColumn {
     fn from(value: Vec<KeyString>) -> Self {
         DbColumn::Texts(value)
@@ -291,6 +790,18 @@ impl Cbor for DbColumn {
                 bytes.extend_from_slice(&col.to_cbor_bytes());
 
             },
+            DbColumn::Durations(col) => {
+                bytes.push(0xc6+3);
+                let raw: Vec<u8> = col.iter().flat_map(|d| d.to_le_bytes()).collect();
+                bytes.extend_from_slice(&byteslice_to_cbor(&raw));
+            },
+            // The row count goes first since the layout doesn't carry it
+            DbColumn::LongTexts(col) => {
+                bytes.push(0xc6+4);
+                let mut raw = col.len().to_le_bytes().to_vec();
+                col.write_binary(&mut raw);
+                bytes.extend_from_slice(&byteslice_to_cbor(&raw));
+            },
         }
         bytes
     }
@@ -315,7 +826,24 @@ impl Cbor for DbColumn {
                     let (thing, bytes_read) = <Vec<f32> as Cbor>::from_cbor_bytes(&bytes[1..])?;
                     Ok((DbColumn::Floats(thing), bytes_read+1))
                 },
-                _ => return Err(CborError::Unexpected(format!("Unexpected byte encountered while decoding a DbColumn. Should only allow 0x0, 0x1, or 0x2 but encounterd '{:x}'", byte))),
+                3 => {
+                    let (raw, bytes_read) = byteslice_from_cbor(&bytes[1..])?;
+                    if raw.len() % 8 != 0 {
+                        return Err(CborError::Unexpected(format!("A duration column must be a multiple of 8 bytes. Was '{}' bytes", raw.len())))
+                    }
+                    let thing = raw.chunks_exact(8).map(i64_from_le_slice).collect();
+                    Ok((DbColumn::Durations(thing), bytes_read+1))
+                },
+                4 => {
+                    let (raw, bytes_read) = byteslice_from_cbor(&bytes[1..])?;
+                    if raw.len() < 8 {
+                        return Err(CborError::Unexpected("A long text column must start with its row count".to_owned()))
+                    }
+                    let (thing, _) = LongTexts::from_binary(&raw[8..], u64_from_le_slice(&raw[0..8]) as usize)
+                        .map_err(|e| CborError::Unexpected(e.text))?;
+                    Ok((DbColumn::LongTexts(thing), bytes_read+1))
+                },
+                _ => Err(CborError::Unexpected(format!("Unexpected byte encountered while decoding a DbColumn. Should only allow 0x0 to 0x4 but encounterd '{:x}'", byte))),
             },
             _ => return Err(CborError::Unexpected("Error originated from TableKey implementation".to_owned())),
         }
@@ -328,9 +856,56 @@ impl DbColumn {
             DbColumn::Floats(v) => v.len(),
             DbColumn::Ints(v) => v.len(),
             DbColumn::Texts(v) => v.len(),
+            DbColumn::Durations(v) => v.len(),
+            DbColumn::LongTexts(v) => v.len(),
+        }
+    }
+
+    /// Bytes the items of the column hold in memory. Unused capacity is not counted.
+    pub fn byte_size(&self) -> usize {
+        match self {
+            DbColumn::Ints(v) => v.len() * size_of::<i32>(),
+            DbColumn::Floats(v) => v.len() * size_of::<f32>(),
+            DbColumn::Texts(v) => v.len() * size_of::<KeyString>(),
+            DbColumn::Durations(v) => v.len() * size_of::<i64>(),
+            DbColumn::LongTexts(v) => v.byte_size(),
         }
     }
 
+    /// Bytes the column takes in the uncompressed EZ binary format.
+    pub fn binary_size(&self) -> usize {
+        match self {
+            DbColumn::Ints(v) => v.len() * 4,
+            DbColumn::Floats(v) => v.len() * 4,
+            DbColumn::Texts(v) => v.len() * 64,
+            DbColumn::Durations(v) => v.len() * 8,
+            DbColumn::LongTexts(v) => v.binary_size(),
+        }
+    }
+
+    /// The value in row `index`. Long texts don't fit in a DbValue and are an error.
+    pub fn value_at(&self, index: usize) -> Result<DbValue, EzError> {
+        match self {
+            DbColumn::Ints(col) => Ok(DbValue::Int(col[index])),
+            DbColumn::Floats(col) => Ok(DbValue::Float(col[index])),
+            DbColumn::Texts(col) => Ok(DbValue::Text(col[index])),
+            DbColumn::Durations(col) => Ok(DbValue::Duration(col[index])),
+            DbColumn::LongTexts(_) => Err(EzError{tag: ErrorTag::Query, text: "Long text cells can't be read as a single value".to_owned()}),
+        }
+    }
+
+    /// Appends a value of the column's type.
+    pub fn push_value(&mut self, value: DbValue) -> Result<(), EzError> {
+        match (self, value) {
+            (DbColumn::Ints(col), DbValue::Int(x)) => col.push(x),
+            (DbColumn::Floats(col), DbValue::Float(x)) => col.push(x),
+            (DbColumn::Texts(col), DbValue::Text(x)) => col.push(x),
+            (DbColumn::Durations(col), DbValue::Duration(x)) => col.push(x),
+            (_, value) => return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' does not fit in the column", value)}),
+        }
+        Ok(())
+    }
+
     pub fn get_i32_col(&self) -> &Vec<i32> {
         match self {
             DbColumn::Ints(col) => col,
@@ -351,6 +926,20 @@ impl DbColumn {
             _ => panic!("Never call this function unless you are sure it's a KeyString column"),
         }
     }
+
+    pub fn get_duration_col(&self) -> &Vec<i64> {
+        match self {
+            DbColumn::Durations(col) => col,
+            _ => panic!("Never call this function unless you are sure it's a duration column"),
+        }
+    }
+
+    pub fn get_long_text_col(&self) -> &LongTexts {
+        match self {
+            DbColumn::LongTexts(col) => col,
+            _ => panic!("Never call this function unless you are sure it's a long text column"),
+        }
+    }
 }
 
 /// The header of a database column. Identifies name, type, and whether it is the primary key,
@@ -360,6 +949,10 @@ pub struct HeaderItem {
     pub name: KeyString,
     pub kind: DbType,
     pub key: TableKey,
+    /// The values an Enum column allows, in the order they were declared. Empty for every other type.
+    pub values: Vec<KeyString>,
+    /// Binary for every type but text.
+    pub collation: Collation,
 }
 
 impl Display for HeaderItem {
@@ -373,12 +966,19 @@ impl Display for HeaderItem {
             DbType::Float => printer.push('f'),
             DbType::Int => printer.push('i'),
             DbType::Text => printer.push('t'),
+            DbType::Duration => printer.push('d'),
+            DbType::LongText => printer.push('l'),
+            DbType::Enum => printer.push_str(&format!("e({})", print_sep_list(&self.values, "|"))),
         }
         match &self.key {
             TableKey::Primary => printer.push_str("-P"),
             TableKey::Foreign => printer.push_str("-F"),
             TableKey::None => printer.push_str("-N"),
         }
+        if self.collation != Collation::Binary {
+            printer.push('-');
+            printer.push_str(self.collation.name());
+        }
         write!(f, "{}", printer)
     }
 }
@@ -397,6 +997,12 @@ impl Cbor for HeaderItem {
         bytes.extend_from_slice(&self.name.to_cbor_bytes());
         bytes.extend_from_slice(&self.kind.to_cbor_bytes());
         bytes.extend_from_slice(&self.key.to_cbor_bytes());
+        if self.kind == DbType::Enum {
+            bytes.extend_from_slice(&self.values.to_cbor_bytes());
+        }
+        if self.kind == DbType::Text {
+            bytes.extend_from_slice(&self.collation.to_cbor_bytes());
+        }
         bytes
     }
 
@@ -413,9 +1019,21 @@ impl Cbor for HeaderItem {
         i += bytes_read;
         let (key, bytes_read) = <TableKey as Cbor>::from_cbor_bytes(&bytes[i..])?;
         i += bytes_read;
+        let mut values = Vec::new();
+        if kind == DbType::Enum {
+            let (read, bytes_read) = <Vec<KeyString> as Cbor>::from_cbor_bytes(&bytes[i..])?;
+            values = read;
+            i += bytes_read;
+        }
+        let mut collation = Collation::Binary;
+        if kind == DbType::Text {
+            let (read, bytes_read) = <Collation as Cbor>::from_cbor_bytes(&bytes[i..])?;
+            collation = read;
+            i += bytes_read;
+        }
         Ok(
             (
-                Self { name, kind, key },
+                Self { name, kind, key, values, collation },
                 i
             )
         )
@@ -428,6 +1046,42 @@ impl HeaderItem {
             name: KeyString::from("default_name"),
             kind: DbType::Text,
             key: TableKey::None,
+            values: Vec::new(),
+            collation: Collation::Binary,
+        }
+    }
+
+    /// An Enum column allowing these values. Values are text and can't be repeated.
+    pub fn new_enum(name: KeyString, key: TableKey, values: Vec<KeyString>) -> Result<HeaderItem, EzError> {
+        if values.is_empty() {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("Enum column '{}' needs at least one value", name)})
+        }
+        for (i, value) in values.iter().enumerate() {
+            if values[..i].contains(value) {
+                return Err(EzError{tag: ErrorTag::Structure, text: format!("Enum column '{}' lists '{}' more than once", name, value)})
+            }
+        }
+        if key == TableKey::Primary {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("Enum column '{}' can't be the primary key", name)})
+        }
+        Ok(HeaderItem{name, kind: DbType::Enum, key, values, collation: Collation::Binary})
+    }
+
+    /// What an Enum column stores for `value`.
+    pub fn enum_index(&self, value: &str) -> Result<i32, EzError> {
+        match self.values.iter().position(|allowed| allowed.as_str() == value) {
+            Some(index) => Ok(index as i32),
+            None => Err(EzError{tag: ErrorTag::Query, text: format!(
+                "'{}' is not allowed in column '{}'. Allowed values are: {}", value, self.name, print_sep_list(&self.values, ", ")
+            )}),
+        }
+    }
+
+    /// The value an Enum column stores as `index`.
+    pub fn enum_value(&self, index: i32) -> Result<KeyString, EzError> {
+        match usize::try_from(index).ok().and_then(|index| self.values.get(index)) {
+            Some(value) => Ok(*value),
+            None => Err(EzError{tag: ErrorTag::Structure, text: format!("Column '{}' holds {} which is not one of its {} enum values", self.name, index, self.values.len())}),
         }
     }
 }
@@ -472,71 +1126,320 @@ impl Cbor for TableKey {
 }
 
 
-/// This is the main data structure of EZDB. It represents a table as a list of columns.
-#[derive(Clone, Debug)]
-pub struct ColumnTable {
-    pub name: KeyString,
-    pub header: BTreeSet<HeaderItem>,
-    pub columns: BTreeMap<KeyString, DbColumn>,
-}
-
-impl PartialOrd for ColumnTable {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match self.name.partial_cmp(&other.name) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
+impl TableKey {
+    /// The lowercase name used in system tables and DESCRIBE results.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TableKey::Primary => "primary",
+            TableKey::Foreign => "foreign",
+            TableKey::None => "none",
         }
-        match self.header.partial_cmp(&other.header) {
-            Some(core::cmp::Ordering::Equal) => {}
-            ord => return ord,
+    }
+
+    pub fn from_name(name: &str) -> Result<TableKey, EzError> {
+        match name {
+            "primary" => Ok(TableKey::Primary),
+            "foreign" => Ok(TableKey::Foreign),
+            "none" => Ok(TableKey::None),
+            other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("'{}' is not a key type", other)}),
         }
-        self.columns.partial_cmp(&other.columns)
     }
 }
 
-impl PartialEq for ColumnTable {
-    fn eq(&self, other: &Self) -> bool {
-        self.header == other.header && self.columns == other.columns
-    }
+/// How a text column compares its values. Sorting and looking up the primary key, condition tests and joins
+/// all follow it. Only text columns can have a collation other than binary.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Collation {
+    /// Byte by byte, so 'Apple' and 'apple' differ and upper case sorts first.
+    #[default]
+    Binary,
+    /// ASCII letters compare without their case. The values are kept as they were written.
+    CaseInsensitive,
 }
 
-impl Cbor for ColumnTable {
+impl Cbor for Collation {
     fn to_cbor_bytes(&self) -> Vec<u8> {
-
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(&self.name.to_cbor_bytes());
-        bytes.extend_from_slice(&self.header.to_cbor_bytes());
-        bytes.extend_from_slice(&self.columns.to_cbor_bytes());
-        bytes
+        match self {
+            Collation::Binary => vec![0xc6],
+            Collation::CaseInsensitive => vec![0xc6+1],
+        }
     }
 
     fn from_cbor_bytes(bytes: &[u8]) -> Result<(Self, usize), CborError>
-        where 
-            Self: Sized 
+        where
+            Self: Sized
     {
-        
-
-        let mut i = 0;
-        
-        let (name, bytes_read) = <KeyString as Cbor>::from_cbor_bytes(&bytes[i..])?;
-        i += bytes_read;
-        let (header, bytes_read) = <BTreeSet<HeaderItem> as Cbor>::from_cbor_bytes(&bytes[i..])?;
-        i += bytes_read;
-        let (columns, bytes_read) = <BTreeMap<KeyString, DbColumn> as Cbor>::from_cbor_bytes(&bytes[i..])?;
-        i += bytes_read;
-        Ok(
-            (
-                Self { name, header, columns  },
-                i
-            )
-        )
+        match expected_data_item(bytes[0]) {
+            DataItem::Tag(0) => Ok((Collation::Binary, 1)),
+            DataItem::Tag(1) => Ok((Collation::CaseInsensitive, 1)),
+            _ => Err(CborError::Unexpected("Error originated from Collation implementation".to_owned())),
+        }
     }
 }
 
-/// Prints the ColumnTable as a csv (separated by semicolons ;)
-impl Display for ColumnTable {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        
+impl Collation {
+    /// The name written after the key in a csv header, as in "name,t-N-ci". Binary is not written.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Collation::Binary => "binary",
+            Collation::CaseInsensitive => "ci",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Collation, EzError> {
+        match name {
+            "binary" => Ok(Collation::Binary),
+            "ci" | "case_insensitive" => Ok(Collation::CaseInsensitive),
+            other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("'{}' is not a collation. Use binary or ci", other)}),
+        }
+    }
+
+    /// The text as this collation sees it. Texts that compare equal fold to the same text.
+    pub fn fold(&self, text: &KeyString) -> KeyString {
+        match self {
+            Collation::Binary => *text,
+            Collation::CaseInsensitive => text.to_ascii_lowercase(),
+        }
+    }
+
+    pub fn compare(&self, a: &KeyString, b: &KeyString) -> std::cmp::Ordering {
+        match self {
+            Collation::Binary => a.cmp(b),
+            Collation::CaseInsensitive => a.as_bytes().iter().map(u8::to_ascii_lowercase).cmp(b.as_bytes().iter().map(u8::to_ascii_lowercase)),
+        }
+    }
+}
+
+/// One column of a TableSchema.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnSchema {
+    pub name: KeyString,
+    pub kind: DbType,
+    pub key: TableKey,
+    /// Whether every value in the column is distinct. Only the primary key guarantees this.
+    pub unique: bool,
+}
+
+/// The shape of a table without its data. This is what the DESCRIBE query returns.
+/// It travels as a table with one row per column (see to_table()) and clients turn it back with from_table().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableSchema {
+    pub name: KeyString,
+    /// In the same order as the table header.
+    pub columns: Vec<ColumnSchema>,
+}
+
+impl TableSchema {
+    pub fn from_header(name: KeyString, header: &BTreeSet<HeaderItem>) -> TableSchema {
+        let columns = header.iter()
+            .map(|item| ColumnSchema{name: item.name, kind: item.kind, key: item.key, unique: item.key == TableKey::Primary})
+            .collect();
+        TableSchema{name, columns}
+    }
+
+    pub fn column(&self, name: &KeyString) -> Option<&ColumnSchema> {
+        self.columns.iter().find(|column| column.name == *name)
+    }
+
+    /// The DESCRIBE result: position, column_name, type, key, unique.
+    pub fn to_table(&self) -> Result<ColumnTable, EzError> {
+
+        let mut table = ColumnTable::create_empty(&format!("{}_description", self.name), "describe");
+        table.add_column(ksf("position"), DbColumn::Ints((0..self.columns.len() as i32).collect()))?;
+        table.add_column(ksf("column_name"), DbColumn::Texts(self.columns.iter().map(|c| c.name).collect()))?;
+        table.add_column(ksf("type"), DbColumn::Texts(self.columns.iter().map(|c| ksf(c.kind.name())).collect()))?;
+        table.add_column(ksf("key"), DbColumn::Texts(self.columns.iter().map(|c| ksf(c.key.name())).collect()))?;
+        table.add_column(ksf("unique"), DbColumn::Ints(self.columns.iter().map(|c| c.unique as i32).collect()))?;
+
+        Ok(table)
+    }
+
+    /// Reads a DESCRIBE result back. The name is not part of the result so the caller passes the one it asked for.
+    pub fn from_table(name: KeyString, table: &ColumnTable) -> Result<TableSchema, EzError> {
+
+        let positions = table.get_column_int(&ksf("position"))?;
+        let names = table.get_column_text(&ksf("column_name"))?;
+        let kinds = table.get_column_text(&ksf("type"))?;
+        let keys = table.get_column_text(&ksf("key"))?;
+        let unique = table.get_column_int(&ksf("unique"))?;
+
+        let mut rows: Vec<usize> = (0..table.len()).collect();
+        rows.sort_by_key(|row| positions[*row]);
+        let mut columns = Vec::with_capacity(rows.len());
+        for row in rows {
+            columns.push(ColumnSchema{
+                name: names[row],
+                kind: DbType::from_name(kinds[row].as_str())?,
+                key: TableKey::from_name(keys[row].as_str())?,
+                unique: unique[row] != 0,
+            });
+        }
+
+        Ok(TableSchema{name, columns})
+    }
+
+    /// Checks that a struct with the given fields can be read out of this table before any data is pulled.
+    /// Every field needs a column of the same name and type. Columns without a field are fine.
+    pub fn check_mapping(&self, fields: &[(KeyString, DbType)]) -> Result<(), EzError> {
+
+        let mut problems = Vec::new();
+        for (field, kind) in fields {
+            match self.column(field) {
+                Some(column) if column.kind == *kind => (),
+                Some(column) => problems.push(format!("Field '{}' expects {} but column '{}' of '{}' is {}", field, kind.name(), column.name, self.name, column.kind.name())),
+                None => problems.push(format!("Field '{}' has no matching column in '{}'", field, self.name)),
+            }
+        }
+
+        match problems.len() {
+            0 => Ok(()),
+            _ => Err(EzError{tag: ErrorTag::Deserialization, text: problems.join("\n")}),
+        }
+    }
+}
+
+/// When on, NaN in a float column is refused on CREATE, INSERT, UPDATE and bulk loads. Off by default.
+static REJECT_NAN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_reject_nan(reject: bool) {
+    REJECT_NAN.store(reject, Ordering::Relaxed);
+}
+
+pub fn reject_nan() -> bool {
+    REJECT_NAN.load(Ordering::Relaxed)
+}
+
+/// Refuses a table coming into the database if it holds NaN and reject_nan() is on.
+pub fn check_nan_ingest(table: &ColumnTable) -> Result<(), EzError> {
+    if !reject_nan() {
+        return Ok(())
+    }
+    let columns = table.nan_columns();
+    if columns.is_empty() {
+        Ok(())
+    } else {
+        Err(EzError{tag: ErrorTag::Query, text: format!("NaN is not accepted. Found NaN in column(s): {}", print_sep_list(&columns, ", "))})
+    }
+}
+
+/// What an INSERT does with rows whose primary key is already in the table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OnConflict {
+    /// Keeps the row in the table and drops the inserted one. The other rows are still inserted.
+    #[default]
+    Skip,
+    /// Inserts nothing and fails with ErrorTag::Conflict.
+    Error,
+    /// Replaces the row in the table with the inserted one, so the INSERT is an upsert.
+    Overwrite,
+}
+
+impl OnConflict {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OnConflict::Skip => "skip",
+            OnConflict::Error => "error",
+            OnConflict::Overwrite => "overwrite",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<OnConflict, EzError> {
+        match name {
+            "skip" => Ok(OnConflict::Skip),
+            "error" => Ok(OnConflict::Error),
+            "overwrite" => Ok(OnConflict::Overwrite),
+            other => Err(EzError{tag: ErrorTag::Query, text: format!("on_conflict is skip, error or overwrite but found '{}'", other)}),
+        }
+    }
+
+    /// How the policy is written in the handles of an INSERT query. Clients that don't know about policies send 0.
+    pub fn to_handle(&self) -> u64 {
+        match self {
+            OnConflict::Skip => 0,
+            OnConflict::Error => 1,
+            OnConflict::Overwrite => 2,
+        }
+    }
+
+    pub fn from_handle(handle: usize) -> Result<OnConflict, EzError> {
+        match handle {
+            0 => Ok(OnConflict::Skip),
+            1 => Ok(OnConflict::Error),
+            2 => Ok(OnConflict::Overwrite),
+            other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("{} is not an insert conflict policy", other)}),
+        }
+    }
+}
+
+/// This is the main data structure of EZDB. It represents a table as a list of columns.
+#[derive(Clone, Debug)]
+pub struct ColumnTable {
+    pub name: KeyString,
+    pub header: BTreeSet<HeaderItem>,
+    pub columns: BTreeMap<KeyString, DbColumn>,
+    pub metadata: Metadata,
+}
+
+impl PartialOrd for ColumnTable {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        match self.name.partial_cmp(&other.name) {
+            Some(core::cmp::Ordering::Equal) => {}
+            ord => return ord,
+        }
+        match self.header.partial_cmp(&other.header) {
+            Some(core::cmp::Ordering::Equal) => {}
+            ord => return ord,
+        }
+        self.columns.partial_cmp(&other.columns)
+    }
+}
+
+impl PartialEq for ColumnTable {
+    fn eq(&self, other: &Self) -> bool {
+        self.header == other.header && self.columns == other.columns
+    }
+}
+
+impl Cbor for ColumnTable {
+    fn to_cbor_bytes(&self) -> Vec<u8> {
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.name.to_cbor_bytes());
+        bytes.extend_from_slice(&self.header.to_cbor_bytes());
+        bytes.extend_from_slice(&self.columns.to_cbor_bytes());
+        bytes.extend_from_slice(&self.metadata.to_cbor_bytes());
+        bytes
+    }
+
+    fn from_cbor_bytes(bytes: &[u8]) -> Result<(Self, usize), CborError>
+        where 
+            Self: Sized 
+    {
+        
+
+        let mut i = 0;
+        
+        let (name, bytes_read) = <KeyString as Cbor>::from_cbor_bytes(&bytes[i..])?;
+        i += bytes_read;
+        let (header, bytes_read) = <BTreeSet<HeaderItem> as Cbor>::from_cbor_bytes(&bytes[i..])?;
+        i += bytes_read;
+        let (columns, bytes_read) = <BTreeMap<KeyString, DbColumn> as Cbor>::from_cbor_bytes(&bytes[i..])?;
+        i += bytes_read;
+        let (metadata, bytes_read) = <Metadata as Cbor>::from_cbor_bytes(&bytes[i..])?;
+        i += bytes_read;
+        Ok(
+            (
+                Self { name, header, columns, metadata },
+                i
+            )
+        )
+    }
+}
+
+/// Prints the ColumnTable as a csv (separated by semicolons ;)
+impl Display for ColumnTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        
 
         let mut printer = String::new();
 
@@ -548,13 +1451,20 @@ impl Display for ColumnTable {
         printer.push('\n');
 
         for i in 0..(self.len()) {
-            for vec in self.columns.values() {
+            for (item, vec) in self.header.iter().zip(self.columns.values()) {
                 match vec {
                     DbColumn::Floats(col) => {
                         // println!("float: col.len(): {}", col.len());
                         printer.push_str(&col[i].to_string());
                         printer.push(';');
                     }
+                    DbColumn::Ints(col) if item.kind == DbType::Enum => {
+                        match item.enum_value(col[i]) {
+                            Ok(value) => printer.push_str(value.as_str()),
+                            Err(_) => printer.push_str(&col[i].to_string()),
+                        }
+                        printer.push(';');
+                    }
                     DbColumn::Ints(col) => {
                         // println!("int: col.len(): {}", col.len());
                         printer.push_str(&col[i].to_string());
@@ -565,6 +1475,14 @@ impl Display for ColumnTable {
                         printer.push_str(col[i].as_str());
                         printer.push(';');
                     },
+                    DbColumn::Durations(col) => {
+                        printer.push_str(&format_duration(col[i]));
+                        printer.push(';');
+                    },
+                    DbColumn::LongTexts(col) => {
+                        printer.push_str(col.get(i));
+                        printer.push(';');
+                    },
                 }
             }
             printer.pop();
@@ -584,6 +1502,7 @@ impl ColumnTable {
             name: ksf(name),
             header: BTreeSet::new(),
             columns: BTreeMap::new(),
+            metadata: Metadata::new(created_by),
         }
     }
 
@@ -596,6 +1515,9 @@ impl ColumnTable {
                 DbType::Int => columns.insert(head.name, DbColumn::Ints(Vec::new())),
                 DbType::Float => columns.insert(head.name, DbColumn::Floats(Vec::new())),
                 DbType::Text => columns.insert(head.name, DbColumn::Texts(Vec::new())),
+                DbType::Duration => columns.insert(head.name, DbColumn::Durations(Vec::new())),
+                DbType::LongText => columns.insert(head.name, DbColumn::LongTexts(LongTexts::new())),
+                DbType::Enum => columns.insert(head.name, DbColumn::Ints(Vec::new())),
             };
         }
 
@@ -603,6 +1525,7 @@ impl ColumnTable {
             name: name,
             header: header.clone(),
             columns,
+            metadata: Metadata::new(created_by),
         }
 
     }
@@ -613,6 +1536,17 @@ impl ColumnTable {
         table_name: &str,
         created_by: &str,
     ) -> Result<ColumnTable, EzError> {
+        ColumnTable::from_csv_string_with_spec(s, table_name, created_by, &CsvImportSpec::new())
+    }
+
+    /// Same as from_csv_string but every cell of a column named in the spec is first rewritten from the
+    /// column's parse format, so numbers like "1.234,56" and dates can be imported without preprocessing.
+    pub fn from_csv_string_with_spec(
+        s: &str,
+        table_name: &str,
+        created_by: &str,
+        spec: &CsvImportSpec,
+    ) -> Result<ColumnTable, EzError> {
         
 
         /*
@@ -629,6 +1563,10 @@ impl ColumnTable {
         I, Int, int, or i for integer data (i32)
         F, Float, float, or f for floating point data (f32)
         T, Text, text, or t for text data (String, ax length 255)
+        D, Duration, duration, or d for spans of time written with units like 150ms or 2s (stored as i64 nanoseconds)
+        L, LongText, longtext, or l for text of any length. Can't be the primary key
+        E(a|b|c), Enum(a|b|c), enum(a|b|c), or e(a|b|c) for text that has to be one of the listed values. Stored as the
+        position of the value in the list. Values can't contain ( ) | , ; and can't be the primary key
 
         The key should be one of the three:
         P - This column will be treated as the primary key. There can be only one P column
@@ -647,60 +1585,15 @@ impl ColumnTable {
 
         If a value needs to contain a ";" character, you can enclose the calue in triple quotes """value"""
         Values will not be trimmed. Any whitespace will be included. Take care that the triple quotes are included in the 255 character limit for text values
-        if you need to store text values longer than 255 characters, reference them by foreign keys to key_value storage
+        if you need to store longer text values, use a LongText column or reference them by foreign keys to key_value storage
         */
 
         if s.is_empty() {
             return Err(EzError{tag: ErrorTag::Deserialization, text: ("Input string is empty".to_owned())});
         }
 
-        let mut header = Vec::new();
-        let mut primary_key_set = false;
-
-        let first_line: Vec<&str> = s
-            .split('\n')
-            .next()
-            .expect("confirmed to exist because of earlier check")
-            .split(';')
-            .collect();
-        for item in first_line {
-            let temp: Vec<&str> = item.split(',').collect();
-            let mut header_item = HeaderItem::new();
-            if temp.is_empty() {
-                return Err(EzError{tag: ErrorTag::Deserialization, text: ("Header is empty".to_owned())});
-            } else if temp.len() == 1 {
-                header_item.kind = DbType::Text;
-            } else if temp.len() > 2 {
-                return Err(EzError{tag: ErrorTag::Deserialization, text: ("Incorrectly formatted header".to_owned())});
-            } else {
-                header_item.name = KeyString::from(temp[0].trim());
-                let mut t = temp[1].trim().split('-');
-                let next = t.next().unwrap();
-                match next {
-                    "I" | "Int" | "int" | "i" => header_item.kind = DbType::Int,
-                    "F" | "Float" | "float" | "f" => header_item.kind = DbType::Float,
-                    "T" | "Text" | "text" | "t" => header_item.kind = DbType::Text,
-                    _ => return Err(EzError{tag: ErrorTag::Deserialization, text: (format!("Unsupported type: {}", next))}),
-                }
-                match t.next().unwrap() {
-                    "P" => {
-                        if primary_key_set {
-                            return Err(EzError{tag: ErrorTag::Deserialization, text: ("Too many primary keys specified".to_owned())});
-                        }
-                        header_item.key = TableKey::Primary;
-                        primary_key_set = true;
-                    }
-                    "N" => header_item.key = TableKey::None,
-                    "F" => header_item.key = TableKey::Foreign,
-                    _ => return Err(EzError{tag: ErrorTag::Deserialization, text: ("Unsupported key type".to_owned())}),
-                }
-            }
-            header.push(header_item);
-        }
-
-        if !primary_key_set {
-            panic!("You need to specify a primary key")
-        }
+        let header = parse_csv_header(s.split('\n').next().expect("confirmed to exist because of earlier check"))?;
+        spec.check_header(&header)?;
 
         let mut line_index = 0;
         let mut data: Vec<Vec<&str>> = Vec::new();
@@ -722,6 +1615,8 @@ impl ColumnTable {
 
         let mut result = BTreeMap::new();
         for (i, col) in data.into_iter().enumerate() {
+            let col = spec.normalize_column(&header[i], col)?;
+            let col: Vec<&str> = col.iter().map(|cell| cell.as_ref()).collect();
             let db_vec = match header.iter().nth(i).unwrap().kind {
                 DbType::Float => {
                     let mut outvec = Vec::with_capacity(col.len());
@@ -755,19 +1650,33 @@ impl ColumnTable {
                 DbType::Text => {
                     let mut outvec = Vec::with_capacity(col.len());
                     for cell in col {
-                        outvec.push(KeyString::from(cell));
+                        outvec.push(KeyString::from_input(cell)?);
                     }
                     DbColumn::Texts(outvec)
                 }
+                DbType::Duration => {
+                    let mut outvec = Vec::with_capacity(col.len());
+                    for cell in col {
+                        outvec.push(parse_duration(cell)?);
+                    }
+                    DbColumn::Durations(outvec)
+                }
+                DbType::LongText => DbColumn::LongTexts(col.iter().copied().collect()),
+                DbType::Enum => {
+                    let item = &header[i];
+                    DbColumn::Ints(col.iter().map(|cell| item.enum_index(cell)).collect::<Result<_, _>>()?)
+                },
             };
 
             result.insert(header.iter().nth(i).unwrap().name, db_vec);
         }
 
         let mut primary_key_index = None;
+        let mut key_collation = Collation::Binary;
         for item in header.iter() {
             if item.key == TableKey::Primary {
                 primary_key_index = Some(item.name);
+                key_collation = item.collation;
             }
         }
 
@@ -789,26 +1698,113 @@ impl ColumnTable {
             DbColumn::Texts(col) => {
                 let mut test_set = HashSet::new();
                 for item in col.iter() {
-                    if test_set.contains(item) {
+                    if !test_set.insert(key_collation.fold(item)) {
                         return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Primary key is not unique. Item {} is repeated", item)})
                     }
-                    test_set.insert(item);
                 }
             }
             DbColumn::Floats(_) => unreachable!("Should never have a float primary key. Something went wrong in the parsing csv code near column {} line{}. Abort and crash.", column!(), line!()),
+            DbColumn::Durations(_) => return Err(EzError{tag: ErrorTag::Deserialization, text: "The primary key can't be a duration column".to_owned()}),
+            DbColumn::LongTexts(_) => return Err(EzError{tag: ErrorTag::Deserialization, text: "The primary key can't be a long text column".to_owned()}),
         }
 
         let header: BTreeSet<HeaderItem> = header.iter().cloned().collect();
 
         let mut output = ColumnTable {
-            name: KeyString::from(table_name),
+            name: KeyString::from_input(table_name)?,
             header: header,
             columns: result,
+            metadata: Metadata::new(created_by),
         };
         output.sort();
         Ok(output)
     }
 
+    /// Parses a ColumnTable from a stream of EZ CSV one line at a time, so the raw text of a large file
+    /// never has to be held in memory. Rows are parsed into batches of CSV_IMPORT_BATCH_ROWS and the
+    /// primary key is checked for uniqueness as they are read.
+    pub fn from_csv_reader<R: BufRead>(
+        reader: R,
+        table_name: &str,
+        created_by: &str,
+    ) -> Result<ColumnTable, EzError> {
+        ColumnTable::from_csv_reader_with_spec(reader, table_name, created_by, &CsvImportSpec::new())
+    }
+
+    /// Same as from_csv_reader but applies the parse formats in the spec. See from_csv_string_with_spec.
+    pub fn from_csv_reader_with_spec<R: BufRead>(
+        mut reader: R,
+        table_name: &str,
+        created_by: &str,
+        spec: &CsvImportSpec,
+    ) -> Result<ColumnTable, EzError> {
+
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: ("Input string is empty".to_owned())});
+        }
+        let header = parse_csv_header(line.trim_end_matches(['\n', '\r']))?;
+        spec.check_header(&header)?;
+        let primary_key = header.iter().find(|item| item.key == TableKey::Primary).expect("parse_csv_header checks for a primary key");
+        match primary_key.kind {
+            DbType::Float => return Err(EzError{tag: ErrorTag::Deserialization, text: "The primary key can't be a float column".to_owned()}),
+            DbType::Duration => return Err(EzError{tag: ErrorTag::Deserialization, text: "The primary key can't be a duration column".to_owned()}),
+            DbType::LongText => return Err(EzError{tag: ErrorTag::Deserialization, text: "The primary key can't be a long text column".to_owned()}),
+            _ => (),
+        }
+        let key_collation = primary_key.collation;
+        let primary_key = primary_key.name;
+
+        let header_set: BTreeSet<HeaderItem> = header.iter().cloned().collect();
+        let name = KeyString::from_input(table_name)?;
+        let mut output = ColumnTable::blank(&header_set, name, created_by);
+        let mut batch = ColumnTable::blank(&header_set, name, created_by);
+
+        let mut int_keys = HashSet::new();
+        let mut text_keys = HashSet::new();
+        let mut line_number = 1;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            line_number += 1;
+            let row = line.trim_end_matches(['\n', '\r']);
+            if row.is_empty() {
+                continue;
+            }
+
+            let cells: Vec<&str> = row.split(';').collect();
+            if cells.len() != header.len() {
+                return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Line {} has {} values but the header has {} columns", line_number, cells.len(), header.len())})
+            }
+            for (item, cell) in header.iter().zip(cells) {
+                let column = batch.columns.get_mut(&item.name).expect("batch is built from the same header");
+                let cell = spec.normalize(item, cell)?;
+                push_csv_cell(item, column, &cell, line_number)?;
+                if item.name == primary_key {
+                    let is_new = match column {
+                        DbColumn::Ints(col) => int_keys.insert(col[col.len() - 1]),
+                        DbColumn::Texts(col) => text_keys.insert(key_collation.fold(&col[col.len() - 1])),
+                        _ => unreachable!("Checked above that the primary key is an int or text column"),
+                    };
+                    if !is_new {
+                        return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Primary key is not unique. Item {} on line {} is repeated", cell, line_number)})
+                    }
+                }
+            }
+
+            if batch.len() >= CSV_IMPORT_BATCH_ROWS {
+                let full_batch = std::mem::replace(&mut batch, ColumnTable::blank(&header_set, name, created_by));
+                output.extend_from_table(full_batch)?;
+            }
+        }
+        output.extend_from_table(batch)?;
+
+        output.sort();
+        Ok(output)
+    }
+
     /// Helper function to update a ColumnTable with a csv
     pub fn update_from_csv(&mut self, input_csv: &str) -> Result<(), EzError> {
         
@@ -820,37 +1816,68 @@ impl ColumnTable {
         Ok(())
     }
 
+    /// Inserts the rows whose primary key is new and drops the rest. See insert_on_conflict().
     pub fn insert(&mut self, inserts: ColumnTable) -> Result<(), EzError> {
-        
+        self.insert_on_conflict(inserts, OnConflict::Skip)
+    }
 
+    /// Inserts the rows and handles those whose primary key is already in the table as the policy says.
+    pub fn insert_on_conflict(&mut self, inserts: ColumnTable, on_conflict: OnConflict) -> Result<(), EzError> {
 
         let mut input_table = inserts;
 
-        let mut losers = Vec::new();
+        // Merging takes the incoming row wherever the keys are equal
+        if on_conflict == OnConflict::Overwrite {
+            if input_table.len() == 0 {
+                return Ok(())
+            }
+            return self.update(&input_table)
+        }
+
+        let losers = self.keys_present(&input_table);
+
+        if on_conflict == OnConflict::Error && !losers.is_empty() {
+            let key = input_table.columns[&input_table.get_primary_key_col_index()].value_at(losers[0])?;
+            return Err(EzError{tag: ErrorTag::Conflict, text: format!("{} of the inserted rows have a primary key that is already in '{}', the first is {}. Nothing was inserted", losers.len(), self.name, key)})
+        }
+
+        input_table.delete_by_indexes(&losers);
+
+        // Every key was already in the table
+        if input_table.len() == 0 {
+            return Ok(())
+        }
+
+        self.update(&input_table)?;
+
+        Ok(())
+    }
 
-        match &input_table.columns[&input_table.get_primary_key_col_index()] {
+    /// The rows of `inserts` whose primary key is already in the table.
+    pub fn keys_present(&self, inserts: &ColumnTable) -> Vec<usize> {
+        let mut present = Vec::new();
+
+        match &inserts.columns[&inserts.get_primary_key_col_index()] {
             DbColumn::Ints(column) => {
-                for item in column {
-                    if let Some(index) = self.contains_key_i32(*item) {
-                        losers.push(index);
+                for (index, item) in column.iter().enumerate() {
+                    if self.contains_key_i32(*item).is_some() {
+                        present.push(index);
                     }
                 }
             },
             DbColumn::Texts(column) => {
-                for item in column {
-                    if let Some(index) = self.contains_key_string(*item) {
-                        losers.push(index);
+                for (index, item) in column.iter().enumerate() {
+                    if self.contains_key_string(*item).is_some() {
+                        present.push(index);
                     }
                 }
             },
             DbColumn::Floats(_column) => unreachable!("There should never be a float primary key"),
+            DbColumn::Durations(_column) => unreachable!("There should never be a duration primary key"),
+            DbColumn::LongTexts(_column) => unreachable!("There should never be a long text primary key"),
         }
 
-        input_table.delete_by_indexes(&losers);
-
-        self.update(&input_table)?;
-
-        Ok(())
+        present
     }
 
     pub fn contains_key_i32(&self, key: i32) -> Option<usize> {
@@ -870,12 +1897,10 @@ impl ColumnTable {
 
     pub fn contains_key_string(&self, key: KeyString) -> Option<usize> {
         
+        let collation = self.key_collation();
         match &self.columns[&self.get_primary_key_col_index()] {
             DbColumn::Texts(column) => {
-                match column.binary_search(&key) {
-                    Ok(x) => Some(x),
-                    Err(_) => None,
-                }
+                column.binary_search_by(|probe| collation.compare(probe, &key)).ok()
             },
            _ => unreachable!("Already checked the key type earlier")
         }
@@ -883,20 +1908,15 @@ impl ColumnTable {
 
     
 
+    /// Bytes the table holds in memory. This is what the buffer pool budget, the metrics, the namespace
+    /// quotas and idle unloading all count. Unused capacity and the nodes of the maps are not counted.
     pub fn byte_size(&self) -> usize {
 
-        let mut total = 0;
-        
-        for item in &self.header {
-            total += item.name.as_bytes().len();
-            total += 16; // DbType and TableKey are both raw enums which are 8 bytes in memory for the tag.
-        }
+        let mut total = size_of::<ColumnTable>();
+        total += self.header.len() * size_of::<HeaderItem>();
+        total += self.header.iter().map(|item| item.values.len() * size_of::<KeyString>()).sum::<usize>();
         for column in self.columns.values() {
-            match column {
-                DbColumn::Ints(c) => total += c.len() * 4,
-                DbColumn::Floats(c) => total += c.len() * 4,
-                DbColumn::Texts(c) => total += c.len() * 64,
-            }
+            total += size_of::<KeyString>() + size_of::<DbColumn>() + column.byte_size();
         }
         total
     }
@@ -922,9 +1942,21 @@ impl ColumnTable {
             DbColumn::Ints(_) => DbType::Int,
             DbColumn::Texts(_) => DbType::Text,
             DbColumn::Floats(_) => unreachable!("There should never be a float primary key"),
+            DbColumn::Durations(_) => unreachable!("There should never be a duration primary key"),
+            DbColumn::LongTexts(_) => unreachable!("There should never be a long text primary key"),
         }
     }
 
+    /// How the primary key is sorted and looked up. Binary for int keys.
+    pub fn key_collation(&self) -> Collation {
+        self.collation_of(&self.get_primary_key_col_index())
+    }
+
+    /// Binary for columns that aren't in the table.
+    pub fn collation_of(&self, column: &KeyString) -> Collation {
+        self.header.iter().find(|item| item.name == *column).map_or(Collation::Binary, |item| item.collation)
+    }
+
     /// Updates a ColumnTable. Overwrites existing keys and adds new ones in proper order
     pub fn update(&mut self, other_table: &ColumnTable) -> Result<(), EzError> {
         
@@ -932,11 +1964,32 @@ impl ColumnTable {
             return Err(EzError{tag: ErrorTag::Query, text: "Can't update anything with an empty table".to_owned()})
         }
 
+        // The incoming rows never bring their own timestamps, ids or versions. Overwritten rows keep their creation time
+        // and id and have their version bumped below.
+        let stamped;
+        let other_table = if self.has_row_timestamps() || self.has_row_ids() || self.has_row_versions() {
+            let mut copy = other_table.clone();
+            if self.has_row_timestamps() {
+                copy.set_row_timestamps(get_current_time())?;
+            }
+            if self.has_row_ids() {
+                copy.set_row_ids(self.next_row_id())?;
+            }
+            if self.has_row_versions() {
+                copy.set_row_versions()?;
+            }
+            stamped = copy;
+            &stamped
+        } else {
+            other_table
+        };
+
         if self.header != other_table.header {
             return Err(EzError{tag: ErrorTag::Query, text: "Headers don't match".to_owned()})
         }
 
         let self_primary_key_index = self.get_primary_key_col_index();
+        let key_collation = self.key_collation();
 
         let record_vec: Vec<u8>;
         match self.columns.get_mut(&self_primary_key_index).unwrap() {
@@ -950,11 +2003,13 @@ impl ColumnTable {
             DbColumn::Texts(col) => match &other_table.columns[&self_primary_key_index] {
                 DbColumn::Texts(other_col) => {
                     
-                    (*col, record_vec) = merge_sorted(col, other_col);
+                    (*col, record_vec) = merge_sorted_by(col, other_col, |a, b| key_collation.compare(a, b));
                 }
                 _ => unreachable!("Should always have the same primary key column"),
             },
             DbColumn::Floats(_) => unreachable!("Should never have a float primary key column"),
+            DbColumn::Durations(_) => unreachable!("Should never have a duration primary key column"),
+            DbColumn::LongTexts(_) => unreachable!("Should never have a long text primary key column"),
         }
 
         let pk = self.get_primary_key_col_index();
@@ -981,12 +2036,221 @@ impl ColumnTable {
                     }
                     _ => unreachable!("Should always have the same type column"),
                 },
+                DbColumn::Durations(col) => match &other_table.columns[key] {
+                    DbColumn::Durations(other_col) if key.as_str() == CREATED_AT_COLUMN || key.as_str() == ROW_ID_COLUMN => {
+                        *col = merge_in_order_keeping_existing(col, other_col, &record_vec);
+                    }
+                    DbColumn::Durations(other_col) if key.as_str() == ROW_VERSION_COLUMN => {
+                        *col = merge_versions(col, other_col, &record_vec);
+                    }
+                    DbColumn::Durations(other_col) => {
+                        *col = merge_in_order(col, other_col, &record_vec);
+                    }
+                    _ => unreachable!("Should always have the same type column"),
+                },
+                DbColumn::LongTexts(col) => match &other_table.columns[key] {
+                    DbColumn::LongTexts(other_col) => {
+                        *col = col.merged_in_order(other_col, &record_vec);
+                    }
+                    _ => unreachable!("Should always have the same type column"),
+                },
             }
         }
 
         Ok(())
     }
 
+    /// Turns the Text columns that are LongText columns in `header` into LongText columns.
+    /// The primary key is left alone since it can't be a LongText column.
+    pub fn widen_texts_to(&mut self, header: &BTreeSet<HeaderItem>) {
+        for item in header.iter().filter(|item| item.kind == DbType::LongText) {
+            let existing = match self.header.iter().find(|existing| existing.name == item.name && existing.kind == DbType::Text && existing.key != TableKey::Primary) {
+                Some(existing) => existing.clone(),
+                None => continue,
+            };
+            if let Some(DbColumn::Texts(col)) = self.columns.get(&item.name) {
+                let widened = DbColumn::LongTexts(col.iter().map(|value| value.as_str()).collect());
+                self.columns.insert(item.name, widened);
+                self.header.remove(&existing);
+                self.header.insert(HeaderItem{kind: DbType::LongText, collation: Collation::Binary, ..existing});
+            }
+        }
+    }
+
+    /// Turns the columns that are Enum columns in `header` into the positions of their values there.
+    /// Text columns are looked up by value. Enum columns with other values are mapped over by value.
+    pub fn encode_enums_to(&mut self, header: &BTreeSet<HeaderItem>) -> Result<(), EzError> {
+        for item in header.iter().filter(|item| item.kind == DbType::Enum) {
+            let existing = match self.header.iter().find(|existing| existing.name == item.name) {
+                Some(existing) if existing.kind == DbType::Enum && existing.values == item.values => continue,
+                Some(existing) => existing.clone(),
+                None => continue,
+            };
+            let encoded = match (existing.kind, self.columns.get(&item.name)) {
+                (DbType::Text, Some(DbColumn::Texts(col))) => col.iter()
+                    .map(|value| item.enum_index(value.as_str()))
+                    .collect::<Result<Vec<i32>, EzError>>()?,
+                (DbType::Enum, Some(DbColumn::Ints(col))) => col.iter()
+                    .map(|index| existing.enum_value(*index).and_then(|value| item.enum_index(value.as_str())))
+                    .collect::<Result<Vec<i32>, EzError>>()?,
+                _ => continue,
+            };
+            self.columns.insert(item.name, DbColumn::Ints(encoded));
+            self.header.remove(&existing);
+            self.header.insert(HeaderItem{kind: DbType::Enum, values: item.values.clone(), collation: Collation::Binary, ..existing});
+        }
+        Ok(())
+    }
+
+    /// Turns every Enum column into a Text column of its values. Query results are sent this way.
+    pub fn decode_enums(&mut self) -> Result<(), EzError> {
+        let enums: Vec<HeaderItem> = self.header.iter().filter(|item| item.kind == DbType::Enum).cloned().collect();
+        for item in enums {
+            if let Some(DbColumn::Ints(col)) = self.columns.get(&item.name) {
+                let values = col.iter().map(|index| item.enum_value(*index)).collect::<Result<Vec<KeyString>, EzError>>()?;
+                self.columns.insert(item.name, DbColumn::Texts(values));
+            }
+            self.header.remove(&item);
+            self.header.insert(HeaderItem{kind: DbType::Text, values: Vec::new(), ..item});
+        }
+        Ok(())
+    }
+
+    pub fn has_row_timestamps(&self) -> bool {
+        self.columns.contains_key(&ksf(CREATED_AT_COLUMN)) && self.columns.contains_key(&ksf(UPDATED_AT_COLUMN))
+    }
+
+    /// Adds the engine maintained __created_at and __updated_at columns. Existing rows are stamped with the current time.
+    /// From then on insert(), update() and UPDATE queries keep them current.
+    pub fn add_row_timestamps(&mut self) -> Result<(), EzError> {
+        if self.has_row_timestamps() {
+            return Ok(())
+        }
+        self.set_row_timestamps(get_current_time())
+    }
+
+    /// Sets both timestamps of every row to `now`, adding the columns if they are missing.
+    fn set_row_timestamps(&mut self, now: u64) -> Result<(), EzError> {
+        let stamp = epoch_nanos(now);
+        for name in [CREATED_AT_COLUMN, UPDATED_AT_COLUMN] {
+            let column = DbColumn::Durations(vec![stamp; self.len()]);
+            match self.columns.get_mut(&ksf(name)) {
+                Some(existing) => *existing = column,
+                None => self.add_column(ksf(name), column)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the rows at the given indexes as changed at `now`. Does nothing on tables without row timestamps.
+    pub fn touch_rows(&mut self, indexes: &[usize], now: u64) {
+        if let Some(DbColumn::Durations(updated)) = self.columns.get_mut(&ksf(UPDATED_AT_COLUMN)) {
+            let stamp = epoch_nanos(now);
+            for index in indexes {
+                updated[*index] = stamp;
+            }
+        }
+    }
+
+    pub fn has_row_ids(&self) -> bool {
+        self.columns.contains_key(&ksf(ROW_ID_COLUMN))
+    }
+
+    /// Adds the engine maintained __row_id column. Existing rows are numbered from 1 in their current order.
+    /// From then on insert() and update() give every new row the next id.
+    pub fn add_row_ids(&mut self) -> Result<(), EzError> {
+        if self.has_row_ids() {
+            return Ok(())
+        }
+        self.set_row_ids(1)
+    }
+
+    /// Numbers the rows from `first` up, adding the column if it is missing.
+    fn set_row_ids(&mut self, first: i64) -> Result<(), EzError> {
+        let column = DbColumn::Durations((first..first + self.len() as i64).collect());
+        match self.columns.get_mut(&ksf(ROW_ID_COLUMN)) {
+            Some(existing) => *existing = column,
+            None => self.add_column(ksf(ROW_ID_COLUMN), column)?,
+        }
+        Ok(())
+    }
+
+    /// The id the next inserted row gets. This is one more than the highest id in the table so if the newest
+    /// rows are deleted their ids are handed out again.
+    pub fn next_row_id(&self) -> i64 {
+        match self.columns.get(&ksf(ROW_ID_COLUMN)) {
+            Some(DbColumn::Durations(ids)) => ids.iter().max().map(|max| max + 1).unwrap_or(1),
+            _ => 1,
+        }
+    }
+
+    /// Where the row with the given id currently is.
+    pub fn row_id_index(&self, id: i64) -> Option<usize> {
+        match self.columns.get(&ksf(ROW_ID_COLUMN)) {
+            Some(DbColumn::Durations(ids)) => ids.iter().position(|x| *x == id),
+            _ => None,
+        }
+    }
+
+    pub fn has_row_versions(&self) -> bool {
+        self.columns.contains_key(&ksf(ROW_VERSION_COLUMN))
+    }
+
+    /// Adds the engine maintained __version column. Existing rows start at version 1.
+    /// From then on insert(), update() and UPDATE queries bump the version of every row they write.
+    pub fn add_row_versions(&mut self) -> Result<(), EzError> {
+        if self.has_row_versions() {
+            return Ok(())
+        }
+        self.set_row_versions()
+    }
+
+    /// Sets every row to version 1, adding the column if it is missing.
+    fn set_row_versions(&mut self) -> Result<(), EzError> {
+        let column = DbColumn::Durations(vec![1; self.len()]);
+        match self.columns.get_mut(&ksf(ROW_VERSION_COLUMN)) {
+            Some(existing) => *existing = column,
+            None => self.add_column(ksf(ROW_VERSION_COLUMN), column)?,
+        }
+        Ok(())
+    }
+
+    /// Adds one to the version of the rows at the given indexes. Does nothing on tables without row versions.
+    pub fn bump_row_versions(&mut self, indexes: &[usize]) {
+        if let Some(DbColumn::Durations(versions)) = self.columns.get_mut(&ksf(ROW_VERSION_COLUMN)) {
+            for index in indexes {
+                versions[*index] += 1;
+            }
+        }
+    }
+
+    /// Fails with ErrorTag::Conflict, naming the first row that differs, unless every row at the given indexes
+    /// is at the expected version.
+    pub fn check_row_versions(&self, indexes: &[usize], expected: i64) -> Result<(), EzError> {
+        let versions = match self.columns.get(&ksf(ROW_VERSION_COLUMN)) {
+            Some(DbColumn::Durations(versions)) => versions,
+            _ => return Err(EzError{tag: ErrorTag::Query, text: format!("Table '{}' has no row versions to check", self.name)}),
+        };
+        for index in indexes {
+            if versions[*index] != expected {
+                let key = match &self.columns[&self.get_primary_key_col_index()] {
+                    DbColumn::Ints(keys) => keys[*index].to_string(),
+                    DbColumn::Texts(keys) => keys[*index].to_string(),
+                    _ => index.to_string(),
+                };
+                return Err(EzError{tag: ErrorTag::Conflict, text: format!("Row '{}' is at version {} but the write expected version {}", key, versions[*index], expected)})
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the __row_id column from a result that didn't ask for it.
+    pub fn hide_row_ids(&mut self) {
+        if self.columns.remove(&ksf(ROW_ID_COLUMN)).is_some() {
+            self.header.retain(|item| item.name.as_str() != ROW_ID_COLUMN);
+        }
+    }
+
     pub fn key_index(&self, key: &KeyString) -> Option<usize> {
         
 
@@ -998,12 +2262,12 @@ impl ColumnTable {
                 }
             },
             DbColumn::Texts(column) => {
-                match column.binary_search(key) {
-                    Ok(x) => Some(x),
-                    Err(_) => None
-                }
+                let collation = self.key_collation();
+                column.binary_search_by(|probe| collation.compare(probe, key)).ok()
             },
             DbColumn::Floats(_) => unreachable!("There should never be a float primary key"),
+            DbColumn::Durations(_) => unreachable!("There should never be a duration primary key"),
+            DbColumn::LongTexts(_) => unreachable!("There should never be a long text primary key"),
         }
     }
 
@@ -1015,6 +2279,8 @@ impl ColumnTable {
                 DbColumn::Floats(col) => col.len(),
                 DbColumn::Ints(col) => col.len(),
                 DbColumn::Texts(col) => col.len(),
+                DbColumn::Durations(col) => col.len(),
+                DbColumn::LongTexts(col) => col.len(),
             },
             None => 0,
         }
@@ -1029,6 +2295,7 @@ impl ColumnTable {
         let mut indexer: Vec<usize> = (0..len).collect();
 
         let primary_index = self.get_primary_key_col_index();
+        let collation = self.key_collation();
 
         let vec = self.columns.get_mut(&primary_index).unwrap();
         match vec {
@@ -1036,9 +2303,11 @@ impl ColumnTable {
                 indexer.sort_unstable_by_key(|&i| col[i]);
             }
             DbColumn::Texts(col) => {
-                indexer.sort_unstable_by_key(|&i| &col[i]);
+                indexer.sort_unstable_by(|&a, &b| collation.compare(&col[a], &col[b]));
             }
             DbColumn::Floats(_) => unreachable!("There should never be a float primary key"),
+            DbColumn::Durations(_) => unreachable!("There should never be a duration primary key"),
+            DbColumn::LongTexts(_) => unreachable!("There should never be a long text primary key"),
         }
 
         for column in self.columns.iter_mut() {
@@ -1046,6 +2315,8 @@ impl ColumnTable {
                 DbColumn::Floats(col) => rearrange_by_index(col, &indexer),
                 DbColumn::Ints(col) => rearrange_by_index(col, &indexer),
                 DbColumn::Texts(col) => rearrange_by_index(col, &indexer),
+                DbColumn::Durations(col) => rearrange_by_index(col, &indexer),
+                DbColumn::LongTexts(col) => *col = col.select(&indexer),
             }
         };
     }
@@ -1073,6 +2344,12 @@ impl ColumnTable {
                     let item = &col[index];
                     output.push_str(item.as_str());
                 }
+                DbColumn::Durations(col) => {
+                    output.push_str(&format_duration(col[index]));
+                }
+                DbColumn::LongTexts(col) => {
+                    output.push_str(col.get(index));
+                }
             }
 
             output.push(';');
@@ -1082,13 +2359,55 @@ impl ColumnTable {
         Ok(output)
     }
     
+    pub fn schema(&self) -> TableSchema {
+        TableSchema::from_header(self.name, &self.header)
+    }
+
+    /// Inserts the rows the same way as an INSERT query, so rows whose key is already in the table are skipped.
+    /// T has to have a field for every column except the row ids, timestamps and versions the table adds itself.
+    pub fn insert_rows<T: Row>(&mut self, rows: &[T]) -> Result<(), EzError> {
+
+        let fields = T::columns();
+        self.schema().check_mapping(&fields)?;
+        let header: BTreeSet<HeaderItem> = self.header.iter().filter(|item| !is_engine_column(&item.name)).cloned().collect();
+        if let Some(item) = header.iter().find(|item| fields.iter().all(|(name, _)| *name != item.name)) {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("Rows inserted into '{}' need a field for column '{}'", self.name, item.name)})
+        }
+
+        let mut inserts = ColumnTable::blank(&header, self.name, "rows");
+        for row in rows {
+            for ((name, _), value) in fields.iter().zip(row.to_row()) {
+                if let Some(column) = inserts.columns.get_mut(name) {
+                    column.push_value(value)?;
+                }
+            }
+        }
+        inserts.sort();
+        check_nan_ingest(&inserts)?;
+
+        self.insert(inserts)
+    }
+
+    /// Reads every row into T after checking that T fits the schema. Columns without a field are skipped.
+    pub fn rows_as<T: Row>(&self) -> Result<Vec<T>, EzError> {
+
+        let fields = T::columns();
+        self.schema().check_mapping(&fields)?;
+        let columns: Vec<&DbColumn> = fields.iter().map(|(name, _)| &self.columns[name]).collect();
+
+        (0..self.len()).map(|index| {
+            let values = columns.iter().map(|column| column.value_at(index)).collect::<Result<Vec<_>, _>>()?;
+            T::from_row(&values)
+        }).collect()
+    }
+
     pub fn get_column_int<'a>(&'a self, index: &KeyString) -> Result<&'a Vec<i32>, EzError> {
         match self.columns.get(index) {
             Some(dbcol) => match dbcol {
                 DbColumn::Ints(column) => Ok(column),
                 _ => Err(EzError{tag: ErrorTag::Structure, text: "Wrong column type".to_owned()}),
             },
-            None => Err(EzError{tag: ErrorTag::Structure, text: format!("No such column as {}", index)})
+            None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No such column as {}", index)})
         }
 
     }
@@ -1099,7 +2418,7 @@ impl ColumnTable {
                 DbColumn::Texts(column) => Ok(column),
                 _ => Err(EzError{tag: ErrorTag::Structure, text: "Wrong column type".to_owned()}),
             },
-            None => Err(EzError{tag: ErrorTag::Structure, text: format!("No such column as {}", index)})
+            None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No such column as {}", index)})
         }
 
     }
@@ -1112,52 +2431,97 @@ impl ColumnTable {
                 DbColumn::Floats(column) => Ok(column),
                 _ => Err(EzError{tag: ErrorTag::Structure, text: "Wrong column type".to_owned()}),
             },
-            None => Err(EzError{tag: ErrorTag::Structure, text: format!("No such column as {}", index)})
+            None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No such column as {}", index)})
+        }
+
+    }
+
+    pub fn get_column_duration<'a>(&'a self, index: &KeyString) -> Result<&'a Vec<i64>, EzError> {
+
+        match self.columns.get(index) {
+            Some(dbcol) => match dbcol {
+                DbColumn::Durations(column) => Ok(column),
+                _ => Err(EzError{tag: ErrorTag::Structure, text: "Wrong column type".to_owned()}),
+            },
+            None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No such column as {}", index)})
+        }
+
+    }
+
+    pub fn get_column_long_text<'a>(&'a self, index: &KeyString) -> Result<&'a LongTexts, EzError> {
+
+        match self.columns.get(index) {
+            Some(dbcol) => match dbcol {
+                DbColumn::LongTexts(column) => Ok(column),
+                _ => Err(EzError{tag: ErrorTag::Structure, text: "Wrong column type".to_owned()}),
+            },
+            None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No such column as {}", index)})
         }
 
     }
 
     pub fn subtable_from_indexes(&self, indexes: &[usize], new_name: &KeyString) -> ColumnTable {
-        
-        let mut result_columns = BTreeMap::new();
+        let columns: Vec<KeyString> = self.columns.keys().copied().collect();
+        self.copy_rows(&columns, indexes, new_name)
+    }
 
-        for (key, column) in self.columns.iter() {
-            for index in indexes {
-                assert!(*index < self.len());
-                match column {
-                    DbColumn::Ints(column) => {
-                        let mut temp = Vec::with_capacity(indexes.len());
-                        for index in indexes {
-                            temp.push(column[*index]);
-                        }
-                        result_columns.insert(*key, DbColumn::Ints(temp));
-                    },
-                    DbColumn::Floats(column) => {
-                        let mut temp = Vec::with_capacity(indexes.len());
-                        for index in indexes {
-                            temp.push(column[*index]);
-                        }
-                        result_columns.insert(*key, DbColumn::Floats(temp));
-                    },
-                    DbColumn::Texts(column) => {
-                        let mut temp = Vec::with_capacity(indexes.len());
-                        for index in indexes {
-                            temp.push(column[*index]);
-                        }
-                        result_columns.insert(*key, DbColumn::Texts(temp));
-                    },
-                }
-            }
+    /// The given rows of the given columns. Only the kept rows are copied, so a filtered query costs the size
+    /// of its result rather than the size of the table. Columns follow the rules of subtable_from_columns().
+    pub fn select_rows(&self, columns: &[KeyString], indexes: &[usize], new_name: &KeyString) -> Result<ColumnTable, EzError> {
+        if columns.is_empty() {
+            return Err(EzError{tag: ErrorTag::Query, text: "No columns specified. If you want all columns, us '*'".to_owned()})
+        }
+        if columns[0].as_str() == "*" {
+            return Ok(self.subtable_from_indexes(indexes, new_name))
+        }
+        if let Some(missing) = columns.iter().find(|column| !self.columns.contains_key(*column)) {
+            return Err(EzError{tag: ErrorTag::NotFound, text: format!("No such column as {}", missing)})
+        }
+        Ok(self.copy_rows(columns, indexes, new_name))
+    }
+
+    fn copy_rows(&self, columns: &[KeyString], indexes: &[usize], new_name: &KeyString) -> ColumnTable {
+        let _phase = alloc_stats::enter(AllocPhase::Subtable);
+        for index in indexes {
+            assert!(*index < self.len());
+        }
+
+        let mut result_columns = BTreeMap::new();
+        for key in columns {
+            let column = &self.columns[key];
+            #[cfg(test)]
+            CELLS_COPIED.with(|cells| cells.set(cells.get() + indexes.len()));
+            let copied = match column {
+                DbColumn::Ints(column) => DbColumn::Ints(indexes.iter().map(|index| column[*index]).collect()),
+                DbColumn::Floats(column) => DbColumn::Floats(indexes.iter().map(|index| column[*index]).collect()),
+                DbColumn::Texts(column) => DbColumn::Texts(indexes.iter().map(|index| column[*index]).collect()),
+                DbColumn::Durations(column) => DbColumn::Durations(indexes.iter().map(|index| column[*index]).collect()),
+                DbColumn::LongTexts(column) => DbColumn::LongTexts(column.select(indexes)),
+            };
+            result_columns.insert(*key, copied);
         }
 
         ColumnTable {
             name: *new_name,
-            header: self.header.clone(),
+            header: self.header.iter().filter(|item| result_columns.contains_key(&item.name)).cloned().collect(),
             columns: result_columns,
+            metadata: self.metadata.inherit(),
+        }
+    }
+
+    /// A copy with only the given columns. Names that aren't columns of the table are skipped.
+    /// The copy keeps the name and metadata so queries on it report errors the same way as on the whole table.
+    pub fn copy_columns(&self, columns: &BTreeSet<KeyString>) -> ColumnTable {
+        ColumnTable {
+            name: self.name,
+            header: self.header.iter().filter(|item| columns.contains(&item.name)).cloned().collect(),
+            columns: self.columns.iter().filter(|(name, _)| columns.contains(name)).map(|(name, column)| (*name, column.clone())).collect(),
+            metadata: self.metadata.inherit(),
         }
     }
 
     pub fn subtable_from_columns(&self, columns: &[KeyString], new_name: &str) -> Result<ColumnTable, EzError> {
+        let _phase = alloc_stats::enter(AllocPhase::Subtable);
         
 
         let mut new_table_inner = BTreeMap::new();
@@ -1168,11 +2532,14 @@ impl ColumnTable {
         }
 
         if columns[0].as_str() == "*" || columns[0].as_str() == "*" {
+            #[cfg(test)]
+            CELLS_COPIED.with(|cells| cells.set(cells.get() + self.len() * self.columns.len()));
             return Ok(
                 ColumnTable {
                     name: KeyString::from(new_name),
                     header: self.header.clone(),
                     columns: self.columns.clone(),
+                    metadata: self.metadata.inherit(),
                 }
             )
         }
@@ -1180,6 +2547,8 @@ impl ColumnTable {
         for column in columns {
             match self.columns.get(column) {
                 Some(col) => {
+                    #[cfg(test)]
+                    CELLS_COPIED.with(|cells| cells.set(cells.get() + col.len()));
                     new_table_inner.insert(*column, col.clone());
                     let header_item = self.header
                         .iter()
@@ -1188,7 +2557,7 @@ impl ColumnTable {
                         .clone();
                     new_table_header.insert(header_item);
                 },
-                None => return Err(EzError{tag: ErrorTag::Query, text: format!("No such column as {}", column)})
+                None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No such column as {}", column)})
             };
         }
 
@@ -1197,6 +2566,7 @@ impl ColumnTable {
                 name: KeyString::from(new_name),
                 header: new_table_header,
                 columns: new_table_inner,
+                metadata: self.metadata.inherit(),
             }
         )
     }
@@ -1211,6 +2581,7 @@ impl ColumnTable {
             name: KeyString::from("none"),
             header: target.header.clone(),
             columns: BTreeMap::new(),
+            metadata: target.metadata.inherit(),
         };
 
         let mut temp_tree = BTreeMap::new();
@@ -1219,6 +2590,9 @@ impl ColumnTable {
                 DbType::Int => temp_tree.insert(item.name, DbColumn::Ints(Vec::with_capacity(line_keys.len()))),
                 DbType::Float => temp_tree.insert(item.name, DbColumn::Floats(Vec::with_capacity(line_keys.len()))),
                 DbType::Text => temp_tree.insert(item.name, DbColumn::Texts(Vec::with_capacity(line_keys.len()))),
+                DbType::Duration => temp_tree.insert(item.name, DbColumn::Durations(Vec::with_capacity(line_keys.len()))),
+                DbType::LongText => temp_tree.insert(item.name, DbColumn::LongTexts(LongTexts::with_capacity(line_keys.len()))),
+                DbType::Enum => temp_tree.insert(item.name, DbColumn::Ints(Vec::with_capacity(line_keys.len()))),
             };
         }
 
@@ -1246,8 +2620,9 @@ impl ColumnTable {
                     DbColumn::Texts(col) => col,
                     _ => return Err(EzError{tag: ErrorTag::Structure, text: "Source and target table do not have matching primary key types".to_owned()}),
                 };
+                let collation = self.key_collation();
                 for key in col {
-                    match source_col.binary_search(key) {
+                    match source_col.binary_search_by(|probe| collation.compare(probe, key)) {
                         Ok(i) => indexes.push(i),
                         Err(_) => continue,
                     }
@@ -1282,6 +2657,22 @@ impl ColumnTable {
                         }
                     }
                 },
+                DbColumn::Durations(col) => {
+                    for index in &indexes {
+                        match temp_table.columns.get_mut(key).unwrap() {
+                            DbColumn::Durations(temp) => temp.push(col[*index]),
+                            _ => unreachable!("Source and target column should always have the same type"),
+                        }
+                    }
+                },
+                DbColumn::LongTexts(col) => {
+                    for index in &indexes {
+                        match temp_table.columns.get_mut(key).unwrap() {
+                            DbColumn::LongTexts(temp) => temp.push(col.get(*index)),
+                            _ => unreachable!("Source and target column should always have the same type"),
+                        }
+                    }
+                },
             }
         }
 
@@ -1312,6 +2703,12 @@ impl ColumnTable {
                 DbColumn::Texts(column) => {
                     subtable.insert(*key, DbColumn::Texts(column[start..stop].to_vec()));
                 },
+                DbColumn::Durations(column) => {
+                    subtable.insert(*key, DbColumn::Durations(column[start..stop].to_vec()));
+                },
+                DbColumn::LongTexts(column) => {
+                    subtable.insert(*key, DbColumn::LongTexts(column.slice(start, stop)));
+                },
             }
         }
         
@@ -1319,6 +2716,7 @@ impl ColumnTable {
             name: KeyString::from("subtable"),
             header: self.header.clone(),
             columns: subtable,
+            metadata: self.metadata.inherit(),
         }
 
     }
@@ -1364,14 +2762,17 @@ impl ColumnTable {
                 }
             },
             DbColumn::Texts(col) => {
-                let index: usize = col.partition_point(|n| n < &KeyString::from(range.0));
+                let collation = self.key_collation();
+                let start = KeyString::from(range.0);
+                let index: usize = col.partition_point(|n| collation.compare(n, &start).is_lt());
                 indexes[0] = index;
 
                 if range.1.is_empty() {
                     indexes[1] = col.len();
                 }
 
-                let index: usize = col.partition_point(|n| n < &KeyString::from(range.1));
+                let end = KeyString::from(range.1);
+                let index: usize = col.partition_point(|n| collation.compare(n, &end).is_lt());
 
                 if col[index] == KeyString::from(range.1) {
                     indexes[1] = index;
@@ -1382,6 +2783,8 @@ impl ColumnTable {
                 indexes[1] = index;
             },
             DbColumn::Floats(_) => return Err(EzError{tag: ErrorTag::Structure, text: "There should never be a float primary key".to_owned()}),
+            DbColumn::Durations(_) => return Err(EzError{tag: ErrorTag::Structure, text: "There should never be a duration primary key".to_owned()}),
+            DbColumn::LongTexts(_) => return Err(EzError{tag: ErrorTag::Structure, text: "There should never be a long text primary key".to_owned()}),
         }
 
         for col in self.columns.values_mut() {
@@ -1395,6 +2798,13 @@ impl ColumnTable {
                 DbColumn::Texts(v) => {
                     v.drain(indexes[0]..indexes[1]);
                 }
+                DbColumn::Durations(v) => {
+                    v.drain(indexes[0]..indexes[1]);
+                }
+                DbColumn::LongTexts(v) => {
+                    let removed: Vec<usize> = (indexes[0]..indexes[1]).collect();
+                    v.remove_indices(&removed);
+                }
             };
         }
 
@@ -1420,88 +2830,23 @@ impl ColumnTable {
 
                     let index: usize = match col.binary_search(&key) {
                         Ok(num) => num,
-                        Err(_) => continue,
-                    };
-                    indexes.push(index);
-                },
-                DbColumn::Texts(col) => {
-                    let index: usize = match col.binary_search(&KeyString::from(item)) {
-                        Ok(num) => num,
-                        Err(_) => continue,
-                    };
-                    indexes.push(index);
-                },
-                DbColumn::Floats(_) => return Err(EzError{tag: ErrorTag::Structure, text: "There should never be a float primary key".to_owned()}),
-            }
-        }
-
-        let imut = self.columns.values_mut();
-        for col in imut {
-            match col {
-                DbColumn::Floats(v) => {
-                    remove_indices(v, &indexes);
-                }
-                DbColumn::Ints(v) => {
-                    remove_indices(v, &indexes);
-                }
-                DbColumn::Texts(v) => {
-                    remove_indices(v, &indexes);
-                }
-            };
-        }
-
-        Ok(())
-    }
-
-
-    pub fn delete_by_vec(&mut self, key_list: DbColumn) -> Result<(), EzError> {
-        
-
-        let primary_index = self.get_primary_key_col_index();
-
-        let mut indexes = Vec::with_capacity(key_list.len());
-        match key_list {
-            DbColumn::Ints(mut column) => {
-                column.sort();
-                for item in column {
-                    match &self.columns[&primary_index] {
-                        DbColumn::Ints(col) => {
-
-                            let index: usize = match col.binary_search(&item) {
-                                Ok(num) => num,
-                                Err(_) => continue,
-                            };
-                            indexes.push(index);
-                        },
-                        _ => unreachable!(
-                            "If we ever get here then the table is invalid. Crash immediately.\n###################\nTable name: {}\n##########################"
-                            , self.name
-                        ),
-                    }
-                }
-            },
-            DbColumn::Texts(mut column) => {
-                column.sort();
-                for item in column {
-                    match &self.columns[&primary_index] {
-                        DbColumn::Texts(col) => {
-                            let index: usize = match col.binary_search(&item) {
-                                Ok(num) => num,
-                                Err(_) => continue,
-                            };
-                            indexes.push(index);
-                        },
-                        _ => unreachable!(
-                            "If we ever get here then the table is invalid. Crash immediately.\n###################\nTable name: {}\n##########################"
-                            , self.name
-                        ),
-                    }
-                }
-            },
-            DbColumn::Floats(_) => unreachable!(
-                "If we ever get here then the table is invalid. Crash immediately.\n###################\nTable name: {}\n##########################"
-                , self.name
-            ),
+                        Err(_) => continue,
+                    };
+                    indexes.push(index);
+                },
+                DbColumn::Texts(col) => {
+                    let key = KeyString::from(item);
+                    let collation = self.key_collation();
+                    let index: usize = match col.binary_search_by(|probe| collation.compare(probe, &key)) {
+                        Ok(num) => num,
+                        Err(_) => continue,
+                    };
+                    indexes.push(index);
+                },
+                DbColumn::Floats(_) => return Err(EzError{tag: ErrorTag::Structure, text: "There should never be a float primary key".to_owned()}),
+                DbColumn::Durations(_) => return Err(EzError{tag: ErrorTag::Structure, text: "There should never be a duration primary key".to_owned()}),
+                DbColumn::LongTexts(_) => return Err(EzError{tag: ErrorTag::Structure, text: "There should never be a long text primary key".to_owned()}),
+            }
         }
 
         let imut = self.columns.values_mut();
@@ -1516,12 +2861,35 @@ impl ColumnTable {
                 DbColumn::Texts(v) => {
                     remove_indices(v, &indexes);
                 }
+                DbColumn::Durations(v) => {
+                    remove_indices(v, &indexes);
+                }
+                DbColumn::LongTexts(v) => {
+                    v.remove_indices(&indexes);
+                }
             };
         }
 
         Ok(())
     }
 
+
+    /// Deletes the rows whose primary keys are in the column. The column must have the type of the primary key.
+    pub fn delete_by_vec(&mut self, key_list: DbColumn) -> Result<(), EzError> {
+
+        self.delete_by_keys(&KeyList::from_column(key_list)?)
+    }
+
+    /// Deletes the rows whose primary keys are in the list. Keys that are not in the table are skipped.
+    pub fn delete_by_keys(&mut self, keys: &KeyList) -> Result<(), EzError> {
+
+        let primary_key = &self.columns[&self.get_primary_key_col_index()];
+        let indexes = keys.indexes_in(&db_slice_from_column(primary_key, 0, self.len()), self.key_collation())?;
+        self.delete_by_indexes(&indexes);
+
+        Ok(())
+    }
+
     pub fn delete_by_indexes(&mut self, indexes: &[usize]) {
         
 
@@ -1537,10 +2905,157 @@ impl ColumnTable {
                 DbColumn::Texts(v) => {
                     remove_indices(v, indexes);
                 }
+                DbColumn::Durations(v) => {
+                    remove_indices(v, indexes);
+                }
+                DbColumn::LongTexts(v) => {
+                    v.remove_indices(indexes);
+                }
+            };
+        }
+    }
+
+
+    /// Keeps the rows whose bit is set in the mask and drops the rest. Every column is compacted in
+    /// place so the only memory needed besides the table is the mask itself.
+    pub fn retain_rows(&mut self, keep: &[u64]) {
+
+        if keep.iter().all(|bits| *bits == u64::MAX) {
+            return
+        }
+        for col in self.columns.values_mut() {
+            match col {
+                DbColumn::Floats(v) => retain_by_mask(v, keep),
+                DbColumn::Ints(v) => retain_by_mask(v, keep),
+                DbColumn::Texts(v) => retain_by_mask(v, keep),
+                DbColumn::Durations(v) => retain_by_mask(v, keep),
+                DbColumn::LongTexts(v) => v.retain_by_mask(keep),
+            };
+        }
+    }
+
+    /// Hashes every row across all columns except the key and the engine columns, which differ between rows
+    /// even when the data is the same. The hashes are built one column at a time so rows never have to be
+    /// materialized.
+    pub fn row_hashes(&self) -> Vec<u64> {
+        let key = self.get_primary_key_col_index();
+        self.hash_rows_except(|name| *name == key || is_engine_column(name))
+    }
+
+    fn hash_rows_except(&self, skip: impl Fn(&KeyString) -> bool) -> Vec<u64> {
+
+        const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        #[inline]
+        fn fold(hash: &mut u64, bytes: &[u8]) {
+            for byte in bytes {
+                *hash ^= *byte as u64;
+                *hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        }
+
+        let mut hashes = vec![FNV_OFFSET; self.len()];
+        for (name, column) in &self.columns {
+            if skip(name) {
+                continue
+            }
+            match column {
+                DbColumn::Ints(col) => for (hash, item) in hashes.iter_mut().zip(col) {
+                    fold(hash, &item.to_le_bytes());
+                },
+                DbColumn::Floats(col) => for (hash, item) in hashes.iter_mut().zip(col) {
+                    fold(hash, &item.to_bits().to_le_bytes());
+                },
+                DbColumn::Texts(col) => for (hash, item) in hashes.iter_mut().zip(col) {
+                    fold(hash, item.raw());
+                },
+                DbColumn::Durations(col) => for (hash, item) in hashes.iter_mut().zip(col) {
+                    fold(hash, &item.to_le_bytes());
+                },
+                DbColumn::LongTexts(col) => for (hash, item) in hashes.iter_mut().zip(col.iter()) {
+                    fold(hash, item.as_bytes());
+                    fold(hash, &item.len().to_le_bytes());
+                },
+            }
+        }
+
+        hashes
+    }
+
+    /// Compares two rows across the columns row_hashes() hashes. Floats are compared bitwise to agree with it
+    fn rows_equal_ignoring_key(&self, a: usize, b: usize) -> bool {
+        let key = self.get_primary_key_col_index();
+        self.rows_equal_except(a, b, |name| *name == key || is_engine_column(name))
+    }
+
+    fn rows_equal_except(&self, a: usize, b: usize, skip: impl Fn(&KeyString) -> bool) -> bool {
+
+        for (name, column) in &self.columns {
+            if skip(name) {
+                continue
+            }
+            let equal = match column {
+                DbColumn::Ints(col) => col[a] == col[b],
+                DbColumn::Floats(col) => col[a].to_bits() == col[b].to_bits(),
+                DbColumn::Texts(col) => col[a] == col[b],
+                DbColumn::Durations(col) => col[a] == col[b],
+                DbColumn::LongTexts(col) => col.get(a) == col.get(b),
             };
+            if !equal {
+                return false
+            }
+        }
+        true
+    }
+
+    /// Returns the indexes of every row that is identical to an earlier row across all columns except the key
+    /// and the engine columns.
+    /// The first occurrence (lowest primary key) is never included.
+    pub fn duplicate_indexes(&self) -> Vec<usize> {
+
+        let hashes = self.row_hashes();
+        let mut seen: HashMap<u64, Vec<usize>> = HashMap::new();
+        let mut duplicates = Vec::new();
+        for (index, hash) in hashes.iter().enumerate() {
+            let candidates = seen.entry(*hash).or_default();
+            // Hash collisions are possible so equal hashes are confirmed before a row is thrown out
+            if candidates.iter().any(|candidate| self.rows_equal_ignoring_key(*candidate, index)) {
+                duplicates.push(index);
+            } else {
+                candidates.push(index);
+            }
         }
+
+        duplicates
+    }
+
+    /// Returns the index of the first occurrence of every distinct row, comparing all columns including the key.
+    /// Used by SELECT with distinct: true, where the key is only compared if it was selected.
+    pub fn distinct_row_indexes(&self) -> Vec<usize> {
+
+        let hashes = self.hash_rows_except(|_| false);
+        let mut seen: HashMap<u64, Vec<usize>> = HashMap::new();
+        let mut keepers = Vec::new();
+        for (index, hash) in hashes.iter().enumerate() {
+            let candidates = seen.entry(*hash).or_default();
+            if !candidates.iter().any(|candidate| self.rows_equal_except(*candidate, index, |_| false)) {
+                candidates.push(index);
+                keepers.push(index);
+            }
+        }
+
+        keepers
     }
 
+    /// Removes every row that duplicates an earlier row, ignoring the key and the engine columns. Returns the number
+    /// of rows removed.
+    pub fn deduplicate(&mut self) -> usize {
+
+        let duplicates = self.duplicate_indexes();
+        self.delete_by_indexes(&duplicates);
+        duplicates.len()
+    }
 
     /// Deletes a single row from the table by primary key
     fn delete(&mut self, query: &str) -> Result<(), EzError> {
@@ -1562,6 +3077,12 @@ impl ColumnTable {
                 DbColumn::Texts(col) => {
                     *col = Vec::with_capacity(0);
                 },
+                DbColumn::Durations(col) => {
+                    *col = Vec::with_capacity(0);
+                },
+                DbColumn::LongTexts(col) => {
+                    *col = LongTexts::new();
+                },
             }
         }
     }
@@ -1572,13 +3093,20 @@ impl ColumnTable {
             DbColumn::Ints(_) => DbType::Int,
             DbColumn::Texts(_) => DbType::Text,
             DbColumn::Floats(_) => DbType::Float,
+            DbColumn::Durations(_) => DbType::Duration,
+            DbColumn::LongTexts(_) => DbType::LongText,
         };
 
         if self.columns.is_empty() {
+            if kind == DbType::LongText {
+                return Err(EzError{tag: ErrorTag::Structure, text: "The first column is the primary key and can't be a long text column".to_owned()})
+            }
             self.header.insert(HeaderItem {
                 name: name,
                 key: TableKey::Primary,
                 kind: kind,
+                values: Vec::new(),
+                collation: Collation::Binary,
             });
             self.columns.insert(name, column);
         } else {
@@ -1590,6 +3118,8 @@ impl ColumnTable {
                 name: name,
                 key: TableKey::None,
                 kind: kind,
+                values: Vec::new(),
+                collation: Collation::Binary,
             });
             self.columns.insert(name, column);
 
@@ -1597,6 +3127,95 @@ impl ColumnTable {
         Ok(())
     }
 
+    /// Adds a column with every existing row set to `default`. The new column can't be the primary key.
+    pub fn add_column_with_default(&mut self, item: HeaderItem, default: &DbValue) -> Result<(), EzError> {
+
+        if item.key == TableKey::Primary {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("Table '{}' already has a primary key so '{}' can't be one", self.name, item.name)})
+        }
+        if is_engine_column(&item.name) {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("Column '{}' is kept by the engine and can't be added", item.name)})
+        }
+        if self.columns.contains_key(&item.name) {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("Table '{}' already has a column '{}'", self.name, item.name)})
+        }
+
+        let rows = self.len();
+        let column = match (item.kind, default) {
+            (DbType::Int, DbValue::Int(x)) => DbColumn::Ints(vec![*x; rows]),
+            (DbType::Float, DbValue::Float(x)) => DbColumn::Floats(vec![*x; rows]),
+            (DbType::Float, DbValue::Int(x)) => DbColumn::Floats(vec![*x as f32; rows]),
+            (DbType::Text, DbValue::Text(x)) => DbColumn::Texts(vec![*x; rows]),
+            (DbType::LongText, DbValue::Text(x)) => DbColumn::LongTexts((0..rows).map(|_| x.as_str()).collect()),
+            (DbType::Duration, value) => DbColumn::Durations(vec![value.as_duration()?; rows]),
+            (DbType::Enum, DbValue::Text(x)) => DbColumn::Ints(vec![item.enum_index(x.as_str())?; rows]),
+            (kind, value) => return Err(EzError{tag: ErrorTag::Query, text: format!("{} can't be the default of {} column '{}'", value, kind.name(), item.name)}),
+        };
+
+        self.columns.insert(item.name, column);
+        self.header.insert(item);
+        Ok(())
+    }
+
+    /// Removes a column. The primary key and the engine columns can't be dropped.
+    pub fn drop_column(&mut self, name: &KeyString) -> Result<(), EzError> {
+
+        let item = self.alterable_column(name)?;
+        if item.key == TableKey::Primary {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("'{}' is the primary key of '{}' and can't be dropped", name, self.name)})
+        }
+
+        self.header.remove(&item);
+        self.columns.remove(name);
+        Ok(())
+    }
+
+    /// Gives a column a new name. Its values, type and key stay the same.
+    pub fn rename_column(&mut self, from: &KeyString, to: KeyString) -> Result<(), EzError> {
+
+        let item = self.alterable_column(from)?;
+        if is_engine_column(&to) {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("Column '{}' is kept by the engine and can't be a new name", to)})
+        }
+        if self.columns.contains_key(&to) {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("Table '{}' already has a column '{}'", self.name, to)})
+        }
+
+        let column = self.columns.remove(from).unwrap();
+        self.header.remove(&item);
+        self.header.insert(HeaderItem{name: to, ..item});
+        self.columns.insert(to, column);
+        Ok(())
+    }
+
+    /// Casts every cell of a column to `kind`. Nothing changes if any cell can't be cast. See cast_column().
+    pub fn change_column_type(&mut self, name: &KeyString, kind: DbType) -> Result<(), EzError> {
+
+        let item = self.alterable_column(name)?;
+        if item.key == TableKey::Primary {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("'{}' is the primary key of '{}' and can't change type", name, self.name)})
+        }
+
+        let cast = cast_column(&item, &self.columns[name], kind)?;
+        self.header.remove(&item);
+        // A collation only means something to text
+        let collation = if kind == DbType::Text {item.collation} else {Collation::Binary};
+        self.header.insert(HeaderItem{kind, values: Vec::new(), collation, ..item});
+        self.columns.insert(*name, cast);
+        Ok(())
+    }
+
+    /// The header of a column that ALTER_TABLE may change. Engine columns are left to the engine.
+    fn alterable_column(&self, name: &KeyString) -> Result<HeaderItem, EzError> {
+        if is_engine_column(name) {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("Column '{}' is kept by the engine and can't be altered", name)})
+        }
+        match self.header.iter().find(|item| item.name == *name) {
+            Some(item) => Ok(item.clone()),
+            None => Err(EzError{tag: ErrorTag::NotFound, text: format!("Table '{}' has no column '{}'", self.name, name)}),
+        }
+    }
+
     pub fn extend_from_table(&mut self, source_table: ColumnTable) -> Result<(), EzError> {
 
         if self.header != source_table.header {
@@ -1618,6 +3237,16 @@ impl ColumnTable {
                     let src_col = source_table.get_column_float(&name).unwrap();
                     vec.extend_from_slice(src_col);
                 },
+                DbColumn::Durations(vec) => {
+                    let src_col = source_table.get_column_duration(&name).unwrap();
+                    vec.extend_from_slice(src_col);
+                },
+                DbColumn::LongTexts(vec) => {
+                    let src_col = source_table.get_column_long_text(&name).unwrap();
+                    for item in src_col.iter() {
+                        vec.push(item);
+                    }
+                },
             }
         }
 
@@ -1638,6 +3267,11 @@ impl ColumnTable {
         };
 
         
+        // Every row has to find its match since there are no empty cells for the right columns of a row without one
+        let unmatched = |item: &dyn Display| EzError{
+            tag: ErrorTag::Query,
+            text: format!("'{}' in column '{}' has no match in table '{}'", item, predicate_column, right_table.name),
+        };
         let mut indexes: Vec<usize> = Vec::with_capacity(self.len());
         match &self.columns[predicate_column] {
             DbColumn::Ints(column) => {
@@ -1648,21 +3282,31 @@ impl ColumnTable {
                 }
 
                 for item in column {
-                    indexes.push(lookup[item]);
+                    match lookup.get(item) {
+                        Some(index) => indexes.push(*index),
+                        None => return Err(unmatched(item)),
+                    }
                 }
             },
             DbColumn::Texts(column) => {
                 let right_col = right_table.get_column_text(predicate_column)?;
+                // Matched the way the right table compares the column
+                let collation = right_table.collation_of(predicate_column);
                 let mut lookup = HashMap::with_capacity(right_col.len());
                 for (index, item) in right_col.iter().enumerate() {
-                    lookup.insert(item, index);
+                    lookup.insert(collation.fold(item), index);
                 }
 
                 for item in column {
-                    indexes.push(lookup[item]);
+                    match lookup.get(&collation.fold(item)) {
+                        Some(index) => indexes.push(*index),
+                        None => return Err(unmatched(item)),
+                    }
                 }
             },
-            DbColumn::Floats(_column) => unreachable!("Can never have a float key column"),
+            DbColumn::Floats(_column) => return Err(EzError{tag: ErrorTag::Query, text: "Can't join on a float column".to_owned()}),
+            DbColumn::Durations(_column) => return Err(EzError{tag: ErrorTag::Query, text: "Can't join on a duration column".to_owned()}),
+            DbColumn::LongTexts(_column) => return Err(EzError{tag: ErrorTag::Query, text: "Can't join on a long text column".to_owned()}),
         }
         
         for (name, column) in right_table.columns.iter() {
@@ -1692,6 +3336,16 @@ impl ColumnTable {
                     }
                     self.add_column(*name, DbColumn::Floats(new_column))?;
                 },
+                DbColumn::Durations(col) => {
+                    let mut new_column = Vec::with_capacity(indexes.len());
+                    for index in &indexes {
+                        new_column.push(col[*index]);
+                    }
+                    self.add_column(*name, DbColumn::Durations(new_column))?;
+                },
+                DbColumn::LongTexts(col) => {
+                    self.add_column(*name, DbColumn::LongTexts(col.select(&indexes)))?;
+                },
             }
         }
 
@@ -1732,12 +3386,13 @@ impl ColumnTable {
             },
             DbColumn::Texts(column) => {
                 let right_col = right_table.get_column_text(predicate_column)?;
+                let collation = right_table.collation_of(predicate_column);
                 let mut lookup = HashMap::with_capacity(right_col.len());
                 for item in column.iter() {
                     if lookup.contains_key(item) {
                         indexes.push(lookup[item]);
                     } else {
-                        match right_col.binary_search(item) {
+                        match right_col.binary_search_by(|probe| collation.compare(probe, item)) {
                             Ok(x) => {
                                 indexes.push(x);
                                 lookup.insert(item, x);
@@ -1748,6 +3403,8 @@ impl ColumnTable {
                 }
             },
             DbColumn::Floats(_column) => unreachable!("Can never have a float key column"),
+            DbColumn::Durations(_column) => unreachable!("Can never have a duration key column"),
+            DbColumn::LongTexts(_column) => return Err(EzError{tag: ErrorTag::Query, text: "Can't join on a long text column".to_owned()}),
 
         }
         
@@ -1778,23 +3435,38 @@ impl ColumnTable {
                     }
                     self.add_column(*name, DbColumn::Floats(new_column))?;
                 },
+                DbColumn::Durations(col) => {
+                    let mut new_column = Vec::with_capacity(indexes.len());
+                    for index in &indexes {
+                        new_column.push(col[*index]);
+                    }
+                    self.add_column(*name, DbColumn::Durations(new_column))?;
+                },
+                DbColumn::LongTexts(col) => {
+                    self.add_column(*name, DbColumn::LongTexts(col.select(&indexes)))?;
+                },
             }
         }
 
         Ok(())
     }
 
+    /// The exact length of to_binary(). See byte_size() for the size in memory.
     pub fn size_of_table(&self) -> usize {
-        let mut acc = 128; // the table name and the packet type are 64 byte KeyStrings 
-
-        acc += self.header.len() * 72;
+        // The magic, the name and the two lengths, then 8 bytes of types and a 64 byte name per column
+        let mut acc = 144 + self.header.len() * 72 + METADATA_BINARY_SIZE;
 
-        for (_, col) in &self.columns {
-            acc += 64;
-            match col {
-                DbColumn::Ints(vec) => acc += vec.len() * 4,
-                DbColumn::Texts(vec) => acc += vec.len() * 64,
-                DbColumn::Floats(vec) => acc += vec.len() * 4,
+        for col in self.columns.values() {
+            acc += col.binary_size();
+        }
+        // Versioned formats have a section count, then the section name and length, then each enum column with its values
+        if table_versions(self, false).0 > 1 {
+            acc += 8;
+        }
+        if self.has_enum_columns() {
+            acc += 72;
+            for item in self.header.iter().filter(|item| item.kind == DbType::Enum) {
+                acc += 72 + item.values.len() * 64;
             }
         }
 
@@ -1810,6 +3482,9 @@ impl ColumnTable {
                 DbColumn::Ints(_) => acc += 4,
                 DbColumn::Texts(_) => acc += 64,
                 DbColumn::Floats(_) => acc += 4,
+                DbColumn::Durations(_) => acc += 8,
+                // The mean length since the rows differ
+                DbColumn::LongTexts(col) => acc += 8 + col.byte_len() / col.len().max(1),
             }
         }
 
@@ -1825,46 +3500,122 @@ impl ColumnTable {
         
         // WRITING COLUMNS
         for column in self.columns.values() {
-            match &column {
-                DbColumn::Floats(col) => {
-                    for item in col {
-                        binary.extend_from_slice(&item.to_le_bytes());
-                    }
-                }
-                &DbColumn::Ints(col) => {
-                    for item in col {
-                        // println!("item: {}", item);
-                        binary.extend_from_slice(&item.to_le_bytes());
+            write_column(column, &mut binary);
+        }
+        write_table_sections(&mut binary, self, &[]);
+        binary
+    }
+
+    /// Writes to EZ binary format with each column compressed. See COMPRESSED_COLUMN_TABLE_MAGIC.
+    pub fn to_compressed_binary(&self) -> Result<Vec<u8>, EzError> {
+        let mut binary: Vec<u8> = Vec::new();
+        write_column_table_binary_header(&mut binary, self);
+        binary[0..64].copy_from_slice(table_magic(self, true, false).raw());
+        for column in self.columns.values() {
+            compress_column(column, &mut binary)?;
+        }
+        write_table_sections(&mut binary, self, &[]);
+        Ok(binary)
+    }
+
+    pub fn has_enum_columns(&self) -> bool {
+        self.header.iter().any(|item| item.kind == DbType::Enum)
+    }
+
+    /// The binary to write to the table file. Compressed unless table compression is turned off.
+    /// The header and every column are checksummed so corruption is caught when the file is read. See CHECKSUMS_SECTION.
+    pub fn to_disk_binary(&self) -> Result<Vec<u8>, EzError> {
+        let compressed = table_compression();
+        let mut binary: Vec<u8> = Vec::new();
+        write_column_table_binary_header(&mut binary, self);
+        binary[0..64].copy_from_slice(table_magic(self, compressed, true).raw());
+
+        let mut checksums = vec![crc32(&binary)];
+        for column in self.columns.values() {
+            let start = binary.len();
+            match compressed {
+                true => { compress_column(column, &mut binary)?; },
+                false => write_column(column, &mut binary),
+            }
+            checksums.push(crc32(&binary[start..]));
+        }
+        write_table_sections(&mut binary, self, &checksums);
+        Ok(binary)
+    }
+
+
+    /// The float columns that hold at least one NaN.
+    pub fn nan_columns(&self) -> Vec<KeyString> {
+        self.columns.iter()
+            .filter(|(_, column)| matches!(column, DbColumn::Floats(col) if col.iter().any(|x| x.is_nan())))
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    /// Checks that the table has exactly one primary key which is unique and not a float or duration,
+    /// and that all columns have the same length. Then sorts the table by primary key.
+    pub fn validate_and_sort(&mut self) -> Result<(), EzError> {
+
+        let primary_keys: Vec<&HeaderItem> = self.header.iter().filter(|item| item.key == TableKey::Primary).collect();
+        if primary_keys.len() != 1 {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("A table needs exactly one primary key. Found {}", primary_keys.len())})
+        }
+        let primary_key = primary_keys[0].name;
+
+        let len = self.columns[&primary_key].len();
+        for (name, column) in &self.columns {
+            if column.len() != len {
+                return Err(EzError{tag: ErrorTag::Structure, text: format!("Column '{}' has {} items but the primary key has {}", name, column.len(), len)})
+            }
+        }
+
+        match &self.columns[&primary_key] {
+            DbColumn::Ints(col) => {
+                let mut test_set = HashSet::new();
+                for item in col {
+                    if !test_set.insert(item) {
+                        return Err(EzError{tag: ErrorTag::Structure, text: format!("Primary key is not unique. Item {} is repeated", item)})
                     }
                 }
-                DbColumn::Texts(col) => {
-                    for item in col {
-                        binary.extend_from_slice(item.raw());
+            },
+            DbColumn::Texts(col) => {
+                let collation = primary_keys[0].collation;
+                let mut test_set = HashSet::new();
+                for item in col {
+                    if !test_set.insert(collation.fold(item)) {
+                        return Err(EzError{tag: ErrorTag::Structure, text: format!("Primary key is not unique. Item {} is repeated", item)})
                     }
                 }
-            };
+            },
+            DbColumn::Floats(_) => return Err(EzError{tag: ErrorTag::Structure, text: "The primary key can't be a float column".to_owned()}),
+            DbColumn::Durations(_) => return Err(EzError{tag: ErrorTag::Structure, text: "The primary key can't be a duration column".to_owned()}),
+            DbColumn::LongTexts(_) => return Err(EzError{tag: ErrorTag::Structure, text: "The primary key can't be a long text column".to_owned()}),
         }
-        binary
-    }
 
+        self.sort();
+        Ok(())
+    }
 
     /// Reads an EZ binary formatted file to a ColumnTable, checking for strictness.
     pub fn from_binary(name: Option<&str>, binary: &[u8]) -> Result<ColumnTable, EzError> {
 
-        if binary.len() < 128 + 8 + 8 {
-            return Err(EzError{tag: ErrorTag::Deserialization, text: ("binary is less than 144 bytes".to_owned())});
+        // Checks every length in the header against the binary so nothing below can read out of bounds
+        let layout = table_binary_layout(binary)?;
+        if binary.len() < layout.len {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Table binary should be {} bytes according to its header but is {} bytes", layout.len, binary.len())})
         }
 
-        let packet_type = match KeyString::try_from(&binary[0..64]) {
-            Ok(x) => x,
-            Err(_) => return Err(EzError{tag: ErrorTag::Deserialization, text: ("Packet_type corrupted".to_owned())}),
-        };
-
+        let format = table_format(&binary[0..64])?;
+        // Before anything is decoded so a flipped bit is reported as such rather than as whatever it decodes to
+        if format.has_sections {
+            let display_name = match name {
+                Some(name) => name.to_owned(),
+                None => KeyString::try_from(&binary[64..128]).map(|name| name.to_string()).unwrap_or_default(),
+            };
+            verify_checksums(binary, &layout, &display_name)?;
+        }
+        let (has_metadata, compressed) = (format.has_metadata, format.compressed);
         let mut table_name = KeyString::try_from(&binary[64..128])?;
-        match packet_type.as_str() {
-            "EZDB_COLUMNTABLE" => (),
-            _ => return Err(EzError{tag: ErrorTag::Deserialization, text: "Not ColumnTable".to_owned()})
-        };
 
         let header_len = u64_from_le_slice(&binary[128..136]) as usize;
         let column_len = u64_from_le_slice(&binary[136..144]) as usize;
@@ -1876,36 +3627,60 @@ impl ColumnTable {
                 b'i' => DbType::Int,
                 b'f' => DbType::Float,
                 b't' => DbType::Text,
-                _ => panic!("TODO: Make this a proper error"),
+                b'd' => DbType::Duration,
+                b'l' => DbType::LongText,
+                b'e' => DbType::Enum,
+                other => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unknown column type: '{}'", other)}),
             };
             let key = match chunk[7] {
                 b'P' => TableKey::Primary,
                 b'N' => TableKey::None,
                 b'F' => TableKey::Foreign,
-                _ => panic!("TODO: Make this a proper error"),
+                other => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unknown key type: '{}'", other)}),
+            };
+            // Written as 0 before collations existed
+            let collation = match chunk[5] {
+                b'c' => Collation::CaseInsensitive,
+                _ => Collation::Binary,
             };
-            acc_kk.push((kind, key));
+            acc_kk.push((kind, key, collation));
         }
 
         let header_names = &binary[144+header_len*8..144+header_len*8 + header_len*64];
         
         let mut names = Vec::new();
         for chunk in header_names.chunks_exact(64) {
-            names.push(KeyString::try_from(chunk).unwrap());
+            names.push(KeyString::try_from(chunk)?);
         }
 
         let mut header = BTreeSet::new();
 
         for i in 0..header_len {
-            header.insert(HeaderItem{name: names[i], kind: acc_kk[i].0, key: acc_kk[i].1 });
+            header.insert(HeaderItem{name: names[i], kind: acc_kk[i].0, key: acc_kk[i].1, values: Vec::new(), collation: acc_kk[i].2 });
         }
 
+        let mut pointer = 144+header_len*8 + header_len*64;
+        let metadata = if has_metadata {
+            if binary.len() < pointer + METADATA_BINARY_SIZE {
+                return Err(EzError{tag: ErrorTag::Deserialization, text: "Binary is too short to contain the table metadata".to_owned()})
+            }
+            pointer += METADATA_BINARY_SIZE;
+            Metadata::from_binary(&binary[pointer - METADATA_BINARY_SIZE..pointer])?
+        } else {
+            Metadata::new("unknown")
+        };
+
         let mut columns = BTreeMap::new();
 
-        let mut pointer = 144+header_len*8 + header_len*64;
         for item in &header {
+            if compressed {
+                let (column, len) = decompress_column(item.kind, column_len, &binary[pointer..])?;
+                columns.insert(item.name, column);
+                pointer += len;
+                continue
+            }
             match item.kind {
-                DbType::Int => {
+                DbType::Int | DbType::Enum => {
                     let blob = &binary[pointer..pointer + (column_len * 4)];
                     let v = blob.chunks(4).map(i32_from_le_slice).collect();
                     
@@ -1926,7 +3701,42 @@ impl ColumnTable {
                     pointer += column_len * 64;
                     columns.insert(item.name, DbColumn::Texts(v));
                 },
+                DbType::Duration => {
+                    let blob = &binary[pointer..pointer + (column_len * 8)];
+                    let v = blob.chunks(8).map(i64_from_le_slice).collect();
+
+                    columns.insert(item.name, DbColumn::Durations(v));
+                    pointer += column_len*8;
+                }
+                DbType::LongText => {
+                    let (v, len) = LongTexts::from_binary(&binary[pointer..], column_len)?;
+                    columns.insert(item.name, DbColumn::LongTexts(v));
+                    pointer += len;
+                }
+            }
+        }
+
+        if header.iter().any(|item| item.kind == DbType::Enum) {
+            let mut enums = match format.has_sections {
+                true => read_enum_values(&binary[pointer..])?,
+                false => BTreeMap::new(),
+            };
+            let mut with_values = BTreeSet::new();
+            for mut item in header {
+                if item.kind == DbType::Enum {
+                    item.values = match enums.remove(&item.name) {
+                        Some(values) => values,
+                        None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Enum column '{}' has no values", item.name)}),
+                    };
+                    if let Some(DbColumn::Ints(col)) = columns.get(&item.name) {
+                        if let Some(bad) = col.iter().find(|index| item.enum_value(**index).is_err()) {
+                            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Enum column '{}' holds {} which is not one of its values", item.name, bad)})
+                        }
+                    }
+                }
+                with_values.insert(item);
             }
+            header = with_values;
         }
 
         if name.is_some() {
@@ -1937,17 +3747,174 @@ impl ColumnTable {
             name: table_name,
             header,
             columns,
+            metadata,
         };
 
         Ok(new_table)
     }
 
-    
+    
+}
+
+/// Reads the header of an EZ binary table and computes how long the whole binary should be.
+/// Used to check untrusted input before handing it to ColumnTable::from_binary()
+/// Optional sections of newer formats are counted but not read.
+pub fn column_table_binary_len(binary: &[u8]) -> Result<usize, EzError> {
+    Ok(table_binary_layout(binary)?.len)
+}
+
+/// Where each part of an EZ binary table starts and ends according to its header.
+struct TableLayout {
+    /// The end of the header and metadata, which is where the first column starts.
+    header: usize,
+    /// One range per column in the order they are stored.
+    columns: Vec<std::ops::Range<usize>>,
+    /// Where the optional sections start. The same as len if there are none.
+    sections: usize,
+    len: usize,
+}
+
+fn table_binary_layout(binary: &[u8]) -> Result<TableLayout, EzError> {
+
+    if binary.len() < 144 {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: ("binary is less than 144 bytes".to_owned())});
+    }
+
+    let format = table_format(&binary[0..64])?;
+    let metadata_len = if format.has_metadata {METADATA_BINARY_SIZE} else {0};
+    let compressed = format.compressed;
+    let header_len = u64_from_le_slice(&binary[128..136]) as usize;
+    let column_len = u64_from_le_slice(&binary[136..144]) as usize;
+
+    let header_end = match header_len.checked_mul(72).and_then(|x| x.checked_add(144 + metadata_len)) {
+        Some(x) => x,
+        None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Header length {} is too large", header_len)}),
+    };
+    if binary.len() < header_end {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Binary is too short to contain a header of {} columns", header_len)});
+    }
+
+    let too_short = || EzError{tag: ErrorTag::Deserialization, text: "Binary is too short to contain its columns".to_owned()};
+    let mut total = header_end;
+    let mut columns = Vec::with_capacity(header_len);
+    for chunk in binary[144..144+header_len*8].chunks(8) {
+        // Long text columns have no fixed item size. Their length is written in front of them
+        let item_size = match chunk[3] {
+            b'i' | b'f' | b'e' => 4,
+            b'd' => 8,
+            b't' => 64,
+            b'l' => 0,
+            other => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unknown column type: '{}'", other)}),
+        };
+        match chunk[7] {
+            b'P' if chunk[3] == b'l' => return Err(EzError{tag: ErrorTag::Deserialization, text: "The primary key can't be a long text column".to_owned()}),
+            b'P' if chunk[3] == b'e' => return Err(EzError{tag: ErrorTag::Deserialization, text: "The primary key can't be an enum column".to_owned()}),
+            b'P' | b'N' | b'F' => (),
+            other => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unknown key type: '{}'", other)}),
+        }
+        let column = if compressed {
+            // The length of each column is written in front of it
+            compressed_column_len(binary.get(total..).ok_or_else(too_short)?)?
+        } else if chunk[3] == b'l' {
+            LongTexts::binary_len(binary.get(total..).ok_or_else(too_short)?, column_len)?
+        } else {
+            match column_len.checked_mul(item_size) {
+                Some(x) => x,
+                None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Column length {} is too large", column_len)}),
+            }
+        };
+        let start = total;
+        total = match total.checked_add(column) {
+            Some(x) => x,
+            None => return Err(EzError{tag: ErrorTag::Deserialization, text: "Column length is too large".to_owned()}),
+        };
+        columns.push(start..total);
+    }
+
+    let sections = total;
+    if format.has_sections {
+        let cut_short = || EzError{tag: ErrorTag::Deserialization, text: "Binary is too short to contain its optional sections".to_owned()};
+        let count = match binary.get(total..total.saturating_add(8)) {
+            Some(count) if count.len() == 8 => u64_from_le_slice(count),
+            _ => return Err(cut_short()),
+        };
+        total += 8;
+        for _ in 0..count {
+            let section_len = match binary.get(total + 64..total.saturating_add(72)) {
+                Some(len) if len.len() == 8 => u64_from_le_slice(len) as usize,
+                _ => return Err(cut_short()),
+            };
+            total = match section_len.checked_add(total + 72) {
+                Some(x) if x <= binary.len() => x,
+                _ => return Err(cut_short()),
+            };
+        }
+    }
+
+    Ok(TableLayout{header: header_end, columns, sections, len: total})
+}
+
+/// Checks the header and every column of a table binary against its CHECKSUMS_SECTION. Binaries without
+/// one, such as tables sent over the network and files written before version 3, are taken as they are.
+fn verify_checksums(binary: &[u8], layout: &TableLayout, table_name: &str) -> Result<(), EzError> {
+    let body = match find_table_section(&binary[layout.sections..], CHECKSUMS_SECTION)? {
+        Some(body) => body,
+        None => return Ok(()),
+    };
+    if body.len() != 4 * (layout.columns.len() + 1) {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Table '{}' is corrupt. It has {} checksums for {} columns", table_name, body.len() / 4, layout.columns.len())})
+    }
+    let checksums: Vec<u32> = body.chunks_exact(4).map(u32_from_le_slice).collect();
+
+    if crc32(&binary[..layout.header]) != checksums[0] {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Table '{}' is corrupt. The checksum of its header does not match", table_name)})
+    }
+    // The header is intact so the column names can be trusted
+    let names_start = 144 + layout.columns.len() * 8;
+    for (index, (range, checksum)) in layout.columns.iter().zip(&checksums[1..]).enumerate() {
+        if crc32(&binary[range.clone()]) != *checksum {
+            let name = KeyString::try_from(&binary[names_start + index*64..names_start + (index+1)*64])?;
+            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Table '{}' is corrupt. The checksum of column '{}' does not match", table_name, name)})
+        }
+    }
+    Ok(())
+}
+
+/// The format version a table binary is written in and the oldest version that can read it. Each feature the table
+/// uses raises them to the version that added it, so a table that needs nothing newer stays readable by older servers.
+pub fn table_versions(table: &ColumnTable, checksummed: bool) -> (u64, u64) {
+    let mut versions = (1, 1);
+    let mut needs = |version: u64, reader: u64| versions = (versions.0.max(version), versions.1.max(reader));
+    if table.has_enum_columns() {
+        needs(2, 2);
+    }
+    // Older servers skip the section but only servers from version 2 on know to look for sections
+    if checksummed {
+        needs(3, 2);
+    }
+    if table.header.iter().any(|item| item.kind == DbType::Duration) {
+        needs(4, 4);
+    }
+    if table.header.iter().any(|item| item.kind == DbType::LongText) {
+        needs(5, 5);
+    }
+    versions
+}
+
+/// The magic a table binary starts with. See table_versions() and VERSIONED_COLUMN_TABLE_PREFIX.
+pub fn table_magic(table: &ColumnTable, compressed: bool, checksummed: bool) -> KeyString {
+    match (table_versions(table, checksummed), compressed) {
+        ((1, _), false) => ksf(COLUMN_TABLE_MAGIC),
+        ((1, _), true) => ksf(COMPRESSED_COLUMN_TABLE_MAGIC),
+        ((version, reader), compressed) => KeyString::from(format!(
+            "{}{}_R{}{}", VERSIONED_COLUMN_TABLE_PREFIX, version, reader, if compressed {"_Z"} else {""}
+        ).as_str()),
+    }
 }
 
 pub fn write_column_table_binary_header(binary: &mut Vec<u8>, table: &ColumnTable) -> usize {
     
-    binary.extend_from_slice(ksf("EZDB_COLUMNTABLE").raw());
+    binary.extend_from_slice(table_magic(table, false, false).raw());
     binary.extend_from_slice(table.name.raw());
     
     // WRITING LENGTHS
@@ -1964,29 +3931,133 @@ pub fn write_column_table_binary_header(binary: &mut Vec<u8>, table: &ColumnTabl
             DbType::Int => b'i',
             DbType::Float => b'f',
             DbType::Text => b't',
+            DbType::Duration => b'd',
+            DbType::LongText => b'l',
+            DbType::Enum => b'e',
         };
         let key_type = match &item.key {
             TableKey::Primary => b'P',
             TableKey::None => b'N',
             TableKey::Foreign => b'F',
         };
-        keys_and_kinds.extend_from_slice(&[0,0,0,kind,0,0,0,key_type]);
+        // Readers from before collations only look at the kind and the key so they still read the table, comparing bytes
+        let collation = match item.collation {
+            Collation::Binary => 0,
+            Collation::CaseInsensitive => b'c',
+        };
+        keys_and_kinds.extend_from_slice(&[0,0,0,kind,0,collation,0,key_type]);
         names.extend_from_slice(item.name.raw());
     }
     binary.extend_from_slice(&keys_and_kinds);
     binary.extend_from_slice(&names);
+    binary.extend_from_slice(&table.metadata.to_binary());
     
-    128 + table.header.len()+80
-} 
+    144 + table.header.len()*72 + METADATA_BINARY_SIZE
+}
 
+/// Writes the optional sections that follow the columns. Tables written in a versioned format always get the count
+/// of their sections, even when it is 0, since readers of versioned formats expect it.
+fn write_table_sections(binary: &mut Vec<u8>, table: &ColumnTable, checksums: &[u32]) {
+    let mut sections = Vec::new();
+    if table.has_enum_columns() {
+        let mut body = Vec::new();
+        for item in table.header.iter().filter(|item| item.kind == DbType::Enum) {
+            body.extend_from_slice(item.name.raw());
+            body.extend_from_slice(&item.values.len().to_le_bytes());
+            for value in &item.values {
+                body.extend_from_slice(value.raw());
+            }
+        }
+        sections.push((ENUM_VALUES_SECTION, body));
+    }
+    if !checksums.is_empty() {
+        sections.push((CHECKSUMS_SECTION, checksums.iter().flat_map(|checksum| checksum.to_le_bytes()).collect()));
+    }
+    if sections.is_empty() && table_versions(table, false).0 == 1 {
+        return
+    }
+    binary.extend_from_slice(&sections.len().to_le_bytes());
+    for (name, body) in sections {
+        binary.extend_from_slice(ksf(name).raw());
+        binary.extend_from_slice(&body.len().to_le_bytes());
+        binary.extend_from_slice(&body);
+    }
+}
 
-pub struct DbRow<'a> {
-    inner: &'a [u8],
+/// Writes the items of a column one after the other in the uncompressed layout.
+fn write_column(column: &DbColumn, binary: &mut Vec<u8>) {
+    match column {
+        DbColumn::Floats(col) => {
+            for item in col {
+                binary.extend_from_slice(&item.to_le_bytes());
+            }
+        }
+        DbColumn::Ints(col) => {
+            for item in col {
+                binary.extend_from_slice(&item.to_le_bytes());
+            }
+        }
+        DbColumn::Texts(col) => {
+            for item in col {
+                binary.extend_from_slice(item.raw());
+            }
+        }
+        DbColumn::Durations(col) => {
+            for item in col {
+                binary.extend_from_slice(&item.to_le_bytes());
+            }
+        }
+        DbColumn::LongTexts(col) => col.write_binary(binary),
+    }
 }
 
+/// The body of the optional section with the given name, starting right after the columns. None if there is no such section.
+fn find_table_section<'a>(sections: &'a [u8], name: &str) -> Result<Option<&'a [u8]>, EzError> {
+    let cut_short = || EzError{tag: ErrorTag::Deserialization, text: "Binary is too short to contain its optional sections".to_owned()};
+    let slice = |from: usize, len: usize| from.checked_add(len).and_then(|to| sections.get(from..to)).ok_or_else(cut_short);
+
+    let count = u64_from_le_slice(slice(0, 8)?);
+    let mut pointer = 8;
+    for _ in 0..count {
+        let section_name = KeyString::try_from(slice(pointer, 64)?)?;
+        let len = u64_from_le_slice(slice(pointer + 64, 8)?) as usize;
+        let body = slice(pointer + 72, len)?;
+        if section_name.as_str() == name {
+            return Ok(Some(body))
+        }
+        pointer += 72 + len;
+    }
+    Ok(None)
+}
 
-pub struct RowTable {
-    arena: bumpalo::Bump
+/// Reads the values of the enum columns out of the optional sections of a table binary, starting right after the columns.
+/// Sections this server doesn't know are skipped.
+pub fn read_enum_values(sections: &[u8]) -> Result<BTreeMap<KeyString, Vec<KeyString>>, EzError> {
+    let cut_short = || EzError{tag: ErrorTag::Deserialization, text: "Binary is too short to contain its optional sections".to_owned()};
+    let slice = |from: usize, len: usize| from.checked_add(len).and_then(|to| sections.get(from..to)).ok_or_else(cut_short);
+
+    let mut enums = BTreeMap::new();
+    let count = u64_from_le_slice(slice(0, 8)?);
+    let mut pointer = 8;
+    for _ in 0..count {
+        let name = KeyString::try_from(slice(pointer, 64)?)?;
+        let len = u64_from_le_slice(slice(pointer + 64, 8)?) as usize;
+        let body = slice(pointer + 72, len)?;
+        pointer += 72 + len;
+        if name.as_str() != ENUM_VALUES_SECTION {
+            continue
+        }
+        let mut i = 0;
+        while i < body.len() {
+            let field = |from: usize, len: usize| from.checked_add(len).and_then(|to| body.get(from..to)).ok_or_else(cut_short);
+            let column = KeyString::try_from(field(i, 64)?)?;
+            let values_len = (u64_from_le_slice(field(i + 64, 8)?) as usize).checked_mul(64).ok_or_else(cut_short)?;
+            let values = field(i + 72, values_len)?;
+            enums.insert(column, values.chunks(64).map(KeyString::try_from).collect::<Result<_, _>>()?);
+            i += 72 + values_len;
+        }
+    }
+    Ok(enums)
 }
 
 
@@ -2023,6 +4094,9 @@ pub fn subtable_from_keys(table: &ColumnTable, mut keys: Vec<KeyString>) -> Resu
             }
         },
         DbType::Float => unreachable!("There should never be a float primary key"),
+        DbType::Duration => unreachable!("There should never be a duration primary key"),
+        DbType::LongText => unreachable!("There should never be a long text primary key"),
+        DbType::Enum => unreachable!("There should never be an enum primary key"),
     };
 
     Ok(
@@ -2030,32 +4104,369 @@ pub fn subtable_from_keys(table: &ColumnTable, mut keys: Vec<KeyString>) -> Resu
     )
 }
 
-pub fn table_from_inserts(value_columns: &[KeyString], values: &str, table_name: &str) -> Result<ColumnTable, EzError> {
-    let mut new_header = Vec::new();
+/// Parses the header line of an EZ CSV. See EZ CSV FORMAT in ColumnTable::from_csv_string.
+/// Casts every cell of a column to another type. Ints and floats convert to each other with floats rounded to the
+/// nearest int. Durations convert to and from ints and floats as seconds. Every type converts to text, and text
+/// converts to any type its cells parse as. Enum columns only convert to text and nothing converts to an enum.
+pub fn cast_column(item: &HeaderItem, column: &DbColumn, kind: DbType) -> Result<DbColumn, EzError> {
 
-    let first_line = match values.split('\n').next() {
-        Some(x) => x,
-        None => return Err(EzError{tag: ErrorTag::Deserialization, text: ("Empty input".to_owned())}),
+    let fail = |row: usize, cell: String| EzError{tag: ErrorTag::Query, text: format!("Row {} of column '{}' can't be cast to {}: '{}'", row, item.name, kind.name(), cell)};
+
+    if kind == DbType::Enum {
+        return Err(EzError{tag: ErrorTag::Query, text: format!("Column '{}' can't be cast to an enum. Add an enum column instead", item.name)})
+    }
+    if item.kind == kind {
+        return Ok(column.clone())
+    }
+
+    let cast = match (column, kind) {
+        (DbColumn::Ints(col), DbType::Float) if item.kind == DbType::Int => DbColumn::Floats(col.iter().map(|x| *x as f32).collect()),
+        (DbColumn::Ints(col), DbType::Duration) if item.kind == DbType::Int => DbColumn::Durations(col.iter().map(|x| *x as i64 * 1_000_000_000).collect()),
+        (DbColumn::Floats(col), DbType::Int) => DbColumn::Ints(col.iter().enumerate()
+            .map(|(row, x)| match x.is_finite() && x.round() >= i32::MIN as f32 && x.round() <= i32::MAX as f32 {
+                true => Ok(x.round() as i32),
+                false => Err(fail(row, x.to_string())),
+            })
+            .collect::<Result<_, _>>()?),
+        (DbColumn::Floats(col), DbType::Duration) => DbColumn::Durations(col.iter().enumerate()
+            .map(|(row, x)| match x.is_finite() {
+                true => Ok((*x as f64 * 1e9).round() as i64),
+                false => Err(fail(row, x.to_string())),
+            })
+            .collect::<Result<_, _>>()?),
+        (DbColumn::Durations(col), DbType::Int) => DbColumn::Ints(col.iter().enumerate()
+            .map(|(row, x)| i32::try_from(x / 1_000_000_000).map_err(|_| fail(row, format_duration(*x))))
+            .collect::<Result<_, _>>()?),
+        (DbColumn::Durations(col), DbType::Float) => DbColumn::Floats(col.iter().map(|x| (*x as f64 / 1e9) as f32).collect()),
+        (_, DbType::Text) => DbColumn::Texts(cells_as_text(item, column)?.into_iter().enumerate()
+            .map(|(row, cell)| KeyString::from_str_checked(&cell).map_err(|_| fail(row, cell)))
+            .collect::<Result<_, _>>()?),
+        (_, DbType::LongText) => DbColumn::LongTexts(cells_as_text(item, column)?.iter().map(String::as_str).collect()),
+        (DbColumn::Texts(_) | DbColumn::LongTexts(_), _) => {
+            let cells = cells_as_text(item, column)?.into_iter().enumerate();
+            match kind {
+                DbType::Int => DbColumn::Ints(cells.map(|(row, cell)| cell.trim().parse::<i32>().map_err(|_| fail(row, cell))).collect::<Result<_, _>>()?),
+                DbType::Float => DbColumn::Floats(cells.map(|(row, cell)| cell.trim().parse::<f32>().map_err(|_| fail(row, cell))).collect::<Result<_, _>>()?),
+                DbType::Duration => DbColumn::Durations(cells.map(|(row, cell)| parse_duration(&cell).map_err(|_| fail(row, cell))).collect::<Result<_, _>>()?),
+                _ => unreachable!("Text targets and enums are handled above"),
+            }
+        },
+        _ => return Err(EzError{tag: ErrorTag::Query, text: format!("{} column '{}' can't be cast to {}", item.kind.name(), item.name, kind.name())}),
     };
 
-    let mut i = 0;
-    for value in first_line.split(';') {
-        let temp_key: TableKey;
-        if i == 0 {
-            temp_key = TableKey::Primary;
-        } else {
-            temp_key = TableKey::None;
-        }
-        if value.parse::<f32>().is_ok() {
-            new_header.push(HeaderItem{name: value_columns[i], kind: DbType::Float, key: temp_key})
-        } else if value.parse::<i32>().is_ok() {
-            new_header.push(HeaderItem{name: value_columns[i], kind: DbType::Int, key: temp_key})
-        } else if value.len() <= 64 {
-            new_header.push(HeaderItem{name: value_columns[i], kind: DbType::Text, key: temp_key})
-        } else {
-            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unsupported type: {}", value)})
+    Ok(cast)
+}
+
+/// Every cell of a column as it reads in a query result. Enum cells are their values.
+fn cells_as_text(item: &HeaderItem, column: &DbColumn) -> Result<Vec<String>, EzError> {
+    let cells: Vec<String> = match column {
+        DbColumn::Ints(col) if item.kind == DbType::Enum => col.iter().map(|index| item.enum_value(*index).map(|value| value.to_string())).collect::<Result<_, _>>()?,
+        DbColumn::Ints(col) => col.iter().map(|x| x.to_string()).collect(),
+        DbColumn::Floats(col) => col.iter().map(|x| x.to_string()).collect(),
+        DbColumn::Texts(col) => col.iter().map(|x| x.as_str().to_owned()).collect(),
+        DbColumn::Durations(col) => col.iter().map(|x| format_duration(*x)).collect(),
+        DbColumn::LongTexts(col) => col.iter().map(str::to_owned).collect(),
+    };
+    Ok(cells)
+}
+
+pub fn parse_csv_header(line: &str) -> Result<Vec<HeaderItem>, EzError> {
+
+    let mut header = Vec::new();
+    let mut primary_key_set = false;
+
+    let first_line: Vec<&str> = line.split(';').collect();
+    for item in first_line {
+        let header_item = parse_header_item(item)?;
+        if header_item.key == TableKey::Primary {
+            if primary_key_set {
+                return Err(EzError{tag: ErrorTag::Deserialization, text: ("Too many primary keys specified".to_owned())});
+            }
+            primary_key_set = true;
+        }
+        header.push(header_item);
+    }
+
+    if !primary_key_set {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: "No primary key specified".to_owned()})
+    }
+
+    Ok(header)
+}
+
+/// Parses one column of an EZ CSV header, like "price,f-N", "status,e(open|closed)-N" or "name,t-N-ci".
+pub fn parse_header_item(item: &str) -> Result<HeaderItem, EzError> {
+
+    let temp: Vec<&str> = item.split(',').collect();
+    let mut header_item = HeaderItem::new();
+    if temp.is_empty() {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: ("Header is empty".to_owned())});
+    } else if temp.len() == 1 {
+        header_item.kind = DbType::Text;
+    } else if temp.len() > 2 {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: ("Incorrectly formatted header".to_owned())});
+    } else {
+        header_item.name = KeyString::from_input(temp[0].trim())?;
+        // Enum values may contain '-' so the key comes after the closing parenthesis
+        let kind_and_key = temp[1].trim();
+        let (next, key) = match kind_and_key.find(')') {
+            Some(close) => (&kind_and_key[..=close], kind_and_key[close + 1..].strip_prefix('-').unwrap_or("")),
+            None => kind_and_key.split_once('-').unwrap_or((kind_and_key, "")),
+        };
+        match next {
+            "I" | "Int" | "int" | "i" => header_item.kind = DbType::Int,
+            "F" | "Float" | "float" | "f" => header_item.kind = DbType::Float,
+            "T" | "Text" | "text" | "t" => header_item.kind = DbType::Text,
+            "D" | "Duration" | "duration" | "d" => header_item.kind = DbType::Duration,
+            "L" | "LongText" | "longtext" | "l" => header_item.kind = DbType::LongText,
+            _ => match parse_enum_type(next)? {
+                Some(values) => {
+                    header_item.kind = DbType::Enum;
+                    header_item.values = values;
+                },
+                None => return Err(EzError{tag: ErrorTag::Deserialization, text: (format!("Unsupported type: {}", next))}),
+            },
+        }
+        // A collation can follow the key, as in "name,t-N-ci"
+        let (key, collation) = key.split_once('-').unwrap_or((key, ""));
+        match key {
+            "P" => header_item.key = TableKey::Primary,
+            "N" => header_item.key = TableKey::None,
+            "F" => header_item.key = TableKey::Foreign,
+            _ => return Err(EzError{tag: ErrorTag::Deserialization, text: ("Unsupported key type".to_owned())}),
+        }
+        if !collation.is_empty() {
+            header_item.collation = Collation::from_name(collation)?;
+            if header_item.collation != Collation::Binary && header_item.kind != DbType::Text {
+                return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Column '{}' is not a text column and can't have a collation", header_item.name)})
+            }
+        }
+    }
+    if header_item.kind == DbType::Enum {
+        header_item = HeaderItem::new_enum(header_item.name, header_item.key, header_item.values)
+            .map_err(|e| EzError{tag: ErrorTag::Deserialization, text: e.text})?;
+    }
+    Ok(header_item)
+}
+
+/// Reads the values out of an enum type like "e(open|closed)". None if it is not an enum type.
+fn parse_enum_type(kind: &str) -> Result<Option<Vec<KeyString>>, EzError> {
+    let values = ["E(", "Enum(", "enum(", "e("].iter()
+        .find_map(|prefix| kind.strip_prefix(prefix))
+        .and_then(|rest| rest.strip_suffix(')'));
+    match values {
+        Some(values) => Ok(Some(values.split('|').map(|value| KeyString::from_input(value.trim())).collect::<Result<_, _>>()?)),
+        None => Ok(None),
+    }
+}
+
+/// How the cells of one csv column are written, for data exported with locale formats.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsvColumnFormat {
+    /// Replaced with '.' in float columns.
+    pub decimal_separator: char,
+    /// Removed from int and float columns. Commonly ',', '.', ' ' or '\''.
+    pub thousands_separator: Option<char>,
+    /// Parses dates into duration columns as time since the Unix epoch, like the row timestamp columns,
+    /// or into int columns as seconds since the epoch. Supports %Y, %m, %d, %H, %M, %S and %% with
+    /// every other character matched literally, e.g. "%d.%m.%Y" or "%Y-%m-%d %H:%M:%S". Times are UTC.
+    pub date_format: Option<String>,
+}
+
+impl Default for CsvColumnFormat {
+    fn default() -> Self {
+        CsvColumnFormat { decimal_separator: '.', thousands_separator: None, date_format: None }
+    }
+}
+
+impl CsvColumnFormat {
+    pub fn decimal(decimal_separator: char, thousands_separator: Option<char>) -> CsvColumnFormat {
+        CsvColumnFormat { decimal_separator, thousands_separator, date_format: None }
+    }
+
+    pub fn date(date_format: &str) -> CsvColumnFormat {
+        CsvColumnFormat { date_format: Some(date_format.to_owned()), ..Default::default() }
+    }
+}
+
+/// Per column parse formats applied while importing a csv. Columns not in the spec are parsed as usual.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CsvImportSpec {
+    pub columns: BTreeMap<KeyString, CsvColumnFormat>,
+}
+
+impl CsvImportSpec {
+    pub fn new() -> CsvImportSpec {
+        CsvImportSpec { columns: BTreeMap::new() }
+    }
+
+    pub fn column(mut self, name: &str, format: CsvColumnFormat) -> CsvImportSpec {
+        self.columns.insert(ksf(name), format);
+        self
+    }
+
+    /// Makes sure every column in the spec is in the csv and that its format makes sense for the column type.
+    pub fn check_header(&self, header: &[HeaderItem]) -> Result<(), EzError> {
+        for (name, format) in &self.columns {
+            let item = match header.iter().find(|item| item.name == *name) {
+                Some(item) => item,
+                None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("The import spec has a format for column '{}' which is not in the csv", name)}),
+            };
+            if Some(format.decimal_separator) == format.thousands_separator {
+                return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Column '{}' uses '{}' as both the decimal and the thousands separator", name, format.decimal_separator)})
+            }
+            match (&format.date_format, item.kind) {
+                (Some(_), DbType::Int | DbType::Duration) => (),
+                (Some(_), kind) => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Column '{}' has a date format but dates can only be imported into int or duration columns, not {}", name, kind.name())}),
+                (None, DbType::Int | DbType::Float) => (),
+                (None, kind) => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Column '{}' has number separators but is a {} column", name, kind.name())}),
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites a cell into the form the EZ CSV parser expects. Cells of columns without a format are borrowed as is.
+    pub fn normalize<'a>(&self, item: &HeaderItem, cell: &'a str) -> Result<std::borrow::Cow<'a, str>, EzError> {
+        use std::borrow::Cow;
+
+        let format = match self.columns.get(&item.name) {
+            Some(format) => format,
+            None => return Ok(Cow::Borrowed(cell)),
+        };
+
+        if let Some(date_format) = &format.date_format {
+            let seconds = parse_date(cell, date_format)?;
+            return match item.kind {
+                DbType::Duration => match seconds.checked_mul(1_000_000_000) {
+                    Some(nanos) => Ok(Cow::Owned(format!("{}ns", nanos))),
+                    None => Err(EzError{tag: ErrorTag::Deserialization, text: format!("Date '{}' is out of range", cell)}),
+                },
+                _ => match i32::try_from(seconds) {
+                    Ok(seconds) => Ok(Cow::Owned(seconds.to_string())),
+                    Err(_) => Err(EzError{tag: ErrorTag::Deserialization, text: format!("Date '{}' does not fit in an int column as seconds since 1970. Use a duration column", cell)}),
+                },
+            }
+        }
+
+        let mut normalized = String::with_capacity(cell.len());
+        for c in cell.chars() {
+            if Some(c) == format.thousands_separator {
+                continue;
+            } else if c == format.decimal_separator && item.kind == DbType::Float {
+                normalized.push('.');
+            } else {
+                normalized.push(c);
+            }
+        }
+        Ok(Cow::Owned(normalized))
+    }
+
+    fn normalize_column<'a>(&self, item: &HeaderItem, column: Vec<&'a str>) -> Result<Vec<std::borrow::Cow<'a, str>>, EzError> {
+        column.into_iter().map(|cell| self.normalize(item, cell)).collect()
+    }
+}
+
+/// Parses a date written in the given format to seconds since the Unix epoch. See CsvColumnFormat::date_format.
+pub fn parse_date(s: &str, format: &str) -> Result<i64, EzError> {
+
+    let error = || EzError{tag: ErrorTag::Deserialization, text: format!("Could not parse '{}' as a date with the format '{}'", s, format)};
+
+    let (mut year, mut month, mut day, mut hour, mut minute, mut second) = (1970i64, 1i64, 1i64, 0i64, 0i64, 0i64);
+    let mut input = s.trim();
+    let mut spec = format.chars();
+    while let Some(c) = spec.next() {
+        if c != '%' {
+            input = input.strip_prefix(c).ok_or_else(error)?;
+            continue;
         }
-        i += 1;
+        let field = spec.next().ok_or_else(error)?;
+        if field == '%' {
+            input = input.strip_prefix('%').ok_or_else(error)?;
+            continue;
+        }
+        let max_digits = if field == 'Y' { 4 } else { 2 };
+        let digits = input.chars().take(max_digits).take_while(|c| c.is_ascii_digit()).count();
+        if digits == 0 {
+            return Err(error())
+        }
+        let value: i64 = input[..digits].parse().map_err(|_| error())?;
+        input = &input[digits..];
+        match field {
+            'Y' => year = value,
+            'm' => month = value,
+            'd' => day = value,
+            'H' => hour = value,
+            'M' => minute = value,
+            'S' => second = value,
+            _ => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unsupported date format field '%{}'. Use %Y, %m, %d, %H, %M or %S", field)}),
+        }
+    }
+    if !input.is_empty() {
+        return Err(error())
+    }
+
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return Err(error()),
+    };
+    if day < 1 || day > days_in_month || hour > 23 || minute > 59 || second > 59 {
+        return Err(error())
+    }
+
+    // Days from the civil calendar date, counting years from March so the leap day comes last
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Ok(days * 86_400 + hour * 3_600 + minute * 60 + second)
+}
+
+/// Rows that ColumnTable::from_csv_reader parses before appending them to the table.
+pub const CSV_IMPORT_BATCH_ROWS: usize = 65_536;
+
+/// Parses one cell of a csv body and pushes it to the end of the column.
+fn push_csv_cell(item: &HeaderItem, column: &mut DbColumn, cell: &str, line_number: usize) -> Result<(), EzError> {
+    if item.kind == DbType::Enum {
+        match (column, item.enum_index(cell)) {
+            (DbColumn::Ints(col), Ok(index)) => col.push(index),
+            (_, Err(e)) => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("{} on line {}", e.text, line_number)}),
+            _ => unreachable!("Enum columns are always int columns"),
+        }
+        return Ok(())
+    }
+    match column {
+        DbColumn::Ints(col) => match cell.parse::<i32>() {
+            Ok(x) => col.push(x),
+            Err(_) => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Could not parse int '{}' on line {}", cell, line_number)}),
+        },
+        DbColumn::Floats(col) => match cell.parse::<f32>() {
+            Ok(x) => col.push(x),
+            Err(_) => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Could not parse float '{}' on line {}", cell, line_number)}),
+        },
+        DbColumn::Texts(col) => col.push(KeyString::from_input(cell)?),
+        DbColumn::Durations(col) => col.push(parse_duration(cell)?),
+        DbColumn::LongTexts(col) => col.push(cell),
+    }
+    Ok(())
+}
+
+pub fn table_from_inserts(value_columns: &[KeyString], values: &str, table_name: &str) -> Result<ColumnTable, EzError> {
+
+    if values.split('\n').next().is_none() {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: ("Empty input".to_owned())})
+    }
+
+    // Inserts are matched against the target table on the first value column, whatever its values look like
+    let mut new_header = infer_header(value_columns, values)?;
+    for (i, item) in new_header.iter_mut().enumerate() {
+        item.key = if i == 0 { TableKey::Primary } else { TableKey::None };
     }
 
     let mut csv = print_sep_list(&new_header, ";");
@@ -2066,6 +4477,119 @@ pub fn table_from_inserts(value_columns: &[KeyString], values: &str, table_name:
     Ok(input_table)
 }
 
+/// Picks the narrowest type every value fits in. Ints are tried before floats since every int also parses as a float.
+/// Durations need a unit on every value so plain numbers are never inferred as durations.
+/// Text longer than a KeyString makes the column a LongText.
+pub fn infer_column_type(values: &[&str]) -> Result<DbType, EzError> {
+
+    if values.iter().all(|v| v.parse::<i32>().is_ok()) {
+        Ok(DbType::Int)
+    } else if values.iter().all(|v| v.parse::<f32>().is_ok()) {
+        Ok(DbType::Float)
+    } else if values.iter().all(|v| parse_duration(v).is_ok()) {
+        Ok(DbType::Duration)
+    } else if values.iter().any(|v| v.len() > 64) {
+        Ok(DbType::LongText)
+    } else {
+        Ok(DbType::Text)
+    }
+}
+
+/// Infers a header from ';' separated rows of values.
+/// The primary key is the first Int column whose values are all unique, or failing that the first such Text column.
+/// If no column qualifies the first column is used, same as before inference looked at more than one row.
+pub fn infer_header(value_columns: &[KeyString], values: &str) -> Result<Vec<HeaderItem>, EzError> {
+
+    let rows: Vec<Vec<&str>> = values.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.split(';').collect())
+        .collect();
+
+    if rows.is_empty() {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: ("Empty input".to_owned())})
+    }
+
+    let mut header = Vec::with_capacity(value_columns.len());
+    let mut unique = Vec::with_capacity(value_columns.len());
+    for (i, name) in value_columns.iter().enumerate() {
+        let mut column = Vec::with_capacity(rows.len());
+        for row in &rows {
+            match row.get(i) {
+                Some(value) => column.push(*value),
+                None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Row '{}' has fewer than {} values", print_sep_list(row, ";"), value_columns.len())}),
+            }
+        }
+        let kind = infer_column_type(&column)?;
+        let distinct: HashSet<&str> = column.iter().copied().collect();
+        unique.push(distinct.len() == column.len());
+        header.push(HeaderItem{name: *name, kind, key: TableKey::None, values: Vec::new(), collation: Collation::Binary});
+    }
+
+    let candidate = header.iter().zip(&unique).position(|(item, u)| *u && item.kind == DbType::Int)
+        .or_else(|| header.iter().zip(&unique).position(|(item, u)| *u && item.kind == DbType::Text))
+        .unwrap_or(0);
+    if let Some(item) = header.get_mut(candidate) {
+        item.key = TableKey::Primary;
+    }
+
+    Ok(header)
+}
+
+/// Proposes a schema for sample CSV data without creating anything.
+/// The first line of the sample names the columns. Any type annotations on it are ignored.
+/// The result has one row per column, with the proposed header item in the "header" column ready to paste into a CSV.
+pub fn infer_schema(sample: &str, table_name: &str) -> Result<ColumnTable, EzError> {
+    println!("calling: infer_schema()");
+
+    let (first_line, values) = match sample.split_once('\n') {
+        Some(x) => x,
+        None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Sample for '{}' needs a line of column names and at least one row", table_name)}),
+    };
+
+    let value_columns: Vec<KeyString> = first_line.split(';')
+        .map(|item| ksf(item.split(',').next().unwrap_or(item)))
+        .collect();
+    let distinct_names: HashSet<&KeyString> = value_columns.iter().collect();
+    if distinct_names.len() != value_columns.len() {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Repeated column name in '{}'", first_line)})
+    }
+
+    let header = infer_header(&value_columns, values)?;
+
+    let rows: Vec<Vec<&str>> = values.lines().filter(|line| !line.is_empty()).map(|line| line.split(';').collect()).collect();
+    let mut kinds = Vec::new();
+    let mut keys = Vec::new();
+    let mut distinct = Vec::new();
+    let mut header_items = Vec::new();
+    for (i, item) in header.iter().enumerate() {
+        let values: HashSet<&str> = rows.iter().map(|row| row[i]).collect();
+        kinds.push(match item.kind {
+            DbType::Int => ksf("Int"),
+            DbType::Float => ksf("Float"),
+            DbType::Text => ksf("Text"),
+            DbType::Duration => ksf("Duration"),
+            DbType::LongText => ksf("LongText"),
+            DbType::Enum => ksf("Enum"),
+        });
+        keys.push(match item.key {
+            TableKey::Primary => ksf("P"),
+            TableKey::Foreign => ksf("F"),
+            TableKey::None => ksf("N"),
+        });
+        distinct.push(values.len() as i32);
+        header_items.push(ksf(&item.to_string()));
+    }
+
+    let mut table = ColumnTable::create_empty(&format!("{}_schema", table_name), "inference");
+    table.add_column(ksf("column_name"), DbColumn::Texts(value_columns))?;
+    table.add_column(ksf("type"), DbColumn::Texts(kinds))?;
+    table.add_column(ksf("key"), DbColumn::Texts(keys))?;
+    table.add_column(ksf("distinct"), DbColumn::Ints(distinct))?;
+    table.add_column(ksf("header"), DbColumn::Texts(header_items))?;
+
+    Ok(table)
+}
+
 
 /// Helper function for the table sorting.
 /// This rearranges a column by a list of given indexes.
@@ -2099,8 +4623,23 @@ pub fn remove_indices<T>(vec: &mut Vec<T>, indices: &[usize]) {
     vec.truncate(vec.len() - shift);
 }
 
+/// Keeps the items whose bit is set in the mask. Bit i of word i / 64 belongs to item i.
+pub fn retain_by_mask<T>(vec: &mut Vec<T>, keep: &[u64]) {
+    let mut index = 0;
+    vec.retain(|_| {
+        let kept = keep[index / 64] & (1 << (index % 64)) != 0;
+        index += 1;
+        kept
+    });
+}
+
 /// Helper function to merge two sorted Vecs. Used in the update methods.
 fn merge_sorted<T: Ord + Clone + Display + Debug>(one: &[T], two: &[T]) -> (Vec<T>, Vec<u8>) {
+    merge_sorted_by(one, two, T::cmp)
+}
+
+/// Like merge_sorted() for Vecs sorted by `compare`, such as text keys sorted by their collation.
+fn merge_sorted_by<T: Clone, F: Fn(&T, &T) -> std::cmp::Ordering>(one: &[T], two: &[T], compare: F) -> (Vec<T>, Vec<u8>) {
     
 
     let mut output: Vec<T> = Vec::with_capacity(one.len() + two.len());
@@ -2108,11 +4647,19 @@ fn merge_sorted<T: Ord + Clone + Display + Debug>(one: &[T], two: &[T]) -> (Vec<
     let mut one_pointer = 0;
     let mut two_pointer = 0;
 
+    if one.is_empty() || two.is_empty() {
+        output.extend_from_slice(one);
+        output.extend_from_slice(two);
+        record_vec.resize(one.len(), 1);
+        record_vec.resize(one.len() + two.len(), 2);
+        return (output, record_vec)
+    }
+
     // println!("RUNNING merge_sorted()!!!--------------------------------");
     loop {
         // println!("one[{one_pointer}]: {}\t\ttwo[{two_pointer}]: {}", one[one_pointer], two[two_pointer]);
 
-        match one[one_pointer].cmp(&two[two_pointer]) {
+        match compare(&one[one_pointer], &two[two_pointer]) {
             std::cmp::Ordering::Less => {
                 output.push(one[one_pointer].clone());
                 record_vec.push(1);
@@ -2171,6 +4718,44 @@ fn merge_sorted<T: Ord + Clone + Display + Debug>(one: &[T], two: &[T]) -> (Vec<
     (output, record_vec)
 }
 
+/// Like merge_in_order() but keeps the value from `one` where a row of `two` overwrites it.
+/// Used for the creation time of rows that an update replaces.
+fn merge_in_order_keeping_existing<T: Clone>(one: &[T], two: &[T], record_vec: &[u8]) -> Vec<T> {
+
+    let mut output = Vec::with_capacity(record_vec.len());
+    let mut one_pointer = 0;
+    let mut two_pointer = 0;
+    for index in record_vec {
+        match index {
+            1 => {
+                output.push(one[one_pointer].clone());
+                one_pointer += 1;
+            }
+            2 => {
+                output.push(two[two_pointer].clone());
+                two_pointer += 1;
+            }
+            _ => {
+                output.push(one[one_pointer].clone());
+                one_pointer += 1;
+                two_pointer += 1;
+            }
+        }
+    }
+    output
+}
+
+/// Like merge_in_order_keeping_existing() but overwritten rows go up one version.
+fn merge_versions(one: &[i64], two: &[i64], record_vec: &[u8]) -> Vec<i64> {
+    let mut output = merge_in_order_keeping_existing(one, two, record_vec);
+    for (version, record) in output.iter_mut().zip(record_vec) {
+        if *record == 3 {
+            *version += 1;
+        }
+    }
+    output
+}
+
 /// Helper function for merging two unsorted vecs in the order of another vec. Used to sort.
 fn merge_in_order<T: Clone>(one: &[T], two: &[T], record_vec: &[u8]) -> Vec<T> {
     
@@ -2222,6 +4807,11 @@ impl Value {
         }
     }
 
+    /// Bytes the value holds in memory, the same way ColumnTable::byte_size() counts.
+    pub fn byte_size(&self) -> usize {
+        size_of::<KeyString>() + self.body.len()
+    }
+
     pub fn update(&mut self, value: Value) {
         
 
@@ -2266,10 +4856,12 @@ mod tests {
 
     use std::io::Write;
 
-    use aes_gcm::Key;
     use ezcbor::cbor::decode_cbor;
     use rand::Rng;
 
+    use crate::paths::test_file;
+    use crate::testing_tools::create_fixed_table;
+
     use super::*;
 
     #[test]
@@ -2331,17 +4923,11 @@ mod tests {
 
     #[test]
     fn test_columntable_combine_unsorted_csv() {
-        let unsorted1 = std::fs::read_to_string(format!(
-            "test_files{PATH_SEP}test_csv_from_google_sheets_unsorted.csv"
-        ))
+        let unsorted1 = std::fs::read_to_string(test_file("test_csv_from_google_sheets_unsorted.csv"))
         .unwrap();
-        let unsorted2 = std::fs::read_to_string(format!(
-            "test_files{PATH_SEP}test_csv_from_google_sheets2_unsorted.csv"
-        ))
+        let unsorted2 = std::fs::read_to_string(test_file("test_csv_from_google_sheets2_unsorted.csv"))
         .unwrap();
-        let sorted_combined = std::fs::read_to_string(format!(
-            "test_files{PATH_SEP}test_csv_from_google_sheets_combined_sorted.csv"
-        ))
+        let sorted_combined = std::fs::read_to_string(test_file("test_csv_from_google_sheets_combined_sorted.csv"))
         .unwrap();
 
         let mut a = ColumnTable::from_csv_string(&unsorted1, "a", "test").unwrap();
@@ -2382,9 +4968,7 @@ mod tests {
     #[test]
     fn test_binary_format() {
         // let input = "vnr,i-P;heiti,t;magn,i\n113035;undirlegg;200\n113050;annad undirlegg;500";
-        let input = std::fs::read_to_string(format!(
-            "test_files{PATH_SEP}test_csv_from_google_sheets_combined_sorted.csv"
-        ))
+        let input = std::fs::read_to_string(test_file("test_csv_from_google_sheets_combined_sorted.csv"))
         .unwrap();
         let t = ColumnTable::from_csv_string(&input, "test", "test").unwrap();
         let bin_t = t.to_binary();
@@ -2396,13 +4980,9 @@ mod tests {
 
     #[test]
     fn test_delete_range() {
-        let input = std::fs::read_to_string(format!(
-            "test_files{PATH_SEP}test_csv_from_google_sheets_combined_sorted.csv"
-        ))
+        let input = std::fs::read_to_string(test_file("test_csv_from_google_sheets_combined_sorted.csv"))
         .unwrap();
-        let test_input = std::fs::read_to_string(format!(
-            "test_files{PATH_SEP}test_csv_from_google_sheets_combined_sorted_test_range.csv"
-        ))
+        let test_input = std::fs::read_to_string(test_file("test_csv_from_google_sheets_combined_sorted_test_range.csv"))
         .unwrap();
         let mut t = ColumnTable::from_csv_string(&input, "test", "test").unwrap();
 
@@ -2415,13 +4995,9 @@ mod tests {
 
     #[test]
     fn test_delete_list() {
-        let input = std::fs::read_to_string(format!(
-            "test_files{PATH_SEP}test_csv_from_google_sheets_combined_sorted.csv"
-        ))
+        let input = std::fs::read_to_string(test_file("test_csv_from_google_sheets_combined_sorted.csv"))
         .unwrap();
-        let test_input = std::fs::read_to_string(format!(
-            "test_files{PATH_SEP}test_csv_from_google_sheets_combined_sorted_test_range.csv"
-        ))
+        let test_input = std::fs::read_to_string(test_file("test_csv_from_google_sheets_combined_sorted_test_range.csv"))
         .unwrap();
         let mut t = ColumnTable::from_csv_string(&input, "test", "test").unwrap();
 
@@ -2434,16 +5010,12 @@ mod tests {
 
     #[test]
     fn test_copy_lines() {
-        let input_string = std::fs::read_to_string(format!(
-            "test_files{PATH_SEP}test_csv_from_google_sheets_combined_sorted.csv"
-        ))
+        let input_string = std::fs::read_to_string(test_file("test_csv_from_google_sheets_combined_sorted.csv"))
         .unwrap();
 
         let table = ColumnTable::from_csv_string(&input_string, "source", "test").unwrap();
 
-        let input_string = std::fs::read_to_string(format!(
-            "test_files{PATH_SEP}test_csv_from_google_sheets_sorted.csv"
-        ))
+        let input_string = std::fs::read_to_string(test_file("test_csv_from_google_sheets_sorted.csv"))
         .unwrap();
 
         let mut target = ColumnTable::from_csv_string(&input_string, "target", "test").unwrap();
@@ -2463,7 +5035,7 @@ mod tests {
 
     #[test]
     fn test_subtable_from_index_range() {
-        let table_string = std::fs::read_to_string(&format!("test_files{PATH_SEP}test_csv_from_google_sheets_combined_sorted.csv")).unwrap();
+        let table_string = std::fs::read_to_string(test_file("test_csv_from_google_sheets_combined_sorted.csv")).unwrap();
         let table = ColumnTable::from_csv_string(&table_string, "basic_test", "test").unwrap();
         let subtable = table.create_subtable_from_index_range(0, 7515);
         println!("{}", subtable);
@@ -2471,8 +5043,8 @@ mod tests {
 
     #[test]
     fn test_left_join() {
-        let left_string = std::fs::read_to_string(format!("test_files{PATH_SEP}employees.csv")).unwrap();
-        let right_string = std::fs::read_to_string(format!("test_files{PATH_SEP}departments.csv")).unwrap();
+        let left_string = std::fs::read_to_string(test_file("employees.csv")).unwrap();
+        let right_string = std::fs::read_to_string(test_file("departments.csv")).unwrap();
 
         let mut left_table = ColumnTable::from_csv_string(&left_string, "employees", "test").unwrap();
         let right_table = ColumnTable::from_csv_string(&right_string, "departments", "test").unwrap();
@@ -2485,7 +5057,7 @@ mod tests {
 
     #[test]
     fn test_cbor_eztable() {
-        let csv = std::fs::read_to_string(format!("test_files{PATH_SEP}departments.csv")).unwrap();
+        let csv = std::fs::read_to_string(test_file("departments.csv")).unwrap();
         let table = ColumnTable::from_csv_string(&csv, "cbor test", "test").unwrap();
         println!("table:\n{}", table);
         let bytes = table.to_cbor_bytes();
@@ -2494,6 +5066,489 @@ mod tests {
         assert_eq!(table, decoded_table);
     }
 
+    #[test]
+    fn test_deduplicate() {
+        let input = "id,i-P;name,t-N;price,f-N\n1;apple;1.5\n2;pear;2\n3;apple;1.5\n4;apple;2.5\n5;pear;2";
+        let mut table = ColumnTable::from_csv_string(input, "dedup", "test").unwrap();
+        assert_eq!(table.duplicate_indexes(), vec![2, 4]);
+        assert_eq!(table.deduplicate(), 2);
+        assert_eq!(table.get_column_int(&ksf("id")).unwrap(), &vec![1, 2, 4]);
+        assert_eq!(table.deduplicate(), 0);
+
+        // Every row has its own row id so engine columns are left out of the comparison
+        let mut table = ColumnTable::from_csv_string(input, "dedup", "test").unwrap();
+        table.add_row_ids().unwrap();
+        assert_eq!(table.deduplicate(), 2);
+        assert_eq!(table.get_column_int(&ksf("id")).unwrap(), &vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn test_bulk_load_validation() {
+        let table = create_fixed_table(10);
+        let binary = table.to_binary();
+        assert_eq!(column_table_binary_len(&binary).unwrap(), binary.len());
+
+        let mut unsorted = ColumnTable::from_csv_string("id,i-P;name,t-N\n3;c\n1;a\n2;b", "bulk", "test").unwrap();
+        unsorted.columns.insert(ksf("id"), DbColumn::Ints(vec![3, 1, 2]));
+        unsorted.columns.insert(ksf("name"), DbColumn::Texts(vec![ksf("c"), ksf("a"), ksf("b")]));
+        unsorted.validate_and_sort().unwrap();
+        assert_eq!(unsorted.get_column_int(&ksf("id")).unwrap(), &vec![1, 2, 3]);
+
+        unsorted.columns.insert(ksf("id"), DbColumn::Ints(vec![1, 1, 2]));
+        assert!(unsorted.validate_and_sort().is_err());
+    }
+
+    #[test]
+    fn test_row_ids() {
+        let mut table = ColumnTable::from_csv_string("id,i-P;name,t-N\n5;saw\n7;drill", "tools", "test").unwrap();
+        table.add_row_ids().unwrap();
+        assert_eq!(table.get_column_duration(&ksf(ROW_ID_COLUMN)).unwrap(), &vec![1, 2]);
+
+        // 7 keeps its id, 1 and 6 get new ones and move the others
+        let inserts = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;hammer\n6;wrench\n7;drill", "inserts", "test").unwrap();
+        table.update(&inserts).unwrap();
+        assert_eq!(table.get_column_int(&ksf("id")).unwrap(), &vec![1, 5, 6, 7]);
+        assert_eq!(table.get_column_duration(&ksf(ROW_ID_COLUMN)).unwrap(), &vec![3, 1, 4, 2]);
+        assert_eq!(table.row_id_index(2), Some(3));
+
+        table.delete_by_indexes(&[0]);
+        assert_eq!(table.row_id_index(2), Some(2));
+        assert_eq!(table.next_row_id(), 5);
+
+        table.hide_row_ids();
+        assert!(!table.has_row_ids());
+        assert!(table.header.iter().all(|item| item.name.as_str() != ROW_ID_COLUMN));
+    }
+
+    #[test]
+    fn test_row_timestamps() {
+        let mut table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;saw\n2;drill", "tools", "test").unwrap();
+        table.add_row_timestamps().unwrap();
+        assert!(table.has_row_timestamps());
+        // Backdate so the changes below are visible
+        table.set_row_timestamps(1000).unwrap();
+
+        let inserts = ColumnTable::from_csv_string("id,i-P;name,t-N\n2;hammer\n3;wrench", "inserts", "test").unwrap();
+        let before = get_current_time();
+        table.update(&inserts).unwrap();
+        let created = table.get_column_duration(&ksf(CREATED_AT_COLUMN)).unwrap().clone();
+        let updated = table.get_column_duration(&ksf(UPDATED_AT_COLUMN)).unwrap().clone();
+        assert_eq!(created[0..2], [epoch_nanos(1000), epoch_nanos(1000)]);
+        assert!(created[2] > epoch_nanos(1000));
+        assert_eq!(updated[0], epoch_nanos(1000));
+        assert!(updated[1] > epoch_nanos(1000) && updated[2] > epoch_nanos(1000));
+        // Whole epoch seconds in nanoseconds
+        assert_eq!(created[2] % 1_000_000_000, 0);
+        assert!(created[2] >= epoch_nanos(before) && created[2] <= epoch_nanos(get_current_time()));
+        assert_eq!(table.get_column_text(&ksf("name")).unwrap()[1], ksf("hammer"));
+
+        table.touch_rows(&[0], 2000);
+        assert_eq!(table.get_column_duration(&ksf(UPDATED_AT_COLUMN)).unwrap()[0], epoch_nanos(2000));
+    }
+
+    #[test]
+    fn test_table_schema() {
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N;price,f-N;ttl,d-N\n1;saw;9.5;1h\n2;drill;20.0;2h", "tools", "test").unwrap();
+        let schema = table.schema();
+        assert_eq!(schema.columns.len(), 4);
+        assert!(schema.column(&ksf("id")).unwrap().unique);
+        assert!(!schema.column(&ksf("name")).unwrap().unique);
+
+        let described = schema.to_table().unwrap();
+        assert_eq!(TableSchema::from_table(ksf("tools"), &described).unwrap(), schema);
+
+        assert!(schema.check_mapping(&[(ksf("id"), DbType::Int), (ksf("price"), DbType::Float)]).is_ok());
+        let err = schema.check_mapping(&[(ksf("price"), DbType::Int), (ksf("weight"), DbType::Float)]).unwrap_err();
+        assert_eq!(err.text.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_metadata_persistence() {
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b", "owned", "alice").unwrap();
+        table.metadata.touch();
+        let binary = table.to_binary();
+        assert_eq!(column_table_binary_len(&binary).unwrap(), binary.len());
+        let restored = ColumnTable::from_binary(None, &binary).unwrap();
+        assert_eq!(restored.metadata.created_by, ksf("alice"));
+        assert_eq!(restored.metadata.times_accessed.load(Ordering::Relaxed), 1);
+
+        let decoded = decode_cbor::<ColumnTable>(&table.to_cbor_bytes()).unwrap();
+        assert_eq!(decoded.metadata.created_by, ksf("alice"));
+
+        assert_eq!(table.subtable_from_columns(&[ksf("name")], "derived").unwrap().metadata.created_by, ksf("alice"));
+
+        let header_end = 144 + table.header.len() * 72;
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(ksf(LEGACY_COLUMN_TABLE_MAGIC).raw());
+        legacy.extend_from_slice(&binary[64..header_end]);
+        legacy.extend_from_slice(&binary[header_end + METADATA_BINARY_SIZE..]);
+        assert_eq!(column_table_binary_len(&legacy).unwrap(), legacy.len());
+        let restored = ColumnTable::from_binary(None, &legacy).unwrap();
+        assert_eq!(restored, table);
+        assert_eq!(restored.metadata.created_by, ksf("unknown"));
+    }
+
+    #[test]
+    fn test_format_versions() {
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b", "versions", "alice").unwrap();
+        let binary = table.to_binary();
+        let with_magic = |magic: &str, sections: &[u8]| {
+            let mut versioned = binary.clone();
+            versioned[0..64].copy_from_slice(ksf(magic).raw());
+            versioned.extend_from_slice(sections);
+            versioned
+        };
+
+        // A newer version that older servers may read. Its unknown section is skipped
+        let mut sections = Vec::new();
+        sections.extend_from_slice(&1u64.to_le_bytes());
+        sections.extend_from_slice(ksf("row_checksums").raw());
+        sections.extend_from_slice(&3u64.to_le_bytes());
+        sections.extend_from_slice(&[1, 2, 3]);
+        let readable = with_magic("EZDB_COLUMNTABLE_V3_R1", &sections);
+        assert_eq!(column_table_binary_len(&readable).unwrap(), readable.len());
+        assert_eq!(ColumnTable::from_binary(None, &readable).unwrap(), table);
+        assert!(ColumnTable::from_binary(None, &readable[..readable.len() - 1]).is_err());
+
+        let compressed = table.to_compressed_binary().unwrap();
+        let mut readable = compressed.clone();
+        readable[0..64].copy_from_slice(ksf("EZDB_COLUMNTABLE_V2_R1_Z").raw());
+        readable.extend_from_slice(&0u64.to_le_bytes());
+        assert_eq!(ColumnTable::from_binary(None, &readable).unwrap(), table);
+
+        // A version that needs a newer server is refused with both versions named
+        let (future, future_reader) = (TABLE_FORMAT_VERSION + 2, TABLE_FORMAT_VERSION + 1);
+        let future_magic = format!("EZDB_COLUMNTABLE_V{}_R{}", future, future_reader);
+        let e = ColumnTable::from_binary(None, &with_magic(&future_magic, &sections)).unwrap_err();
+        assert!(e.text.contains(&format!("version {}", future)) && e.text.contains(&format!("version {}", future_reader)));
+        assert!(e.text.contains(&format!("up to version {}", TABLE_FORMAT_VERSION)));
+        assert!(column_table_binary_len(&with_magic(&future_magic, &sections)).is_err());
+        assert!(ColumnTable::from_binary(None, &with_magic("EZDB_COLUMNTABLE_V2_R1_Q", &[0; 8])).is_err());
+        assert!(ColumnTable::from_binary(None, &with_magic("EZDB_COLUMNTABLE_Vx", &[])).is_err());
+
+        // Corrupt headers are errors, not panics
+        let mut bad_kind = binary.clone();
+        bad_kind[147] = b'q';
+        assert!(ColumnTable::from_binary(None, &bad_kind).is_err());
+        assert!(ColumnTable::from_binary(None, &binary[..200]).is_err());
+    }
+
+    #[test]
+    fn test_long_text_column() {
+        let long = "a review that goes on for a good deal longer than the 64 bytes a KeyString can hold";
+        let csv = format!("id,i-P;review,l-N\n1;{}\n2;short\n3;", long);
+        let mut table = ColumnTable::from_csv_string(&csv, "reviews", "test").unwrap();
+        assert_eq!(table.get_column_long_text(&ksf("review")).unwrap().iter().collect::<Vec<_>>(), vec![long, "short", ""]);
+        assert_eq!(table.to_string(), csv);
+
+        // Servers from before version 5 don't know LongText columns so the table needs a version 5 reader
+        let binary = table.to_binary();
+        assert_eq!(KeyString::try_from(&binary[0..64]).unwrap(), ksf("EZDB_COLUMNTABLE_V5_R5"));
+        assert_eq!(table.size_of_table(), binary.len());
+        assert_eq!(column_table_binary_len(&binary).unwrap(), binary.len());
+        assert_eq!(ColumnTable::from_binary(None, &binary).unwrap(), table);
+        assert!(ColumnTable::from_binary(None, &binary[..binary.len() - 1]).is_err());
+        let compressed = table.to_compressed_binary().unwrap();
+        assert_eq!(KeyString::try_from(&compressed[0..64]).unwrap(), ksf("EZDB_COLUMNTABLE_V5_R5_Z"));
+        assert_eq!(ColumnTable::from_binary(None, &compressed).unwrap(), table);
+        let on_disk = table.to_disk_binary().unwrap();
+        assert_eq!(table_format(&on_disk[0..64]).unwrap().version, 5);
+        assert_eq!(ColumnTable::from_binary(None, &on_disk).unwrap(), table);
+        let cbor = table.to_cbor_bytes();
+        assert_eq!(ColumnTable::from_cbor_bytes(&cbor).unwrap().0, table);
+
+        // Short values are read as Text and widened to fit the table
+        let inserts = table_from_inserts(&[ksf("id"), ksf("review")], "5;fine\n4;also fine", "inserts").unwrap();
+        crate::ezql::execute_insert_query(crate::ezql::Query::INSERT{table_name: ksf("reviews"), inserts, on_conflict: OnConflict::Skip}, &mut table).unwrap();
+        assert_eq!(table.get_line(3).unwrap(), "4;also fine");
+
+        let keepers = table.subtable_from_indexes(&[0, 4], &ksf("some"));
+        assert_eq!(keepers.get_column_long_text(&ksf("review")).unwrap().iter().collect::<Vec<_>>(), vec![long, "fine"]);
+        table.delete_by_indexes(&[0]);
+        assert_eq!(table.len(), 4);
+        assert_eq!(table.get_line(0).unwrap(), "2;short");
+
+        // The primary key has to be searchable so it can't be a long text
+        assert!(ColumnTable::from_csv_string("id,l-P\n1", "reviews", "test").is_err());
+        assert_eq!(infer_column_type(&[long, "short"]).unwrap(), DbType::LongText);
+    }
+
+    #[test]
+    fn test_table_checksums() {
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N;price,f-N\n1;a;1.5\n2;b;2.5\n3;c;3.5", "checked", "test").unwrap();
+        let binary = table.to_disk_binary().unwrap();
+        assert_eq!(table_format(&binary[0..64]).unwrap().version, 3);
+        assert_eq!(ColumnTable::from_binary(None, &binary).unwrap(), table);
+
+        // A flipped bit is reported with the block it is in
+        let layout = table_binary_layout(&binary).unwrap();
+        let mut flipped = binary.clone();
+        flipped[layout.columns[1].end - 1] ^= 1;
+        let e = ColumnTable::from_binary(Some("checked"), &flipped).unwrap_err();
+        assert_eq!(e.tag, ErrorTag::Deserialization);
+        assert!(e.text.contains("'checked'") && e.text.contains("column 'name'"));
+        let mut flipped = binary.clone();
+        flipped[layout.header - 1] ^= 1;
+        assert!(ColumnTable::from_binary(None, &flipped).unwrap_err().text.contains("its header"));
+        assert!(ColumnTable::from_binary(None, &binary[..binary.len() - 1]).is_err());
+
+        // Network binaries have no checksums
+        assert_eq!(ColumnTable::from_binary(None, &table.to_binary()).unwrap(), table);
+    }
+
+    #[test]
+    fn test_enum_columns() {
+        let table = ColumnTable::from_csv_string("id,i-P;status,e(open|in-progress|closed)-N\n1;open\n2;closed\n3;in-progress", "tickets", "test").unwrap();
+        let item = table.header.iter().find(|item| item.name.as_str() == "status").unwrap();
+        assert_eq!(item.kind, DbType::Enum);
+        assert_eq!(item.values, vec![ksf("open"), ksf("in-progress"), ksf("closed")]);
+        assert_eq!(table.get_column_int(&ksf("status")).unwrap(), &vec![0, 2, 1]);
+        assert_eq!(ColumnTable::from_csv_string(&table.to_string(), "tickets", "test").unwrap(), table);
+
+        // The values are stored in a section that only servers reading version 2 understand
+        let binary = table.to_binary();
+        assert_eq!(table_format(&binary[0..64]).unwrap(), TableFormat{version: 2, has_metadata: true, compressed: false, has_sections: true});
+        assert_eq!(table.size_of_table(), binary.len());
+        assert_eq!(column_table_binary_len(&binary).unwrap(), binary.len());
+        assert_eq!(ColumnTable::from_binary(None, &binary).unwrap(), table);
+        assert_eq!(ColumnTable::from_binary(None, &table.to_compressed_binary().unwrap()).unwrap(), table);
+        assert!(ColumnTable::from_binary(None, &binary[..binary.len() - 1]).is_err());
+        let plain = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a", "plain", "test").unwrap();
+        assert_eq!(table_format(&plain.to_binary()[0..64]).unwrap().version, 1);
+
+        let mut decoded = table.clone();
+        decoded.decode_enums().unwrap();
+        assert_eq!(decoded.get_column_text(&ksf("status")).unwrap(), &vec![ksf("open"), ksf("closed"), ksf("in-progress")]);
+        let mut encoded = decoded.clone();
+        encoded.encode_enums_to(&table.header).unwrap();
+        assert_eq!(encoded.header, table.header);
+        assert_eq!(encoded.columns, table.columns);
+
+        assert!(ColumnTable::from_csv_string("id,i-P;status,e(open|closed)-N\n1;pending", "tickets", "test").is_err());
+        assert!(ColumnTable::from_csv_string("id,e(a|b)-P\na", "tickets", "test").is_err());
+        assert!(ColumnTable::from_csv_string("id,i-P;status,e(a|a)-N\n1;a", "tickets", "test").is_err());
+    }
+
+    #[test]
+    fn test_collations() {
+        let mut table = ColumnTable::from_csv_string("id,t-P-ci;name,t-N\nbanana;b\nApple;a\ncherry;c", "fruit", "test").unwrap();
+        assert_eq!(table.key_collation(), Collation::CaseInsensitive);
+        assert_eq!(table.collation_of(&ksf("name")), Collation::Binary);
+        assert_eq!(table.get_column_text(&ksf("id")).unwrap(), &vec![ksf("Apple"), ksf("banana"), ksf("cherry")]);
+        assert_eq!(table.contains_key_string(ksf("BANANA")), Some(1));
+        assert_eq!(table.key_index(&ksf("apple")), Some(0));
+
+        assert!(ColumnTable::from_csv_string("id,t-P-ci\nApple\napple", "fruit", "test").is_err());
+        assert!(ColumnTable::from_csv_string("id,t-P\nApple\napple", "fruit", "test").is_ok());
+        assert!(ColumnTable::from_csv_string("id,i-P-ci\n1", "fruit", "test").is_err());
+        assert!(ColumnTable::from_csv_string("id,t-P-upper\na", "fruit", "test").is_err());
+
+        table.insert(ColumnTable::from_csv_string("id,t-P-ci;name,t-N\nAPPLE;x\nBlueberry;y", "fruit", "test").unwrap()).unwrap();
+        assert_eq!(table.get_column_text(&ksf("id")).unwrap(), &vec![ksf("Apple"), ksf("banana"), ksf("Blueberry"), ksf("cherry")]);
+        assert_eq!(table.get_column_text(&ksf("name")).unwrap(), &vec![ksf("a"), ksf("b"), ksf("y"), ksf("c")]);
+
+        assert_eq!(ColumnTable::from_csv_string(&table.to_string(), "fruit", "test").unwrap(), table);
+        assert_eq!(ColumnTable::from_binary(None, &table.to_binary()).unwrap(), table);
+
+        let prices = ColumnTable::from_csv_string("fruit,t-P-ci;price,i-N\nApple;3\nCherry;5", "prices", "test").unwrap();
+        let mut orders = ColumnTable::from_csv_string("id,i-P;fruit,t-N\n1;APPLE\n2;cherry\n3;apple", "orders", "test").unwrap();
+        orders.alt_left_join(&prices, &ksf("fruit")).unwrap();
+        assert_eq!(orders.get_column_int(&ksf("price")).unwrap(), &vec![3, 5, 3]);
+    }
+
+    #[test]
+    fn test_size_accounting() {
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N;review,l-N\n1;a;hello\n2;b;", "sizes", "test").unwrap();
+        let fixed = size_of::<ColumnTable>() + 3 * (size_of::<HeaderItem>() + size_of::<KeyString>() + size_of::<DbColumn>());
+        // 2 ints, 2 KeyStrings, and 5 bytes of text with 3 offsets
+        assert_eq!(table.byte_size(), fixed + 2 * 4 + 2 * 64 + 5 + 3 * size_of::<usize>());
+        assert_eq!(table.size_of_table(), table.to_binary().len());
+
+        for _ in 0..20 {
+            let table = crate::testing_tools::random_column_table(8, 50);
+            assert_eq!(table.size_of_table(), table.to_binary().len());
+            assert!(table.byte_size() >= table.columns.values().map(DbColumn::byte_size).sum::<usize>());
+        }
+
+        assert_eq!(Value::new("key", &[1, 2, 3]).byte_size(), 64 + 3);
+    }
+
+    #[test]
+    fn test_csv_reader() {
+        let csv = std::fs::read_to_string(test_file("good_csv.txt")).unwrap();
+        let streamed = ColumnTable::from_csv_reader(std::io::BufReader::new(csv.as_bytes()), "good", "test").unwrap();
+        let parsed = ColumnTable::from_csv_string(&csv, "good", "test").unwrap();
+        assert_eq!(streamed, parsed);
+
+        let mut big = String::from("id,i-P;latency,d-N\r\n");
+        for i in (0..CSV_IMPORT_BATCH_ROWS + 10).rev() {
+            big.push_str(&format!("{};{}ms\r\n", i, i));
+        }
+        let table = ColumnTable::from_csv_reader(big.as_bytes(), "big", "test").unwrap();
+        assert_eq!(table.len(), CSV_IMPORT_BATCH_ROWS + 10);
+        assert_eq!(table.get_column_int(&ksf("id")).unwrap()[0], 0);
+
+        big.push_str("17;1s\n");
+        assert!(ColumnTable::from_csv_reader(big.as_bytes(), "big", "test").is_err());
+        assert!(ColumnTable::from_csv_reader("id,i-P;name,t-N\n1;a;extra".as_bytes(), "bad", "test").is_err());
+        assert!(ColumnTable::from_csv_reader("".as_bytes(), "empty", "test").is_err());
+    }
+
+    #[test]
+    fn test_csv_import_spec() {
+        let csv = "id,i-P;price,f-N;stock,i-N;sold,d-N;day,i-N\n1;1.234,56;12.000;24.12.2023;01.01.1970\n2;-0,5;7;29.02.2024;02.01.1970";
+        let spec = CsvImportSpec::new()
+            .column("price", CsvColumnFormat::decimal(',', Some('.')))
+            .column("stock", CsvColumnFormat::decimal(',', Some('.')))
+            .column("sold", CsvColumnFormat::date("%d.%m.%Y"))
+            .column("day", CsvColumnFormat::date("%d.%m.%Y"));
+
+        let table = ColumnTable::from_csv_string_with_spec(csv, "sales", "test", &spec).unwrap();
+        assert_eq!(table.get_column_float(&ksf("price")).unwrap(), &vec![1234.56, -0.5]);
+        assert_eq!(table.get_column_int(&ksf("stock")).unwrap(), &vec![12000, 7]);
+        assert_eq!(table.get_column_duration(&ksf("sold")).unwrap(), &vec![epoch_nanos(1703376000), epoch_nanos(1709164800)]);
+        assert_eq!(table.get_column_int(&ksf("day")).unwrap(), &vec![0, 86400]);
+
+        let streamed = ColumnTable::from_csv_reader_with_spec(csv.as_bytes(), "sales", "test", &spec).unwrap();
+        assert_eq!(streamed, table);
+
+        // Without the spec the locale formats are rejected
+        assert!(ColumnTable::from_csv_string(csv, "sales", "test").is_err());
+
+        assert_eq!(parse_date("2000-03-01 12:30:05", "%Y-%m-%d %H:%M:%S").unwrap(), 951913805);
+        assert_eq!(parse_date("1969-12-31", "%Y-%m-%d").unwrap(), -86400);
+        assert!(parse_date("2023-02-29", "%Y-%m-%d").is_err());
+        assert!(parse_date("2023-01-01x", "%Y-%m-%d").is_err());
+        assert!(parse_date("01/01/2023", "%Y-%m-%d").is_err());
+
+        let bad_column = CsvImportSpec::new().column("missing", CsvColumnFormat::decimal(',', None));
+        assert!(ColumnTable::from_csv_string_with_spec(csv, "sales", "test", &bad_column).is_err());
+        let date_on_float = CsvImportSpec::new().column("price", CsvColumnFormat::date("%Y"));
+        assert!(ColumnTable::from_csv_string_with_spec(csv, "sales", "test", &date_on_float).is_err());
+        let same_separators = CsvImportSpec::new().column("price", CsvColumnFormat::decimal(',', Some(',')));
+        assert!(ColumnTable::from_csv_string_with_spec(csv, "sales", "test", &same_separators).is_err());
+    }
+
+    #[test]
+    fn test_infer_schema() {
+        let sample = "name;id;price;group\nwidget;3;1.5;tools\ngadget;1;2;tools\nwidget;2;3.25;toys";
+        let schema = infer_schema(sample, "products").unwrap();
+        assert_eq!(schema.get_column_text(&ksf("column_name")).unwrap(), &vec![ksf("name"), ksf("id"), ksf("price"), ksf("group")]);
+        assert_eq!(schema.get_column_text(&ksf("type")).unwrap(), &vec![ksf("Text"), ksf("Int"), ksf("Float"), ksf("Text")]);
+        assert_eq!(schema.get_column_text(&ksf("key")).unwrap(), &vec![ksf("N"), ksf("P"), ksf("N"), ksf("N")]);
+        assert_eq!(schema.get_column_int(&ksf("distinct")).unwrap(), &vec![2, 3, 3, 2]);
+        assert_eq!(schema.get_column_text(&ksf("header")).unwrap()[1], ksf("id,i-P"));
+
+        let table = table_from_inserts(&[ksf("id"), ksf("amount")], "1;10\n2;20", "inserts").unwrap();
+        assert!(table.header.contains(&HeaderItem{name: ksf("amount"), kind: DbType::Int, key: TableKey::None, values: Vec::new(), collation: Collation::Binary}));
+
+        // Inserts keep the first value column as their key even when a later column looks more like one
+        let table = table_from_inserts(&[ksf("name"), ksf("id")], "widget;3\ngadget;1", "inserts").unwrap();
+        assert_eq!(table.get_primary_key_col_index(), ksf("name"));
+        assert!(table.header.contains(&HeaderItem{name: ksf("id"), kind: DbType::Int, key: TableKey::None, values: Vec::new(), collation: Collation::Binary}));
+
+        assert!(infer_schema("id;name", "products").is_err());
+        assert!(infer_schema("id;id\n1;2", "products").is_err());
+    }
+
+    #[test]
+    fn test_durations() {
+        assert_eq!(parse_duration("150ms").unwrap(), 150_000_000);
+        assert_eq!(parse_duration("2s").unwrap(), 2_000_000_000);
+        assert_eq!(parse_duration("1.5s").unwrap(), 1_500_000_000);
+        assert_eq!(parse_duration("1h30m").unwrap(), 5_400_000_000_000);
+        assert_eq!(parse_duration("-20us").unwrap(), -20_000);
+        assert!(parse_duration("150").is_err());
+        assert!(parse_duration("2 weeks").is_err());
+        assert!(parse_duration("ms").is_err());
+
+        for nanos in [0, 1, 1_500_000, 2_000_000_000, 90_000_000_000, -7_000] {
+            assert_eq!(parse_duration(&format_duration(nanos)).unwrap(), nanos);
+        }
+        assert_eq!(format_duration(150_000_000), "150ms");
+        assert_eq!(humanize_duration(1_234_567_891.0), "1.235s");
+        assert_eq!(humanize_duration(2_000_000.0), "2ms");
+
+        let csv = "id,i-P;latency,d-N\n1;150ms\n2;2s\n3;1m";
+        let table = ColumnTable::from_csv_string(csv, "metrics", "test").unwrap();
+        assert_eq!(table.get_column_duration(&ksf("latency")).unwrap(), &vec![150_000_000, 2_000_000_000, 60_000_000_000]);
+        assert_eq!(ColumnTable::from_csv_string(&table.to_string(), "metrics", "test").unwrap(), table);
+        assert_eq!(ColumnTable::from_binary(Some("metrics"), &table.to_binary()).unwrap(), table);
+
+        // Servers from before version 4 don't know Duration columns so the table needs a version 4 reader
+        let binary = table.to_binary();
+        assert_eq!(KeyString::try_from(&binary[0..64]).unwrap(), ksf("EZDB_COLUMNTABLE_V4_R4"));
+        assert_eq!(table_format(&binary[0..64]).unwrap(), TableFormat{version: 4, has_metadata: true, compressed: false, has_sections: true});
+        assert_eq!(table.size_of_table(), binary.len());
+        assert_eq!(column_table_binary_len(&binary).unwrap(), binary.len());
+        let compressed = table.to_compressed_binary().unwrap();
+        assert_eq!(KeyString::try_from(&compressed[0..64]).unwrap(), ksf("EZDB_COLUMNTABLE_V4_R4_Z"));
+        assert_eq!(ColumnTable::from_binary(Some("metrics"), &compressed).unwrap(), table);
+        let on_disk = table.to_disk_binary().unwrap();
+        assert_eq!(table_format(&on_disk[0..64]).unwrap().version, 4);
+        assert_eq!(ColumnTable::from_binary(Some("metrics"), &on_disk).unwrap(), table);
+
+        // Tables without anything newer keep the magics older servers read
+        let plain = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a", "plain", "test").unwrap();
+        assert_eq!(table_magic(&plain, false, false), ksf(COLUMN_TABLE_MAGIC));
+        assert_eq!(table_magic(&plain, true, true), ksf(COMPRESSED_CHECKED_COLUMN_TABLE_MAGIC));
+        let tickets = ColumnTable::from_csv_string("id,i-P;status,e(open|closed)-N\n1;open", "tickets", "test").unwrap();
+        assert_eq!(table_magic(&tickets, false, false), ksf(ENUM_COLUMN_TABLE_MAGIC));
+        assert_eq!(table_magic(&tickets, false, true), ksf(CHECKED_COLUMN_TABLE_MAGIC));
+
+        let value = DbValue::Duration(150_000_000);
+        assert_eq!(DbValue::from_binary(&value.to_binary()).unwrap(), value);
+        assert_eq!(DbValue::Text(ksf("0.15s")).as_duration().unwrap(), 150_000_000);
+
+        assert!(ColumnTable::from_csv_string("latency,d-P;id,i-N\n1s;1", "metrics", "test").is_err());
+    }
+
+    #[test]
+    fn test_alter_columns() {
+        let csv = "id,i-P;price,f-N;code,t-N;status,e(open|closed)-N\n1;2.6;10;open\n2;-1.4;x;closed";
+        let mut table = ColumnTable::from_csv_string(csv, "products", "test").unwrap();
+
+        table.add_column_with_default(parse_header_item("stock,i-N").unwrap(), &DbValue::Int(5)).unwrap();
+        assert_eq!(table.get_column_int(&ksf("stock")).unwrap(), &vec![5, 5]);
+        table.add_column_with_default(parse_header_item("wait,d-N").unwrap(), &DbValue::Text(ksf("2s"))).unwrap();
+        assert_eq!(table.get_column_duration(&ksf("wait")).unwrap(), &vec![2_000_000_000; 2]);
+        assert!(table.add_column_with_default(parse_header_item("stock,i-N").unwrap(), &DbValue::Int(1)).is_err());
+        assert!(table.add_column_with_default(parse_header_item("other,i-P").unwrap(), &DbValue::Int(1)).is_err());
+        assert!(table.add_column_with_default(parse_header_item("note,i-N").unwrap(), &DbValue::Text(ksf("a"))).is_err());
+
+        table.change_column_type(&ksf("price"), DbType::Int).unwrap();
+        assert_eq!(table.get_column_int(&ksf("price")).unwrap(), &vec![3, -1]);
+        table.change_column_type(&ksf("wait"), DbType::Float).unwrap();
+        assert_eq!(table.get_column_float(&ksf("wait")).unwrap(), &vec![2.0, 2.0]);
+        table.change_column_type(&ksf("status"), DbType::Text).unwrap();
+        assert_eq!(table.get_column_text(&ksf("status")).unwrap(), &vec![ksf("open"), ksf("closed")]);
+
+        // "x" is not a number so nothing changes
+        let before = table.clone();
+        let e = table.change_column_type(&ksf("code"), DbType::Int).unwrap_err();
+        assert!(e.text.contains("Row 1"));
+        assert_eq!(table, before);
+        assert!(table.change_column_type(&ksf("id"), DbType::Float).is_err());
+        assert!(table.change_column_type(&ksf("code"), DbType::Enum).is_err());
+
+        table.rename_column(&ksf("code"), ksf("sku")).unwrap();
+        assert_eq!(table.get_column_text(&ksf("sku")).unwrap(), &vec![ksf("10"), ksf("x")]);
+        assert!(table.rename_column(&ksf("sku"), ksf("price")).is_err());
+        table.rename_column(&ksf("id"), ksf("product_id")).unwrap();
+        assert_eq!(table.get_primary_key_col_index(), ksf("product_id"));
+
+        table.drop_column(&ksf("sku")).unwrap();
+        assert!(!table.columns.contains_key(&ksf("sku")));
+        assert!(table.drop_column(&ksf("product_id")).is_err());
+        assert!(table.drop_column(&ksf("missing")).is_err());
+
+        let mut with_ids = table.clone();
+        with_ids.add_row_ids().unwrap();
+        assert!(with_ids.drop_column(&ksf(ROW_ID_COLUMN)).is_err());
+
+        assert_eq!(ColumnTable::from_binary(Some("products"), &table.to_binary()).unwrap(), table);
+    }
+
     #[test]
     fn test_keystring_display() {
         let s = KeyString::from("test");
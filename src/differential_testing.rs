@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+
+use crate::{db_structure::{ColumnTable, DbColumn, DbValue, OnConflict}, ezql::{execute_delete_query, execute_insert_query, execute_select_query, execute_update_query, Condition, ConditionBranch, KeyList, OpOrCond, Query, RangeOrListOrAll, TestOp, Update, UpdateOp}, testing_tools::random_query_for_table, utilities::{compile_regex, glob_matches, ErrorTag, EzError, KeyString}};
+
+
+/// A single row keyed by column name.
+pub type Row = BTreeMap<KeyString, DbValue>;
+
+/// The rows of a ColumnTable in order.
+pub fn table_rows(table: &ColumnTable) -> Vec<Row> {
+    let mut rows = vec![Row::new(); table.len()];
+    for (name, column) in &table.columns {
+        for (index, row) in rows.iter_mut().enumerate() {
+            let value = match column {
+                DbColumn::Ints(col) => DbValue::Int(col[index]),
+                DbColumn::Floats(col) => DbValue::Float(col[index]),
+                DbColumn::Texts(col) => DbValue::Text(col[index]),
+                DbColumn::Durations(col) => DbValue::Duration(col[index]),
+                // The random tables never have long text columns
+                DbColumn::LongTexts(col) => DbValue::Text(KeyString::from(col.get(index))),
+            };
+            row.insert(*name, value);
+        }
+    }
+    rows
+}
+
+/// A naive row at a time model of a table that the columnar engine is checked against.
+/// Nothing here shares code with ezql.rs on purpose, including the precedence of NOT, AND and OR,
+/// so a bug in the engine can't hide by being in both.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReferenceTable {
+    pub primary_key: KeyString,
+    /// Sorted by primary key like the ColumnTable.
+    pub rows: Vec<Row>,
+}
+
+impl ReferenceTable {
+    pub fn from_table(table: &ColumnTable) -> ReferenceTable {
+        ReferenceTable {
+            primary_key: table.get_primary_key_col_index(),
+            rows: table_rows(table),
+        }
+    }
+
+    fn key_in_range(&self, row: &Row, start: &KeyString, stop: &KeyString) -> bool {
+        match &row[&self.primary_key] {
+            DbValue::Int(key) => start.to_i32() <= *key && *key < stop.to_i32(),
+            DbValue::Text(key) => start.as_str() <= key.as_str() && key.as_str() < stop.as_str(),
+            other => panic!("Primary keys are ints or text, not {}", other),
+        }
+    }
+
+    fn row_matches(&self, row: &Row, primary_keys: &RangeOrListOrAll, conditions: &[OpOrCond]) -> bool {
+        let in_keys = match primary_keys {
+            RangeOrListOrAll::All => true,
+            RangeOrListOrAll::Range(start, stop) => self.key_in_range(row, start, stop),
+            RangeOrListOrAll::List(keys) => keys.iter().any(|key| match &row[&self.primary_key] {
+                DbValue::Int(x) => key.to_i32() == *x,
+                DbValue::Text(x) => key == x,
+                _ => false,
+            }),
+            RangeOrListOrAll::Keys(KeyList::Ints(keys)) => matches!(&row[&self.primary_key], DbValue::Int(x) if keys.contains(x)),
+            RangeOrListOrAll::Keys(KeyList::Texts(keys)) => matches!(&row[&self.primary_key], DbValue::Text(x) if keys.contains(x)),
+        };
+        in_keys && conditions_hold(row, conditions)
+    }
+
+    pub fn select(&self, columns: &[KeyString], primary_keys: &RangeOrListOrAll, conditions: &[OpOrCond]) -> Vec<Row> {
+        let select_all = columns.first().map(|c| c.as_str() == "*").unwrap_or(false);
+        self.rows
+            .iter()
+            .filter(|row| self.row_matches(row, primary_keys, conditions))
+            .map(|row| match select_all {
+                true => row.clone(),
+                false => columns.iter().map(|column| (*column, row[column].clone())).collect(),
+            })
+            .collect()
+    }
+
+    pub fn update(&mut self, primary_keys: &RangeOrListOrAll, conditions: &[OpOrCond], updates: &[Update]) {
+        let matching: Vec<usize> = (0..self.rows.len()).filter(|i| self.row_matches(&self.rows[*i], primary_keys, conditions)).collect();
+        for index in matching {
+            for update in updates {
+                let value = self.rows[index].get_mut(&update.attribute).unwrap();
+                *value = apply_update(value, update);
+            }
+        }
+    }
+
+    pub fn delete(&mut self, primary_keys: &RangeOrListOrAll, conditions: &[OpOrCond]) {
+        let rows = std::mem::take(&mut self.rows);
+        self.rows = rows.into_iter().filter(|row| !self.row_matches(row, primary_keys, conditions)).collect();
+    }
+
+    /// Inserts rows whose key is new. Rows whose key is already there are dropped or replace it as the policy says.
+    pub fn insert(&mut self, rows: Vec<Row>, on_conflict: OnConflict) -> Result<(), EzError> {
+        if on_conflict == OnConflict::Error && rows.iter().any(|row| self.rows.iter().any(|existing| existing[&self.primary_key] == row[&self.primary_key])) {
+            return Err(EzError{tag: ErrorTag::Conflict, text: "An inserted key is already in the reference table".to_owned()})
+        }
+        for row in rows {
+            let key = row[&self.primary_key].clone();
+            match self.rows.iter().position(|existing| existing[&self.primary_key] >= key) {
+                Some(index) if self.rows[index][&self.primary_key] == key => if on_conflict == OnConflict::Overwrite {
+                    self.rows[index] = row;
+                },
+                Some(index) => self.rows.insert(index, row),
+                None => self.rows.push(row),
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a SELECT, UPDATE, DELETE or INSERT and returns the rows a SELECT produces.
+    pub fn execute(&mut self, query: &Query) -> Result<Option<Vec<Row>>, EzError> {
+        match query {
+            Query::SELECT { primary_keys, columns, conditions, .. } => return Ok(Some(self.select(columns, primary_keys, conditions))),
+            Query::UPDATE { primary_keys, conditions, updates, .. } => self.update(primary_keys, conditions, updates),
+            Query::DELETE { primary_keys, conditions, .. } => self.delete(primary_keys, conditions),
+            Query::INSERT { inserts, on_conflict, .. } => self.insert(table_rows(inserts), *on_conflict)?,
+            other => return Err(EzError{tag: ErrorTag::Unimplemented, text: format!("The reference table can't run: {}", other)}),
+        }
+        Ok(None)
+    }
+}
+
+/// Builds the condition tree and tests every leaf on the row. Malformed conditions match no rows.
+fn conditions_hold(row: &Row, conditions: &[OpOrCond]) -> bool {
+    if conditions.is_empty() {
+        return true
+    }
+    match ConditionBranch::parse(conditions) {
+        Ok(tree) => tree.evaluate(&mut |cond| Ok(condition_holds(row, cond))).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+fn condition_holds(row: &Row, cond: &Condition) -> bool {
+    let value = &row[&cond.attribute];
+    let target = match &cond.other_column {
+        Some(other) => &row[other],
+        None => &cond.value,
+    };
+    let text = |v: &DbValue| match v {
+        DbValue::Text(t) => t.as_str().to_owned(),
+        _ => String::new(),
+    };
+    let order_to = |target: &DbValue| match (value, target) {
+        (DbValue::Int(a), DbValue::Int(b)) => a.partial_cmp(b),
+        (DbValue::Float(a), DbValue::Float(b)) => a.partial_cmp(b),
+        (DbValue::Text(a), DbValue::Text(b)) => a.as_str().partial_cmp(b.as_str()),
+        (DbValue::Duration(a), b) => b.as_duration().ok().and_then(|b| a.partial_cmp(&b)),
+        _ => None,
+    };
+    let order = order_to(target);
+    match &cond.op {
+        TestOp::Equals => order == Some(std::cmp::Ordering::Equal),
+        TestOp::NotEquals => order != Some(std::cmp::Ordering::Equal),
+        TestOp::Less => order == Some(std::cmp::Ordering::Less),
+        TestOp::Greater => order == Some(std::cmp::Ordering::Greater),
+        TestOp::Starts => text(value).starts_with(&text(target)),
+        TestOp::Ends => text(value).ends_with(&text(target)),
+        TestOp::Contains => text(value).contains(&text(target)),
+        TestOp::NotStarts => !text(value).starts_with(&text(target)),
+        TestOp::NotEnds => !text(value).ends_with(&text(target)),
+        TestOp::NotContains => !text(value).contains(&text(target)),
+        TestOp::IsNaN => matches!(value, DbValue::Float(f) if f.is_nan()),
+        TestOp::In(values) => values.iter().any(|v| order_to(v) == Some(std::cmp::Ordering::Equal)),
+        TestOp::Between(low, high) => order_to(low).is_some_and(|o| o != std::cmp::Ordering::Less) && order_to(high).is_some_and(|o| o != std::cmp::Ordering::Greater),
+        TestOp::Matches => glob_matches(&text(target), &text(value)),
+        TestOp::MatchesRegex => compile_regex(&text(target)).is_ok_and(|regex| regex.is_match(&text(value))),
+    }
+}
+
+fn apply_update(value: &DbValue, update: &Update) -> DbValue {
+    match (value, update.operator, &update.value) {
+        (_, UpdateOp::Assign, DbValue::Text(t)) if matches!(value, DbValue::Duration(_)) => DbValue::Duration(DbValue::Text(*t).as_duration().unwrap()),
+        (_, UpdateOp::Assign, new) => new.clone(),
+        (DbValue::Int(a), UpdateOp::PlusEquals, DbValue::Int(b)) => DbValue::Int(a + b),
+        (DbValue::Int(a), UpdateOp::MinusEquals, DbValue::Int(b)) => DbValue::Int(a - b),
+        (DbValue::Int(a), UpdateOp::TimesEquals, DbValue::Int(b)) => DbValue::Int(a * b),
+        (DbValue::Float(a), UpdateOp::PlusEquals, DbValue::Float(b)) => DbValue::Float(a + b),
+        (DbValue::Float(a), UpdateOp::MinusEquals, DbValue::Float(b)) => DbValue::Float(a - b),
+        (DbValue::Float(a), UpdateOp::TimesEquals, DbValue::Float(b)) => DbValue::Float(a * b),
+        (DbValue::Duration(a), UpdateOp::PlusEquals, b) => DbValue::Duration(a.saturating_add(b.as_duration().unwrap())),
+        (DbValue::Duration(a), UpdateOp::MinusEquals, b) => DbValue::Duration(a.saturating_sub(b.as_duration().unwrap())),
+        (DbValue::Duration(a), UpdateOp::TimesEquals, DbValue::Int(b)) => DbValue::Duration((*a as f64 * *b as f64).round() as i64),
+        (DbValue::Duration(a), UpdateOp::TimesEquals, DbValue::Float(b)) => DbValue::Duration((*a as f64 * *b as f64).round() as i64),
+        // KeyString::from cuts text at 64 bytes like KeyString::push does
+        (DbValue::Text(a), UpdateOp::Append, DbValue::Text(b)) => DbValue::Text(KeyString::from(format!("{}{}", a.as_str(), b.as_str()).as_str())),
+        (DbValue::Text(a), UpdateOp::Prepend, DbValue::Text(b)) => DbValue::Text(KeyString::from(format!("{}{}", b.as_str(), a.as_str()).as_str())),
+        (DbValue::Text(a), UpdateOp::ToLower, _) => DbValue::Text(KeyString::from(a.as_str().to_lowercase().as_str())),
+        (DbValue::Text(a), UpdateOp::ToUpper, _) => DbValue::Text(KeyString::from(a.as_str().to_uppercase().as_str())),
+        (DbValue::Text(a), UpdateOp::Trim, _) => DbValue::Text(KeyString::from(a.as_str().trim())),
+        (value, op, new) => panic!("Update {} {} can't be applied to {}", op.to_keystring(), new, value),
+    }
+}
+
+fn describe_rows(rows: &[Row]) -> String {
+    rows.iter().map(|row| format!("{:?}", row)).collect::<Vec<String>>().join("\n")
+}
+
+/// Runs the query against both the table and the reference and reports the first difference in
+/// the result or in the table afterwards.
+pub fn check_query(table: &mut ColumnTable, reference: &mut ReferenceTable, query: &Query) -> Result<(), EzError> {
+    let engine_result = match query {
+        Query::SELECT { .. } => execute_select_query(query, table),
+        Query::UPDATE { .. } => execute_update_query(query.clone(), table),
+        Query::DELETE { .. } => execute_delete_query(query.clone(), table),
+        Query::INSERT { .. } => execute_insert_query(query.clone(), table),
+        other => return Err(EzError{tag: ErrorTag::Unimplemented, text: format!("Differential testing does not cover: {}", other)}),
+    };
+    let engine_result = match engine_result {
+        Ok(result) => result.map(|table| table_rows(&table)),
+        Err(e) => return Err(EzError{tag: ErrorTag::Debug, text: format!("The engine failed a query the reference ran.\nQuery: {}\nError: {}", query, e)}),
+    };
+    let reference_result = reference.execute(query)?;
+
+    if engine_result != reference_result {
+        return Err(EzError{tag: ErrorTag::Debug, text: format!(
+            "Results differ.\nQuery: {}\nEngine:\n{}\nReference:\n{}",
+            query,
+            describe_rows(&engine_result.unwrap_or_default()),
+            describe_rows(&reference_result.unwrap_or_default()),
+        )})
+    }
+
+    let engine_rows = table_rows(table);
+    if engine_rows != reference.rows {
+        return Err(EzError{tag: ErrorTag::Debug, text: format!(
+            "Tables differ after the query.\nQuery: {}\nEngine:\n{}\nReference:\n{}",
+            query,
+            describe_rows(&engine_rows),
+            describe_rows(&reference.rows),
+        )})
+    }
+
+    Ok(())
+}
+
+/// Runs `queries` random queries that are valid against the table, checking each one against the reference.
+/// The table is changed by the queries so later ones see the results of earlier ones.
+pub fn run_differential_test(mut table: ColumnTable, queries: usize) -> Result<(), EzError> {
+    let mut reference = ReferenceTable::from_table(&table);
+    for _ in 0..queries {
+        let query = random_query_for_table(&table);
+        check_query(&mut table, &mut reference, &query)?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{ezql::Operator, testing_tools::random_realistic_table, utilities::ksf};
+
+    use super::*;
+
+    #[test]
+    fn test_differential_queries() {
+        for _ in 0..50 {
+            let table = random_realistic_table(6, 100);
+            if let Err(e) = run_differential_test(table, 200) {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reference_condition_precedence() {
+        let mut table = ColumnTable::create_empty("people", "test");
+        table.add_column(ksf("id"), DbColumn::Ints(vec![1, 2, 3, 4])).unwrap();
+        table.add_column(ksf("age"), DbColumn::Ints(vec![10, 20, 30, 40])).unwrap();
+        let mut reference = ReferenceTable::from_table(&table);
+
+        // age = 10 OR ((NOT age < 30) AND age < 40) matches ids 1 and 3
+        let query = Query::SELECT {
+            table_name: ksf("people"),
+            primary_keys: RangeOrListOrAll::All,
+            columns: vec![ksf("*")],
+            conditions: vec![
+                OpOrCond::Cond(Condition::new("age", TestOp::Equals, 10).unwrap()),
+                OpOrCond::Op(Operator::OR),
+                OpOrCond::Not,
+                OpOrCond::Cond(Condition::new("age", TestOp::Less, 30).unwrap()),
+                OpOrCond::Op(Operator::AND),
+                OpOrCond::Cond(Condition::new("age", TestOp::Less, 40).unwrap()),
+            ],
+            distinct: false,
+            limit: None,
+        };
+        let selected = reference.execute(&query).unwrap().unwrap();
+        let ids: Vec<DbValue> = selected.iter().map(|row| row[&ksf("id")].clone()).collect();
+        assert_eq!(ids, vec![DbValue::Int(1), DbValue::Int(3)]);
+        check_query(&mut table, &mut reference, &query).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::db_structure::{ColumnTable, DbColumn};
+use crate::config::{log, LogLevel};
+use crate::paths::config_dir;
+use crate::utilities::{get_current_time, ksf, ErrorTag, EzError, KeyString};
+
+
+/// How many samples of disk usage to keep. With the default sampling interval this covers a day.
+pub const MAX_DISK_SAMPLES: usize = 1440;
+
+/// Minimum number of seconds between two samples.
+pub const DISK_SAMPLE_INTERVAL: u64 = 60;
+
+/// When to start complaining about disk space. Either threshold triggers a warning.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiskThresholds {
+    pub warn_days_until_full: f64,
+    pub warn_used_percent: f64,
+}
+
+pub const DEFAULT_DISK_THRESHOLDS: DiskThresholds = DiskThresholds {
+    warn_days_until_full: 7.0,
+    warn_used_percent: 90.0,
+};
+
+impl Default for DiskThresholds {
+    fn default() -> Self {
+        DEFAULT_DISK_THRESHOLDS
+    }
+}
+
+static DISK_THRESHOLDS: RwLock<DiskThresholds> = RwLock::new(DEFAULT_DISK_THRESHOLDS);
+
+/// Sets the thresholds the disk monitor of the database warns at. Read when the database is initialized.
+pub fn set_disk_thresholds(thresholds: DiskThresholds) {
+    *DISK_THRESHOLDS.write().unwrap() = thresholds;
+}
+
+pub fn disk_thresholds() -> DiskThresholds {
+    *DISK_THRESHOLDS.read().unwrap()
+}
+
+/// A single measurement of the data directory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiskSample {
+    pub timestamp: u64,
+    pub data_bytes: u64,
+    pub available_bytes: u64,
+    pub total_bytes: u64,
+}
+
+/// The current state of the disk and where it's heading.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiskReport {
+    pub data_bytes: u64,
+    pub available_bytes: u64,
+    pub used_percent: f64,
+    pub growth_bytes_per_day: f64,
+    /// None if the data directory is not growing
+    pub days_until_full: Option<f64>,
+}
+
+/// Keeps a rolling window of disk usage samples of the data directory and forecasts when the disk will be full.
+pub struct DiskMonitor {
+    samples: RwLock<VecDeque<DiskSample>>,
+    pub thresholds: DiskThresholds,
+}
+
+impl Default for DiskMonitor {
+    fn default() -> Self {
+        Self::new(disk_thresholds())
+    }
+}
+
+impl DiskMonitor {
+    pub fn new(thresholds: DiskThresholds) -> DiskMonitor {
+        DiskMonitor {
+            samples: RwLock::new(VecDeque::new()),
+            thresholds,
+        }
+    }
+
+    /// Takes a new sample of the given data directory if the last one is older than DISK_SAMPLE_INTERVAL
+    /// and logs a warning if any threshold is crossed.
+    pub fn sample(&self, data_dir: &Path) -> Result<(), EzError> {
+
+        let now = get_current_time();
+        if let Some(last) = self.samples.read().unwrap().back() {
+            if now < last.timestamp + DISK_SAMPLE_INTERVAL {
+                return Ok(())
+            }
+        }
+
+        let (available_bytes, total_bytes) = filesystem_space(data_dir)?;
+        let sample = DiskSample {
+            timestamp: now,
+            data_bytes: directory_size(data_dir)?,
+            available_bytes,
+            total_bytes,
+        };
+        self.record(sample);
+
+        if let Some(report) = self.report() {
+            for warning in check_thresholds(&report, &self.thresholds) {
+                log(LogLevel::Warn, format_args!("WARNING: {}", warning));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn record(&self, sample: DiskSample) {
+        let mut samples = self.samples.write().unwrap();
+        samples.push_back(sample);
+        while samples.len() > MAX_DISK_SAMPLES {
+            samples.pop_front();
+        }
+    }
+
+    pub fn report(&self) -> Option<DiskReport> {
+        forecast(&self.samples.read().unwrap())
+    }
+
+    /// The ez_disk system table. A single row describing the data directory.
+    pub fn to_table(&self) -> Result<ColumnTable, EzError> {
+
+        let mut table = ColumnTable::create_empty("ez_disk", "system");
+        let report = match self.report() {
+            Some(report) => report,
+            None => {
+                table.add_column(ksf("path"), DbColumn::Texts(Vec::new()))?;
+                table.add_column(ksf("data_mb"), DbColumn::Floats(Vec::new()))?;
+                table.add_column(ksf("available_mb"), DbColumn::Floats(Vec::new()))?;
+                table.add_column(ksf("used_percent"), DbColumn::Floats(Vec::new()))?;
+                table.add_column(ksf("growth_mb_per_day"), DbColumn::Floats(Vec::new()))?;
+                table.add_column(ksf("days_until_full"), DbColumn::Floats(Vec::new()))?;
+                return Ok(table)
+            },
+        };
+
+        const MB: f64 = 1_000_000.0;
+        table.add_column(ksf("path"), DbColumn::Texts(vec![KeyString::from_str_lossy(&config_dir().display().to_string())]))?;
+        table.add_column(ksf("data_mb"), DbColumn::Floats(vec![(report.data_bytes as f64 / MB) as f32]))?;
+        table.add_column(ksf("available_mb"), DbColumn::Floats(vec![(report.available_bytes as f64 / MB) as f32]))?;
+        table.add_column(ksf("used_percent"), DbColumn::Floats(vec![report.used_percent as f32]))?;
+        table.add_column(ksf("growth_mb_per_day"), DbColumn::Floats(vec![(report.growth_bytes_per_day / MB) as f32]))?;
+        // -1 means the data directory is not growing
+        table.add_column(ksf("days_until_full"), DbColumn::Floats(vec![report.days_until_full.unwrap_or(-1.0) as f32]))?;
+
+        Ok(table)
+    }
+}
+
+/// Available and total bytes of the filesystem the path is on.
+#[cfg(all(feature = "server", target_os = "linux"))]
+pub fn filesystem_space(path: &Path) -> Result<(u64, u64), EzError> {
+    let stats = match nix::sys::statvfs::statvfs(path) {
+        Ok(stats) => stats,
+        Err(e) => return Err(EzError{tag: ErrorTag::Io, text: format!("Could not stat filesystem of '{}': {}", path.display(), e)}),
+    };
+    Ok((stats.blocks_available() as u64 * stats.fragment_size() as u64, stats.blocks() as u64 * stats.fragment_size() as u64))
+}
+
+#[cfg(not(all(feature = "server", target_os = "linux")))]
+pub fn filesystem_space(path: &Path) -> Result<(u64, u64), EzError> {
+    Err(EzError{tag: ErrorTag::Unimplemented, text: format!("Could not stat filesystem of '{}': needs a Linux build with the server feature", path.display())})
+}
+
+/// Total size of all files under the given path.
+pub fn directory_size(path: &Path) -> Result<u64, EzError> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            total += directory_size(&entry.path())?;
+        } else {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Fits the growth rate from the oldest to the newest sample and extrapolates when the free space runs out.
+pub fn forecast(samples: &VecDeque<DiskSample>) -> Option<DiskReport> {
+
+    let newest = samples.back()?;
+    let oldest = samples.front()?;
+
+    let elapsed = newest.timestamp.saturating_sub(oldest.timestamp);
+    let growth_bytes_per_day = if elapsed == 0 {
+        0.0
+    } else {
+        (newest.data_bytes as f64 - oldest.data_bytes as f64) / elapsed as f64 * 86400.0
+    };
+
+    let days_until_full = if growth_bytes_per_day > 0.0 {
+        Some(newest.available_bytes as f64 / growth_bytes_per_day)
+    } else {
+        None
+    };
+
+    let used_percent = if newest.total_bytes == 0 {
+        0.0
+    } else {
+        (newest.total_bytes - newest.available_bytes) as f64 / newest.total_bytes as f64 * 100.0
+    };
+
+    Some(DiskReport {
+        data_bytes: newest.data_bytes,
+        available_bytes: newest.available_bytes,
+        used_percent,
+        growth_bytes_per_day,
+        days_until_full,
+    })
+}
+
+pub fn check_thresholds(report: &DiskReport, thresholds: &DiskThresholds) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if report.used_percent >= thresholds.warn_used_percent {
+        warnings.push(format!("Disk is {:.1}% full", report.used_percent));
+    }
+    if let Some(days) = report.days_until_full {
+        if days <= thresholds.warn_days_until_full {
+            warnings.push(format!("At the current growth rate the disk will be full in {:.1} days", days));
+        }
+    }
+    warnings
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forecast() {
+        let monitor = DiskMonitor::default();
+        assert!(monitor.report().is_none());
+
+        monitor.record(DiskSample{timestamp: 0, data_bytes: 1000, available_bytes: 10_000, total_bytes: 100_000});
+        let report = monitor.report().unwrap();
+        assert_eq!(report.growth_bytes_per_day, 0.0);
+        assert_eq!(report.days_until_full, None);
+
+        monitor.record(DiskSample{timestamp: 86400, data_bytes: 2000, available_bytes: 9_000, total_bytes: 100_000});
+        let report = monitor.report().unwrap();
+        assert_eq!(report.growth_bytes_per_day, 1000.0);
+        assert_eq!(report.days_until_full, Some(9.0));
+        assert_eq!(report.used_percent, 91.0);
+
+        let warnings = check_thresholds(&report, &DiskThresholds::default());
+        assert_eq!(warnings.len(), 1);
+    }
+}
@@ -1,33 +1,545 @@
-use std::collections::{BTreeMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::ffi::c_void;
 use std::fs::{read_dir, File};
-use std::io::{Read, Write};
-use std::os::unix::fs::MetadataExt;
-use std::sync::atomic::AtomicU64;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 
-use crate::db_structure::{write_column_table_binary_header, DbColumn, Metadata, Value};
-use crate::utilities::{ksf, KeyString, ErrorTag, EzError};
+use ezcbor::cbor::{decode_cbor, Cbor};
+
+use crate::auth::User;
+use crate::bloom_filter::KeyFilter;
+use crate::db_structure::{Collation, CsvImportSpec, DbColumn, HeaderItem, Metadata, Value};
+use crate::utilities::{get_current_time, ksf, u64_from_le_slice, KeyString, ErrorTag, EzError};
 use crate::db_structure::ColumnTable;
-use crate::PATH_SEP;
+use crate::ezql::{first_insert_row, KeyList, KvQuery, RangeOrListOrAll, ValueFilter};
+use crate::partitions::{partition_name, partition_names, partitions_from_binary, partitions_to_binary, PartitionMap, PARTITIONS_FILE};
+use crate::row_table::{row_engine_tables_from_binary, row_engine_tables_to_binary, RowTable, ROW_BUFFER_MERGE_ROWS, ROW_ENGINE_FILE};
+use crate::paths::{chunk_file, config_file, validate_name, key_filter_file, table_chunk_file, table_chunks_dir, table_file, test_file, value_file};
+use crate::shared_tables::{remove_table_file, write_table_file};
+use crate::trash::{move_to_trash, restore_from_trash};
 
 pub const BIN_TABLE_DIR: &str = "Binary_tables";
 pub const MAX_BUFFERPOOL_SIZE: u64 = 4_000_000_000;   // 4gb
 pub const CHUNK_SIZE: usize = 1_000_000;                // 1mb
+pub const DEFAULT_VALUE_HISTORY_DEPTH: u64 = 8;
+pub const DEFAULT_TABLE_IDLE_SECS: u64 = 0;
+pub const DEFAULT_VALUE_COMPACTION_PERCENT: u64 = 30;
+pub const DEFAULT_TABLE_CHUNK_ROWS: u64 = 65_536;
+pub const CHUNKED_TABLE_MAGIC: &str = "EZDB_CHUNKED_TABLE_V1";
+pub const VALUE_EXPIRY_FILE: &str = ".value_expiry";
+pub const VALUE_HISTORY_FILE: &str = ".value_history";
+pub const USERS_FILE: &str = ".users";
+
+static VALUE_HISTORY_DEPTH: AtomicU64 = AtomicU64::new(DEFAULT_VALUE_HISTORY_DEPTH);
+static TABLE_IDLE_SECS: AtomicU64 = AtomicU64::new(DEFAULT_TABLE_IDLE_SECS);
+static VALUE_COMPACTION_PERCENT: AtomicU64 = AtomicU64::new(DEFAULT_VALUE_COMPACTION_PERCENT);
+static TABLE_CHUNK_ROWS: AtomicU64 = AtomicU64::new(DEFAULT_TABLE_CHUNK_ROWS);
+static BUFFER_POOL_CAP: AtomicU64 = AtomicU64::new(MAX_BUFFERPOOL_SIZE);
+
+/// Sets the most bytes the buffer pool of a new database holds. See BufferPool::set_max_size() for a running one.
+pub fn set_buffer_pool_cap(bytes: u64) {
+    BUFFER_POOL_CAP.store(bytes, Ordering::Relaxed);
+}
+
+pub fn buffer_pool_cap() -> u64 {
+    BUFFER_POOL_CAP.load(Ordering::Relaxed)
+}
+
+/// Sets how many previous versions of each KV value are kept. 0 disables versioning.
+pub fn set_value_history_depth(depth: u64) {
+    VALUE_HISTORY_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+pub fn value_history_depth() -> u64 {
+    VALUE_HISTORY_DEPTH.load(Ordering::Relaxed)
+}
+
+/// Sets how many seconds a table may go unaccessed before maintenance unloads it. 0 keeps every table loaded.
+pub fn set_table_idle_secs(secs: u64) {
+    TABLE_IDLE_SECS.store(secs, Ordering::Relaxed);
+}
+
+pub fn table_idle_secs() -> u64 {
+    TABLE_IDLE_SECS.load(Ordering::Relaxed)
+}
+
+/// Sets the percentage of dead bytes in the value files at which maintenance compacts them. 0 turns the
+/// background compaction off. The COMPACT_VALUES admin command still works.
+pub fn set_value_compaction_percent(percent: u64) {
+    VALUE_COMPACTION_PERCENT.store(percent, Ordering::Relaxed);
+}
+
+pub fn value_compaction_percent() -> u64 {
+    VALUE_COMPACTION_PERCENT.load(Ordering::Relaxed)
+}
+
+/// Sets how many rows go in each chunk of a table stored in chunks. Tables with more rows than this are
+/// written as a manifest plus chunks so a change only rewrites the chunks it touched. 0 writes every table
+/// as a single file.
+pub fn set_table_chunk_rows(rows: u64) {
+    TABLE_CHUNK_ROWS.store(rows, Ordering::Relaxed);
+}
+
+pub fn table_chunk_rows() -> u64 {
+    TABLE_CHUNK_ROWS.load(Ordering::Relaxed)
+}
+
+/// A whole file mapped read-only into memory. Table files are replaced by renaming a new file over them and
+/// never written in place, so a mapping of one stays readable for as long as it is held.
+/// MappedTable reads columns in place through one. The buffer pool reads table files into memory instead,
+/// since its tables own their columns and decoding a mapping would copy every value out of it anyway.
+pub struct FileMapping {
+    pointer: NonNull<c_void>,
+    len: usize,
+}
+
+// The mapping is read-only and owned by the FileMapping
+unsafe impl Send for FileMapping {}
+unsafe impl Sync for FileMapping {}
+
+impl FileMapping {
+    /// Empty files can't be mapped. The path is only used in errors.
+    #[cfg(all(target_os = "linux", feature = "server"))]
+    pub fn open(file: &File, path: &Path) -> Result<FileMapping, EzError> {
+        use nix::sys::mman::{mmap, MapFlags, ProtFlags};
+
+        let len = file.metadata()?.len() as usize;
+        let length = match std::num::NonZeroUsize::new(len) {
+            Some(length) => length,
+            None => return Err(EzError{tag: ErrorTag::Io, text: format!("Could not map '{}': the file is empty", path.display())}),
+        };
+        let pointer = unsafe {
+            mmap(None, length, ProtFlags::PROT_READ, MapFlags::MAP_SHARED, file, 0)
+                .map_err(|e| EzError{tag: ErrorTag::Io, text: format!("Could not map '{}': {}", path.display(), e)})?
+        };
+        Ok(FileMapping{pointer, len})
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "server")))]
+    pub fn open(_file: &File, path: &Path) -> Result<FileMapping, EzError> {
+        Err(EzError{tag: ErrorTag::Unimplemented, text: format!("Could not map '{}': mmap needs a Linux build with the server feature", path.display())})
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.pointer.as_ptr() as *const u8, self.len) }
+    }
+}
+
+impl Drop for FileMapping {
+    fn drop(&mut self) {
+        #[cfg(all(target_os = "linux", feature = "server"))]
+        unsafe {
+            let _ = nix::sys::mman::munmap(self.pointer, self.len);
+        }
+    }
+}
+
+/// How much of the value directory is still in use. A file is dead if no value has its key or if it holds
+/// an older version of a value that is not waiting to be written by maintenance anyway.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ValueStorageStats {
+    pub files: u64,
+    pub bytes: u64,
+    pub dead_files: u64,
+    pub dead_bytes: u64,
+}
+
+impl ValueStorageStats {
+    /// The share of bytes on disk that are dead, between 0 and 1.
+    pub fn fragmentation(&self) -> f32 {
+        if self.bytes == 0 {
+            0.0
+        } else {
+            self.dead_bytes as f32 / self.bytes as f32
+        }
+    }
+}
+
+/// The answer to COMPACT_VALUES. One row for the value files before compaction and one for after.
+pub fn value_compaction_table(before: &ValueStorageStats, after: &ValueStorageStats) -> Result<ColumnTable, EzError> {
+    let rows = [before, after];
+    let mut table = ColumnTable::create_empty("ez_value_compaction", "system");
+    table.add_column(ksf("stage"), DbColumn::Texts(vec![ksf("before"), ksf("after")]))?;
+    table.add_column(ksf("files"), DbColumn::Ints(rows.iter().map(|s| s.files as i32).collect()))?;
+    table.add_column(ksf("bytes"), DbColumn::Ints(rows.iter().map(|s| s.bytes as i32).collect()))?;
+    table.add_column(ksf("dead_files"), DbColumn::Ints(rows.iter().map(|s| s.dead_files as i32).collect()))?;
+    table.add_column(ksf("dead_bytes"), DbColumn::Ints(rows.iter().map(|s| s.dead_bytes as i32).collect()))?;
+    table.add_column(ksf("fragmentation"), DbColumn::Floats(rows.iter().map(|s| s.fragmentation()).collect()))?;
+    Ok(table)
+}
+
+/// What compaction does with a file in the value directory.
+enum ValueFile {
+    Live,
+    /// No value has this key.
+    Dead,
+    /// The file holds an older version of a value.
+    Stale,
+}
+
+/// When values with a TTL expire, in seconds since the epoch. Values without a TTL are not in here.
+/// Indexed both ways so the expiration sweep only looks at values that are due.
+#[derive(Clone, Debug, Default)]
+pub struct ValueExpiry {
+    by_key: BTreeMap<KeyString, u64>,
+    by_time: BTreeSet<(u64, KeyString)>,
+    /// Whether anything changed since the expiry file was last written.
+    changed: bool,
+}
+
+impl ValueExpiry {
+    pub fn get(&self, key: &KeyString) -> Option<u64> {
+        self.by_key.get(key).copied()
+    }
+
+    pub fn set(&mut self, key: KeyString, expires_at: u64) {
+        if let Some(old) = self.by_key.insert(key, expires_at) {
+            self.by_time.remove(&(old, key));
+        }
+        self.by_time.insert((expires_at, key));
+        self.changed = true;
+    }
+
+    pub fn clear(&mut self, key: &KeyString) {
+        if let Some(old) = self.by_key.remove(key) {
+            self.by_time.remove(&(old, *key));
+            self.changed = true;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_key.is_empty()
+    }
+
+    /// Takes out every key that expires at or before `now`.
+    fn take_due(&mut self, now: u64) -> Vec<KeyString> {
+        let mut due = Vec::new();
+        while let Some(&(expires_at, key)) = self.by_time.first() {
+            if expires_at > now {
+                break
+            }
+            self.by_time.pop_first();
+            self.by_key.remove(&key);
+            due.push(key);
+        }
+        if !due.is_empty() {
+            self.changed = true;
+        }
+        due
+    }
+
+    /// [key: 64][expires_at: u64] for each value.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut binary = Vec::with_capacity(self.by_key.len() * 72);
+        for (key, expires_at) in &self.by_key {
+            binary.extend_from_slice(key.raw());
+            binary.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        binary
+    }
+
+    pub fn from_binary(binary: &[u8]) -> Result<ValueExpiry, EzError> {
+        if !binary.len().is_multiple_of(72) {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: "Value expiry file is corrupted".to_owned()})
+        }
+        let mut expiry = ValueExpiry::default();
+        for chunk in binary.chunks(72) {
+            expiry.set(KeyString::try_from(&chunk[0..64])?, u64_from_le_slice(&chunk[64..72]));
+        }
+        expiry.changed = false;
+        Ok(expiry)
+    }
+}
+
+/// What stays in memory of a table that was unloaded for being idle. The data itself is on disk
+/// and is read back the next time a query touches the table.
+#[derive(Clone, Debug)]
+pub struct TableStub {
+    pub header: BTreeSet<HeaderItem>,
+    pub metadata: Metadata,
+    pub rows: usize,
+    pub byte_size: usize,
+}
+
+/// The chunks of a table that changed since it was last written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirtyRows {
+    /// The number of rows per chunk the chunk indexes were counted with.
+    pub chunk_rows: usize,
+    /// Chunks with rows that changed in place.
+    pub chunks: BTreeSet<usize>,
+    /// Every row from this one on may have moved, as after an insert or a delete.
+    pub from: Option<usize>,
+}
+
+impl DirtyRows {
+    pub fn new(chunk_rows: usize) -> DirtyRows {
+        DirtyRows {
+            chunk_rows: chunk_rows.max(1),
+            chunks: BTreeSet::new(),
+            from: None,
+        }
+    }
+
+    pub fn mark_rows(&mut self, rows: &[usize]) {
+        for row in rows {
+            self.chunks.insert(row / self.chunk_rows);
+        }
+    }
+
+    pub fn mark_from(&mut self, row: usize) {
+        self.from = Some(self.from.map_or(row, |from| from.min(row)));
+    }
+
+    pub fn chunk_is_dirty(&self, index: usize) -> bool {
+        self.chunks.contains(&index) || self.from.is_some_and(|from| (index + 1) * self.chunk_rows > from)
+    }
+}
+
+impl Default for DirtyRows {
+    fn default() -> Self {
+        Self::new(table_chunk_rows() as usize)
+    }
+}
+
+/// One chunk of a table stored in chunks. Its file is table_chunk_file(table, index, generation).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableChunk {
+    pub rows: usize,
+    pub generation: u64,
+}
+
+/// What the raw_tables file of a table stored in chunks holds instead of the table. Chunks are written under
+/// a new generation and the manifest is replaced after them, so a crash leaves the old version readable.
+/// [magic: 64][chunk_rows: 8][generation: 8][chunk count: 8]{[rows: 8][generation: 8]}[the table without rows]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkManifest {
+    pub chunk_rows: usize,
+    pub generation: u64,
+    pub chunks: Vec<TableChunk>,
+}
+
+impl ChunkManifest {
+    /// The header and metadata of the table are stored as a table with no rows after the chunk list.
+    pub fn to_binary(&self, table: &ColumnTable) -> Result<Vec<u8>, EzError> {
+        let mut binary = Vec::with_capacity(88 + self.chunks.len() * 16);
+        binary.extend_from_slice(KeyString::from(CHUNKED_TABLE_MAGIC).raw());
+        binary.extend_from_slice(&(self.chunk_rows as u64).to_le_bytes());
+        binary.extend_from_slice(&self.generation.to_le_bytes());
+        binary.extend_from_slice(&(self.chunks.len() as u64).to_le_bytes());
+        for chunk in &self.chunks {
+            binary.extend_from_slice(&(chunk.rows as u64).to_le_bytes());
+            binary.extend_from_slice(&chunk.generation.to_le_bytes());
+        }
+        let mut head = table.create_subtable_from_index_range(0, 0);
+        head.name = table.name;
+        head.metadata = table.metadata.clone();
+        binary.extend_from_slice(&head.to_disk_binary()?);
+
+        Ok(binary)
+    }
+
+    /// The manifest and the table without rows. See to_binary().
+    pub fn from_binary(table_name: &str, binary: &[u8]) -> Result<(ChunkManifest, ColumnTable), EzError> {
+        let corrupt = |what: &str| EzError{tag: ErrorTag::Deserialization, text: format!("The chunk manifest of table '{}' is corrupt: {}", table_name, what)};
+        if !is_chunk_manifest(binary) {
+            return Err(corrupt("wrong magic"))
+        }
+        if binary.len() < 88 {
+            return Err(corrupt("cut short"))
+        }
+        let chunk_rows = u64_from_le_slice(&binary[64..72]) as usize;
+        let generation = u64_from_le_slice(&binary[72..80]);
+        let count = u64_from_le_slice(&binary[80..88]) as usize;
+        let head_start = count.checked_mul(16).and_then(|n| n.checked_add(88)).ok_or_else(|| corrupt("impossible chunk count"))?;
+        if binary.len() < head_start {
+            return Err(corrupt("cut short"))
+        }
+        let chunks = binary[88..head_start].chunks_exact(16).map(|chunk| TableChunk {
+            rows: u64_from_le_slice(&chunk[0..8]) as usize,
+            generation: u64_from_le_slice(&chunk[8..16]),
+        }).collect();
+        let head = ColumnTable::from_binary(Some(table_name), &binary[head_start..])?;
+
+        Ok((ChunkManifest{chunk_rows, generation, chunks}, head))
+    }
+
+    fn references(&self, file_name: &str) -> bool {
+        self.chunks.iter().enumerate().any(|(index, chunk)| file_name == format!("{}.{}", index, chunk.generation))
+    }
+}
+
+pub fn is_chunk_manifest(binary: &[u8]) -> bool {
+    binary.len() >= 64 && binary[0..64] == *KeyString::from(CHUNKED_TABLE_MAGIC).raw()
+}
+
+/// The manifest of the table if it is stored in chunks. None if it is stored as a single file or not at all.
+pub fn read_chunk_manifest(table_name: &str) -> Result<Option<ChunkManifest>, EzError> {
+    let binary = match std::fs::read(table_file(table_name)) {
+        Ok(binary) => binary,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if !is_chunk_manifest(&binary) {
+        return Ok(None)
+    }
+    Ok(Some(ChunkManifest::from_binary(table_name, &binary)?.0))
+}
+
+/// Writes a table to raw_tables. Tables longer than chunk_rows are stored in chunks and, given the rows that
+/// changed since the last write, only the changed chunks and the manifest are rewritten. Without dirty rows
+/// every chunk is rewritten. 0 chunk_rows writes the table as a single file.
+pub fn write_table_chunks(table_name: &str, table: &ColumnTable, dirty: Option<&DirtyRows>, chunk_rows: usize) -> Result<(), EzError> {
+    let old = read_chunk_manifest(table_name)?;
+    let chunks_dir = table_chunks_dir(table_name);
+    if chunk_rows == 0 || table.len() <= chunk_rows {
+        write_table_file(table_name, &table.to_disk_binary()?)?;
+        if chunks_dir.exists() {
+            std::fs::remove_dir_all(&chunks_dir)?;
+        }
+        return Ok(())
+    }
+
+    let mut manifest = ChunkManifest {
+        chunk_rows,
+        generation: old.as_ref().map_or(1, |old| old.generation + 1),
+        chunks: Vec::with_capacity(table.len().div_ceil(chunk_rows)),
+    };
+    std::fs::create_dir_all(&chunks_dir)?;
+    for (index, start) in (0..table.len()).step_by(chunk_rows).enumerate() {
+        let rows = chunk_rows.min(table.len() - start);
+        let unchanged = match (&old, dirty) {
+            (Some(old), Some(dirty)) if old.chunk_rows == chunk_rows && dirty.chunk_rows == chunk_rows && !dirty.chunk_is_dirty(index) => {
+                old.chunks.get(index).filter(|chunk| chunk.rows == rows).copied()
+            },
+            _ => None,
+        };
+        let chunk = match unchanged {
+            Some(chunk) => chunk,
+            None => {
+                let mut part = table.create_subtable_from_index_range(start, start + rows);
+                part.name = table.name;
+                let mut file = File::create(table_chunk_file(table_name, index, manifest.generation))?;
+                file.write_all(&part.to_disk_binary()?)?;
+                file.sync_all()?;
+                TableChunk{rows, generation: manifest.generation}
+            },
+        };
+        manifest.chunks.push(chunk);
+    }
+    write_table_file(table_name, &manifest.to_binary(table)?)?;
+
+    // What the new manifest doesn't point at belongs to older versions of the table
+    for entry in read_dir(&chunks_dir)? {
+        let entry = entry?;
+        if !manifest.references(&entry.file_name().to_string_lossy()) {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a table from raw_tables, putting it back together from its chunks if it is stored in chunks.
+pub fn read_table_file(table_name: &str) -> Result<ColumnTable, EzError> {
+    read_table_at(table_name, &table_file(table_name), &table_chunks_dir(table_name))
+}
+
+/// Reads a table whose file and chunks are somewhere else than where the buffer pool keeps them, such as in the trash.
+pub fn read_table_at(table_name: &str, path: &Path, chunks_dir: &Path) -> Result<ColumnTable, EzError> {
+    let binary = std::fs::read(path)?;
+    if !is_chunk_manifest(&binary) {
+        return ColumnTable::from_binary(Some(table_name), &binary)
+    }
+
+    let (manifest, mut table) = ChunkManifest::from_binary(table_name, &binary)?;
+
+    for (index, chunk) in manifest.chunks.iter().enumerate() {
+        let part = ColumnTable::from_binary(Some(table_name), &std::fs::read(chunk_file(chunks_dir, index, chunk.generation))?)?;
+        if part.len() != chunk.rows {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Chunk {} of table '{}' has {} rows but the manifest says {}", index, table_name, part.len(), chunk.rows)})
+        }
+        table.extend_from_table(part)?;
+    }
+
+    Ok(table)
+}
+
+/// Removes the file of a table and its chunks if it has any.
+pub fn remove_table_files(table_name: &str) -> Result<(), EzError> {
+    remove_table_file(table_name)?;
+    let chunks_dir = table_chunks_dir(table_name);
+    if chunks_dir.exists() {
+        std::fs::remove_dir_all(&chunks_dir)?;
+    }
+    Ok(())
+}
+
+
+/// What the buffer pool holds in memory, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct MemoryUsage {
+    pub tables: u64,
+    pub values: u64,
+    pub value_history: u64,
+}
 
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.tables + self.values + self.value_history
+    }
+}
 
 pub struct BufferPool {
     max_size: AtomicU64,
-    pub tables: Arc<RwLock<BTreeMap<KeyString, RwLock<ColumnTable>>>>,
+    pub tables: Arc<RwLock<BTreeMap<KeyString, RwLock<Arc<ColumnTable>>>>>,
     pub values: Arc<RwLock<BTreeMap<KeyString, Value>>>,
+    /// Previous versions of each value, most recent first. Bounded by value_history_depth().
+    /// Written to VALUE_HISTORY_FILE by write_value_history() and read back at startup.
+    pub value_history: Arc<RwLock<BTreeMap<KeyString, VecDeque<Value>>>>,
+    /// Whether the value history changed since it was last written.
+    value_history_changed: AtomicBool,
     pub table_naughty_list: Arc<RwLock<HashSet<KeyString>>>,
+    /// What changed in the tables on the naughty list. A table on the list without an entry here is rewritten
+    /// whole. Always lock `table_naughty_list` first when holding both.
+    pub table_dirty_rows: Arc<RwLock<BTreeMap<KeyString, DirtyRows>>>,
     pub value_naughty_list: Arc<RwLock<HashSet<KeyString>>>,
     pub table_delete_list: Arc<RwLock<HashSet<KeyString>>>,
     pub value_delete_list: Arc<RwLock<HashSet<KeyString>>>,
-    
+    /// Tables that were unloaded for being idle. Always lock `tables` first when holding both.
+    pub unloaded_tables: Arc<RwLock<BTreeMap<KeyString, TableStub>>>,
+    /// Keys whose files were not loaded at startup because the buffer pool was full. Compaction leaves them alone.
+    pub unloaded_values: Arc<RwLock<HashSet<KeyString>>>,
+    /// Expiration times of values that were given a TTL. Always lock `values` first when holding both.
+    pub value_expiry: Arc<RwLock<ValueExpiry>>,
+    /// When each value was last written, in seconds since the epoch. Values read at startup count from the
+    /// modification time of their file. Always lock `values` first when holding both.
+    pub value_modified: Arc<RwLock<BTreeMap<KeyString, u64>>>,
+    /// How each partitioned table is split. Its partitions are in `tables` and `unloaded_tables` under the names
+    /// from partition_name(). Always lock `tables` first when holding both.
+    pub partitions: Arc<RwLock<BTreeMap<KeyString, PartitionMap>>>,
+    /// Tables created with the row engine. See TableEngine::Row.
+    pub row_engine_tables: Arc<RwLock<BTreeSet<KeyString>>>,
+    /// Rows inserted into row engine tables that are not merged into the table yet. See merge_row_buffer().
+    /// Always lock `row_buffers` first when holding it with `tables`.
+    pub row_buffers: Arc<RwLock<BTreeMap<KeyString, RowTable>>>,
+    /// A bloom filter over the primary keys of each table, kept while the table is unloaded so lookups of
+    /// missing keys never reload it. Written next to the table by flush_table(). See KeyFilter.
+    pub key_filters: Arc<RwLock<BTreeMap<KeyString, KeyFilter>>>,
+    pub table_unloads: AtomicU64,
+    pub table_reloads: AtomicU64,
 }
 
 impl BufferPool {
-    pub fn init_tables(&self, path: &str) -> Result<(), EzError> {
+    pub fn init_tables(&self, path: &Path) -> Result<(), EzError> {
         println!("calling: BufferPool::init_tables()");
 
 
@@ -35,34 +547,48 @@ impl BufferPool {
 
         for file in data_dir{
             let file = file?;
-            let file_size = file.metadata()?.size();
+            let file_size = file.metadata()?.len();
             if file_size + self.occupied_buffer() > self.max_size() {
                 break;
             }
 
             let name = file.file_name().into_string().unwrap();
-            let mut table_file = File::open(file.path())?;
+            let table = read_table_file(&name)?;
+            let name = table.name;
 
-            let mut binary = Vec::with_capacity(file_size as usize);
-            table_file.read_to_end(&mut binary)?;
-
-            let table = ColumnTable::from_binary(Some(&name), &binary)?;
-            
-            self.add_table(table)?;
+            // A table stored in chunks has a small manifest so the check above can't catch it
+            match self.add_table(table) {
+                Ok(()) => (),
+                Err(e) if e.tag == ErrorTag::NoMoreBufferSpace => break,
+                Err(e) => return Err(e),
+            }
+            // It was just read from disk so there is nothing to write
+            self.table_naughty_list.write().unwrap().remove(&name);
         }
 
-        let good_table = std::fs::read_to_string(&format!("test_files{PATH_SEP}good_csv.txt")).unwrap();
-        let good_table = ColumnTable::from_csv_string(&good_table, "good_table", "server").unwrap();
-        println!("good_table.len() = {}", good_table.to_binary().len());
-        match self.add_table(good_table) {
-            Ok(_) => (),
-            Err(_) => (),
-        };
+        // The test table is only there when the test files are
+        let _ = self.import_csv_table(&test_file("good_csv.txt"), "good_table");
 
         Ok(())
     }
 
-    pub fn init_values(&self, path: &str) -> Result<(), EzError> {
+    /// Streams a csv file from disk into a new table without reading the whole file into memory first.
+    pub fn import_csv_table(&self, path: &Path, table_name: &str) -> Result<(), EzError> {
+        self.import_csv_table_with_spec(path, table_name, &CsvImportSpec::new())
+    }
+
+    /// Imports a csv file whose columns are written in the parse formats of the spec. See CsvColumnFormat.
+    pub fn import_csv_table_with_spec(&self, path: &Path, table_name: &str, spec: &CsvImportSpec) -> Result<(), EzError> {
+        println!("calling: BufferPool::import_csv_table()");
+
+        let reader = BufReader::new(File::open(path)?);
+        let table = ColumnTable::from_csv_reader_with_spec(reader, table_name, "server", spec)?;
+        println!("{}.len() = {}", table_name, table.len());
+
+        self.add_table(table)
+    }
+
+    pub fn init_values(&self, path: &Path) -> Result<(), EzError> {
         
         println!("calling: BufferPool::init_values()");
 
@@ -70,20 +596,26 @@ impl BufferPool {
 
         for file in data_dir{
             let file = file?;
-            let file_size = file.metadata()?.size();
+            let file_size = file.metadata()?.len();
+            let name = file.file_name().into_string().unwrap();
             if file_size + self.occupied_buffer() > self.max_size() {
-                break;
+                self.unloaded_values.write().unwrap().insert(KeyString::from(name.as_str()));
+                continue;
             }
 
-            let name = file.file_name().into_string().unwrap();
             let mut value_file = File::open(file.path())?;
 
             let mut binary = Vec::with_capacity(file_size as usize);
             value_file.read_to_end(&mut binary)?;
 
             let value = Value::from_binary(&name, &binary)?;
+            let key = value.name;
 
             self.add_value(value)?;
+            if let Ok(modified) = file.metadata()?.modified() {
+                let modified = modified.duration_since(std::time::UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+                self.value_modified.write().unwrap().insert(key, modified);
+            }
         }
 
         let core_value_1 = Value{name: ksf("core1"), body: vec![1,2,3,4,5,6,7,8]};
@@ -102,71 +634,555 @@ impl BufferPool {
 
         let tables = Arc::new(RwLock::new(BTreeMap::new()));
         let values = Arc::new(RwLock::new(BTreeMap::new()));
+        let value_history = Arc::new(RwLock::new(BTreeMap::new()));
         let table_naughty_list = Arc::new(RwLock::new(HashSet::new()));
+        let table_dirty_rows = Arc::new(RwLock::new(BTreeMap::new()));
         let value_naughty_list = Arc::new(RwLock::new(HashSet::new()));
         let table_delete_list = Arc::new(RwLock::new(HashSet::new()));
         let value_delete_list = Arc::new(RwLock::new(HashSet::new()));
+        let unloaded_tables = Arc::new(RwLock::new(BTreeMap::new()));
+        let unloaded_values = Arc::new(RwLock::new(HashSet::new()));
+        let value_expiry = Arc::new(RwLock::new(ValueExpiry::default()));
+        let value_modified = Arc::new(RwLock::new(BTreeMap::new()));
+        let partitions = Arc::new(RwLock::new(BTreeMap::new()));
+        let row_engine_tables = Arc::new(RwLock::new(BTreeSet::new()));
+        let row_buffers = Arc::new(RwLock::new(BTreeMap::new()));
+        let key_filters = Arc::new(RwLock::new(BTreeMap::new()));
 
         BufferPool {
             max_size,
             tables,
             values,
+            value_history,
+            value_history_changed: AtomicBool::new(false),
             table_naughty_list,
+            table_dirty_rows,
             value_naughty_list,
             table_delete_list,
             value_delete_list,
-            
+            unloaded_tables,
+            unloaded_values,
+            value_expiry,
+            value_modified,
+            partitions,
+            row_engine_tables,
+            row_buffers,
+            key_filters,
+            table_unloads: AtomicU64::new(0),
+            table_reloads: AtomicU64::new(0),
+        }
+    }
+
+    /// Bytes held by loaded tables, current values and previous versions of values. Counted the same way
+    /// as ColumnTable::byte_size() and Value::byte_size().
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            tables: self.tables.read().unwrap().values().map(|table| table.read().unwrap().byte_size() as u64).sum(),
+            values: self.values.read().unwrap().values().map(|value| value.byte_size() as u64).sum(),
+            value_history: self.value_history_size(),
         }
     }
 
+    /// Everything the buffer pool budget counts. See memory_usage().
     pub fn occupied_buffer(&self) -> u64 {
         println!("calling: BufferPool::occupied_buffer()");
 
-        let mut output: u64 = 0;
-        for table in self.tables.read().unwrap().values() {
-            output += table.read().unwrap().byte_size() as u64;
-        }
-
-        output
+        self.memory_usage().total()
     }
 
     pub fn max_size(&self) -> u64 {
         self.max_size.load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    /// Changes the budget of the buffer pool. Nothing is evicted when it shrinks below what is loaded, only
+    /// further growth is refused.
+    pub fn set_max_size(&self, bytes: u64) {
+        self.max_size.store(bytes, std::sync::atomic::Ordering::Relaxed);
+    }
+
     pub fn add_table(&self, table: ColumnTable) -> Result<(), EzError> {
         println!("calling: BufferPool::add_table()");
 
+        validate_name(table.name.as_str())?;
 
-        if self.occupied_buffer() + table.size_of_table() as u64 > self.max_size() {
-            return Err(EzError{tag: ErrorTag::NoMoreBufferSpace, text: format!("Table sized: {} is too big. Remaining space is: {}",table.size_of_table(), self.max_size()-self.occupied_buffer())})
+        if self.occupied_buffer() + table.byte_size() as u64 > self.max_size() {
+            return Err(EzError{tag: ErrorTag::NoMoreBufferSpace, text: format!("Table sized: {} is too big. Remaining space is: {}",table.byte_size(), self.max_size().saturating_sub(self.occupied_buffer()))})
         }
 
-        if self.tables.read().unwrap().contains_key(&table.name) {
+        if self.table_exists(&table.name) {
             return Err(EzError{tag: ErrorTag::Structure, text: format!("Table named '{}' already exists", table.name)});
         } else {
-            self.table_naughty_list.write().unwrap().insert(table.name);
-            self.tables.write().unwrap().insert(table.name, RwLock::new(table));
+            self.mark_table_changed(table.name);
+            self.load_key_filter(&table);
+            self.tables.write().unwrap().insert(table.name, RwLock::new(Arc::new(table)));
         }
 
         Ok(())
     }
 
-    pub fn remove_table(&self, table_name: KeyString) -> Result<(), EzError> {
-        println!("calling: BufferPool::add_table()");
+    /// Whether the table exists, loaded, unloaded or partitioned.
+    pub fn table_exists(&self, table_name: &KeyString) -> bool {
+        self.tables.read().unwrap().contains_key(table_name) || self.unloaded_tables.read().unwrap().contains_key(table_name)
+            || self.partitions.read().unwrap().contains_key(table_name)
+    }
+
+    /// How many rows the table has, loaded or unloaded. None for a table that doesn't exist or is partitioned.
+    pub fn table_rows(&self, table_name: &KeyString) -> Option<usize> {
+        if let Some(table) = self.tables.read().unwrap().get(table_name) {
+            return Some(table.read().unwrap().len())
+        }
+        self.unloaded_tables.read().unwrap().get(table_name).map(|stub| stub.rows)
+    }
+
+    /// The partition map of the table if it is partitioned.
+    pub fn partition_map(&self, table_name: &KeyString) -> Option<PartitionMap> {
+        self.partitions.read().unwrap().get(table_name).cloned()
+    }
+
+    /// The header of the table, loaded or unloaded.
+    pub fn table_header(&self, table_name: &KeyString) -> Option<BTreeSet<HeaderItem>> {
+        if let Some(table) = self.tables.read().unwrap().get(table_name) {
+            return Some(table.read().unwrap().header.clone())
+        }
+        self.unloaded_tables.read().unwrap().get(table_name).map(|stub| stub.header.clone())
+    }
+
+    /// Builds the key filter of the table from its primary keys, replacing the one it had. Tables with a
+    /// case-insensitive key get none since the filter hashes the bytes of the keys.
+    pub fn rebuild_key_filter(&self, table: &ColumnTable) {
+        let filter = match table.key_collation() {
+            Collation::Binary => table.columns.get(&table.get_primary_key_col_index()).and_then(KeyFilter::from_keys),
+            Collation::CaseInsensitive => None,
+        };
+        let mut filters = self.key_filters.write().unwrap();
+        match filter {
+            Some(filter) => filters.insert(table.name, filter),
+            None => filters.remove(&table.name),
+        };
+    }
+
+    /// Reads the key filter flush_table() wrote for the table. It is built from the table instead if there is no
+    /// file or the file was written for a different number of rows, as happens after a crash between the two writes.
+    fn load_key_filter(&self, table: &ColumnTable) {
+        let stored = std::fs::read(key_filter_file(table.name.as_str())).ok().and_then(|binary| KeyFilter::from_binary(&binary).ok());
+        match stored {
+            Some((filter, rows)) if rows == table.len() && !filter.needs_rebuild() && table.key_collation() == Collation::Binary => {
+                self.key_filters.write().unwrap().insert(table.name, filter);
+            },
+            _ => self.rebuild_key_filter(table),
+        }
+    }
+
+    /// Records the primary keys of rows about to be inserted into the table. Done before the insert so the filter
+    /// never misses a key that is in the table. Keys of a failed insert only cost a wasted lookup.
+    pub fn add_filter_keys(&self, table_name: &KeyString, key_column: &KeyString, inserts: &ColumnTable) {
+        if let (Some(filter), Some(keys)) = (self.key_filters.write().unwrap().get_mut(table_name), inserts.columns.get(key_column)) {
+            filter.add_keys(keys);
+        }
+    }
+
+    /// Keeps the key filter of the table in step with a write that removed rows or, if `rekeyed`, changed
+    /// primary keys in place. Only a rebuild can follow changed keys. Call with the table still locked.
+    pub fn update_key_filter(&self, table: &ColumnTable, removed: usize, rekeyed: bool) {
+        let rebuild = match self.key_filters.write().unwrap().get_mut(&table.name) {
+            Some(filter) => {
+                filter.note_removed(removed);
+                rekeyed || filter.needs_rebuild()
+            },
+            None => true,
+        };
+        if rebuild {
+            self.rebuild_key_filter(table);
+        }
+    }
+
+    /// Whether the key filter of the table shows that none of the keys are in it, so a query for them matches
+    /// nothing. False whenever it can't tell, including for keys of the wrong type, which the query reports.
+    pub fn rules_out_keys(&self, table_name: &KeyString, keys: &RangeOrListOrAll) -> bool {
+        let filters = self.key_filters.read().unwrap();
+        let filter = match filters.get(table_name) {
+            Some(filter) => filter,
+            None => return false,
+        };
+        match keys {
+            RangeOrListOrAll::List(keys) => !keys.is_empty() && keys.iter().all(|key| !filter.might_contain(key)),
+            RangeOrListOrAll::Keys(KeyList::Ints(keys)) if filter.has_int_keys() => {
+                !keys.is_empty() && keys.iter().all(|key| !filter.might_contain_int(*key))
+            },
+            RangeOrListOrAll::Keys(KeyList::Texts(keys)) if !filter.has_int_keys() => {
+                !keys.is_empty() && keys.iter().all(|key| !filter.might_contain_text(key))
+            },
+            _ => false,
+        }
+    }
+
+    /// Forgets the key filter of a table that is gone and removes its file.
+    fn remove_key_filter(&self, table_name: &KeyString) -> Result<(), EzError> {
+        self.key_filters.write().unwrap().remove(table_name);
+        remove_key_filter_file(table_name.as_str())
+    }
+
+    /// Whether the table was created with the row engine.
+    pub fn uses_row_engine(&self, table_name: &KeyString) -> bool {
+        self.row_engine_tables.read().unwrap().contains(table_name)
+    }
+
+    /// Switches an existing table to the row engine. The choice is written to disk right away.
+    pub fn use_row_engine(&self, table_name: KeyString) -> Result<(), EzError> {
+        let mut row_engine_tables = self.row_engine_tables.write().unwrap();
+        if row_engine_tables.insert(table_name) {
+            if let Err(e) = save_row_engine_tables(&row_engine_tables, &config_file(ROW_ENGINE_FILE)) {
+                row_engine_tables.remove(&table_name);
+                return Err(e)
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the list of row engine tables written by use_row_engine(). Names of tables that no longer exist are dropped.
+    pub fn load_row_engine_tables(&self, path: &Path) -> Result<(), EzError> {
+        let mut loaded = match std::fs::read(path) {
+            Ok(binary) => row_engine_tables_from_binary(&binary)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        loaded.retain(|table_name| self.table_exists(table_name));
+        *self.row_engine_tables.write().unwrap() = loaded;
+        Ok(())
+    }
+
+    /// Adds inserted rows to the row buffer of a row engine table instead of the table itself, so the sorted
+    /// columns of the table are only rebuilt once per batch of inserts. The caller has checked the rows against
+    /// the table and encoded their enums. Once the buffer holds ROW_BUFFER_MERGE_ROWS rows it is merged.
+    /// Rows whose key is already in the table or the buffer would be skipped by the merge, so they are left out
+    /// here. Returns how many rows were added.
+    pub fn buffer_rows(&self, table_name: KeyString, header: &BTreeSet<HeaderItem>, inserts: &ColumnTable) -> Result<usize, EzError> {
+        self.ensure_loaded(&table_name)?;
+        let (added, buffered) = {
+            let mut buffers = self.row_buffers.write().unwrap();
+            let present = match self.tables.read().unwrap().get(&table_name) {
+                Some(table) => table.read().unwrap().keys_present(inserts),
+                None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named: '{}'", table_name)}),
+            };
+            let mut fresh = Cow::Borrowed(inserts);
+            if !present.is_empty() {
+                fresh.to_mut().delete_by_indexes(&present);
+            }
+            let buffer = match buffers.entry(table_name) {
+                std::collections::btree_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::btree_map::Entry::Vacant(entry) => entry.insert(RowTable::new(table_name, header, "row buffer")?),
+            };
+            // The buffer skips keys it already holds
+            let added = buffer.insert(&fresh)?;
+            (added, buffer.len())
+        };
+        if buffered >= ROW_BUFFER_MERGE_ROWS {
+            self.merge_row_buffer(&table_name)?;
+        }
+        Ok(added)
+    }
+
+    /// Inserts the rows waiting in the row buffer of the table into the table, sorted once for the whole batch.
+    /// Anything that reads the table, or writes it other than by INSERT, calls this first so it sees every row.
+    /// Returns how many rows were merged.
+    pub fn merge_row_buffer(&self, table_name: &KeyString) -> Result<usize, EzError> {
+        let mut buffers = self.row_buffers.write().unwrap();
+        let inserts = match buffers.get(table_name) {
+            Some(buffer) if !buffer.is_empty() => buffer.to_column_table(),
+            _ => return Ok(0),
+        };
+        self.ensure_loaded(table_name)?;
+        {
+            let tables = self.tables.read().unwrap();
+            let mut table = match tables.get(table_name) {
+                Some(table) => table.write().unwrap(),
+                None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named: '{}'", table_name)}),
+            };
+            let first = first_insert_row(&table, &inserts);
+            Arc::make_mut(&mut table).insert(inserts)?;
+            self.mark_rows_changed(*table_name, |dirty| dirty.mark_from(first));
+        }
+        Ok(buffers.remove(table_name).map_or(0, |buffer| buffer.len()))
+    }
+
+    /// Merges the row buffers of every row engine table. Run before the tables are flushed.
+    pub fn merge_row_buffers(&self) -> Result<(), EzError> {
+        let waiting: Vec<KeyString> = self.row_buffers.read().unwrap().keys().copied().collect();
+        for table_name in waiting {
+            self.merge_row_buffer(&table_name)?;
+        }
+        Ok(())
+    }
+
+    /// Splits a table into partitions at the given primary keys. See PartitionMap. The partitions are written
+    /// to disk before the partition map and the file of the whole table is only removed after that, so a crash
+    /// halfway leaves either the whole table or all of its partitions. See load_partitions().
+    /// Tables with row ids can't be partitioned since each partition would number its rows on its own.
+    /// Neither can tables with a case-insensitive key, since partition bounds are compared byte by byte.
+    pub fn partition_table(&self, table_name: KeyString, bounds: KeyList) -> Result<(), EzError> {
+        println!("calling: BufferPool::partition_table()");
+
+        let map = PartitionMap::new(bounds)?;
+        self.ensure_loaded(&table_name)?;
+        let mut tables = self.tables.write().unwrap();
+        if self.partitions.read().unwrap().contains_key(&table_name) {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("Table '{}' is already partitioned", table_name)})
+        }
+        let parts = match tables.get(&table_name) {
+            Some(table) => {
+                let table = table.read().unwrap();
+                if table.has_row_ids() {
+                    return Err(EzError{tag: ErrorTag::Structure, text: format!("Table '{}' has row ids and can't be partitioned", table_name)})
+                }
+                if self.uses_row_engine(&table_name) {
+                    return Err(EzError{tag: ErrorTag::Structure, text: format!("Table '{}' uses the row engine and can't be partitioned", table_name)})
+                }
+                if table.key_collation() != Collation::Binary {
+                    return Err(EzError{tag: ErrorTag::Structure, text: format!("Table '{}' has a {} primary key and can't be partitioned", table_name, table.key_collation().name())})
+                }
+                map.split(&table)?
+            },
+            None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named: '{}'", table_name)}),
+        };
+        if let Some(taken) = parts.iter().find(|part| tables.contains_key(&part.name) || self.unloaded_tables.read().unwrap().contains_key(&part.name)) {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("A table named '{}' exists. Drop it before partitioning '{}'", taken.name, table_name)})
+        }
+
+        for part in &parts {
+            self.flush_table(&part.name, part, true)?;
+        }
+        {
+            let mut partitions = self.partitions.write().unwrap();
+            partitions.insert(table_name, map);
+            if let Err(e) = save_partitions(&partitions, &config_file(PARTITIONS_FILE)) {
+                partitions.remove(&table_name);
+                return Err(e)
+            }
+        }
+        for part in parts {
+            self.rebuild_key_filter(&part);
+            tables.insert(part.name, RwLock::new(Arc::new(part)));
+        }
+        tables.remove(&table_name);
+        self.table_naughty_list.write().unwrap().remove(&table_name);
+        self.table_dirty_rows.write().unwrap().remove(&table_name);
+        self.remove_key_filter(&table_name)?;
+        if table_file(table_name.as_str()).exists() {
+            remove_table_files(table_name.as_str())?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the partition maps written by partition_table(). Call after init_tables(). A table that is still
+    /// stored whole next to its partitions was being partitioned when the server stopped. Its partitions were
+    /// all written by then, so the whole copy is removed.
+    pub fn load_partitions(&self, path: &Path) -> Result<(), EzError> {
+        let loaded = match std::fs::read(path) {
+            Ok(binary) => partitions_from_binary(&binary)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut tables = self.tables.write().unwrap();
+        for table_name in loaded.keys() {
+            let loaded_whole = tables.remove(table_name).is_some();
+            let unloaded_whole = self.unloaded_tables.write().unwrap().remove(table_name).is_some();
+            self.table_naughty_list.write().unwrap().remove(table_name);
+            self.remove_key_filter(table_name)?;
+            if (loaded_whole || unloaded_whole) && table_file(table_name.as_str()).exists() {
+                remove_table_files(table_name.as_str())?;
+            }
+        }
+        *self.partitions.write().unwrap() = loaded;
+        Ok(())
+    }
+
+    /// Adds every table that does not exist yet, loaded or unloaded, and returns the names of the ones it added.
+    /// Used to apply a schema file on startup and by CREATE_FROM_SCHEMA.
+    pub fn create_missing_tables(&self, tables: Vec<ColumnTable>) -> Result<Vec<KeyString>, EzError> {
+
+        let mut created = Vec::new();
+        for table in tables {
+            if self.table_exists(&table.name) {
+                continue
+            }
+            let table_name = table.name;
+            self.add_table(table)?;
+            created.push(table_name);
+        }
+
+        Ok(created)
+    }
+
+    /// Takes the table out of the buffer pool and moves its files to the trash, from where the UNDROP admin
+    /// command can bring it back until it is purged. A loaded table is written out first so the trash holds
+    /// its latest state.
+    /// The partitions of a partitioned table go to the trash as tables of their own.
+    pub fn drop_table(&self, table_name: KeyString) -> Result<(), EzError> {
+        println!("calling: BufferPool::drop_table()");
+
+        if let Some(map) = self.partition_map(&table_name) {
+            for partition in partition_names(&table_name, &(0..map.count()).collect::<Vec<_>>())? {
+                match self.drop_table(partition) {
+                    Ok(()) => (),
+                    Err(e) if e.tag == ErrorTag::NotFound => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            let mut partitions = self.partitions.write().unwrap();
+            partitions.remove(&table_name);
+            return save_partitions(&partitions, &config_file(PARTITIONS_FILE))
+        }
+
+        // The trash gets the rows still waiting in the row buffer too
+        self.merge_row_buffer(&table_name)?;
+        if self.row_engine_tables.read().unwrap().contains(&table_name) {
+            let mut row_engine_tables = self.row_engine_tables.write().unwrap();
+            row_engine_tables.remove(&table_name);
+            save_row_engine_tables(&row_engine_tables, &config_file(ROW_ENGINE_FILE))?;
+        }
+
+        let mut tables = self.tables.write().unwrap();
+        let mut stubs = self.unloaded_tables.write().unwrap();
+        match tables.get(&table_name) {
+            Some(table) => {
+                self.flush_table(&table_name, &table.read().unwrap(), true)?;
+                tables.remove(&table_name);
+            },
+            None => if stubs.remove(&table_name).is_none() {
+                return Err(EzError { tag: ErrorTag::NotFound, text: format!("No table named: '{}'", table_name) })
+            },
+        }
+        self.table_naughty_list.write().unwrap().remove(&table_name);
+        self.table_dirty_rows.write().unwrap().remove(&table_name);
+        // The trash only keeps the table. Its filter is built again if it is restored
+        self.remove_key_filter(&table_name)?;
+
+        move_to_trash(table_name.as_str(), get_current_time())
+    }
+
+    /// Brings a dropped table back from the trash and loads it. Fails if a table of the same name has been
+    /// created since.
+    pub fn undrop_table(&self, table_name: KeyString) -> Result<(), EzError> {
+        println!("calling: BufferPool::undrop_table()");
+
+        let mut tables = self.tables.write().unwrap();
+        if tables.contains_key(&table_name) || self.unloaded_tables.read().unwrap().contains_key(&table_name) {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("A table named '{}' exists. Drop it before restoring the old one", table_name)})
+        }
+        restore_from_trash(table_name.as_str())?;
+        let table = read_table_file(table_name.as_str())?;
+        table.metadata.touch();
+        self.rebuild_key_filter(&table);
+        tables.insert(table_name, RwLock::new(Arc::new(table)));
+
+        Ok(())
+    }
+
+    /// The user that owns the table, loaded or not. Tables are owned by whoever created them until ownership is transferred.
+    pub fn table_owner(&self, table_name: &KeyString) -> Option<KeyString> {
+        if self.partitions.read().unwrap().contains_key(table_name) {
+            return self.table_owner(&partition_name(table_name, 0).ok()?)
+        }
+        if let Some(table) = self.tables.read().unwrap().get(table_name) {
+            return Some(table.read().unwrap().metadata.created_by)
+        }
+        self.unloaded_tables.read().unwrap().get(table_name).map(|stub| stub.metadata.created_by)
+    }
+
+    /// Makes new_owner the owner of the table. The table is written to disk with its new owner on the next maintenance pass.
+    pub fn transfer_ownership(&self, table_name: &KeyString, new_owner: KeyString) -> Result<(), EzError> {
+        if let Some(map) = self.partition_map(table_name) {
+            for partition in partition_names(table_name, &(0..map.count()).collect::<Vec<_>>())? {
+                self.transfer_ownership(&partition, new_owner)?;
+            }
+            return Ok(())
+        }
+        self.ensure_loaded(table_name)?;
+        match self.tables.read().unwrap().get(table_name) {
+            Some(table) => Arc::make_mut(&mut table.write().unwrap()).metadata.created_by = new_owner,
+            None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named: '{}'", table_name)}),
+        }
+        // Only the manifest of a table stored in chunks holds the owner
+        self.mark_rows_changed(*table_name, |_| ());
+        Ok(())
+    }
+
+    /// Marks the whole table to be rewritten on the next flush.
+    pub fn mark_table_changed(&self, table_name: KeyString) {
+        let mut naughty_list = self.table_naughty_list.write().unwrap();
+        naughty_list.insert(table_name);
+        self.table_dirty_rows.write().unwrap().remove(&table_name);
+    }
+
+    /// Marks some rows of the table to be rewritten on the next flush. A table that is already marked whole stays so.
+    pub fn mark_rows_changed(&self, table_name: KeyString, mark: impl FnOnce(&mut DirtyRows)) {
+        let mut naughty_list = self.table_naughty_list.write().unwrap();
+        let mut dirty_rows = self.table_dirty_rows.write().unwrap();
+        if naughty_list.insert(table_name) {
+            mark(dirty_rows.entry(table_name).or_default());
+        } else if let Some(rows) = dirty_rows.get_mut(&table_name) {
+            mark(rows);
+        }
+    }
+
+    /// Writes the table to disk if it has unwritten changes or no file yet, or always if forced. Only the chunks
+    /// that changed are rewritten. See write_table_chunks(). Returns whether anything was written.
+    /// The key filter of the table is written after it. See load_key_filter().
+    /// The caller holds a lock on the table so it can't change while it is written.
+    pub fn flush_table(&self, table_name: &KeyString, table: &ColumnTable, force: bool) -> Result<bool, EzError> {
+        let dirty = {
+            let naughty_list = self.table_naughty_list.read().unwrap();
+            if !force && !naughty_list.contains(table_name) && table_file(table_name.as_str()).exists() {
+                return Ok(false)
+            }
+            // If the write fails the table stays on the naughty list without dirty rows and is rewritten whole
+            self.table_dirty_rows.write().unwrap().remove(table_name)
+        };
+        // The old filter goes first so a crash before the new one is written leaves no filter rather than a stale one
+        remove_key_filter_file(table_name.as_str())?;
+        write_table_chunks(table_name.as_str(), table, dirty.as_ref(), table_chunk_rows() as usize)?;
+        if let Some(filter) = self.key_filters.read().unwrap().get(table_name) {
+            save_key_filter(filter, table.len(), &key_filter_file(table_name.as_str()))?;
+        }
+        self.table_naughty_list.write().unwrap().remove(table_name);
+        Ok(true)
+    }
+
+    /// Writes every table and value with unwritten changes, after merging the row buffers into their tables, so
+    /// nothing is left only in memory. Stops at the first write that fails. Returns how many tables and values
+    /// were written.
+    pub fn flush_dirty(&self) -> Result<(usize, usize), EzError> {
+        self.merge_row_buffers()?;
 
+        let mut tables_written = 0;
+        for (name, table) in self.tables.read().unwrap().iter() {
+            if self.table_naughty_list.read().unwrap().contains(name) && self.flush_table(name, &table.read().unwrap(), false)? {
+                tables_written += 1;
+            }
+        }
 
-        match self.tables.write().unwrap().remove(&table_name) {
-            Some(_) => Ok(()),
-            None => Err(EzError { tag: ErrorTag::Structure, text: format!("No table named: '{}'", table_name) }),
+        let mut values_written = 0;
+        let values = self.values.read().unwrap();
+        let mut value_naughty_list = self.value_naughty_list.write().unwrap();
+        for key in value_naughty_list.clone() {
+            if let Some(value) = values.get(&key) {
+                std::fs::write(value_file(key.as_str()), value.write_to_binary())?;
+                values_written += 1;
+            }
+            value_naughty_list.remove(&key);
         }
+
+        Ok((tables_written, values_written))
     }
 
     pub fn add_value(&self, value: Value) -> Result<(), EzError> {
         println!("calling: BufferPool::add_value()");
 
-        if self.occupied_buffer() + value.body.len() as u64 > self.max_size() {
-            return Err(EzError{tag: ErrorTag::NoMoreBufferSpace, text: format!("Table sized: {} is too big. Remaining space is: {}",value.body.len(), self.max_size()-self.occupied_buffer())})
+        validate_name(value.name.as_str())?;
+
+        if self.occupied_buffer() + value.byte_size() as u64 > self.max_size() {
+            return Err(EzError{tag: ErrorTag::NoMoreBufferSpace, text: format!("Value sized: {} is too big. Remaining space is: {}",value.byte_size(), self.max_size().saturating_sub(self.occupied_buffer()))})
 
         }
 
@@ -174,11 +1190,463 @@ impl BufferPool {
             return Err(EzError{tag: ErrorTag::Structure, text: format!("value named '{}' already exists", value.name)});
         } else {
             self.value_naughty_list.write().unwrap().insert(value.name);
-            self.values.write().unwrap().insert(value.name, value);
+            let mut values = self.values.write().unwrap();
+            self.value_modified.write().unwrap().insert(value.name, get_current_time());
+            values.insert(value.name, value);
         }
         Ok(())
     }
     
+    /// Replaces a value, keeping the old version in the value history.
+    pub fn update_value(&self, value: Value) -> Result<(), EzError> {
+        println!("calling: BufferPool::update_value()");
+
+        let mut values = self.values.write().unwrap();
+        match values.get_mut(&value.name) {
+            Some(current) => {
+                let name = value.name;
+                let old = std::mem::replace(current, value);
+                self.push_history(old);
+                self.value_naughty_list.write().unwrap().insert(name);
+                self.value_modified.write().unwrap().insert(name, get_current_time());
+                Ok(())
+            },
+            None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No value corresponds to key: '{}'", value.name)}),
+        }
+    }
+
+    /// Creates the value or replaces it if it already exists. A replaced value goes into the history.
+    pub fn put_value(&self, value: Value) -> Result<(), EzError> {
+        println!("calling: BufferPool::put_value()");
+
+        validate_name(value.name.as_str())?;
+
+        if self.occupied_buffer() + value.byte_size() as u64 > self.max_size() {
+            return Err(EzError{tag: ErrorTag::NoMoreBufferSpace, text: format!("Value sized: {} is too big. Remaining space is: {}", value.byte_size(), self.max_size().saturating_sub(self.occupied_buffer()))})
+        }
+
+        let mut values = self.values.write().unwrap();
+        let name = value.name;
+        if let Some(old) = values.insert(name, value) {
+            self.push_history(old);
+        }
+        self.value_naughty_list.write().unwrap().insert(name);
+        self.value_modified.write().unwrap().insert(name, get_current_time());
+        Ok(())
+    }
+
+    /// Removes a value and its history.
+    pub fn remove_value(&self, key: &KeyString) -> Result<Value, EzError> {
+        println!("calling: BufferPool::remove_value()");
+
+        let mut values = self.values.write().unwrap();
+        self.forget_history(key);
+        match values.remove(key) {
+            Some(value) => {
+                self.value_expiry.write().unwrap().clear(key);
+                self.value_modified.write().unwrap().remove(key);
+                self.value_delete_list.write().unwrap().insert(*key);
+                Ok(value)
+            },
+            None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No value corresponds to key: '{}'", key)}),
+        }
+    }
+
+    /// Makes the value expire `ttl_secs` after `now`. Replaces any TTL the value had before.
+    pub fn set_value_ttl(&self, key: &KeyString, ttl_secs: u64, now: u64) -> Result<(), EzError> {
+        if ttl_secs == 0 {
+            return Err(EzError{tag: ErrorTag::Query, text: "A TTL must be at least 1 second".to_owned()})
+        }
+        let values = self.values.read().unwrap();
+        if !values.contains_key(key) {
+            return Err(EzError{tag: ErrorTag::NotFound, text: format!("No value corresponds to key: '{}'", key)})
+        }
+        self.value_expiry.write().unwrap().set(*key, now.saturating_add(ttl_secs));
+        Ok(())
+    }
+
+    /// Seconds since the value was last written, if it exists.
+    pub fn value_age(&self, key: &KeyString, now: u64) -> Option<u64> {
+        self.value_modified.read().unwrap().get(key).map(|modified| now.saturating_sub(*modified))
+    }
+
+    /// The values whose keys start with the prefix and that pass the filter, in key order. The filter runs
+    /// on the values in place so only the matches are copied.
+    pub fn scan_values(&self, prefix: &KeyString, filter: &ValueFilter, now: u64) -> Vec<Value> {
+        let values = self.values.read().unwrap();
+        let modified = self.value_modified.read().unwrap();
+        values.range(*prefix..)
+            .take_while(|(key, _)| key.as_str().starts_with(prefix.as_str()))
+            .filter(|(key, value)| filter.matches(value, modified.get(key).map(|modified| now.saturating_sub(*modified))))
+            .map(|(_, value)| value.clone())
+            .collect()
+    }
+
+    /// When the value expires, if it has a TTL.
+    pub fn value_expires_at(&self, key: &KeyString) -> Option<u64> {
+        self.value_expiry.read().unwrap().get(key)
+    }
+
+    /// Removes every value whose TTL has run out, the same way a delete would. Runs before every KV batch
+    /// so expired values are never read, and from maintenance. Returns the keys that expired.
+    pub fn expire_values(&self, now: u64) -> Vec<KeyString> {
+        let mut values = self.values.write().unwrap();
+        let due = self.value_expiry.write().unwrap().take_due(now);
+        for key in &due {
+            self.forget_history(key);
+            self.value_modified.write().unwrap().remove(key);
+            if values.remove(key).is_some() || self.unloaded_values.write().unwrap().remove(key) {
+                self.value_delete_list.write().unwrap().insert(*key);
+            }
+        }
+        due
+    }
+
+    /// Writes the expiration times if they changed since the last write.
+    pub fn write_value_expiry(&self, path: &Path) -> Result<(), EzError> {
+        let mut expiry = self.value_expiry.write().unwrap();
+        if expiry.changed {
+            std::fs::write(path, expiry.to_binary())?;
+            expiry.changed = false;
+        }
+        Ok(())
+    }
+
+    /// Reads the expiration times written by write_value_expiry(). A missing file means no value has a TTL.
+    pub fn load_value_expiry(&self, path: &Path) -> Result<(), EzError> {
+        if !path.exists() {
+            return Ok(())
+        }
+        *self.value_expiry.write().unwrap() = ValueExpiry::from_binary(&std::fs::read(path)?)?;
+        Ok(())
+    }
+
+    /// Writes the value history if it changed since the last write.
+    /// [key: 64][versions: u64] for each key, then [length: u64][body] for each version, most recent first.
+    pub fn write_value_history(&self, path: &Path) -> Result<(), EzError> {
+        let history = self.value_history.read().unwrap();
+        if !self.value_history_changed.swap(false, Ordering::Relaxed) {
+            return Ok(())
+        }
+        let mut binary = Vec::new();
+        for (key, versions) in history.iter() {
+            binary.extend_from_slice(key.raw());
+            binary.extend_from_slice(&(versions.len() as u64).to_le_bytes());
+            for version in versions {
+                binary.extend_from_slice(&(version.body.len() as u64).to_le_bytes());
+                binary.extend_from_slice(&version.body);
+            }
+        }
+        if let Err(e) = std::fs::write(path, binary) {
+            self.value_history_changed.store(true, Ordering::Relaxed);
+            return Err(e.into())
+        }
+        Ok(())
+    }
+
+    /// Reads the value history written by write_value_history(), keeping at most value_history_depth() versions
+    /// of each value. A missing file means no value has history.
+    pub fn load_value_history(&self, path: &Path) -> Result<(), EzError> {
+        if !path.exists() {
+            return Ok(())
+        }
+        let binary = std::fs::read(path)?;
+        let corrupted = || EzError{tag: ErrorTag::Deserialization, text: "Value history file is corrupted".to_owned()};
+        let depth = value_history_depth() as usize;
+        let mut history = BTreeMap::new();
+        let mut pointer = 0;
+        while pointer < binary.len() {
+            if binary.len() - pointer < 72 {
+                return Err(corrupted())
+            }
+            let key = KeyString::try_from(&binary[pointer..pointer + 64])?;
+            let count = u64_from_le_slice(&binary[pointer + 64..pointer + 72]);
+            pointer += 72;
+            let mut versions = VecDeque::new();
+            for _ in 0..count {
+                if binary.len() - pointer < 8 {
+                    return Err(corrupted())
+                }
+                let len = u64_from_le_slice(&binary[pointer..pointer + 8]) as usize;
+                pointer += 8;
+                if binary.len() - pointer < len {
+                    return Err(corrupted())
+                }
+                versions.push_back(Value{name: key, body: binary[pointer..pointer + len].to_vec()});
+                pointer += len;
+            }
+            versions.truncate(depth);
+            if !versions.is_empty() {
+                history.insert(key, versions);
+            }
+        }
+        *self.value_history.write().unwrap() = history;
+        self.value_history_changed.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn push_history(&self, old: Value) {
+        let depth = value_history_depth() as usize;
+        let mut history = self.value_history.write().unwrap();
+        self.value_history_changed.store(true, Ordering::Relaxed);
+        if depth == 0 {
+            history.remove(&old.name);
+            return
+        }
+        let versions = history.entry(old.name).or_default();
+        versions.push_front(old);
+        versions.truncate(depth);
+    }
+
+    fn forget_history(&self, key: &KeyString) {
+        if self.value_history.write().unwrap().remove(key).is_some() {
+            self.value_history_changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Reads a version of a value. Version 0 is the current value, 1 is the one before that and so on.
+    pub fn read_value_version(&self, key: &KeyString, version: u64) -> Result<Value, EzError> {
+
+        if version == 0 {
+            return match self.values.read().unwrap().get(key) {
+                Some(value) => Ok(value.clone()),
+                None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No value corresponds to key: '{}'", key)}),
+            }
+        }
+
+        match self.value_history.read().unwrap().get(key).and_then(|versions| versions.get(version as usize - 1)) {
+            Some(value) => Ok(value.clone()),
+            None => Err(EzError{tag: ErrorTag::Query, text: format!("Key '{}' has no version {}", key, version)}),
+        }
+    }
+
+    /// Makes a previous version the current value again. The value being replaced goes into the history
+    /// like any other update so a rollback can itself be rolled back.
+    pub fn rollback_value(&self, key: &KeyString, version: u64) -> Result<Value, EzError> {
+        println!("calling: BufferPool::rollback_value()");
+
+        if version == 0 {
+            return Err(EzError{tag: ErrorTag::Query, text: "Can't roll back to version 0. That is the current value".to_owned()})
+        }
+        let old = self.read_value_version(key, version)?;
+        self.update_value(old.clone())?;
+
+        Ok(old)
+    }
+
+    /// Applies the creates, updates and deletes in the batch all together or not at all. Every query is
+    /// checked against the values as the earlier queries of the batch leave them before anything changes,
+    /// and the values stay write locked while the batch is applied so no reader sees part of it.
+    /// Returns what each query returns on its own: the removed value for a delete and nothing otherwise.
+    /// On failure the error names the query that failed.
+    /// The value files are written by maintenance like any other change.
+    pub fn apply_value_batch(&self, queries: &[KvQuery]) -> Result<Vec<Option<Value>>, EzError> {
+        println!("calling: BufferPool::apply_value_batch()");
+
+        let batch_error = |i: usize, text: String| EzError{tag: ErrorTag::Query, text: format!("Query {} of the batch failed: {}", i, text)};
+
+        let now = get_current_time();
+        self.expire_values(now);
+
+        let added: u64 = queries.iter().map(|query| match query {
+            KvQuery::Create(_, body) | KvQuery::Update(_, body) => (std::mem::size_of::<KeyString>() + body.len()) as u64,
+            KvQuery::CreateWithTtl(_, body, _) | KvQuery::UpdateWithTtl(_, body, _) => (std::mem::size_of::<KeyString>() + body.len()) as u64,
+            _ => 0,
+        }).sum();
+        if self.occupied_buffer() + added > self.max_size() {
+            return Err(EzError{tag: ErrorTag::NoMoreBufferSpace, text: format!("The batch adds {} bytes. Remaining space is: {}", added, self.max_size().saturating_sub(self.occupied_buffer()))})
+        }
+
+        let mut values = self.values.write().unwrap();
+
+        // Whether each key touched so far exists after the queries before it
+        let mut exists: BTreeMap<KeyString, bool> = BTreeMap::new();
+        for (i, query) in queries.iter().enumerate() {
+            let (key, should_exist, will_exist) = match query {
+                KvQuery::CreateWithTtl(_, _, 0) | KvQuery::UpdateWithTtl(_, _, 0) => return Err(batch_error(i, "A TTL must be at least 1 second".to_owned())),
+                KvQuery::Create(key, _) | KvQuery::CreateWithTtl(key, _, _) => (key, false, true),
+                KvQuery::Update(key, _) | KvQuery::UpdateWithTtl(key, _, _) => (key, true, true),
+                KvQuery::Delete(key) => (key, true, false),
+                other => return Err(batch_error(i, format!("Only creates, updates and deletes can be batched. Got '{}'", other))),
+            };
+            validate_name(key.as_str()).map_err(|e| batch_error(i, e.text))?;
+            let existed = *exists.entry(*key).or_insert_with(|| values.contains_key(key));
+            match (existed, should_exist) {
+                (true, false) => return Err(batch_error(i, format!("value named '{}' already exists", key))),
+                (false, true) => return Err(batch_error(i, format!("No value corresponds to key: '{}'", key))),
+                _ => (),
+            }
+            exists.insert(*key, will_exist);
+        }
+
+        let mut results = Vec::with_capacity(queries.len());
+        let mut expiry = self.value_expiry.write().unwrap();
+        let mut modified = self.value_modified.write().unwrap();
+        for query in queries {
+            match query {
+                KvQuery::Create(key, body) | KvQuery::CreateWithTtl(key, body, _) => {
+                    values.insert(*key, Value{name: *key, body: body.clone()});
+                    self.value_naughty_list.write().unwrap().insert(*key);
+                    modified.insert(*key, now);
+                    results.push(None);
+                },
+                KvQuery::Update(key, body) | KvQuery::UpdateWithTtl(key, body, _) => {
+                    let old = values.insert(*key, Value{name: *key, body: body.clone()}).expect("checked above");
+                    self.push_history(old);
+                    self.value_naughty_list.write().unwrap().insert(*key);
+                    modified.insert(*key, now);
+                    results.push(None);
+                },
+                KvQuery::Delete(key) => {
+                    expiry.clear(key);
+                    modified.remove(key);
+                    self.forget_history(key);
+                    self.value_delete_list.write().unwrap().insert(*key);
+                    results.push(Some(values.remove(key).expect("checked above")));
+                },
+                _ => unreachable!("checked above"),
+            }
+            if let KvQuery::CreateWithTtl(key, _, ttl) | KvQuery::UpdateWithTtl(key, _, ttl) = query {
+                expiry.set(*key, now.saturating_add(*ttl));
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Total bytes held by previous versions of values.
+    pub fn value_history_size(&self) -> u64 {
+        self.value_history.read().unwrap().values()
+            .flat_map(|versions| versions.iter())
+            .map(|value| value.byte_size() as u64)
+            .sum()
+    }
+
+    fn classify_value_file(&self, values: &BTreeMap<KeyString, Value>, key: &KeyString, file_size: u64) -> ValueFile {
+        match values.get(key) {
+            // Maintenance rewrites values on the naughty list anyway
+            Some(_) if self.value_naughty_list.read().unwrap().contains(key) => ValueFile::Live,
+            Some(value) if value.body.len() as u64 + 64 == file_size => ValueFile::Live,
+            Some(_) => ValueFile::Stale,
+            None if self.unloaded_values.read().unwrap().contains(key) => ValueFile::Live,
+            None => ValueFile::Dead,
+        }
+    }
+
+    /// Counts the dead files in the value directory. Files whose names are not keys are skipped.
+    pub fn value_storage_stats(&self, dir: &Path) -> Result<ValueStorageStats, EzError> {
+        let values = self.values.read().unwrap();
+        let mut stats = ValueStorageStats::default();
+        for file in read_dir(dir)? {
+            let file = file?;
+            let key = match file.file_name().to_str().map(KeyString::from_str_checked) {
+                Some(Ok(key)) => key,
+                _ => continue,
+            };
+            let file_size = file.metadata()?.len();
+            stats.files += 1;
+            stats.bytes += file_size;
+            if !matches!(self.classify_value_file(&values, &key, file_size), ValueFile::Live) {
+                stats.dead_files += 1;
+                stats.dead_bytes += file_size;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Rewrites the value directory so it only holds the current version of each value. Files of deleted
+    /// values are removed and files holding an older version are written again. Values keep being readable
+    /// while this runs but can't be changed. Returns the storage stats from before and after.
+    pub fn compact_values(&self, dir: &Path) -> Result<(ValueStorageStats, ValueStorageStats), EzError> {
+        println!("calling: BufferPool::compact_values()");
+
+        let before = self.value_storage_stats(dir)?;
+        {
+            let values = self.values.read().unwrap();
+            for file in read_dir(dir)? {
+                let file = file?;
+                let key = match file.file_name().to_str().map(KeyString::from_str_checked) {
+                    Some(Ok(key)) => key,
+                    _ => continue,
+                };
+                match self.classify_value_file(&values, &key, file.metadata()?.len()) {
+                    ValueFile::Live => (),
+                    ValueFile::Dead => {
+                        std::fs::remove_file(file.path())?;
+                        self.value_delete_list.write().unwrap().remove(&key);
+                    },
+                    ValueFile::Stale => std::fs::write(file.path(), values[&key].write_to_binary())?,
+                }
+            }
+        }
+        let after = self.value_storage_stats(dir)?;
+
+        Ok((before, after))
+    }
+
+    /// Moves every table that has not been accessed for `idle_secs` out of memory, leaving a TableStub behind.
+    /// Tables with unwritten changes are written to disk first. Returns the names of the unloaded tables.
+    pub fn unload_idle_tables(&self, now: u64, idle_secs: u64) -> Result<Vec<KeyString>, EzError> {
+        println!("calling: BufferPool::unload_idle_tables()");
+
+        if idle_secs == 0 {
+            return Ok(Vec::new())
+        }
+
+        let mut tables = self.tables.write().unwrap();
+        let mut unloaded = Vec::new();
+        for (name, table) in tables.iter() {
+            let table = table.read().unwrap();
+            if now.saturating_sub(table.metadata.last_access.load(Ordering::Relaxed)) < idle_secs {
+                continue
+            }
+            self.flush_table(name, &table, false)?;
+            unloaded.push(*name);
+        }
+
+        let mut stubs = self.unloaded_tables.write().unwrap();
+        for name in &unloaded {
+            let table = Arc::unwrap_or_clone(tables.remove(name).expect("Collected from the map above").into_inner().unwrap());
+            self.table_naughty_list.write().unwrap().remove(name);
+            stubs.insert(*name, TableStub {
+                rows: table.len(),
+                byte_size: table.byte_size(),
+                header: table.header,
+                metadata: table.metadata,
+            });
+            self.table_unloads.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(unloaded)
+    }
+
+    /// Reads a table that was unloaded for being idle back into memory. Returns whether a reload happened.
+    pub fn ensure_loaded(&self, table_name: &KeyString) -> Result<bool, EzError> {
+
+        if !self.unloaded_tables.read().unwrap().contains_key(table_name) {
+            return Ok(false)
+        }
+        println!("calling: BufferPool::ensure_loaded()");
+
+        let mut tables = self.tables.write().unwrap();
+        let mut stubs = self.unloaded_tables.write().unwrap();
+        // Another thread may have reloaded it while we waited for the locks
+        if !stubs.contains_key(table_name) {
+            return Ok(false)
+        }
+
+        let table = read_table_file(table_name.as_str())?;
+        table.metadata.touch();
+        if !self.key_filters.read().unwrap().contains_key(table_name) {
+            self.load_key_filter(&table);
+        }
+        stubs.remove(table_name);
+        tables.insert(*table_name, RwLock::new(Arc::new(table)));
+        self.table_reloads.fetch_add(1, Ordering::Relaxed);
+
+        Ok(true)
+    }
+
     pub fn write_table_to_disk(&self) -> Result<(), EzError> {
         println!("calling: BufferPool::write_table_to_disk()");
 
@@ -189,6 +1657,77 @@ impl BufferPool {
 
 }
 
+/// Writes the users through a temporary file so a crash never leaves a half written user file behind.
+pub fn save_users(users: &BTreeMap<KeyString, RwLock<User>>, path: &Path) -> Result<(), EzError> {
+    let mut plain = BTreeMap::new();
+    for (username, user) in users {
+        plain.insert(*username, user.read().unwrap().clone());
+    }
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&plain.to_cbor_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Writes the list of row engine tables through a temporary file like save_users().
+fn save_row_engine_tables(tables: &BTreeSet<KeyString>, path: &Path) -> Result<(), EzError> {
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&row_engine_tables_to_binary(tables))?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Writes a key filter through a temporary file like save_users().
+fn save_key_filter(filter: &KeyFilter, table_rows: usize, path: &Path) -> Result<(), EzError> {
+    // restore_backup() moves the directory aside
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&filter.to_binary(table_rows))?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+fn remove_key_filter_file(table_name: &str) -> Result<(), EzError> {
+    match std::fs::remove_file(key_filter_file(table_name)) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes the partition maps through a temporary file like save_users().
+fn save_partitions(partitions: &BTreeMap<KeyString, PartitionMap>, path: &Path) -> Result<(), EzError> {
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&partitions_to_binary(partitions))?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Reads the users written by save_users(). Without a file there is a single user, admin, with the password "admin",
+/// and the file is written so the salt stays the same across restarts.
+pub fn load_users(path: &Path) -> Result<BTreeMap<KeyString, RwLock<User>>, EzError> {
+    let plain: BTreeMap<KeyString, User> = if path.exists() {
+        decode_cbor(&std::fs::read(path)?)?
+    } else {
+        BTreeMap::from([(ksf("admin"), User::admin("admin", "admin"))])
+    };
+    let users: BTreeMap<KeyString, RwLock<User>> = plain.into_iter().map(|(username, user)| (username, RwLock::new(user))).collect();
+    if !path.exists() {
+        save_users(&users, path)?;
+    }
+    Ok(users)
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -196,5 +1735,430 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_value_history() {
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        pool.add_value(Value{name: ksf("config"), body: vec![1]}).unwrap();
+        for i in 2..(DEFAULT_VALUE_HISTORY_DEPTH as u8 + 4) {
+            pool.update_value(Value{name: ksf("config"), body: vec![i]}).unwrap();
+        }
+        let newest = DEFAULT_VALUE_HISTORY_DEPTH as u8 + 3;
+        assert_eq!(pool.read_value_version(&ksf("config"), 0).unwrap().body, vec![newest]);
+        assert_eq!(pool.read_value_version(&ksf("config"), 1).unwrap().body, vec![newest - 1]);
+        assert_eq!(pool.value_history.read().unwrap()[&ksf("config")].len(), DEFAULT_VALUE_HISTORY_DEPTH as usize);
+        assert!(pool.read_value_version(&ksf("config"), DEFAULT_VALUE_HISTORY_DEPTH + 1).is_err());
+
+        pool.rollback_value(&ksf("config"), 2).unwrap();
+        assert_eq!(pool.read_value_version(&ksf("config"), 0).unwrap().body, vec![newest - 2]);
+        assert_eq!(pool.read_value_version(&ksf("config"), 1).unwrap().body, vec![newest]);
+
+        pool.remove_value(&ksf("config")).unwrap();
+        assert!(pool.read_value_version(&ksf("config"), 1).is_err());
+        assert!(pool.update_value(Value{name: ksf("config"), body: vec![0]}).is_err());
+    }
+
+    #[test]
+    fn test_value_history_survives_reload() {
+        let path = std::env::temp_dir().join(format!("ezdb_value_history_{}", std::process::id()));
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        pool.add_value(Value{name: ksf("config"), body: vec![1]}).unwrap();
+        pool.update_value(Value{name: ksf("config"), body: vec![2, 2]}).unwrap();
+        pool.update_value(Value{name: ksf("config"), body: vec![3]}).unwrap();
+        pool.add_value(Value{name: ksf("other"), body: vec![9]}).unwrap();
+        pool.update_value(Value{name: ksf("other"), body: Vec::new()}).unwrap();
+        pool.write_value_history(&path).unwrap();
+
+        let reloaded = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        reloaded.add_value(Value{name: ksf("config"), body: vec![3]}).unwrap();
+        reloaded.load_value_history(&path).unwrap();
+        assert_eq!(reloaded.read_value_version(&ksf("config"), 1).unwrap().body, vec![2, 2]);
+        assert_eq!(reloaded.read_value_version(&ksf("config"), 2).unwrap().body, vec![1]);
+        assert_eq!(reloaded.read_value_version(&ksf("other"), 1).unwrap().body, vec![9]);
+        assert_eq!(*reloaded.value_history.read().unwrap(), *pool.value_history.read().unwrap());
+        reloaded.rollback_value(&ksf("config"), 2).unwrap();
+        assert_eq!(reloaded.read_value_version(&ksf("config"), 0).unwrap().body, vec![1]);
+
+        // Deleting a value deletes its history from the file too
+        pool.remove_value(&ksf("config")).unwrap();
+        pool.write_value_history(&path).unwrap();
+        reloaded.load_value_history(&path).unwrap();
+        assert!(reloaded.read_value_version(&ksf("config"), 1).is_err());
+
+        let binary = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &binary[..binary.len() - 1]).unwrap();
+        assert!(reloaded.load_value_history(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_names_stay_in_the_data_dir() {
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        for name in ["../escaped", "/tmp/escaped", ".."] {
+            assert!(pool.add_value(Value{name: ksf(name), body: vec![1]}).is_err());
+            assert!(pool.put_value(Value{name: ksf(name), body: vec![1]}).is_err());
+            assert!(pool.apply_value_batch(&[KvQuery::Create(ksf(name), vec![1])]).is_err());
+            let table = ColumnTable::from_csv_string("id,i-P\n1", name, "test").unwrap();
+            assert!(pool.add_table(table).is_err());
+        }
+        assert!(pool.values.read().unwrap().is_empty());
+        assert!(pool.tables.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_value_batch() {
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        pool.add_value(Value{name: ksf("a"), body: vec![1]}).unwrap();
+        pool.add_value(Value{name: ksf("b"), body: vec![2]}).unwrap();
+
+        let results = pool.apply_value_batch(&[
+            KvQuery::Update(ksf("a"), vec![10]),
+            KvQuery::Delete(ksf("b")),
+            KvQuery::Create(ksf("b"), vec![20]),
+            KvQuery::Create(ksf("c"), vec![30]),
+        ]).unwrap();
+        assert_eq!(results, vec![None, Some(Value{name: ksf("b"), body: vec![2]}), None, None]);
+        assert_eq!(pool.read_value_version(&ksf("a"), 0).unwrap().body, vec![10]);
+        assert_eq!(pool.read_value_version(&ksf("a"), 1).unwrap().body, vec![1]);
+        assert_eq!(pool.read_value_version(&ksf("b"), 0).unwrap().body, vec![20]);
+
+        // The last query fails so none of them are applied
+        let before = pool.values.read().unwrap().clone();
+        assert!(pool.apply_value_batch(&[KvQuery::Update(ksf("a"), vec![0]), KvQuery::Delete(ksf("c")), KvQuery::Update(ksf("c"), vec![0])]).is_err());
+        assert!(pool.apply_value_batch(&[KvQuery::Create(ksf("d"), vec![0]), KvQuery::Create(ksf("d"), vec![0])]).is_err());
+        assert!(pool.apply_value_batch(&[KvQuery::Delete(ksf("a")), KvQuery::Read(ksf("b"))]).is_err());
+        assert_eq!(*pool.values.read().unwrap(), before);
+    }
+
+    #[test]
+    fn test_value_expiry() {
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        pool.add_value(Value{name: ksf("short"), body: vec![1]}).unwrap();
+        pool.add_value(Value{name: ksf("long"), body: vec![2]}).unwrap();
+        pool.add_value(Value{name: ksf("forever"), body: vec![3]}).unwrap();
+        pool.set_value_ttl(&ksf("short"), 10, 1000).unwrap();
+        pool.set_value_ttl(&ksf("long"), 100, 1000).unwrap();
+        assert!(pool.set_value_ttl(&ksf("forever"), 0, 1000).is_err());
+        assert!(pool.set_value_ttl(&ksf("missing"), 10, 1000).is_err());
+        assert_eq!(pool.value_expires_at(&ksf("short")), Some(1010));
+
+        // A new TTL replaces the old one
+        pool.set_value_ttl(&ksf("long"), 50, 1000).unwrap();
+        assert_eq!(pool.value_expires_at(&ksf("long")), Some(1050));
+
+        assert!(pool.expire_values(1009).is_empty());
+        assert_eq!(pool.expire_values(1010), vec![ksf("short")]);
+        assert!(!pool.values.read().unwrap().contains_key(&ksf("short")));
+        assert!(pool.value_delete_list.read().unwrap().contains(&ksf("short")));
+
+        let binary = pool.value_expiry.read().unwrap().to_binary();
+        let loaded = ValueExpiry::from_binary(&binary).unwrap();
+        assert_eq!(loaded.get(&ksf("long")), Some(1050));
+        assert_eq!(loaded.len(), 1);
+        assert!(ValueExpiry::from_binary(&binary[1..]).is_err());
+
+        // Deleting a value drops its TTL
+        pool.remove_value(&ksf("long")).unwrap();
+        assert!(pool.value_expiry.read().unwrap().is_empty());
+        assert_eq!(pool.expire_values(u64::MAX), Vec::<KeyString>::new());
+        assert!(pool.values.read().unwrap().contains_key(&ksf("forever")));
+
+        let results = pool.apply_value_batch(&[KvQuery::CreateWithTtl(ksf("batched"), vec![4], 60), KvQuery::UpdateWithTtl(ksf("forever"), vec![5], 60)]).unwrap();
+        assert_eq!(results, vec![None, None]);
+        assert!(pool.value_expires_at(&ksf("batched")).is_some());
+        assert!(pool.apply_value_batch(&[KvQuery::CreateWithTtl(ksf("zero"), vec![4], 0)]).is_err());
+    }
+
+    #[test]
+    fn test_scan_values() {
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        pool.add_value(Value{name: ksf("user"), body: b"top".to_vec()}).unwrap();
+        pool.add_value(Value{name: ksf("user:1"), body: b"alice".to_vec()}).unwrap();
+        pool.add_value(Value{name: ksf("user:2"), body: b"bob".to_vec()}).unwrap();
+        pool.add_value(Value{name: ksf("user:3"), body: b"albert".to_vec()}).unwrap();
+        pool.add_value(Value{name: ksf("users"), body: b"all".to_vec()}).unwrap();
+        let now = get_current_time();
+        pool.value_modified.write().unwrap().insert(ksf("user:3"), now - 100);
+
+        let names = |values: Vec<Value>| values.into_iter().map(|value| value.name.to_string()).collect::<Vec<_>>();
+        assert_eq!(names(pool.scan_values(&ksf("user:"), &ValueFilter::new(), now)), vec!["user:1", "user:2", "user:3"]);
+        assert_eq!(names(pool.scan_values(&ksf("user"), &ValueFilter::new(), now)).len(), 5);
+        assert!(pool.scan_values(&ksf("nobody"), &ValueFilter::new(), now).is_empty());
+
+        let filter = ValueFilter{body_prefix: b"al".to_vec(), ..ValueFilter::new()};
+        assert_eq!(names(pool.scan_values(&ksf("user:"), &filter, now)), vec!["user:1", "user:3"]);
+        let filter = ValueFilter{min_size: 4, max_size: 5, ..ValueFilter::new()};
+        assert_eq!(names(pool.scan_values(&ksf("user:"), &filter, now)), vec!["user:1"]);
+        let filter = ValueFilter{min_age_secs: 60, ..ValueFilter::new()};
+        assert_eq!(names(pool.scan_values(&ksf("user:"), &filter, now)), vec!["user:3"]);
+
+        // Writing a value makes it young again and deleting it forgets its age
+        pool.update_value(Value{name: ksf("user:3"), body: b"albert2".to_vec()}).unwrap();
+        assert!(pool.value_age(&ksf("user:3"), now + 1).unwrap() <= 1);
+        pool.remove_value(&ksf("user:3")).unwrap();
+        assert_eq!(pool.value_age(&ksf("user:3"), now), None);
+    }
+
+    #[test]
+    fn test_value_compaction() {
+        let dir = std::env::temp_dir().join(format!("ezdb_value_compaction_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        let live = Value{name: ksf("live"), body: vec![1; 100]};
+        let changed = Value{name: ksf("changed"), body: vec![2; 10]};
+        std::fs::write(dir.join("live"), live.write_to_binary()).unwrap();
+        std::fs::write(dir.join("changed"), Value{name: ksf("changed"), body: vec![2; 50]}.write_to_binary()).unwrap();
+        std::fs::write(dir.join("deleted"), Value{name: ksf("deleted"), body: vec![3; 200]}.write_to_binary()).unwrap();
+        std::fs::write(dir.join("unloaded"), Value{name: ksf("unloaded"), body: vec![4; 20]}.write_to_binary()).unwrap();
+        pool.values.write().unwrap().insert(live.name, live.clone());
+        pool.values.write().unwrap().insert(changed.name, changed.clone());
+        pool.unloaded_values.write().unwrap().insert(ksf("unloaded"));
+
+        let stats = pool.value_storage_stats(&dir).unwrap();
+        assert_eq!(stats.files, 4);
+        assert_eq!(stats.dead_files, 2);
+        assert_eq!(stats.dead_bytes, 114 + 264);
+
+        let (before, after) = pool.compact_values(&dir).unwrap();
+        assert_eq!(before, stats);
+        assert_eq!(after, ValueStorageStats{files: 3, bytes: 164 + 74 + 84, dead_files: 0, dead_bytes: 0});
+        assert!(!dir.join("deleted").exists());
+        assert_eq!(std::fs::read(dir.join("changed")).unwrap(), changed.write_to_binary());
+        assert_eq!(after.fragmentation(), 0.0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_idle_table_unloading() {
+        crate::paths::create_data_dirs().unwrap();
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        let name = ksf("idle_unloading_test");
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b", name.as_str(), "test").unwrap();
+        table.metadata.last_access.store(1_000, Ordering::Relaxed);
+        pool.add_table(table.clone()).unwrap();
+
+        assert!(pool.unload_idle_tables(1_030, 60).unwrap().is_empty());
+        assert_eq!(pool.unload_idle_tables(1_060, 60).unwrap(), vec![name]);
+        assert!(!pool.tables.read().unwrap().contains_key(&name));
+        assert_eq!(pool.unloaded_tables.read().unwrap()[&name].rows, 2);
+        assert!(pool.add_table(table.clone()).is_err());
+
+        assert!(pool.ensure_loaded(&name).unwrap());
+        assert!(!pool.ensure_loaded(&name).unwrap());
+        assert_eq!(**pool.tables.read().unwrap()[&name].read().unwrap(), table);
+        assert_eq!(pool.table_unloads.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.table_reloads.load(Ordering::Relaxed), 1);
+
+        std::fs::remove_file(table_file(name.as_str())).unwrap();
+    }
+
+    #[test]
+    fn test_drop_and_undrop() {
+        crate::paths::create_data_dirs().unwrap();
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        let name = ksf("drop_undrop_test");
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b", name.as_str(), "test").unwrap();
+        pool.add_table(table.clone()).unwrap();
+
+        pool.drop_table(name).unwrap();
+        assert!(!pool.table_exists(&name));
+        assert!(!table_file(name.as_str()).exists());
+        assert!(pool.drop_table(name).is_err());
+
+        pool.add_table(table.clone()).unwrap();
+        assert!(pool.undrop_table(name).is_err());
+        pool.tables.write().unwrap().remove(&name);
+
+        pool.undrop_table(name).unwrap();
+        assert_eq!(**pool.tables.read().unwrap()[&name].read().unwrap(), table);
+        assert!(pool.undrop_table(name).is_err());
+
+        std::fs::remove_file(table_file(name.as_str())).unwrap();
+    }
+
+    #[test]
+    fn test_flush_dirty() {
+        crate::paths::create_data_dirs().unwrap();
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        let name = ksf("flush_dirty_test");
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b", name.as_str(), "test").unwrap();
+        pool.add_table(table.clone()).unwrap();
+        pool.add_value(Value{name: ksf("flush_dirty_value"), body: vec![1, 2]}).unwrap();
+
+        assert_eq!(pool.flush_dirty().unwrap(), (1, 1));
+        assert_eq!(read_table_file(name.as_str()).unwrap(), table);
+        assert!(value_file("flush_dirty_value").exists());
+        assert_eq!(pool.flush_dirty().unwrap(), (0, 0));
+
+        std::fs::remove_file(table_file(name.as_str())).unwrap();
+        std::fs::remove_file(key_filter_file(name.as_str())).unwrap();
+        std::fs::remove_file(value_file("flush_dirty_value")).unwrap();
+    }
+
+    #[test]
+    fn test_buffer_rows_counts_new_keys() {
+        crate::paths::create_data_dirs().unwrap();
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        let name = ksf("buffer_rows_test");
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b", name.as_str(), "test").unwrap();
+        pool.add_table(table.clone()).unwrap();
+
+        // 2 is already in the table
+        let inserts = ColumnTable::from_csv_string("id,i-P;name,t-N\n2;x\n3;c\n4;d", name.as_str(), "test").unwrap();
+        assert_eq!(pool.buffer_rows(name, &table.header, &inserts).unwrap(), 2);
+        // Both are already waiting in the buffer
+        let again = ColumnTable::from_csv_string("id,i-P;name,t-N\n3;z\n4;z\n5;e", name.as_str(), "test").unwrap();
+        assert_eq!(pool.buffer_rows(name, &table.header, &again).unwrap(), 1);
+
+        assert_eq!(pool.merge_row_buffer(&name).unwrap(), 3);
+        let merged = pool.tables.read().unwrap()[&name].read().unwrap().get_column_text(&ksf("name")).unwrap().clone();
+        assert_eq!(merged, vec![ksf("a"), ksf("b"), ksf("c"), ksf("d"), ksf("e")]);
+        pool.drop_table(name).unwrap();
+        crate::trash::purge_trash(Some(&name)).unwrap();
+    }
+
+    #[test]
+    fn test_key_filter_lookups() {
+        crate::paths::create_data_dirs().unwrap();
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        let name = ksf("key_filter_test");
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b", name.as_str(), "test").unwrap();
+        table.metadata.last_access.store(1_000, Ordering::Relaxed);
+        pool.add_table(table.clone()).unwrap();
+
+        let list = |keys: &[&str]| RangeOrListOrAll::List(keys.iter().map(|key| ksf(key)).collect());
+        assert!(pool.rules_out_keys(&name, &list(&["3", "4"])));
+        assert!(!pool.rules_out_keys(&name, &list(&["3", "2"])));
+        assert!(!pool.rules_out_keys(&name, &list(&["three"])));
+        assert!(!pool.rules_out_keys(&name, &RangeOrListOrAll::Keys(KeyList::Texts(vec![ksf("3")]))));
+
+        let inserts = ColumnTable::from_csv_string("id,i-P;name,t-N\n3;c", name.as_str(), "test").unwrap();
+        pool.add_filter_keys(&name, &ksf("id"), &inserts);
+        assert!(!pool.rules_out_keys(&name, &RangeOrListOrAll::Keys(KeyList::Ints(vec![3]))));
+
+        // The filter is written with the table and read back with it
+        pool.unload_idle_tables(1_060, 60).unwrap();
+        assert!(key_filter_file(name.as_str()).exists());
+        pool.key_filters.write().unwrap().clear();
+        pool.ensure_loaded(&name).unwrap();
+        assert!(!pool.rules_out_keys(&name, &list(&["3"])));
+        assert!(pool.rules_out_keys(&name, &list(&["4"])));
+
+        pool.drop_table(name).unwrap();
+        assert!(!key_filter_file(name.as_str()).exists());
+        assert!(!pool.rules_out_keys(&name, &list(&["4"])));
+        crate::trash::purge_trash(Some(&name)).unwrap();
+    }
+
+    #[test]
+    #[cfg(all(feature = "server", target_os = "linux"))]
+    fn test_file_mapping() {
+        crate::paths::create_data_dirs().unwrap();
+        let name = "file_mapping_test";
+        let table = ColumnTable::from_csv_string("id,i-P;price,f-N\n1;1.5\n2;2.5", name, "test").unwrap();
+        write_table_file(name, &table.to_binary()).unwrap();
+
+        let path = table_file(name);
+        let mapping = FileMapping::open(&File::open(&path).unwrap(), &path).unwrap();
+        assert_eq!(mapping.bytes(), std::fs::read(&path).unwrap().as_slice());
+        assert_eq!(read_table_file(name).unwrap(), table);
+
+        // Replacing the file leaves the old mapping readable
+        write_table_file(name, b"").unwrap();
+        assert_eq!(ColumnTable::from_binary(Some(name), mapping.bytes()).unwrap(), table);
+        assert!(FileMapping::open(&File::open(&path).unwrap(), &path).is_err());
+        assert!(read_table_file(name).is_err());
+        remove_table_file(name).unwrap();
+    }
+
+    #[test]
+    fn test_chunked_tables() {
+        crate::paths::create_data_dirs().unwrap();
+        let name = "chunked_table_test";
+        let mut csv = "id,i-P;name,t-N".to_owned();
+        for i in 0..10 {
+            csv.push_str(&format!("\n{};row{}", i * 10, i));
+        }
+        let mut table = ColumnTable::from_csv_string(&csv, name, "test").unwrap();
+        let generations = |name: &str| read_chunk_manifest(name).unwrap().unwrap().chunks.iter().map(|chunk| chunk.generation).collect::<Vec<_>>();
+
+        write_table_chunks(name, &table, None, 4).unwrap();
+        assert_eq!(generations(name), vec![1, 1, 1]);
+        assert_eq!(read_table_file(name).unwrap(), table);
+
+        // Only the chunk holding the changed row is rewritten
+        let mut dirty = DirtyRows::new(4);
+        dirty.mark_rows(&[5]);
+        table.metadata.created_by = ksf("someone_else");
+        write_table_chunks(name, &table, Some(&dirty), 4).unwrap();
+        assert_eq!(generations(name), vec![1, 2, 1]);
+        assert_eq!(read_table_file(name).unwrap().metadata.created_by, ksf("someone_else"));
+        assert_eq!(std::fs::read_dir(table_chunks_dir(name)).unwrap().count(), 3);
+
+        // An insert moves every row after it
+        let first = table.len() - 1;
+        table.insert(ColumnTable::from_csv_string("id,i-P;name,t-N
+85;new", name, "test").unwrap()).unwrap();
+        let mut dirty = DirtyRows::new(4);
+        dirty.mark_from(first);
+        write_table_chunks(name, &table, Some(&dirty), 4).unwrap();
+        assert_eq!(generations(name), vec![1, 2, 3]);
+        assert_eq!(read_table_file(name).unwrap(), table);
+
+        // Small enough for a single file again
+        let small = table.create_subtable_from_index_range(0, 3);
+        write_table_chunks(name, &small, None, 4).unwrap();
+        assert_eq!(read_chunk_manifest(name).unwrap(), None);
+        assert!(!table_chunks_dir(name).exists());
+        assert_eq!(read_table_file(name).unwrap().len(), 3);
+
+        write_table_chunks(name, &table, None, 4).unwrap();
+        remove_table_files(name).unwrap();
+        assert!(!table_file(name).exists());
+        assert!(!table_chunks_dir(name).exists());
+    }
+
+    #[test]
+    fn test_table_ownership_transfer() {
+        crate::paths::create_data_dirs().unwrap();
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        let name = ksf("ownership_transfer_test");
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b", name.as_str(), "alice").unwrap();
+        table.metadata.last_access.store(1_000, Ordering::Relaxed);
+        pool.add_table(table).unwrap();
+        assert_eq!(pool.table_owner(&name), Some(ksf("alice")));
+        assert_eq!(pool.table_owner(&ksf("no_such_table")), None);
+
+        // Unloaded tables keep their owner and are loaded to change it
+        assert_eq!(pool.unload_idle_tables(1_060, 60).unwrap(), vec![name]);
+        assert_eq!(pool.table_owner(&name), Some(ksf("alice")));
+        pool.transfer_ownership(&name, ksf("bob")).unwrap();
+        assert_eq!(pool.table_owner(&name), Some(ksf("bob")));
+        assert!(pool.table_naughty_list.read().unwrap().contains(&name));
+        assert!(pool.transfer_ownership(&ksf("no_such_table"), ksf("bob")).is_err());
+
+        std::fs::remove_file(table_file(name.as_str())).unwrap();
+    }
+
+    #[test]
+    fn test_users_persist() {
+        crate::paths::create_data_dirs().unwrap();
+        let path = crate::paths::config_file(".users_persist_test");
+        let _ = std::fs::remove_file(&path);
+
+        let users = RwLock::new(load_users(&path).unwrap());
+        assert!(path.exists());
+        crate::auth::add_user(&users, User::new("carol", "hunter2")).unwrap();
+        save_users(&users.read().unwrap(), &path).unwrap();
+
+        let reloaded = RwLock::new(load_users(&path).unwrap());
+        assert!(crate::auth::authenticate_user(&reloaded, "carol", "hunter2").is_ok());
+        assert!(crate::auth::authenticate_user(&reloaded, "admin", "admin").is_ok());
+        assert!(crate::auth::authenticate_user(&reloaded, "carol", "admin").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
 
 }
\ No newline at end of file
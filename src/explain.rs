@@ -0,0 +1,297 @@
+//! EXPLAIN. Describes how a query would run without running it, one step per row of the answer: where the
+//! rows come from, how many there are likely to be and what is done with them, in the order it is done.
+//! The plan is made with the same checks the executor makes (the key filter, partition routing and the
+//! primary key span) so it tells whether a SELECT reads the whole table or only the keys it asks for.
+
+use crate::db_structure::{ColumnTable, DbColumn, LongTexts, OnConflict};
+use crate::disk_utilities::BufferPool;
+use crate::ezql::{keys_to_indexes, OpOrCond, Query, RangeOrListOrAll};
+use crate::partitions::partition_names;
+use crate::system_tables::is_system_table;
+use crate::utilities::{is_glob, ksf, print_sep_list, ErrorTag, EzError, KeyString};
+
+
+/// One thing the executor does to answer a query.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlanStep {
+    pub operation: &'static str,
+    pub table: KeyString,
+    /// How the rows are found, as in "full scan" or "primary key range 10..20".
+    pub access_path: String,
+    /// The rows the step reads or produces. An upper bound for filters, since the conditions are not tested.
+    /// -1 when it can't be known without running the query.
+    pub estimated_rows: i32,
+    pub detail: String,
+}
+
+impl PlanStep {
+    fn new(operation: &'static str, table: KeyString, access_path: impl Into<String>, estimated_rows: i32, detail: impl Into<String>) -> PlanStep {
+        PlanStep{operation, table, access_path: access_path.into(), estimated_rows, detail: detail.into()}
+    }
+}
+
+/// The steps the executor would take to answer the query. `previous` is the result of the query before it in
+/// the batch, which the query runs on instead of the stored table.
+pub fn plan_query(query: &Query, previous: Option<&ColumnTable>, pool: &BufferPool) -> Result<Vec<PlanStep>, EzError> {
+    let mut steps = Vec::new();
+    match query {
+        Query::SELECT { table_name, primary_keys, columns, conditions, distinct, limit } => {
+            let rows = plan_read(&mut steps, table_name, primary_keys, previous, pool)?;
+            // Distinct drops rows after filtering so the scan can only stop early without it
+            let rows = plan_filter(&mut steps, table_name, conditions, rows, if *distinct { None } else { *limit });
+            if *distinct {
+                steps.push(PlanStep::new("distinct", *table_name, "compare whole rows", rows, "rows equal to an earlier row are dropped"));
+            }
+            let rows = match limit {
+                Some(limit) => {
+                    steps.push(PlanStep::new("limit", *table_name, "first rows by primary key", at_most(rows, *limit), format!("keeps {} rows", limit)));
+                    at_most(rows, *limit)
+                },
+                None => rows,
+            };
+            if columns.iter().any(|column| column.as_str() != "*") {
+                steps.push(PlanStep::new("project", *table_name, "columns", rows, print_sep_list(columns, ", ")));
+            }
+        },
+        Query::LEFT_JOIN { left_table_name, right_table_name, match_columns, primary_keys } => {
+            let rows = plan_read(&mut steps, left_table_name, primary_keys, previous, pool)?;
+            plan_read(&mut steps, right_table_name, &RangeOrListOrAll::All, None, pool)?;
+            steps.push(PlanStep::new(
+                "left join",
+                *right_table_name,
+                format!("hash lookup on {}", match_columns.1),
+                rows,
+                format!("{}.{} = {}.{}", left_table_name, match_columns.0, right_table_name, match_columns.1),
+            ));
+        },
+        Query::UPDATE { table_name, primary_keys, conditions, updates, version } => {
+            let rows = plan_read(&mut steps, table_name, primary_keys, previous, pool)?;
+            let rows = plan_filter(&mut steps, table_name, conditions, rows, None);
+            let detail = match version {
+                Some(version) => format!("{} if the table is at version {}", print_sep_list(updates, ", "), version),
+                None => print_sep_list(updates, ", "),
+            };
+            steps.push(PlanStep::new("update", *table_name, "in place", rows, detail));
+        },
+        Query::DELETE { table_name, primary_keys, conditions } => {
+            let rows = plan_read(&mut steps, table_name, primary_keys, previous, pool)?;
+            let rows = plan_filter(&mut steps, table_name, conditions, rows, None);
+            steps.push(PlanStep::new("delete", *table_name, "keep mask", rows, "the remaining rows are compacted"));
+        },
+        Query::INSERT { table_name, inserts, on_conflict } => {
+            // Only skipping inserts wait in the row buffer. See write_to_table()
+            let access_path = match pool.uses_row_engine(table_name) && *on_conflict == OnConflict::Skip {
+                true => "row buffer",
+                false => "merge by primary key",
+            };
+            let detail = match on_conflict {
+                OnConflict::Skip => "rows with a key already in the table are dropped",
+                OnConflict::Error => "fails without inserting if a key is already in the table",
+                OnConflict::Overwrite => "rows with a key already in the table replace it",
+            };
+            steps.push(PlanStep::new("insert", *table_name, access_path, inserts.len() as i32, detail));
+        },
+        Query::SUMMARY { table_name, columns } => {
+            let rows = plan_read(&mut steps, table_name, &RangeOrListOrAll::All, previous, pool)?;
+            let names: Vec<KeyString> = columns.iter().map(|statistic| statistic.column).collect();
+            steps.push(PlanStep::new("summarize", *table_name, "columns", rows, print_sep_list(&names, ", ")));
+        },
+        Query::MULTI_SUMMARY { tables, columns } => {
+            let names: Vec<KeyString> = columns.iter().map(|statistic| statistic.column).collect();
+            for table_name in tables {
+                // Globs are only resolved against the tables there are when the query runs
+                let rows = match is_glob(table_name.as_str()) {
+                    true => {
+                        steps.push(PlanStep::new("scan", *table_name, "every matching table", -1, "run in parallel"));
+                        -1
+                    },
+                    false => plan_read(&mut steps, table_name, &RangeOrListOrAll::All, None, pool)?,
+                };
+                steps.push(PlanStep::new("summarize", *table_name, "columns", rows, print_sep_list(&names, ", ")));
+            }
+        },
+        Query::INTO { query, target } => {
+            steps = plan_query(query, previous, pool)?;
+            let rows = steps.last().map(|step| step.estimated_rows).unwrap_or(-1);
+            steps.push(PlanStep::new("store", target.name(), "result", rows, target.to_string()));
+        },
+        other => {
+            steps.push(PlanStep::new("run", other.get_table_name(), other.type_name(), -1, "does not read rows"));
+        },
+    }
+    Ok(steps)
+}
+
+/// The plan of the query as a table with one row per step, for the answer to EXPLAIN.
+pub fn explain_query(query: &Query, previous: Option<&ColumnTable>, pool: &BufferPool) -> Result<ColumnTable, EzError> {
+    plan_table(&plan_query(query, previous, pool)?)
+}
+
+pub fn plan_table(steps: &[PlanStep]) -> Result<ColumnTable, EzError> {
+    let mut table = ColumnTable::create_empty("explain", "system");
+    table.add_column(ksf("step"), DbColumn::Ints((1..=steps.len() as i32).collect()))?;
+    table.add_column(ksf("operation"), DbColumn::Texts(steps.iter().map(|step| ksf(step.operation)).collect()))?;
+    table.add_column(ksf("table"), DbColumn::Texts(steps.iter().map(|step| step.table).collect()))?;
+    table.add_column(ksf("access_path"), DbColumn::Texts(steps.iter().map(|step| KeyString::from_str_lossy(&step.access_path)).collect()))?;
+    table.add_column(ksf("estimated_rows"), DbColumn::Ints(steps.iter().map(|step| step.estimated_rows).collect()))?;
+    table.add_column(ksf("detail"), DbColumn::LongTexts(steps.iter().map(|step| step.detail.as_str()).collect::<LongTexts>()))?;
+    Ok(table)
+}
+
+fn at_most(rows: i32, limit: usize) -> i32 {
+    match rows {
+        -1 => limit.min(i32::MAX as usize) as i32,
+        rows => rows.min(limit.min(i32::MAX as usize) as i32),
+    }
+}
+
+fn access_path(keys: &RangeOrListOrAll) -> String {
+    match keys {
+        RangeOrListOrAll::All => "full scan".to_owned(),
+        RangeOrListOrAll::Range(start, stop) => format!("primary key range {}..{}", start, stop),
+        RangeOrListOrAll::List(_) | RangeOrListOrAll::Keys(_) => "primary key lookup".to_owned(),
+    }
+}
+
+/// Adds the step that finds the rows of the keys and returns how many it finds.
+/// Follows the executor: the key filter is asked first, then a partitioned table only reads the partitions the
+/// keys fall in, and unloaded tables are read back from disk before they are searched.
+fn plan_read(steps: &mut Vec<PlanStep>, table_name: &KeyString, keys: &RangeOrListOrAll, previous: Option<&ColumnTable>, pool: &BufferPool) -> Result<i32, EzError> {
+    if let Some(previous) = previous {
+        let rows = keys_to_indexes(previous, keys)?.len() as i32;
+        steps.push(PlanStep::new("scan", previous.name, access_path(keys), rows, "the result of the query before it"));
+        return Ok(rows)
+    }
+    if is_system_table(table_name) {
+        steps.push(PlanStep::new("scan", *table_name, "system table", -1, "built from the state of the server when it is read"));
+        return Ok(-1)
+    }
+    if pool.rules_out_keys(table_name, keys) {
+        steps.push(PlanStep::new("scan", *table_name, "key filter", 0, "none of the keys are in the table so it is not read"));
+        return Ok(0)
+    }
+
+    if let Some(map) = pool.partition_map(table_name) {
+        let touched = map.partitions_for(keys);
+        let mut rows = 0;
+        let mut unloaded = 0;
+        for partition in partition_names(table_name, &touched)? {
+            let (partition_rows, loaded) = stored_rows(pool, &partition, keys)?;
+            rows += partition_rows;
+            unloaded += !loaded as usize;
+        }
+        let mut detail = format!("partitions {} of {}", print_sep_list(&touched, ", "), map.count());
+        if unloaded > 0 {
+            detail.push_str(&format!(". {} are read back from disk first", unloaded));
+        }
+        steps.push(PlanStep::new("scan", *table_name, access_path(keys), rows, detail));
+        return Ok(rows)
+    }
+
+    let (rows, loaded) = stored_rows(pool, table_name, keys)?;
+    let mut notes = Vec::new();
+    if !loaded {
+        notes.push("read back from disk first");
+    }
+    if pool.uses_row_engine(table_name) {
+        notes.push("buffered rows are merged first");
+    }
+    steps.push(PlanStep::new("scan", *table_name, access_path(keys), rows, print_sep_list(&notes, ". ")));
+    Ok(rows)
+}
+
+/// The rows the keys select in a stored table and whether the table is loaded. Unloaded tables are not read,
+/// so for them a list of keys counts as found and a range as the whole table.
+fn stored_rows(pool: &BufferPool, table_name: &KeyString, keys: &RangeOrListOrAll) -> Result<(i32, bool), EzError> {
+    if let Some(table) = pool.tables.read().unwrap().get(table_name) {
+        let table = table.read().unwrap();
+        return Ok((keys_to_indexes(&table, keys)?.len() as i32, true))
+    }
+    match pool.unloaded_tables.read().unwrap().get(table_name) {
+        Some(stub) => {
+            let rows = match keys {
+                RangeOrListOrAll::All | RangeOrListOrAll::Range(..) => stub.rows,
+                RangeOrListOrAll::List(list) => list.len().min(stub.rows),
+                RangeOrListOrAll::Keys(list) => list.len().min(stub.rows),
+            };
+            Ok((rows as i32, false))
+        },
+        None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named: '{}'", table_name)}),
+    }
+}
+
+/// Adds the step that tests the conditions, if there are any, and returns the most rows that can pass.
+fn plan_filter(steps: &mut Vec<PlanStep>, table_name: &KeyString, conditions: &[OpOrCond], rows: i32, limit: Option<usize>) -> i32 {
+    if conditions.is_empty() {
+        return match limit {
+            Some(limit) => at_most(rows, limit),
+            None => rows,
+        }
+    }
+    let mut detail = print_sep_list(conditions, " ");
+    let rows = match limit {
+        Some(limit) => {
+            detail.push_str(&format!(". Stops after {} matching rows", limit));
+            at_most(rows, limit)
+        },
+        // Large scans are split between threads. See filter_keepers_limited()
+        None => rows,
+    };
+    steps.push(PlanStep::new("filter", *table_name, "test every row", rows, detail));
+    rows
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU64;
+
+    use crate::disk_utilities::MAX_BUFFERPOOL_SIZE;
+    use crate::testing_tools::create_fixed_table;
+
+    use super::*;
+
+    #[test]
+    fn test_explain_select() {
+        crate::paths::create_data_dirs().unwrap();
+        let pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+        let table = create_fixed_table(10);
+        let name = table.name;
+        pool.add_table(table).unwrap();
+
+        let everything = Query::new_select(name.as_str());
+        let steps = plan_query(&everything, None, &pool).unwrap();
+        assert_eq!(steps[0].access_path, "full scan");
+        assert_eq!(steps[0].estimated_rows, 10);
+
+        let text = "SELECT(table_name: fixed_table, primary_keys: 0..3, conditions: ((floats greater_than 0.5)), limit: 2)";
+        let query: Query = text.parse().unwrap();
+        let steps = plan_query(&query, None, &pool).unwrap();
+        let operations: Vec<&str> = steps.iter().map(|step| step.operation).collect();
+        assert_eq!(operations, vec!["scan", "filter", "limit"]);
+        assert_eq!(steps[0].access_path, "primary key range 0..3");
+        assert_eq!(steps[0].estimated_rows, 3);
+        assert_eq!(steps[2].estimated_rows, 2);
+
+        let missing = Query::SELECT {
+            table_name: name,
+            primary_keys: RangeOrListOrAll::List(vec![ksf("1000"), ksf("2000")]),
+            columns: vec![ksf("*")],
+            conditions: Vec::new(),
+            distinct: false,
+            limit: None,
+        };
+        let steps = plan_query(&missing, None, &pool).unwrap();
+        assert_eq!((steps[0].access_path.as_str(), steps[0].estimated_rows), ("key filter", 0));
+
+        let explain: Query = format!("EXPLAIN(query: \"{}\")", text).parse().unwrap();
+        assert_eq!(explain, Query::EXPLAIN { query: Box::new(query.clone()) });
+        assert_eq!(Query::from_binary(&explain.to_binary()).unwrap(), explain);
+
+        let table = explain_query(&query, None, &pool).unwrap();
+        assert_eq!(table.len(), 3);
+        assert!(plan_query(&Query::new_select("no_such_table"), None, &pool).is_err());
+        pool.drop_table(name).unwrap();
+        crate::trash::purge_trash(Some(&name)).unwrap();
+    }
+}
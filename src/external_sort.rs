@@ -0,0 +1,448 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+use crate::alloc_stats::{self, AllocPhase};
+use crate::db_structure::{Collation, ColumnTable, DbColumn, HeaderItem, LongTexts};
+use crate::paths::sort_spill_dir;
+#[cfg(feature = "server")]
+use crate::query_execution::StreamBuffer;
+use crate::utilities::{get_precise_time, u64_from_le_slice, ErrorTag, EzError, KeyString};
+
+
+/// Results up to this size in bytes are sorted in memory. Anything bigger is sorted in runs on disk.
+pub const DEFAULT_SORT_SPILL_THRESHOLD: u64 = 256_000_000;  // 256mb
+
+/// How many rows each chunk of a run holds. Merging keeps one chunk per run in memory.
+pub const RUN_CHUNK_ROWS: usize = 4096;
+
+static SORT_SPILL_THRESHOLD: AtomicU64 = AtomicU64::new(DEFAULT_SORT_SPILL_THRESHOLD);
+
+static RUN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_sort_spill_threshold(bytes: u64) {
+    SORT_SPILL_THRESHOLD.store(bytes, AtomicOrdering::Relaxed);
+}
+
+pub fn sort_spill_threshold() -> u64 {
+    SORT_SPILL_THRESHOLD.load(AtomicOrdering::Relaxed)
+}
+
+/// Deletes runs left behind by a crash. Called on startup.
+pub fn clear_sort_spill_dir() -> Result<(), EzError> {
+    let dir = sort_spill_dir();
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    Ok(())
+}
+
+/// The value a row is sorted by. Floats are ordered with total_cmp so NaN has a place.
+#[derive(Clone, Debug, PartialEq)]
+enum SortKey {
+    Int(i32),
+    Float(f32),
+    Text(KeyString),
+    Duration(i64),
+    LongText(String),
+}
+
+impl Eq for SortKey {}
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (SortKey::Int(a), SortKey::Int(b)) => a.cmp(b),
+            (SortKey::Float(a), SortKey::Float(b)) => a.total_cmp(b),
+            (SortKey::Text(a), SortKey::Text(b)) => a.cmp(b),
+            (SortKey::Duration(a), SortKey::Duration(b)) => a.cmp(b),
+            (SortKey::LongText(a), SortKey::LongText(b)) => a.cmp(b),
+            // A column only holds one type so this never happens
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+/// Orders the heap of the merge. For descending sorts the order is flipped so the heap still pops the next row first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct MergeKey {
+    key: SortKey,
+    descending: bool,
+}
+
+impl PartialOrd for MergeKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MergeKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let order = self.key.cmp(&other.key);
+        if self.descending { order.reverse() } else { order }
+    }
+}
+
+/// Texts are folded to the collation of their column, which orders them the way the collation compares them.
+fn sort_key(column: &DbColumn, index: usize, collation: Collation) -> SortKey {
+    match column {
+        DbColumn::Ints(col) => SortKey::Int(col[index]),
+        DbColumn::Floats(col) => SortKey::Float(col[index]),
+        DbColumn::Texts(col) => SortKey::Text(collation.fold(&col[index])),
+        DbColumn::Durations(col) => SortKey::Duration(col[index]),
+        DbColumn::LongTexts(col) => SortKey::LongText(col.get(index).to_owned()),
+    }
+}
+
+fn empty_like(column: &DbColumn, capacity: usize) -> DbColumn {
+    match column {
+        DbColumn::Ints(_) => DbColumn::Ints(Vec::with_capacity(capacity)),
+        DbColumn::Floats(_) => DbColumn::Floats(Vec::with_capacity(capacity)),
+        DbColumn::Texts(_) => DbColumn::Texts(Vec::with_capacity(capacity)),
+        DbColumn::Durations(_) => DbColumn::Durations(Vec::with_capacity(capacity)),
+        DbColumn::LongTexts(_) => DbColumn::LongTexts(LongTexts::with_capacity(capacity)),
+    }
+}
+
+/// Appends row `index` of `source` to `target`. Both tables must have the same columns.
+fn push_row(target: &mut BTreeMap<KeyString, DbColumn>, source: &ColumnTable, index: usize) {
+    for (name, column) in target.iter_mut() {
+        match (column, &source.columns[name]) {
+            (DbColumn::Ints(to), DbColumn::Ints(from)) => to.push(from[index]),
+            (DbColumn::Floats(to), DbColumn::Floats(from)) => to.push(from[index]),
+            (DbColumn::Texts(to), DbColumn::Texts(from)) => to.push(from[index]),
+            (DbColumn::Durations(to), DbColumn::Durations(from)) => to.push(from[index]),
+            (DbColumn::LongTexts(to), DbColumn::LongTexts(from)) => to.push(from.get(index)),
+            _ => unreachable!("Runs of one sort always have the same columns"),
+        }
+    }
+}
+
+/// Builds a table out of the rows of `source` in the given order.
+fn gather_rows(source: &ColumnTable, indexes: &[usize]) -> ColumnTable {
+    let mut columns: BTreeMap<KeyString, DbColumn> = source.columns.iter().map(|(name, column)| (*name, empty_like(column, indexes.len()))).collect();
+    for index in indexes {
+        push_row(&mut columns, source, *index);
+    }
+    ColumnTable{name: source.name, header: source.header.clone(), columns, metadata: source.metadata.inherit()}
+}
+
+/// Sorts a table by a column in memory. Rows with equal keys keep their order.
+/// The result is ordered by the given column, not the primary key, so it is only meant for output.
+pub fn sort_table_by_column(table: &ColumnTable, column: &KeyString, descending: bool) -> Result<ColumnTable, EzError> {
+    let _phase = alloc_stats::enter(AllocPhase::Sort);
+
+    let sort_column = match table.columns.get(column) {
+        Some(col) => col,
+        None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("Can not sort by '{}'. There is no such column", column)}),
+    };
+    let collation = table.collation_of(column);
+    let mut indexes: Vec<usize> = (0..table.len()).collect();
+    indexes.sort_by(|a, b| {
+        let order = match sort_column {
+            // Compared in place so sorting doesn't copy every value twice per comparison
+            DbColumn::LongTexts(col) => col.get(*a).cmp(col.get(*b)),
+            _ => sort_key(sort_column, *a, collation).cmp(&sort_key(sort_column, *b, collation)),
+        };
+        if descending { order.reverse() } else { order }
+    });
+
+    Ok(gather_rows(table, &indexes))
+}
+
+/// Sorts results of any size by a column using a bounded amount of memory.
+/// Parts of the result are pushed in any order. Once the buffered parts exceed sort_spill_threshold()
+/// they are sorted and written to disk as a run. finish() merges the runs while the output is streamed.
+pub struct ExternalSorter {
+    column: KeyString,
+    descending: bool,
+    threshold: u64,
+    header: Option<BTreeSet<HeaderItem>>,
+    buffer: Vec<ColumnTable>,
+    buffered_bytes: u64,
+    runs: Vec<PathBuf>,
+}
+
+impl ExternalSorter {
+    pub fn new(column: KeyString, descending: bool) -> ExternalSorter {
+        ExternalSorter::with_threshold(column, descending, sort_spill_threshold())
+    }
+
+    pub fn with_threshold(column: KeyString, descending: bool, threshold: u64) -> ExternalSorter {
+        ExternalSorter {
+            column,
+            descending,
+            threshold,
+            header: None,
+            buffer: Vec::new(),
+            buffered_bytes: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, part: ColumnTable) -> Result<(), EzError> {
+        match &self.header {
+            Some(header) if *header != part.header => {
+                return Err(EzError{tag: ErrorTag::Structure, text: format!("Can not sort '{}' together with the parts before it. The headers are different", part.name)})
+            },
+            Some(_) => (),
+            None => self.header = Some(part.header.clone()),
+        }
+        self.buffered_bytes += part.byte_size() as u64;
+        self.buffer.push(part);
+        if self.buffered_bytes > self.threshold {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Combines the buffered parts into one sorted table and empties the buffer.
+    fn sort_buffer(&mut self) -> Result<Option<ColumnTable>, EzError> {
+        let mut parts = std::mem::take(&mut self.buffer).into_iter();
+        self.buffered_bytes = 0;
+        let mut combined = match parts.next() {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        for part in parts {
+            for index in 0..part.len() {
+                push_row(&mut combined.columns, &part, index);
+            }
+        }
+        Ok(Some(sort_table_by_column(&combined, &self.column, self.descending)?))
+    }
+
+    /// Writes the buffered parts to disk as a single sorted run.
+    fn spill(&mut self) -> Result<(), EzError> {
+        println!("calling: ExternalSorter::spill()");
+
+        let sorted = match self.sort_buffer()? {
+            Some(sorted) => sorted,
+            None => return Ok(()),
+        };
+
+        std::fs::create_dir_all(sort_spill_dir())?;
+        let path = sort_spill_dir().join(format!("{}_{}.run", get_precise_time(), RUN_COUNTER.fetch_add(1, AtomicOrdering::Relaxed)));
+        let mut file = BufWriter::new(File::create(&path)?);
+        let mut start = 0;
+        while start < sorted.len() {
+            let stop = std::cmp::min(start + RUN_CHUNK_ROWS, sorted.len());
+            let indexes: Vec<usize> = (start..stop).collect();
+            let chunk = gather_rows(&sorted, &indexes).to_binary();
+            file.write_all(&chunk.len().to_le_bytes())?;
+            file.write_all(&chunk)?;
+            start = stop;
+        }
+        file.flush()?;
+        self.runs.push(path);
+
+        Ok(())
+    }
+
+    /// Sorts whatever is left. If nothing was spilled the result never touches the disk.
+    pub fn finish(mut self) -> Result<SortedOutput, EzError> {
+        if self.runs.is_empty() {
+            return Ok(SortedOutput::InMemory(self.sort_buffer()?))
+        }
+        self.spill()?;
+        let runs = std::mem::take(&mut self.runs);
+        Ok(SortedOutput::Merged(RunMerger::open(runs, self.column, self.descending)?))
+    }
+}
+
+impl Drop for ExternalSorter {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            let _ = std::fs::remove_file(run);
+        }
+    }
+}
+
+/// One spilled run being read back a chunk at a time.
+struct RunReader {
+    file: BufReader<File>,
+    chunk: ColumnTable,
+    position: usize,
+}
+
+impl RunReader {
+    /// Returns false when the run is used up.
+    fn next_chunk(&mut self) -> Result<bool, EzError> {
+        let mut len = [0u8; 8];
+        match self.file.read_exact(&mut len) {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        let mut binary = vec![0u8; u64_from_le_slice(&len) as usize];
+        self.file.read_exact(&mut binary)?;
+        self.chunk = ColumnTable::from_binary(Some("sorted"), &binary)?;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+/// Merges sorted runs. Only the current chunk of each run is held in memory.
+pub struct RunMerger {
+    readers: Vec<RunReader>,
+    heap: BinaryHeap<Reverse<(MergeKey, usize)>>,
+    paths: Vec<PathBuf>,
+    column: KeyString,
+    descending: bool,
+}
+
+impl RunMerger {
+    fn open(paths: Vec<PathBuf>, column: KeyString, descending: bool) -> Result<RunMerger, EzError> {
+        let mut merger = RunMerger {
+            readers: Vec::with_capacity(paths.len()),
+            heap: BinaryHeap::new(),
+            paths,
+            column,
+            descending,
+        };
+        for (i, path) in merger.paths.iter().enumerate() {
+            let mut reader = RunReader {
+                file: BufReader::new(File::open(path)?),
+                chunk: ColumnTable::create_empty("sorted", "sort"),
+                position: 0,
+            };
+            if reader.next_chunk()? {
+                merger.heap.push(Reverse((Self::key_of(&reader, &column, descending), i)));
+            }
+            merger.readers.push(reader);
+        }
+        Ok(merger)
+    }
+
+    fn key_of(reader: &RunReader, column: &KeyString, descending: bool) -> MergeKey {
+        MergeKey{key: sort_key(&reader.chunk.columns[column], reader.position, reader.chunk.collation_of(column)), descending}
+    }
+
+    /// The next chunk of up to RUN_CHUNK_ROWS rows in sorted order, or None when every run is used up.
+    pub fn next_chunk(&mut self) -> Result<Option<ColumnTable>, EzError> {
+        let template = match self.readers.iter().find(|reader| !reader.chunk.columns.is_empty()) {
+            Some(reader) => &reader.chunk,
+            None => return Ok(None),
+        };
+        let mut columns: BTreeMap<KeyString, DbColumn> = template.columns.iter().map(|(name, column)| (*name, empty_like(column, RUN_CHUNK_ROWS))).collect();
+        let (name, header, metadata) = (template.name, template.header.clone(), template.metadata.inherit());
+
+        let mut rows = 0;
+        while rows < RUN_CHUNK_ROWS {
+            let Reverse((_, run)) = match self.heap.pop() {
+                Some(next) => next,
+                None => break,
+            };
+            let reader = &mut self.readers[run];
+            push_row(&mut columns, &reader.chunk, reader.position);
+            rows += 1;
+            reader.position += 1;
+            if reader.position < reader.chunk.len() || reader.next_chunk()? {
+                self.heap.push(Reverse((Self::key_of(reader, &self.column, self.descending), run)));
+            }
+        }
+
+        if rows == 0 {
+            return Ok(None)
+        }
+        Ok(Some(ColumnTable{name, header, columns, metadata}))
+    }
+}
+
+impl Drop for RunMerger {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// The sorted result. Small results stay in memory, big ones are merged from disk as they are read.
+pub enum SortedOutput {
+    InMemory(Option<ColumnTable>),
+    Merged(RunMerger),
+}
+
+impl SortedOutput {
+    pub fn next_chunk(&mut self) -> Result<Option<ColumnTable>, EzError> {
+        match self {
+            SortedOutput::InMemory(table) => Ok(table.take()),
+            SortedOutput::Merged(merger) => merger.next_chunk(),
+        }
+    }
+
+    /// Streams the sorted rows to the client as a sequence of [length: u64][EZ binary table] chunks.
+    #[cfg(feature = "server")]
+    pub fn write_to(&mut self, stream: &mut StreamBuffer) -> Result<(), EzError> {
+        while let Some(chunk) = self.next_chunk()? {
+            let binary = chunk.to_binary();
+            stream.push(&binary.len().to_le_bytes())?;
+            stream.push(&binary)?;
+        }
+        stream.flush()
+    }
+
+    /// Reads the whole result into memory. Only for results known to fit.
+    pub fn collect(mut self) -> Result<Option<ColumnTable>, EzError> {
+        let mut result: Option<ColumnTable> = None;
+        while let Some(chunk) = self.next_chunk()? {
+            match &mut result {
+                Some(table) => {
+                    for index in 0..chunk.len() {
+                        push_row(&mut table.columns, &chunk, index);
+                    }
+                },
+                None => result = Some(chunk),
+            }
+        }
+        Ok(result)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::testing_tools::create_fixed_table;
+    use crate::utilities::ksf;
+
+    use super::*;
+
+    #[test]
+    fn test_external_sort() {
+        let table = create_fixed_table(10_000);
+        let sort_column = ksf("texts");
+        let expected = sort_table_by_column(&table, &sort_column, true).unwrap();
+
+        let mut sorter = ExternalSorter::with_threshold(sort_column, true, table.byte_size() as u64 / 4);
+        let mut start = 0;
+        while start < table.len() {
+            let stop = std::cmp::min(start + 1500, table.len());
+            let indexes: Vec<usize> = (start..stop).collect();
+            sorter.push(gather_rows(&table, &indexes)).unwrap();
+            start = stop;
+        }
+        assert!(sorter.runs.len() > 1);
+        let run_paths = sorter.runs.clone();
+
+        let output = sorter.finish().unwrap();
+        assert!(matches!(output, SortedOutput::Merged(_)));
+        let merged = output.collect().unwrap().unwrap();
+        assert_eq!(merged.columns[&sort_column], expected.columns[&sort_column]);
+        assert_eq!(merged.len(), table.len());
+        assert!(run_paths.iter().all(|path| !path.exists()));
+
+        let mut small = ExternalSorter::new(sort_column, false);
+        small.push(table.clone()).unwrap();
+        assert!(matches!(small.finish().unwrap(), SortedOutput::InMemory(Some(_))));
+        assert!(sort_table_by_column(&table, &ksf("no_such_column"), false).is_err());
+    }
+}
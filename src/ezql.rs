@@ -1,8 +1,23 @@
 use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, fmt::Display, str::FromStr, sync::Arc};
 
-use crate::{db_structure::{remove_indices, table_from_inserts, ColumnTable, DbColumn, DbValue, Metadata, Value}, server_networking::Database, utilities::{i32_from_le_slice, ksf, mean_f32_slice, mean_i32_slice, median_f32_slice, median_i32_slice, mode_i32_slice, mode_string_slice, print_sep_list, stdev_f32_slice, stdev_i32_slice, sum_f32_slice, sum_i32_slice, u64_from_le_slice, usize_from_le_slice, ErrorTag, EzError, KeyString}};
+use crate::{db_structure::{check_nan_ingest, format_duration, humanize_duration, infer_schema, is_count_column, is_engine_column, is_row_id_column, reject_nan, row_id_from_value, table_from_inserts, Collation, ColumnTable, DbColumn, DbType, DbValue, HeaderItem, LongTexts, OnConflict, TableKey, TableSchema, Value}, database::Database, utilities::{checked_slice, get_current_time, i32_from_le_slice, i64_from_le_slice, ksf, max_f32_slice, max_i32_slice, max_i64_slice, mean_i64_slice, median_f32_slice, median_i32_slice, median_i64_slice, min_f32_slice, min_i32_slice, min_i64_slice, mode_i32_slice, mode_i64_slice, mode_string_slice, print_sep_list, stdev_f32_slice, stdev_i32_slice, stdev_i64_slice, sum_f32_slice, sum_i32_slice, sum_i64_slice, u64_from_le_slice, usize_from_le_slice, ErrorTag, EzError, KeyString}};
 
 use crate::PATH_SEP;
+use crate::alloc_stats::{self, AllocPhase};
+use crate::system_tables::{is_system_table, materialize_system_table};
+use crate::namespaces::check_quota;
+use crate::snapshot::{execute_snapshot_queries, is_read_only_batch, Snapshot};
+use crate::query_execution::{db_slice_from_column, DbSlice};
+use crate::help::help_table;
+use crate::explain::explain_query;
+use crate::cancellation::check_cancelled;
+use crate::thread_pool::scan_in_chunks;
+use crate::partitions::{partition_name, partition_names, write_to_partitions};
+use crate::utilities::{compile_regex, glob_matches, is_glob, Regex};
+use crate::schema_file::parse_schema;
+use crate::db_structure::parse_header_item;
+use crate::row_table::{RowTable, TableEngine};
+use crate::paths::validate_name;
 
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Default)]
@@ -30,6 +45,15 @@ pub enum StatOp {
     MEDIAN,
     MODE,
     STDEV,
+    MIN,
+    MAX,
+    /// The number of rows. The only statistic besides MODE that works on text.
+    COUNT,
+}
+
+impl StatOp {
+    /// Every statistic, in the order of their binary codes.
+    pub const ALL: [StatOp; 8] = [StatOp::SUM, StatOp::MEAN, StatOp::MEDIAN, StatOp::MODE, StatOp::STDEV, StatOp::MIN, StatOp::MAX, StatOp::COUNT];
 }
 
 impl Display for StatOp {
@@ -40,6 +64,9 @@ impl Display for StatOp {
             StatOp::MEDIAN => write!(f, "MEDIAN"),
             StatOp::MODE => write!(f, "MODE"),
             StatOp::STDEV => write!(f, "STDEV"),
+            StatOp::MIN => write!(f, "MIN"),
+            StatOp::MAX => write!(f, "MAX"),
+            StatOp::COUNT => write!(f, "COUNT"),
         }
     }
 }
@@ -56,6 +83,9 @@ pub fn statistics_to_binary(statistics: &[Statistic]) -> Vec<u8> {
                 StatOp::MEDIAN => stats.push(2),
                 StatOp::MODE => stats.push(3),
                 StatOp::STDEV => stats.push(4),
+                StatOp::MIN => stats.push(5),
+                StatOp::MAX => stats.push(6),
+                StatOp::COUNT => stats.push(7),
             }
         }
     }
@@ -78,6 +108,9 @@ pub fn append_statistics(binary: &mut Vec<u8>, statistics: &[Statistic]) -> u64
                 StatOp::MEDIAN => binary.push(2),
                 StatOp::MODE => binary.push(3),
                 StatOp::STDEV => binary.push(4),
+                StatOp::MIN => binary.push(5),
+                StatOp::MAX => binary.push(6),
+                StatOp::COUNT => binary.push(7),
             }
         }
     }
@@ -90,18 +123,21 @@ pub fn statistics_from_binary(binary: &[u8]) -> Result<Vec<Statistic>, EzError>
 
     let mut i = 0;
     while i < binary.len() {
-        let column = KeyString::try_from(&binary[i..i+64])?;
+        let column = KeyString::try_from(checked_slice(binary, i, 64, "Statistic")?)?;
         i += 64;
-        let len = binary[i];
+        let len = checked_slice(binary, i, 1, "Statistic")?[0];
         i += 1;
         let mut actions = BTreeSet::new();
-        for j in 0..len as usize {
-            let action = match binary[i+j] {
+        for op in checked_slice(binary, i, len as usize, "Statistic")? {
+            let action = match *op {
                 0 => StatOp::SUM,
                 1 => StatOp::MEAN,
                 2 => StatOp::MEDIAN,
                 3 => StatOp::MODE,
                 4 => StatOp::STDEV,
+                5 => StatOp::MIN,
+                6 => StatOp::MAX,
+                7 => StatOp::COUNT,
                 other => return Err(EzError{tag: ErrorTag::Query, text: format!("Unparseable stat op: '{}'", other)}),
             };
             actions.insert(action);
@@ -117,12 +153,102 @@ pub fn statistics_from_binary(binary: &[u8]) -> Result<Vec<Statistic>, EzError>
 }
 
 
+/// What a value has to pass to be returned by a KV scan. Checked on the server before any value is copied
+/// into the response. Every bound is inclusive and ValueFilter::new() lets everything through.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub struct ValueFilter {
+    /// Size of the body in bytes.
+    pub min_size: u64,
+    pub max_size: u64,
+    /// The body has to start with these bytes, for example the start of a text value. Empty matches every body.
+    pub body_prefix: Vec<u8>,
+    /// Seconds since the value was last written.
+    pub min_age_secs: u64,
+    pub max_age_secs: u64,
+}
+
+impl Default for ValueFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ValueFilter {
+    pub fn new() -> ValueFilter {
+        ValueFilter {
+            min_size: 0,
+            max_size: u64::MAX,
+            body_prefix: Vec::new(),
+            min_age_secs: 0,
+            max_age_secs: u64::MAX,
+        }
+    }
+
+    /// A value whose age is unknown counts as just written.
+    pub fn matches(&self, value: &Value, age_secs: Option<u64>) -> bool {
+        let size = value.body.len() as u64;
+        let age = age_secs.unwrap_or(0);
+        size >= self.min_size && size <= self.max_size
+            && age >= self.min_age_secs && age <= self.max_age_secs
+            && value.body.starts_with(&self.body_prefix)
+    }
+
+    /// [min_size: u64][max_size: u64][min_age_secs: u64][max_age_secs: u64][prefix length: u64][body prefix]
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut binary = Vec::with_capacity(40 + self.body_prefix.len());
+        binary.extend_from_slice(&self.min_size.to_le_bytes());
+        binary.extend_from_slice(&self.max_size.to_le_bytes());
+        binary.extend_from_slice(&self.min_age_secs.to_le_bytes());
+        binary.extend_from_slice(&self.max_age_secs.to_le_bytes());
+        binary.extend_from_slice(&self.body_prefix.len().to_le_bytes());
+        binary.extend_from_slice(&self.body_prefix);
+        binary
+    }
+
+    pub fn from_binary(binary: &[u8]) -> Result<ValueFilter, EzError> {
+        let too_short = || EzError{tag: ErrorTag::Deserialization, text: "Value filter is cut short".to_owned()};
+        if binary.len() < 40 {
+            return Err(too_short())
+        }
+        let len = usize_from_le_slice(&binary[32..40]);
+        if binary.len() - 40 < len {
+            return Err(too_short())
+        }
+        Ok(ValueFilter {
+            min_size: u64_from_le_slice(&binary[0..8]),
+            max_size: u64_from_le_slice(&binary[8..16]),
+            min_age_secs: u64_from_le_slice(&binary[16..24]),
+            max_age_secs: u64_from_le_slice(&binary[24..32]),
+            body_prefix: binary[40..40+len].to_vec(),
+        })
+    }
+
+    pub fn binary_len(&self) -> usize {
+        40 + self.body_prefix.len()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum KvQuery {
     Create(KeyString, Vec<u8>),
     Read(KeyString),
     Update(KeyString, Vec<u8>),
     Delete(KeyString),
+    /// Read a previous version of a value. Version 0 is the current value.
+    ReadVersion(KeyString, u64),
+    /// Make a previous version the current value again.
+    Rollback(KeyString, u64),
+    /// Read several values at once. Gives one result per key, in order.
+    ReadMany(Vec<KeyString>),
+    /// Create or replace several values at once. Gives one result per pair, in order.
+    WriteMany(Vec<(KeyString, Vec<u8>)>),
+    /// Create a value that expires after the given number of seconds.
+    CreateWithTtl(KeyString, Vec<u8>, u64),
+    /// Replace a value and make it expire after the given number of seconds. A plain Update keeps the TTL it had.
+    UpdateWithTtl(KeyString, Vec<u8>, u64),
+    /// Read every value whose key starts with the prefix and that passes the filter. Gives one result per
+    /// matching value, in key order, so a scan is best sent last or alone in a batch.
+    Scan(KeyString, ValueFilter),
 }
 
 impl Display for KvQuery {
@@ -132,11 +258,35 @@ impl Display for KvQuery {
             KvQuery::Read(key_string) => write!(f, "Read: '{}'", key_string),
             KvQuery::Update(key_string, vec) => write!(f, "Update: '{}':\n{:x?}", key_string, vec),
             KvQuery::Delete(key_string) => write!(f, "Delete: '{}'", key_string),
+            KvQuery::ReadVersion(key_string, version) => write!(f, "ReadVersion: '{}' version: {}", key_string, version),
+            KvQuery::Rollback(key_string, version) => write!(f, "Rollback: '{}' version: {}", key_string, version),
+            KvQuery::ReadMany(keys) => write!(f, "ReadMany: {:?}", keys.iter().map(|key| key.as_str()).collect::<Vec<_>>()),
+            KvQuery::WriteMany(pairs) => write!(f, "WriteMany: {:?}", pairs.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>()),
+            KvQuery::CreateWithTtl(key_string, vec, ttl) => write!(f, "Create: '{}' ttl: {}:\n{:x?}", key_string, ttl, vec),
+            KvQuery::UpdateWithTtl(key_string, vec, ttl) => write!(f, "Update: '{}' ttl: {}:\n{:x?}", key_string, ttl, vec),
+            KvQuery::Scan(prefix, filter) => write!(f, "Scan: '{}' {:?}", prefix, filter),
         }
     }
 }
 
 impl KvQuery {
+    /// The name the query is counted under in the metrics.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            KvQuery::Create(..) => "KV_CREATE",
+            KvQuery::Read(..) => "KV_READ",
+            KvQuery::Update(..) => "KV_UPDATE",
+            KvQuery::Delete(..) => "KV_DELETE",
+            KvQuery::ReadVersion(..) => "KV_READ_VERSION",
+            KvQuery::Rollback(..) => "KV_ROLLBACK",
+            KvQuery::ReadMany(..) => "KV_READ_MANY",
+            KvQuery::WriteMany(..) => "KV_WRITE_MANY",
+            KvQuery::CreateWithTtl(..) => "KV_CREATE",
+            KvQuery::UpdateWithTtl(..) => "KV_UPDATE",
+            KvQuery::Scan(..) => "KV_SCAN",
+        }
+    }
+
     pub fn to_binary(&self) -> Vec<u8> {
         let mut binary = Vec::new();
         match self {
@@ -160,6 +310,50 @@ impl KvQuery {
                 binary.extend_from_slice(ksf("DELETE").raw());
                 binary.extend_from_slice(key_string.raw());
             },
+            KvQuery::ReadVersion(key_string, version) => {
+                binary.extend_from_slice(ksf("READ_VERSION").raw());
+                binary.extend_from_slice(key_string.raw());
+                binary.extend_from_slice(&version.to_le_bytes());
+            },
+            KvQuery::Rollback(key_string, version) => {
+                binary.extend_from_slice(ksf("ROLLBACK").raw());
+                binary.extend_from_slice(key_string.raw());
+                binary.extend_from_slice(&version.to_le_bytes());
+            },
+            // The key field is left empty. The keys follow the count.
+            KvQuery::ReadMany(keys) => {
+                binary.extend_from_slice(ksf("READ_MANY").raw());
+                binary.extend_from_slice(KeyString::new().raw());
+                binary.extend_from_slice(&keys.len().to_le_bytes());
+                for key in keys {
+                    binary.extend_from_slice(key.raw());
+                }
+            },
+            KvQuery::WriteMany(pairs) => {
+                binary.extend_from_slice(ksf("WRITE_MANY").raw());
+                binary.extend_from_slice(KeyString::new().raw());
+                binary.extend_from_slice(&pairs.len().to_le_bytes());
+                for (key, vec) in pairs {
+                    binary.extend_from_slice(key.raw());
+                    binary.extend_from_slice(&vec.len().to_le_bytes());
+                    binary.extend_from_slice(vec);
+                }
+            },
+            // Like CREATE and UPDATE with the TTL in seconds before the length
+            KvQuery::CreateWithTtl(key_string, vec, ttl) | KvQuery::UpdateWithTtl(key_string, vec, ttl) => {
+                let kind = if matches!(self, KvQuery::CreateWithTtl(..)) {"CREATE_TTL"} else {"UPDATE_TTL"};
+                binary.extend_from_slice(ksf(kind).raw());
+                binary.extend_from_slice(key_string.raw());
+                binary.extend_from_slice(&ttl.to_le_bytes());
+                binary.extend_from_slice(&vec.len().to_le_bytes());
+                binary.extend_from_slice(vec);
+            },
+            // The prefix goes in the key field
+            KvQuery::Scan(prefix, filter) => {
+                binary.extend_from_slice(ksf("SCAN").raw());
+                binary.extend_from_slice(prefix.raw());
+                binary.extend_from_slice(&filter.to_binary());
+            },
         };
 
         binary
@@ -174,23 +368,82 @@ impl KvQuery {
         let key = KeyString::try_from(&binary[64..128])?;
         match kind.as_str() {
             "CREATE" => {
-                let len = usize_from_le_slice(&binary[128..136]);
-                let mut value = Vec::with_capacity(len);
-                value.extend_from_slice(&binary[136..136+len]);
+                let len = usize_from_le_slice(checked_slice(binary, 128, 8, "KV query")?);
+                let value = checked_slice(binary, 136, len, "KV query")?.to_vec();
                 Ok(KvQuery::Create(key, value))
             }
             "READ" => {
                 Ok(KvQuery::Read(key))
             }
             "UPDATE" => {
-                let len = usize_from_le_slice(&binary[128..136]);
-                let mut value = Vec::with_capacity(len);
-                value.extend_from_slice(&binary[136..136+len]);
+                let len = usize_from_le_slice(checked_slice(binary, 128, 8, "KV query")?);
+                let value = checked_slice(binary, 136, len, "KV query")?.to_vec();
                 Ok(KvQuery::Update(key, value))
             }
             "DELETE" => {
                 Ok(KvQuery::Delete(key))
             }
+            "READ_VERSION" | "ROLLBACK" => {
+                if binary.len() < 136 {
+                    return Err(EzError{tag: ErrorTag::Deserialization, text: format!("{} query needs a version number", kind)})
+                }
+                let version = u64_from_le_slice(&binary[128..136]);
+                match kind.as_str() {
+                    "READ_VERSION" => Ok(KvQuery::ReadVersion(key, version)),
+                    _ => Ok(KvQuery::Rollback(key, version)),
+                }
+            }
+            "READ_MANY" | "WRITE_MANY" => {
+                let too_short = || EzError{tag: ErrorTag::Deserialization, text: format!("{} query is cut short", kind)};
+                if binary.len() < 136 {
+                    return Err(too_short())
+                }
+                let count = usize_from_le_slice(&binary[128..136]);
+                let mut counter = 136;
+                if kind.as_str() == "READ_MANY" {
+                    let mut keys = Vec::new();
+                    for _ in 0..count {
+                        if binary.len() < counter + 64 {
+                            return Err(too_short())
+                        }
+                        keys.push(KeyString::try_from(&binary[counter..counter+64])?);
+                        counter += 64;
+                    }
+                    Ok(KvQuery::ReadMany(keys))
+                } else {
+                    let mut pairs = Vec::new();
+                    for _ in 0..count {
+                        if binary.len() < counter + 72 {
+                            return Err(too_short())
+                        }
+                        let key = KeyString::try_from(&binary[counter..counter+64])?;
+                        let len = usize_from_le_slice(&binary[counter+64..counter+72]);
+                        counter += 72;
+                        if binary.len() - counter < len {
+                            return Err(too_short())
+                        }
+                        pairs.push((key, binary[counter..counter+len].to_vec()));
+                        counter += len;
+                    }
+                    Ok(KvQuery::WriteMany(pairs))
+                }
+            }
+            "CREATE_TTL" | "UPDATE_TTL" => {
+                if binary.len() < 144 {
+                    return Err(EzError{tag: ErrorTag::Deserialization, text: format!("{} query is cut short", kind)})
+                }
+                let ttl = u64_from_le_slice(&binary[128..136]);
+                let len = usize_from_le_slice(&binary[136..144]);
+                if binary.len() - 144 < len {
+                    return Err(EzError{tag: ErrorTag::Deserialization, text: format!("{} query is cut short", kind)})
+                }
+                let value = binary[144..144+len].to_vec();
+                match kind.as_str() {
+                    "CREATE_TTL" => Ok(KvQuery::CreateWithTtl(key, value, ttl)),
+                    _ => Ok(KvQuery::UpdateWithTtl(key, value, ttl)),
+                }
+            }
+            "SCAN" => Ok(KvQuery::Scan(key, ValueFilter::from_binary(&binary[128..])?)),
             other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unsupported KvQuery type '{}'", other)})
         }
     }
@@ -209,6 +462,13 @@ pub fn parse_kv_queries_from_binary(binary: &[u8]) -> Result<Vec<KvQuery>, EzErr
             KvQuery::Read(_) => counter += 128,
             KvQuery::Update(_, vec) => counter += 128 + 8 + vec.len(),
             KvQuery::Delete(_) => counter += 128,
+            KvQuery::ReadVersion(_, _) => counter += 136,
+            KvQuery::Rollback(_, _) => counter += 136,
+            KvQuery::ReadMany(keys) => counter += 136 + 64 * keys.len(),
+            KvQuery::WriteMany(pairs) => counter += 136 + pairs.iter().map(|(_, vec)| 72 + vec.len()).sum::<usize>(),
+            KvQuery::CreateWithTtl(_, vec, _) => counter += 144 + vec.len(),
+            KvQuery::UpdateWithTtl(_, vec, _) => counter += 144 + vec.len(),
+            KvQuery::Scan(_, filter) => counter += 128 + filter.binary_len(),
         };
         queries.push(query);
     }
@@ -229,17 +489,158 @@ pub fn parse_kv_queries_from_binary(binary: &[u8]) -> Result<Vec<KvQuery>, EzErr
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 #[allow(non_camel_case_types)]
 pub enum Query {
-    CREATE{table: ColumnTable},
+    /// The engine decides how the table stores its rows. See row_table.rs.
+    CREATE{table: ColumnTable, engine: TableEngine},
+    /// Creates every table of a schema (see schema_file.rs) that does not exist yet. Existing tables are skipped.
+    CREATE_FROM_SCHEMA{tables: Vec<ColumnTable>},
     DROP{table_name: KeyString},
-    SELECT{table_name: KeyString, primary_keys: RangeOrListOrAll, columns: Vec<KeyString>, conditions: Vec<OpOrCond>},
+    /// With distinct set, rows that repeat an earlier row across every selected column are left out.
+    /// With a limit, only the first rows in primary key order are returned and the scan stops once it has them.
+    SELECT{table_name: KeyString, primary_keys: RangeOrListOrAll, columns: Vec<KeyString>, conditions: Vec<OpOrCond>, distinct: bool, limit: Option<usize>},
     LEFT_JOIN{left_table_name: KeyString, right_table_name: KeyString, match_columns: (KeyString, KeyString), primary_keys: RangeOrListOrAll},
     INNER_JOIN,
     RIGHT_JOIN,
     FULL_JOIN,
-    UPDATE{table_name: KeyString, primary_keys: RangeOrListOrAll, conditions: Vec<OpOrCond>, updates: Vec<Update>},
-    INSERT{table_name: KeyString, inserts: ColumnTable},
+    /// With a version, every row the update matches must be at that version or nothing is written and it fails
+    /// with ErrorTag::Conflict. Only tables with row versions take one. See ColumnTable::add_row_versions().
+    UPDATE{table_name: KeyString, primary_keys: RangeOrListOrAll, conditions: Vec<OpOrCond>, updates: Vec<Update>, version: Option<i64>},
+    /// on_conflict decides what happens to rows whose primary key is already in the table. See OnConflict.
+    INSERT{table_name: KeyString, inserts: ColumnTable, on_conflict: OnConflict},
     DELETE{primary_keys: RangeOrListOrAll, table_name: KeyString, conditions: Vec<OpOrCond>},
     SUMMARY{table_name: KeyString, columns: Vec<Statistic>},
+    /// The same SUMMARY of several tables, run in parallel. Names with * or ? are globs, so sales.* is the
+    /// whole sales namespace. Answered with the summaries stacked in table name order with a Table column.
+    MULTI_SUMMARY{tables: Vec<KeyString>, columns: Vec<Statistic>},
+    DEDUPLICATE{table_name: KeyString},
+    /// Proposes a header for the sample CSV rows. Nothing is created.
+    INFER_SCHEMA{table_name: KeyString, sample: String},
+    /// Returns the TableSchema of the table as a table with one row per column.
+    DESCRIBE{table_name: KeyString},
+    /// Keeps the query on the server for this connection. Answered with its handle. See prepared.rs.
+    PREPARE{query: Box<Query>},
+    /// Runs a prepared query with the parameters filled in.
+    EXECUTE{handle: u64, params: Vec<DbValue>},
+    /// Stores the result of a SELECT or SUMMARY on the server instead of sending it back.
+    /// Answered with the target and the number of rows stored.
+    INTO{query: Box<Query>, target: IntoTarget},
+    /// Describes how the query would run instead of running it. Answered with one row per step. See explain.rs.
+    EXPLAIN{query: Box<Query>},
+    /// Lists what this server supports. Answered with the table from help::help_table().
+    HELP,
+    /// Changes the columns of a stored table. The whole table is rewritten on the next flush.
+    ALTER_TABLE{table_name: KeyString, alteration: Alteration},
+}
+
+/// A change to the columns of a stored table. See ALTER_TABLE.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub enum Alteration {
+    /// Adds a column with every existing row set to the default.
+    AddColumn{column: HeaderItem, default: DbValue},
+    DropColumn{column: KeyString},
+    RenameColumn{from: KeyString, to: KeyString},
+    /// Casts every cell of the column to the new type. See cast_column().
+    ChangeType{column: KeyString, kind: DbType},
+}
+
+impl Alteration {
+    /// Applies the alteration to the table. Nothing changes if it fails.
+    pub fn apply(&self, table: &mut ColumnTable) -> Result<(), EzError> {
+        match self {
+            Alteration::AddColumn { column, default } => table.add_column_with_default(column.clone(), default),
+            Alteration::DropColumn { column } => table.drop_column(column),
+            Alteration::RenameColumn { from, to } => table.rename_column(from, *to),
+            Alteration::ChangeType { column, kind } => table.change_column_type(column, *kind),
+        }
+    }
+
+    /// [kind: 64][fields]. An added column is [kind][default: 72][header item as EZ CSV text].
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut binary = Vec::new();
+        match self {
+            Alteration::AddColumn { column, default } => {
+                binary.extend_from_slice(ksf("ADD_COLUMN").raw());
+                binary.extend_from_slice(&default.to_binary());
+                binary.extend_from_slice(column.to_string().as_bytes());
+            },
+            Alteration::DropColumn { column } => {
+                binary.extend_from_slice(ksf("DROP_COLUMN").raw());
+                binary.extend_from_slice(column.raw());
+            },
+            Alteration::RenameColumn { from, to } => {
+                binary.extend_from_slice(ksf("RENAME_COLUMN").raw());
+                binary.extend_from_slice(from.raw());
+                binary.extend_from_slice(to.raw());
+            },
+            Alteration::ChangeType { column, kind } => {
+                binary.extend_from_slice(ksf("CHANGE_TYPE").raw());
+                binary.extend_from_slice(column.raw());
+                binary.extend_from_slice(ksf(kind.name()).raw());
+            },
+        }
+        binary
+    }
+
+    pub fn from_binary(binary: &[u8]) -> Result<Alteration, EzError> {
+        let short = || EzError{tag: ErrorTag::Deserialization, text: format!("{} bytes is too short for an alteration", binary.len())};
+        let keystring_at = |i: usize| binary.get(i..i+64).ok_or_else(short).and_then(KeyString::try_from);
+        match keystring_at(0)?.as_str() {
+            "ADD_COLUMN" => {
+                let default = DbValue::from_binary(binary.get(64..136).ok_or_else(short)?)?;
+                let column = parse_header_item(std::str::from_utf8(&binary[136..])?)?;
+                Ok(Alteration::AddColumn { column, default })
+            },
+            "DROP_COLUMN" => Ok(Alteration::DropColumn { column: keystring_at(64)? }),
+            "RENAME_COLUMN" => Ok(Alteration::RenameColumn { from: keystring_at(64)?, to: keystring_at(128)? }),
+            "CHANGE_TYPE" => Ok(Alteration::ChangeType { column: keystring_at(64)?, kind: DbType::from_name(keystring_at(128)?.as_str())? }),
+            other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unknown alteration: '{}'", other)}),
+        }
+    }
+}
+
+impl Display for Alteration {
+    /// As the arguments of ALTER_TABLE in EZQL.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Alteration::AddColumn { column, default } => {
+                let default = match default {
+                    DbValue::Int(x) => x.to_string(),
+                    DbValue::Float(x) => format!("{:?}", x),
+                    DbValue::Text(x) => format!("\"{}\"", x),
+                    DbValue::Duration(x) => format_duration(*x),
+                };
+                write!(f, "add_column: \"{}\", default: {}", column, default)
+            },
+            Alteration::DropColumn { column } => write!(f, "drop_column: {}", column),
+            Alteration::RenameColumn { from, to } => write!(f, "rename_column: ({}, {})", from, to),
+            Alteration::ChangeType { column, kind } => write!(f, "change_type: ({}, {})", column, kind.name()),
+        }
+    }
+}
+
+/// Where an INTO query stores its result.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub enum IntoTarget {
+    /// A new table. It must not exist yet.
+    Table(KeyString),
+    /// A KV value holding the result in the EZ binary table format. Replaces the value if there is one.
+    Value(KeyString),
+}
+
+impl IntoTarget {
+    pub fn name(&self) -> KeyString {
+        match self {
+            IntoTarget::Table(name) | IntoTarget::Value(name) => *name,
+        }
+    }
+}
+
+impl Display for IntoTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntoTarget::Table(name) => write!(f, "into_table: {}", name),
+            IntoTarget::Value(key) => write!(f, "into_value: {}", key),
+        }
+    }
 }
 
 impl Display for Query {
@@ -248,12 +649,14 @@ impl Display for Query {
 
         let mut printer = String::new();
         match self {
-            Query::SELECT { table_name, primary_keys, columns, conditions } => {
-                printer.push_str(&format!("SELECT(table_name: {}, primary_keys: {}, columns: {}, conditions: ({}))",
+            Query::SELECT { table_name, primary_keys, columns, conditions, distinct, limit } => {
+                printer.push_str(&format!("SELECT(table_name: {}, primary_keys: {}, columns: {}, conditions: ({}){}{})",
                         table_name,
                         primary_keys,
                         print_sep_list(columns, ", "),
                         print_sep_list(conditions, " "),
+                        if *distinct { ", distinct: true" } else { "" },
+                        match limit { Some(limit) => format!(", limit: {}", limit), None => String::new() },
                 ));
 
             },
@@ -266,15 +669,16 @@ impl Display for Query {
                         match_columns.1,
                 ));
             },
-            Query::UPDATE{ table_name, primary_keys, conditions, updates } => {
-                printer.push_str(&format!("UPDATE(table_name: {}, primary_keys: {}, conditions: ({}), updates: ({}))",
+            Query::UPDATE{ table_name, primary_keys, conditions, updates, version } => {
+                printer.push_str(&format!("UPDATE(table_name: {}, primary_keys: {}, conditions: ({}), updates: ({}){})",
                         table_name,
                         primary_keys,
                         print_sep_list(conditions, " "),
                         print_sep_list(updates, ", "),
+                        match version { Some(version) => format!(", version: {}", version), None => String::new() },
                 ));
             },
-            Query::INSERT{ table_name, inserts } => {
+            Query::INSERT{ table_name, inserts, on_conflict } => {
 
                 let new_values = inserts.to_string();
                 let mut temp = String::from("");
@@ -285,10 +689,11 @@ impl Display for Query {
                 temp.pop();
                 
                 let value_columns = inserts.header.iter().map(|n| n.name).collect::<Vec<KeyString>>();
-                printer.push_str(&format!("INSERT(table_name: {}, value_columns: ({}), new_values: ({}))",
+                printer.push_str(&format!("INSERT(table_name: {}, value_columns: ({}), new_values: ({}){})",
                         table_name,
                         print_sep_list(&value_columns, ", "),
                         temp,
+                        match on_conflict { OnConflict::Skip => String::new(), other => format!(", on_conflict: {}", other.name()) },
                 ));
             },
             Query::DELETE { primary_keys, table_name, conditions } => {
@@ -309,8 +714,30 @@ impl Display for Query {
                     printer.push(')');
                 }
             },
-            Query::CREATE { table } => printer.push_str(&format!("CREATE(table_name: {}", table.name)),
+            Query::MULTI_SUMMARY { tables, columns } => {
+                let stats: Vec<String> = columns.iter()
+                    .map(|stat| format!("({}, {})", stat.column, stat.actions.iter().map(|action| action.to_string()).collect::<Vec<_>>().join(", ")))
+                    .collect();
+                printer.push_str(&format!("MULTI_SUMMARY(tables: ({}), columns: ({}))", print_sep_list(tables, ", "), stats.join(", ")));
+            },
+            Query::CREATE { table, engine } => printer.push_str(&format!("CREATE(table_name: {}, engine: {}", table.name, engine.name())),
+            Query::CREATE_FROM_SCHEMA { tables } => {
+                let names: Vec<KeyString> = tables.iter().map(|table| table.name).collect();
+                printer.push_str(&format!("CREATE_FROM_SCHEMA(tables: ({}))", print_sep_list(&names, ", ")));
+            },
             Query::DROP { table_name } => printer.push_str(&format!("DROP(table_name: {}", table_name)),
+            Query::DEDUPLICATE { table_name } => printer.push_str(&format!("DEDUPLICATE(table_name: {})", table_name)),
+            Query::INFER_SCHEMA { table_name, sample } => printer.push_str(&format!("INFER_SCHEMA(table_name: {}, sample_rows: {})", table_name, sample.lines().count().saturating_sub(1))),
+            Query::DESCRIBE { table_name } => printer.push_str(&format!("DESCRIBE(table_name: {})", table_name)),
+            Query::PREPARE { query } => printer.push_str(&format!("PREPARE(query: {})", query)),
+            Query::EXECUTE { handle, params } => printer.push_str(&format!("EXECUTE(handle: {}, params: ({}))", handle, print_sep_list(params, ", "))),
+            Query::INTO { query, target } => {
+                let inner = query.to_string();
+                printer.push_str(&format!("{}, {})", inner.strip_suffix(')').unwrap_or(&inner), target));
+            },
+            Query::EXPLAIN { query } => printer.push_str(&format!("EXPLAIN(query: {})", query)),
+            Query::HELP => printer.push_str("HELP()"),
+            Query::ALTER_TABLE { table_name, alteration } => printer.push_str(&format!("ALTER_TABLE(table_name: {}, {})", table_name, alteration)),
             Query::INNER_JOIN => todo!(),
             Query::RIGHT_JOIN => todo!(),
             Query::FULL_JOIN => todo!(),
@@ -337,6 +764,8 @@ impl Query {
             primary_keys: RangeOrListOrAll::All,
             columns: Vec::new(),
             conditions: Vec::new(),
+            distinct: false,
+            limit: None,
         }
     }
 
@@ -344,14 +773,27 @@ impl Query {
         // println!("calling: Query::blank()");
 
         match keyword {
-            "INSERT" => Ok(Query::INSERT{ table_name: KeyString::new(), inserts: ColumnTable::blank(&BTreeSet::new(), KeyString::new(), "blank") }),
-            "SELECT" => Ok(Query::SELECT{ table_name: KeyString::new(), primary_keys: RangeOrListOrAll::All, columns: Vec::new(), conditions: Vec::new()  }),
-            "UPDATE" => Ok(Query::UPDATE{ table_name: KeyString::new(), primary_keys: RangeOrListOrAll::All, conditions: Vec::new(), updates: Vec::new() }),
+            "CREATE" => Ok(Query::CREATE{ table: ColumnTable::blank(&BTreeSet::new(), KeyString::new(), "blank"), engine: TableEngine::Column }),
+            "CREATE_FROM_SCHEMA" => Ok(Query::CREATE_FROM_SCHEMA{ tables: Vec::new() }),
+            "DROP" => Ok(Query::DROP{ table_name: KeyString::new() }),
+            "INSERT" => Ok(Query::INSERT{ table_name: KeyString::new(), inserts: ColumnTable::blank(&BTreeSet::new(), KeyString::new(), "blank"), on_conflict: OnConflict::Skip }),
+            "SELECT" => Ok(Query::SELECT{ table_name: KeyString::new(), primary_keys: RangeOrListOrAll::All, columns: Vec::new(), conditions: Vec::new(), distinct: false, limit: None }),
+            "UPDATE" => Ok(Query::UPDATE{ table_name: KeyString::new(), primary_keys: RangeOrListOrAll::All, conditions: Vec::new(), updates: Vec::new(), version: None }),
             "DELETE" => Ok(Query::DELETE{ table_name: KeyString::new(), primary_keys: RangeOrListOrAll::All, conditions: Vec::new() }),
             "LEFT_JOIN" => Ok(Query::LEFT_JOIN{ left_table_name: KeyString::new(), right_table_name: KeyString::new(), match_columns: (KeyString::new(), KeyString::new()), primary_keys: RangeOrListOrAll::All }),
             "FULL_JOIN" => Ok(Query::FULL_JOIN),
             "INNER_JOIN" => Ok(Query::INNER_JOIN),
             "SUMMARY" => Ok(Query::SUMMARY{ table_name: KeyString::new(), columns: Vec::new() }),
+            "MULTI_SUMMARY" => Ok(Query::MULTI_SUMMARY{ tables: Vec::new(), columns: Vec::new() }),
+            "DEDUPLICATE" => Ok(Query::DEDUPLICATE{ table_name: KeyString::new() }),
+            "INFER_SCHEMA" => Ok(Query::INFER_SCHEMA{ table_name: KeyString::new(), sample: String::new() }),
+            "DESCRIBE" => Ok(Query::DESCRIBE{ table_name: KeyString::new() }),
+            "PREPARE" => Ok(Query::PREPARE{ query: Box::new(Query::new()) }),
+            "EXECUTE" => Ok(Query::EXECUTE{ handle: 0, params: Vec::new() }),
+            "INTO" => Ok(Query::INTO{ query: Box::new(Query::new()), target: IntoTarget::Table(KeyString::new()) }),
+            "EXPLAIN" => Ok(Query::EXPLAIN{ query: Box::new(Query::new()) }),
+            "HELP" => Ok(Query::HELP),
+            "ALTER_TABLE" => Ok(Query::ALTER_TABLE{ table_name: KeyString::new(), alteration: Alteration::DropColumn{ column: KeyString::new() } }),
             _ => return Err(EzError{tag: ErrorTag::Query, text: format!("Query type: '{}' is not supported", keyword)}),
         }
     }
@@ -360,9 +802,9 @@ impl Query {
         // println!("calling: Query::get_primary_keys_ref()");
 
         match self {
-            Query::SELECT { table_name: _, primary_keys, columns: _, conditions: _ } => Some(primary_keys),
+            Query::SELECT { table_name: _, primary_keys, columns: _, conditions: _, distinct: _, limit: _ } => Some(primary_keys),
             Query::LEFT_JOIN { left_table_name: _, right_table_name: _, match_columns: _, primary_keys } => Some(primary_keys),
-            Query::UPDATE { table_name: _, primary_keys, conditions: _, updates: _ } => Some(primary_keys),
+            Query::UPDATE { table_name: _, primary_keys, conditions: _, updates: _, version: _ } => Some(primary_keys),
             Query::DELETE { primary_keys, table_name: _, conditions: _ } => Some(primary_keys),
             _ => None
         }
@@ -372,17 +814,57 @@ impl Query {
         // println!("calling: Query::get_table_name()");
 
         match self {
-            Query::SELECT { table_name, primary_keys: _, columns: _, conditions: _ } => *table_name,
+            Query::SELECT { table_name, primary_keys: _, columns: _, conditions: _, distinct: _, limit: _ } => *table_name,
             Query::LEFT_JOIN { left_table_name, right_table_name: _, match_columns: _, primary_keys: _ } => *left_table_name,
-            Query::UPDATE { table_name, primary_keys: _, conditions: _, updates: _ } => *table_name,
-            Query::INSERT { table_name, inserts: _ } => *table_name,
+            Query::UPDATE { table_name, primary_keys: _, conditions: _, updates: _, version: _ } => *table_name,
+            Query::INSERT { table_name, .. } => *table_name,
             Query::DELETE { primary_keys: _, table_name, conditions: _ } => *table_name,
             Query::SUMMARY { table_name, columns: _ } => *table_name,
+            Query::MULTI_SUMMARY { tables, columns: _ } => tables.first().copied().unwrap_or_default(),
             Query::INNER_JOIN => todo!(),
             Query::RIGHT_JOIN => todo!(),
             Query::FULL_JOIN => todo!(),
-            Query::CREATE { table } => table.name,
+            Query::CREATE { table, .. } => table.name,
+            Query::CREATE_FROM_SCHEMA { tables } => tables.first().map(|table| table.name).unwrap_or_default(),
             Query::DROP { table_name } => *table_name,
+            Query::DEDUPLICATE { table_name } => *table_name,
+            Query::INFER_SCHEMA { table_name, sample: _ } => *table_name,
+            Query::DESCRIBE { table_name } => *table_name,
+            Query::PREPARE { query } => query.get_table_name(),
+            // Only known once the query is bound to its prepared query
+            Query::EXECUTE { .. } => KeyString::new(),
+            Query::INTO { query, .. } => query.get_table_name(),
+            Query::EXPLAIN { query } => query.get_table_name(),
+            Query::HELP => KeyString::new(),
+            Query::ALTER_TABLE { table_name, .. } => *table_name,
+        }
+    }
+
+    /// The keyword the query is written with, as in SELECT(...).
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Query::CREATE { .. } => "CREATE",
+            Query::CREATE_FROM_SCHEMA { .. } => "CREATE_FROM_SCHEMA",
+            Query::DROP { .. } => "DROP",
+            Query::SELECT { .. } => "SELECT",
+            Query::LEFT_JOIN { .. } => "LEFT_JOIN",
+            Query::INNER_JOIN => "INNER_JOIN",
+            Query::RIGHT_JOIN => "RIGHT_JOIN",
+            Query::FULL_JOIN => "FULL_JOIN",
+            Query::UPDATE { .. } => "UPDATE",
+            Query::INSERT { .. } => "INSERT",
+            Query::DELETE { .. } => "DELETE",
+            Query::SUMMARY { .. } => "SUMMARY",
+            Query::MULTI_SUMMARY { .. } => "MULTI_SUMMARY",
+            Query::DEDUPLICATE { .. } => "DEDUPLICATE",
+            Query::INFER_SCHEMA { .. } => "INFER_SCHEMA",
+            Query::DESCRIBE { .. } => "DESCRIBE",
+            Query::PREPARE { .. } => "PREPARE",
+            Query::EXECUTE { .. } => "EXECUTE",
+            Query::INTO { .. } => "INTO",
+            Query::EXPLAIN { .. } => "EXPLAIN",
+            Query::HELP => "HELP",
+            Query::ALTER_TABLE { .. } => "ALTER_TABLE",
         }
     }
 
@@ -391,7 +873,7 @@ impl Query {
         let mut binary = Vec::with_capacity(1024);
         let mut handles = [0u8;32];
         match self {
-            Query::SELECT { table_name, primary_keys, columns, conditions } => {
+            Query::SELECT { table_name, primary_keys, columns, conditions, distinct, limit } => {
                 let binary_primary_keys = primary_keys.to_binary();
                 let binary_columns = columns.iter().map(|n| n.raw().to_vec()).flatten().collect::<Vec<u8>>();
                 let mut binary_conditions = Vec::new();
@@ -408,6 +890,15 @@ impl Query {
                 binary.extend_from_slice(&binary_primary_keys);
                 binary.extend_from_slice(&binary_columns);
                 binary.extend_from_slice(&binary_conditions);
+                // Trailing flags so queries from older clients, which end after the conditions, still decode.
+                // Bit 0 is distinct and bit 1 means the limit follows as a u64
+                let flags = *distinct as u8 | (limit.is_some() as u8) << 1;
+                if flags != 0 {
+                    binary.push(flags);
+                }
+                if let Some(limit) = limit {
+                    binary.extend_from_slice(&(*limit as u64).to_le_bytes());
+                }
                 let len = &binary.len().to_le_bytes();
                 binary[24..32].copy_from_slice(len);
             },
@@ -428,7 +919,7 @@ impl Query {
             Query::INNER_JOIN => todo!(),
             Query::RIGHT_JOIN => todo!(),
             Query::FULL_JOIN => todo!(),
-            Query::UPDATE { table_name, primary_keys, conditions, updates } => {
+            Query::UPDATE { table_name, primary_keys, conditions, updates, version } => {
                 let binary_primary_keys = primary_keys.to_binary();
                 let binary_updates = updates_to_binary(updates);
                 let binary_conditions = conditions.iter().map(|n| n.to_binary()).flatten().collect::<Vec<u8>>();
@@ -441,12 +932,17 @@ impl Query {
                 binary.extend_from_slice(&binary_primary_keys);
                 binary.extend_from_slice(&binary_conditions);
                 binary.extend_from_slice(&binary_updates);
+                // Trailing so updates from older clients, which end after the updates, still decode
+                if let Some(version) = version {
+                    binary.extend_from_slice(&version.to_le_bytes());
+                }
                 let len = &binary.len().to_le_bytes();
                 binary[24..32].copy_from_slice(len);
             },
-            Query::INSERT { table_name, inserts } => {
+            Query::INSERT { table_name, inserts, on_conflict } => {
                 let table = inserts.to_binary();
                 handles[0..8].copy_from_slice(&table.len().to_le_bytes());
+                handles[8..16].copy_from_slice(&on_conflict.to_handle().to_le_bytes());
                 binary.extend_from_slice(&handles);
                 binary.extend_from_slice(KeyString::from("INSERT").raw());
                 binary.extend_from_slice(table_name.raw());
@@ -480,10 +976,25 @@ impl Query {
                 binary[24..32].copy_from_slice(len);
                 
             },
-            Query::CREATE { table } => {
+            Query::MULTI_SUMMARY { tables, columns } => {
+                let stats = statistics_to_binary(columns);
+                handles[0..8].copy_from_slice(&stats.len().to_le_bytes());
+                handles[8..16].copy_from_slice(&tables.len().to_le_bytes());
+                binary.extend_from_slice(&handles);
+                binary.extend_from_slice(KeyString::from("MULTI_SUMMARY").raw());
+                binary.extend_from_slice(KeyString::new().raw());
+                for table in tables {
+                    binary.extend_from_slice(table.raw());
+                }
+                binary.extend_from_slice(&stats);
+                let len = &binary.len().to_le_bytes();
+                binary[24..32].copy_from_slice(len);
+            },
+            Query::CREATE { table, engine } => {
                 let table_name = table.name;
                 let table = table.to_binary();
                 handles[0..8].copy_from_slice(&table.len().to_le_bytes());
+                handles[8..16].copy_from_slice(&engine.to_handle().to_le_bytes());
                 binary.extend_from_slice(&handles);
                 binary.extend_from_slice(KeyString::from("CREATE").raw());
                 binary.extend_from_slice(table_name.raw());
@@ -491,6 +1002,20 @@ impl Query {
                 let len = &binary.len().to_le_bytes();
                 binary[24..32].copy_from_slice(len);
             },
+            // Each table is [length: u64][table binary]
+            Query::CREATE_FROM_SCHEMA { tables } => {
+                handles[0..8].copy_from_slice(&tables.len().to_le_bytes());
+                binary.extend_from_slice(&handles);
+                binary.extend_from_slice(KeyString::from("CREATE_FROM_SCHEMA").raw());
+                binary.extend_from_slice(KeyString::new().raw());
+                for table in tables {
+                    let table = table.to_binary();
+                    binary.extend_from_slice(&table.len().to_le_bytes());
+                    binary.extend_from_slice(&table);
+                }
+                let len = &binary.len().to_le_bytes();
+                binary[24..32].copy_from_slice(len);
+            },
             Query::DROP { table_name } => {
                 let table_name = table_name;
                 binary.extend_from_slice(&handles);
@@ -499,88 +1024,241 @@ impl Query {
                 let len = &binary.len().to_le_bytes();
                 binary[24..32].copy_from_slice(len);
             },
+            Query::DEDUPLICATE { table_name } => {
+                binary.extend_from_slice(&handles);
+                binary.extend_from_slice(KeyString::from("DEDUPLICATE").raw());
+                binary.extend_from_slice(table_name.raw());
+                let len = &binary.len().to_le_bytes();
+                binary[24..32].copy_from_slice(len);
+            },
+            Query::INFER_SCHEMA { table_name, sample } => {
+                handles[0..8].copy_from_slice(&sample.len().to_le_bytes());
+                binary.extend_from_slice(&handles);
+                binary.extend_from_slice(KeyString::from("INFER_SCHEMA").raw());
+                binary.extend_from_slice(table_name.raw());
+                binary.extend_from_slice(sample.as_bytes());
+                let len = &binary.len().to_le_bytes();
+                binary[24..32].copy_from_slice(len);
+            },
+            Query::DESCRIBE { table_name } => {
+                binary.extend_from_slice(&handles);
+                binary.extend_from_slice(KeyString::from("DESCRIBE").raw());
+                binary.extend_from_slice(table_name.raw());
+                let len = &binary.len().to_le_bytes();
+                binary[24..32].copy_from_slice(len);
+            },
+            Query::PREPARE { query } => {
+                let inner = query.to_binary();
+                handles[0..8].copy_from_slice(&inner.len().to_le_bytes());
+                binary.extend_from_slice(&handles);
+                binary.extend_from_slice(KeyString::from("PREPARE").raw());
+                binary.extend_from_slice(query.get_table_name().raw());
+                binary.extend_from_slice(&inner);
+                let len = &binary.len().to_le_bytes();
+                binary[24..32].copy_from_slice(len);
+            },
+            Query::EXECUTE { handle, params } => {
+                handles[0..8].copy_from_slice(&handle.to_le_bytes());
+                handles[8..16].copy_from_slice(&params.len().to_le_bytes());
+                binary.extend_from_slice(&handles);
+                binary.extend_from_slice(KeyString::from("EXECUTE").raw());
+                binary.extend_from_slice(KeyString::new().raw());
+                for param in params {
+                    binary.extend_from_slice(&param.to_binary());
+                }
+                let len = &binary.len().to_le_bytes();
+                binary[24..32].copy_from_slice(len);
+            },
+            // The target goes where the table name usually is. The kind of target is in the handles.
+            Query::INTO { query, target } => {
+                let inner = query.to_binary();
+                handles[0..8].copy_from_slice(&inner.len().to_le_bytes());
+                handles[8..16].copy_from_slice(&(matches!(target, IntoTarget::Value(_)) as u64).to_le_bytes());
+                binary.extend_from_slice(&handles);
+                binary.extend_from_slice(KeyString::from("INTO").raw());
+                binary.extend_from_slice(target.name().raw());
+                binary.extend_from_slice(&inner);
+                let len = &binary.len().to_le_bytes();
+                binary[24..32].copy_from_slice(len);
+            },
+            Query::EXPLAIN { query } => {
+                let inner = query.to_binary();
+                handles[0..8].copy_from_slice(&inner.len().to_le_bytes());
+                binary.extend_from_slice(&handles);
+                binary.extend_from_slice(KeyString::from("EXPLAIN").raw());
+                binary.extend_from_slice(query.get_table_name().raw());
+                binary.extend_from_slice(&inner);
+                let len = &binary.len().to_le_bytes();
+                binary[24..32].copy_from_slice(len);
+            },
+            Query::HELP => {
+                binary.extend_from_slice(&handles);
+                binary.extend_from_slice(KeyString::from("HELP").raw());
+                binary.extend_from_slice(KeyString::new().raw());
+                let len = &binary.len().to_le_bytes();
+                binary[24..32].copy_from_slice(len);
+            },
+            Query::ALTER_TABLE { table_name, alteration } => {
+                let alteration = alteration.to_binary();
+                handles[0..8].copy_from_slice(&alteration.len().to_le_bytes());
+                binary.extend_from_slice(&handles);
+                binary.extend_from_slice(KeyString::from("ALTER_TABLE").raw());
+                binary.extend_from_slice(table_name.raw());
+                binary.extend_from_slice(&alteration);
+                let len = &binary.len().to_le_bytes();
+                binary[24..32].copy_from_slice(len);
+            },
         }
         binary
     }
 
+    /// Every length in the handles is checked against the binary before it is used, so a malformed query
+    /// is a Deserialization error and never a panic.
     pub fn from_binary(binary: &[u8]) -> Result<Query, EzError> {
-        if binary.len() < 160 { // TODO: Check actual minimum
+        if binary.len() < 160 {
             return Err(EzError{tag: ErrorTag::Deserialization, text: "Binary is smaller than minimum valid binary".to_owned()})
         }
         let handles = &binary[0..32];
         let body = &binary[32..];
-        let query_type = KeyString::try_from(&body[0..64]).unwrap();
-        let table_name = KeyString::try_from(&body[64..128]).unwrap();
+        let query_type = KeyString::try_from(&body[0..64])?;
+        let table_name = KeyString::try_from(&body[64..128])?;
+        let handle = |index: usize| u64_from_le_slice(&handles[8*index..8*index + 8]) as usize;
+        // Each part is checked before the next one's offset is computed from it, so the offsets can't overflow
+        let part = |from: usize, len: usize| checked_slice(body, from, len, query_type.as_str());
+        let keystrings = |binary: &[u8]| -> Result<Vec<KeyString>, EzError> {
+            if !binary.len().is_multiple_of(64) {
+                return Err(EzError{tag: ErrorTag::Deserialization, text: format!("{} names {} bytes that are not a whole number of names", query_type, binary.len())})
+            }
+            binary.chunks_exact(64).map(KeyString::try_from).collect()
+        };
         match query_type.as_str() {
             "INSERT" => {
-                let inserts_len = u64_from_le_slice(&handles[0..8]) as usize;
-                let inserts = ColumnTable::from_binary(Some("inserts"), &body[128..128+inserts_len])?;
-                Ok( Query::INSERT { table_name, inserts })
+                let inserts = ColumnTable::from_binary(Some("inserts"), part(128, handle(0))?)?;
+                Ok( Query::INSERT { table_name, inserts, on_conflict: OnConflict::from_handle(handle(1))? })
             },
             "SELECT" => {
-                let pk_length = u64_from_le_slice(&handles[0..8]) as usize;
-                let cols_length = u64_from_le_slice(&handles[8..16]) as usize;
-                let conds_length = u64_from_le_slice(&handles[16..24]) as usize;
-                let primary_keys = RangeOrListOrAll::from_binary(&body[128..128+pk_length]).unwrap();
-                let mut columns = Vec::new();
-                for chunk in body[128+pk_length..128+pk_length+cols_length].chunks(64) {
-                    columns.push(KeyString::try_from(chunk).unwrap());
-                }
-                let conditions = conditions_from_binary(&body[128+pk_length+cols_length..128+pk_length+cols_length+conds_length]).unwrap();
+                let (pk_length, cols_length, conds_length) = (handle(0), handle(1), handle(2));
+                let primary_keys = RangeOrListOrAll::from_binary(part(128, pk_length)?)?;
+                let columns = keystrings(part(128+pk_length, cols_length)?)?;
+                let conditions = conditions_from_binary(part(128+pk_length+cols_length, conds_length)?)?;
+                let flags_offset = 128+pk_length+cols_length+conds_length;
+                let flags = body.get(flags_offset).copied().unwrap_or(0);
+                let distinct = flags & 1 != 0;
+                let limit = match flags & 2 != 0 {
+                    true => match body.get(flags_offset+1..flags_offset+9) {
+                        Some(limit) => Some(u64_from_le_slice(limit) as usize),
+                        None => return Err(EzError{tag: ErrorTag::Deserialization, text: "SELECT says it has a limit but the binary ends before it".to_owned()}),
+                    },
+                    false => None,
+                };
 
-                Ok(Query::SELECT { table_name, primary_keys, columns, conditions })
+                Ok(Query::SELECT { table_name, primary_keys, columns, conditions, distinct, limit })
 
             },
             "UPDATE" => {
-                let pk_length = u64_from_le_slice(&handles[0..8]) as usize;
-                let conds_length = u64_from_le_slice(&handles[8..16]) as usize;
-                let updates_len = u64_from_le_slice(&handles[16..24]) as usize;
-                let primary_keys = RangeOrListOrAll::from_binary(&body[128..128+pk_length])?;
-                let conditions = conditions_from_binary(&body[128+pk_length..128+pk_length+conds_length])?;
-                let updates = updates_from_binary(&body[128+pk_length+conds_length..128+pk_length+conds_length+updates_len])?;
-                Ok( Query::UPDATE { table_name, primary_keys, conditions, updates } )
+                let (pk_length, conds_length, updates_len) = (handle(0), handle(1), handle(2));
+                let primary_keys = RangeOrListOrAll::from_binary(part(128, pk_length)?)?;
+                let conditions = conditions_from_binary(part(128+pk_length, conds_length)?)?;
+                let updates = updates_from_binary(part(128+pk_length+conds_length, updates_len)?)?;
+                let updates_end = 128+pk_length+conds_length+updates_len;
+                let version = body.get(updates_end..updates_end+8).map(i64_from_le_slice);
+                Ok( Query::UPDATE { table_name, primary_keys, conditions, updates, version } )
             },
             "DELETE" => {
-                
-                let pk_length = u64_from_le_slice(&handles[0..8]) as usize;
-                let conds_length = u64_from_le_slice(&handles[8..16]) as usize;
-                let primary_keys = RangeOrListOrAll::from_binary(&body[128..128+pk_length]).unwrap();
-                let conditions = conditions_from_binary(&body[128+pk_length..128+pk_length+conds_length]).unwrap();
+                let (pk_length, conds_length) = (handle(0), handle(1));
+                let primary_keys = RangeOrListOrAll::from_binary(part(128, pk_length)?)?;
+                let conditions = conditions_from_binary(part(128+pk_length, conds_length)?)?;
 
                 Ok(Query::DELETE { table_name, primary_keys, conditions })
             },
             "LEFT_JOIN" => {
-                
-                let pk_len = u64_from_le_slice(&handles[0..8]) as usize;
-                let right_table_name = KeyString::try_from(&body[128..192])?;
-                let match1 = KeyString::try_from(&body[192..256])?;
-                let match2 = KeyString::try_from(&body[256..320])?;
-                let match_columns = (match1, match2);
-                let primary_keys = RangeOrListOrAll::from_binary(&body[320..320+pk_len])?;
-                
-                Ok( Query::LEFT_JOIN { left_table_name: table_name, right_table_name, match_columns, primary_keys } )
-            },
-            "FULL_JOIN" => {
-                todo!()
+                let names = keystrings(part(128, 192)?)?;
+                let primary_keys = RangeOrListOrAll::from_binary(part(320, handle(0))?)?;
+
+                Ok( Query::LEFT_JOIN { left_table_name: table_name, right_table_name: names[0], match_columns: (names[1], names[2]), primary_keys } )
             },
-            "INNER_JOIN" => {
-                todo!()
+            "FULL_JOIN" | "INNER_JOIN" | "RIGHT_JOIN" => {
+                Err(EzError{tag: ErrorTag::Unimplemented, text: format!("{} is not implemented yet", query_type)})
             },
             "SUMMARY" => {
-                let stat_len = u64_from_le_slice(&handles[0..8]) as usize;
-                let columns = statistics_from_binary(&body[128..128+stat_len])?;
+                let columns = statistics_from_binary(part(128, handle(0))?)?;
 
                 Ok( Query::SUMMARY { table_name, columns } )
 
             },
+            "MULTI_SUMMARY" => {
+                let (stat_len, table_count) = (handle(0), handle(1));
+                let tables_len = match table_count.checked_mul(64) {
+                    Some(len) => len,
+                    None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("MULTI_SUMMARY of {} tables does not fit in the {} bytes sent", table_count, body.len())}),
+                };
+                let tables = keystrings(part(128, tables_len)?)?;
+                let columns = statistics_from_binary(part(128+tables_len, stat_len)?)?;
+
+                Ok( Query::MULTI_SUMMARY { tables, columns } )
+            },
             "CREATE" => {
-                let table_len = u64_from_le_slice(&handles[0..8]) as usize;
-                let table = ColumnTable::from_binary(None, &body[128..128+table_len])?;
-                Ok( Query::CREATE { table })
+                let table = ColumnTable::from_binary(None, part(128, handle(0))?)?;
+                Ok( Query::CREATE { table, engine: TableEngine::from_handle(handle(1))? })
+            },
+            "CREATE_FROM_SCHEMA" => {
+                let table_count = handle(0);
+                let mut tables = Vec::new();
+                let mut i = 128;
+                for _ in 0..table_count {
+                    let table_len = u64_from_le_slice(part(i, 8)?) as usize;
+                    tables.push(ColumnTable::from_binary(None, part(i+8, table_len)?)?);
+                    i += 8 + table_len;
+                }
+                Ok( Query::CREATE_FROM_SCHEMA { tables })
             },
             "DROP" => {
                 Ok( Query::DROP { table_name })
-            }
+            },
+            "DEDUPLICATE" => {
+                Ok( Query::DEDUPLICATE { table_name })
+            },
+            "INFER_SCHEMA" => {
+                let sample = String::from_utf8(part(128, handle(0))?.to_vec())?;
+                Ok( Query::INFER_SCHEMA { table_name, sample })
+            },
+            "DESCRIBE" => {
+                Ok( Query::DESCRIBE { table_name })
+            },
+            "HELP" => Ok(Query::HELP),
+            "ALTER_TABLE" => {
+                let alteration = Alteration::from_binary(part(128, handle(0))?)?;
+                Ok( Query::ALTER_TABLE { table_name, alteration })
+            },
+            "PREPARE" => {
+                let query = Query::from_binary(part(128, handle(0))?)?;
+                Ok( Query::PREPARE { query: Box::new(query) })
+            },
+            "EXECUTE" => {
+                let handle_id = u64_from_le_slice(&handles[0..8]);
+                let param_count = handle(1);
+                let params_len = match param_count.checked_mul(72) {
+                    Some(len) => len,
+                    None => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("EXECUTE has {} parameters but only {} bytes were sent", param_count, body.len() - 128)}),
+                };
+                let params = part(128, params_len)?.chunks_exact(72).map(DbValue::from_binary).collect::<Result<Vec<_>, _>>()?;
+                Ok( Query::EXECUTE { handle: handle_id, params })
+            },
+            "INTO" => {
+                let query = Query::from_binary(part(128, handle(0))?)?;
+                if !matches!(query, Query::SELECT{..} | Query::SUMMARY{..}) {
+                    return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Only SELECT and SUMMARY can store their result with INTO. Got '{}'", query)})
+                }
+                let target = match handle(1) {
+                    0 => IntoTarget::Table(table_name),
+                    _ => IntoTarget::Value(table_name),
+                };
+                Ok( Query::INTO { query: Box::new(query), target })
+            },
+            "EXPLAIN" => {
+                let query = Query::from_binary(part(128, handle(0))?)?;
+                Ok( Query::EXPLAIN { query: Box::new(query) })
+            },
             _ => return Err(EzError{tag: ErrorTag::Query, text: format!("Query type '{}' is not supported", query_type)}),
         }
 
@@ -590,15 +1268,17 @@ impl Query {
         Query::SELECT {
             table_name: ksf(table_name),
             primary_keys: RangeOrListOrAll::All,
-            columns: Vec::new(),
+            columns: vec![ksf("*")],
             conditions: Vec::new(),
+            distinct: false,
+            limit: None,
         }
     }
 
     pub fn and_condition(mut self, attribute: impl Into<KeyString>, op: TestOp, value: impl Into<DbValue>) -> Query {
-        let condition = Condition{attribute: attribute.into(), op, value: value.into()};
+        let condition = Condition{attribute: attribute.into(), op, value: value.into(), other_column: None};
         match &mut self {
-            Query::SELECT { table_name, primary_keys, columns, conditions } => {
+            Query::SELECT { conditions, .. } => {
                 if conditions.is_empty() {
                     ()
                 } else {
@@ -607,7 +1287,7 @@ impl Query {
                 conditions.push(OpOrCond::Cond(condition));
 
             },
-            Query::UPDATE { table_name, primary_keys, conditions, updates } => {
+            Query::UPDATE { conditions, .. } => {
                 if conditions.is_empty() {
                     ()
                 } else {
@@ -630,9 +1310,9 @@ impl Query {
     }
 
     pub fn or_condition(mut self, attribute: impl Into<KeyString>, op: TestOp, value: impl Into<DbValue>) -> Query {
-        let condition = Condition{attribute: attribute.into(), op, value: value.into()};
+        let condition = Condition{attribute: attribute.into(), op, value: value.into(), other_column: None};
         match &mut self {
-            Query::SELECT { table_name, primary_keys, columns, conditions } => {
+            Query::SELECT { conditions, .. } => {
                 if conditions.is_empty() {
                     ()
                 } else {
@@ -641,7 +1321,7 @@ impl Query {
                 conditions.push(OpOrCond::Cond(condition));
 
             },
-            Query::UPDATE { table_name, primary_keys, conditions, updates } => {
+            Query::UPDATE { conditions, .. } => {
                 if conditions.is_empty() {
                     ()
                 } else {
@@ -662,18 +1342,53 @@ impl Query {
         
         self
     }
+
+    /// Like and_condition() but the new condition is negated.
+    pub fn and_not_condition(self, attribute: impl Into<KeyString>, op: TestOp, value: impl Into<DbValue>) -> Query {
+        self.and_condition(attribute, op, value).negate_last_condition()
+    }
+
+    /// Like or_condition() but the new condition is negated.
+    pub fn or_not_condition(self, attribute: impl Into<KeyString>, op: TestOp, value: impl Into<DbValue>) -> Query {
+        self.or_condition(attribute, op, value).negate_last_condition()
+    }
+
+    fn negate_last_condition(mut self) -> Query {
+        match &mut self {
+            Query::SELECT { conditions, .. } | Query::UPDATE { conditions, .. } | Query::DELETE { conditions, .. } if !conditions.is_empty() => {
+                conditions.insert(conditions.len() - 1, OpOrCond::Not);
+            },
+            _ => (),
+        };
+
+        self
+    }
+}
+
+/// The most queries a single batch may hold.
+pub const MAX_BATCH_QUERIES: usize = 1024;
+
+fn too_many_queries() -> EzError {
+    EzError{tag: ErrorTag::Query, text: format!("A batch can hold at most {} queries", MAX_BATCH_QUERIES)}
 }
 
 pub fn parse_queries_from_binary(binary: &[u8]) -> Result<Vec<Query>, EzError> {
+    let _phase = alloc_stats::enter(AllocPhase::Parse);
     if binary.len() < 160 {
         return Err(EzError{tag: ErrorTag::Query, text: "Binary is too short. Cannot be a valid query".to_owned()})
     }
     let mut queries = Vec::new();
     let mut counter = 0;
     while counter < binary.len() {
-        let len = u64_from_le_slice(&binary[counter+24..counter+32]) as usize;
-        let block = &binary[counter..counter + len];
-        let query = Query::from_binary(block)?;
+        if queries.len() == MAX_BATCH_QUERIES {
+            return Err(too_many_queries())
+        }
+        // The length of each query is the last of its handles and counts the handles themselves
+        let len = u64_from_le_slice(checked_slice(binary, counter + 24, 8, "Query batch")?) as usize;
+        if len < 32 {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Query {} of the batch claims to be {} bytes long", queries.len(), len)})
+        }
+        let query = Query::from_binary(checked_slice(binary, counter, len, "Query batch")?)?;
         queries.push(query);
         counter += len;
     }
@@ -690,6 +1405,92 @@ pub fn queries_to_binary(queries: &[Query]) -> Vec<u8> {
     binary
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AckStatus {
+    Done,
+    Failed,
+    /// The batch stopped at an earlier failure
+    NotRun,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueryAck {
+    pub status: AckStatus,
+    pub affected: u64,
+}
+
+/// The answer to a batch of write queries. Only a status and an affected row count per query are sent back
+/// so bulk writers don't pay for the rows they just sent. If a query failed its error comes last.
+/// [ACK: 64 bytes][number of queries: u64][write version: u64][status: u8, affected: u64 per query][EzError binary if a query failed]
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteAck {
+    pub statuses: Vec<QueryAck>,
+    /// The write version of the server once the batch was applied. Reads sent with it see the batch. See write_versions.rs
+    pub version: u64,
+    pub error: Option<EzError>,
+}
+
+impl WriteAck {
+    pub fn total_affected(&self) -> u64 {
+        self.statuses.iter().map(|ack| ack.affected).sum()
+    }
+
+    /// Turns a failed batch into the error of the query that failed
+    pub fn into_result(self) -> Result<WriteAck, EzError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self),
+        }
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut binary = Vec::with_capacity(80 + self.statuses.len() * 9);
+        binary.extend_from_slice(ksf("ACK").raw());
+        binary.extend_from_slice(&(self.statuses.len() as u64).to_le_bytes());
+        binary.extend_from_slice(&self.version.to_le_bytes());
+        for ack in &self.statuses {
+            binary.push(match ack.status {
+                AckStatus::Done => 0,
+                AckStatus::Failed => 1,
+                AckStatus::NotRun => 2,
+            });
+            binary.extend_from_slice(&ack.affected.to_le_bytes());
+        }
+        if let Some(e) = &self.error {
+            binary.extend_from_slice(&e.to_binary());
+        }
+        binary
+    }
+
+    pub fn from_binary(binary: &[u8]) -> Result<WriteAck, EzError> {
+        if binary.len() < 80 || KeyString::try_from(&binary[0..64])?.as_str() != "ACK" {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: "Response is not a write acknowledgment".to_owned()})
+        }
+        let count = u64_from_le_slice(&binary[64..72]) as usize;
+        let version = u64_from_le_slice(&binary[72..80]);
+        let end = match count.checked_mul(9).and_then(|x| x.checked_add(80)) {
+            Some(end) if end <= binary.len() => end,
+            _ => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Acknowledgment is too short for {} queries", count)}),
+        };
+        let mut statuses = Vec::with_capacity(count);
+        for chunk in binary[80..end].chunks_exact(9) {
+            let status = match chunk[0] {
+                0 => AckStatus::Done,
+                1 => AckStatus::Failed,
+                2 => AckStatus::NotRun,
+                other => return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unknown acknowledgment status: {}", other)}),
+            };
+            statuses.push(QueryAck{status, affected: u64_from_le_slice(&chunk[1..9])});
+        }
+        let error = if binary.len() > end {
+            Some(EzError::from_binary(&binary[end..])?)
+        } else {
+            None
+        };
+        Ok(WriteAck{statuses, version, error})
+    }
+}
+
 pub fn append_primary_keys(binary: &mut Vec<u8>, primary_keys: &RangeOrListOrAll) -> u64{
     let mut i = 0;
     match primary_keys {
@@ -709,6 +1510,11 @@ pub fn append_primary_keys(binary: &mut Vec<u8>, primary_keys: &RangeOrListOrAll
             }
 
         },
+        RangeOrListOrAll::Keys(keys) => {
+            let start = binary.len();
+            keys.write_binary(binary);
+            i = (binary.len() - start) as u64;
+        },
         RangeOrListOrAll::All => {
             binary.extend_from_slice(KeyString::from("ALL").raw());
             i = 64
@@ -722,13 +1528,18 @@ pub fn append_conditions(binary: &mut Vec<u8>, conditions: &Vec<OpOrCond>) -> u6
     for condition in conditions {
         match condition {
             OpOrCond::Cond(condition) => {
-                i += 144;
-                binary.extend_from_slice(&condition.to_binary());
+                let condition = condition.to_binary();
+                i += condition.len() as u64;
+                binary.extend_from_slice(&condition);
             },
             OpOrCond::Op(operator) => {
                 i+= 64;
                 binary.extend_from_slice(operator.to_keystring().raw());
             },
+            OpOrCond::Not | OpOrCond::Open | OpOrCond::Close => {
+                i += 64;
+                binary.extend_from_slice(&condition.to_binary());
+            },
         }
     }
 
@@ -736,26 +1547,28 @@ pub fn append_conditions(binary: &mut Vec<u8>, conditions: &Vec<OpOrCond>) -> u6
 
 }
 
+/// The size of an update in the binary of an UPDATE query.
+pub const UPDATE_BINARY_SIZE: usize = 208;
+
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub struct Update {
     pub attribute: KeyString,
     pub operator: UpdateOp,
     pub value: DbValue,
+    /// Computes the value of every row from other columns of the same row, as in price = cost * 1.2.
+    /// When set, value is not used. Every expression is computed before any column changes.
+    pub expression: Option<Expression>,
 }
 
 impl Display for Update {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // println!("calling: Update::fmt()");
 
-        let op = match self.operator {
-            UpdateOp::Assign => "=",
-            UpdateOp::PlusEquals => "+=",
-            UpdateOp::MinusEquals => "-=",
-            UpdateOp::TimesEquals => "*=",
-            UpdateOp::Append => "append",
-            UpdateOp::Prepend => "prepend",
-        };
-        write!(f, "({} {} {})", self.attribute.as_str(), op, self.value.to_string())
+        match &self.expression {
+            Some(Expression::Column(column)) => write!(f, "({} {} column({}))", self.attribute.as_str(), self.operator.symbol(), column),
+            Some(expression) => write!(f, "({} {} {})", self.attribute.as_str(), self.operator.symbol(), expression),
+            None => write!(f, "({} {} {})", self.attribute.as_str(), self.operator.symbol(), self.value),
+        }
     }
 }
 
@@ -772,9 +1585,10 @@ impl FromStr for Update {
         }
         if s.split_whitespace().count() == 3 {
             output = Update {
-                attribute: KeyString::from(t.next().unwrap()),
+                attribute: KeyString::from_input(t.next().unwrap())?,
                 operator: UpdateOp::from_str(t.next().unwrap())?,
-                value: DbValue::Text(KeyString::from(t.next().unwrap())),
+                value: DbValue::Text(KeyString::from_input(t.next().unwrap())?),
+                expression: None,
             };
         } else {
             let mut acc = Vec::new();
@@ -804,9 +1618,10 @@ impl FromStr for Update {
 
             if acc.len() == 3 {
                 output = Update {
-                    attribute: KeyString::from(acc[0].as_str()),
+                    attribute: KeyString::from_input(acc[0].as_str())?,
                     operator: UpdateOp::from_str(acc[1].as_str())?,
-                    value: DbValue::Text(KeyString::from(acc[2].as_str())),
+                    value: DbValue::Text(KeyString::from_input(acc[2].as_str())?),
+                    expression: None,
                 };
             } else {
                 return Err(EzError{tag: ErrorTag::Query, text: format!("Update: '{}' could not be parsed from string", ksf(s))})
@@ -826,25 +1641,36 @@ impl Update {
             attribute: KeyString::new(),
             operator: UpdateOp::Assign,
             value: DbValue::Text(KeyString::new()),
+            expression: None,
         }
     }
 
-    pub fn to_binary(&self) -> [u8;144] {
-        let mut binary = [0u8;144];
+    /// The expression is stored as text in the last 64 bytes, which are zero when there is none.
+    /// Expressions whose text doesn't fit are refused by validate_query().
+    pub fn to_binary(&self) -> [u8;UPDATE_BINARY_SIZE] {
+        let mut binary = [0u8;UPDATE_BINARY_SIZE];
         binary[0..64].copy_from_slice(self.attribute.raw());
         binary[64..72].copy_from_slice(&self.operator.to_binary());
         binary[72..144].copy_from_slice(&self.value.to_binary());
+        if let Some(expression) = &self.expression {
+            binary[144..208].copy_from_slice(KeyString::from(expression.to_string().as_str()).raw());
+        }
         binary
     }
 
     pub fn from_binary(binary: &[u8]) -> Result<Update, EzError> {
-        if binary.len() != 144 {
-            return Err(EzError { tag: ErrorTag::Deserialization, text: format!("Update binaries are exactly 144 bytes") })
+        if binary.len() != UPDATE_BINARY_SIZE {
+            return Err(EzError { tag: ErrorTag::Deserialization, text: format!("Update binaries are exactly {} bytes", UPDATE_BINARY_SIZE) })
         }
         let attribute = KeyString::try_from(&binary[0..64])?;
         let operator = UpdateOp::from_binary(&binary[64..72])?;
         let value = DbValue::from_binary(&binary[72..144])?;
-        Ok(Update { attribute, operator, value })
+        let text = KeyString::try_from(&binary[144..208])?;
+        let expression = match text.as_str() {
+            "" => None,
+            text => Some(Expression::parse(text)?.unwrap_or(Expression::Column(KeyString::from_input(text)?))),
+        };
+        Ok(Update { attribute, operator, value, expression })
     }
 }
 
@@ -862,7 +1688,7 @@ pub fn updates_to_binary(updates: &[Update]) -> Vec<u8> {
 pub fn updates_from_binary(binary: &[u8]) -> Result<Vec<Update>, EzError> {
     let mut updates = Vec::new();
 
-    for chunk in binary.chunks(144) {
+    for chunk in binary.chunks(UPDATE_BINARY_SIZE) {
 
         updates.push(Update::from_binary(&chunk)?);
 
@@ -880,9 +1706,33 @@ pub enum UpdateOp {
     TimesEquals,
     Append,
     Prepend,
+    ToLower,
+    ToUpper,
+    Trim,
 }
 
 impl UpdateOp {
+    /// Every update operator, in the order of their binary codes.
+    pub const ALL: [UpdateOp; 9] = [
+        UpdateOp::Assign, UpdateOp::PlusEquals, UpdateOp::MinusEquals, UpdateOp::TimesEquals,
+        UpdateOp::Append, UpdateOp::Prepend, UpdateOp::ToLower, UpdateOp::ToUpper, UpdateOp::Trim,
+    ];
+
+    /// How the operator is written in EZQL. from_str() accepts it.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            UpdateOp::Assign => "=",
+            UpdateOp::PlusEquals => "+=",
+            UpdateOp::MinusEquals => "-=",
+            UpdateOp::TimesEquals => "*=",
+            UpdateOp::Append => "append",
+            UpdateOp::Prepend => "prepend",
+            UpdateOp::ToLower => "to_lower",
+            UpdateOp::ToUpper => "to_upper",
+            UpdateOp::Trim => "trim",
+        }
+    }
+
     fn from_str(s: &str) -> Result<Self, EzError> {
         // println!("calling: UpdateOp::from_str()");
 
@@ -894,6 +1744,9 @@ impl UpdateOp {
             "append" => Ok(UpdateOp::Append),
             "assign" => Ok(UpdateOp::Assign),
             "prepend" => Ok(UpdateOp::Prepend),
+            "to_lower" => Ok(UpdateOp::ToLower),
+            "to_upper" => Ok(UpdateOp::ToUpper),
+            "trim" => Ok(UpdateOp::Trim),
             _ => Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is not a valid UpdateOp", s)}),
         }
     }
@@ -906,17 +1759,23 @@ impl UpdateOp {
             UpdateOp::TimesEquals => KeyString::from("TimesEquals"),
             UpdateOp::Append => KeyString::from("Append"),
             UpdateOp::Prepend => KeyString::from("Prepend"),
+            UpdateOp::ToLower => KeyString::from("ToLower"),
+            UpdateOp::ToUpper => KeyString::from("ToUpper"),
+            UpdateOp::Trim => KeyString::from("Trim"),
         }
     }
 
     pub fn to_binary(&self) -> [u8;8] {
         match self {
-            UpdateOp::Assign => (1 as u64).to_le_bytes(),
-            UpdateOp::PlusEquals => (2 as u64).to_le_bytes(),
-            UpdateOp::MinusEquals => (3 as u64).to_le_bytes(),
-            UpdateOp::TimesEquals => (4 as u64).to_le_bytes(),
-            UpdateOp::Append => (5 as u64).to_le_bytes(),
-            UpdateOp::Prepend => (6 as u64).to_le_bytes(),
+            UpdateOp::Assign => 1_u64.to_le_bytes(),
+            UpdateOp::PlusEquals => 2_u64.to_le_bytes(),
+            UpdateOp::MinusEquals => 3_u64.to_le_bytes(),
+            UpdateOp::TimesEquals => 4_u64.to_le_bytes(),
+            UpdateOp::Append => 5_u64.to_le_bytes(),
+            UpdateOp::Prepend => 6_u64.to_le_bytes(),
+            UpdateOp::ToLower => 7_u64.to_le_bytes(),
+            UpdateOp::ToUpper => 8_u64.to_le_bytes(),
+            UpdateOp::Trim => 9_u64.to_le_bytes(),
         }
     }
 
@@ -928,6 +1787,9 @@ impl UpdateOp {
             4 => Ok(UpdateOp::TimesEquals),
             5 => Ok(UpdateOp::Append),
             6 => Ok(UpdateOp::Prepend),
+            7 => Ok(UpdateOp::ToLower),
+            8 => Ok(UpdateOp::ToUpper),
+            9 => Ok(UpdateOp::Trim),
             other => return Err(EzError { tag: ErrorTag::Deserialization, text: format!("Unknown value: '{other}' encountered as UpdateOp") })
         }
     }
@@ -936,12 +1798,389 @@ impl UpdateOp {
 
 
 
+/// Functions that can be applied to text columns in the column list of a SELECT query.
+/// They are written like LOWER(name) in place of the column name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TextFunction {
+    Lower,
+    Upper,
+    Trim,
+}
+
+impl TextFunction {
+    pub const ALL: [TextFunction; 3] = [TextFunction::Lower, TextFunction::Upper, TextFunction::Trim];
+
+    /// How the function is written in a column list, as in LOWER(name).
+    pub fn name(&self) -> &'static str {
+        match self {
+            TextFunction::Lower => "LOWER",
+            TextFunction::Upper => "UPPER",
+            TextFunction::Trim => "TRIM",
+        }
+    }
+
+    pub fn apply(&self, column: &[KeyString]) -> Vec<KeyString> {
+        match self {
+            TextFunction::Lower => column.iter().map(|item| item.to_lowercase()).collect(),
+            TextFunction::Upper => column.iter().map(|item| item.to_uppercase()).collect(),
+            TextFunction::Trim => column.iter().map(|item| item.trim()).collect(),
+        }
+    }
+}
+
+/// The operators of arithmetic in a SELECT column list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl ArithOp {
+    pub const ALL: [ArithOp; 4] = [ArithOp::Add, ArithOp::Sub, ArithOp::Mul, ArithOp::Div];
+
+    pub fn from_symbol(symbol: &str) -> Option<ArithOp> {
+        match symbol {
+            "+" => Some(ArithOp::Add),
+            "-" => Some(ArithOp::Sub),
+            "*" => Some(ArithOp::Mul),
+            "/" => Some(ArithOp::Div),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            ArithOp::Add => "+",
+            ArithOp::Sub => "-",
+            ArithOp::Mul => "*",
+            ArithOp::Div => "/",
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            ArithOp::Add | ArithOp::Sub => 1,
+            ArithOp::Mul | ArithOp::Div => 2,
+        }
+    }
+}
+
+/// A computed column such as price * quantity or (price - cost) / 2. Operands are int and float columns and
+/// number literals. Operators are separated from their operands by whitespace so column names may contain them.
+/// Ints stay ints, wrapping on overflow, until they meet a float. Integer division by zero is an error.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub enum Expression {
+    Column(KeyString),
+    Int(i32),
+    Float(f32),
+    Arithmetic{op: ArithOp, left: Box<Expression>, right: Box<Expression>},
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::Column(name) => write!(f, "{}", name),
+            Expression::Int(i) => write!(f, "{}", i),
+            Expression::Float(x) => write!(f, "{:?}", x),
+            Expression::Arithmetic { op, left, right } => {
+                // Only the parentheses needed to keep the meaning are written so the text fits in a column name
+                let needs_parens = |side: &Expression, is_right: bool| match side {
+                    Expression::Arithmetic { op: inner, .. } => inner.precedence() < op.precedence() || (is_right && inner.precedence() == op.precedence()),
+                    _ => false,
+                };
+                match needs_parens(left, false) {
+                    true => write!(f, "({})", left)?,
+                    false => write!(f, "{}", left)?,
+                }
+                write!(f, " {} ", op.symbol())?;
+                match needs_parens(right, true) {
+                    true => write!(f, "({})", right),
+                    false => write!(f, "{}", right),
+                }
+            },
+        }
+    }
+}
+
+/// The value of an operand: a whole column or a literal that applies to every row.
+enum Operand<T> {
+    Column(Vec<T>),
+    Scalar(T),
+}
+
+impl<T: Copy> Operand<T> {
+    fn map<U>(self, f: impl Fn(T) -> U) -> Operand<U> {
+        match self {
+            Operand::Column(column) => Operand::Column(column.into_iter().map(f).collect()),
+            Operand::Scalar(value) => Operand::Scalar(f(value)),
+        }
+    }
+
+    fn zip_with(self, other: Operand<T>, f: impl Fn(T, T) -> T) -> Operand<T> {
+        match (self, other) {
+            (Operand::Scalar(a), Operand::Scalar(b)) => Operand::Scalar(f(a, b)),
+            (Operand::Column(mut a), Operand::Scalar(b)) => {
+                a.iter_mut().for_each(|x| *x = f(*x, b));
+                Operand::Column(a)
+            },
+            (Operand::Scalar(a), Operand::Column(mut b)) => {
+                b.iter_mut().for_each(|y| *y = f(a, *y));
+                Operand::Column(b)
+            },
+            (Operand::Column(mut a), Operand::Column(b)) => {
+                a.iter_mut().zip(b).for_each(|(x, y)| *x = f(*x, y));
+                Operand::Column(a)
+            },
+        }
+    }
+
+    fn contains(&self, value: T) -> bool where T: PartialEq {
+        match self {
+            Operand::Column(column) => column.contains(&value),
+            Operand::Scalar(x) => *x == value,
+        }
+    }
+
+    fn into_column(self, len: usize) -> Vec<T> {
+        match self {
+            Operand::Column(column) => column,
+            Operand::Scalar(value) => vec![value; len],
+        }
+    }
+}
+
+enum Evaluated {
+    Ints(Operand<i32>),
+    Floats(Operand<f32>),
+}
+
+impl Evaluated {
+    fn into_floats(self) -> Operand<f32> {
+        match self {
+            Evaluated::Ints(ints) => ints.map(|i| i as f32),
+            Evaluated::Floats(floats) => floats,
+        }
+    }
+}
+
+impl Expression {
+    /// Parses the text of a column list entry. Returns None if it has no operators and so is a plain column or
+    /// a text function. A lone * is the whole table, not an operator.
+    pub fn parse(text: &str) -> Result<Option<Expression>, EzError> {
+
+        let mut tokens = Vec::new();
+        let mut word = String::new();
+        for c in text.chars() {
+            if c.is_whitespace() || c == '(' || c == ')' {
+                if !word.is_empty() {
+                    tokens.push(std::mem::take(&mut word));
+                }
+                if !c.is_whitespace() {
+                    tokens.push(c.to_string());
+                }
+            } else {
+                word.push(c);
+            }
+        }
+        if !word.is_empty() {
+            tokens.push(word);
+        }
+        if tokens.len() < 2 || !tokens.iter().any(|token| ArithOp::from_symbol(token).is_some()) {
+            return Ok(None)
+        }
+
+        let mut position = 0;
+        let expression = Expression::parse_sum(&tokens, &mut position, text)?;
+        if position != tokens.len() {
+            return Err(query_error(format!("Unexpected '{}' in '{}'", tokens[position], text)))
+        }
+        Ok(Some(expression))
+    }
+
+    fn parse_sum(tokens: &[String], position: &mut usize, text: &str) -> Result<Expression, EzError> {
+        let mut left = Expression::parse_product(tokens, position, text)?;
+        while let Some(op) = tokens.get(*position).and_then(|token| ArithOp::from_symbol(token)).filter(|op| op.precedence() == 1) {
+            *position += 1;
+            let right = Expression::parse_product(tokens, position, text)?;
+            left = Expression::Arithmetic { op, left: Box::new(left), right: Box::new(right) };
+        }
+        Ok(left)
+    }
+
+    fn parse_product(tokens: &[String], position: &mut usize, text: &str) -> Result<Expression, EzError> {
+        let mut left = Expression::parse_operand(tokens, position, text)?;
+        while let Some(op) = tokens.get(*position).and_then(|token| ArithOp::from_symbol(token)).filter(|op| op.precedence() == 2) {
+            *position += 1;
+            let right = Expression::parse_operand(tokens, position, text)?;
+            left = Expression::Arithmetic { op, left: Box::new(left), right: Box::new(right) };
+        }
+        Ok(left)
+    }
+
+    fn parse_operand(tokens: &[String], position: &mut usize, text: &str) -> Result<Expression, EzError> {
+        let token = match tokens.get(*position) {
+            Some(token) => token,
+            None => return Err(query_error(format!("'{}' ends where an operand was expected", text))),
+        };
+        *position += 1;
+        if token == "(" {
+            let inner = Expression::parse_sum(tokens, position, text)?;
+            match tokens.get(*position).map(String::as_str) {
+                Some(")") => *position += 1,
+                _ => return Err(query_error(format!("Unclosed '(' in '{}'", text))),
+            }
+            return Ok(inner)
+        }
+        if token == ")" || ArithOp::from_symbol(token).is_some() {
+            return Err(query_error(format!("Expected a column or a number but found '{}' in '{}'", token, text)))
+        }
+        if let Ok(i) = token.parse::<i32>() {
+            return Ok(Expression::Int(i))
+        }
+        if token.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.') {
+            if let Ok(x) = token.parse::<f32>() {
+                return Ok(Expression::Float(x))
+            }
+        }
+        Ok(Expression::Column(KeyString::from_input(token)?))
+    }
+
+    /// Every column the expression reads, each once.
+    pub fn columns(&self) -> Vec<KeyString> {
+        let mut columns = Vec::new();
+        self.collect_columns(&mut columns);
+        columns
+    }
+
+    fn collect_columns(&self, columns: &mut Vec<KeyString>) {
+        match self {
+            Expression::Column(name) => if !columns.contains(name) {
+                columns.push(*name);
+            },
+            Expression::Int(_) | Expression::Float(_) => (),
+            Expression::Arithmetic { left, right, .. } => {
+                left.collect_columns(columns);
+                right.collect_columns(columns);
+            },
+        }
+    }
+
+    /// Whether evaluate() gives floats for this table. Ints stay ints until they meet a float.
+    pub fn is_float(&self, table: &ColumnTable) -> bool {
+        match self {
+            Expression::Column(name) => matches!(table.columns.get(name), Some(DbColumn::Floats(_))),
+            Expression::Int(_) => false,
+            Expression::Float(_) => true,
+            Expression::Arithmetic { left, right, .. } => left.is_float(table) || right.is_float(table),
+        }
+    }
+
+    /// Computes the expression for every row of the table, a whole column at a time.
+    pub fn evaluate(&self, table: &ColumnTable) -> Result<DbColumn, EzError> {
+        Ok(match self.evaluate_operand(table)? {
+            Evaluated::Ints(ints) => DbColumn::Ints(ints.into_column(table.len())),
+            Evaluated::Floats(floats) => DbColumn::Floats(floats.into_column(table.len())),
+        })
+    }
+
+    fn evaluate_operand(&self, table: &ColumnTable) -> Result<Evaluated, EzError> {
+        match self {
+            Expression::Column(name) => match table.columns.get(name) {
+                Some(DbColumn::Ints(column)) => Ok(Evaluated::Ints(Operand::Column(column.clone()))),
+                Some(DbColumn::Floats(column)) => Ok(Evaluated::Floats(Operand::Column(column.clone()))),
+                Some(other) => Err(EzError{tag: ErrorTag::Query, text: format!("Arithmetic needs int or float columns but '{}' is a {} column", name, column_kind(other))}),
+                None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No such column as {}", name)}),
+            },
+            Expression::Int(i) => Ok(Evaluated::Ints(Operand::Scalar(*i))),
+            Expression::Float(x) => Ok(Evaluated::Floats(Operand::Scalar(*x))),
+            Expression::Arithmetic { op, left, right } => {
+                match (left.evaluate_operand(table)?, right.evaluate_operand(table)?) {
+                    (Evaluated::Ints(a), Evaluated::Ints(b)) => {
+                        let result = match op {
+                            ArithOp::Add => a.zip_with(b, i32::wrapping_add),
+                            ArithOp::Sub => a.zip_with(b, i32::wrapping_sub),
+                            ArithOp::Mul => a.zip_with(b, i32::wrapping_mul),
+                            ArithOp::Div => {
+                                if b.contains(0) {
+                                    return Err(EzError{tag: ErrorTag::Query, text: format!("Division by zero in '{}'", self)})
+                                }
+                                a.zip_with(b, i32::wrapping_div)
+                            },
+                        };
+                        Ok(Evaluated::Ints(result))
+                    },
+                    (a, b) => {
+                        let (a, b) = (a.into_floats(), b.into_floats());
+                        let result = match op {
+                            ArithOp::Add => a.zip_with(b, |x, y| x + y),
+                            ArithOp::Sub => a.zip_with(b, |x, y| x - y),
+                            ArithOp::Mul => a.zip_with(b, |x, y| x * y),
+                            ArithOp::Div => a.zip_with(b, |x, y| x / y),
+                        };
+                        Ok(Evaluated::Floats(result))
+                    },
+                }
+            },
+        }
+    }
+}
+
+/// A single entry in the column list of a SELECT query. Either a plain column, a function applied to one or
+/// arithmetic on several. Computed entries are named as they are written.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Projection {
+    pub function: Option<TextFunction>,
+    pub column: KeyString,
+    pub expression: Option<Expression>,
+}
+
+impl Projection {
+    pub fn from_keystring(s: &KeyString) -> Projection {
+        let text = s.as_str();
+        // Text that doesn't parse as arithmetic is taken as a column name and fails as one if there is no such column
+        if let Ok(Some(expression)) = Expression::parse(text) {
+            return Projection{function: None, column: *s, expression: Some(expression)}
+        }
+        let (function, rest) = if let Some(rest) = text.strip_prefix("LOWER(") {
+            (Some(TextFunction::Lower), rest)
+        } else if let Some(rest) = text.strip_prefix("UPPER(") {
+            (Some(TextFunction::Upper), rest)
+        } else if let Some(rest) = text.strip_prefix("TRIM(") {
+            (Some(TextFunction::Trim), rest)
+        } else {
+            return Projection{function: None, column: *s, expression: None}
+        };
+
+        match rest.strip_suffix(')') {
+            Some(column) => Projection{function, column: KeyString::from(column), expression: None},
+            None => Projection{function: None, column: *s, expression: None},
+        }
+    }
+
+    /// Whether the entry is computed rather than a column of the table.
+    pub fn is_computed(&self) -> bool {
+        self.function.is_some() || self.expression.is_some()
+    }
+
+    /// The columns of the table the entry reads.
+    pub fn base_columns(&self) -> Vec<KeyString> {
+        match &self.expression {
+            Some(expression) => expression.columns(),
+            None => vec![self.column],
+        }
+    }
+}
+
 /// This enum represents the possible ways to list primary keys to test. 
 /// See EZQL spec for details (handlers.rs).
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum RangeOrListOrAll {
     Range(KeyString, KeyString),
     List(Vec<KeyString>),
+    /// A key list in the type of the primary key column. Sent packed and looked up without parsing any strings.
+    Keys(KeyList),
     All,
 }
 
@@ -957,6 +2196,16 @@ impl Display for RangeOrListOrAll {
                 printer.push_str(&print_sep_list(list, ", "));
                 printer.push(')');
             },
+            RangeOrListOrAll::Keys(KeyList::Ints(list)) => {
+                printer.push('(');
+                printer.push_str(&print_sep_list(list, ", "));
+                printer.push(')');
+            },
+            RangeOrListOrAll::Keys(KeyList::Texts(list)) => {
+                printer.push('(');
+                printer.push_str(&print_sep_list(list, ", "));
+                printer.push(')');
+            },
             RangeOrListOrAll::All => printer.push('*'),
         };
         write!(f, "{}", printer)
@@ -980,6 +2229,7 @@ impl RangeOrListOrAll {
 
                 }
             },
+            RangeOrListOrAll::Keys(keys) => keys.write_binary(&mut binary),
             RangeOrListOrAll::All => {
                 binary.extend_from_slice(KeyString::from("ALL").raw());
             },
@@ -991,35 +2241,136 @@ impl RangeOrListOrAll {
         if binary.len() < 64 {
             return Err(EzError{tag: ErrorTag::Query, text: format!("RangeOrListOrAll is always at least 64 bytes. Input binary is only '{}'", binary.len())})
         }
-        let first = KeyString::try_from(&binary[0..64]).unwrap();
+        let first = KeyString::try_from(&binary[0..64])?;
         match first.as_str() {
             "RANGE" => {
                 if binary.len() != 192 {
                     return Err(EzError{tag: ErrorTag::Query, text: format!("Range is always 192 bytes. Input binary is '{}'", binary.len())})
                 }
-                let from = KeyString::try_from(&binary[64..128]).unwrap();
-                let to = KeyString::try_from(&binary[128..192]).unwrap();
+                let from = KeyString::try_from(&binary[64..128])?;
+                let to = KeyString::try_from(&binary[128..192])?;
                 Ok(RangeOrListOrAll::Range(from, to))
             }
             "LIST" => {
-                if (binary.len()-8) % 64 != 0 {
-                    return Err(EzError{tag: ErrorTag::Query, text: format!("List is always a multiple of 64 bytes. Input binary is {}", binary.len())})
-                }
-                let mut list = Vec::new();
-                let list_len = u64_from_le_slice(&binary[64..72]) as usize;
-                for chunk in binary[72..72+64*list_len].chunks(64) {
-                    list.push(KeyString::try_from(chunk).unwrap());
+                let list_len = u64_from_le_slice(checked_slice(binary, 64, 8, "List")?) as usize;
+                if list_len.checked_mul(64).and_then(|len| len.checked_add(72)) != Some(binary.len()) {
+                    return Err(EzError{tag: ErrorTag::Query, text: format!("A list of {} keys should be {} bytes. Input binary is {}", list_len, list_len.saturating_mul(64).saturating_add(72), binary.len())})
                 }
+                let list = binary[72..].chunks_exact(64).map(KeyString::try_from).collect::<Result<_, _>>()?;
                 Ok(RangeOrListOrAll::List(list))
             }
+            "INT_KEYS" | "TEXT_KEYS" => {
+                Ok(RangeOrListOrAll::Keys(KeyList::from_binary(binary)?))
+            }
             "ALL" => {
                 Ok(RangeOrListOrAll::All)
             }
-            _ => return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is neither 'RANGE' nor 'LIST' nor 'ALL'", first)})
+            _ => Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is neither 'RANGE' nor 'LIST' nor 'INT_KEYS' nor 'TEXT_KEYS' nor 'ALL'", first)})
+        }
+    }
+}
+
+
+/// Primary keys in the type of the primary key column. Long key lists go over the wire packed,
+/// 4 bytes per int key, and are looked up with binary searches without turning any key into a string.
+///     [INT_KEYS: 64 bytes][number of keys: u64][i32 per key]
+///     [TEXT_KEYS: 64 bytes][number of keys: u64][64 bytes per key]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyList {
+    Ints(Vec<i32>),
+    Texts(Vec<KeyString>),
+}
+
+impl KeyList {
+    /// Takes the keys of a primary key column, or a column of the same type.
+    pub fn from_column(column: DbColumn) -> Result<KeyList, EzError> {
+        match column {
+            DbColumn::Ints(keys) => Ok(KeyList::Ints(keys)),
+            DbColumn::Texts(keys) => Ok(KeyList::Texts(keys)),
+            _ => Err(EzError{tag: ErrorTag::Query, text: "Primary keys are ints or texts. Only an int or text column can be a key list".to_owned()}),
+        }
+    }
+
+    /// Converts a list of keys written as text to the type of the primary key column. Keys that are not
+    /// valid ints match nothing in an int key column.
+    pub fn from_keystrings(keys: &[KeyString], primary_key_column: &DbSlice) -> KeyList {
+        match primary_key_column {
+            DbSlice::Ints(_) => KeyList::Ints(keys.iter().filter_map(|key| key.to_i32_checked().ok()).collect()),
+            _ => KeyList::Texts(keys.to_vec()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            KeyList::Ints(keys) => keys.len(),
+            KeyList::Texts(keys) => keys.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The rows of the primary key column that hold one of the keys, in ascending order.
+    /// Keys that are not in the column are skipped. The key list must have the type of the column and
+    /// text keys are compared with the collation of the column.
+    pub fn indexes_in(&self, primary_key_column: &DbSlice, collation: Collation) -> Result<Vec<usize>, EzError> {
+        match (self, primary_key_column) {
+            (KeyList::Ints(keys), DbSlice::Ints(column)) => Ok(sorted_key_indexes(keys, column, i32::cmp)),
+            (KeyList::Texts(keys), DbSlice::Texts(column)) => Ok(sorted_key_indexes(keys, column, |a, b| collation.compare(a, b))),
+            (KeyList::Ints(_), _) => Err(EzError{tag: ErrorTag::Query, text: "Int keys were given for a table whose primary key is not an int column".to_owned()}),
+            (KeyList::Texts(_), _) => Err(EzError{tag: ErrorTag::Query, text: "Text keys were given for a table whose primary key is not a text column".to_owned()}),
+        }
+    }
+
+    pub fn write_binary(&self, binary: &mut Vec<u8>) {
+        match self {
+            KeyList::Ints(keys) => {
+                binary.extend_from_slice(ksf("INT_KEYS").raw());
+                binary.extend_from_slice(&(keys.len() as u64).to_le_bytes());
+                for key in keys {
+                    binary.extend_from_slice(&key.to_le_bytes());
+                }
+            },
+            KeyList::Texts(keys) => {
+                binary.extend_from_slice(ksf("TEXT_KEYS").raw());
+                binary.extend_from_slice(&(keys.len() as u64).to_le_bytes());
+                for key in keys {
+                    binary.extend_from_slice(key.raw());
+                }
+            },
+        }
+    }
+
+    pub fn from_binary(binary: &[u8]) -> Result<KeyList, EzError> {
+        if binary.len() < 72 {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("A key list is always at least 72 bytes. Input binary is only '{}'", binary.len())})
+        }
+        let kind = KeyString::try_from(&binary[0..64])?;
+        let count = u64_from_le_slice(&binary[64..72]) as usize;
+        let body = &binary[72..];
+        let key_size = match kind.as_str() {
+            "INT_KEYS" => 4,
+            "TEXT_KEYS" => 64,
+            other => return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is neither 'INT_KEYS' nor 'TEXT_KEYS'", other)}),
+        };
+        if count.checked_mul(key_size) != Some(body.len()) {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("A list of {} keys should be {} bytes but is {}", count, count.saturating_mul(key_size), body.len())})
+        }
+        match key_size {
+            4 => Ok(KeyList::Ints(body.chunks(4).map(i32_from_le_slice).collect())),
+            _ => Ok(KeyList::Texts(body.chunks(64).map(KeyString::try_from).collect::<Result<_, _>>()?)),
         }
     }
 }
 
+/// Looks each key up in a sorted column. Sorting the keys first makes the result sorted without a second pass.
+fn sorted_key_indexes<T: Clone>(keys: &[T], column: &[T], compare: impl Fn(&T, &T) -> std::cmp::Ordering) -> Vec<usize> {
+    let mut keys = keys.to_vec();
+    keys.sort_by(&compare);
+    keys.dedup_by(|a, b| compare(a, b).is_eq());
+    keys.iter().filter_map(|key| column.binary_search_by(|probe| compare(probe, key)).ok()).collect()
+}
 
 /// Represents the condition a item must pass to be included in the result
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
@@ -1027,13 +2378,18 @@ pub struct Condition {
     pub attribute: KeyString,
     pub op: TestOp,
     pub value: DbValue,
+    /// Compare against this column in the same row instead of value. value is ignored when this is set.
+    pub other_column: Option<KeyString>,
 }
 
 impl Display for Condition {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // println!("calling: Condition::fmt()");
 
-        write!(f, "{} - {} - {}", self.attribute, "OP", self.value)
+        match &self.other_column {
+            Some(other) => write!(f, "{} - OP - column {}", self.attribute, other),
+            None => write!(f, "{} - OP - {}", self.attribute, self.value),
+        }
     }
 }
 
@@ -1043,28 +2399,99 @@ impl Condition {
         // println!("calling: Condition::new()");
 
         Ok(Condition {
-            attribute: KeyString::from(attribute),
+            attribute: KeyString::from_input(attribute)?,
             op,
             value: value.into(),
+            other_column: None,
+        })
+    }
+
+    /// A condition that compares two columns of the same row, such as "price greater_than cost".
+    pub fn against_column(attribute: &str, op: TestOp, other_column: &str) -> Result<Self, EzError> {
+        Ok(Condition {
+            attribute: KeyString::from_input(attribute)?,
+            op,
+            value: 0.into(),
+            other_column: Some(KeyString::from_input(other_column)?),
+        })
+    }
+
+    /// The condition with the other column replaced by its value in the given row.
+    pub fn resolve(&self, other: &DbSlice, index: usize) -> Result<Condition, EzError> {
+        Ok(Condition {
+            attribute: self.attribute,
+            op: self.op.clone(),
+            value: other.value_at(index)?,
+            other_column: None,
         })
     }
 
-    pub fn to_binary(&self) -> [u8;144] {
-        let mut binary = [0u8;144];
+    /// A column operand is stored in the value slot with the kind byte 'c' and the column name in bytes 8..72.
+    /// The values of in and between follow the 144 bytes, 72 each, and the value slot holds the kind byte 'l'
+    /// and their count in bytes 8..16.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let values = self.op.values();
+        let mut binary = vec![0u8; 144 + 72 * values.len()];
 
         binary[0..64].copy_from_slice(self.attribute.raw());
         binary[64..72].copy_from_slice(&self.op.to_binary());
-        binary[72..144].copy_from_slice(&self.value.to_binary());
+        match &self.other_column {
+            _ if matches!(self.op, TestOp::In(_) | TestOp::Between(..)) => {
+                binary[72] = b'l';
+                binary[80..88].copy_from_slice(&(values.len() as u64).to_le_bytes());
+                for (i, value) in values.iter().enumerate() {
+                    binary[144 + 72*i..216 + 72*i].copy_from_slice(&value.to_binary());
+                }
+            },
+            Some(other) => {
+                binary[72] = b'c';
+                binary[80..144].copy_from_slice(other.raw());
+            },
+            None => binary[72..144].copy_from_slice(&self.value.to_binary()),
+        }
 
         binary
     }
 
+    /// How many bytes the condition at the start of the binary takes. See to_binary().
+    pub fn binary_len(binary: &[u8]) -> Result<usize, EzError> {
+        if binary.len() < 144 {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Condition is at least 144 bytes. Input binary is {}", binary.len())})
+        }
+        if binary[72] != b'l' {
+            return Ok(144)
+        }
+        match (u64_from_le_slice(&binary[80..88]) as usize).checked_mul(72).and_then(|len| len.checked_add(144)) {
+            Some(len) => Ok(len),
+            None => Err(EzError{tag: ErrorTag::Deserialization, text: "Condition claims more values than can exist".to_owned()}),
+        }
+    }
 
     pub fn from_binary(binary: &[u8]) -> Result<Self, EzError> {
+        let len = Condition::binary_len(binary)?;
+        if binary.len() != len {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Condition is exactly {} bytes. Input binary is {}", len, binary.len())})
+        }
         let attribute = KeyString::try_from(&binary[0..64])?;
         let op = TestOp::from_binary(&binary[64..72])?;
-        let value = DbValue::from_binary(&binary[72..144])?;
-        Ok( Condition {attribute, op, value} )
+        match binary[72] {
+            b'c' => {
+                let other = KeyString::try_from(&binary[80..144])?;
+                Ok( Condition {attribute, op, value: 0.into(), other_column: Some(other)} )
+            },
+            b'l' => {
+                let mut values = Vec::with_capacity((len - 144) / 72);
+                for chunk in binary[144..].chunks(72) {
+                    values.push(DbValue::from_binary(chunk)?);
+                }
+                let op = op.with_values(values).map_err(|e| EzError{tag: ErrorTag::Deserialization, text: e.text})?;
+                Ok( Condition {attribute, op, value: 0.into(), other_column: None} )
+            },
+            _ => {
+                let value = DbValue::from_binary(&binary[72..144])?;
+                Ok( Condition {attribute, op, value, other_column: None} )
+            },
+        }
     }
 
     pub fn blank() -> Self {
@@ -1074,6 +2501,7 @@ impl Condition {
             attribute: KeyString::from(""),
             op: TestOp::Equals,
             value: 0.into(),
+            other_column: None,
         }
     }
 }
@@ -1095,10 +2523,17 @@ impl Operator {
     }
 }
 
+/// A flat list of these makes up the conditions of a query. See ConditionBranch::parse() for precedence.
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum OpOrCond {
     Cond(Condition),
     Op(Operator),
+    /// Negates the condition or group that follows it
+    Not,
+    /// Opens a parenthesized group of conditions
+    Open,
+    /// Closes the group opened by the matching Open
+    Close,
 }
 
 impl Display for OpOrCond {
@@ -1106,11 +2541,17 @@ impl Display for OpOrCond {
         // println!("calling: OpOrCond::fmt()");
 
         match self {
-            OpOrCond::Cond(cond) => write!(f, "({} {} {})", cond.attribute, "OP", cond.value),
+            OpOrCond::Cond(cond) => match &cond.other_column {
+                Some(other) => write!(f, "({} OP column {})", cond.attribute, other),
+                None => write!(f, "({} OP {})", cond.attribute, cond.value),
+            },
             OpOrCond::Op(op) => match op {
                 Operator::AND => write!(f, "AND"),
                 Operator::OR => write!(f, "OR"),
             },
+            OpOrCond::Not => write!(f, "NOT"),
+            OpOrCond::Open => write!(f, "("),
+            OpOrCond::Close => write!(f, ")"),
         }
     }
 }
@@ -1123,6 +2564,9 @@ impl OpOrCond {
                 binary.extend_from_slice(&condition.to_binary());
             },
             OpOrCond::Op(operator) => binary.extend_from_slice(operator.to_keystring().raw()),
+            OpOrCond::Not => binary.extend_from_slice(ksf("NOT").raw()),
+            OpOrCond::Open => binary.extend_from_slice(ksf("(").raw()),
+            OpOrCond::Close => binary.extend_from_slice(ksf(")").raw()),
         }
         binary
     }
@@ -1136,10 +2580,10 @@ impl OpOrCond {
         match first.as_str() {
             "AND" => Ok(OpOrCond::Op(Operator::AND)),
             "OR" => Ok(OpOrCond::Op(Operator::OR)),
+            "NOT" => Ok(OpOrCond::Not),
+            "(" => Ok(OpOrCond::Open),
+            ")" => Ok(OpOrCond::Close),
             _ => {
-                if binary.len() != 144 {
-                    return Err(EzError{tag: ErrorTag::Query, text: format!("Cond is exactly 144 bytes. Input binary is {}", binary.len())})
-                }
                 let condition = Condition::from_binary(binary)?;
                 Ok(OpOrCond::Cond(condition))
             }
@@ -1155,21 +2599,47 @@ pub fn conditions_from_binary(binary: &[u8]) -> Result<Vec<OpOrCond>, EzError> {
     }
     
     if binary.len() < 144 {
-        return Err(EzError{tag: ErrorTag::Query, text: format!("Condition is exactly 144 bytes. Input binary is '{}'", binary.len())})
+        return Err(EzError{tag: ErrorTag::Query, text: format!("Condition is at least 144 bytes. Input binary is '{}'", binary.len())})
     }
     let mut conditions = Vec::new();
 
+    // Where a condition is expected only NOT and '(' are 64 byte markers. Anything else is a condition of at least
+    // 144 bytes, so a column can be named AND or OR. After a condition only AND, OR and ')' can follow.
     let mut offset = 0;
-    let mut i = 1;
+    let mut expecting_condition = true;
     while offset < binary.len() {
-        if i % 2 == 0 {
-            conditions.push(OpOrCond::from_binary(&binary[offset..offset+64])?);
+        if binary.len() < offset + 64 {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("Conditions end with {} stray bytes", binary.len() - offset)})
+        }
+        let marker = &binary[offset..offset+64];
+        if !expecting_condition {
+            match OpOrCond::from_binary(marker)? {
+                OpOrCond::Op(op) => {
+                    conditions.push(OpOrCond::Op(op));
+                    expecting_condition = true;
+                },
+                OpOrCond::Close => conditions.push(OpOrCond::Close),
+                other => return Err(EzError{tag: ErrorTag::Query, text: format!("Expected AND, OR or ')' after a condition but found '{}'", other)}),
+            }
+            offset += 64;
+        } else if marker == ksf("NOT").raw() {
+            conditions.push(OpOrCond::Not);
+            offset += 64;
+        } else if marker == ksf("(").raw() {
+            conditions.push(OpOrCond::Open);
             offset += 64;
         } else {
-            conditions.push(OpOrCond::from_binary(&binary[offset..offset+144])?);
-            offset += 144;
+            if binary.len() < offset + 144 {
+                return Err(EzError{tag: ErrorTag::Query, text: format!("Condition is at least 144 bytes. Only {} bytes left", binary.len() - offset)})
+            }
+            let len = Condition::binary_len(&binary[offset..])?;
+            if binary.len() - offset < len {
+                return Err(EzError{tag: ErrorTag::Query, text: format!("Condition is {} bytes. Only {} bytes left", len, binary.len() - offset)})
+            }
+            conditions.push(OpOrCond::Cond(Condition::from_binary(&binary[offset..offset+len])?));
+            offset += len;
+            expecting_condition = false;
         }
-        i += 1;
     }
 
     Ok(conditions)
@@ -1184,34 +2654,151 @@ pub enum TestOp {
     Starts,
     Ends,
     Contains,
+    NotStarts,
+    NotEnds,
+    NotContains,
+    /// True for NaN in a float column. Takes no value.
+    IsNaN,
+    /// True when the value is one of the list. Scans look rows up in a hash set of the list. See PreparedTests.
+    In(Vec<DbValue>),
+    /// True when the value lies between the two, both included. On the primary key it narrows the rows that are
+    /// scanned since the keys are sorted. See between_key_span().
+    Between(DbValue, DbValue),
+    /// True when the whole text matches a glob pattern, where '*' is any run of characters and '?' any one.
+    Matches,
+    /// True when a regex matches the text. Needs the regex feature. Scans compile the regex once. See PreparedTests.
+    MatchesRegex,
 }
 
 impl TestOp {
-    pub fn to_binary(&self) -> [u8;8] {
+    /// Every test, in the order of their binary codes. The tests that hold values hold placeholders.
+    pub const ALL: [TestOp; 15] = [
+        TestOp::Equals, TestOp::NotEquals, TestOp::Less, TestOp::Greater, TestOp::Starts, TestOp::Ends,
+        TestOp::Contains, TestOp::NotStarts, TestOp::NotEnds, TestOp::NotContains, TestOp::IsNaN,
+        TestOp::In(Vec::new()), TestOp::Between(DbValue::Int(0), DbValue::Int(0)), TestOp::Matches, TestOp::MatchesRegex,
+    ];
+
+    /// Whether this test is the negation of another one.
+    pub fn is_negated(&self) -> bool {
+        matches!(self, TestOp::NotStarts | TestOp::NotEnds | TestOp::NotContains)
+    }
+
+    /// The canonical name of the test. from_name() accepts it.
+    pub fn name(&self) -> &'static str {
         match self {
-            TestOp::Equals => 0u64.to_le_bytes(),
-            TestOp::NotEquals => 1u64.to_le_bytes(),
-            TestOp::Less => 2u64.to_le_bytes(),
-            TestOp::Greater => 3u64.to_le_bytes(),
-            TestOp::Starts => 4u64.to_le_bytes(),
-            TestOp::Ends => 5u64.to_le_bytes(),
-            TestOp::Contains => 6u64.to_le_bytes(),
+            TestOp::Equals => "equals",
+            TestOp::NotEquals => "not_equals",
+            TestOp::Less => "less_than",
+            TestOp::Greater => "greater_than",
+            TestOp::Starts => "starts_with",
+            TestOp::Ends => "ends_with",
+            TestOp::Contains => "contains",
+            TestOp::NotStarts => "not_starts_with",
+            TestOp::NotEnds => "not_ends_with",
+            TestOp::NotContains => "not_contains",
+            TestOp::IsNaN => "is_nan",
+            TestOp::In(_) => "in",
+            TestOp::Between(..) => "between",
+            TestOp::Matches => "matches",
+            TestOp::MatchesRegex => "matches_regex",
         }
     }
 
-    pub fn from_binary(binary: &[u8]) -> Result<TestOp, EzError> {
-        if binary.len() != 8 {
-            return Err(EzError { tag: ErrorTag::Deserialization, text: format!("Binary is wrong length for a TestOp: '{}'", binary.len()) })
+    /// The values in and between hold in place of the value of the condition. Empty for the other tests.
+    pub fn values(&self) -> Vec<&DbValue> {
+        match self {
+            TestOp::In(values) => values.iter().collect(),
+            TestOp::Between(low, high) => vec![low, high],
+            _ => Vec::new(),
         }
-        let number = u64_from_le_slice(binary);
-        match number {
-            0 => Ok(TestOp::Equals),
+    }
+
+    fn values_mut(&mut self) -> Vec<&mut DbValue> {
+        match self {
+            TestOp::In(values) => values.iter_mut().collect(),
+            TestOp::Between(low, high) => vec![low, high],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The test holding the given values. Only in and between hold values and between takes exactly two.
+    pub fn with_values(self, values: Vec<DbValue>) -> Result<TestOp, EzError> {
+        match self {
+            TestOp::In(_) if values.is_empty() => Err(EzError{tag: ErrorTag::Query, text: "'in' needs at least one value".to_owned()}),
+            TestOp::In(_) => Ok(TestOp::In(values)),
+            TestOp::Between(..) => match <[DbValue; 2]>::try_from(values) {
+                Ok([low, high]) => Ok(TestOp::Between(low, high)),
+                Err(values) => Err(EzError{tag: ErrorTag::Query, text: format!("'between' takes two values but was given {}", values.len())}),
+            },
+            other => Err(EzError{tag: ErrorTag::Query, text: format!("'{}' does not take a list of values", other.name())}),
+        }
+    }
+
+    /// Parses the textual name of a test as written in EZQL, such as greater_than or >.
+    /// in and between come back holding placeholders. See with_values().
+    pub fn from_name(name: &str) -> Result<TestOp, EzError> {
+        match name.to_lowercase().replace('-', "_").as_str() {
+            "equals" | "=" | "==" => Ok(TestOp::Equals),
+            "not_equals" | "!=" => Ok(TestOp::NotEquals),
+            "less" | "less_than" | "<" => Ok(TestOp::Less),
+            "greater" | "greater_than" | ">" => Ok(TestOp::Greater),
+            "starts" | "starts_with" => Ok(TestOp::Starts),
+            "ends" | "ends_with" => Ok(TestOp::Ends),
+            "contains" => Ok(TestOp::Contains),
+            "not_starts" | "not_starts_with" => Ok(TestOp::NotStarts),
+            "not_ends" | "not_ends_with" => Ok(TestOp::NotEnds),
+            "not_contains" => Ok(TestOp::NotContains),
+            "is_nan" | "isnan" => Ok(TestOp::IsNaN),
+            "in" => Ok(TestOp::In(Vec::new())),
+            "between" => Ok(TestOp::Between(DbValue::Int(0), DbValue::Int(0))),
+            "matches" | "glob" => Ok(TestOp::Matches),
+            "matches_regex" | "regex" => Ok(TestOp::MatchesRegex),
+            _ => Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is not a test. Use one of equals, not_equals, less_than, greater_than, starts_with, ends_with, contains, not_starts_with, not_ends_with, not_contains, is_nan, in, between, matches, matches_regex", name)}),
+        }
+    }
+
+    pub fn to_binary(&self) -> [u8;8] {
+        match self {
+            TestOp::Equals => 0u64.to_le_bytes(),
+            TestOp::NotEquals => 1u64.to_le_bytes(),
+            TestOp::Less => 2u64.to_le_bytes(),
+            TestOp::Greater => 3u64.to_le_bytes(),
+            TestOp::Starts => 4u64.to_le_bytes(),
+            TestOp::Ends => 5u64.to_le_bytes(),
+            TestOp::Contains => 6u64.to_le_bytes(),
+            TestOp::NotStarts => 7u64.to_le_bytes(),
+            TestOp::NotEnds => 8u64.to_le_bytes(),
+            TestOp::NotContains => 9u64.to_le_bytes(),
+            TestOp::IsNaN => 10u64.to_le_bytes(),
+            TestOp::In(_) => 11u64.to_le_bytes(),
+            TestOp::Between(..) => 12u64.to_le_bytes(),
+            TestOp::Matches => 13u64.to_le_bytes(),
+            TestOp::MatchesRegex => 14u64.to_le_bytes(),
+        }
+    }
+
+    /// in and between come back holding placeholders. Condition::from_binary() reads their values.
+    pub fn from_binary(binary: &[u8]) -> Result<TestOp, EzError> {
+        if binary.len() != 8 {
+            return Err(EzError { tag: ErrorTag::Deserialization, text: format!("Binary is wrong length for a TestOp: '{}'", binary.len()) })
+        }
+        let number = u64_from_le_slice(binary);
+        match number {
+            0 => Ok(TestOp::Equals),
             1 => Ok(TestOp::NotEquals),
             2 => Ok(TestOp::Less),
             3 => Ok(TestOp::Greater),
             4 => Ok(TestOp::Starts),
             5 => Ok(TestOp::Ends),
             6 => Ok(TestOp::Contains),
+            7 => Ok(TestOp::NotStarts),
+            8 => Ok(TestOp::NotEnds),
+            9 => Ok(TestOp::NotContains),
+            10 => Ok(TestOp::IsNaN),
+            11 => Ok(TestOp::In(Vec::new())),
+            12 => Ok(TestOp::Between(DbValue::Int(0), DbValue::Int(0))),
+            13 => Ok(TestOp::Matches),
+            14 => Ok(TestOp::MatchesRegex),
             other => Err(EzError { tag: ErrorTag::Deserialization, text: format!("No Testop maps to '{}'", other) })
         }
     }
@@ -1227,7 +2814,7 @@ impl Display for AltTest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // println!("calling: Test::fmt()");
 
-        match self.op {
+        match &self.op {
             TestOp::Equals => write!(f, "equals {}", self.value),
             TestOp::NotEquals => write!(f, "not_equals {}", self.value),
             TestOp::Less => write!(f, "less_than {}", self.value),
@@ -1235,6 +2822,14 @@ impl Display for AltTest {
             TestOp::Starts => write!(f, "starts_with {}", self.value),
             TestOp::Ends => write!(f, "ends_with {}", self.value),
             TestOp::Contains => write!(f, "contains {}", self.value),
+            TestOp::NotStarts => write!(f, "not_starts_with {}", self.value),
+            TestOp::NotEnds => write!(f, "not_ends_with {}", self.value),
+            TestOp::NotContains => write!(f, "not_contains {}", self.value),
+            TestOp::IsNaN => write!(f, "is_nan"),
+            TestOp::In(values) => write!(f, "in ({})", print_sep_list(values, ", ")),
+            TestOp::Between(low, high) => write!(f, "between {} and {}", low, high),
+            TestOp::Matches => write!(f, "matches {}", self.value),
+            TestOp::MatchesRegex => write!(f, "matches_regex {}", self.value),
         }
     }
 }
@@ -1250,6 +2845,12 @@ impl AltTest {
             "Starts" | "starts_with" => AltTest{op: TestOp::Starts, value: bar},
             "Ends" | "ends_with" => AltTest{op: TestOp::Ends, value: bar},
             "Contains" | "contains"=> AltTest{op: TestOp::Contains, value: bar},
+            "NotStarts" | "not_starts_with" => AltTest{op: TestOp::NotStarts, value: bar},
+            "NotEnds" | "not_ends_with" => AltTest{op: TestOp::NotEnds, value: bar},
+            "NotContains" | "not_contains" => AltTest{op: TestOp::NotContains, value: bar},
+            "IsNaN" | "is_nan" => AltTest{op: TestOp::IsNaN, value: bar},
+            "Matches" | "matches" => AltTest{op: TestOp::Matches, value: bar},
+            "MatchesRegex" | "matches_regex" => AltTest{op: TestOp::MatchesRegex, value: bar},
             _ => todo!(),
         }
     }
@@ -1278,13 +2879,38 @@ impl AltTest {
             TestOp::Contains => {
                 binary[0..64].copy_from_slice(KeyString::from("CONTAINS").raw());
             },
+            TestOp::NotStarts => {
+                binary[0..64].copy_from_slice(KeyString::from("NOT_STARTS").raw());
+            },
+            TestOp::NotEnds => {
+                binary[0..64].copy_from_slice(KeyString::from("NOT_ENDS").raw());
+            },
+            TestOp::NotContains => {
+                binary[0..64].copy_from_slice(KeyString::from("NOT_CONTAINS").raw());
+            },
+            TestOp::IsNaN => {
+                binary[0..64].copy_from_slice(KeyString::from("IS_NAN").raw());
+            },
+            // There is room for a single value so the values of these are not kept
+            TestOp::In(_) => {
+                binary[0..64].copy_from_slice(KeyString::from("IN").raw());
+            },
+            TestOp::Between(..) => {
+                binary[0..64].copy_from_slice(KeyString::from("BETWEEN").raw());
+            },
+            TestOp::Matches => {
+                binary[0..64].copy_from_slice(KeyString::from("MATCHES").raw());
+            },
+            TestOp::MatchesRegex => {
+                binary[0..64].copy_from_slice(KeyString::from("MATCHES_REGEX").raw());
+            },
         }
         binary[64..136].copy_from_slice(&self.value.to_binary());
         binary
     }
 
     pub fn from_binary(binary: &[u8]) -> Result<Self, EzError> {
-        let t = KeyString::try_from(&binary[0..64])?;
+        let t = KeyString::try_from(checked_slice(binary, 0, 64, "Test")?)?;
         let v = DbValue::from_binary(&binary[64..])?;
         let x = match t.as_str() {
             "EQUALS" => AltTest{op: TestOp::Equals, value: v},
@@ -1294,6 +2920,12 @@ impl AltTest {
             "STARTS" => AltTest{op: TestOp::Starts, value: v},
             "ENDS" => AltTest{op: TestOp::Ends, value: v},
             "CONTAINS" => AltTest{op: TestOp::Contains, value: v},
+            "NOT_STARTS" => AltTest{op: TestOp::NotStarts, value: v},
+            "NOT_ENDS" => AltTest{op: TestOp::NotEnds, value: v},
+            "NOT_CONTAINS" => AltTest{op: TestOp::NotContains, value: v},
+            "IS_NAN" => AltTest{op: TestOp::IsNaN, value: v},
+            "MATCHES" => AltTest{op: TestOp::Matches, value: v},
+            "MATCHES_REGEX" => AltTest{op: TestOp::MatchesRegex, value: v},
             _ => return Err(EzError{tag: ErrorTag::Query, text: format!("Test: '{}' is not supported", t)})
         };
         Ok(x)
@@ -1310,6 +2942,9 @@ pub enum Test {
     Starts(DbValue),
     Ends(DbValue),
     Contains(DbValue),
+    NotStarts(DbValue),
+    NotEnds(DbValue),
+    NotContains(DbValue),
     //Closure,   could you imagine?
 }
 
@@ -1325,6 +2960,9 @@ impl Display for Test {
             Test::Starts(value) => write!(f, "starts_with {}", value),
             Test::Ends(value) => write!(f, "ends_with {}", value),
             Test::Contains(value) => write!(f, "contains {}", value),
+            Test::NotStarts(value) => write!(f, "not_starts_with {}", value),
+            Test::NotEnds(value) => write!(f, "not_ends_with {}", value),
+            Test::NotContains(value) => write!(f, "not_contains {}", value),
         }
     }
 }
@@ -1341,6 +2979,9 @@ impl Test {
             "Starts" | "starts_with" => Test::Starts(bar),
             "Ends" | "ends_with" => Test::Ends(bar),
             "Contains" | "contains"=> Test::Contains(bar),
+            "NotStarts" | "not_starts_with" => Test::NotStarts(bar),
+            "NotEnds" | "not_ends_with" => Test::NotEnds(bar),
+            "NotContains" | "not_contains" => Test::NotContains(bar),
             _ => todo!(),
         }
     }
@@ -1376,12 +3017,24 @@ impl Test {
                 binary[0..64].copy_from_slice(KeyString::from("CONTAINS").raw());
                 binary[64..136].copy_from_slice(&val.to_binary());    
             },
+            Test::NotStarts(val) => {
+                binary[0..64].copy_from_slice(KeyString::from("NOT_STARTS").raw());
+                binary[64..136].copy_from_slice(&val.to_binary());
+            },
+            Test::NotEnds(val) => {
+                binary[0..64].copy_from_slice(KeyString::from("NOT_ENDS").raw());
+                binary[64..136].copy_from_slice(&val.to_binary());
+            },
+            Test::NotContains(val) => {
+                binary[0..64].copy_from_slice(KeyString::from("NOT_CONTAINS").raw());
+                binary[64..136].copy_from_slice(&val.to_binary());
+            },
         }
         binary
     }
 
     pub fn from_binary(binary: &[u8]) -> Result<Self, EzError> {
-        let t = KeyString::try_from(&binary[0..64])?;
+        let t = KeyString::try_from(checked_slice(binary, 0, 64, "Test")?)?;
         let v = DbValue::from_binary(&binary[64..])?;
         let x = match t.as_str() {
             "EQUALS" => Test::Equals(v),
@@ -1391,15 +3044,180 @@ impl Test {
             "STARTS" => Test::Starts(v),
             "ENDS" => Test::Ends(v),
             "CONTAINS" => Test::Contains(v),
+            "NOT_STARTS" => Test::NotStarts(v),
+            "NOT_ENDS" => Test::NotEnds(v),
+            "NOT_CONTAINS" => Test::NotContains(v),
             _ => return Err(EzError{tag: ErrorTag::Query, text: format!("Test: '{}' is not supported", t)})
         };
         Ok(x)
     }
 }
 
-pub enum ConditionBranch<'a> {
-    Branch(Vec<&'a ConditionBranch<'a>>),
-    Leaf(Condition),
+/// How deeply groups and NOTs can nest in the conditions of a query. Keeps hostile input from exhausting the stack.
+pub const MAX_CONDITION_DEPTH: usize = 64;
+
+/// The conditions of a query as an expression tree. Queries carry the flat OpOrCond list and the tree is
+/// built from it with parse() once per query, then evaluated for every row.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub enum ConditionBranch {
+    Leaf(Box<Condition>),
+    Not(Box<ConditionBranch>),
+    /// Holds when every branch holds. An empty And always holds.
+    And(Vec<ConditionBranch>),
+    /// Holds when any branch holds. An empty Or never holds.
+    Or(Vec<ConditionBranch>),
+}
+
+impl ConditionBranch {
+    /// Builds the tree from a flat list of conditions. NOT binds tightest, then AND, then OR, and parentheses
+    /// group anything: "a OR NOT b AND c" means "a OR ((NOT b) AND c)" while "(a OR b) AND c" needs its group.
+    pub fn parse(conditions: &[OpOrCond]) -> Result<ConditionBranch, EzError> {
+        if conditions.is_empty() {
+            return Err(EzError{tag: ErrorTag::Query, text: "There are no conditions to evaluate".to_owned()})
+        }
+        let mut position = 0;
+        let tree = ConditionBranch::parse_or(conditions, &mut position, 0)?;
+        match conditions.get(position) {
+            None => Ok(tree),
+            Some(OpOrCond::Close) => Err(EzError{tag: ErrorTag::Query, text: "')' has no matching '('".to_owned()}),
+            Some(OpOrCond::Not) => Err(EzError{tag: ErrorTag::Query, text: "NOT must come before a condition, not after one".to_owned()}),
+            Some(_) => Err(EzError{tag: ErrorTag::Query, text: "Two conditions need an AND or OR between them".to_owned()}),
+        }
+    }
+
+    fn parse_or(conditions: &[OpOrCond], position: &mut usize, depth: usize) -> Result<ConditionBranch, EzError> {
+        let mut branches = vec![ConditionBranch::parse_and(conditions, position, depth)?];
+        while conditions.get(*position) == Some(&OpOrCond::Op(Operator::OR)) {
+            *position += 1;
+            branches.push(ConditionBranch::parse_and(conditions, position, depth)?);
+        }
+        Ok(if branches.len() == 1 { branches.remove(0) } else { ConditionBranch::Or(branches) })
+    }
+
+    fn parse_and(conditions: &[OpOrCond], position: &mut usize, depth: usize) -> Result<ConditionBranch, EzError> {
+        let mut branches = vec![ConditionBranch::parse_unary(conditions, position, depth)?];
+        while conditions.get(*position) == Some(&OpOrCond::Op(Operator::AND)) {
+            *position += 1;
+            branches.push(ConditionBranch::parse_unary(conditions, position, depth)?);
+        }
+        Ok(if branches.len() == 1 { branches.remove(0) } else { ConditionBranch::And(branches) })
+    }
+
+    fn parse_unary(conditions: &[OpOrCond], position: &mut usize, depth: usize) -> Result<ConditionBranch, EzError> {
+        if depth > MAX_CONDITION_DEPTH {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("Conditions can't nest more than {} levels deep", MAX_CONDITION_DEPTH)})
+        }
+        let item = conditions.get(*position);
+        *position += 1;
+        match item {
+            Some(OpOrCond::Cond(cond)) => Ok(ConditionBranch::Leaf(Box::new(cond.clone()))),
+            Some(OpOrCond::Not) => Ok(ConditionBranch::Not(Box::new(ConditionBranch::parse_unary(conditions, position, depth + 1)?))),
+            Some(OpOrCond::Open) => {
+                let group = ConditionBranch::parse_or(conditions, position, depth + 1)?;
+                match conditions.get(*position) {
+                    Some(OpOrCond::Close) => {
+                        *position += 1;
+                        Ok(group)
+                    },
+                    Some(OpOrCond::Not) => Err(EzError{tag: ErrorTag::Query, text: "NOT must come before a condition, not after one".to_owned()}),
+                    Some(OpOrCond::Cond(_)) | Some(OpOrCond::Open) => Err(EzError{tag: ErrorTag::Query, text: "Two conditions need an AND or OR between them".to_owned()}),
+                    _ => Err(EzError{tag: ErrorTag::Query, text: "'(' is never closed".to_owned()}),
+                }
+            },
+            Some(OpOrCond::Op(op)) => Err(EzError{tag: ErrorTag::Query, text: format!("{} needs a condition before it", op.to_keystring())}),
+            Some(OpOrCond::Close) => Err(EzError{tag: ErrorTag::Query, text: "Expected a condition before ')'".to_owned()}),
+            None => Err(EzError{tag: ErrorTag::Query, text: "Conditions can't end with an operator or NOT".to_owned()}),
+        }
+    }
+
+    /// Tests the tree with the given test for each leaf. AND stops at the first branch that fails
+    /// and OR at the first that holds, so the remaining leaves are not tested.
+    pub fn evaluate<F>(&self, test: &mut F) -> Result<bool, EzError>
+    where F: FnMut(&Condition) -> Result<bool, EzError> {
+        match self {
+            ConditionBranch::Leaf(cond) => test(cond),
+            ConditionBranch::Not(branch) => Ok(!branch.evaluate(test)?),
+            ConditionBranch::And(branches) => {
+                for branch in branches {
+                    if !branch.evaluate(test)? {
+                        return Ok(false)
+                    }
+                }
+                Ok(true)
+            },
+            ConditionBranch::Or(branches) => {
+                for branch in branches {
+                    if branch.evaluate(test)? {
+                        return Ok(true)
+                    }
+                }
+                Ok(false)
+            },
+        }
+    }
+
+    /// Every condition in the tree, in the order they are written.
+    pub fn leaves(&self) -> Vec<&Condition> {
+        match self {
+            ConditionBranch::Leaf(cond) => vec![cond.as_ref()],
+            ConditionBranch::Not(branch) => branch.leaves(),
+            ConditionBranch::And(branches) | ConditionBranch::Or(branches) => branches.iter().flat_map(|branch| branch.leaves()).collect(),
+        }
+    }
+
+    /// The flat list that parse() turns back into this tree. Groups are only added where precedence needs them.
+    pub fn to_conditions(&self) -> Vec<OpOrCond> {
+        let mut conditions = Vec::new();
+        self.flatten(&mut conditions);
+        conditions
+    }
+
+    fn flatten(&self, conditions: &mut Vec<OpOrCond>) {
+        match self {
+            ConditionBranch::Leaf(cond) => conditions.push(OpOrCond::Cond(cond.as_ref().clone())),
+            ConditionBranch::Not(branch) => {
+                conditions.push(OpOrCond::Not);
+                branch.flatten_grouped(conditions, matches!(**branch, ConditionBranch::And(_) | ConditionBranch::Or(_)));
+            },
+            ConditionBranch::And(branches) => {
+                for (i, branch) in branches.iter().enumerate() {
+                    if i > 0 {
+                        conditions.push(OpOrCond::Op(Operator::AND));
+                    }
+                    branch.flatten_grouped(conditions, matches!(branch, ConditionBranch::Or(_)));
+                }
+            },
+            ConditionBranch::Or(branches) => {
+                for (i, branch) in branches.iter().enumerate() {
+                    if i > 0 {
+                        conditions.push(OpOrCond::Op(Operator::OR));
+                    }
+                    branch.flatten(conditions);
+                }
+            },
+        }
+    }
+
+    fn flatten_grouped(&self, conditions: &mut Vec<OpOrCond>, grouped: bool) {
+        if grouped {
+            conditions.push(OpOrCond::Open);
+        }
+        self.flatten(conditions);
+        if grouped {
+            conditions.push(OpOrCond::Close);
+        }
+    }
+
+    /// The binary of the flat list. See conditions_from_binary().
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut binary = Vec::new();
+        append_conditions(&mut binary, &self.to_conditions());
+        binary
+    }
+
+    pub fn from_binary(binary: &[u8]) -> Result<ConditionBranch, EzError> {
+        ConditionBranch::parse(&conditions_from_binary(binary)?)
+    }
 }
 
 
@@ -1480,217 +3298,1515 @@ pub fn parse_contained_token(s: &str, container_open: char, container_close: cha
     Some(&s[start..stop])
 }
 
-pub fn execute_kv_queries(kv_queries: Vec<KvQuery>, database: Arc<Database>) -> Vec<Result<Option<Value>, EzError>> {
+/// The tokens of textual EZQL. Each token remembers its byte offset in the input for error messages.
+#[derive(Clone, Debug, PartialEq)]
+enum EzqlToken {
+    Open,
+    Close,
+    Comma,
+    Colon,
+    Arrow,
+    Word(String),
+    Quoted(String),
+}
 
-    let mut result_values = Vec::new();
+/// Splits EZQL text into tokens. Whitespace only separates words.
+/// Double quotes make a single word of anything between them, including whitespace, commas, and parentheses.
+fn tokenize_ezql(s: &str) -> Result<Vec<(usize, EzqlToken)>, EzError> {
+    // println!("calling: tokenize_ezql()");
 
-    for query in kv_queries {
-        match query {
-            KvQuery::Create(key_string, vec) => {
-                let value = Value{
-                    name: key_string,
-                    body: vec,
-                };
-                match database.buffer_pool.add_value(value) {
-                    Ok(_) => continue,
-                    Err(e) => result_values.push(Err(e))
-                };
-                result_values.push(Ok(None));
-            },
-            KvQuery::Read(key_string) => {
-                match database.buffer_pool.values.read().unwrap().get(&key_string) {
-                    Some(v) => {
-                        result_values.push(Ok(Some(v.clone())));
-                    },
-                    None => result_values.push(Err(EzError{tag: ErrorTag::Query, text: format!("No value corresponds to key: '{}'", key_string)}))
-                };
-            },
-            KvQuery::Update(key_string, vec) => {
-                let value = Value{
-                    name: key_string,
-                    body: vec,
-                };
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut word_start = 0;
+    let mut chars = s.char_indices().peekable();
 
-                let read_lock = database.buffer_pool.values.read().unwrap();
-                if read_lock.contains_key(&key_string) {
-                    drop(read_lock);
-                    let mut write_lock = database.buffer_pool.values.write().unwrap();
-                    write_lock.insert(key_string, value);
-                    result_values.push(Ok(None));
-                } else {
-                    result_values.push(Err(EzError{tag: ErrorTag::Query, text: format!("No value corresponds to key: '{}'", key_string)}))
-                }
+    fn flush(tokens: &mut Vec<(usize, EzqlToken)>, word: &mut String, word_start: usize) {
+        if !word.is_empty() {
+            tokens.push((word_start, EzqlToken::Word(std::mem::take(word))));
+        }
+    }
 
+    while let Some((index, c)) = chars.next() {
+        let token = match c {
+            '(' => EzqlToken::Open,
+            ')' => EzqlToken::Close,
+            ',' => EzqlToken::Comma,
+            ':' => EzqlToken::Colon,
+            '-' if chars.peek().map(|(_, next)| *next) == Some('>') => {
+                chars.next();
+                EzqlToken::Arrow
             },
-            KvQuery::Delete(key_string) => {
-                match database.buffer_pool.values.write().unwrap().remove(&key_string) {
-                    Some(v) => {
-                        result_values.push(Ok(Some(v.clone())));
-                    },
-                    None => result_values.push(Err(EzError{tag: ErrorTag::Query, text: format!("No value corresponds to key: '{}'", key_string)}))
-                };
+            '"' => {
+                let mut quoted = String::new();
+                let mut closed = false;
+                for (_, c) in chars.by_ref() {
+                    if c == '"' {
+                        closed = true;
+                        break
+                    }
+                    quoted.push(c);
+                }
+                if !closed {
+                    return Err(EzError{tag: ErrorTag::Query, text: format!("Unclosed '\"' at position {}", index)})
+                }
+                EzqlToken::Quoted(quoted)
             },
-        }
+            c if c.is_whitespace() => {
+                flush(&mut tokens, &mut word, word_start);
+                continue
+            },
+            c => {
+                if word.is_empty() {
+                    word_start = index;
+                }
+                word.push(c);
+                continue
+            },
+        };
+        flush(&mut tokens, &mut word, word_start);
+        tokens.push((index, token));
     }
+    flush(&mut tokens, &mut word, word_start);
 
-    result_values
-
+    Ok(tokens)
 }
 
-#[allow(non_snake_case)]
-pub fn execute_EZQL_queries(queries: Vec<Query>, database: Arc<Database>) -> Result<Option<ColumnTable>, EzError> {
-    // println!("calling: execute_EZQL_queries()");
+/// A parenthesized group is a comma separated list of elements and each element is a whitespace separated
+/// sequence of expressions. "((a b), c d)" is one group with the elements [(a b)] and [c, d].
+#[derive(Clone, Debug, PartialEq)]
+enum EzqlExpr {
+    Word(String),
+    Quoted(String),
+    Colon,
+    Group(Vec<Vec<EzqlExpr>>),
+}
 
+impl Display for EzqlExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EzqlExpr::Word(word) => write!(f, "{}", word),
+            EzqlExpr::Quoted(quoted) => write!(f, "\"{}\"", quoted),
+            EzqlExpr::Colon => write!(f, ":"),
+            EzqlExpr::Group(elements) => {
+                let elements: Vec<String> = elements.iter()
+                    .map(|element| element.iter().map(|expr| expr.to_string()).collect::<Vec<String>>().join(" "))
+                    .collect();
+                write!(f, "({})", elements.join(", "))
+            },
+        }
+    }
+}
 
-    let mut result_table = None;
-    for query in queries.into_iter() {
+fn query_error(text: String) -> EzError {
+    EzError{tag: ErrorTag::Query, text}
+}
 
-        match &query {
-            Query::DELETE{ primary_keys: _, table_name, conditions: _ } => {
-                match result_table {
-                    Some(mut table) => result_table = execute_delete_query(query, &mut table)?,
-                    None => {
-                        let tables = database.buffer_pool.tables.read().unwrap();
-                        let mut table = tables.get(table_name).unwrap().write().unwrap();
-                        result_table = execute_delete_query(query, &mut table)?;
-                        database.buffer_pool.table_naughty_list.write().unwrap().insert(table.name);
-                    },
-                }
-                
-            },
-            Query::SELECT{ table_name, primary_keys: _, columns: _, conditions: _ } => {
-                match result_table {
-                    Some(mut table) => result_table = execute_select_query(&query, &mut table)?,
-                    None => {
-                        println!("table name: {}", table_name);
-                        let tables = database.buffer_pool.tables.read().unwrap();
-                        let table = tables.get(table_name).unwrap().read().unwrap();
-                        result_table = execute_select_query(&query, &table)?;
-                    },
+/// Parses the group following an opening parenthesis. `position` is where that parenthesis was.
+fn parse_ezql_group(tokens: &[(usize, EzqlToken)], i: &mut usize, position: usize) -> Result<Vec<Vec<EzqlExpr>>, EzError> {
+
+    let mut elements = Vec::new();
+    let mut element = Vec::new();
+    while *i < tokens.len() {
+        let (offset, token) = &tokens[*i];
+        *i += 1;
+        match token {
+            EzqlToken::Open => element.push(EzqlExpr::Group(parse_ezql_group(tokens, i, *offset)?)),
+            EzqlToken::Close => {
+                if !element.is_empty() || !elements.is_empty() {
+                    elements.push(element);
                 }
+                return Ok(elements)
             },
-            Query::LEFT_JOIN{ left_table_name, right_table_name, match_columns: _, primary_keys: _ } => {
-                match result_table {
-                    Some(table) => {
-                        let tables = database.buffer_pool.tables.read().unwrap();
-                        let right_table = tables.get(right_table_name).unwrap().read().unwrap();
-                        result_table = execute_left_join_query(query, &table, &right_table)?;
-                    },
-                    None => {
-                        let tables = database.buffer_pool.tables.read().unwrap();
-                        let left_table = tables.get(left_table_name).unwrap().read().unwrap();
-                        let right_table = tables.get(right_table_name).unwrap().read().unwrap();
-                        execute_left_join_query(query, &left_table, &right_table)?;
-                    },
+            EzqlToken::Comma => {
+                if element.is_empty() {
+                    return Err(query_error(format!("Empty item before ',' at position {}", offset)))
                 }
-                
-            },
-            Query::INNER_JOIN => {
-                unimplemented!("Inner joins are not yet implemented");
-                // execute_inner_join_query(query, database);
+                elements.push(std::mem::take(&mut element));
             },
-            Query::RIGHT_JOIN => {
-                unimplemented!("Right joins are not yet implemented");
+            EzqlToken::Colon => element.push(EzqlExpr::Colon),
+            EzqlToken::Arrow => return Err(query_error(format!("'->' at position {} is inside parentheses. Queries can only be chained at the top level", offset))),
+            EzqlToken::Word(word) => element.push(EzqlExpr::Word(word.clone())),
+            EzqlToken::Quoted(quoted) => element.push(EzqlExpr::Quoted(quoted.clone())),
+        }
+    }
 
-                // execute_right_join_query(query, database);
-            },
-            Query::FULL_JOIN => {
-                unimplemented!("Full joins are not yet implemented");
+    Err(query_error(format!("'(' at position {} is never closed", position)))
+}
 
-                // execute_full_join_query(query, database);
-            },
-            Query::UPDATE{ table_name, primary_keys: _, conditions: _, updates: _ } => {
-                match result_table {
-                    Some(mut table) => result_table = execute_update_query(query, &mut table)?,
-                    None => {
-                        let tables = database.buffer_pool.tables.read().unwrap();
-                        let mut table = tables.get(table_name).unwrap().write().unwrap();
-                        result_table = execute_update_query(query, &mut table)?;
-                        database.buffer_pool.table_naughty_list.write().unwrap().insert(table.name);
-                    },
-                }
-            },
-            Query::INSERT{ table_name, inserts: _ } => {
-                match result_table {
-                    Some(mut table) => result_table = execute_insert_query(query, &mut table)?,
-                    None => {
-                        let tables = database.buffer_pool.tables.read().unwrap();
-                        let mut table = tables.get(table_name).unwrap().write().unwrap();
-                        result_table = execute_insert_query(query, &mut table)?;
-                        database.buffer_pool.table_naughty_list.write().unwrap().insert(table.name);
-                    },
-                }
-            },
-            
-            Query::SUMMARY { table_name, columns } => {
-                match result_table {
-                    Some(table) => {
-                        let result = execute_summary_query(&query, &table)?;
-                        match result {
-                            Some(s) => return Ok(Some(s)),
-                            None => todo!(),
-                        };
-                    },
-                    None => {
-                        let tables = database.buffer_pool.tables.read().unwrap();
-                        let table = tables.get(table_name).unwrap().read().unwrap();
-                        let result = execute_summary_query(&query, &table)?;
-                        match result {
-                            Some(s) => return Ok(Some(s)),
-                            None => todo!(),
-                        };
-                    },
-                }
+/// The named arguments of a query, as in SELECT(table_name: products, primary_keys: *).
+struct EzqlArgs {
+    query_type: String,
+    args: BTreeMap<String, Vec<EzqlExpr>>,
+}
+
+impl EzqlArgs {
+    fn new(query_type: &str, elements: Vec<Vec<EzqlExpr>>) -> Result<EzqlArgs, EzError> {
+
+        let mut args = BTreeMap::new();
+        for element in elements {
+            let mut exprs = element.into_iter();
+            let name = match (exprs.next(), exprs.next()) {
+                (Some(EzqlExpr::Word(name)), Some(EzqlExpr::Colon)) => name,
+                (Some(first), _) => return Err(query_error(format!("Expected 'argument_name: value' in {} but found '{}'", query_type, first))),
+                (None, _) => continue,
+            };
+            let value: Vec<EzqlExpr> = exprs.collect();
+            if value.is_empty() {
+                return Err(query_error(format!("Argument '{}' of {} has no value", name, query_type)))
+            }
+            if args.insert(name.clone(), value).is_some() {
+                return Err(query_error(format!("Argument '{}' is given twice in {}", name, query_type)))
             }
-            Query::CREATE { table } => {
-                match database.buffer_pool.add_table(table.clone()) {
-                    Ok(_) => {
-                        result_table = None;
-                    },
-                    Err(e) => return Err(e),
-                }
-            },
-            Query::DROP { table_name } => {
-                match database.buffer_pool.remove_table(*table_name) {
-                    Ok(_) => {
-                        result_table = None;
-                    },
-                    Err(e) => return Err(e),
-                }
-            },
         }
+
+        Ok(EzqlArgs{query_type: query_type.to_owned(), args})
     }
 
-    match result_table {
-        Some(table) => Ok(Some(table)),
-        None => Ok(None),
+    /// Takes the first of the given names that is present. Later names are accepted aliases.
+    fn optional(&mut self, names: &[&str]) -> Option<Vec<EzqlExpr>> {
+        names.iter().find_map(|name| self.args.remove(*name))
     }
-}
 
+    fn required(&mut self, names: &[&str]) -> Result<Vec<EzqlExpr>, EzError> {
+        match self.optional(names) {
+            Some(value) => Ok(value),
+            None => Err(query_error(format!("{} requires the argument '{}'", self.query_type, names[0]))),
+        }
+    }
 
-pub fn execute_delete_query(query: Query, table: &mut ColumnTable) -> Result<Option<ColumnTable>, EzError> {
+    /// Errors on any argument that was not taken.
+    fn finish(self) -> Result<(), EzError> {
+        match self.args.keys().next() {
+            Some(name) => Err(query_error(format!("{} does not take the argument '{}'", self.query_type, name))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn ezql_keystring(expr: &EzqlExpr, what: &str) -> Result<KeyString, EzError> {
+    match expr {
+        EzqlExpr::Word(word) => KeyString::from_input(word),
+        EzqlExpr::Quoted(quoted) => KeyString::from_input(quoted),
+        other => Err(query_error(format!("Expected a {} but found '{}'", what, other))),
+    }
+}
+
+fn ezql_single_keystring(value: &[EzqlExpr], what: &str) -> Result<KeyString, EzError> {
+    match value {
+        [expr] => ezql_keystring(expr, what),
+        _ => Err(query_error(format!("Expected a single {} but found '{}'", what, print_sep_list(value, " ")))),
+    }
+}
+
+/// Quoted values are always text. Unquoted values are ints if they parse as ints, then floats, then text.
+/// Numbers with a leading zero, such as the product code 011, stay text so the zero isn't lost.
+fn ezql_value(expr: &EzqlExpr) -> Result<DbValue, EzError> {
+    match expr {
+        EzqlExpr::Quoted(quoted) => Ok(DbValue::Text(KeyString::from_input(quoted)?)),
+        EzqlExpr::Word(word) if has_leading_zero(word) => Ok(DbValue::Text(KeyString::from_input(word)?)),
+        EzqlExpr::Word(word) => {
+            if let Ok(int) = word.parse::<i32>() {
+                Ok(DbValue::Int(int))
+            } else if let Ok(float) = word.parse::<f32>() {
+                Ok(DbValue::Float(float))
+            } else {
+                Ok(DbValue::Text(KeyString::from_input(word)?))
+            }
+        },
+        other => Err(query_error(format!("Expected a value but found '{}'", other))),
+    }
+}
+
+/// Whether the word is a number written with a leading zero, like 011 or -007.5. A lone 0 or 0.5 has none.
+fn has_leading_zero(word: &str) -> bool {
+    let digits = word.strip_prefix('-').unwrap_or(word).as_bytes();
+    digits.len() > 1 && digits[0] == b'0' && digits[1].is_ascii_digit()
+}
+
+/// The columns of a SELECT. A bare * selects every column, the same as leaving the argument out.
+fn ezql_select_columns(value: &[EzqlExpr]) -> Result<Vec<KeyString>, EzError> {
+    match value {
+        [EzqlExpr::Word(word)] if word == "*" => Ok(vec![ksf("*")]),
+        _ => ezql_name_list(value, "columns"),
+    }
+}
+
+/// The elements of a group where each element is a single name, such as "(price, stock)".
+fn ezql_name_list(value: &[EzqlExpr], what: &str) -> Result<Vec<KeyString>, EzError> {
+    let elements = match value {
+        [EzqlExpr::Group(elements)] => elements,
+        _ => return Err(query_error(format!("Expected a list of {} in parentheses but found '{}'", what, print_sep_list(value, " ")))),
+    };
+    let mut names = Vec::with_capacity(elements.len());
+    for element in elements {
+        match element.as_slice() {
+            [expr] => names.push(ezql_keystring(expr, what)?),
+            // Column functions such as LOWER(name) are stored as written
+            [EzqlExpr::Word(function), EzqlExpr::Group(inner)] => match inner.as_slice() {
+                [inner] if inner.len() == 1 => names.push(KeyString::from_input(&format!("{}({})", function, ezql_keystring(&inner[0], what)?))?),
+                _ => return Err(query_error(format!("'{}' takes a single column", function))),
+            },
+            // Arithmetic such as price * quantity is stored as written, with only the parentheses it needs
+            parts if parts.iter().any(|part| matches!(part, EzqlExpr::Word(word) if ArithOp::from_symbol(word).is_some())) => {
+                let text = print_sep_list(parts, " ");
+                match Expression::parse(&text)? {
+                    Some(expression) => names.push(KeyString::from_input(&expression.to_string())?),
+                    None => return Err(query_error(format!("Expected one of the {} but found '{}'", what, text))),
+                }
+            },
+            other => return Err(query_error(format!("Expected one of the {} but found '{}'. Separate them with commas", what, print_sep_list(other, " ")))),
+        }
+    }
+    Ok(names)
+}
+
+fn ezql_primary_keys(value: &[EzqlExpr]) -> Result<RangeOrListOrAll, EzError> {
+    match value {
+        [EzqlExpr::Word(word)] if word == "*" => Ok(RangeOrListOrAll::All),
+        [EzqlExpr::Word(word)] => match word.split_once("..") {
+            Some((start, stop)) => Ok(RangeOrListOrAll::Range(KeyString::from_input(start)?, KeyString::from_input(stop)?)),
+            None => Ok(RangeOrListOrAll::List(vec![KeyString::from_input(word)?])),
+        },
+        [EzqlExpr::Quoted(quoted)] => Ok(RangeOrListOrAll::List(vec![KeyString::from_input(quoted)?])),
+        _ => Ok(RangeOrListOrAll::List(ezql_name_list(value, "primary keys")?)),
+    }
+}
+
+/// A single condition written as "attribute test value", such as "price greater_than 500".
+/// Write "column(name)" as the value to compare against another column of the same row.
+/// in takes a list, "status in (open, closed)", and between two values, "price between 10 and 20".
+fn ezql_condition(element: &[EzqlExpr]) -> Result<Condition, EzError> {
+    match element {
+        [attribute, EzqlExpr::Word(op), EzqlExpr::Word(function), EzqlExpr::Group(inner)] if function.eq_ignore_ascii_case("column") => match inner.as_slice() {
+            [other] if other.len() == 1 => Ok(Condition {
+                attribute: ezql_keystring(attribute, "column name")?,
+                op: ezql_single_value_test(op)?,
+                value: 0.into(),
+                other_column: Some(ezql_keystring(&other[0], "column name")?),
+            }),
+            _ => Err(query_error("'column' takes a single column name, such as column(cost)".to_owned())),
+        },
+        [attribute, EzqlExpr::Word(op), EzqlExpr::Group(elements)] if op.eq_ignore_ascii_case("in") => {
+            let mut values = Vec::with_capacity(elements.len());
+            for element in elements {
+                match element.as_slice() {
+                    [value] => values.push(ezql_value(value)?),
+                    other => return Err(query_error(format!("Expected one of the values of 'in' but found '{}'. Separate them with commas", print_sep_list(other, " ")))),
+                }
+            }
+            Ok(Condition {
+                attribute: ezql_keystring(attribute, "column name")?,
+                op: TestOp::In(Vec::new()).with_values(values)?,
+                value: 0.into(),
+                other_column: None,
+            })
+        },
+        [attribute, EzqlExpr::Word(op), low, EzqlExpr::Word(and), high] if op.eq_ignore_ascii_case("between") && and.eq_ignore_ascii_case("AND") => Ok(Condition {
+            attribute: ezql_keystring(attribute, "column name")?,
+            op: TestOp::Between(ezql_value(low)?, ezql_value(high)?),
+            value: 0.into(),
+            other_column: None,
+        }),
+        [attribute, EzqlExpr::Word(op), value] => Ok(Condition {
+            attribute: ezql_keystring(attribute, "column name")?,
+            op: ezql_single_value_test(op)?,
+            value: ezql_value(value)?,
+            other_column: None,
+        }),
+        // Tests that take no value, such as "price is_nan"
+        [attribute, EzqlExpr::Word(op)] => match TestOp::from_name(op)? {
+            TestOp::IsNaN => Ok(Condition {
+                attribute: ezql_keystring(attribute, "column name")?,
+                op: TestOp::IsNaN,
+                value: 0.into(),
+                other_column: None,
+            }),
+            other => Err(query_error(format!("'{}' needs a value to test against", other.name()))),
+        },
+        other => Err(query_error(format!("Expected a condition like '(price greater_than 500)' but found '({})'", print_sep_list(other, " ")))),
+    }
+}
+
+/// The test of a condition against one value or column. in and between hold their values and are parsed on their own.
+fn ezql_single_value_test(op: &str) -> Result<TestOp, EzError> {
+    match TestOp::from_name(op)? {
+        TestOp::In(_) => Err(query_error("'in' takes its values in parentheses, such as (status in (open, closed))".to_owned())),
+        TestOp::Between(..) => Err(query_error("'between' takes two values joined by AND, such as (price between 10 and 20)".to_owned())),
+        op => Ok(op),
+    }
+}
+
+/// Conditions are parenthesized conditions joined by AND and OR, each optionally preceded by NOT.
+/// Precedence is NOT > AND > OR. Parenthesize a group of conditions to override it:
+/// "((a equals 1) AND (b equals 2)) OR ((c equals 3) AND (d equals 4))".
+fn ezql_conditions(value: &[EzqlExpr]) -> Result<Vec<OpOrCond>, EzError> {
+    let elements = match value {
+        [EzqlExpr::Group(elements)] => elements,
+        _ => return Err(query_error(format!("Expected conditions in parentheses but found '{}'", print_sep_list(value, " ")))),
+    };
+    let sequence = match elements.as_slice() {
+        [] => return Ok(Vec::new()),
+        [sequence] => sequence,
+        _ => return Err(query_error("Conditions are joined with AND or OR, not commas".to_owned())),
+    };
+
+    // A single condition does not need its own parentheses: conditions: (id equals 4)
+    if !is_condition_sequence(sequence) {
+        return Ok(vec![OpOrCond::Cond(ezql_condition(sequence)?)])
+    }
+
+    let mut conditions = Vec::new();
+    ezql_condition_sequence(sequence, &mut conditions, 0)?;
+
+    Ok(conditions)
+}
+
+/// A sequence of conditions starts with a parenthesized condition or group, or with NOT before one.
+/// Anything else is a single condition, such as "price greater_than column(cost)".
+fn is_condition_sequence(sequence: &[EzqlExpr]) -> bool {
+    match sequence {
+        [EzqlExpr::Group(_), ..] => true,
+        [EzqlExpr::Word(word), EzqlExpr::Group(_), ..] => word.eq_ignore_ascii_case("NOT"),
+        _ => false,
+    }
+}
+
+/// Appends a sequence of conditions joined by AND and OR. A parenthesized sequence becomes a group.
+fn ezql_condition_sequence(sequence: &[EzqlExpr], conditions: &mut Vec<OpOrCond>, depth: usize) -> Result<(), EzError> {
+    if depth > MAX_CONDITION_DEPTH {
+        return Err(query_error(format!("Condition groups can't nest more than {} levels deep", MAX_CONDITION_DEPTH)))
+    }
+
+    let mut expecting_condition = true;
+    for expr in sequence {
+        match (expr, expecting_condition) {
+            (EzqlExpr::Word(word), true) if word.eq_ignore_ascii_case("NOT") => conditions.push(OpOrCond::Not),
+            (EzqlExpr::Group(inner), true) => {
+                match inner.as_slice() {
+                    [element] if is_condition_sequence(element) => {
+                        conditions.push(OpOrCond::Open);
+                        ezql_condition_sequence(element, conditions, depth + 1)?;
+                        conditions.push(OpOrCond::Close);
+                    },
+                    [element] => conditions.push(OpOrCond::Cond(ezql_condition(element)?)),
+                    _ => return Err(query_error(format!("Expected a single condition but found '{}'", expr))),
+                }
+                expecting_condition = false;
+            },
+            (EzqlExpr::Word(word), false) if word.eq_ignore_ascii_case("AND") => {
+                conditions.push(OpOrCond::Op(Operator::AND));
+                expecting_condition = true;
+            },
+            (EzqlExpr::Word(word), false) if word.eq_ignore_ascii_case("OR") => {
+                conditions.push(OpOrCond::Op(Operator::OR));
+                expecting_condition = true;
+            },
+            (other, true) => return Err(query_error(format!("Expected a condition but found '{}'", other))),
+            (other, false) => return Err(query_error(format!("Expected AND or OR between conditions but found '{}'", other))),
+        }
+    }
+    if expecting_condition {
+        return Err(query_error(format!("Conditions can not end with '{}'", sequence.last().map(|expr| expr.to_string()).unwrap_or_default())))
+    }
+
+    Ok(())
+}
+
+/// Updates are written "(column operator value)", such as "(price += 100)" or "(name trim)".
+/// The value can be arithmetic on other columns, "(price = cost * 1.2)", or another column, "(price = column(cost))".
+fn ezql_updates(value: &[EzqlExpr]) -> Result<Vec<Update>, EzError> {
+    let elements = match value {
+        [EzqlExpr::Group(elements)] => elements,
+        _ => return Err(query_error(format!("Expected updates in parentheses but found '{}'", print_sep_list(value, " ")))),
+    };
+
+    let mut updates = Vec::new();
+    for element in elements {
+        let sequence = match element.as_slice() {
+            [EzqlExpr::Group(inner)] if inner.len() == 1 => &inner[0],
+            bare => bare,
+        };
+        let update = match sequence {
+            [attribute, EzqlExpr::Word(op), EzqlExpr::Word(function), EzqlExpr::Group(inner)] if function.eq_ignore_ascii_case("column") => match inner.as_slice() {
+                [other] if other.len() == 1 => Update {
+                    attribute: ezql_keystring(attribute, "column name")?,
+                    operator: UpdateOp::from_str(op)?,
+                    value: DbValue::Text(KeyString::new()),
+                    expression: Some(Expression::Column(ezql_keystring(&other[0], "column name")?)),
+                },
+                _ => return Err(query_error("'column' takes a single column".to_owned())),
+            },
+            [attribute, EzqlExpr::Word(op), value] => Update {
+                attribute: ezql_keystring(attribute, "column name")?,
+                operator: UpdateOp::from_str(op)?,
+                value: ezql_value(value)?,
+                expression: None,
+            },
+            [attribute, EzqlExpr::Word(op), value @ ..] if value.iter().any(|part| matches!(part, EzqlExpr::Word(word) if ArithOp::from_symbol(word).is_some())) => {
+                let text = print_sep_list(value, " ");
+                match Expression::parse(&text)? {
+                    Some(expression) => Update {
+                        attribute: ezql_keystring(attribute, "column name")?,
+                        operator: UpdateOp::from_str(op)?,
+                        value: DbValue::Text(KeyString::new()),
+                        expression: Some(expression),
+                    },
+                    None => return Err(query_error(format!("Expected arithmetic but found '{}'", text))),
+                }
+            },
+            [attribute, EzqlExpr::Word(op)] => Update {
+                attribute: ezql_keystring(attribute, "column name")?,
+                operator: UpdateOp::from_str(op)?,
+                value: DbValue::Text(KeyString::new()),
+                expression: None,
+            },
+            other => return Err(query_error(format!("Expected an update like '(price += 100)' but found '({})'", print_sep_list(other, " ")))),
+        };
+        updates.push(update);
+    }
+
+    Ok(updates)
+}
+
+fn ezql_stat_op(expr: &EzqlExpr) -> Result<StatOp, EzError> {
+    match expr {
+        EzqlExpr::Word(word) => match word.to_uppercase().as_str() {
+            "SUM" => Ok(StatOp::SUM),
+            "MEAN" => Ok(StatOp::MEAN),
+            "MEDIAN" => Ok(StatOp::MEDIAN),
+            "MODE" => Ok(StatOp::MODE),
+            "STDEV" => Ok(StatOp::STDEV),
+            "MIN" => Ok(StatOp::MIN),
+            "MAX" => Ok(StatOp::MAX),
+            "COUNT" => Ok(StatOp::COUNT),
+            _ => Err(query_error(format!("'{}' is not a statistic. Use SUM, MEAN, MEDIAN, MODE, STDEV, MIN, MAX or COUNT", word))),
+        },
+        other => Err(query_error(format!("Expected a statistic but found '{}'", other))),
+    }
+}
+
+/// Statistics are written "(SUM stock)" or, for several statistics of one column, "(stock, SUM, MEAN)".
+fn ezql_statistics(value: &[EzqlExpr]) -> Result<Vec<Statistic>, EzError> {
+    let elements = match value {
+        [EzqlExpr::Group(elements)] => elements,
+        _ => return Err(query_error(format!("Expected statistics in parentheses but found '{}'", print_sep_list(value, " ")))),
+    };
+
+    let mut statistics: Vec<Statistic> = Vec::new();
+    for element in elements {
+        let (column, actions) = match element.as_slice() {
+            [EzqlExpr::Group(inner)] => match inner.as_slice() {
+                [pair] if pair.len() == 2 => (ezql_keystring(&pair[1], "column name")?, vec![ezql_stat_op(&pair[0])?]),
+                [column, ops @ ..] if column.len() == 1 && !ops.is_empty() => {
+                    let mut actions = Vec::new();
+                    for op in ops {
+                        match op.as_slice() {
+                            [op] => actions.push(ezql_stat_op(op)?),
+                            other => return Err(query_error(format!("Expected a statistic but found '{}'", print_sep_list(other, " ")))),
+                        }
+                    }
+                    (ezql_keystring(&column[0], "column name")?, actions)
+                },
+                _ => return Err(query_error(format!("Expected a statistic like '(SUM stock)' but found '{}'", element[0]))),
+            },
+            other => return Err(query_error(format!("Expected a statistic like '(SUM stock)' but found '{}'", print_sep_list(other, " ")))),
+        };
+        match statistics.iter_mut().find(|stat| stat.column == column) {
+            Some(stat) => stat.actions.extend(actions),
+            None => statistics.push(Statistic{column, actions: actions.into_iter().collect()}),
+        }
+    }
+
+    Ok(statistics)
+}
+
+/// new_values is a list of rows in parentheses. The rows are turned into a table with table_from_inserts().
+fn ezql_inserts(value_columns: &[KeyString], value: &[EzqlExpr]) -> Result<ColumnTable, EzError> {
+    let rows = match value {
+        [EzqlExpr::Group(rows)] => rows,
+        _ => return Err(query_error(format!("Expected new_values in parentheses but found '{}'", print_sep_list(value, " ")))),
+    };
+
+    // A single row does not need its own parentheses: new_values: (1, apple)
+    let rows: Vec<Vec<EzqlExpr>> = match rows.first().map(|row| row.as_slice()) {
+        Some([EzqlExpr::Group(_)]) => {
+            let mut unwrapped = Vec::with_capacity(rows.len());
+            for row in rows {
+                match row.as_slice() {
+                    [EzqlExpr::Group(values)] => unwrapped.push(values.iter().flatten().cloned().collect()),
+                    other => return Err(query_error(format!("Expected a row in parentheses but found '{}'", print_sep_list(other, " ")))),
+                }
+            }
+            unwrapped
+        },
+        _ => vec![rows.iter().flatten().cloned().collect()],
+    };
+
+    let mut csv = String::new();
+    for row in &rows {
+        if row.len() != value_columns.len() {
+            return Err(query_error(format!("Row '({})' has {} values but there are {} value_columns", print_sep_list(row, ", "), row.len(), value_columns.len())))
+        }
+        let values: Vec<String> = row.iter().map(|expr| ezql_keystring(expr, "value").map(|value| value.to_string())).collect::<Result<_, _>>()?;
+        csv.push_str(&values.join(";"));
+        csv.push('\n');
+    }
+    csv.pop();
+
+    table_from_inserts(value_columns, &csv, "inserts")
+}
+
+fn ezql_query(query_type: &str, elements: Vec<Vec<EzqlExpr>>) -> Result<Query, EzError> {
+
+    let mut args = EzqlArgs::new(query_type, elements)?;
+    let all = || vec![EzqlExpr::Word("*".to_owned())];
+    let empty = || vec![EzqlExpr::Group(Vec::new())];
+
+    let query = match query_type {
+        "SELECT" => Query::SELECT {
+            table_name: ezql_single_keystring(&args.required(&["table_name"])?, "table name")?,
+            primary_keys: ezql_primary_keys(&args.optional(&["primary_keys"]).unwrap_or_else(all))?,
+            columns: ezql_select_columns(&args.optional(&["columns"]).unwrap_or_else(all))?,
+            conditions: ezql_conditions(&args.optional(&["conditions"]).unwrap_or_else(empty))?,
+            distinct: match args.optional(&["distinct"]).as_deref() {
+                None => false,
+                Some([EzqlExpr::Word(flag)]) if flag == "true" => true,
+                Some([EzqlExpr::Word(flag)]) if flag == "false" => false,
+                Some(other) => return Err(query_error(format!("distinct is either true or false but found '{}'", print_sep_list(other, " ")))),
+            },
+            limit: match args.optional(&["limit"]).as_deref() {
+                None => None,
+                Some([EzqlExpr::Word(limit)]) => match limit.parse::<usize>() {
+                    Ok(limit) => Some(limit),
+                    Err(_) => return Err(query_error(format!("limit is a number of rows but found '{}'", limit))),
+                },
+                Some(other) => return Err(query_error(format!("limit is a number of rows but found '{}'", print_sep_list(other, " ")))),
+            },
+        },
+        "UPDATE" => Query::UPDATE {
+            table_name: ezql_single_keystring(&args.required(&["table_name"])?, "table name")?,
+            primary_keys: ezql_primary_keys(&args.optional(&["primary_keys"]).unwrap_or_else(all))?,
+            conditions: ezql_conditions(&args.optional(&["conditions"]).unwrap_or_else(empty))?,
+            updates: ezql_updates(&args.required(&["updates"])?)?,
+            version: match args.optional(&["version"]).as_deref() {
+                None => None,
+                Some([EzqlExpr::Word(version)]) => match version.parse::<i64>() {
+                    Ok(version) => Some(version),
+                    Err(_) => return Err(query_error(format!("version is a row version number but found '{}'", version))),
+                },
+                Some(other) => return Err(query_error(format!("version is a row version number but found '{}'", print_sep_list(other, " ")))),
+            },
+        },
+        "DELETE" => Query::DELETE {
+            table_name: ezql_single_keystring(&args.required(&["table_name"])?, "table name")?,
+            primary_keys: ezql_primary_keys(&args.optional(&["primary_keys"]).unwrap_or_else(all))?,
+            conditions: ezql_conditions(&args.optional(&["conditions"]).unwrap_or_else(empty))?,
+        },
+        "INSERT" => {
+            let table_name = ezql_single_keystring(&args.required(&["table_name"])?, "table name")?;
+            let value_columns = ezql_name_list(&args.required(&["value_columns"])?, "value_columns")?;
+            let inserts = ezql_inserts(&value_columns, &args.required(&["new_values"])?)?;
+            let on_conflict = match args.optional(&["on_conflict"]).as_deref() {
+                None => OnConflict::Skip,
+                Some([EzqlExpr::Word(policy)]) => OnConflict::from_name(policy)?,
+                Some(other) => return Err(query_error(format!("on_conflict is skip, error or overwrite but found '{}'", print_sep_list(other, " ")))),
+            };
+            Query::INSERT { table_name, inserts, on_conflict }
+        },
+        "SUMMARY" => Query::SUMMARY {
+            table_name: ezql_single_keystring(&args.required(&["table_name"])?, "table name")?,
+            columns: ezql_statistics(&args.required(&["columns", "stats"])?)?,
+        },
+        "MULTI_SUMMARY" => Query::MULTI_SUMMARY {
+            tables: ezql_name_list(&args.required(&["tables"])?, "tables")?,
+            columns: ezql_statistics(&args.required(&["columns", "stats"])?)?,
+        },
+        "LEFT_JOIN" => {
+            let match_columns = ezql_name_list(&args.required(&["match_columns"])?, "match_columns")?;
+            if match_columns.len() != 2 {
+                return Err(query_error(format!("match_columns takes exactly 2 columns but {} were given", match_columns.len())))
+            }
+            Query::LEFT_JOIN {
+                left_table_name: ezql_single_keystring(&args.required(&["left_table", "left_table_name"])?, "table name")?,
+                right_table_name: ezql_single_keystring(&args.required(&["right_table", "right_table_name"])?, "table name")?,
+                match_columns: (match_columns[0], match_columns[1]),
+                primary_keys: ezql_primary_keys(&args.optional(&["primary_keys"]).unwrap_or_else(all))?,
+            }
+        },
+        "CREATE" => {
+            let table_name = ezql_single_keystring(&args.required(&["table_name"])?, "table name")?;
+            let csv = match args.required(&["table", "header"])?.as_slice() {
+                [EzqlExpr::Quoted(csv)] => csv.clone(),
+                other => return Err(query_error(format!("CREATE takes the table as a quoted EZ CSV string but found '{}'", print_sep_list(other, " ")))),
+            };
+            let mut table = ColumnTable::from_csv_string(&csv, table_name.as_str(), "ezql")?;
+            match args.optional(&["row_timestamps"]).as_deref() {
+                None => (),
+                Some([EzqlExpr::Word(flag)]) if flag == "true" => table.add_row_timestamps()?,
+                Some([EzqlExpr::Word(flag)]) if flag == "false" => (),
+                Some(other) => return Err(query_error(format!("row_timestamps is either true or false but found '{}'", print_sep_list(other, " ")))),
+            }
+            match args.optional(&["row_ids"]).as_deref() {
+                None => (),
+                Some([EzqlExpr::Word(flag)]) if flag == "true" => table.add_row_ids()?,
+                Some([EzqlExpr::Word(flag)]) if flag == "false" => (),
+                Some(other) => return Err(query_error(format!("row_ids is either true or false but found '{}'", print_sep_list(other, " ")))),
+            }
+            match args.optional(&["row_versions"]).as_deref() {
+                None => (),
+                Some([EzqlExpr::Word(flag)]) if flag == "true" => table.add_row_versions()?,
+                Some([EzqlExpr::Word(flag)]) if flag == "false" => (),
+                Some(other) => return Err(query_error(format!("row_versions is either true or false but found '{}'", print_sep_list(other, " ")))),
+            }
+            let engine = match args.optional(&["engine"]).as_deref() {
+                None => TableEngine::Column,
+                Some([EzqlExpr::Word(engine)]) => TableEngine::from_name(engine)?,
+                Some(other) => return Err(query_error(format!("engine is either column or row but found '{}'", print_sep_list(other, " ")))),
+            };
+            Query::CREATE { table, engine }
+        },
+        "CREATE_FROM_SCHEMA" => {
+            let schema = match args.required(&["schema"])?.as_slice() {
+                [EzqlExpr::Quoted(schema)] => schema.clone(),
+                other => return Err(query_error(format!("CREATE_FROM_SCHEMA takes the schema as a quoted string but found '{}'", print_sep_list(other, " ")))),
+            };
+            Query::CREATE_FROM_SCHEMA { tables: parse_schema(&schema)? }
+        },
+        "DROP" => Query::DROP {
+            table_name: ezql_single_keystring(&args.required(&["table_name"])?, "table name")?,
+        },
+        "ALTER_TABLE" => {
+            let table_name = ezql_single_keystring(&args.required(&["table_name"])?, "table name")?;
+            let pair = |value: Vec<EzqlExpr>, what: &str| -> Result<(KeyString, KeyString), EzError> {
+                match ezql_name_list(&value, what)?.as_slice() {
+                    [first, second] => Ok((*first, *second)),
+                    other => Err(query_error(format!("{} takes exactly 2 names but {} were given", what, other.len()))),
+                }
+            };
+            let alterations = [
+                args.optional(&["add_column"]).map(|value| match (value.as_slice(), args.required(&["default"])) {
+                    ([EzqlExpr::Quoted(column)], Ok(default)) => match default.as_slice() {
+                        [default] => Ok(Alteration::AddColumn { column: parse_header_item(column)?, default: ezql_value(default)? }),
+                        other => Err(query_error(format!("Expected a single default value but found '{}'", print_sep_list(other, " ")))),
+                    },
+                    (_, Err(e)) => Err(e),
+                    (other, _) => Err(query_error(format!("add_column takes the column as a quoted EZ CSV header like \"stock,i-N\" but found '{}'", print_sep_list(other, " ")))),
+                }),
+                args.optional(&["drop_column"]).map(|value| Ok(Alteration::DropColumn { column: ezql_single_keystring(&value, "column name")? })),
+                args.optional(&["rename_column"]).map(|value| pair(value, "rename_column").map(|(from, to)| Alteration::RenameColumn { from, to })),
+                args.optional(&["change_type"]).map(|value| {
+                    let (column, kind) = pair(value, "change_type")?;
+                    Ok(Alteration::ChangeType { column, kind: DbType::from_name(kind.as_str()).map_err(|e| query_error(e.text))? })
+                }),
+            ];
+            let mut given = alterations.into_iter().flatten();
+            let alteration = match (given.next(), given.next()) {
+                (Some(alteration), None) => alteration?,
+                _ => return Err(query_error("ALTER_TABLE takes exactly one of add_column, drop_column, rename_column or change_type".to_owned())),
+            };
+            Query::ALTER_TABLE { table_name, alteration }
+        },
+        "DEDUPLICATE" => Query::DEDUPLICATE {
+            table_name: ezql_single_keystring(&args.required(&["table_name"])?, "table name")?,
+        },
+        "INFER_SCHEMA" => {
+            let table_name = ezql_single_keystring(&args.required(&["table_name"])?, "table name")?;
+            let sample = match args.required(&["sample"])?.as_slice() {
+                [EzqlExpr::Quoted(sample)] => sample.clone(),
+                other => return Err(query_error(format!("INFER_SCHEMA takes the sample as a quoted string but found '{}'", print_sep_list(other, " ")))),
+            };
+            Query::INFER_SCHEMA { table_name, sample }
+        },
+        "DESCRIBE" => Query::DESCRIBE {
+            table_name: ezql_single_keystring(&args.required(&["table_name"])?, "table name")?,
+        },
+        "PREPARE" => {
+            let text = match args.required(&["query"])?.as_slice() {
+                [EzqlExpr::Quoted(text)] => text.clone(),
+                other => return Err(query_error(format!("PREPARE takes the query as a quoted string but found '{}'", print_sep_list(other, " ")))),
+            };
+            Query::PREPARE { query: Box::new(text.parse()?) }
+        },
+        "EXPLAIN" => {
+            let text = match args.required(&["query"])?.as_slice() {
+                [EzqlExpr::Quoted(text)] => text.clone(),
+                other => return Err(query_error(format!("EXPLAIN takes the query as a quoted string but found '{}'", print_sep_list(other, " ")))),
+            };
+            Query::EXPLAIN { query: Box::new(text.parse()?) }
+        },
+        "EXECUTE" => {
+            let handle = match args.required(&["handle"])?.as_slice() {
+                [EzqlExpr::Word(word)] => match word.parse::<u64>() {
+                    Ok(handle) => handle,
+                    Err(_) => return Err(query_error(format!("The handle of EXECUTE is a number but found '{}'", word))),
+                },
+                other => return Err(query_error(format!("The handle of EXECUTE is a number but found '{}'", print_sep_list(other, " ")))),
+            };
+            let params = match args.optional(&["params"]).as_deref() {
+                None => Vec::new(),
+                Some([EzqlExpr::Group(elements)]) => {
+                    let mut params = Vec::with_capacity(elements.len());
+                    for element in elements {
+                        match element.as_slice() {
+                            [expr] => params.push(ezql_value(expr)?),
+                            other => return Err(query_error(format!("Expected a single parameter value but found '{}'", print_sep_list(other, " ")))),
+                        }
+                    }
+                    params
+                },
+                Some(other) => return Err(query_error(format!("EXECUTE takes its params in parentheses but found '{}'", print_sep_list(other, " ")))),
+            };
+            Query::EXECUTE { handle, params }
+        },
+        "HELP" => Query::HELP,
+        other => {
+            // Gives the same error as the binary parser for the known but unimplemented joins
+            Query::blank(other)?;
+            return Err(query_error(format!("Query type '{}' can not be written as text yet", other)))
+        },
+    };
+    // SELECT and SUMMARY can store their result on the server instead of sending it back
+    let query = if matches!(query, Query::SELECT{..} | Query::SUMMARY{..}) {
+        match (args.optional(&["into_table"]), args.optional(&["into_value"])) {
+            (None, None) => query,
+            (Some(name), None) => Query::INTO { query: Box::new(query), target: IntoTarget::Table(ezql_single_keystring(&name, "table name")?) },
+            (None, Some(key)) => Query::INTO { query: Box::new(query), target: IntoTarget::Value(ezql_single_keystring(&key, "key")?) },
+            (Some(_), Some(_)) => return Err(query_error("A result goes either into_table or into_value, not both".to_owned())),
+        }
+    } else {
+        query
+    };
+    args.finish()?;
+
+    Ok(query)
+}
+
+/// Parses a chain of textual EZQL queries separated by "->". See EZQL.txt for the syntax.
+/// Each query after the first runs on the result of the one before it.
+#[allow(non_snake_case)]
+pub fn parse_EZQL(s: &str) -> Result<Vec<Query>, EzError> {
+    // println!("calling: parse_EZQL()");
+
+    let tokens = tokenize_ezql(s)?;
+    if tokens.is_empty() {
+        return Err(query_error("Query is empty".to_owned()))
+    }
+
+    let mut queries = Vec::new();
+    let mut i = 0;
+    loop {
+        let query_type = match tokens.get(i) {
+            Some((_, EzqlToken::Word(word))) => word.to_uppercase(),
+            Some((offset, token)) => return Err(query_error(format!("Expected a query type such as SELECT at position {} but found {:?}", offset, token))),
+            None => return Err(query_error("Expected a query after '->'".to_owned())),
+        };
+        let open_position = match tokens.get(i + 1) {
+            Some((offset, EzqlToken::Open)) => *offset,
+            _ => return Err(query_error(format!("Expected '(' after '{}'", query_type))),
+        };
+        i += 2;
+        let elements = parse_ezql_group(&tokens, &mut i, open_position)?;
+        if queries.len() == MAX_BATCH_QUERIES {
+            return Err(too_many_queries())
+        }
+        queries.push(ezql_query(&query_type, elements)?);
+
+        match tokens.get(i) {
+            None => break,
+            Some((_, EzqlToken::Arrow)) => i += 1,
+            Some((offset, _)) => return Err(query_error(format!("Unexpected text at position {} after {}(...). Chain queries with '->'", offset, query_type))),
+        }
+    }
+
+    Ok(queries)
+}
+
+impl FromStr for Query {
+    type Err = EzError;
+
+    /// Parses a single textual EZQL query. Use parse_EZQL() for chains.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut queries = parse_EZQL(s)?;
+        match queries.len() {
+            1 => Ok(queries.remove(0)),
+            n => Err(query_error(format!("Expected a single query but found a chain of {}. Use parse_EZQL() for chains", n))),
+        }
+    }
+}
+
+pub fn execute_kv_queries(kv_queries: Vec<KvQuery>, database: Arc<Database>) -> Vec<Result<Option<Value>, EzError>> {
+
+    let now = get_current_time();
+    database.buffer_pool.expire_values(now);
+
+    let mut result_values = Vec::new();
+
+    for query in kv_queries {
+        match query {
+            KvQuery::Create(key_string, vec) => {
+                let value = Value{
+                    name: key_string,
+                    body: vec,
+                };
+                match database.buffer_pool.add_value(value) {
+                    Ok(_) => result_values.push(Ok(None)),
+                    Err(e) => result_values.push(Err(e)),
+                };
+            },
+            KvQuery::Read(key_string) => {
+                match database.buffer_pool.values.read().unwrap().get(&key_string) {
+                    Some(v) => {
+                        result_values.push(Ok(Some(v.clone())));
+                    },
+                    None => result_values.push(Err(EzError{tag: ErrorTag::NotFound, text: format!("No value corresponds to key: '{}'", key_string)}))
+                };
+            },
+            KvQuery::Update(key_string, vec) => {
+                let value = Value{
+                    name: key_string,
+                    body: vec,
+                };
+
+                match database.buffer_pool.update_value(value) {
+                    Ok(_) => result_values.push(Ok(None)),
+                    Err(e) => result_values.push(Err(e)),
+                }
+            },
+            KvQuery::Delete(key_string) => {
+                match database.buffer_pool.remove_value(&key_string) {
+                    Ok(v) => result_values.push(Ok(Some(v))),
+                    Err(e) => result_values.push(Err(e)),
+                };
+            },
+            KvQuery::ReadVersion(key_string, version) => {
+                match database.buffer_pool.read_value_version(&key_string, version) {
+                    Ok(v) => result_values.push(Ok(Some(v))),
+                    Err(e) => result_values.push(Err(e)),
+                };
+            },
+            KvQuery::Rollback(key_string, version) => {
+                match database.buffer_pool.rollback_value(&key_string, version) {
+                    Ok(v) => result_values.push(Ok(Some(v))),
+                    Err(e) => result_values.push(Err(e)),
+                };
+            },
+            KvQuery::ReadMany(keys) => {
+                let values = database.buffer_pool.values.read().unwrap();
+                for key_string in keys {
+                    match values.get(&key_string) {
+                        Some(v) => result_values.push(Ok(Some(v.clone()))),
+                        None => result_values.push(Err(EzError{tag: ErrorTag::NotFound, text: format!("No value corresponds to key: '{}'", key_string)})),
+                    }
+                }
+            },
+            KvQuery::WriteMany(pairs) => {
+                for (key_string, vec) in pairs {
+                    match database.buffer_pool.put_value(Value{name: key_string, body: vec}) {
+                        Ok(_) => result_values.push(Ok(None)),
+                        Err(e) => result_values.push(Err(e)),
+                    }
+                }
+            },
+            KvQuery::CreateWithTtl(key_string, vec, ttl) => {
+                if ttl == 0 {
+                    result_values.push(Err(EzError{tag: ErrorTag::Query, text: "A TTL must be at least 1 second".to_owned()}));
+                    continue
+                }
+                let result = database.buffer_pool.add_value(Value{name: key_string, body: vec})
+                    .and_then(|_| database.buffer_pool.set_value_ttl(&key_string, ttl, now));
+                match result {
+                    Ok(_) => result_values.push(Ok(None)),
+                    Err(e) => result_values.push(Err(e)),
+                }
+            },
+            KvQuery::UpdateWithTtl(key_string, vec, ttl) => {
+                if ttl == 0 {
+                    result_values.push(Err(EzError{tag: ErrorTag::Query, text: "A TTL must be at least 1 second".to_owned()}));
+                    continue
+                }
+                let result = database.buffer_pool.update_value(Value{name: key_string, body: vec})
+                    .and_then(|_| database.buffer_pool.set_value_ttl(&key_string, ttl, now));
+                match result {
+                    Ok(_) => result_values.push(Ok(None)),
+                    Err(e) => result_values.push(Err(e)),
+                }
+            },
+            KvQuery::Scan(prefix, filter) => {
+                for value in database.buffer_pool.scan_values(&prefix, &filter, now) {
+                    result_values.push(Ok(Some(value)));
+                }
+            },
+        }
+    }
+
+    result_values
+
+}
+
+/// Reads back any table the batch refers to that was unloaded for being idle.
+pub fn reload_unloaded_tables(queries: &[Query], database: &Database) -> Result<(), EzError> {
+    for query in queries {
+        match query {
+            Query::INNER_JOIN | Query::RIGHT_JOIN | Query::FULL_JOIN => (),
+            // Answered from the stub of an unloaded table
+            Query::DESCRIBE { .. } => (),
+            Query::PREPARE { .. } | Query::EXECUTE { .. } | Query::HELP => (),
+            // Planned from what is in memory. Unloaded tables are described from their stubs
+            Query::EXPLAIN { .. } => (),
+            Query::LEFT_JOIN { left_table_name, right_table_name, .. } => {
+                ensure_stored_loaded(left_table_name, query, database)?;
+                ensure_stored_loaded(right_table_name, query, database)?;
+            },
+            Query::MULTI_SUMMARY { tables, .. } => {
+                for table_name in resolve_table_names(tables, database)? {
+                    database.buffer_pool.ensure_loaded(&table_name)?;
+                    database.buffer_pool.merge_row_buffer(&table_name)?;
+                }
+            },
+            other => {
+                ensure_stored_loaded(&other.get_table_name(), other, database)?;
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Reloads the table if it was unloaded. Only the partitions the query touches are reloaded for a partitioned table.
+fn ensure_stored_loaded(table_name: &KeyString, query: &Query, database: &Database) -> Result<(), EzError> {
+    match database.buffer_pool.partition_map(table_name) {
+        Some(map) => for partition in partition_names(table_name, &map.touched_by(query))? {
+            database.buffer_pool.ensure_loaded(&partition)?;
+        },
+        None => {
+            database.buffer_pool.ensure_loaded(table_name)?;
+            // Everything but another INSERT has to see the rows waiting in the row buffer
+            if !matches!(query, Query::INSERT{..}) {
+                database.buffer_pool.merge_row_buffer(table_name)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+#[allow(non_snake_case)]
+pub fn execute_EZQL_queries(queries: Vec<Query>, database: Arc<Database>) -> Result<Option<ColumnTable>, EzError> {
+    // println!("calling: execute_EZQL_queries()");
+
+    // A lookup of keys the key filter rules out matches nothing, so the table is neither reloaded nor searched
+    if let [query @ Query::SELECT{ table_name, primary_keys, .. }] = queries.as_slice() {
+        if database.buffer_pool.rules_out_keys(table_name, primary_keys) {
+            if let Some(header) = database.buffer_pool.table_header(table_name) {
+                return execute_select_query(query, &ColumnTable::blank(&header, *table_name, "key filter"))
+            }
+        }
+    }
+
+    reload_unloaded_tables(&queries, &database)?;
+
+    // Read only batches run against a snapshot so they neither block writers for long nor see torn state
+    if is_read_only_batch(&queries) {
+        return execute_snapshot_queries(queries, &database)
+    }
+
+    let mut result_table = None;
+    for query in queries.into_iter() {
+        check_cancelled()?;
+
+        match &query {
+            Query::SELECT { .. } | Query::SUMMARY { .. } | Query::MULTI_SUMMARY { .. } | Query::LEFT_JOIN { .. } => (),
+            Query::INNER_JOIN | Query::RIGHT_JOIN | Query::FULL_JOIN => (),
+            Query::INFER_SCHEMA { .. } | Query::DESCRIBE { .. } | Query::HELP | Query::EXPLAIN { .. } => (),
+            Query::PREPARE { .. } | Query::EXECUTE { .. } => (),
+            Query::INTO { target, .. } => if is_system_table(&target.name()) {
+                return Err(EzError{tag: ErrorTag::Query, text: format!("System table '{}' is read only", target.name())})
+            },
+            other => if is_system_table(&other.get_table_name()) {
+                return Err(EzError{tag: ErrorTag::Query, text: format!("System table '{}' is read only", other.get_table_name())})
+            },
+        }
+        check_quota(&query, &database)?;
+        // An INSERT earlier in the batch may have left rows in a row buffer that this query has to see
+        reload_unloaded_tables(std::slice::from_ref(&query), &database)?;
+
+        match &query {
+            Query::DELETE{ .. } => {
+                match result_table {
+                    Some(mut table) => result_table = execute_delete_query(query, &mut table)?,
+                    None => {
+                        write_to_table(query, &database)?;
+                        result_table = None;
+                    },
+                }
+                
+            },
+            Query::SELECT{ table_name, primary_keys: _, columns: _, conditions: _, distinct: _, limit: _ } => {
+                match result_table {
+                    Some(mut table) => result_table = execute_select_query(&query, &mut table)?,
+                    None => {
+                        println!("table name: {}", table_name);
+                        if is_system_table(table_name) {
+                            let table = materialize_system_table(table_name, &database)?;
+                            result_table = execute_select_query(&query, &table)?;
+                            continue
+                        }
+                        // Put together from the partitions the query touches
+                        if database.buffer_pool.partition_map(table_name).is_some() {
+                            let snapshot = Snapshot::take(std::slice::from_ref(&query), &database)?;
+                            result_table = execute_select_query(&query, snapshot.get(table_name)?)?;
+                            continue
+                        }
+                        let tables = database.buffer_pool.tables.read().unwrap();
+                        let table = database.locks.read_table(*table_name, tables.get(table_name).unwrap())?;
+                        result_table = execute_select_query(&query, &table)?;
+                    },
+                }
+            },
+            Query::LEFT_JOIN{ left_table_name, right_table_name, match_columns: _, primary_keys: _ } => {
+                // A snapshot puts partitions back together and materializes system tables
+                let needs_snapshot = |name: &KeyString| is_system_table(name) || database.buffer_pool.partition_map(name).is_some();
+                if needs_snapshot(left_table_name) || needs_snapshot(right_table_name) {
+                    let snapshot = Snapshot::take(std::slice::from_ref(&query), &database)?;
+                    let right_table = snapshot.get(right_table_name)?;
+                    result_table = match &result_table {
+                        Some(table) => execute_left_join_query(query.clone(), table, right_table)?,
+                        None => execute_left_join_query(query.clone(), snapshot.get(left_table_name)?, right_table)?,
+                    };
+                    continue
+                }
+                let tables = database.buffer_pool.tables.read().unwrap();
+                let stored = |name: &KeyString| match tables.get(name) {
+                    Some(table) => database.locks.read_table(*name, table),
+                    None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named '{}'", name)}),
+                };
+                result_table = match &result_table {
+                    Some(table) => execute_left_join_query(query.clone(), table, &*stored(right_table_name)?)?,
+                    None => execute_left_join_query(query.clone(), &*stored(left_table_name)?, &*stored(right_table_name)?)?,
+                };
+            },
+            Query::INNER_JOIN => {
+                unimplemented!("Inner joins are not yet implemented");
+                // execute_inner_join_query(query, database);
+            },
+            Query::RIGHT_JOIN => {
+                unimplemented!("Right joins are not yet implemented");
+
+                // execute_right_join_query(query, database);
+            },
+            Query::FULL_JOIN => {
+                unimplemented!("Full joins are not yet implemented");
+
+                // execute_full_join_query(query, database);
+            },
+            Query::UPDATE{ .. } => {
+                match result_table {
+                    Some(mut table) => result_table = execute_update_query(query, &mut table)?,
+                    None => {
+                        write_to_table(query, &database)?;
+                        result_table = None;
+                    },
+                }
+            },
+            Query::INSERT{ .. } => {
+                match result_table {
+                    Some(mut table) => result_table = execute_insert_query(query, &mut table)?,
+                    None => {
+                        write_to_table(query, &database)?;
+                        result_table = None;
+                    },
+                }
+            },
+            
+            Query::SUMMARY { table_name, columns } => {
+                match result_table {
+                    Some(table) => {
+                        let result = execute_summary_query(&query, &table)?;
+                        match result {
+                            Some(s) => return Ok(Some(s)),
+                            None => return Err(EzError{tag: ErrorTag::Query, text: format!("SUMMARY of '{}' produced no table", table_name)}),
+                        };
+                    },
+                    None => {
+                        if is_system_table(table_name) {
+                            let table = materialize_system_table(table_name, &database)?;
+                            let result = execute_summary_query(&query, &table)?;
+                            match result {
+                                Some(s) => return Ok(Some(s)),
+                                None => return Err(EzError{tag: ErrorTag::Query, text: format!("SUMMARY of '{}' produced no table", table_name)}),
+                            };
+                        }
+                        if database.buffer_pool.partition_map(table_name).is_some() {
+                            let snapshot = Snapshot::take(std::slice::from_ref(&query), &database)?;
+                            return execute_summary_query(&query, snapshot.get(table_name)?)
+                        }
+                        let tables = database.buffer_pool.tables.read().unwrap();
+                        let table = database.locks.read_table(*table_name, tables.get(table_name).unwrap())?;
+                        let result = execute_summary_query(&query, &table)?;
+                        match result {
+                            Some(s) => return Ok(Some(s)),
+                            None => return Err(EzError{tag: ErrorTag::Query, text: format!("SUMMARY of '{}' produced no table", table_name)}),
+                        };
+                    },
+                }
+            }
+            // Always summarizes the stored tables, never the result of the query before it
+            Query::MULTI_SUMMARY { .. } => {
+                result_table = Some(execute_multi_summary_query(&query, &database)?);
+            },
+            Query::DEDUPLICATE { .. } => {
+                match result_table {
+                    // Deduplicating leaves the rows in place so the chain goes on with the same table
+                    Some(mut table) => {
+                        execute_deduplicate_query(query, &mut table)?;
+                        result_table = Some(table);
+                    },
+                    None => {
+                        write_to_table(query, &database)?;
+                        result_table = None;
+                    },
+                }
+            },
+            Query::CREATE { .. } | Query::CREATE_FROM_SCHEMA { .. } | Query::DROP { .. } | Query::ALTER_TABLE { .. } => {
+                write_to_table(query, &database)?;
+                result_table = None;
+            },
+            Query::INFER_SCHEMA { table_name, sample } => {
+                result_table = Some(infer_schema(sample, table_name.as_str())?);
+            },
+            Query::DESCRIBE { table_name } => {
+                let schema = match &result_table {
+                    Some(table) => table.schema(),
+                    None => describe_stored_table(table_name, &database)?,
+                };
+                result_table = Some(schema.to_table()?);
+            },
+            Query::HELP => {
+                result_table = Some(help_table()?);
+            },
+            Query::EXPLAIN { query: inner } => {
+                result_table = Some(explain_query(inner, result_table.as_ref(), &database.buffer_pool)?);
+            },
+            // Prepared queries belong to a connection so they are handled where the batch arrives. See answer_query()
+            Query::PREPARE { .. } | Query::EXECUTE { .. } => {
+                return Err(EzError{tag: ErrorTag::Query, text: "PREPARE and EXECUTE can only be sent over a connection".to_owned()})
+            },
+            Query::INTO { query: inner, target } => {
+                let result = match result_table.take() {
+                    Some(table) => match **inner {
+                        Query::SUMMARY { .. } => execute_summary_query(inner, &table)?,
+                        _ => execute_select_query(inner, &table)?,
+                    },
+                    None => execute_EZQL_queries(vec![(**inner).clone()], database.clone())?,
+                };
+                let rows = match result {
+                    Some(result) => store_query_result(result, target, &database)?,
+                    None => return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' gave no result to store", inner)}),
+                };
+                result_table = Some(into_result_table(target, rows)?);
+            },
+        }
+    }
+
+    match result_table {
+        Some(table) => Ok(Some(table)),
+        None => Ok(None),
+    }
+}
+
+
+/// Stores the result of an INTO query and returns how many rows it has.
+pub fn store_query_result(mut result: ColumnTable, target: &IntoTarget, database: &Database) -> Result<usize, EzError> {
+    let rows = result.len();
+    match target {
+        IntoTarget::Table(table_name) => {
+            result.name = *table_name;
+            let create = Query::CREATE { table: result, engine: TableEngine::Column };
+            check_quota(&create, database)?;
+            write_to_table(create, database)?;
+        },
+        IntoTarget::Value(key) => database.buffer_pool.put_value(Value{name: *key, body: result.to_binary()})?,
+    }
+    Ok(rows)
+}
+
+/// The answer to an INTO query. One row with where the result went and how many rows it has.
+pub fn into_result_table(target: &IntoTarget, rows: usize) -> Result<ColumnTable, EzError> {
+    let kind = match target {
+        IntoTarget::Table(_) => "table",
+        IntoTarget::Value(_) => "value",
+    };
+    let mut table = ColumnTable::create_empty("into", "system");
+    table.add_column(ksf("target"), DbColumn::Texts(vec![target.name()]))?;
+    table.add_column(ksf("kind"), DbColumn::Texts(vec![ksf(kind)]))?;
+    table.add_column(ksf("rows"), DbColumn::Ints(vec![rows as i32]))?;
+    Ok(table)
+}
+
+/// The schema of a stored table, a system table, or an unloaded table. Unloaded tables are not reloaded.
+pub fn describe_stored_table(table_name: &KeyString, database: &Database) -> Result<TableSchema, EzError> {
+    if is_system_table(table_name) {
+        return Ok(materialize_system_table(table_name, database)?.schema())
+    }
+    // Every partition has the columns of the table
+    if database.buffer_pool.partition_map(table_name).is_some() {
+        let mut schema = describe_stored_table(&partition_name(table_name, 0)?, database)?;
+        schema.name = *table_name;
+        return Ok(schema)
+    }
+    if let Some(table) = database.buffer_pool.tables.read().unwrap().get(table_name) {
+        return Ok(database.locks.read_table(*table_name, table)?.schema())
+    }
+    match database.buffer_pool.unloaded_tables.read().unwrap().get(table_name) {
+        Some(stub) => Ok(TableSchema::from_header(*table_name, &stub.header)),
+        None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named '{}'", table_name)}),
+    }
+}
+
+/// Whether every query in the batch only writes. Write batches are answered with a WriteAck instead of a table.
+pub fn is_write_batch(queries: &[Query]) -> bool {
+    !queries.is_empty() && queries.iter().all(|query| matches!(query,
+        Query::CREATE{..} | Query::CREATE_FROM_SCHEMA{..} | Query::DROP{..} | Query::UPDATE{..} | Query::INSERT{..} | Query::DELETE{..} | Query::DEDUPLICATE{..}
+            | Query::ALTER_TABLE{..}
+    ))
+}
+
+/// Runs a single write query against the stored tables and returns the number of rows it affected.
+/// CREATE counts the rows of the new table. CREATE_FROM_SCHEMA counts the tables it created. DROP counts nothing.
+/// ALTER_TABLE counts every row of the table.
+pub fn write_to_table(query: Query, database: &Database) -> Result<u64, EzError> {
+
+    match query {
+        Query::CREATE { table, engine } => {
+            check_nan_ingest(&table)?;
+            if engine == TableEngine::Row {
+                RowTable::check_header(&table.header)?;
+            }
+            let (table_name, rows) = (table.name, table.len());
+            database.buffer_pool.add_table(table)?;
+            if engine == TableEngine::Row {
+                database.buffer_pool.use_row_engine(table_name)?;
+            }
+            Ok(rows as u64)
+        },
+        Query::CREATE_FROM_SCHEMA { tables } => {
+            if let Some(table) = tables.iter().find(|table| is_system_table(&table.name)) {
+                return Err(EzError{tag: ErrorTag::Query, text: format!("System table '{}' is read only", table.name)})
+            }
+            let created = database.buffer_pool.create_missing_tables(tables)?;
+            Ok(created.len() as u64)
+        },
+        Query::DROP { table_name } => {
+            database.buffer_pool.drop_table(table_name)?;
+            Ok(0)
+        },
+        query => {
+            let table_name = query.get_table_name();
+            if let Some(map) = database.buffer_pool.partition_map(&table_name) {
+                return write_to_partitions(query, &map, database)
+            }
+            if let Query::INSERT { on_conflict, .. } = &query {
+                validate_name(table_name.as_str())?;
+                if database.buffer_pool.uses_row_engine(&table_name) {
+                    // The row buffer is merged with OnConflict::Skip, so the other policies write to the table
+                    // once the rows waiting in the buffer are in it
+                    match on_conflict {
+                        OnConflict::Skip => return insert_into_row_buffer(query, database),
+                        _ => { database.buffer_pool.merge_row_buffer(&table_name)?; },
+                    }
+                }
+            }
+            let tables = database.buffer_pool.tables.read().unwrap();
+            let mut table = match tables.get(&table_name) {
+                Some(table) => database.locks.write_table(table_name, table)?,
+                None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named '{}'", table_name)}),
+            };
+            table.metadata.touch();
+            let before = table.len();
+            let buffer_pool = &database.buffer_pool;
+            let primary_key = table.get_primary_key_col_index();
+            // Changing primary keys in place or altering the table leaves keys the filter can't follow
+            let rekeyed = match &query {
+                Query::UPDATE { updates, .. } => updates.iter().any(|update| update.attribute == primary_key),
+                Query::ALTER_TABLE { .. } => true,
+                _ => false,
+            };
+            let affected = match query {
+                Query::UPDATE { .. } => {
+                    let rows = update_rows_at(query, &mut table)?;
+                    buffer_pool.mark_rows_changed(table.name, |dirty| dirty.mark_rows(&rows));
+                    rows.len()
+                },
+                Query::INSERT { ref inserts, on_conflict, .. } => {
+                    let rows = inserts.len();
+                    let first = first_insert_row(&table, inserts);
+                    buffer_pool.add_filter_keys(&table_name, &primary_key, inserts);
+                    execute_insert_query(query, &mut table)?;
+                    buffer_pool.mark_rows_changed(table.name, |dirty| dirty.mark_from(first));
+                    // Skipped rows are not counted. Overwritten rows are
+                    match on_conflict {
+                        OnConflict::Skip => table.len() - before,
+                        OnConflict::Error | OnConflict::Overwrite => rows,
+                    }
+                },
+                Query::DELETE { .. } => {
+                    if let Some(first) = delete_rows(query, &mut table)? {
+                        buffer_pool.mark_rows_changed(table.name, |dirty| dirty.mark_from(first));
+                    }
+                    before - table.len()
+                },
+                Query::DEDUPLICATE { .. } => {
+                    execute_deduplicate_query(query, &mut table)?;
+                    buffer_pool.mark_table_changed(table.name);
+                    before - table.len()
+                },
+                Query::ALTER_TABLE { ref alteration, .. } => {
+                    alteration.apply(&mut table)?;
+                    buffer_pool.mark_table_changed(table.name);
+                    before
+                },
+                other => return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is not a write query", other)}),
+            };
+            buffer_pool.update_key_filter(&table, before.saturating_sub(table.len()), rekeyed);
+            Ok(affected as u64)
+        },
+    }
+}
+
+/// Checks an INSERT into a row engine table against the table and adds its rows to the row buffer of the table.
+/// See BufferPool::buffer_rows().
+fn insert_into_row_buffer(mut query: Query, database: &Database) -> Result<u64, EzError> {
+    let table_name = query.get_table_name();
+    let (header, primary_key) = {
+        let tables = database.buffer_pool.tables.read().unwrap();
+        let table = match tables.get(&table_name) {
+            Some(table) => database.locks.read_table(table_name, table)?,
+            None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named '{}'", table_name)}),
+        };
+        table.metadata.touch();
+        validate_query(&query, &table)?;
+        encode_enums(&mut query, &table.header)?;
+        (table.header.clone(), table.get_primary_key_col_index())
+    };
+    match query {
+        Query::INSERT { inserts, .. } => {
+            database.buffer_pool.add_filter_keys(&table_name, &primary_key, &inserts);
+            let added = database.buffer_pool.buffer_rows(table_name, &header, &inserts)?;
+            Ok(added as u64)
+        },
+        other => Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to insert_into_row_buffer().\nReceived query: {}", other)}),
+    }
+}
+
+/// Runs a batch of write queries one by one. Execution stops at the first failure.
+/// Queries before it have been applied and queries after it are reported as NotRun.
+pub fn execute_write_queries(queries: Vec<Query>, database: Arc<Database>) -> WriteAck {
+
+    // Every query is checked before any of them runs so a typo late in the batch doesn't leave the earlier writes applied
+    let problems: Vec<Vec<String>> = queries.iter().map(|query| stored_table_problems(query, &database)).collect();
+    if problems.iter().any(|p| !p.is_empty()) {
+        let statuses = problems.iter()
+            .map(|p| QueryAck{status: if p.is_empty() { AckStatus::NotRun } else { AckStatus::Failed }, affected: 0})
+            .collect();
+        return WriteAck{statuses, version: database.versions.applied(), error: problems_to_result(problems.concat()).err()}
+    }
+
+    let mut ack = WriteAck{statuses: Vec::with_capacity(queries.len()), version: 0, error: None};
+    for query in queries {
+        if ack.error.is_some() {
+            ack.statuses.push(QueryAck{status: AckStatus::NotRun, affected: 0});
+            continue
+        }
+        if let Err(e) = check_cancelled() {
+            ack.statuses.push(QueryAck{status: AckStatus::NotRun, affected: 0});
+            ack.error = Some(e);
+            continue
+        }
+        let result = if is_system_table(&query.get_table_name()) {
+            Err(EzError{tag: ErrorTag::Query, text: format!("System table '{}' is read only", query.get_table_name())})
+        } else {
+            reload_unloaded_tables(std::slice::from_ref(&query), &database)
+                .and_then(|_| check_quota(&query, &database))
+                .and_then(|_| write_to_table(query, &database))
+        };
+        match result {
+            Ok(affected) => ack.statuses.push(QueryAck{status: AckStatus::Done, affected}),
+            Err(e) => {
+                ack.statuses.push(QueryAck{status: AckStatus::Failed, affected: 0});
+                ack.error = Some(e);
+            },
+        }
+    }
+    // A batch that stopped partway still applied the queries before the failure
+    ack.version = if ack.statuses.iter().any(|query| query.status == AckStatus::Done) {
+        match database.versions.advance() {
+            Ok(version) => version,
+            Err(e) => {
+                ack.error.get_or_insert(e);
+                database.versions.applied()
+            },
+        }
+    } else {
+        database.versions.applied()
+    };
+
+    ack
+}
+
+pub fn execute_delete_query(query: Query, table: &mut ColumnTable) -> Result<Option<ColumnTable>, EzError> {
     // println!("calling: execute_delete_query()");
-    
+    delete_rows(query, table)?;
+
+    Ok(
+        None
+    )
+}
+
+/// Applies a DELETE and returns the index of the first row it removed, if it removed any.
+pub fn delete_rows(mut query: Query, table: &mut ColumnTable) -> Result<Option<usize>, EzError> {
+    validate_query(&query, table)?;
+    encode_enums(&mut query, &table.header)?;
+
     match query {
         Query::DELETE { primary_keys, table_name: _, conditions } => {
-            let keepers = filter_keepers(&conditions, &primary_keys, table)?;
-            table.delete_by_indexes(&keepers);
-        
+            let keep = delete_keep_mask(&conditions, &primary_keys, table)?;
+            let first = keep.iter().enumerate()
+                .find(|(_, word)| **word != u64::MAX)
+                .map(|(i, word)| i * 64 + (!word).trailing_zeros() as usize)
+                .filter(|row| *row < table.len());
+            table.retain_rows(&keep);
+
+            Ok(first)
+        },
+        other_query => Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to delete_rows() function.\nReceived query: {}", other_query)}),
+    }
+}
+
+pub fn execute_deduplicate_query(query: Query, table: &mut ColumnTable) -> Result<Option<ColumnTable>, EzError> {
+    // println!("calling: execute_deduplicate_query()");
+
+    match query {
+        Query::DEDUPLICATE { table_name: _ } => {
+            table.deduplicate();
+
             Ok(
                 None
             )
         },
-        other_query => return Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to execute_delete_query() function.\nReceived query: {}", other_query)}),
+        other_query => Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to execute_deduplicate_query() function.\nReceived query: {}", other_query)}),
     }
-
 }
 
 pub fn execute_left_join_query(query: Query, left_table: &ColumnTable, right_table: &ColumnTable) -> Result<Option<ColumnTable>, EzError> {
     // println!("calling: execute_left_join_query()");
+    let _phase = alloc_stats::enter(AllocPhase::Join);
     
     match query {
-        Query::LEFT_JOIN { left_table_name: _, right_table_name: _, match_columns, primary_keys } => {
-            let filtered_indexes = keys_to_indexes(left_table, &primary_keys)?;
+        Query::LEFT_JOIN { left_table_name: _, right_table_name: _, ref match_columns, ref primary_keys } => {
+            let mut problems = query_problems(&query, left_table);
+            if !right_table.columns.contains_key(&match_columns.1) {
+                problems.push(format!("Table '{}' has no column '{}' to join on", right_table.name, match_columns.1));
+            }
+            problems_to_result(problems)?;
+            let filtered_indexes = keys_to_indexes(left_table, primary_keys)?;
             let mut filtered_table = left_table.subtable_from_indexes(&filtered_indexes, &KeyString::from("__RESULT__"));
         
             filtered_table.alt_left_join(right_table, &match_columns.0)?;
@@ -1736,6 +4852,9 @@ pub fn update_i32(keepers: &[usize], column: &mut [i32], op: UpdateOp, value: &D
         UpdateOp::Prepend => {
             return Err(EzError{tag: ErrorTag::Query, text: "'prepend' operator can only be performed on text data".to_owned()})
         },
+        UpdateOp::ToLower | UpdateOp::ToUpper | UpdateOp::Trim => {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' operator can only be performed on text data", op.to_keystring())})
+        },
     }
     Ok(())
 }
@@ -1774,12 +4893,99 @@ pub fn update_f32(keepers: &[usize], column: &mut [f32], op: UpdateOp, value: &D
         UpdateOp::Prepend => {
             return Err(EzError{tag: ErrorTag::Query, text: "'prepend' operator can only be performed on text data".to_owned()})
         },
+        UpdateOp::ToLower | UpdateOp::ToUpper | UpdateOp::Trim => {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' operator can only be performed on text data", op.to_keystring())})
+        },
+    }
+    Ok(())
+}
+
+/// Like update_i32() but each row takes its own value, computed from other columns of the row.
+#[inline]
+pub fn update_i32_from_column(keepers: &[usize], column: &mut [i32], op: UpdateOp, values: &[i32]) -> Result<(), EzError> {
+    match op {
+        UpdateOp::Assign => {
+            for keeper in keepers {
+                column[*keeper] = values[*keeper];
+            }
+        },
+        UpdateOp::PlusEquals => {
+            for keeper in keepers {
+                column[*keeper] += values[*keeper];
+            }
+        },
+        UpdateOp::MinusEquals => {
+            for keeper in keepers {
+                column[*keeper] -= values[*keeper];
+            }
+        },
+        UpdateOp::TimesEquals => {
+            for keeper in keepers {
+                column[*keeper] *= values[*keeper];
+            }
+        },
+        other => {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' operator can't take a computed value", other.to_keystring())})
+        },
+    }
+    Ok(())
+}
+
+/// Like update_f32() but each row takes its own value, computed from other columns of the row.
+#[inline]
+pub fn update_f32_from_column(keepers: &[usize], column: &mut [f32], op: UpdateOp, values: &[f32]) -> Result<(), EzError> {
+    match op {
+        UpdateOp::Assign => {
+            for keeper in keepers {
+                column[*keeper] = values[*keeper];
+            }
+        },
+        UpdateOp::PlusEquals => {
+            for keeper in keepers {
+                column[*keeper] += values[*keeper];
+            }
+        },
+        UpdateOp::MinusEquals => {
+            for keeper in keepers {
+                column[*keeper] -= values[*keeper];
+            }
+        },
+        UpdateOp::TimesEquals => {
+            for keeper in keepers {
+                column[*keeper] *= values[*keeper];
+            }
+        },
+        other => {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' operator can't take a computed value", other.to_keystring())})
+        },
     }
     Ok(())
 }
 
 #[inline]
 pub fn update_keystrings(keepers: &[usize], column: &mut [KeyString], op: UpdateOp, value: &DbValue) -> Result<(), EzError> {
+    // These operators don't take a value so they are handled before the value is checked
+    match op {
+        UpdateOp::ToLower => {
+            for keeper in keepers {
+                column[*keeper] = column[*keeper].to_lowercase();
+            }
+            return Ok(())
+        },
+        UpdateOp::ToUpper => {
+            for keeper in keepers {
+                column[*keeper] = column[*keeper].to_uppercase();
+            }
+            return Ok(())
+        },
+        UpdateOp::Trim => {
+            for keeper in keepers {
+                column[*keeper] = column[*keeper].trim();
+            }
+            return Ok(())
+        },
+        _ => (),
+    }
     let new_value = match value {
         DbValue::Text(x) => x,
         _ => return Err(EzError { tag: ErrorTag::Query, text: format!("an int can only be updated by an int") })
@@ -1800,50 +5006,191 @@ pub fn update_keystrings(keepers: &[usize], column: &mut [KeyString], op: Update
         },
         UpdateOp::Prepend => {
             for keeper in keepers {
-                let mut temp = column[*keeper];
-                temp.push(new_value.as_str());
-                column[*keeper].push(temp.as_str());
+                let mut temp = *new_value;
+                temp.push(column[*keeper].as_str());
+                column[*keeper] = temp;
+            }
+        },
+        UpdateOp::ToLower | UpdateOp::ToUpper | UpdateOp::Trim => unreachable!("Handled above"),
+    }
+    Ok(())
+}
+
+#[inline]
+/// Same as update_keystrings() except nothing is cut off at 64 bytes.
+/// The value itself is a KeyString so values longer than that have to be written with INSERT.
+pub fn update_long_texts(keepers: &[usize], column: &mut LongTexts, op: UpdateOp, value: &DbValue) -> Result<(), EzError> {
+    let new_value = match (op, value) {
+        (UpdateOp::ToLower | UpdateOp::ToUpper | UpdateOp::Trim, _) => "",
+        (_, DbValue::Text(x)) => x.as_str(),
+        _ => return Err(EzError { tag: ErrorTag::Query, text: "text can only be updated by text".to_owned() }),
+    };
+    let apply: Box<dyn Fn(&str) -> String + '_> = match op {
+        UpdateOp::Assign => Box::new(|_| new_value.to_owned()),
+        UpdateOp::Append => Box::new(|old| format!("{}{}", old, new_value)),
+        UpdateOp::Prepend => Box::new(|old| format!("{}{}", new_value, old)),
+        UpdateOp::ToLower => Box::new(|old| old.to_lowercase()),
+        UpdateOp::ToUpper => Box::new(|old| old.to_uppercase()),
+        UpdateOp::Trim => Box::new(|old| old.trim().to_owned()),
+        UpdateOp::PlusEquals | UpdateOp::MinusEquals | UpdateOp::TimesEquals => return Err(EzError{tag: ErrorTag::Query, text: "Can't do math on text".to_owned()}),
+    };
+
+    let mut keepers = keepers.iter().peekable();
+    let mut updated = LongTexts::with_capacity(column.len());
+    for (index, old) in column.iter().enumerate() {
+        if keepers.next_if(|keeper| **keeper == index).is_some() {
+            updated.push(&apply(old));
+        } else {
+            updated.push(old);
+        }
+    }
+    *column = updated;
+    Ok(())
+}
+
+pub fn update_durations(keepers: &[usize], column: &mut [i64], op: UpdateOp, value: &DbValue) -> Result<(), EzError> {
+    match op {
+        UpdateOp::Assign => {
+            let new_value = value.as_duration()?;
+            for keeper in keepers {
+                column[*keeper] = new_value;
+            }
+        },
+        UpdateOp::PlusEquals => {
+            let new_value = value.as_duration()?;
+            for keeper in keepers {
+                column[*keeper] = column[*keeper].saturating_add(new_value);
+            }
+        },
+        UpdateOp::MinusEquals => {
+            let new_value = value.as_duration()?;
+            for keeper in keepers {
+                column[*keeper] = column[*keeper].saturating_sub(new_value);
+            }
+        },
+        // Scaling a duration by a duration makes no sense so the factor is a plain number
+        UpdateOp::TimesEquals => {
+            let factor = match value {
+                DbValue::Int(x) => *x as f64,
+                DbValue::Float(x) => *x as f64,
+                _ => return Err(EzError { tag: ErrorTag::Query, text: "a duration can only be multiplied by an int or a float".to_owned() })
+            };
+            for keeper in keepers {
+                column[*keeper] = (column[*keeper] as f64 * factor).round() as i64;
             }
         },
+        UpdateOp::Append => {
+            return Err(EzError{tag: ErrorTag::Query, text: "'append' operator can only be performed on text data".to_owned()})
+        },
+        UpdateOp::Prepend => {
+            return Err(EzError{tag: ErrorTag::Query, text: "'prepend' operator can only be performed on text data".to_owned()})
+        },
+        UpdateOp::ToLower | UpdateOp::ToUpper | UpdateOp::Trim => {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' operator can only be performed on text data", op.to_keystring())})
+        },
     }
     Ok(())
 }
 
 pub fn execute_update_query(query: Query, table: &mut ColumnTable) -> Result<Option<ColumnTable>, EzError> {
     match query {
-        Query::UPDATE { table_name: _, primary_keys, conditions, mut updates } => {
+        Query::UPDATE { .. } => {
+            update_rows(query, table)?;
+
+            Ok(
+                None
+            )
+        },
+        other_query => Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to execute_update_query() function.\nReceived query: {}", other_query)}),
+    }
+}
+
+/// Applies an UPDATE and returns how many rows it matched.
+pub fn update_rows(query: Query, table: &mut ColumnTable) -> Result<usize, EzError> {
+    Ok(update_rows_at(query, table)?.len())
+}
+
+/// Applies an UPDATE and returns the indexes of the rows it matched.
+pub fn update_rows_at(mut query: Query, table: &mut ColumnTable) -> Result<Vec<usize>, EzError> {
+    validate_query(&query, table)?;
+    encode_enums(&mut query, &table.header)?;
+    match query {
+        Query::UPDATE { table_name: _, primary_keys, conditions, mut updates, version } => {
             let keepers = filter_keepers(&conditions, &primary_keys, table)?;
+            if let Some(version) = version {
+                table.check_row_versions(&keepers, version)?;
+            }
 
             updates.sort_by(|a, b| a.attribute.cmp(&b.attribute));
 
-            for update in &updates{
+            // Computed before any column changes so every expression reads the rows as they were
+            let computed = updates.iter()
+                .map(|update| update.expression.as_ref().map(|expression| expression.evaluate(table)).transpose())
+                .collect::<Result<Vec<Option<DbColumn>>, EzError>>()?;
+
+            for (update, computed) in updates.iter().zip(computed) {
 
                 let active_column = match table.columns.get_mut(&update.attribute) {
                     Some(x) => x,
-                    None => return Err(EzError{tag: ErrorTag::Query, text: format!("Table does not contain column {}", update.attribute)})
+                    None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("Table does not contain column {}", update.attribute)})
                 };
 
+                if let Some(values) = computed {
+                    match (active_column, values) {
+                        (DbColumn::Ints(vec), DbColumn::Ints(values)) => update_i32_from_column(&keepers, vec.as_mut_slice(), update.operator, &values)?,
+                        (DbColumn::Floats(vec), DbColumn::Floats(values)) => update_f32_from_column(&keepers, vec.as_mut_slice(), update.operator, &values)?,
+                        (DbColumn::Floats(vec), DbColumn::Ints(values)) => {
+                            let values: Vec<f32> = values.iter().map(|x| *x as f32).collect();
+                            update_f32_from_column(&keepers, vec.as_mut_slice(), update.operator, &values)?
+                        },
+                        (column, _) => return Err(EzError{tag: ErrorTag::Query, text: format!("The {} column '{}' can't be computed from '{}'", column_kind(column), update.attribute, update)}),
+                    }
+                    continue
+                }
+
                 match active_column {
                     DbColumn::Ints(vec) => update_i32(&keepers, vec.as_mut_slice(), update.operator, &update.value)?,
                     DbColumn::Texts(vec) => update_keystrings(&keepers, vec.as_mut_slice(), update.operator, &update.value)?,
                     DbColumn::Floats(vec) => update_f32(&keepers, vec.as_mut_slice(), update.operator, &update.value)?,
+                    DbColumn::Durations(vec) => update_durations(&keepers, vec.as_mut_slice(), update.operator, &update.value)?,
+                    DbColumn::LongTexts(col) => update_long_texts(&keepers, col, update.operator, &update.value)?,
                 }
             }
-            
-            Ok(
-                None    
-            )
+            table.touch_rows(&keepers, get_current_time());
+            table.bump_row_versions(&keepers);
+
+            Ok(keepers)
+        },
+        other_query => Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to update_rows() function.\nReceived query: {}", other_query)}),
+    }
+}
+
+/// The first row of the table that inserting these rows can change. Rows are kept sorted by primary key so
+/// every row from the smallest new key on may move.
+pub fn first_insert_row(table: &ColumnTable, inserts: &ColumnTable) -> usize {
+    let key = table.get_primary_key_col_index();
+    match (table.columns.get(&key), inserts.columns.get(&key)) {
+        (Some(DbColumn::Ints(column)), Some(DbColumn::Ints(new))) => new.iter().min().map_or(table.len(), |min| column.partition_point(|x| x < min)),
+        (Some(DbColumn::Texts(column)), Some(DbColumn::Texts(new))) => {
+            let collation = table.key_collation();
+            new.iter().min_by(|a, b| collation.compare(a, b)).map_or(table.len(), |min| column.partition_point(|x| collation.compare(x, min).is_lt()))
         },
-        other_query => return Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to execute_update_query() function.\nReceived query: {}", other_query)}),
+        _ => 0,
     }
 }
 
-pub fn execute_insert_query(query: Query, table: &mut ColumnTable) -> Result<Option<ColumnTable>, EzError> {
+pub fn execute_insert_query(mut query: Query, table: &mut ColumnTable) -> Result<Option<ColumnTable>, EzError> {
     // println!("calling: execute_insert_query()");
+    // Values of up to 64 bytes are read as Text even when they are meant for a LongText column
+    if let Query::INSERT { inserts, .. } = &mut query {
+        inserts.widen_texts_to(&table.header);
+    }
+    validate_query(&query, table)?;
+    encode_enums(&mut query, &table.header)?;
 
     match query {
-        Query::INSERT { table_name: _, inserts } => {
-            table.insert(inserts)?;
+        Query::INSERT { table_name: _, inserts, on_conflict } => {
+            table.insert_on_conflict(inserts, on_conflict)?;
         
             Ok(
                 None
@@ -1857,22 +5204,69 @@ pub fn execute_insert_query(query: Query, table: &mut ColumnTable) -> Result<Opt
 pub fn execute_select_query(query: &Query, table: &ColumnTable) -> Result<Option<ColumnTable>, EzError> {
     // println!("calling: execute_select_query()");
 
+    // Conditions compare the positions enum columns store and the result shows the values again
+    if table.has_enum_columns() {
+        validate_query(query, table)?;
+        let mut encoded = query.clone();
+        encode_enums(&mut encoded, &table.header)?;
+        let mut result = select_from_table(&encoded, table)?;
+        if let Some(result) = &mut result {
+            result.decode_enums()?;
+        }
+        return Ok(result)
+    }
+    select_from_table(query, table)
+}
+
+fn select_from_table(query: &Query, table: &ColumnTable) -> Result<Option<ColumnTable>, EzError> {
     match query {
-        Query::SELECT { table_name: _, primary_keys, columns, conditions } => {
-            let table = table.subtable_from_columns(columns, "RESULT")?;
-            let keepers = filter_keepers(&conditions, &primary_keys, &table)?;
-        
-            Ok(
-                Some(
-                    table
-                        .subtable_from_indexes(&keepers, &KeyString::from("RESULT"))
-                    )
-            )
+        Query::SELECT { table_name: _, primary_keys, columns, conditions, distinct, limit } => {
+            validate_query(query, table)?;
+            let projections: Vec<Projection> = columns.iter().map(Projection::from_keystring).collect();
+            // Distinct drops rows after filtering so the scan can only stop early without it
+            let scan_limit = if *distinct { None } else { *limit };
+            if projections.iter().all(|p| !p.is_computed()) {
+                // Filtering the source first means only the kept rows of the selected columns are copied
+                let keepers = filter_keepers_limited(conditions, primary_keys, table, scan_limit)?.keepers;
+                let mut result = table.select_rows(columns, &keepers, &KeyString::from("RESULT"))?;
+                if !columns.iter().any(is_row_id_column) {
+                    result.hide_row_ids();
+                }
+                if *distinct {
+                    result = result.subtable_from_indexes(&result.distinct_row_indexes(), &KeyString::from("RESULT"));
+                    truncate_to_limit(&mut result, *limit);
+                }
+
+                return Ok(Some(result))
+            }
+
+            let mut base_columns: Vec<KeyString> = projections.iter().flat_map(Projection::base_columns).collect();
+            base_columns.sort();
+            base_columns.dedup();
+            let keepers = filter_keepers_limited(conditions, primary_keys, table, scan_limit)?.keepers;
+            let mut result = table.select_rows(&base_columns, &keepers, &KeyString::from("RESULT"))?;
+            apply_projections(&mut result, columns, &projections)?;
+            // Projections can turn different rows into equal ones, so duplicates are found after they are applied
+            if *distinct {
+                result = result.subtable_from_indexes(&result.distinct_row_indexes(), &KeyString::from("RESULT"));
+                truncate_to_limit(&mut result, *limit);
+            }
+
+            Ok(Some(result))
         },
         other_query => return Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to execute_select_query() function.\nReceived query: {}", other_query)}),
     }
 }
 
+fn truncate_to_limit(result: &mut ColumnTable, limit: Option<usize>) {
+    if let Some(limit) = limit {
+        if result.len() > limit {
+            let first: Vec<usize> = (0..limit).collect();
+            *result = result.subtable_from_indexes(&first, &KeyString::from("RESULT"));
+        }
+    }
+}
+
 // pub fn alt_execute_select_query(query: Query, table: &ColumnTable) -> Result<Option<ColumnTable>, EzError> {
 //     // println!("calling: execute_select_query()");
 
@@ -1963,6 +5357,43 @@ pub fn execute_select_query(query: &Query, table: &ColumnTable) -> Result<Option
 //     todo!()
 // }
 
+/// Adds a column for every function projection and removes source columns that were not asked for directly.
+/// The primary key column is always kept.
+fn apply_projections(table: &mut ColumnTable, columns: &[KeyString], projections: &[Projection]) -> Result<(), EzError> {
+
+    for (name, projection) in columns.iter().zip(projections) {
+        if let Some(expression) = &projection.expression {
+            let new_column = expression.evaluate(table)?;
+            table.add_column(*name, new_column)?;
+            continue
+        }
+        let function = match projection.function {
+            Some(f) => f,
+            None => continue,
+        };
+        let new_column = match table.columns.get(&projection.column) {
+            Some(DbColumn::Texts(col)) => function.apply(col),
+            Some(_) => return Err(EzError{tag: ErrorTag::Query, text: format!("{} can only be applied to text columns", name)}),
+            None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No such column as {}", projection.column)}),
+        };
+        table.add_column(*name, DbColumn::Texts(new_column))?;
+    }
+
+    // The result only has a primary key if it was selected or a projection needed it
+    let primary_key = table.header.iter().find(|item| item.key == TableKey::Primary).map(|item| item.name);
+    for projection in projections.iter().filter(|p| p.is_computed()) {
+        for column in projection.base_columns() {
+            if Some(column) == primary_key || columns.contains(&column) {
+                continue
+            }
+            table.columns.remove(&column);
+            table.header.retain(|item| item.name != column);
+        }
+    }
+
+    Ok(())
+}
+
 pub fn execute_select_query_with_pk_list(table: &ColumnTable, start: KeyString, stop: KeyString, columns: Vec<KeyString>, conditions: Vec<OpOrCond>) -> Result<Option<ColumnTable>, EzError> {
     todo!()
 }
@@ -1971,7 +5402,145 @@ pub fn execute_select_query_with_pk_all(table: &ColumnTable, start: KeyString, s
     todo!()
 }
 
+/// The tables the names stand for, each once and in name order. A name with * or ? is a glob matched against
+/// every stored table, loaded or not. A glob that matches nothing is an error so a typo doesn't look like an
+/// empty namespace. Other names are kept as they are and fail later if there is no such table.
+pub fn resolve_table_names(names: &[KeyString], database: &Database) -> Result<Vec<KeyString>, EzError> {
+    let mut resolved = BTreeSet::new();
+    for name in names {
+        if !is_glob(name.as_str()) {
+            resolved.insert(*name);
+            continue
+        }
+        let mut matched: Vec<KeyString> = database.buffer_pool.tables.read().unwrap().keys()
+            .filter(|table| glob_matches(name.as_str(), table.as_str()))
+            .copied()
+            .collect();
+        matched.extend(database.buffer_pool.unloaded_tables.read().unwrap().keys().filter(|table| glob_matches(name.as_str(), table.as_str())));
+        if matched.is_empty() {
+            return Err(EzError{tag: ErrorTag::NotFound, text: format!("No table matches '{}'", name)})
+        }
+        resolved.extend(matched);
+    }
+    Ok(resolved.into_iter().collect())
+}
+
+/// Replaces the globs of every MULTI_SUMMARY in the batch with the tables they match, so permissions are
+/// checked against real table names.
+pub fn expand_table_globs(queries: &mut [Query], database: &Database) -> Result<(), EzError> {
+    for query in queries {
+        if let Query::MULTI_SUMMARY { tables, .. } = query {
+            *tables = resolve_table_names(tables, database)?;
+        }
+    }
+    Ok(())
+}
+
+fn summarize_stored_table(table_name: KeyString, columns: &[Statistic], database: &Database) -> Result<ColumnTable, EzError> {
+    let query = Query::SUMMARY { table_name, columns: columns.to_vec() };
+    let summary = if is_system_table(&table_name) {
+        execute_summary_query(&query, &materialize_system_table(&table_name, database)?)?
+    } else {
+        let tables = database.buffer_pool.tables.read().unwrap();
+        let table = match tables.get(&table_name) {
+            Some(table) => database.locks.read_table(table_name, table)?,
+            None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named '{}'", table_name)}),
+        };
+        execute_summary_query(&query, &table)?
+    };
+    summary.ok_or_else(|| EzError{tag: ErrorTag::Query, text: format!("The summary of '{}' gave no result", table_name)})
+}
+
+/// Summarizes every table of a MULTI_SUMMARY and stacks the results. The tables are split between scoped
+/// threads, one per core at most, so the worker running the batch isn't tied up one table at a time.
+/// Every table needs the summarized columns with the same types.
+pub fn execute_multi_summary_query(query: &Query, database: &Database) -> Result<ColumnTable, EzError> {
+    let _phase = alloc_stats::enter(AllocPhase::Summary);
+    let (names, columns) = match query {
+        Query::MULTI_SUMMARY { tables, columns } => (tables, columns),
+        other => return Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to execute_multi_summary_query() function.\nReceived query: {}", other)}),
+    };
+    let tables = resolve_table_names(names, database)?;
+    if tables.is_empty() {
+        return Err(EzError{tag: ErrorTag::Query, text: "MULTI_SUMMARY needs at least one table".to_owned()})
+    }
+
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get()).min(tables.len());
+    let summaries: Vec<Result<ColumnTable, EzError>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = tables.chunks(tables.len().div_ceil(workers))
+            .map(|names| scope.spawn(move || names.iter().map(|name| summarize_stored_table(*name, columns, database)).collect::<Vec<_>>()))
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().expect("A summary thread panicked")).collect()
+    });
+
+    let mut result: Option<ColumnTable> = None;
+    for (table_name, summary) in tables.iter().zip(summaries) {
+        let mut summary = summary?;
+        summary.add_column(ksf("Table"), DbColumn::Texts(vec![*table_name; summary.len()]))?;
+        match &mut result {
+            Some(result) => result.extend_from_table(summary).map_err(|_| EzError{tag: ErrorTag::Query, text: format!(
+                "The summarized columns of '{}' don't have the same types as in '{}'", table_name, tables[0]
+            )})?,
+            None => result = Some(summary),
+        }
+    }
+
+    Ok(result.expect("There is at least one table"))
+}
+
+/// The rows of a SUMMARY result. One per StatOp in the order they are declared, then NAN_EXCLUDED.
+pub const STATISTIC_ROWS: usize = 9;
+
+/// The statistics of a column that can be worked out chunk by chunk and merged. The rest need the whole column.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Partials<T> {
+    sum: T,
+    /// The sum of int columns as a float, which the mean is taken from so it doesn't saturate.
+    float_sum: f32,
+    min: T,
+    max: T,
+}
+
+impl Default for Partials<i32> {
+    fn default() -> Self {
+        Partials{sum: 0, float_sum: 0.0, min: i32::MAX, max: i32::MIN}
+    }
+}
+
+impl Default for Partials<f32> {
+    fn default() -> Self {
+        Partials{sum: 0.0, float_sum: 0.0, min: f32::INFINITY, max: f32::NEG_INFINITY}
+    }
+}
+
+fn int_partials(column: &[i32]) -> Partials<i32> {
+    scan_in_chunks(column.len(), |range| {
+        let chunk = &column[range];
+        Partials{sum: sum_i32_slice(chunk), float_sum: chunk.iter().map(|x| *x as f32).sum(), min: min_i32_slice(chunk), max: max_i32_slice(chunk)}
+    }).into_iter().fold(Partials::default(), |a, b| Partials{
+        sum: a.sum.saturating_add(b.sum),
+        float_sum: a.float_sum + b.float_sum,
+        min: a.min.min(b.min),
+        max: a.max.max(b.max),
+    })
+}
+
+/// NaN has to be taken out of the column first.
+fn float_partials(column: &[f32]) -> Partials<f32> {
+    scan_in_chunks(column.len(), |range| {
+        let chunk = &column[range];
+        Partials{sum: sum_f32_slice(chunk), float_sum: 0.0, min: min_f32_slice(chunk), max: max_f32_slice(chunk)}
+    }).into_iter().fold(Partials::default(), |a, b| Partials{
+        sum: a.sum + b.sum,
+        float_sum: 0.0,
+        min: a.min.min(b.min),
+        max: a.max.max(b.max),
+    })
+}
+
 pub fn execute_summary_query(query: &Query, table: &ColumnTable) -> Result<Option<ColumnTable>, EzError> {
+    let _phase = alloc_stats::enter(AllocPhase::Summary);
+    validate_query(query, table)?;
     match query {
         Query::SUMMARY { table_name: _, columns } => {
             let mut result = ColumnTable::blank(&BTreeSet::new(), KeyString::from("RESULT"), "QUERY");
@@ -1982,30 +5551,39 @@ pub fn execute_summary_query(query: &Query, table: &ColumnTable) -> Result<Optio
                 ksf("MEDIAN"),
                 ksf("MODE"),
                 ksf("STDEV"),
+                ksf("MIN"),
+                ksf("MAX"),
+                ksf("COUNT"),
+                ksf("NAN_EXCLUDED"),
             ]))?;
 
             for stat in columns {
                 let requested_column = match table.columns.get(&stat.column) {
                     Some(x) => x,
-                    None => return Err(EzError{tag: ErrorTag::Query, text: format!("No column named {} in table {}", stat.column, table.name)}),
+                    None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No column named {} in table {}", stat.column, table.name)}),
                 };
 
+                let needs_partials = stat.actions.iter().any(|action| matches!(action, StatOp::SUM | StatOp::MEAN | StatOp::MIN | StatOp::MAX));
                 match requested_column {
                     DbColumn::Ints(vec) => {
-                        let mut temp = [0i32; 5].to_vec();
+                        let partials = if needs_partials { int_partials(vec) } else { Partials::default() };
+                        let mut temp = [0i32; STATISTIC_ROWS].to_vec();
                         for action in &stat.actions {
                             match action {
-                                StatOp::SUM => temp[0] = sum_i32_slice(&vec),
-                                StatOp::MEAN => temp[1] = mean_i32_slice(&vec) as i32,
+                                StatOp::SUM => temp[0] = partials.sum,
+                                StatOp::MEAN => temp[1] = (partials.float_sum / vec.len() as f32) as i32,
                                 StatOp::MEDIAN => temp[2] = median_i32_slice(&vec) as i32,
                                 StatOp::MODE => temp[3] = mode_i32_slice(&vec),
                                 StatOp::STDEV => temp[4] = stdev_i32_slice(&vec) as i32,
+                                StatOp::MIN => temp[5] = partials.min,
+                                StatOp::MAX => temp[6] = partials.max,
+                                StatOp::COUNT => temp[7] = vec.len().min(i32::MAX as usize) as i32,
                             }
                         }
                         result.add_column(stat.column, DbColumn::Ints(temp))?;
                     },
                     DbColumn::Texts(vec) => {
-                        let mut temp = [ksf(""); 5].to_vec();
+                        let mut temp = [ksf(""); STATISTIC_ROWS].to_vec();
                         for action in &stat.actions {
                             match action {
                                 StatOp::SUM => temp[0] = ksf("can't sum text"),
@@ -2013,22 +5591,82 @@ pub fn execute_summary_query(query: &Query, table: &ColumnTable) -> Result<Optio
                                 StatOp::MEDIAN => temp[2] = ksf("can't median text"),
                                 StatOp::MODE => temp[3] = mode_string_slice(&vec),
                                 StatOp::STDEV => temp[4] = ksf("can't stdev text"),
+                                StatOp::MIN => temp[5] = ksf("can't min text"),
+                                StatOp::MAX => temp[6] = ksf("can't max text"),
+                                StatOp::COUNT => temp[7] = ksf(&vec.len().to_string()),
+                            }
+                        }
+                        result.add_column(stat.column, DbColumn::Texts(temp))?;
+                    },
+                    DbColumn::Floats(vec) => {
+                        // NaN would turn every aggregate into NaN so it is left out and counted in the NAN_EXCLUDED row
+                        let nans = vec.iter().filter(|x| x.is_nan()).count();
+                        let numbers: Vec<f32>;
+                        let vec = if nans > 0 {
+                            numbers = vec.iter().copied().filter(|x| !x.is_nan()).collect();
+                            &numbers
+                        } else {
+                            vec
+                        };
+                        let partials = if needs_partials { float_partials(vec) } else { Partials::default() };
+                        let mut temp = [0f32; STATISTIC_ROWS].to_vec();
+                        temp[8] = nans as f32;
+                        for action in &stat.actions {
+                            match action {
+                                StatOp::SUM => temp[0] = partials.sum,
+                                StatOp::MEAN => temp[1] = partials.sum / vec.len() as f32,
+                                StatOp::MEDIAN => temp[2] = median_f32_slice(&vec),
+                                StatOp::MODE => temp[3] = 0.0,
+                                StatOp::STDEV => temp[4] = stdev_f32_slice(&vec),
+                                StatOp::MIN => temp[5] = partials.min,
+                                StatOp::MAX => temp[6] = partials.max,
+                                StatOp::COUNT => temp[7] = vec.len() as f32,
+                            }
+                        }
+                        result.add_column(stat.column, DbColumn::Floats(temp))?;
+                    },
+                    // Statistics on durations are reported as text with units since the raw nanoseconds are unreadable
+                    DbColumn::Durations(vec) => {
+                        let mut temp = [ksf(""); STATISTIC_ROWS].to_vec();
+                        for action in &stat.actions {
+                            match action {
+                                StatOp::SUM => temp[0] = ksf(&humanize_duration(sum_i64_slice(vec) as f64)),
+                                StatOp::MEAN => temp[1] = ksf(&humanize_duration(mean_i64_slice(vec))),
+                                StatOp::MEDIAN => temp[2] = ksf(&humanize_duration(median_i64_slice(vec))),
+                                StatOp::MODE => temp[3] = ksf(&format_duration(mode_i64_slice(vec))),
+                                StatOp::STDEV => temp[4] = ksf(&humanize_duration(stdev_i64_slice(vec))),
+                                StatOp::MIN if vec.is_empty() => (),
+                                StatOp::MIN => temp[5] = ksf(&format_duration(min_i64_slice(vec))),
+                                StatOp::MAX if vec.is_empty() => (),
+                                StatOp::MAX => temp[6] = ksf(&format_duration(max_i64_slice(vec))),
+                                StatOp::COUNT => temp[7] = ksf(&vec.len().to_string()),
                             }
                         }
                         result.add_column(stat.column, DbColumn::Texts(temp))?;
                     },
-                    DbColumn::Floats(vec) => {
-                        let mut temp = [0f32; 5].to_vec();
+                    DbColumn::LongTexts(col) => {
+                        let count = col.len().to_string();
+                        let mut temp = [""; STATISTIC_ROWS];
+                        let mut counts: HashMap<&str, usize> = HashMap::new();
                         for action in &stat.actions {
                             match action {
-                                StatOp::SUM => temp[0] = sum_f32_slice(&vec),
-                                StatOp::MEAN => temp[1] = mean_f32_slice(&vec),
-                                StatOp::MEDIAN => temp[2] = median_f32_slice(&vec),
-                                StatOp::MODE => temp[3] = 0.0,
-                                StatOp::STDEV => temp[4] = stdev_f32_slice(&vec),
+                                StatOp::SUM => temp[0] = "can't sum text",
+                                StatOp::MEAN => temp[1] = "can't mean text",
+                                StatOp::MEDIAN => temp[2] = "can't median text",
+                                // Ties go to the smallest value
+                                StatOp::MODE => {
+                                    for value in col.iter() {
+                                        *counts.entry(value).or_default() += 1;
+                                    }
+                                    temp[3] = counts.iter().max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0))).map(|(value, _)| *value).unwrap_or("");
+                                },
+                                StatOp::STDEV => temp[4] = "can't stdev text",
+                                StatOp::MIN => temp[5] = "can't min text",
+                                StatOp::MAX => temp[6] = "can't max text",
+                                StatOp::COUNT => temp[7] = &count,
                             }
                         }
-                        result.add_column(stat.column, DbColumn::Floats(temp))?;
+                        result.add_column(stat.column, DbColumn::LongTexts(temp.into_iter().collect()))?;
                     },
                 }
             }
@@ -2079,64 +5717,15 @@ pub fn keys_to_indexes(table: &ColumnTable, keys: &RangeOrListOrAll) -> Result<V
 
     match keys {
         RangeOrListOrAll::Range(ref start, ref stop) => {
-            match &table.columns[&table.get_primary_key_col_index()] {
-                DbColumn::Ints(column) => {
-                    let first = match column.binary_search(&start.to_i32()) {
-                        Ok(x) => x,
-                        Err(x) => x,
-                    };
-                    let last = match column.binary_search(&stop.to_i32()) {
-                        Ok(x) => x,
-                        Err(x) => x,
-                    };
-                    indexes = (first..last).collect();
-                },
-                DbColumn::Texts(column) => {
-                    let first = match column.binary_search(start) {
-                        Ok(x) => x,
-                        Err(x) => x,
-                    };
-                    let last = match column.binary_search(stop) {
-                        Ok(x) => x,
-                        Err(x) => x,
-                    };
-                    indexes = (first..last).collect();
-                },
-                DbColumn::Floats(_n) => unreachable!("There should never be a float primary key"),
-            }
+            indexes = key_range_span(table, start, stop).collect();
         },
         RangeOrListOrAll::List(ref keys) => {
-            match &table.columns[&table.get_primary_key_col_index()] {
-                DbColumn::Ints(column) => {
-                    if keys.len() > column.len() {
-                        return Err(EzError{tag: ErrorTag::Query, text: "There are more keys requested than there are indexes to get".to_owned()})
-                    }
-                    let mut keys = keys.clone();
-                    keys.sort();
-                    let mut key_index: usize = 0;
-                    for index in 0..keys.len() {
-                        if column[index] == keys[key_index].to_i32() {
-                            indexes.push(index);
-                            key_index += 1;
-                        }
-                    }
-                },
-                DbColumn::Texts(column) => {
-                    if keys.len() > column.len() {
-                        return Err(EzError{tag: ErrorTag::Query, text: "There are more keys requested than there are indexes to get".to_owned()})
-                    }
-                    let mut keys = keys.clone();
-                    keys.sort();
-                    let mut key_index = 0;
-                    for index in 0..column.len() {
-                        if column[index] == keys[key_index] {
-                            indexes.push(index);
-                            key_index += 1;
-                        }
-                    }
-                },
-                DbColumn::Floats(_) => unreachable!("There should never be a float primary key"),
-            }
+            let column = db_slice_from_column(&table.columns[&table.get_primary_key_col_index()], 0, table.len());
+            indexes = KeyList::from_keystrings(keys, &column).indexes_in(&column, table.key_collation())?;
+        },
+        RangeOrListOrAll::Keys(ref keys) => {
+            let column = db_slice_from_column(&table.columns[&table.get_primary_key_col_index()], 0, table.len());
+            indexes = keys.indexes_in(&column, table.key_collation())?;
         },
         RangeOrListOrAll::All => indexes = (0..table.len()).collect(),
     };
@@ -2145,134 +5734,840 @@ pub fn keys_to_indexes(table: &ColumnTable, keys: &RangeOrListOrAll) -> Result<V
 }
 
 
+/// The contiguous rows whose primary key lies in start..stop. Primary keys are sorted so this is two binary searches.
+pub fn key_range_span(table: &ColumnTable, start: &KeyString, stop: &KeyString) -> std::ops::Range<usize> {
+    match &table.columns[&table.get_primary_key_col_index()] {
+        DbColumn::Ints(column) => {
+            let first = match column.binary_search(&start.to_i32()) {
+                Ok(x) => x,
+                Err(x) => x,
+            };
+            let last = match column.binary_search(&stop.to_i32()) {
+                Ok(x) => x,
+                Err(x) => x,
+            };
+            first..last.max(first)
+        },
+        DbColumn::Texts(column) => {
+            let collation = table.key_collation();
+            let first = match column.binary_search_by(|probe| collation.compare(probe, start)) {
+                Ok(x) => x,
+                Err(x) => x,
+            };
+            let last = match column.binary_search_by(|probe| collation.compare(probe, stop)) {
+                Ok(x) => x,
+                Err(x) => x,
+            };
+            first..last.max(first)
+        },
+        DbColumn::Floats(_n) => unreachable!("There should never be a float primary key"),
+        DbColumn::Durations(_n) => unreachable!("There should never be a duration primary key"),
+        DbColumn::LongTexts(_n) => unreachable!("There should never be a long text primary key"),
+    }
+}
+
+/// The rows a DELETE leaves behind as a bitmask, one bit per row and set for the rows that are kept.
+/// Rows are tested one 64 row word at a time so a delete that matches most of a large table never
+/// builds a vector of indexes. Pass the result to ColumnTable::retain_rows.
+pub fn delete_keep_mask(conditions: &[OpOrCond], primary_keys: &RangeOrListOrAll, table: &ColumnTable) -> Result<Vec<u64>, EzError> {
+    // println!("calling: delete_keep_mask()");
+
+    let len = table.len();
+    let mut keep = vec![u64::MAX; len.div_ceil(64)];
+
+    let mut columns = BTreeMap::new();
+    for condition in conditions {
+        if let OpOrCond::Cond(cond) = condition {
+            let column = match table.columns.get(&cond.attribute) {
+                Some(column) => db_slice_from_column(column, 0, column.len()),
+                None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("table does not contain column {}", cond.attribute)}),
+            };
+            check_test_type(cond, &column)?;
+            if let Some(other) = &cond.other_column {
+                let other_column = match table.columns.get(other) {
+                    Some(other_column) => db_slice_from_column(other_column, 0, other_column.len()),
+                    None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("table does not contain column {}", other)}),
+                };
+                check_column_comparison(cond, &column, &other_column)?;
+                columns.insert(*other, other_column);
+            }
+            columns.insert(cond.attribute, column);
+        }
+    }
+
+    let tree = match conditions.is_empty() {
+        true => None,
+        false => Some(ConditionBranch::parse(conditions)?),
+    };
+    let prepared = match &tree {
+        Some(tree) => Some(PreparedTests::new(tree, &columns, &table.header)?),
+        None => None,
+    };
+    let matches = |index: usize| -> Result<bool, EzError> {
+        match (&tree, &prepared) {
+            (Some(tree), Some(prepared)) => tree.evaluate(&mut |cond| prepared.matches_row(cond, &columns, index)),
+            _ => Ok(true),
+        }
+    };
+
+    let span = match primary_keys {
+        RangeOrListOrAll::All => 0..len,
+        RangeOrListOrAll::Range(start, stop) => key_range_span(table, start, stop),
+        RangeOrListOrAll::List(_) | RangeOrListOrAll::Keys(_) => {
+            // Key lists are as long as the query so there is nothing to gain from streaming them
+            for index in keys_to_indexes(table, primary_keys)? {
+                if matches(index)? {
+                    keep[index / 64] &= !(1 << (index % 64));
+                }
+            }
+            return Ok(keep)
+        },
+    };
+    let span = match tree.as_ref().and_then(|tree| between_key_span(tree, table)) {
+        Some(between) => span.start.max(between.start)..span.end.min(between.end),
+        None => span,
+    };
+
+    let mut word_start = span.start - span.start % 64;
+    while word_start < span.end {
+        let mut deleted = 0u64;
+        for index in word_start.max(span.start)..(word_start + 64).min(span.end) {
+            if matches(index)? {
+                deleted |= 1 << (index % 64);
+            }
+        }
+        keep[word_start / 64] &= !deleted;
+        word_start += 64;
+    }
+
+    Ok(keep)
+}
+
 pub fn filter_keepers(conditions: &Vec<OpOrCond>, primary_keys: &RangeOrListOrAll, table: &ColumnTable) -> Result<Vec<usize>, EzError> {
     // println!("calling: filter_keepers()");
+    Ok(filter_keepers_limited(conditions, primary_keys, table, None)?.keepers)
+}
+
+/// The rows a filter kept and how many rows it tested to find them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scan {
+    pub keepers: Vec<usize>,
+    pub rows_scanned: usize,
+}
+
+/// Like filter_keepers() but stops as soon as limit rows match. Rows are tested in primary key order,
+/// so the keepers are the first matching rows by primary key, which is what a limit ordered by the key asks for.
+pub fn filter_keepers_limited(conditions: &Vec<OpOrCond>, primary_keys: &RangeOrListOrAll, table: &ColumnTable, limit: Option<usize>) -> Result<Scan, EzError> {
+    let _phase = alloc_stats::enter(AllocPhase::Filter);
 
-    let indexes = keys_to_indexes(table, primary_keys)?;
-    
     if conditions.is_empty() {
-        return Ok(indexes);
+        let mut indexes = keys_to_indexes(table, primary_keys)?;
+        if let Some(limit) = limit {
+            indexes.truncate(limit);
+        }
+        let rows_scanned = indexes.len();
+        return Ok(Scan{keepers: indexes, rows_scanned});
+    }
+
+    let mut columns = BTreeMap::new();
+    for condition in conditions {
+        if let OpOrCond::Cond(cond) = condition {
+            let column = match table.columns.get(&cond.attribute) {
+                Some(column) => db_slice_from_column(column, 0, column.len()),
+                None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("table does not contain column {}", cond.attribute)}),
+            };
+            check_test_type(cond, &column)?;
+            if let Some(other) = &cond.other_column {
+                let other_column = match table.columns.get(other) {
+                    Some(other_column) => db_slice_from_column(other_column, 0, other_column.len()),
+                    None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("table does not contain column {}", other)}),
+                };
+                check_column_comparison(cond, &column, &other_column)?;
+                columns.insert(*other, other_column);
+            }
+            columns.insert(cond.attribute, column);
+        }
     }
+
+    let tree = ConditionBranch::parse(conditions)?;
+    let prepared = PreparedTests::new(&tree, &columns, &table.header)?;
+    let indexes = indexes_within(table, primary_keys, between_key_span(&tree, table))?;
+
+    // Large scans are split between threads. Not with a limit since stopping early beats scanning everything faster
+    if limit.is_none() {
+        let chunks = scan_in_chunks(indexes.len(), |range| {
+            let mut keepers = Vec::new();
+            for index in &indexes[range] {
+                if tree.evaluate(&mut |cond| prepared.matches_row(cond, &columns, *index))? {
+                    keepers.push(*index);
+                }
+            }
+            Ok::<_, EzError>(keepers)
+        });
+        let mut keepers = Vec::<usize>::new();
+        for chunk in chunks {
+            keepers.extend(chunk?);
+        }
+        return Ok(Scan{keepers, rows_scanned: indexes.len()})
+    }
+
     let mut keepers = Vec::<usize>::new();
-    let mut current_op = Operator::OR;
-    for condition in conditions.iter() {
-        match condition {
-            OpOrCond::Op(op) => current_op = *op,
-            OpOrCond::Cond(cond) => {
-                if !table.columns.contains_key(&cond.attribute) {
-                    return Err(EzError{tag: ErrorTag::Query, text: format!("table does not contain column {}", cond.attribute)})
-                }
-                let column = &table.columns[&cond.attribute];
-                if current_op == Operator::OR {
-                    for index in &indexes {
-                        match &cond.op {
-                            TestOp::Equals => {
-                                match column {
-                                    DbColumn::Ints(col) => if col[*index] == cond.value.to_i32() {keepers.push(*index)},
-                                    DbColumn::Floats(col) => if col[*index] == cond.value.to_f32() {keepers.push(*index)},
-                                    DbColumn::Texts(col) => if col[*index] == cond.value.to_keystring() {keepers.push(*index)},
-                                }
-                            },
-                            TestOp::NotEquals => {
-                                match column {
-                                    DbColumn::Ints(col) => if col[*index] != cond.value.to_i32() {keepers.push(*index)},
-                                    DbColumn::Floats(col) => if col[*index] != cond.value.to_f32() {keepers.push(*index)},
-                                    DbColumn::Texts(col) => if col[*index] != cond.value.to_keystring() {keepers.push(*index)},
-                                }
-                            },
-                            TestOp::Less => {
-                                match column {
-                                    DbColumn::Ints(col) => if col[*index] < cond.value.to_i32() {keepers.push(*index)},
-                                    DbColumn::Floats(col) => if col[*index] < cond.value.to_f32() {keepers.push(*index)},
-                                    DbColumn::Texts(col) => if col[*index] < cond.value.to_keystring() {keepers.push(*index)},
-                                }
-                            },
-                            TestOp::Greater => {
-                                match column {
-                                    DbColumn::Ints(col) => if col[*index] > cond.value.to_i32() {keepers.push(*index)},
-                                    DbColumn::Floats(col) => if col[*index] > cond.value.to_f32() {keepers.push(*index)},
-                                    DbColumn::Texts(col) => if col[*index] > cond.value.to_keystring() {keepers.push(*index)},
-                                }
-                            },
-                            TestOp::Starts => {
-                                match column {
-                                    DbColumn::Texts(col) => if col[*index].as_str().starts_with(cond.value.to_keystring().as_str()) {keepers.push(*index)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'starts_with' on text values".to_owned()}),
-                                }
-                            },
-                            TestOp::Ends => {
-                                match column {
-                                    DbColumn::Texts(col) => if col[*index].as_str().ends_with(cond.value.to_keystring().as_str()) {keepers.push(*index)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'ends_with' on text values".to_owned()}),
-                                }
-                            },
-                            TestOp::Contains => {
-                                match column {
-                                    DbColumn::Texts(col) => if col[*index].as_str().contains(cond.value.to_keystring().as_str()) {keepers.push(*index)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'contains' on text values".to_owned()}),
-                                }
-                            },
+    let mut rows_scanned = 0;
+    for index in indexes {
+        if limit.is_some_and(|limit| keepers.len() >= limit) {
+            break
+        }
+        rows_scanned += 1;
+        if tree.evaluate(&mut |cond| prepared.matches_row(cond, &columns, index))? {
+            keepers.push(index);
+        }
+    }
+
+    Ok(Scan{keepers, rows_scanned})
+}
+
+/// Checks that the test makes sense for the type of the column. Text tests can't be run on numbers.
+pub fn check_test_type(cond: &Condition, column: &DbSlice) -> Result<(), EzError> {
+    if cond.op == TestOp::IsNaN {
+        return match column {
+            DbSlice::Floats(_) => Ok(()),
+            _ => Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'is_nan' on float values".to_owned()}),
+        }
+    }
+    let name = match cond.op {
+        TestOp::Starts | TestOp::NotStarts => "starts_with",
+        TestOp::Ends | TestOp::NotEnds => "ends_with",
+        TestOp::Contains | TestOp::NotContains => "contains",
+        TestOp::Matches => "matches",
+        TestOp::MatchesRegex => "matches_regex",
+        _ => return Ok(()),
+    };
+    match column {
+        DbSlice::Texts(_) | DbSlice::LongTexts(..) => Ok(()),
+        _ => Err(EzError{tag: ErrorTag::Query, text: format!("Can only filter by '{}' on text values", name)}),
+    }
+}
+
+/// Checks that the condition can compare the two columns. Both must hold the same kind of value and the other
+/// column can't be long text since its values are read into a DbValue.
+pub fn check_column_comparison(cond: &Condition, column: &DbSlice, other: &DbSlice) -> Result<(), EzError> {
+    if matches!(cond.op, TestOp::In(_) | TestOp::Between(..)) {
+        return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' tests column '{}' against its own values and can't take another column", cond.op.name(), cond.attribute)})
+    }
+    let fits = matches!((column, other),
+        (DbSlice::Ints(_), DbSlice::Ints(_))
+        | (DbSlice::Floats(_), DbSlice::Floats(_))
+        | (DbSlice::Texts(_) | DbSlice::LongTexts(..), DbSlice::Texts(_))
+        | (DbSlice::Durations(_), DbSlice::Durations(_))
+    );
+    if fits {
+        Ok(())
+    } else {
+        let other = cond.other_column.unwrap_or_default();
+        Err(EzError{tag: ErrorTag::Query, text: format!("Can't compare column '{}' to column '{}' since they hold different kinds of values", cond.attribute, other)})
+    }
+}
+
+fn value_kind(value: &DbValue) -> &'static str {
+    match value {
+        DbValue::Int(_) => "an int",
+        DbValue::Float(_) => "a float",
+        DbValue::Text(_) => "text",
+        DbValue::Duration(_) => "a duration",
+    }
+}
+
+/// What is wrong with running the condition against the column, if anything.
+fn condition_problem(cond: &Condition, column: &DbColumn) -> Option<String> {
+    if let Err(e) = check_test_type(cond, &db_slice_from_column(column, 0, 0)) {
+        return Some(format!("Condition on '{}': {}", cond.attribute, e.text))
+    }
+    // in and between hold their own values
+    let values = match cond.op.values() {
+        values if values.is_empty() => vec![&cond.value],
+        values => values,
+    };
+    for value in values {
+        let fits = match (column, value) {
+            (_, _) if matches!(cond.op, TestOp::Starts | TestOp::NotStarts | TestOp::Ends | TestOp::NotEnds | TestOp::Contains | TestOp::NotContains | TestOp::Matches | TestOp::MatchesRegex) => matches!(value, DbValue::Text(_)),
+            (_, _) if cond.op == TestOp::IsNaN => true,
+            (DbColumn::Ints(_), DbValue::Int(_)) => true,
+            (DbColumn::Floats(_), DbValue::Float(_)) => true,
+            (DbColumn::Texts(_), DbValue::Text(_)) => true,
+            (DbColumn::LongTexts(_), DbValue::Text(_)) => true,
+            (DbColumn::Durations(_), value) if is_count_column(&cond.attribute) => row_id_from_value(value).is_ok(),
+            (DbColumn::Durations(_), value) => value.as_duration().is_ok(),
+            _ => false,
+        };
+        if !fits {
+            return Some(format!("Condition on '{}' compares a {} column to {}: {}", cond.attribute, column_kind(column), value_kind(value), value))
+        }
+    }
+    if let (TestOp::MatchesRegex, DbValue::Text(pattern), None) = (&cond.op, &cond.value, &cond.other_column) {
+        if let Some(e) = compile_regex(pattern.as_str()).err() {
+            return Some(format!("Condition on '{}': {}", cond.attribute, e.text))
+        }
+    }
+    None
+}
+
+/// What is wrong with applying the update to the column, if anything. Mirrors the checks in the update_* functions.
+fn update_problem(update: &Update, column: &DbColumn) -> Option<String> {
+    let op = update.operator;
+    let fits = match column {
+        DbColumn::Ints(_) => matches!(update.value, DbValue::Int(_)) && matches!(op, UpdateOp::Assign | UpdateOp::PlusEquals | UpdateOp::MinusEquals | UpdateOp::TimesEquals),
+        DbColumn::Floats(_) => matches!(update.value, DbValue::Float(_)) && matches!(op, UpdateOp::Assign | UpdateOp::PlusEquals | UpdateOp::MinusEquals | UpdateOp::TimesEquals),
+        DbColumn::Texts(_) => match op {
+            UpdateOp::ToLower | UpdateOp::ToUpper | UpdateOp::Trim => true,
+            UpdateOp::Assign | UpdateOp::Append | UpdateOp::Prepend => matches!(update.value, DbValue::Text(_)),
+            _ => false,
+        },
+        DbColumn::Durations(_) => match op {
+            UpdateOp::Assign | UpdateOp::PlusEquals | UpdateOp::MinusEquals => update.value.as_duration().is_ok(),
+            UpdateOp::TimesEquals => matches!(update.value, DbValue::Int(_) | DbValue::Float(_)),
+            _ => false,
+        },
+        DbColumn::LongTexts(_) => match op {
+            UpdateOp::ToLower | UpdateOp::ToUpper | UpdateOp::Trim => true,
+            UpdateOp::Assign | UpdateOp::Append | UpdateOp::Prepend => matches!(update.value, DbValue::Text(_)),
+            _ => false,
+        },
+    };
+    if fits && reject_nan() && matches!(update.value, DbValue::Float(f) if f.is_nan()) {
+        return Some(format!("Update '{} {}' would write NaN into '{}' and NaN is not accepted", op.to_keystring(), update.value, update.attribute))
+    }
+    if fits {
+        None
+    } else {
+        Some(format!("Update '{} {}' can't be applied to the {} column '{}' with {}", op.to_keystring(), update.value, column_kind(column), update.attribute, value_kind(&update.value)))
+    }
+}
+
+/// Arithmetic can only read int and float columns. Enum columns are stored as ints but are not numbers.
+fn expression_problems(expression: &Expression, table: &ColumnTable, what: &str, problems: &mut Vec<String>) {
+    for column in expression.columns() {
+        let is_enum = table.header.iter().any(|item| item.name == column && item.kind == DbType::Enum);
+        match table.columns.get(&column) {
+            Some(DbColumn::Ints(_)) if is_enum => problems.push(format!("Arithmetic can't be done on the enum column '{}'", column)),
+            Some(DbColumn::Ints(_)) | Some(DbColumn::Floats(_)) => (),
+            Some(other) => problems.push(format!("Arithmetic needs int or float columns but '{}' is a {} column", column, column_kind(other))),
+            None => problems.push(format!("Table '{}' has no column '{}' to {}", table.name, column, what)),
+        }
+    }
+}
+
+/// Computed values can go into int and float columns. Ints only take arithmetic that stays in ints.
+fn expression_update_problem(update: &Update, expression: &Expression, column: &DbColumn, table: &ColumnTable) -> Option<String> {
+    let op = update.operator;
+    if !matches!(op, UpdateOp::Assign | UpdateOp::PlusEquals | UpdateOp::MinusEquals | UpdateOp::TimesEquals) {
+        return Some(format!("Update '{}' can't take a computed value. Use =, +=, -= or *=", op.to_keystring()))
+    }
+    if expression.to_string().len() > 64 {
+        return Some(format!("The arithmetic '{}' is longer than 64 bytes", expression))
+    }
+    match column {
+        DbColumn::Ints(_) if expression.is_float(table) => Some(format!("'{}' computes floats and '{}' is an int column", expression, update.attribute)),
+        DbColumn::Ints(_) | DbColumn::Floats(_) => None,
+        other => Some(format!("Only int and float columns can be computed from other columns but '{}' is a {} column", update.attribute, column_kind(other))),
+    }
+}
+
+fn column_kind(column: &DbColumn) -> &'static str {
+    match column {
+        DbColumn::Ints(_) => "int",
+        DbColumn::Floats(_) => "float",
+        DbColumn::Texts(_) => "text",
+        DbColumn::Durations(_) => "duration",
+        DbColumn::LongTexts(_) => "long text",
+    }
+}
+
+fn condition_problems(conditions: &[OpOrCond], table: &ColumnTable, problems: &mut Vec<String>) {
+    for condition in conditions {
+        if let OpOrCond::Cond(cond) = condition {
+            match (table.columns.get(&cond.attribute), &cond.other_column) {
+                (Some(column), None) => problems.extend(condition_problem(cond, column)),
+                (Some(column), Some(other)) => match table.columns.get(other) {
+                    Some(other_column) => {
+                        let (column, other_column) = (db_slice_from_column(column, 0, 0), db_slice_from_column(other_column, 0, 0));
+                        if let Err(e) = check_test_type(cond, &column).and_then(|_| check_column_comparison(cond, &column, &other_column)) {
+                            problems.push(format!("Condition on '{}': {}", cond.attribute, e.text));
                         }
+                    },
+                    None => problems.push(format!("Table '{}' has no column '{}' to compare '{}' with", table.name, other, cond.attribute)),
+                },
+                (None, _) => problems.push(format!("Table '{}' has no column '{}' to filter on", table.name, cond.attribute)),
+            }
+        }
+    }
+}
+
+/// The enum column of that name in the header, if it is one.
+fn enum_column<'a>(header: &'a BTreeSet<HeaderItem>, name: &KeyString) -> Option<&'a HeaderItem> {
+    header.iter().find(|item| item.name == *name && item.kind == DbType::Enum)
+}
+
+/// Turns a value meant for an enum column into the position the column stores. Positions are taken as they are
+/// if they are in range. Other kinds of values are left for the type checks to report.
+fn encode_enum_value(item: &HeaderItem, value: &mut DbValue) -> Option<String> {
+    let encoded = match value {
+        DbValue::Text(text) => item.enum_index(text.as_str()),
+        DbValue::Int(index) => item.enum_value(*index).map(|_| *index),
+        _ => return None,
+    };
+    match encoded {
+        Ok(index) => {
+            *value = DbValue::Int(index);
+            None
+        },
+        Err(e) => Some(e.text),
+    }
+}
+
+fn encode_enum_conditions(conditions: &mut [OpOrCond], header: &BTreeSet<HeaderItem>, problems: &mut Vec<String>) {
+    for condition in conditions {
+        if let OpOrCond::Cond(cond) = condition {
+            // Text tests don't make sense on enums and are refused by the type checks
+            let compares_values = matches!(cond.op, TestOp::Equals | TestOp::NotEquals | TestOp::Less | TestOp::Greater | TestOp::In(_) | TestOp::Between(..));
+            match enum_column(header, &cond.attribute) {
+                Some(item) if compares_values && cond.other_column.is_none() => match cond.op.values_mut() {
+                    values if values.is_empty() => problems.extend(encode_enum_value(item, &mut cond.value)),
+                    values => for value in values {
+                        problems.extend(encode_enum_value(item, value));
+                    },
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Rewrites the values a query gives enum columns into the positions the columns store, so conditions compare ints.
+/// Less and greater follow the order the values were declared in. Returns every value an enum column doesn't allow.
+fn encode_enum_values(query: &mut Query, header: &BTreeSet<HeaderItem>) -> Vec<String> {
+    let mut problems = Vec::new();
+    match query {
+        Query::SELECT { conditions, .. } | Query::DELETE { conditions, .. } => encode_enum_conditions(conditions, header, &mut problems),
+        Query::UPDATE { conditions, updates, .. } => {
+            encode_enum_conditions(conditions, header, &mut problems);
+            for update in updates.iter_mut() {
+                match enum_column(header, &update.attribute) {
+                    Some(_) if update.expression.is_some() => problems.push(format!("Enum column '{}' can't be computed from other columns", update.attribute)),
+                    Some(item) if update.operator == UpdateOp::Assign => problems.extend(encode_enum_value(item, &mut update.value)),
+                    Some(_) => problems.push(format!("Enum column '{}' can only be assigned a value, not changed with '{}'", update.attribute, update.operator.to_keystring())),
+                    None => (),
+                }
+            }
+        },
+        Query::INSERT { inserts, .. } => if let Err(e) = inserts.encode_enums_to(header) {
+            problems.push(e.text);
+        },
+        _ => (),
+    }
+    problems
+}
+
+/// See encode_enum_values(). Does nothing to tables without enum columns.
+pub fn encode_enums(query: &mut Query, header: &BTreeSet<HeaderItem>) -> Result<(), EzError> {
+    problems_to_result(encode_enum_values(query, header))
+}
+
+/// Every problem with the columns and values a query refers to, checked against the table it will run on.
+/// Right tables of joins are checked separately since they are not known here.
+pub fn query_problems(query: &Query, table: &ColumnTable) -> Vec<String> {
+
+    let mut problems = Vec::new();
+    let encoded;
+    let query = match table.has_enum_columns() {
+        true => {
+            let mut copy = query.clone();
+            problems = encode_enum_values(&mut copy, &table.header);
+            if !problems.is_empty() {
+                return problems
+            }
+            encoded = copy;
+            &encoded
+        },
+        false => query,
+    };
+    match query {
+        Query::SELECT { columns, conditions, .. } => {
+            let select_all = columns.first().map(|c| c.as_str() == "*").unwrap_or(false);
+            let projections: Vec<Projection> = columns.iter().map(Projection::from_keystring).collect();
+            if !select_all {
+                for projection in &projections {
+                    if let Some(expression) = &projection.expression {
+                        expression_problems(expression, table, "select", &mut problems);
+                        continue
                     }
-                } else {
-                    let mut losers = Vec::new();
-                    for keeper in &keepers {
-                        match &cond.op {
-                            TestOp::Equals => {
-                                match column {
-                                    DbColumn::Ints(col) => if col[*keeper] == cond.value.to_i32() {losers.push(*keeper)},
-                                    DbColumn::Floats(col) => if col[*keeper] == cond.value.to_f32() {losers.push(*keeper)},
-                                    DbColumn::Texts(col) => if col[*keeper] == cond.value.to_keystring() {losers.push(*keeper)},
-                                }
-                            },
-                            TestOp::NotEquals => {
-                                match column {
-                                    DbColumn::Ints(col) => if col[*keeper] != cond.value.to_i32() {losers.push(*keeper)},
-                                    DbColumn::Floats(col) => if col[*keeper] != cond.value.to_f32() {losers.push(*keeper)},
-                                    DbColumn::Texts(col) => if col[*keeper] != cond.value.to_keystring() {losers.push(*keeper)},
-                                }
-                            },
-                            TestOp::Less => {
-                                match column {
-                                    DbColumn::Ints(col) => if col[*keeper] < cond.value.to_i32() {losers.push(*keeper)},
-                                    DbColumn::Floats(col) => if col[*keeper] < cond.value.to_f32() {losers.push(*keeper)},
-                                    DbColumn::Texts(col) => if col[*keeper] < cond.value.to_keystring() {losers.push(*keeper)},
-                                }
-                            },
-                            TestOp::Greater => {
-                                match column {
-                                    DbColumn::Ints(col) => if col[*keeper] > cond.value.to_i32() {losers.push(*keeper)},
-                                    DbColumn::Floats(col) => if col[*keeper] > cond.value.to_f32() {losers.push(*keeper)},
-                                    DbColumn::Texts(col) => if col[*keeper] > cond.value.to_keystring() {losers.push(*keeper)},
-                                }
-                            },
-                            TestOp::Starts => {
-                                match column {
-                                    DbColumn::Texts(col) => if col[*keeper].as_str().starts_with(cond.value.to_keystring().as_str()) {losers.push(*keeper)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'starts_with' on text values".to_owned()}),
-                                }
-                            },
-                            TestOp::Ends => {
-                                match column {
-                                    DbColumn::Texts(col) => if col[*keeper].as_str().ends_with(cond.value.to_keystring().as_str()) {losers.push(*keeper)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'ends_with' on text values".to_owned()}),
-                                }
-                            },
-                            TestOp::Contains => {
-                                match column {
-                                    DbColumn::Texts(col) => if col[*keeper].as_str().contains(cond.value.to_keystring().as_str()) {losers.push(*keeper)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'contains' on text values".to_owned()}),
-                                }
-                            },
-                        }
+                    match table.columns.get(&projection.column) {
+                        Some(DbColumn::Texts(_)) => (),
+                        Some(column) if projection.function.is_some() => problems.push(format!("Text functions can't be applied to the {} column '{}'", column_kind(column), projection.column)),
+                        Some(_) => (),
+                        None => problems.push(format!("Table '{}' has no column '{}' to select", table.name, projection.column)),
                     }
-                    remove_indices(&mut keepers, &losers);
                 }
+            }
+            condition_problems(conditions, table, &mut problems);
+        },
+        Query::UPDATE { conditions, updates, version, .. } => {
+            condition_problems(conditions, table, &mut problems);
+            if version.is_some() && !table.has_row_versions() {
+                problems.push(format!("Table '{}' has no row versions so the update can't be given a version", table.name));
+            }
+            for update in updates {
+                match table.columns.get(&update.attribute) {
+                    Some(_) if is_engine_column(&update.attribute) => problems.push(format!("Column '{}' is maintained by the engine and can't be updated", update.attribute)),
+                    Some(column) => match &update.expression {
+                        Some(expression) => {
+                            expression_problems(expression, table, "read", &mut problems);
+                            problems.extend(expression_update_problem(update, expression, column, table));
+                        },
+                        None => problems.extend(update_problem(update, column)),
+                    },
+                    None => problems.push(format!("Table '{}' has no column '{}' to update", table.name, update.attribute)),
+                }
+            }
+        },
+        Query::DELETE { conditions, .. } => condition_problems(conditions, table, &mut problems),
+        Query::INSERT { inserts, .. } => {
+            for item in &inserts.header {
+                match table.header.iter().find(|existing| existing.name == item.name) {
+                    Some(existing) if existing.kind != item.kind => problems.push(format!("Column '{}' is {:?} in table '{}' but {:?} in the inserts", item.name, existing.kind, table.name, item.kind)),
+                    Some(_) => (),
+                    None => problems.push(format!("Table '{}' has no column '{}' to insert into", table.name, item.name)),
+                }
+            }
+            if reject_nan() {
+                for name in inserts.nan_columns() {
+                    problems.push(format!("The inserts hold NaN in column '{}' and NaN is not accepted", name));
+                }
+            }
+        },
+        Query::SUMMARY { columns, .. } => {
+            for stat in columns {
+                if !table.columns.contains_key(&stat.column) {
+                    problems.push(format!("Table '{}' has no column '{}' to summarize", table.name, stat.column));
+                }
+            }
+        },
+        Query::LEFT_JOIN { match_columns, .. } if !table.columns.contains_key(&match_columns.0) => {
+            problems.push(format!("Table '{}' has no column '{}' to join on", table.name, match_columns.0));
+        },
+        _ => (),
+    }
+
+    problems
+}
+
+/// Problems with a write query checked against the stored table it targets.
+/// A missing table is not a problem here since a CREATE earlier in the batch may make it. write_to_table() reports it otherwise.
+fn stored_table_problems(query: &Query, database: &Database) -> Vec<String> {
+    if !matches!(query, Query::UPDATE{..} | Query::INSERT{..} | Query::DELETE{..}) {
+        return Vec::new()
+    }
+    let mut table_name = query.get_table_name();
+    // Every partition has the columns of the table so the first one is checked for all of them
+    if database.buffer_pool.partition_map(&table_name).is_some() {
+        table_name = match partition_name(&table_name, 0) {
+            Ok(partition) => partition,
+            Err(e) => return vec![e.text],
+        };
+    }
+    if let Err(e) = database.buffer_pool.ensure_loaded(&table_name) {
+        return vec![e.text]
+    }
+    match database.buffer_pool.tables.read().unwrap().get(&table_name) {
+        Some(table) => match database.locks.read_table(table_name, table) {
+            Ok(table) => query_problems(query, &table),
+            Err(e) => vec![e.text],
+        },
+        None => Vec::new(),
+    }
+}
+
+/// Checks a query against the table it will run on before any work is done, so a typo'd column or a value
+/// of the wrong type is reported up front instead of halfway through. All problems are reported together.
+pub fn validate_query(query: &Query, table: &ColumnTable) -> Result<(), EzError> {
+    problems_to_result(query_problems(query, table))
+}
+
+/// Every missing column problem reads "Table 'x' has no column 'y' ...".
+const MISSING_COLUMN: &str = "' has no column '";
+
+/// A query whose only problems are missing columns is NotFound so clients can tell it from a malformed query.
+fn problems_to_result(problems: Vec<String>) -> Result<(), EzError> {
+    let tag = if problems.iter().all(|problem| problem.contains(MISSING_COLUMN)) { ErrorTag::NotFound } else { ErrorTag::Query };
+    match problems.len() {
+        0 => Ok(()),
+        1 => Err(EzError{tag, text: problems[0].clone()}),
+        n => Err(EzError{tag, text: format!("Query has {} problems:\n{}", n, problems.join("\n"))}),
+    }
+}
+
+/// Whether the row at `index` passes a single condition.
+pub fn condition_matches(cond: &Condition, column: &DbSlice, index: usize) -> Result<bool, EzError> {
+
+    let matched = match (&cond.op, column) {
+        // Scans look up in tests in a hash set instead. See PreparedTests
+        (TestOp::In(values), column) => {
+            let mut found = false;
+            for value in values {
+                if compare_row_to(column, index, &cond.attribute, value)? == Some(std::cmp::Ordering::Equal) {
+                    found = true;
+                    break
+                }
+            }
+            found
+        },
+        (TestOp::Between(low, high), column) => {
+            compare_row_to(column, index, &cond.attribute, low)?.is_some_and(|order| order != std::cmp::Ordering::Less)
+                && compare_row_to(column, index, &cond.attribute, high)?.is_some_and(|order| order != std::cmp::Ordering::Greater)
+        },
+        (TestOp::Equals, DbSlice::Ints(col)) => col[index] == cond.value.to_i32(),
+        (TestOp::Equals, DbSlice::Floats(col)) => col[index] == cond.value.to_f32(),
+        (TestOp::Equals, DbSlice::Texts(col)) => col[index] == cond.value.to_keystring(),
+        (TestOp::NotEquals, DbSlice::Ints(col)) => col[index] != cond.value.to_i32(),
+        (TestOp::NotEquals, DbSlice::Floats(col)) => col[index] != cond.value.to_f32(),
+        (TestOp::NotEquals, DbSlice::Texts(col)) => col[index] != cond.value.to_keystring(),
+        (TestOp::Less, DbSlice::Ints(col)) => col[index] < cond.value.to_i32(),
+        (TestOp::Less, DbSlice::Floats(col)) => col[index] < cond.value.to_f32(),
+        (TestOp::Less, DbSlice::Texts(col)) => col[index] < cond.value.to_keystring(),
+        (TestOp::Greater, DbSlice::Ints(col)) => col[index] > cond.value.to_i32(),
+        (TestOp::Greater, DbSlice::Floats(col)) => col[index] > cond.value.to_f32(),
+        (TestOp::Greater, DbSlice::Texts(col)) => col[index] > cond.value.to_keystring(),
+        (TestOp::Equals | TestOp::NotEquals | TestOp::Less | TestOp::Greater, DbSlice::Durations(col)) if is_count_column(&cond.attribute) => {
+            let id = row_id_from_value(&cond.value)?;
+            match cond.op {
+                TestOp::Equals => col[index] == id,
+                TestOp::NotEquals => col[index] != id,
+                TestOp::Less => col[index] < id,
+                _ => col[index] > id,
+            }
+        },
+        (TestOp::Equals, DbSlice::Durations(col)) => col[index] == cond.value.as_duration()?,
+        (TestOp::NotEquals, DbSlice::Durations(col)) => col[index] != cond.value.as_duration()?,
+        (TestOp::Less, DbSlice::Durations(col)) => col[index] < cond.value.as_duration()?,
+        (TestOp::Greater, DbSlice::Durations(col)) => col[index] > cond.value.as_duration()?,
+        (TestOp::Starts | TestOp::NotStarts, DbSlice::Texts(col)) => col[index].as_str().starts_with(cond.value.to_keystring().as_str()),
+        (TestOp::Ends | TestOp::NotEnds, DbSlice::Texts(col)) => col[index].as_str().ends_with(cond.value.to_keystring().as_str()),
+        (TestOp::Contains | TestOp::NotContains, DbSlice::Texts(col)) => col[index].as_str().contains(cond.value.to_keystring().as_str()),
+        (TestOp::Matches, DbSlice::Texts(col)) => glob_matches(cond.value.to_keystring().as_str(), col[index].as_str()),
+        (TestOp::MatchesRegex, DbSlice::Texts(col)) => compile_regex(cond.value.to_keystring().as_str())?.is_match(col[index].as_str()),
+        (TestOp::IsNaN, DbSlice::Floats(col)) => col[index].is_nan(),
+        (TestOp::IsNaN, column) => return check_test_type(cond, column).map(|_| false),
+        (_, DbSlice::LongTexts(col, start, _)) => {
+            let value = col.get(start + index);
+            let target = cond.value.to_keystring();
+            let target = target.as_str();
+            match cond.op {
+                TestOp::Equals => value == target,
+                TestOp::NotEquals => value != target,
+                TestOp::Less => value < target,
+                TestOp::Greater => value > target,
+                TestOp::Starts | TestOp::NotStarts => value.starts_with(target),
+                TestOp::Ends | TestOp::NotEnds => value.ends_with(target),
+                TestOp::Contains | TestOp::NotContains => value.contains(target),
+                TestOp::Matches => glob_matches(target, value),
+                TestOp::MatchesRegex => compile_regex(target)?.is_match(value),
+                TestOp::IsNaN => false,
+                TestOp::In(_) | TestOp::Between(..) => unreachable!("Handled above"),
+            }
+        },
+        (_, column) => return check_test_type(cond, column).map(|_| false),
+    };
+
+    Ok(matched != cond.op.is_negated())
+}
+
+/// Tests the condition on a row of the columns. A condition on another column reads that column in the same row.
+pub fn condition_matches_row(cond: &Condition, columns: &BTreeMap<KeyString, DbSlice>, index: usize) -> Result<bool, EzError> {
+    match &cond.other_column {
+        Some(other) => condition_matches(&cond.resolve(&columns[other], index)?, &columns[&cond.attribute], index),
+        None => condition_matches(cond, &columns[&cond.attribute], index),
+    }
+}
+
+/// How the value in the row compares to the given value. None when they don't compare, which is only for NaN.
+fn compare_row_to(column: &DbSlice, index: usize, attribute: &KeyString, value: &DbValue) -> Result<Option<std::cmp::Ordering>, EzError> {
+    Ok(match column {
+        DbSlice::Ints(col) => col[index].partial_cmp(&value.to_i32()),
+        DbSlice::Floats(col) => col[index].partial_cmp(&value.to_f32()),
+        DbSlice::Texts(col) => col[index].partial_cmp(&value.to_keystring()),
+        DbSlice::Durations(col) if is_count_column(attribute) => col[index].partial_cmp(&row_id_from_value(value)?),
+        DbSlice::Durations(col) => col[index].partial_cmp(&value.as_duration()?),
+        DbSlice::LongTexts(col, start, _) => Some(col.get(start + index).cmp(value.to_keystring().as_str())),
+    })
+}
+
+/// The values of an in test as a hash set of the kind of values its column holds.
+#[derive(Clone, Debug)]
+enum ValueSet {
+    Ints(HashSet<i32>),
+    /// By their bits, with both zeros as one. NaN is left out since it equals nothing
+    Floats(HashSet<u32>),
+    Texts(HashSet<KeyString>),
+    Durations(HashSet<i64>),
+    LongTexts(HashSet<String>),
+}
+
+fn float_bits(value: f32) -> Option<u32> {
+    match value {
+        x if x.is_nan() => None,
+        0.0 => Some(0),
+        x => Some(x.to_bits()),
+    }
+}
+
+impl ValueSet {
+    fn new(column: &DbSlice, attribute: &KeyString, values: &[DbValue]) -> Result<ValueSet, EzError> {
+        Ok(match column {
+            DbSlice::Ints(_) => ValueSet::Ints(values.iter().map(|value| value.to_i32()).collect()),
+            DbSlice::Floats(_) => ValueSet::Floats(values.iter().filter_map(|value| float_bits(value.to_f32())).collect()),
+            DbSlice::Texts(_) => ValueSet::Texts(values.iter().map(|value| value.to_keystring()).collect()),
+            DbSlice::Durations(_) if is_count_column(attribute) => ValueSet::Durations(values.iter().map(row_id_from_value).collect::<Result<_, _>>()?),
+            DbSlice::Durations(_) => ValueSet::Durations(values.iter().map(|value| value.as_duration()).collect::<Result<_, _>>()?),
+            DbSlice::LongTexts(..) => ValueSet::LongTexts(values.iter().map(|value| value.to_keystring().as_str().to_owned()).collect()),
+        })
+    }
+
+    fn contains_row(&self, column: &DbSlice, index: usize) -> bool {
+        match (self, column) {
+            (ValueSet::Ints(set), DbSlice::Ints(col)) => set.contains(&col[index]),
+            (ValueSet::Floats(set), DbSlice::Floats(col)) => float_bits(col[index]).is_some_and(|bits| set.contains(&bits)),
+            (ValueSet::Texts(set), DbSlice::Texts(col)) => set.contains(&col[index]),
+            (ValueSet::Durations(set), DbSlice::Durations(col)) => set.contains(&col[index]),
+            (ValueSet::LongTexts(set), DbSlice::LongTexts(col, start, _)) => set.contains(col.get(start + index)),
+            _ => false,
+        }
+    }
+}
+
+/// What a test is turned into once per scan instead of once per row.
+enum Prepared {
+    Set(ValueSet),
+    Regex(Regex),
+    /// An in test on a column that isn't binary, with its values folded.
+    FoldedSet(Collation, HashSet<KeyString>),
+    /// The test with its values folded, for a column that isn't binary. Rows are folded before they are tested.
+    Folded(Collation, Box<Condition>),
+}
+
+impl Prepared {
+    fn folded(cond: &Condition, collation: Collation) -> Result<Prepared, EzError> {
+        let fold = |value: &mut DbValue| if let DbValue::Text(text) = value {
+            *text = collation.fold(text);
+        };
+        Ok(match &cond.op {
+            TestOp::In(values) => Prepared::FoldedSet(collation, values.iter().map(|value| collation.fold(&value.to_keystring())).collect()),
+            TestOp::MatchesRegex => Prepared::Regex(compile_regex(&format!("(?i){}", cond.value.to_keystring().as_str()))?),
+            _ => {
+                let mut folded = cond.clone();
+                fold(&mut folded.value);
+                folded.op.values_mut().into_iter().for_each(fold);
+                Prepared::Folded(collation, Box::new(folded))
+            },
+        })
+    }
+}
+
+/// The tests of a condition tree that are worth preparing before a scan. In tests get their values in a hash set,
+/// so a row is tested with one lookup however long the list is, and matches_regex tests get their regex compiled.
+/// Tests on text columns that aren't binary are folded to the collation of the column once here.
+/// Tests are found by their address, so build it from the tree that is evaluated.
+pub struct PreparedTests<'a> {
+    tests: Vec<(&'a Condition, Prepared)>,
+}
+
+impl<'a> PreparedTests<'a> {
+    pub fn new(tree: &'a ConditionBranch, columns: &BTreeMap<KeyString, DbSlice>, header: &BTreeSet<HeaderItem>) -> Result<PreparedTests<'a>, EzError> {
+        let mut tests = Vec::new();
+        for cond in tree.leaves() {
+            let collation = header.iter().find(|item| item.name == cond.attribute).map_or(Collation::Binary, |item| item.collation);
+            match (&cond.op, &cond.other_column) {
+                (_, None) if collation != Collation::Binary && matches!(columns[&cond.attribute], DbSlice::Texts(_)) => tests.push((cond, Prepared::folded(cond, collation)?)),
+                (TestOp::In(values), None) => tests.push((cond, Prepared::Set(ValueSet::new(&columns[&cond.attribute], &cond.attribute, values)?))),
+                (TestOp::MatchesRegex, None) => tests.push((cond, Prepared::Regex(compile_regex(cond.value.to_keystring().as_str())?))),
+                _ => (),
+            }
+        }
+        Ok(PreparedTests{tests})
+    }
+
+    /// Like condition_matches_row() but with the prepared tests.
+    pub fn matches_row(&self, cond: &Condition, columns: &BTreeMap<KeyString, DbSlice>, index: usize) -> Result<bool, EzError> {
+        match self.tests.iter().find(|(leaf, _)| std::ptr::eq(*leaf, cond)) {
+            Some((_, Prepared::Set(set))) => Ok(set.contains_row(&columns[&cond.attribute], index)),
+            Some((_, Prepared::Regex(regex))) => Ok(match &columns[&cond.attribute] {
+                DbSlice::Texts(col) => regex.is_match(col[index].as_str()),
+                DbSlice::LongTexts(col, start, _) => regex.is_match(col.get(start + index)),
+                column => return check_test_type(cond, column).map(|_| false),
+            }),
+            Some((_, Prepared::FoldedSet(collation, set))) => Ok(match &columns[&cond.attribute] {
+                DbSlice::Texts(col) => set.contains(&collation.fold(&col[index])),
+                column => return check_test_type(cond, column).map(|_| false),
+            }),
+            Some((_, Prepared::Folded(collation, folded))) => match &columns[&cond.attribute] {
+                DbSlice::Texts(col) => condition_matches(folded, &DbSlice::Texts(std::slice::from_ref(&collation.fold(&col[index]))), 0),
+                column => condition_matches(cond, column, index),
             },
+            None => condition_matches_row(cond, columns, index),
         }
     }
+}
+
+/// The rows a between test on the primary key leaves, when every row the tree holds for has to pass the test.
+/// That is when the test is the whole tree or a branch of an AND at the top. The keys are sorted so the span
+/// is two binary searches. Several such tests narrow it further.
+pub fn between_key_span(tree: &ConditionBranch, table: &ColumnTable) -> Option<std::ops::Range<usize>> {
+    let branches = match tree {
+        ConditionBranch::And(branches) => branches.as_slice(),
+        leaf => std::slice::from_ref(leaf),
+    };
+    let key_name = table.get_primary_key_col_index();
+    let mut span: Option<std::ops::Range<usize>> = None;
+    for branch in branches {
+        let (low, high) = match branch {
+            ConditionBranch::Leaf(cond) => match cond.as_ref() {
+                Condition{attribute, op: TestOp::Between(low, high), other_column: None, ..} if *attribute == key_name => (low, high),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let narrowed = match (table.columns.get(&key_name)?, low, high) {
+            (DbColumn::Ints(keys), DbValue::Int(low), DbValue::Int(high)) => keys.partition_point(|key| key < low)..keys.partition_point(|key| key <= high),
+            (DbColumn::Texts(keys), DbValue::Text(low), DbValue::Text(high)) => {
+                let collation = table.key_collation();
+                keys.partition_point(|key| collation.compare(key, low).is_lt())..keys.partition_point(|key| collation.compare(key, high).is_le())
+            },
+            _ => continue,
+        };
+        span = Some(match span {
+            Some(span) => span.start.max(narrowed.start)..span.end.min(narrowed.end),
+            None => narrowed,
+        });
+    }
+    span
+}
+
+/// The rows the keys select that lie in the span, if there is one. Rows outside it are never listed.
+fn indexes_within(table: &ColumnTable, primary_keys: &RangeOrListOrAll, span: Option<std::ops::Range<usize>>) -> Result<Vec<usize>, EzError> {
+    match (primary_keys, span) {
+        (RangeOrListOrAll::All, Some(span)) => Ok(span.collect()),
+        (RangeOrListOrAll::Range(start, stop), Some(span)) => {
+            let keys = key_range_span(table, start, stop);
+            Ok((keys.start.max(span.start)..keys.end.min(span.end)).collect())
+        },
+        (_, Some(span)) => Ok(keys_to_indexes(table, primary_keys)?.into_iter().filter(|index| span.contains(index)).collect()),
+        (_, None) => keys_to_indexes(table, primary_keys),
+    }
+}
 
-    Ok(keepers)
+/// Evaluates a flat list of conditions for a single row. See ConditionBranch::parse() for precedence.
+/// Parses the list on every call so callers testing many rows should parse once and evaluate the tree.
+pub fn evaluate_conditions<F>(conditions: &[OpOrCond], mut test: F) -> Result<bool, EzError>
+where F: FnMut(&Condition) -> Result<bool, EzError> {
+    ConditionBranch::parse(conditions)?.evaluate(&mut test)
 }
 
 
@@ -2293,11 +6588,51 @@ mod tests {
 
     use rand::Rng;
 
-    use crate::{testing_tools::{random_column_table, random_kv_query, random_query}, utilities::ksf};
+    use crate::{testing_tools::{random_column_table, random_kv_query, random_query, test_database}, utilities::ksf};
 
     use super::*;
 
 
+    #[test]
+    fn test_text_functions() {
+        let input = "id,i-P;name,t-N\n1;Apple\n2; Pear \n3;ÞORN";
+        let mut table = ColumnTable::from_csv_string(input, "fruit", "test").unwrap();
+
+        let query = Query::SELECT {
+            table_name: ksf("fruit"),
+            primary_keys: RangeOrListOrAll::All,
+            columns: vec![ksf("id"), ksf("UPPER(name)")],
+            conditions: Vec::new(),
+            distinct: false,
+            limit: None,
+        };
+        let result = execute_select_query(&query, &table).unwrap().unwrap();
+        assert_eq!(result.get_column_text(&ksf("UPPER(name)")).unwrap(), &vec![ksf("APPLE"), ksf(" PEAR "), ksf("ÞORN")]);
+        assert!(result.get_column_text(&ksf("name")).is_err());
+
+        let query = Query::UPDATE {
+            table_name: ksf("fruit"),
+            primary_keys: RangeOrListOrAll::All,
+            conditions: Vec::new(),
+            updates: vec![
+                Update{attribute: ksf("name"), operator: UpdateOp::Trim, value: DbValue::Text(KeyString::new()), expression: None},
+            ],
+            version: None,
+        };
+        execute_update_query(query, &mut table).unwrap();
+        let query = Query::UPDATE {
+            table_name: ksf("fruit"),
+            primary_keys: RangeOrListOrAll::All,
+            conditions: Vec::new(),
+            updates: vec![
+                Update{attribute: ksf("name"), operator: UpdateOp::ToLower, value: DbValue::Text(KeyString::new()), expression: None},
+            ],
+            version: None,
+        };
+        execute_update_query(query, &mut table).unwrap();
+        assert_eq!(table.get_column_text(&ksf("name")).unwrap(), &vec![ksf("apple"), ksf("pear"), ksf("þorn")]);
+    }
+
     #[test]
     fn test_parse_contained_token() {
         let text = "hello. (this part is contained). \"This one is not\"";
@@ -2342,11 +6677,13 @@ mod tests {
             primary_keys: RangeOrListOrAll::All,
             columns: vec![ksf("id"), ksf("name"), ksf("price")],
             conditions: vec![
-                OpOrCond::Cond(Condition{attribute: ksf("id"), op: TestOp::Equals, value: DbValue::Int(4)}),
+                OpOrCond::Cond(Condition{attribute: ksf("id"), op: TestOp::Equals, value: DbValue::Int(4), other_column: None}),
                 OpOrCond::Op(Operator::AND),
-                OpOrCond::Cond(Condition{attribute: ksf("name"), op: TestOp::Equals, value: DbValue::Text(ksf("four"))}),
+                OpOrCond::Cond(Condition{attribute: ksf("name"), op: TestOp::Equals, value: DbValue::Text(ksf("four")), other_column: None}),
                 
             ],
+            distinct: false,
+            limit: None,
         };
         let binary = query.to_binary();
         println!("query len = {}", binary.len());
@@ -2365,6 +6702,1013 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_malformed_query_binaries() {
+        let mut queries: Vec<Query> = (0..20).map(|_| random_query()).collect();
+        queries.push(Query::UPDATE {
+            table_name: ksf("fruit"),
+            primary_keys: RangeOrListOrAll::List(vec![ksf("1"), ksf("2")]),
+            conditions: vec![OpOrCond::Cond(Condition{attribute: ksf("name"), op: TestOp::Equals, value: DbValue::Text(ksf("apple")), other_column: None})],
+            updates: vec![Update{attribute: ksf("price"), operator: UpdateOp::Assign, value: DbValue::Float(3.0), expression: None}],
+            version: Some(2),
+        });
+        for query in &queries {
+            let binary = queries_to_binary(std::slice::from_ref(query));
+            // Every cut and every flipped byte has to come back as a Result. A panic fails the test
+            for len in 0..binary.len() {
+                let _ = parse_queries_from_binary(&binary[..len]);
+                let _ = Query::from_binary(&binary[..len]);
+            }
+            for i in 0..binary.len() {
+                let mut flipped = binary.clone();
+                flipped[i] ^= 0xFF;
+                let _ = parse_queries_from_binary(&flipped);
+            }
+        }
+        assert!(parse_queries_from_binary(&[0xFF; 40]).is_err());
+        assert!(Query::from_binary(&[]).is_err());
+    }
+
+    #[test]
+    fn test_not_conditions() {
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;apple\n2;apricot\n3;banana\n4;cherry", "fruit", "test").unwrap();
+
+        // NOT binds tighter than AND, which binds tighter than OR
+        // id = 4 OR NOT name starts_with a AND id less_than 4
+        let conditions = vec![
+            OpOrCond::Cond(Condition{attribute: ksf("id"), op: TestOp::Equals, value: DbValue::Int(4), other_column: None}),
+            OpOrCond::Op(Operator::OR),
+            OpOrCond::Not,
+            OpOrCond::Cond(Condition{attribute: ksf("name"), op: TestOp::Starts, value: DbValue::Text(ksf("a")), other_column: None}),
+            OpOrCond::Op(Operator::AND),
+            OpOrCond::Cond(Condition{attribute: ksf("id"), op: TestOp::Less, value: DbValue::Int(4), other_column: None}),
+        ];
+        let keepers = filter_keepers(&conditions, &RangeOrListOrAll::All, &table).unwrap();
+        assert_eq!(keepers, vec![2, 3]);
+
+        let negated = vec![OpOrCond::Cond(Condition{attribute: ksf("name"), op: TestOp::NotStarts, value: DbValue::Text(ksf("a")), other_column: None})];
+        assert_eq!(filter_keepers(&negated, &RangeOrListOrAll::All, &table).unwrap(), vec![2, 3]);
+
+        let query = Query::new_select("fruit").and_not_condition(ksf("name"), TestOp::Contains, ksf("an"));
+        let binary = query.to_binary();
+        assert_eq!(Query::from_binary(&binary).unwrap(), query);
+
+        let bad = vec![OpOrCond::Cond(Condition{attribute: ksf("id"), op: TestOp::Equals, value: DbValue::Int(1), other_column: None}), OpOrCond::Not];
+        assert!(filter_keepers(&bad, &RangeOrListOrAll::All, &table).is_err());
+    }
+
+    #[test]
+    fn test_grouped_conditions() {
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;apple\n2;apricot\n3;banana\n4;cherry", "fruit", "test").unwrap();
+        let conditions_of = |text: &str| match text.parse::<Query>().unwrap() {
+            Query::SELECT { conditions, .. } => conditions,
+            other => panic!("Parsed as {}", other),
+        };
+
+        let grouped = conditions_of("SELECT(table_name: fruit, conditions: (((id equals 1) OR (id equals 3)) AND (name starts_with b)))");
+        assert_eq!(filter_keepers(&grouped, &RangeOrListOrAll::All, &table).unwrap(), vec![2]);
+        let ungrouped = conditions_of("SELECT(table_name: fruit, conditions: ((id equals 1) OR (id equals 3) AND (name starts_with b)))");
+        assert_eq!(filter_keepers(&ungrouped, &RangeOrListOrAll::All, &table).unwrap(), vec![0, 2]);
+
+        let tree = ConditionBranch::parse(&grouped).unwrap();
+        assert!(matches!(&tree, ConditionBranch::And(branches) if matches!(branches[0], ConditionBranch::Or(_))));
+        assert_eq!(tree.to_conditions(), grouped);
+        assert_eq!(ConditionBranch::from_binary(&tree.to_binary()).unwrap(), tree);
+
+        let negated = conditions_of("SELECT(table_name: fruit, conditions: (NOT ((id equals 1) OR (id equals 2))))");
+        assert_eq!(filter_keepers(&negated, &RangeOrListOrAll::All, &table).unwrap(), vec![2, 3]);
+        let mut keep = delete_keep_mask(&negated, &RangeOrListOrAll::All, &table).unwrap();
+        keep[0] &= 0b1111;
+        assert_eq!(keep, vec![0b0011]);
+
+        let query = Query::SELECT { table_name: ksf("fruit"), primary_keys: RangeOrListOrAll::All, columns: vec![ksf("id")], conditions: negated, distinct: false, limit: None };
+        assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+        // Columns can be named like the operators
+        let named_or = vec![OpOrCond::Cond(Condition::new("AND", TestOp::Equals, 1).unwrap()), OpOrCond::Op(Operator::OR), OpOrCond::Cond(Condition::new("OR", TestOp::Greater, 2).unwrap())];
+        assert_eq!(conditions_from_binary(&named_or.iter().flat_map(|item| item.to_binary()).collect::<Vec<u8>>()).unwrap(), named_or);
+
+        let id_is = |id: i32| OpOrCond::Cond(Condition::new("id", TestOp::Equals, id).unwrap());
+        assert!(ConditionBranch::parse(&[OpOrCond::Open, id_is(1)]).is_err());
+        assert!(ConditionBranch::parse(&[id_is(1), OpOrCond::Close]).is_err());
+        assert!(ConditionBranch::parse(&[OpOrCond::Open, OpOrCond::Close]).is_err());
+        let too_deep: Vec<OpOrCond> = std::iter::repeat_n(OpOrCond::Not, MAX_CONDITION_DEPTH + 2).chain([id_is(1)]).collect();
+        assert!(ConditionBranch::parse(&too_deep).is_err());
+        assert!(parse_EZQL("SELECT(table_name: products, columns: (price, cost), conditions: (price greater_than column(cost)))").is_ok());
+    }
+
+    #[test]
+    fn test_column_conditions() {
+        let table = ColumnTable::from_csv_string("id,i-P;price,i-N;cost,i-N;name,t-N\n1;10;4;apple\n2;3;5;banana\n3;8;8;cherry\n4;20;1;date", "products", "test").unwrap();
+
+        let profitable = vec![OpOrCond::Cond(Condition::against_column("price", TestOp::Greater, "cost").unwrap())];
+        assert_eq!(filter_keepers(&profitable, &RangeOrListOrAll::All, &table).unwrap(), vec![0, 3]);
+        let mut expected = table.clone();
+        expected.delete_by_indexes(&filter_keepers(&profitable, &RangeOrListOrAll::All, &table).unwrap());
+        let mut streamed = table.clone();
+        streamed.retain_rows(&delete_keep_mask(&profitable, &RangeOrListOrAll::All, &table).unwrap());
+        assert_eq!(streamed, expected);
+
+        let query = parse_EZQL("SELECT(table_name: products, primary_keys: *, columns: (id, price, cost, name), conditions: ((price equals column(cost)) OR (name less_than column(name))))").unwrap();
+        match &query[0] {
+            Query::SELECT { conditions, .. } => {
+                assert_eq!(conditions[0], OpOrCond::Cond(Condition::against_column("price", TestOp::Equals, "cost").unwrap()));
+                assert_eq!(filter_keepers(conditions, &RangeOrListOrAll::All, &table).unwrap(), vec![2]);
+            },
+            other => panic!("Expected a SELECT but got {}", other),
+        }
+        assert_eq!(Query::from_binary(&query[0].to_binary()).unwrap(), query[0]);
+
+        let mismatched = vec![OpOrCond::Cond(Condition::against_column("price", TestOp::Equals, "name").unwrap())];
+        assert!(filter_keepers(&mismatched, &RangeOrListOrAll::All, &table).is_err());
+        let missing = vec![OpOrCond::Cond(Condition::against_column("price", TestOp::Equals, "margin").unwrap())];
+        assert!(filter_keepers(&missing, &RangeOrListOrAll::All, &table).is_err());
+        let select = Query::SELECT { table_name: ksf("products"), primary_keys: RangeOrListOrAll::All, columns: vec![ksf("price")], conditions: profitable, distinct: false, limit: None };
+        // Conditions are tested on the stored table so they can use columns that are not selected
+        assert!(query_problems(&select, &table).is_empty());
+        assert_eq!(execute_select_query(&select, &table).unwrap().unwrap().get_column_int(&ksf("price")).unwrap(), &vec![10, 20]);
+    }
+
+    #[test]
+    fn test_in_and_between_conditions() {
+        let table = ColumnTable::from_csv_string("id,i-P;price,f-N;name,t-N\n1;10.5;apple\n2;3;banana\n3;8;cherry\n4;20;date\n5;8;elderberry", "products", "test").unwrap();
+        let keepers = |text: &str| {
+            let conditions = match text.parse::<Query>().unwrap() {
+                Query::SELECT { conditions, .. } => conditions,
+                other => panic!("Expected a SELECT but got {}", other),
+            };
+            let mut deleted = table.clone();
+            deleted.retain_rows(&delete_keep_mask(&conditions, &RangeOrListOrAll::All, &table).unwrap());
+            assert_eq!(deleted.len() + filter_keepers(&conditions, &RangeOrListOrAll::All, &table).unwrap().len(), table.len());
+            filter_keepers(&conditions, &RangeOrListOrAll::All, &table).unwrap()
+        };
+        let select = |conditions: &str| format!("SELECT(table_name: products, primary_keys: *, columns: *, conditions: {})", conditions);
+
+        assert_eq!(keepers(&select("(name in (cherry, apple, fig))")), vec![0, 2]);
+        assert_eq!(keepers(&select("(price in (8.0, 3.0))")), vec![1, 2, 4]);
+        assert_eq!(keepers(&select("(price between 3.0 and 10.5)")), vec![0, 1, 2, 4]);
+        assert_eq!(keepers(&select("(id between 2 and 4)")), vec![1, 2, 3]);
+        assert_eq!(keepers(&select("((id between 2 and 4) AND NOT (name in (cherry)))")), vec![1, 3]);
+        assert_eq!(keepers(&select("((id between 4 and 2) OR (id equals 1))")), vec![0]);
+
+        // A between on the key that every row must pass limits the scan. One under OR can't
+        let tree = |text: &str| match text.parse::<Query>().unwrap() {
+            Query::SELECT { conditions, .. } => ConditionBranch::parse(&conditions).unwrap(),
+            other => panic!("Expected a SELECT but got {}", other),
+        };
+        assert_eq!(between_key_span(&tree(&select("((id between 2 and 4) AND (price less_than 9))")), &table), Some(1..4));
+        assert_eq!(between_key_span(&tree(&select("((id between 2 and 4) OR (price less_than 9))")), &table), None);
+        assert_eq!(between_key_span(&tree(&select("(price between 2 and 4)")), &table), None);
+
+        let query: Query = select("((name in (cherry, \"big box\")) AND (id between 1 and 3))").parse().unwrap();
+        assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+        assert!(select("(id in 1)").parse::<Query>().is_err());
+        assert!(select("(id between 1)").parse::<Query>().is_err());
+        assert!(select("(id in ())").parse::<Query>().is_err());
+        let mismatched = Query::SELECT {
+            table_name: ksf("products"),
+            primary_keys: RangeOrListOrAll::All,
+            columns: vec![ksf("*")],
+            conditions: vec![OpOrCond::Cond(Condition::new("id", TestOp::In(vec![DbValue::Int(1), DbValue::Text(ksf("two"))]), 0).unwrap())],
+            distinct: false,
+            limit: None,
+        };
+        assert_eq!(query_problems(&mismatched, &table).len(), 1);
+    }
+
+    #[test]
+    fn test_case_insensitive_conditions() {
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N-ci;tag,t-N\n1;Big Box;A\n2;big box;a\n3;Small Box;b\n4;crate;B", "boxes", "test").unwrap();
+        let keepers = |conditions: &str| {
+            let query: Query = format!("SELECT(table_name: boxes, primary_keys: *, columns: *, conditions: {})", conditions).parse().unwrap();
+            match query {
+                Query::SELECT { conditions, .. } => filter_keepers(&conditions, &RangeOrListOrAll::All, &table).unwrap(),
+                other => panic!("Expected a SELECT but got {}", other),
+            }
+        };
+
+        assert_eq!(keepers("(name equals \"BIG BOX\")"), vec![0, 1]);
+        assert_eq!(keepers("(tag equals a)"), vec![1]);
+        assert_eq!(keepers("(name starts_with small)"), vec![2]);
+        assert_eq!(keepers("(name in (CRATE, \"small box\"))"), vec![2, 3]);
+        assert_eq!(keepers("(name matches \"*BOX\")"), vec![0, 1, 2]);
+        assert_eq!(keepers("(name between B and C)"), vec![0, 1]);
+        assert_eq!(keepers("(NOT (name less_than c))"), vec![2, 3]);
+        #[cfg(feature = "regex")]
+        assert_eq!(keepers("(name matches_regex \"^big\")"), vec![0, 1]);
+
+        let keyed = ColumnTable::from_csv_string("id,t-P-ci;price,i-N\nApple;1\nbanana;2\nCherry;3", "fruit", "test").unwrap();
+        assert_eq!(keys_to_indexes(&keyed, &RangeOrListOrAll::List(vec![ksf("APPLE"), ksf("cherry"), ksf("apple")])).unwrap(), vec![0, 2]);
+        assert_eq!(keys_to_indexes(&keyed, &RangeOrListOrAll::Range(ksf("b"), ksf("D"))).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_matches_conditions() {
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N;notes,l-N\n1;big red box;fragile\n2;bigger box;heavy\n3;small box;fragile and heavy\n4;big;none", "boxes", "test").unwrap();
+        let keepers = |conditions: &str| {
+            let query: Query = format!("SELECT(table_name: boxes, primary_keys: *, columns: *, conditions: {})", conditions).parse().unwrap();
+            match query {
+                Query::SELECT { conditions, .. } => filter_keepers(&conditions, &RangeOrListOrAll::All, &table).unwrap(),
+                other => panic!("Expected a SELECT but got {}", other),
+            }
+        };
+
+        assert_eq!(keepers("(name matches \"big*box\")"), vec![0, 1]);
+        assert_eq!(keepers("(name matches \"big?box\")"), vec![]);
+        assert_eq!(keepers("(name matches big)"), vec![3]);
+        assert_eq!(keepers("(notes matches \"*heavy\")"), vec![1, 2]);
+        assert_eq!(keepers("(NOT (name matches \"*box\"))"), vec![3]);
+
+        let query: Query = "SELECT(table_name: boxes, primary_keys: *, columns: *, conditions: (name matches_regex \"^b.g\"))".parse().unwrap();
+        assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+
+        #[cfg(feature = "regex")]
+        {
+            assert_eq!(keepers("(name matches_regex \"^big(ger)? \")"), vec![0, 1]);
+            assert_eq!(keepers("(notes matches_regex \"fragile|heavy\")"), vec![0, 1, 2]);
+            let invalid: Query = "SELECT(table_name: boxes, primary_keys: *, columns: *, conditions: (name matches_regex \"(big\"))".parse().unwrap();
+            assert_eq!(query_problems(&invalid, &table).len(), 1);
+        }
+        #[cfg(not(feature = "regex"))]
+        assert_eq!(query_problems(&query, &table).len(), 1);
+
+        let wrong_column: Query = "SELECT(table_name: boxes, primary_keys: *, columns: *, conditions: (id matches \"1*\"))".parse().unwrap();
+        assert!(!query_problems(&wrong_column, &table).is_empty());
+    }
+
+    #[test]
+    fn test_streaming_delete() {
+        let table = crate::testing_tools::create_fixed_table(1000);
+        let conditions = vec![
+            OpOrCond::Cond(Condition{attribute: ksf("floats"), op: TestOp::Less, value: DbValue::Float(700.0), other_column: None}),
+            OpOrCond::Op(Operator::OR),
+            OpOrCond::Cond(Condition{attribute: ksf("texts"), op: TestOp::Ends, value: DbValue::Text(ksf("7")), other_column: None}),
+        ];
+        for keys in [RangeOrListOrAll::All, RangeOrListOrAll::Range(ksf("30"), ksf("931")), RangeOrListOrAll::List(vec![ksf("1"), ksf("999")])] {
+            let mut expected = table.clone();
+            expected.delete_by_indexes(&filter_keepers(&conditions, &keys, &table).unwrap());
+            let mut streamed = table.clone();
+            streamed.retain_rows(&delete_keep_mask(&conditions, &keys, &table).unwrap());
+            assert_eq!(streamed, expected);
+        }
+
+        let mut untouched = table.clone();
+        untouched.retain_rows(&delete_keep_mask(&conditions, &RangeOrListOrAll::Range(ksf("5000"), ksf("6000")), &table).unwrap());
+        assert_eq!(untouched, table);
+    }
+
+    #[test]
+    fn test_typed_keys() {
+        let table = crate::testing_tools::create_fixed_table(1000);
+        let ints = RangeOrListOrAll::Keys(KeyList::Ints(vec![999, 5, 5000, 1, -3]));
+        assert_eq!(keys_to_indexes(&table, &ints).unwrap(), vec![1, 5, 999]);
+        // Text key lists are parsed into the type of the primary key and looked up the same way
+        let strings = RangeOrListOrAll::List(vec![ksf("999"), ksf("5"), ksf("not a number"), ksf("1")]);
+        assert_eq!(keys_to_indexes(&table, &strings).unwrap(), vec![1, 5, 999]);
+        assert!(keys_to_indexes(&table, &RangeOrListOrAll::Keys(KeyList::Texts(vec![ksf("1")]))).is_err());
+
+        for keys in [KeyList::Ints(vec![3, -1, 7]), KeyList::Texts(vec![ksf("a"), ksf("b")]), KeyList::Ints(Vec::new())] {
+            let binary = RangeOrListOrAll::Keys(keys.clone()).to_binary();
+            assert_eq!(RangeOrListOrAll::from_binary(&binary).unwrap(), RangeOrListOrAll::Keys(keys));
+            assert!(RangeOrListOrAll::from_binary(&binary[..binary.len() - 1]).is_err());
+        }
+
+        let query = Query::DELETE { table_name: table.name, primary_keys: ints, conditions: Vec::new() };
+        let mut deleted = table.clone();
+        execute_delete_query(query, &mut deleted).unwrap();
+        let mut by_vec = table.clone();
+        by_vec.delete_by_vec(DbColumn::Ints(vec![1, 999, 5])).unwrap();
+        assert_eq!(deleted, by_vec);
+        assert_eq!(deleted.len(), 997);
+        assert!(by_vec.delete_by_vec(DbColumn::Floats(vec![1.0])).is_err());
+    }
+
+    #[test]
+    fn test_write_ack() {
+        let ack = WriteAck {
+            statuses: vec![
+                QueryAck{status: AckStatus::Done, affected: 10_000},
+                QueryAck{status: AckStatus::Failed, affected: 0},
+                QueryAck{status: AckStatus::NotRun, affected: 0},
+            ],
+            version: 7,
+            error: Some(EzError{tag: ErrorTag::NotFound, text: "No table named 'nope'".to_owned()}),
+        };
+        let binary = ack.to_binary();
+        assert_eq!(binary.len(), 80 + 3*9 + ack.error.as_ref().unwrap().to_binary().len());
+        assert_eq!(WriteAck::from_binary(&binary).unwrap(), ack);
+        assert_eq!(ack.total_affected(), 10_000);
+        assert!(ack.into_result().is_err());
+
+        let clean = WriteAck{statuses: vec![QueryAck{status: AckStatus::Done, affected: 1}], version: 1, error: None};
+        assert_eq!(WriteAck::from_binary(&clean.to_binary()).unwrap(), clean);
+        assert!(WriteAck::from_binary(b"None.").is_err());
+
+        assert!(is_write_batch(&[Query::DROP{table_name: ksf("a")}, Query::DEDUPLICATE{table_name: ksf("b")}]));
+        assert!(!is_write_batch(&[Query::DROP{table_name: ksf("a")}, Query::new_select("b")]));
+    }
+
+    #[test]
+    fn test_write_ack_versions() {
+        let database = Arc::new(test_database());
+        let insert = |id: i32| -> Query {
+            format!("INSERT(table_name: fixed_table, value_columns: (ints, floats, texts), new_values: (({}, 1.5, new)))", id).parse().unwrap()
+        };
+        assert_eq!(execute_write_queries(vec![insert(100)], database.clone()).version, 1);
+        assert_eq!(execute_write_queries(vec![insert(101), insert(102)], database.clone()).version, 2);
+
+        // Nothing was applied so the version stays where it was
+        let failed = execute_write_queries(vec![Query::DROP{table_name: ksf("nope")}], database.clone());
+        assert!(failed.error.is_some());
+        assert_eq!(failed.version, 2);
+
+        // The first insert went through before the second one failed
+        let conflict: Query = "INSERT(table_name: fixed_table, value_columns: (ints, floats, texts), new_values: ((103, 2.5, old)), on_conflict: error)".parse().unwrap();
+        let partial = execute_write_queries(vec![insert(103), conflict], database.clone());
+        assert_eq!(partial.statuses[0].status, AckStatus::Done);
+        assert!(partial.error.is_some());
+        assert_eq!(partial.version, 3);
+        assert_eq!(database.versions.applied(), 3);
+    }
+
+    #[test]
+    fn test_query_validation() {
+        let mut table = ColumnTable::from_csv_string("id,i-P;name,t-N;price,f-N;latency,d-N\n1;apple;1.5;2s\n2;pear;2.5;3s", "fruit", "test").unwrap();
+        let before = table.clone();
+
+        let update = Query::UPDATE {
+            table_name: ksf("fruit"),
+            primary_keys: RangeOrListOrAll::All,
+            conditions: vec![OpOrCond::Cond(Condition{attribute: ksf("nmae"), op: TestOp::Equals, value: DbValue::Text(ksf("apple")), other_column: None})],
+            updates: vec![
+                Update{attribute: ksf("id"), operator: UpdateOp::Append, value: DbValue::Int(1), expression: None},
+                Update{attribute: ksf("price"), operator: UpdateOp::Assign, value: DbValue::Int(3), expression: None},
+                Update{attribute: ksf("latency"), operator: UpdateOp::PlusEquals, value: DbValue::Text(ksf("1s")), expression: None},
+            ],
+            version: None,
+        };
+        let problems = query_problems(&update, &table);
+        assert_eq!(problems.len(), 3);
+        let e = execute_update_query(update, &mut table).unwrap_err();
+        assert!(e.text.starts_with("Query has 3 problems"));
+        assert_eq!(table, before);
+
+        let select = Query::SELECT {
+            table_name: ksf("fruit"),
+            primary_keys: RangeOrListOrAll::All,
+            columns: vec![ksf("id"), ksf("UPPER(price)")],
+            conditions: vec![
+                OpOrCond::Cond(Condition{attribute: ksf("id"), op: TestOp::Contains, value: DbValue::Text(ksf("1")), other_column: None}),
+                OpOrCond::Op(Operator::AND),
+                OpOrCond::Cond(Condition{attribute: ksf("name"), op: TestOp::Equals, value: DbValue::Int(4), other_column: None}),
+            ],
+            distinct: false,
+            limit: None,
+        };
+        // 'name' is not selected but conditions run on the stored table, so only its value is a problem
+        assert_eq!(query_problems(&select, &table).len(), 3);
+        assert!(validate_query(&Query::new_select("fruit"), &table).is_ok());
+    }
+
+    #[test]
+    fn test_duration_queries() {
+        let mut table = ColumnTable::from_csv_string("id,i-P;latency,d-N\n1;150ms\n2;2s\n3;900ms", "requests", "test").unwrap();
+
+        let slow = vec![OpOrCond::Cond(Condition{attribute: ksf("latency"), op: TestOp::Greater, value: DbValue::Text(ksf("500ms")), other_column: None})];
+        assert_eq!(filter_keepers(&slow, &RangeOrListOrAll::All, &table).unwrap(), vec![1, 2]);
+        let exact = vec![OpOrCond::Cond(Condition{attribute: ksf("latency"), op: TestOp::Equals, value: DbValue::Duration(2_000_000_000), other_column: None})];
+        assert_eq!(filter_keepers(&exact, &RangeOrListOrAll::All, &table).unwrap(), vec![1]);
+        let bad = vec![OpOrCond::Cond(Condition{attribute: ksf("latency"), op: TestOp::Less, value: DbValue::Int(500), other_column: None})];
+        assert!(filter_keepers(&bad, &RangeOrListOrAll::All, &table).is_err());
+
+        let update = Query::UPDATE {
+            table_name: ksf("requests"),
+            primary_keys: RangeOrListOrAll::All,
+            conditions: Vec::new(),
+            updates: vec![Update{attribute: ksf("latency"), operator: UpdateOp::PlusEquals, value: DbValue::Text(ksf("50ms")), expression: None}],
+            version: None,
+        };
+        execute_update_query(update, &mut table).unwrap();
+        assert_eq!(table.get_column_duration(&ksf("latency")).unwrap(), &vec![200_000_000, 2_050_000_000, 950_000_000]);
+
+        let summary = Query::SUMMARY {
+            table_name: ksf("requests"),
+            columns: vec![Statistic{column: ksf("latency"), actions: BTreeSet::from([StatOp::SUM, StatOp::MEAN])}],
+        };
+        let result = execute_summary_query(&summary, &table).unwrap().unwrap();
+        let stats = result.get_column_text(&ksf("latency")).unwrap();
+        assert_eq!(stats[0], ksf("3.2s"));
+        assert_eq!(stats[1], ksf("1.067s"));
+    }
+
+    #[test]
+    fn test_enum_queries() {
+        let mut table = ColumnTable::from_csv_string("id,i-P;status,e(open|in-progress|closed)-N\n1;open\n2;closed\n3;in-progress", "tickets", "test").unwrap();
+        let status = |op: TestOp, value: &str| Query::SELECT {
+            table_name: ksf("tickets"),
+            primary_keys: RangeOrListOrAll::All,
+            columns: vec![ksf("*")],
+            conditions: vec![OpOrCond::Cond(Condition{attribute: ksf("status"), op, value: DbValue::Text(ksf(value)), other_column: None})],
+            distinct: false,
+            limit: None,
+        };
+
+        // Results show the values and comparisons follow the declared order
+        let result = execute_select_query(&status(TestOp::Equals, "closed"), &table).unwrap().unwrap();
+        assert_eq!(result.get_column_int(&ksf("id")).unwrap(), &vec![2]);
+        assert_eq!(result.get_column_text(&ksf("status")).unwrap(), &vec![ksf("closed")]);
+        let result = execute_select_query(&status(TestOp::Less, "closed"), &table).unwrap().unwrap();
+        assert_eq!(result.get_column_int(&ksf("id")).unwrap(), &vec![1, 3]);
+        let e = execute_select_query(&status(TestOp::Equals, "pending"), &table).unwrap_err();
+        assert!(e.text.contains("'pending' is not allowed in column 'status'"));
+        assert!(execute_select_query(&status(TestOp::Starts, "op"), &table).is_err());
+
+        let insert = |csv: &str| Query::INSERT{table_name: ksf("tickets"), inserts: ColumnTable::from_csv_string(csv, "inserts", "test").unwrap(), on_conflict: OnConflict::Skip};
+        execute_insert_query(insert("id,i-P;status,t-N\n4;closed"), &mut table).unwrap();
+        assert_eq!(table.get_column_int(&ksf("status")).unwrap(), &vec![0, 2, 1, 2]);
+        assert!(execute_insert_query(insert("id,i-P;status,t-N\n5;pending"), &mut table).is_err());
+        assert_eq!(table.len(), 4);
+
+        let update = |operator: UpdateOp, value: &str| Query::UPDATE {
+            table_name: ksf("tickets"),
+            primary_keys: RangeOrListOrAll::All,
+            conditions: vec![OpOrCond::Cond(Condition{attribute: ksf("status"), op: TestOp::Equals, value: DbValue::Text(ksf("closed")), other_column: None})],
+            updates: vec![Update{attribute: ksf("status"), operator, value: DbValue::Text(ksf(value)), expression: None}],
+            version: None,
+        };
+        assert_eq!(update_rows(update(UpdateOp::Assign, "open"), &mut table).unwrap(), 2);
+        assert_eq!(table.get_column_int(&ksf("status")).unwrap(), &vec![0, 0, 1, 0]);
+        assert!(update_rows(update(UpdateOp::Assign, "reopened"), &mut table).is_err());
+        assert!(update_rows(update(UpdateOp::Append, "!"), &mut table).is_err());
+        assert_eq!(table.get_column_int(&ksf("status")).unwrap(), &vec![0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_insert_conflict_policies() {
+        let query: Query = "INSERT(table_name: people, value_columns: (id, name), new_values: ((2, c), (3, d)), on_conflict: overwrite)".parse().unwrap();
+        assert!(matches!(query, Query::INSERT{on_conflict: OnConflict::Overwrite, ..}));
+        assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+        assert!("INSERT(table_name: people, value_columns: (id), new_values: ((1)), on_conflict: replace)".parse::<Query>().is_err());
+
+        let people = || ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b", "people", "test").unwrap();
+        let insert = |on_conflict: OnConflict| Query::INSERT{
+            table_name: ksf("people"),
+            inserts: ColumnTable::from_csv_string("id,i-P;name,t-N\n2;c\n3;d", "inserts", "test").unwrap(),
+            on_conflict,
+        };
+
+        let mut table = people();
+        execute_insert_query(insert(OnConflict::Skip), &mut table).unwrap();
+        assert_eq!(table.get_column_text(&ksf("name")).unwrap(), &vec![ksf("a"), ksf("b"), ksf("d")]);
+
+        let mut table = people();
+        let e = execute_insert_query(insert(OnConflict::Error), &mut table).unwrap_err();
+        assert_eq!(e.tag, ErrorTag::Conflict);
+        assert_eq!(table.len(), 2);
+
+        let mut table = people();
+        execute_insert_query(insert(OnConflict::Overwrite), &mut table).unwrap();
+        assert_eq!(table.get_column_int(&ksf("id")).unwrap(), &vec![1, 2, 3]);
+        assert_eq!(table.get_column_text(&ksf("name")).unwrap(), &vec![ksf("a"), ksf("c"), ksf("d")]);
+    }
+
+    #[test]
+    fn test_min_max_count_summary() {
+        let table = ColumnTable::from_csv_string("id,i-P;price,f-N;name,t-N;latency,d-N\n1;2.5;b;150ms\n2;-1.0;a;2s\n3;7.25;c;900ms", "products", "test").unwrap();
+        let min_max_count = BTreeSet::from([StatOp::MIN, StatOp::MAX, StatOp::COUNT]);
+        let summary = Query::SUMMARY {
+            table_name: ksf("products"),
+            columns: ["id", "price", "name", "latency"].iter().map(|column| Statistic{column: ksf(column), actions: min_max_count.clone()}).collect(),
+        };
+        assert_eq!(statistics_from_binary(&statistics_to_binary(match &summary {Query::SUMMARY{columns, ..} => columns, _ => unreachable!()})).unwrap()[0].actions, min_max_count);
+
+        let result = execute_summary_query(&summary, &table).unwrap().unwrap();
+        assert_eq!(&result.get_column_text(&ksf("Statistic")).unwrap()[5..8], &[ksf("MIN"), ksf("MAX"), ksf("COUNT")]);
+        assert_eq!(&result.get_column_int(&ksf("id")).unwrap()[5..8], &[1, 3, 3]);
+        assert_eq!(&result.get_column_float(&ksf("price")).unwrap()[5..8], &[-1.0, 7.25, 3.0]);
+        assert_eq!(&result.get_column_text(&ksf("name")).unwrap()[5..8], &[ksf("can't min text"), ksf("can't max text"), ksf("3")]);
+        let latency = result.get_column_text(&ksf("latency")).unwrap();
+        assert_eq!(latency[7], ksf("3"));
+        assert_eq!(crate::db_structure::parse_duration(latency[5].as_str()).unwrap(), 150_000_000);
+        assert_eq!(crate::db_structure::parse_duration(latency[6].as_str()).unwrap(), 2_000_000_000);
+
+        assert!(parse_EZQL("SUMMARY(table_name: products, columns: ((MIN price), (id, MAX, COUNT)))").is_ok());
+    }
+
+    #[test]
+    fn test_nan_handling() {
+        let table = ColumnTable::from_csv_string("id,i-P;reading,f-N\n1;2.0\n2;NaN\n3;4.0\n4;NaN", "sensors", "test").unwrap();
+        assert_eq!(table.nan_columns(), vec![ksf("reading")]);
+
+        let select: Query = "SELECT(table_name: sensors, columns: (id, reading), conditions: (reading is_nan))".parse().unwrap();
+        let result = execute_select_query(&select, &table).unwrap().unwrap();
+        assert_eq!(result.get_column_int(&ksf("id")).unwrap(), &vec![2, 4]);
+        assert_eq!(Query::from_binary(&select.to_binary()).unwrap(), select);
+        assert!(parse_EZQL("SELECT(table_name: sensors, conditions: (id is_nan))").is_ok());
+        assert!(execute_select_query(&"SELECT(table_name: sensors, columns: (id), conditions: (id is_nan))".parse().unwrap(), &table).is_err());
+        assert!("SELECT(table_name: sensors, conditions: (id equals))".parse::<Query>().is_err());
+
+        let summary: Query = "SUMMARY(table_name: sensors, columns: ((reading, MEAN, MAX, COUNT)))".parse().unwrap();
+        let stats = execute_summary_query(&summary, &table).unwrap().unwrap();
+        let reading = stats.get_column_float(&ksf("reading")).unwrap();
+        assert_eq!((reading[1], reading[6], reading[7], reading[8]), (3.0, 4.0, 2.0, 2.0));
+    }
+
+    #[test]
+    fn test_row_timestamp_queries() {
+        let query: Query = "CREATE(table_name: tools, table: \"id,i-P;stock,i-N\n1;5\n2;7\", row_timestamps: true)".parse().unwrap();
+        let mut table = match query {
+            Query::CREATE { table, .. } => table,
+            other => panic!("Parsed as {}", other),
+        };
+        assert!(table.has_row_timestamps());
+
+        let update: Query = "UPDATE(table_name: tools, conditions: (id equals 2), updates: ((stock += 1)))".parse().unwrap();
+        assert_eq!(update_rows(update, &mut table).unwrap(), 1);
+        assert_eq!(table.get_column_int(&ksf("stock")).unwrap(), &vec![5, 8]);
+
+        let forged: Query = "UPDATE(table_name: tools, updates: ((__created_at = 0s)))".parse().unwrap();
+        assert!(update_rows(forged, &mut table).is_err());
+
+        let select: Query = "SELECT(table_name: tools, columns: (id, __updated_at), conditions: (__updated_at greater_than 1s))".parse().unwrap();
+        assert_eq!(execute_select_query(&select, &table).unwrap().unwrap().len(), 2);
+        // Timestamps are epoch seconds written as durations, so they compare with second literals. 1600000000 is 2020.
+        let select: Query = "SELECT(table_name: tools, columns: (id), conditions: (__created_at greater_than 1600000000s))".parse().unwrap();
+        assert_eq!(execute_select_query(&select, &table).unwrap().unwrap().len(), 2);
+        let select: Query = format!("SELECT(table_name: tools, columns: (id), conditions: (__created_at greater_than {}s))", get_current_time() + 60).parse().unwrap();
+        assert_eq!(execute_select_query(&select, &table).unwrap().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_into_queries() {
+        let query: Query = "SELECT(table_name: products, columns: (id, price), into_table: price_snapshot)".parse().unwrap();
+        assert_eq!(query, Query::INTO {
+            query: Box::new(Query::SELECT { table_name: ksf("products"), primary_keys: RangeOrListOrAll::All, columns: vec![ksf("id"), ksf("price")], conditions: Vec::new(), distinct: false, limit: None }),
+            target: IntoTarget::Table(ksf("price_snapshot")),
+        });
+        assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+        assert_eq!(query.get_table_name(), ksf("products"));
+
+        let query: Query = "SUMMARY(table_name: products, columns: ((SUM stock)), into_value: daily_stock)".parse().unwrap();
+        assert!(matches!(&query, Query::INTO { target: IntoTarget::Value(key), .. } if key.as_str() == "daily_stock"));
+        assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+
+        assert!("SELECT(table_name: products, into_table: a, into_value: b)".parse::<Query>().is_err());
+        assert!("DELETE(table_name: products, into_table: a)".parse::<Query>().is_err());
+
+        let result = into_result_table(&IntoTarget::Table(ksf("price_snapshot")), 12).unwrap();
+        assert_eq!(result.get_column_int(&ksf("rows")).unwrap(), &vec![12]);
+    }
+
+    #[test]
+    fn test_multi_summary_query() {
+        let query: Query = "MULTI_SUMMARY(tables: (products, sales.*), columns: ((SUM stock), (price, MEAN, MAX)))".parse().unwrap();
+        match &query {
+            Query::MULTI_SUMMARY { tables, columns } => {
+                assert_eq!(tables, &vec![ksf("products"), ksf("sales.*")]);
+                assert_eq!(columns.len(), 2);
+            },
+            other => panic!("Parsed as {}", other),
+        }
+        assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+        assert_eq!(query.to_string().parse::<Query>().unwrap(), query);
+        assert_eq!(query.get_table_name(), ksf("products"));
+
+        // The table count can't point past the end of what was sent
+        let mut binary = query.to_binary();
+        binary[8..16].copy_from_slice(&1_000u64.to_le_bytes());
+        assert!(Query::from_binary(&binary).is_err());
+        assert!("MULTI_SUMMARY(columns: ((SUM stock)))".parse::<Query>().is_err());
+    }
+
+    #[test]
+    fn test_create_from_schema_query() {
+        let query: Query = "CREATE_FROM_SCHEMA(schema: \"# shop\nproducts: id,i-P;name,t-N\norders (row_ids): id,i-P;product,i-F\")".parse().unwrap();
+        let tables = match &query {
+            Query::CREATE_FROM_SCHEMA { tables } => tables,
+            other => panic!("Parsed as {}", other),
+        };
+        assert_eq!(tables.len(), 2);
+        assert!(tables[1].has_row_ids());
+        assert!(is_write_batch(std::slice::from_ref(&query)));
+        assert_eq!(query.get_table_name(), ksf("products"));
+        assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+
+        // A table length can't point past the end of what was sent
+        let mut binary = query.to_binary();
+        binary[0..8].copy_from_slice(&3u64.to_le_bytes());
+        assert!(Query::from_binary(&binary).is_err());
+        assert!("CREATE_FROM_SCHEMA(schema: \"products id,i-P\")".parse::<Query>().is_err());
+    }
+
+    #[test]
+    fn test_alter_table_query() {
+        let queries = [
+            "ALTER_TABLE(table_name: products, add_column: \"stock,i-N\", default: 0)",
+            "ALTER_TABLE(table_name: products, add_column: \"note,t-N\", default: \"none\")",
+            "ALTER_TABLE(table_name: products, add_column: \"wait,d-N\", default: 2s)",
+            "ALTER_TABLE(table_name: products, drop_column: stock)",
+            "ALTER_TABLE(table_name: products, rename_column: (stock, in_stock))",
+            "ALTER_TABLE(table_name: products, change_type: (stock, float))",
+        ];
+        for text in queries {
+            let query: Query = text.parse().unwrap();
+            assert!(matches!(query, Query::ALTER_TABLE { .. }));
+            assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+            assert_eq!(query.to_string().parse::<Query>().unwrap(), query);
+            assert!(is_write_batch(&[query]));
+        }
+
+        assert!("ALTER_TABLE(table_name: products)".parse::<Query>().is_err());
+        assert!("ALTER_TABLE(table_name: products, drop_column: a, rename_column: (b, c))".parse::<Query>().is_err());
+        assert!("ALTER_TABLE(table_name: products, add_column: \"stock,i-N\")".parse::<Query>().is_err());
+        assert!("ALTER_TABLE(table_name: products, change_type: (stock, money))".parse::<Query>().is_err());
+
+        let mut table = crate::testing_tools::create_fixed_table(3);
+        let query: Query = "ALTER_TABLE(table_name: fixed_table, change_type: (floats, text))".parse().unwrap();
+        match query {
+            Query::ALTER_TABLE { alteration, .. } => alteration.apply(&mut table).unwrap(),
+            other => panic!("Parsed as {}", other),
+        }
+        assert_eq!(table.get_column_text(&ksf("floats")).unwrap(), &vec![ksf("0"), ksf("1"), ksf("2")]);
+    }
+
+    #[test]
+    fn test_arithmetic_projections() {
+        let input = "id,i-P;price,i-N;quantity,i-N;cost,f-N;name,t-N\n1;10;3;2.5;a\n2;20;0;5.0;b\n3;7;2;1.0;c";
+        let table = ColumnTable::from_csv_string(input, "products", "test").unwrap();
+
+        let query: Query = "SELECT(table_name: products, columns: (id, price * quantity, (price - cost) / 2))".parse().unwrap();
+        match &query {
+            Query::SELECT { columns, .. } => assert_eq!(columns, &vec![ksf("id"), ksf("price * quantity"), ksf("(price - cost) / 2")]),
+            other => panic!("Parsed as {}", other),
+        }
+        let result = execute_select_query(&query, &table).unwrap().unwrap();
+        assert_eq!(result.get_column_int(&ksf("price * quantity")).unwrap(), &vec![30, 0, 14]);
+        assert_eq!(result.get_column_float(&ksf("(price - cost) / 2")).unwrap(), &vec![3.75, 7.5, 3.0]);
+        assert!(result.get_column_int(&ksf("price")).is_err());
+
+        // Precedence and the parentheses that are kept
+        let expression = Expression::parse("price + quantity * 2 - (id - 1)").unwrap().unwrap();
+        assert_eq!(expression.to_string(), "price + quantity * 2 - (id - 1)");
+        assert_eq!(expression.columns(), vec![ksf("price"), ksf("quantity"), ksf("id")]);
+        assert_eq!(expression.evaluate(&table).unwrap(), DbColumn::Ints(vec![16, 19, 9]));
+        assert_eq!(Expression::parse("price").unwrap(), None);
+        assert_eq!(Expression::parse("*").unwrap(), None);
+        assert!(Expression::parse("price *").is_err());
+        assert!(Expression::parse("(price + 1").is_err());
+
+        // Columns read by arithmetic can be filtered on without being selected
+        let query: Query = "SELECT(table_name: products, columns: (id, price * 2), conditions: ((quantity greater_than 1)))".parse().unwrap();
+        let result = execute_select_query(&query, &table).unwrap().unwrap();
+        assert_eq!(result.get_column_int(&ksf("price * 2")).unwrap(), &vec![20, 14]);
+
+        let by_zero: Query = "SELECT(table_name: products, columns: (id, price / quantity))".parse().unwrap();
+        assert!(execute_select_query(&by_zero, &table).is_err());
+        let text: Query = "SELECT(table_name: products, columns: (id, name + 1))".parse().unwrap();
+        assert!(execute_select_query(&text, &table).is_err());
+        assert!("SELECT(table_name: products, columns: (id, price + ))".parse::<Query>().is_err());
+    }
+
+    #[test]
+    fn test_update_with_expressions() {
+        let input = "id,i-P;price,f-N;cost,f-N;stock,i-N;reserved,i-N;name,t-N\n1;0;2.5;10;3;a\n2;0;5.0;20;5;b";
+        let mut table = ColumnTable::from_csv_string(input, "products", "test").unwrap();
+
+        let query: Query = "UPDATE(table_name: products, updates: ((price = cost * 2), (stock -= column(reserved))))".parse().unwrap();
+        match &query {
+            Query::UPDATE { updates, .. } => {
+                assert_eq!(updates[0].expression, Expression::parse("cost * 2").unwrap());
+                assert_eq!(updates[1].expression, Some(Expression::Column(ksf("reserved"))));
+                assert_eq!(updates[1].to_string(), "(stock -= column(reserved))");
+            },
+            other => panic!("Parsed as {}", other),
+        }
+        assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+        update_rows(query, &mut table).unwrap();
+        assert_eq!(table.get_column_float(&ksf("price")).unwrap(), &vec![5.0, 10.0]);
+        assert_eq!(table.get_column_int(&ksf("stock")).unwrap(), &vec![7, 15]);
+
+        // Every expression reads the rows as they were before the update
+        let query: Query = "UPDATE(table_name: products, conditions: ((id equals 2)), updates: ((cost = cost + 1), (price = column(cost))))".parse().unwrap();
+        update_rows(query, &mut table).unwrap();
+        assert_eq!(table.get_column_float(&ksf("cost")).unwrap(), &vec![2.5, 6.0]);
+        assert_eq!(table.get_column_float(&ksf("price")).unwrap(), &vec![5.0, 5.0]);
+
+        for bad in [
+            "UPDATE(table_name: products, updates: ((stock = cost * 2)))",
+            "UPDATE(table_name: products, updates: ((name = stock + 1)))",
+            "UPDATE(table_name: products, updates: ((stock = name + 1)))",
+            "UPDATE(table_name: products, updates: ((price append column(cost))))",
+        ] {
+            let query: Query = bad.parse().unwrap();
+            assert!(update_rows(query, &mut table).is_err(), "{}", bad);
+        }
+        assert_eq!(table.get_column_int(&ksf("stock")).unwrap(), &vec![7, 15]);
+    }
+
+    #[test]
+    fn test_select_distinct() {
+        let input = "id,i-P;location,t-N;stock,i-N\n1;LAG15;5\n2;LAG30;5\n3;lag15;7\n4;LAG15;5\n5;LAG30;9";
+        let table = ColumnTable::from_csv_string(input, "products", "test").unwrap();
+
+        let query: Query = "SELECT(table_name: products, columns: (location), distinct: true)".parse().unwrap();
+        let result = execute_select_query(&query, &table).unwrap().unwrap();
+        assert_eq!(result.get_column_text(&ksf("location")).unwrap(), &vec![ksf("LAG15"), ksf("LAG30"), ksf("lag15")]);
+
+        let pairs: Query = "SELECT(table_name: products, columns: (location, stock), distinct: true)".parse().unwrap();
+        let result = execute_select_query(&pairs, &table).unwrap().unwrap();
+        assert_eq!(result.get_column_int(&ksf("stock")).unwrap(), &vec![5, 5, 7, 9]);
+
+        // Distinct is decided after text functions so LAG15 and lag15 count as one
+        let query: Query = "SELECT(table_name: products, columns: (UPPER(location)), distinct: true)".parse().unwrap();
+        let result = execute_select_query(&query, &table).unwrap().unwrap();
+        assert_eq!(result.get_column_text(&ksf("UPPER(location)")).unwrap(), &vec![ksf("LAG15"), ksf("LAG30")]);
+
+        let plain: Query = "SELECT(table_name: products, columns: (location))".parse().unwrap();
+        assert_eq!(execute_select_query(&plain, &table).unwrap().unwrap().len(), 5);
+        assert!("SELECT(table_name: products, distinct: maybe)".parse::<Query>().is_err());
+
+        assert!(pairs.to_string().ends_with(", distinct: true)"));
+        assert!(!plain.to_string().contains("distinct"));
+        for query in [plain, pairs] {
+            assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+        }
+    }
+
+    #[test]
+    fn test_chunked_partials_match_whole_column() {
+        let ints: Vec<i32> = (0..300_000i64).map(|i| ((i * 7919) % 100_003 - 50_000) as i32).collect();
+        let partials = int_partials(&ints);
+        assert_eq!(partials.sum, sum_i32_slice(&ints));
+        assert_eq!(partials.min, min_i32_slice(&ints));
+        assert_eq!(partials.max, max_i32_slice(&ints));
+        assert_eq!(int_partials(&[]), Partials::<i32>::default());
+
+        let floats: Vec<f32> = ints.iter().map(|i| *i as f32 / 8.0).collect();
+        let partials = float_partials(&floats);
+        assert_eq!(partials.min, min_f32_slice(&floats));
+        assert_eq!(partials.max, max_f32_slice(&floats));
+        assert!((partials.sum - sum_f32_slice(&floats)).abs() <= sum_f32_slice(&floats).abs() * 1e-4);
+    }
+
+    #[test]
+    fn test_chained_deduplicate() {
+        let database = Arc::new(test_database());
+        let fruit = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;apple\n2;pear\n3;apple", "fruit", "test").unwrap();
+        database.buffer_pool.add_table(fruit).unwrap();
+        let select: Query = "SELECT(table_name: fruit, primary_keys: *, columns: (id, name))".parse().unwrap();
+        let dedup = Query::DEDUPLICATE{table_name: ksf("fruit")};
+        let result = execute_EZQL_queries(vec![select, dedup], database.clone()).unwrap().unwrap();
+        assert_eq!(result.get_column_int(&ksf("id")).unwrap(), &vec![1, 2]);
+        // Only the result was deduplicated
+        assert_eq!(database.buffer_pool.table_rows(&ksf("fruit")), Some(3));
+    }
+
+    #[test]
+    fn test_left_join_system_tables() {
+        let database = Arc::new(test_database());
+        // Every column in ez_columns finds its table in ez_tables
+        let join: Query = "LEFT_JOIN(left_table: ez_columns, right_table: ez_tables, match_columns: (table_name, table_name), primary_keys: *)".parse().unwrap();
+        let joined = execute_EZQL_queries(vec![join.clone()], database.clone()).unwrap().unwrap();
+        assert_eq!(joined.get_column_int(&ksf("rows")).unwrap(), &vec![10, 10, 10]);
+
+        // The same join in a batch that writes
+        let insert: Query = "INSERT(table_name: fixed_table, value_columns: (ints, floats, texts), new_values: ((100, 1.5, new)))".parse().unwrap();
+        let joined = execute_EZQL_queries(vec![insert, join], database.clone()).unwrap().unwrap();
+        assert_eq!(joined.get_column_int(&ksf("rows")).unwrap(), &vec![11, 11, 11]);
+
+        // A row without a match is an error rather than a panic, with the system table on either side
+        let people = ColumnTable::from_csv_string("username,t-P;age,i-N\nadmin;30\nnobody;40", "people", "test").unwrap();
+        database.buffer_pool.add_table(people).unwrap();
+        let join: Query = "LEFT_JOIN(left_table: people, right_table: ez_users, match_columns: (username, username), primary_keys: *)".parse().unwrap();
+        let e = execute_EZQL_queries(vec![join], database.clone()).unwrap_err();
+        assert_eq!(e.tag, ErrorTag::Query);
+        assert!(e.text.contains("'nobody' in column 'username' has no match in table 'ez_users'"), "{}", e.text);
+        let join: Query = "LEFT_JOIN(left_table: ez_users, right_table: people, match_columns: (username, username), primary_keys: *)".parse().unwrap();
+        assert!(execute_EZQL_queries(vec![join], database.clone()).is_err());
+
+        let join: Query = "LEFT_JOIN(left_table: fixed_table, right_table: ez_tables, match_columns: (floats, floats), primary_keys: *)".parse().unwrap();
+        assert!(execute_EZQL_queries(vec![join], database.clone()).is_err());
+        let insert: Query = "INSERT(table_name: fixed_table, value_columns: (ints, floats, texts), new_values: ((101, 1.5, new)))".parse().unwrap();
+        let join: Query = "LEFT_JOIN(left_table: fixed_table, right_table: nope, match_columns: (ints, ints), primary_keys: *)".parse().unwrap();
+        assert!(execute_EZQL_queries(vec![insert, join], database.clone()).is_err());
+    }
+
+    #[test]
+    fn test_select_copies_only_kept_rows() {
+        let mut input = String::from("id,i-P;stock,i-N;name,t-N");
+        for id in 0..1000 {
+            input.push_str(&format!("\n{};{};item{}", id, id % 10, id));
+        }
+        let table = ColumnTable::from_csv_string(&input, "products", "test").unwrap();
+        let copied = |query: &str| {
+            let query: Query = query.parse().unwrap();
+            crate::db_structure::CELLS_COPIED.with(|cells| cells.set(0));
+            let result = execute_select_query(&query, &table).unwrap().unwrap();
+            (result, crate::db_structure::CELLS_COPIED.with(|cells| cells.get()))
+        };
+
+        // The filter runs on the source, so only the 5 kept rows of the 2 selected columns are copied
+        let (result, cells) = copied("SELECT(table_name: products, columns: (id, stock), conditions: (stock equals 3), limit: 5)");
+        assert_eq!(result.get_column_int(&ksf("id")).unwrap(), &vec![3, 13, 23, 33, 43]);
+        assert_eq!(cells, 10);
+
+        let (result, cells) = copied("SELECT(table_name: products, columns: (name, stock), conditions: (stock equals 7))");
+        assert_eq!(result.len(), 100);
+        assert_eq!(cells, 200);
+
+        // Computed columns copy the kept rows of the columns they read
+        let (result, cells) = copied("SELECT(table_name: products, columns: (id, UPPER(name)), conditions: (id less 4))");
+        assert_eq!(result.len(), 4);
+        assert_eq!(cells, 8);
+    }
+
+    #[test]
+    fn test_select_limit_stops_scanning() {
+        let mut input = String::from("id,i-P;stock,i-N");
+        for id in 0..1000 {
+            input.push_str(&format!("\n{};{}", id, id % 10));
+        }
+        let table = ColumnTable::from_csv_string(&input, "products", "test").unwrap();
+
+        let query: Query = "SELECT(table_name: products, conditions: (stock equals 3), limit: 5)".parse().unwrap();
+        let result = execute_select_query(&query, &table).unwrap().unwrap();
+        assert_eq!(result.get_column_int(&ksf("id")).unwrap(), &vec![3, 13, 23, 33, 43]);
+
+        let conditions = match &query {
+            Query::SELECT { conditions, .. } => conditions.clone(),
+            other => panic!("Parsed as {}", other),
+        };
+        let scan = filter_keepers_limited(&conditions, &RangeOrListOrAll::All, &table, Some(5)).unwrap();
+        assert_eq!(scan.keepers.len(), 5);
+        assert_eq!(scan.rows_scanned, 44);
+        let full = filter_keepers_limited(&conditions, &RangeOrListOrAll::All, &table, None).unwrap();
+        assert_eq!(full.keepers.len(), 100);
+        assert_eq!(full.rows_scanned, 1000);
+        let unfiltered = filter_keepers_limited(&Vec::new(), &RangeOrListOrAll::All, &table, Some(3)).unwrap();
+        assert_eq!(unfiltered, Scan{keepers: vec![0, 1, 2], rows_scanned: 3});
+
+        // With distinct the limit counts distinct rows
+        let query: Query = "SELECT(table_name: products, columns: (stock), distinct: true, limit: 4)".parse().unwrap();
+        let result = execute_select_query(&query, &table).unwrap().unwrap();
+        assert_eq!(result.get_column_int(&ksf("stock")).unwrap(), &vec![0, 1, 2, 3]);
+
+        assert!("SELECT(table_name: products, limit: many)".parse::<Query>().is_err());
+        assert!(query.to_string().ends_with(", distinct: true, limit: 4)"));
+        let plain: Query = "SELECT(table_name: products, limit: 7)".parse().unwrap();
+        for query in [query, plain] {
+            assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), query);
+        }
+    }
+
+    #[test]
+    fn test_row_id_queries() {
+        let query: Query = "CREATE(table_name: tools, table: \"id,i-P;stock,i-N\n1;5\n2;7\", row_ids: true)".parse().unwrap();
+        let mut table = match query {
+            Query::CREATE { table, .. } => table,
+            other => panic!("Parsed as {}", other),
+        };
+        assert!(table.has_row_ids());
+
+        // Hidden unless asked for
+        let select: Query = "SELECT(table_name: tools, columns: *)".parse().unwrap();
+        assert!(!execute_select_query(&select, &table).unwrap().unwrap().has_row_ids());
+        let select: Query = "SELECT(table_name: tools, columns: (stock, __row_id), conditions: (__row_id equals 2))".parse().unwrap();
+        let result = execute_select_query(&select, &table).unwrap().unwrap();
+        assert_eq!(result.get_column_int(&ksf("stock")).unwrap(), &vec![7]);
+        assert_eq!(result.get_column_duration(&ksf("__row_id")).unwrap(), &vec![2]);
+
+        let forged: Query = "UPDATE(table_name: tools, updates: ((__row_id = 0s)))".parse().unwrap();
+        assert!(update_rows(forged, &mut table).is_err());
+    }
+
+    #[test]
+    fn test_versioned_updates() {
+        let query: Query = "CREATE(table_name: tools, table: \"id,i-P;stock,i-N\n1;5\n2;7\", row_versions: true)".parse().unwrap();
+        let mut table = match query {
+            Query::CREATE { table, .. } => table,
+            other => panic!("Parsed as {}", other),
+        };
+        assert_eq!(table.get_column_duration(&ksf("__version")).unwrap(), &vec![1, 1]);
+
+        let update: Query = "UPDATE(table_name: tools, primary_keys: 1, updates: ((stock -= 1)), version: 1)".parse().unwrap();
+        assert!(update.to_string().ends_with(", version: 1)"));
+        assert_eq!(Query::from_binary(&update.to_binary()).unwrap(), update);
+        update_rows(update.clone(), &mut table).unwrap();
+        assert_eq!(table.get_column_duration(&ksf("__version")).unwrap(), &vec![2, 1]);
+
+        // A second client that read version 1 is refused and nothing changes
+        let e = update_rows(update, &mut table).unwrap_err();
+        assert_eq!(e.tag, ErrorTag::Conflict);
+        assert!(e.text.contains("version 2"));
+        assert_eq!(table.get_column_int(&ksf("stock")).unwrap(), &vec![4, 7]);
+
+        // Unconditional writes and overwriting inserts bump versions too
+        let update: Query = "UPDATE(table_name: tools, updates: ((stock += 1)))".parse().unwrap();
+        update_rows(update, &mut table).unwrap();
+        let inserts = ColumnTable::from_csv_string("id,i-P;stock,i-N\n2;0\n3;1", "inserts", "test").unwrap();
+        table.update(&inserts).unwrap();
+        assert_eq!(table.get_column_duration(&ksf("__version")).unwrap(), &vec![3, 3, 1]);
+
+        let select: Query = "SELECT(table_name: tools, columns: (id, __version), conditions: (__version equals 3))".parse().unwrap();
+        assert_eq!(execute_select_query(&select, &table).unwrap().unwrap().get_column_int(&ksf("id")).unwrap(), &vec![1, 2]);
+        let forged: Query = "UPDATE(table_name: tools, updates: ((__version = 0s)))".parse().unwrap();
+        assert!(update_rows(forged, &mut table).is_err());
+
+        let mut plain = ColumnTable::from_csv_string("id,i-P;stock,i-N\n1;5", "plain", "test").unwrap();
+        let update: Query = "UPDATE(table_name: plain, updates: ((stock -= 1)), version: 1)".parse().unwrap();
+        assert!(update_rows(update, &mut plain).is_err());
+    }
+
+    #[test]
+    fn test_parse_ezql_text() {
+        let query: Query = "SELECT(table_name: products, primary_keys: *, columns: (price, LOWER(name)), conditions: ((price greater_than 500) AND NOT (name starts-with \"big box\")))".parse().unwrap();
+        assert_eq!(query, Query::SELECT {
+            table_name: ksf("products"),
+            primary_keys: RangeOrListOrAll::All,
+            columns: vec![ksf("price"), ksf("LOWER(name)")],
+            conditions: vec![
+                OpOrCond::Cond(Condition{attribute: ksf("price"), op: TestOp::Greater, value: DbValue::Int(500), other_column: None}),
+                OpOrCond::Op(Operator::AND),
+                OpOrCond::Not,
+                OpOrCond::Cond(Condition{attribute: ksf("name"), op: TestOp::Starts, value: DbValue::Text(ksf("big box")), other_column: None}),
+            ],
+            distinct: false,
+            limit: None,
+        });
+
+        let query: Query = "UPDATE(table_name: products, primary_keys: (0113035, 0113000), conditions: (id starts_with 011), updates: ((price += 100), (stock -= 1.5), (name trim)))".parse().unwrap();
+        assert_eq!(query, Query::UPDATE {
+            table_name: ksf("products"),
+            primary_keys: RangeOrListOrAll::List(vec![ksf("0113035"), ksf("0113000")]),
+            conditions: vec![OpOrCond::Cond(Condition{attribute: ksf("id"), op: TestOp::Starts, value: DbValue::Text(ksf("011")), other_column: None})],
+            updates: vec![
+                Update{attribute: ksf("price"), operator: UpdateOp::PlusEquals, value: DbValue::Int(100), expression: None},
+                Update{attribute: ksf("stock"), operator: UpdateOp::MinusEquals, value: DbValue::Float(1.5), expression: None},
+                Update{attribute: ksf("name"), operator: UpdateOp::Trim, value: DbValue::Text(KeyString::new()), expression: None},
+            ],
+            version: None,
+        });
+
+        // Leaving out the columns or giving a bare * selects every column
+        for text in ["SELECT(table_name: products)", "SELECT(table_name: products, columns: *)", "SELECT(table_name: products, columns: (*))"] {
+            let query: Query = text.parse().unwrap();
+            assert_eq!(query, Query::new_select("products"), "{}", text);
+        }
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b", "products", "test").unwrap();
+        let query: Query = "SELECT(table_name: products)".parse().unwrap();
+        assert_eq!(execute_select_query(&query, &table).unwrap().unwrap(), table.subtable_from_columns(&[ksf("*")], "RESULT").unwrap());
+
+        // Numbers with a leading zero stay text but zero itself and decimals below one are numbers
+        let query: Query = "DELETE(table_name: products, conditions: ((id equals 0) OR (id equals 0.5) OR (code equals -007) OR (code equals 00)))".parse().unwrap();
+        match query {
+            Query::DELETE { conditions, .. } => {
+                let values: Vec<DbValue> = conditions.iter().filter_map(|c| match c { OpOrCond::Cond(c) => Some(c.value.clone()), _ => None }).collect();
+                assert_eq!(values, vec![DbValue::Int(0), DbValue::Float(0.5), DbValue::Text(ksf("-007")), DbValue::Text(ksf("00"))]);
+            },
+            other => panic!("Expected DELETE, got {}", other),
+        }
+
+        let chain = parse_EZQL("
+            LEFT_JOIN(left_table: products, right_table: warehouses, match_columns: (location, id), primary_keys: 0113000..18572054)
+            ->
+            SUMMARY(table_name: __RESULT__, columns: ((SUM stock), (MEAN stock), (price, MEDIAN, MODE)))
+        ").unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0].get_primary_keys_ref(), Some(&RangeOrListOrAll::Range(ksf("0113000"), ksf("18572054"))));
+        match &chain[1] {
+            Query::SUMMARY { columns, .. } => {
+                assert_eq!(columns.len(), 2);
+                assert_eq!(columns[0].actions, BTreeSet::from([StatOp::SUM, StatOp::MEAN]));
+            },
+            other => panic!("Expected SUMMARY, got {}", other),
+        }
+
+        let query: Query = "INSERT(table_name: products, value_columns: (id, stock, location), new_values: ((113035, 500, LAG15), (113000, 100, LAG30)))".parse().unwrap();
+        match query {
+            Query::INSERT { inserts, .. } => {
+                assert_eq!(inserts.len(), 2);
+                assert_eq!(inserts.get_column_int(&ksf("id")).unwrap(), &vec![113000, 113035]);
+            },
+            other => panic!("Expected INSERT, got {}", other),
+        }
+
+        for (bad, expected) in [
+            ("SELECT(table_name: products", "never closed"),
+            ("SELECT(primary_keys: *)", "requires the argument 'table_name'"),
+            ("SELECT(table_name: products, colour: red)", "does not take the argument 'colour'"),
+            ("SELECT(table_name: products, conditions: ((price bigger 5)))", "'bigger' is not a test"),
+            ("SELECT(table_name: products, conditions: ((price equals 5) AND))", "can not end with 'AND'"),
+            ("SELECT(table_name: products, conditions: ((price equals 5) (stock equals 2)))", "Expected AND or OR"),
+            ("SELECT(table_name: \"products)", "Unclosed"),
+            ("FROB(table_name: products)", "not supported"),
+        ] {
+            let e = parse_EZQL(bad).unwrap_err();
+            assert_eq!(e.tag, ErrorTag::Query);
+            assert!(e.text.contains(expected), "'{}' gave '{}'", bad, e.text);
+        }
+    }
+
     #[test]
     fn test_base_kv_query() {
         let kv_query = KvQuery::Create(ksf("test"), vec![0,1,2,3,4,5,6,7,8,9]);
@@ -2372,6 +7716,43 @@ mod tests {
         let parsed_query = KvQuery::from_binary(&bin_query).unwrap();
 
         assert_eq!(kv_query, parsed_query);
+
+        let kv_query = KvQuery::WriteMany(vec![(ksf("a"), vec![1, 2, 3]), (ksf("b"), vec![]), (ksf("c"), vec![4])]);
+        let bin_query = kv_query.to_binary();
+        assert_eq!(KvQuery::from_binary(&bin_query).unwrap(), kv_query);
+        assert!(KvQuery::from_binary(&bin_query[..bin_query.len() - 1]).is_err());
+
+        let read_many = KvQuery::ReadMany(vec![ksf("a"), ksf("b")]);
+        let mut binary = read_many.to_binary();
+        binary.extend_from_slice(&bin_query);
+        binary.extend_from_slice(&KvQuery::Read(ksf("a")).to_binary());
+        assert_eq!(parse_kv_queries_from_binary(&binary).unwrap(), vec![read_many, kv_query, KvQuery::Read(ksf("a"))]);
+
+        let with_ttl = KvQuery::UpdateWithTtl(ksf("a"), vec![1, 2], 30);
+        let mut binary = with_ttl.to_binary();
+        binary.extend_from_slice(&KvQuery::Read(ksf("a")).to_binary());
+        assert_eq!(parse_kv_queries_from_binary(&binary).unwrap(), vec![with_ttl, KvQuery::Read(ksf("a"))]);
+        assert!(KvQuery::from_binary(&binary[..140]).is_err());
+
+        let scan = KvQuery::Scan(ksf("user:"), ValueFilter{min_size: 2, body_prefix: b"{\"".to_vec(), max_age_secs: 60, ..ValueFilter::new()});
+        let mut binary = scan.to_binary();
+        binary.extend_from_slice(&KvQuery::Read(ksf("a")).to_binary());
+        assert_eq!(parse_kv_queries_from_binary(&binary).unwrap(), vec![scan, KvQuery::Read(ksf("a"))]);
+        assert!(KvQuery::from_binary(&binary[..169]).is_err());
+    }
+
+    #[test]
+    fn test_value_filter() {
+        let value = Value{name: ksf("user:1"), body: b"{\"name\": \"x\"}".to_vec()};
+        assert!(ValueFilter::new().matches(&value, None));
+        assert!(ValueFilter{min_size: 13, max_size: 13, ..ValueFilter::new()}.matches(&value, Some(0)));
+        assert!(!ValueFilter{max_size: 12, ..ValueFilter::new()}.matches(&value, Some(0)));
+        assert!(ValueFilter{body_prefix: b"{\"name".to_vec(), ..ValueFilter::new()}.matches(&value, Some(0)));
+        assert!(!ValueFilter{body_prefix: b"[".to_vec(), ..ValueFilter::new()}.matches(&value, Some(0)));
+        let older_than_a_minute = ValueFilter{min_age_secs: 60, ..ValueFilter::new()};
+        assert!(older_than_a_minute.matches(&value, Some(61)));
+        assert!(!older_than_a_minute.matches(&value, Some(59)));
+        assert!(!older_than_a_minute.matches(&value, None));
     }
 
     #[test]
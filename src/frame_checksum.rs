@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use crate::utilities::{u32_from_le_slice, ErrorTag, EzError};
+
+
+/// A checksum the client asks for by appending a byte to its auth buffer. Once agreed, every frame in
+/// both directions carries the checksum of its plaintext after the payload: [payload][checksum]
+/// The noise transport already catches tampering. This catches our own framing and compression bugs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum FrameChecksum {
+    #[default]
+    None,
+    /// CRC-32 (IEEE), 4 bytes little endian.
+    Crc32,
+}
+
+impl FrameChecksum {
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            FrameChecksum::None => 0,
+            FrameChecksum::Crc32 => 1,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Result<FrameChecksum, EzError> {
+        match byte {
+            0 => Ok(FrameChecksum::None),
+            1 => Ok(FrameChecksum::Crc32),
+            other => Err(EzError{tag: ErrorTag::Authentication, text: format!("Unknown frame checksum: {}", other)}),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            FrameChecksum::None => 0,
+            FrameChecksum::Crc32 => 4,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFF;
+    for byte in bytes {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Appends the checksum of the frame to it.
+pub fn seal_frame(mut frame: Vec<u8>, checksum: FrameChecksum) -> Vec<u8> {
+    match checksum {
+        FrameChecksum::None => (),
+        FrameChecksum::Crc32 => {
+            let crc = crc32(&frame);
+            frame.extend_from_slice(&crc.to_le_bytes());
+        },
+    }
+    frame
+}
+
+/// Verifies the checksum at the end of the frame and strips it off.
+pub fn open_frame(mut frame: Vec<u8>, checksum: FrameChecksum) -> Result<Vec<u8>, EzError> {
+    if frame.len() < checksum.len() {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Frame of {} bytes is too short to carry a checksum", frame.len())})
+    }
+    let payload_len = frame.len() - checksum.len();
+    match checksum {
+        FrameChecksum::None => (),
+        FrameChecksum::Crc32 => {
+            let expected = u32_from_le_slice(&frame[payload_len..]);
+            let actual = crc32(&frame[..payload_len]);
+            if expected != actual {
+                return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Frame is corrupt. CRC-32 should be {:08x} but is {:08x}", expected, actual)})
+            }
+        },
+    }
+    frame.truncate(payload_len);
+    Ok(frame)
+}
+
+/// How many frames were verified and how many failed. The checksum a connection agreed on is kept in its Session.
+pub struct FrameChecks {
+    pub frames_checked: AtomicU64,
+    pub corrupt_frames: AtomicU64,
+}
+
+impl Default for FrameChecks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameChecks {
+    pub fn new() -> FrameChecks {
+        FrameChecks {
+            frames_checked: AtomicU64::new(0),
+            corrupt_frames: AtomicU64::new(0),
+        }
+    }
+
+    /// Verifies an incoming frame with the checksum of its connection, counting it and any corruption.
+    pub fn open(&self, checksum: FrameChecksum, frame: Vec<u8>) -> Result<Vec<u8>, EzError> {
+        if checksum == FrameChecksum::None {
+            return Ok(frame)
+        }
+        self.frames_checked.fetch_add(1, Ordering::Relaxed);
+        let opened = open_frame(frame, checksum);
+        if opened.is_err() {
+            self.corrupt_frames.fetch_add(1, Ordering::Relaxed);
+        }
+        opened
+    }
+}
+
+/// The client side of the agreement, keyed by the file descriptor of each connection.
+static CLIENT_SESSIONS: RwLock<BTreeMap<u64, FrameChecksum>> = RwLock::new(BTreeMap::new());
+
+pub fn set_client_checksum(connection_id: u64, checksum: FrameChecksum) {
+    CLIENT_SESSIONS.write().unwrap().insert(connection_id, checksum);
+}
+
+pub fn client_checksum(connection_id: u64) -> FrameChecksum {
+    CLIENT_SESSIONS.read().unwrap().get(&connection_id).copied().unwrap_or(FrameChecksum::None)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+        let sealed = seal_frame(b"QUERY".to_vec(), FrameChecksum::Crc32);
+        assert_eq!(sealed.len(), 9);
+        assert_eq!(open_frame(sealed.clone(), FrameChecksum::Crc32).unwrap(), b"QUERY".to_vec());
+
+        let checks = FrameChecks::new();
+        let mut corrupt = sealed.clone();
+        corrupt[1] ^= 0x20;
+        assert!(checks.open(FrameChecksum::Crc32, corrupt).is_err());
+        assert_eq!(checks.open(FrameChecksum::Crc32, sealed).unwrap(), b"QUERY".to_vec());
+        assert_eq!(checks.frames_checked.load(Ordering::Relaxed), 2);
+        assert_eq!(checks.corrupt_frames.load(Ordering::Relaxed), 1);
+
+        // Connections that didn't ask for checksums are passed through untouched
+        assert_eq!(checks.open(FrameChecksum::None, b"QUERY".to_vec()).unwrap(), b"QUERY".to_vec());
+        assert_eq!(checks.frames_checked.load(Ordering::Relaxed), 2);
+    }
+}
@@ -1,13 +1,13 @@
 use core::str;
-use std::{collections::BTreeMap, sync::{Arc, RwLock}};
+use std::{collections::BTreeMap, sync::{atomic::Ordering, Arc}};
 
 use ezcbor::cbor::decode_cbor;
-use eznoise::Connection;
+use crate::transport::Transport;
 
-use crate::{auth::{check_permission, User}, utilities::ErrorTag};
+use crate::{auth::{add_user, User}, utilities::ErrorTag};
 use crate::ezql::{execute_EZQL_queries}; 
-use crate::utilities::{EzError, KeyString};
-use crate::server_networking::Database;
+use crate::utilities::EzError;
+use crate::database::Database;
 
 #[allow(unused)]
 use crate::PATH_SEP;
@@ -15,7 +15,7 @@ use crate::PATH_SEP;
 
 /// Handles the request for the list of tables.
 pub fn handle_meta_list_tables(
-    connection: &mut Connection, 
+    connection: &mut Transport, 
     database: Arc<Database>,
 ) -> Result<(), EzError> {
     println!("calling: handle_meta_list_tables()");
@@ -23,13 +23,20 @@ pub fn handle_meta_list_tables(
 
     let mut tables = BTreeMap::new();
     for (table_name, table) in database.buffer_pool.tables.read().unwrap().iter() {
-        tables.insert(*table_name, table.read().unwrap().header.clone());
+        let table = table.read().unwrap();
+        tables.insert(*table_name, (table.header.clone(), table.metadata.clone()));
     }
 
     let mut printer = String::new();
-    for (table_name, table_header) in tables.iter() {
+    for (table_name, (table_header, metadata)) in tables.iter() {
         printer.push_str(table_name.as_str());
         printer.push('\n');
+        printer.push_str(&format!(
+            "created_by:{};\tlast_access:{};\ttimes_accessed:{}\n",
+            metadata.created_by,
+            metadata.last_access.load(Ordering::Relaxed),
+            metadata.times_accessed.load(Ordering::Relaxed),
+        ));
         for item in table_header {
             printer.push_str(&item.to_string());
             printer.push_str(";\t");
@@ -38,7 +45,7 @@ pub fn handle_meta_list_tables(
     }
     printer.pop();
 
-    match connection.SEND_C2(printer.as_bytes()) {
+    match connection.send_to_client(printer.as_bytes()) {
         Ok(_) => Ok(()),
         Err(e) => Err(e.into()),
     }
@@ -47,7 +54,7 @@ pub fn handle_meta_list_tables(
 
 /// Handles the request for a list of keys with associated binary blobs
 pub fn handle_meta_list_key_values(
-    connection: &mut Connection, 
+    connection: &mut Transport, 
     database: Arc<Database>,
 ) -> Result<(), EzError> {
     println!("calling: handle_meta_list_key_values()");
@@ -69,8 +76,8 @@ pub fn handle_meta_list_key_values(
         printer.push_str("No key value pairs in database");
     }
 
-    connection.SEND_C2(printer.as_bytes())?;
-    let response = connection.RECEIVE_C1()?;
+    connection.send_to_client(printer.as_bytes())?;
+    let response = connection.receive_from_client()?;
     let response = String::from_utf8(response)?;
 
     if response == "OK" {
@@ -84,18 +91,18 @@ pub fn handle_meta_list_key_values(
 
 /// Handles a create user request from a client. The user requesting the new user must have permission to create users
 pub fn handle_new_user_request(
-    connection: &mut Connection,
+    connection: &mut Transport,
     database: Arc<Database>,
 ) -> Result<(), EzError> {
     println!("calling: handle_new_user_request()");
 
-    let user_bytes = connection.RECEIVE_C1()?;
+    let user_bytes = connection.receive_from_client()?;
     let user: User = decode_cbor(&user_bytes)?;
 
-    let mut user_lock = database.users.write().unwrap();
-    user_lock.insert(KeyString::from(user.username.as_str()), RwLock::new(user));
+    add_user(&database.users, user)?;
+    database.save_users()?;
     
-    connection.SEND_C2("OK".as_bytes())?;
+    connection.send_to_client("OK".as_bytes())?;
 
     Ok(())
 
@@ -0,0 +1,134 @@
+use crate::cursors::MAX_CURSORS_PER_CONNECTION;
+use crate::db_structure::{ColumnTable, DbColumn, LongTexts};
+use crate::ezql::{ArithOp, StatOp, TestOp, TextFunction, UpdateOp, MAX_BATCH_QUERIES, MAX_CONDITION_DEPTH};
+use crate::prepared::MAX_PREPARED_PER_CONNECTION;
+use crate::utilities::{ksf, u64_from_le_slice, EzError, KeyString};
+
+
+/// Every query type that can be sent, with its arguments as written in EZQL. Arguments in brackets are optional.
+/// SELECT and SUMMARY also take [into_table] or [into_value].
+pub const QUERY_TYPES: [(&str, &str); 19] = [
+    ("CREATE", "CREATE(table_name, table, [row_timestamps], [row_ids], [row_versions], [engine])"),
+    ("CREATE_FROM_SCHEMA", "CREATE_FROM_SCHEMA(schema)"),
+    ("DROP", "DROP(table_name)"),
+    ("ALTER_TABLE", "ALTER_TABLE(table_name, add_column and default, drop_column, rename_column or change_type)"),
+    ("SELECT", "SELECT(table_name, [primary_keys], [columns], [conditions])"),
+    ("LEFT_JOIN", "LEFT_JOIN(left_table, right_table, match_columns, [primary_keys])"),
+    ("UPDATE", "UPDATE(table_name, [primary_keys], [conditions], updates, [version])"),
+    ("INSERT", "INSERT(table_name, value_columns, new_values, [on_conflict])"),
+    ("DELETE", "DELETE(table_name, [primary_keys], [conditions])"),
+    ("SUMMARY", "SUMMARY(table_name, columns)"),
+    ("MULTI_SUMMARY", "MULTI_SUMMARY(tables, columns)"),
+    ("DEDUPLICATE", "DEDUPLICATE(table_name)"),
+    ("INFER_SCHEMA", "INFER_SCHEMA(table_name, sample)"),
+    ("DESCRIBE", "DESCRIBE(table_name)"),
+    ("PREPARE", "PREPARE(query)"),
+    ("EXECUTE", "EXECUTE(handle, [params])"),
+    ("INTO", "SELECT(..., into_table) or SELECT(..., into_value)"),
+    ("EXPLAIN", "EXPLAIN(query)"),
+    ("HELP", "HELP()"),
+];
+
+/// The limits a client has to stay within.
+pub fn limits() -> [(&'static str, usize); 5] {
+    [
+        ("max_key_length", std::mem::size_of::<KeyString>()),
+        ("max_batch_queries", MAX_BATCH_QUERIES),
+        ("max_condition_depth", MAX_CONDITION_DEPTH),
+        ("max_cursors_per_connection", MAX_CURSORS_PER_CONNECTION),
+        ("max_prepared_per_connection", MAX_PREPARED_PER_CONNECTION),
+    ]
+}
+
+/// The answer to a HELP query. One row per query type, condition test, update operator, statistic, text
+/// function and limit, plus the server version. The kind column says which. The value column holds the
+/// binary code of operators and the value of limits, and is 0 for everything else.
+pub fn help_table() -> Result<ColumnTable, EzError> {
+
+    let mut rows: Vec<(&str, String, String, i32)> = vec![("version", "version".to_owned(), env!("CARGO_PKG_VERSION").to_owned(), 0)];
+    for (name, syntax) in QUERY_TYPES {
+        rows.push(("query", name.to_owned(), syntax.to_owned(), 0));
+    }
+    for op in TestOp::ALL {
+        let detail = match op {
+            TestOp::IsNaN => "Takes no value. Float columns only",
+            TestOp::Starts | TestOp::Ends | TestOp::Contains | TestOp::NotStarts | TestOp::NotEnds | TestOp::NotContains => "Takes a value. Text columns only",
+            TestOp::Equals | TestOp::NotEquals | TestOp::Less | TestOp::Greater => "Takes a value or column(name)",
+            TestOp::In(_) => "Takes a list of values, such as (status in (open, closed))",
+            TestOp::Between(..) => "Takes two values, such as (price between 10 and 20). Both are included",
+            TestOp::Matches => "Takes a glob pattern, such as (name matches \"a*e?\"). Text columns only",
+            TestOp::MatchesRegex => "Takes a regex. Text columns only. Needs the regex feature",
+        };
+        rows.push(("test", op.name().to_owned(), detail.to_owned(), u64_from_le_slice(&op.to_binary()) as i32));
+    }
+    for op in UpdateOp::ALL {
+        rows.push(("update", op.symbol().to_owned(), op.to_keystring().to_string(), u64_from_le_slice(&op.to_binary()) as i32));
+    }
+    for (code, op) in StatOp::ALL.iter().enumerate() {
+        rows.push(("stat", op.to_string(), String::new(), code as i32));
+    }
+    for function in TextFunction::ALL {
+        rows.push(("text_function", function.name().to_owned(), format!("{}(column) in the columns of a SELECT", function.name()), 0));
+    }
+    for op in ArithOp::ALL {
+        rows.push(("arithmetic", op.symbol().to_owned(), format!("column {} column in the columns of a SELECT", op.symbol()), 0));
+    }
+    for (name, value) in limits() {
+        rows.push(("limit", name.to_owned(), String::new(), value as i32));
+    }
+
+    let mut table = ColumnTable::create_empty("ez_help", "help");
+    table.add_column(ksf("position"), DbColumn::Ints((0..rows.len() as i32).collect()))?;
+    table.add_column(ksf("kind"), DbColumn::Texts(rows.iter().map(|row| ksf(row.0)).collect()))?;
+    table.add_column(ksf("name"), DbColumn::Texts(rows.iter().map(|row| ksf(&row.1)).collect()))?;
+    table.add_column(ksf("detail"), DbColumn::LongTexts(rows.iter().map(|row| row.2.as_str()).collect::<LongTexts>()))?;
+    table.add_column(ksf("value"), DbColumn::Ints(rows.iter().map(|row| row.3).collect()))?;
+
+    Ok(table)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ezql::{parse_EZQL, Query};
+    use crate::testing_tools::random_query;
+
+    #[test]
+    fn test_help_table() {
+        let table = help_table().unwrap();
+        let kinds = table.get_column_text(&ksf("kind")).unwrap();
+        let names = table.get_column_text(&ksf("name")).unwrap();
+        let values = table.get_column_int(&ksf("value")).unwrap();
+        let count = |kind: &str| kinds.iter().filter(|k| k.as_str() == kind).count();
+        assert_eq!(count("query"), QUERY_TYPES.len());
+        assert_eq!(count("test"), TestOp::ALL.len());
+        assert_eq!(count("update"), UpdateOp::ALL.len());
+        assert_eq!(count("stat"), StatOp::ALL.len());
+        assert_eq!(count("arithmetic"), ArithOp::ALL.len());
+        assert_eq!(count("limit"), limits().len());
+
+        // The codes are the ones the binary format uses
+        for row in 0..table.len() {
+            match kinds[row].as_str() {
+                "test" => assert_eq!(TestOp::from_binary(&(values[row] as u64).to_le_bytes()).unwrap().name(), names[row].as_str()),
+                "update" => assert_eq!(UpdateOp::from_binary(&(values[row] as u64).to_le_bytes()).unwrap().symbol(), names[row].as_str()),
+                "limit" if names[row].as_str() == "max_key_length" => assert_eq!(values[row], 64),
+                _ => (),
+            }
+        }
+
+        // Every query the server takes is listed
+        for _ in 0..200 {
+            let query = random_query();
+            assert!(QUERY_TYPES.iter().any(|(name, _)| *name == query.type_name()), "{} is not in QUERY_TYPES", query.type_name());
+        }
+        for (name, _) in QUERY_TYPES {
+            assert_eq!(Query::blank(name).unwrap().type_name(), name);
+        }
+
+        assert_eq!(parse_EZQL("HELP()").unwrap(), vec![Query::HELP]);
+        assert!(parse_EZQL("HELP(table_name: products)").is_err());
+        assert_eq!(Query::from_binary(&Query::HELP.to_binary()).unwrap(), Query::HELP);
+    }
+}
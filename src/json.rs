@@ -0,0 +1,988 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+use crate::db_structure::{parse_header_item, Collation, ColumnTable, DbColumn, DbType, DbValue, HeaderItem, Metadata, OnConflict, TableKey};
+use crate::ezql::{Alteration, Condition, Expression, IntoTarget, KeyList, OpOrCond, Operator, Query, RangeOrListOrAll, StatOp, Statistic, TestOp, Update, UpdateOp};
+use crate::row_table::TableEngine;
+use crate::utilities::{ErrorTag, EzError, KeyString};
+
+
+/// A parsed JSON document. Numbers keep their text so that they are converted straight to the
+/// type the field needs (i32, i64 or f32) without a detour through f64.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    /// Keys are kept sorted so every value has exactly one canonical encoding.
+    Object(BTreeMap<String, Json>),
+}
+
+fn json_error(text: String) -> EzError {
+    EzError{tag: ErrorTag::Deserialization, text}
+}
+
+impl Display for Json {
+    /// The canonical encoding: no whitespace and object keys in byte order.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_json_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_json_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn write_json_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, EzError> {
+        let mut parser = JsonParser{bytes: text.as_bytes(), position: 0};
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position != parser.bytes.len() {
+            return Err(json_error(format!("Unexpected trailing characters at byte {}", parser.position)))
+        }
+        Ok(value)
+    }
+
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+    }
+
+    pub fn string(s: &str) -> Json {
+        Json::String(s.to_owned())
+    }
+
+    pub fn number(n: impl Display) -> Json {
+        Json::Number(n.to_string())
+    }
+
+    pub fn get(&self, key: &str) -> Result<&Json, EzError> {
+        match self {
+            Json::Object(fields) => match fields.get(key) {
+                Some(value) => Ok(value),
+                None => Err(json_error(format!("Missing field '{}'", key))),
+            },
+            other => Err(json_error(format!("Expected an object with the field '{}' but got: {}", key, other))),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, EzError> {
+        match self {
+            Json::String(s) => Ok(s),
+            other => Err(json_error(format!("Expected a string but got: {}", other))),
+        }
+    }
+
+    pub fn as_array(&self) -> Result<&[Json], EzError> {
+        match self {
+            Json::Array(items) => Ok(items),
+            other => Err(json_error(format!("Expected an array but got: {}", other))),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool, EzError> {
+        match self {
+            Json::Bool(b) => Ok(*b),
+            other => Err(json_error(format!("Expected true or false but got: {}", other))),
+        }
+    }
+
+    pub fn as_keystring(&self) -> Result<KeyString, EzError> {
+        KeyString::from_input(self.as_str()?)
+    }
+
+    fn as_number<T: std::str::FromStr>(&self, kind: &str) -> Result<T, EzError> {
+        match self {
+            Json::Number(n) => n.parse::<T>().map_err(|_| json_error(format!("'{}' is not a valid {}", n, kind))),
+            other => Err(json_error(format!("Expected {} but got: {}", kind, other))),
+        }
+    }
+
+    pub fn as_i32(&self) -> Result<i32, EzError> {
+        self.as_number("an int")
+    }
+
+    pub fn as_i64(&self) -> Result<i64, EzError> {
+        self.as_number("a 64 bit int")
+    }
+
+    pub fn as_usize(&self) -> Result<usize, EzError> {
+        self.as_number("a count")
+    }
+
+    /// Floats that JSON numbers can't hold are strings: "NaN", "inf" and "-inf".
+    pub fn as_f32(&self) -> Result<f32, EzError> {
+        match self {
+            Json::String(s) => s.parse::<f32>().map_err(|_| json_error(format!("'{}' is not a valid float", s))),
+            other => other.as_number("a float"),
+        }
+    }
+}
+
+fn float_to_json(f: f32) -> Json {
+    // f32 Display prints the shortest text that parses back to the same float
+    match f.is_finite() {
+        true => Json::number(f),
+        false => Json::String(f.to_string()),
+    }
+}
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.position < self.bytes.len() && matches!(self.bytes[self.position], b' ' | b'\n' | b'\r' | b'\t') {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), EzError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b) if b == byte => {
+                self.position += 1;
+                Ok(())
+            },
+            _ => Err(json_error(format!("Expected '{}' at byte {}", byte as char, self.position))),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, EzError> {
+        if self.bytes[self.position..].starts_with(word.as_bytes()) {
+            self.position += word.len();
+            Ok(value)
+        } else {
+            Err(json_error(format!("Invalid literal at byte {}", self.position)))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, EzError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(other) => Err(json_error(format!("Unexpected '{}' at byte {}", other as char, self.position))),
+            None => Err(json_error("Unexpected end of JSON".to_owned())),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, EzError> {
+        self.expect(b'{')?;
+        let mut fields = BTreeMap::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+            return Ok(Json::Object(fields))
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(b':')?;
+            let value = self.value()?;
+            if fields.insert(key.clone(), value).is_some() {
+                return Err(json_error(format!("Duplicate field '{}'", key)))
+            }
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b'}') => {
+                    self.position += 1;
+                    return Ok(Json::Object(fields))
+                },
+                _ => return Err(json_error(format!("Expected ',' or '}}' at byte {}", self.position))),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, EzError> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+            return Ok(Json::Array(items))
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b']') => {
+                    self.position += 1;
+                    return Ok(Json::Array(items))
+                },
+                _ => return Err(json_error(format!("Expected ',' or ']' at byte {}", self.position))),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, EzError> {
+        let start = self.position;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+            self.position += 1;
+        }
+        // The bytes are all ASCII
+        let text = std::str::from_utf8(&self.bytes[start..self.position]).unwrap();
+        if text.parse::<f64>().is_err() {
+            return Err(json_error(format!("'{}' is not a number", text)))
+        }
+        Ok(Json::Number(text.to_owned()))
+    }
+
+    fn hex4(&mut self) -> Result<u32, EzError> {
+        let digits = self.bytes.get(self.position..self.position + 4).ok_or(json_error("Unfinished \\u escape".to_owned()))?;
+        let digits = std::str::from_utf8(digits).map_err(|_| json_error("Invalid \\u escape".to_owned()))?;
+        let code = u32::from_str_radix(digits, 16).map_err(|_| json_error(format!("Invalid \\u escape '{}'", digits)))?;
+        self.position += 4;
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, EzError> {
+        self.expect(b'"')?;
+        let mut output = Vec::new();
+        loop {
+            let byte = match self.peek() {
+                Some(byte) => byte,
+                None => return Err(json_error("Unterminated string".to_owned())),
+            };
+            self.position += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.peek().ok_or(json_error("Unterminated string".to_owned()))?;
+                    self.position += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // Characters outside the basic plane come as a surrogate pair
+                            if (0xD800..0xDC00).contains(&code) && self.bytes[self.position..].starts_with(b"\\u") {
+                                self.position += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            char::from_u32(code).ok_or(json_error(format!("Invalid character code {}", code)))?
+                        },
+                        other => return Err(json_error(format!("Invalid escape '\\{}'", other as char))),
+                    };
+                    let mut buffer = [0u8; 4];
+                    output.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                },
+                other => output.push(other),
+            }
+        }
+        String::from_utf8(output).map_err(|e| json_error(e.to_string()))
+    }
+}
+
+
+/// Conversion to and from the canonical JSON form of queries. This lets HTTP clients build queries
+/// in any language without implementing the binary layout. See EZQL.txt for the format.
+pub trait JsonCodec: Sized {
+    fn to_json(&self) -> Json;
+    fn from_json(json: &Json) -> Result<Self, EzError>;
+
+    fn to_json_string(&self) -> String {
+        self.to_json().to_string()
+    }
+
+    fn from_json_str(text: &str) -> Result<Self, EzError> {
+        Self::from_json(&Json::parse(text)?)
+    }
+}
+
+impl JsonCodec for DbValue {
+    /// An object with the type as the only key, such as {"int":5}. Durations are nanoseconds.
+    fn to_json(&self) -> Json {
+        match self {
+            DbValue::Int(i) => Json::object(vec![("int", Json::number(i))]),
+            DbValue::Float(f) => Json::object(vec![("float", float_to_json(*f))]),
+            DbValue::Text(t) => Json::object(vec![("text", Json::string(t.as_str()))]),
+            DbValue::Duration(d) => Json::object(vec![("duration", Json::number(d))]),
+        }
+    }
+
+    fn from_json(json: &Json) -> Result<Self, EzError> {
+        match json {
+            Json::Object(fields) if fields.len() == 1 => {
+                let (kind, value) = fields.iter().next().unwrap();
+                match kind.as_str() {
+                    "int" => Ok(DbValue::Int(value.as_i32()?)),
+                    "float" => Ok(DbValue::Float(value.as_f32()?)),
+                    "text" => Ok(DbValue::Text(value.as_keystring()?)),
+                    "duration" => Ok(DbValue::Duration(value.as_i64()?)),
+                    other => Err(json_error(format!("'{}' is not a value type", other))),
+                }
+            },
+            other => Err(json_error(format!("A value is an object with one of int, float, text or duration. Got: {}", other))),
+        }
+    }
+}
+
+impl JsonCodec for RangeOrListOrAll {
+    fn to_json(&self) -> Json {
+        match self {
+            RangeOrListOrAll::All => Json::object(vec![("kind", Json::string("all"))]),
+            RangeOrListOrAll::Range(start, stop) => Json::object(vec![
+                ("kind", Json::string("range")),
+                ("start", Json::string(start.as_str())),
+                ("stop", Json::string(stop.as_str())),
+            ]),
+            RangeOrListOrAll::List(keys) => Json::object(vec![
+                ("kind", Json::string("list")),
+                ("keys", Json::Array(keys.iter().map(|key| Json::string(key.as_str())).collect())),
+            ]),
+            RangeOrListOrAll::Keys(KeyList::Ints(keys)) => Json::object(vec![
+                ("kind", Json::string("int_keys")),
+                ("keys", Json::Array(keys.iter().map(Json::number).collect())),
+            ]),
+            RangeOrListOrAll::Keys(KeyList::Texts(keys)) => Json::object(vec![
+                ("kind", Json::string("text_keys")),
+                ("keys", Json::Array(keys.iter().map(|key| Json::string(key.as_str())).collect())),
+            ]),
+        }
+    }
+
+    fn from_json(json: &Json) -> Result<Self, EzError> {
+        match json.get("kind")?.as_str()? {
+            "all" => Ok(RangeOrListOrAll::All),
+            "range" => Ok(RangeOrListOrAll::Range(json.get("start")?.as_keystring()?, json.get("stop")?.as_keystring()?)),
+            "list" => Ok(RangeOrListOrAll::List(json.get("keys")?.as_array()?.iter().map(|key| key.as_keystring()).collect::<Result<_, _>>()?)),
+            "int_keys" => Ok(RangeOrListOrAll::Keys(KeyList::Ints(json.get("keys")?.as_array()?.iter().map(|key| key.as_i32()).collect::<Result<_, _>>()?))),
+            "text_keys" => Ok(RangeOrListOrAll::Keys(KeyList::Texts(json.get("keys")?.as_array()?.iter().map(|key| key.as_keystring()).collect::<Result<_, _>>()?))),
+            other => Err(json_error(format!("'{}' is not one of all, range, list, int_keys or text_keys", other))),
+        }
+    }
+}
+
+impl JsonCodec for Condition {
+    /// Conditions on another column have a "column" field instead of a "value".
+    fn to_json(&self) -> Json {
+        let values = self.op.values();
+        let operand = match &self.other_column {
+            // in and between hold a list of values instead
+            _ if !values.is_empty() => ("values", Json::Array(values.into_iter().map(|value| value.to_json()).collect())),
+            Some(other) => ("column", Json::string(other.as_str())),
+            None => ("value", self.value.to_json()),
+        };
+        Json::object(vec![
+            ("attribute", Json::string(self.attribute.as_str())),
+            ("op", Json::string(self.op.name())),
+            operand,
+        ])
+    }
+
+    fn from_json(json: &Json) -> Result<Self, EzError> {
+        let attribute = json.get("attribute")?.as_keystring()?;
+        let op = TestOp::from_name(json.get("op")?.as_str()?)?;
+        if matches!(op, TestOp::In(_) | TestOp::Between(..)) {
+            let values = json.get("values")?.as_array()?.iter().map(DbValue::from_json).collect::<Result<Vec<_>, _>>()?;
+            return Ok(Condition { attribute, op: op.with_values(values)?, value: 0.into(), other_column: None })
+        }
+        match json.get("column") {
+            Ok(other) => Ok(Condition { attribute, op, value: 0.into(), other_column: Some(other.as_keystring()?) }),
+            // is_nan takes no value
+            Err(_) if op == TestOp::IsNaN && json.get("value").is_err() => Ok(Condition { attribute, op, value: 0.into(), other_column: None }),
+            Err(_) => Ok(Condition { attribute, op, value: DbValue::from_json(json.get("value")?)?, other_column: None }),
+        }
+    }
+}
+
+impl JsonCodec for OpOrCond {
+    /// Operators are the strings "AND", "OR", "NOT", "(" and ")". Conditions are objects.
+    fn to_json(&self) -> Json {
+        match self {
+            OpOrCond::Cond(condition) => condition.to_json(),
+            OpOrCond::Op(Operator::AND) => Json::string("AND"),
+            OpOrCond::Op(Operator::OR) => Json::string("OR"),
+            OpOrCond::Not => Json::string("NOT"),
+            OpOrCond::Open => Json::string("("),
+            OpOrCond::Close => Json::string(")"),
+        }
+    }
+
+    fn from_json(json: &Json) -> Result<Self, EzError> {
+        match json {
+            Json::String(op) => match op.as_str() {
+                "AND" => Ok(OpOrCond::Op(Operator::AND)),
+                "OR" => Ok(OpOrCond::Op(Operator::OR)),
+                "NOT" => Ok(OpOrCond::Not),
+                "(" => Ok(OpOrCond::Open),
+                ")" => Ok(OpOrCond::Close),
+                other => Err(json_error(format!("'{}' is not one of AND, OR, NOT, ( or )", other))),
+            },
+            condition => Ok(OpOrCond::Cond(Condition::from_json(condition)?)),
+        }
+    }
+}
+
+fn update_op_name(op: UpdateOp) -> &'static str {
+    match op {
+        UpdateOp::Assign => "assign",
+        UpdateOp::PlusEquals => "plus_equals",
+        UpdateOp::MinusEquals => "minus_equals",
+        UpdateOp::TimesEquals => "times_equals",
+        UpdateOp::Append => "append",
+        UpdateOp::Prepend => "prepend",
+        UpdateOp::ToLower => "to_lower",
+        UpdateOp::ToUpper => "to_upper",
+        UpdateOp::Trim => "trim",
+    }
+}
+
+fn update_op_from_name(name: &str) -> Result<UpdateOp, EzError> {
+    match name {
+        "assign" => Ok(UpdateOp::Assign),
+        "plus_equals" => Ok(UpdateOp::PlusEquals),
+        "minus_equals" => Ok(UpdateOp::MinusEquals),
+        "times_equals" => Ok(UpdateOp::TimesEquals),
+        "append" => Ok(UpdateOp::Append),
+        "prepend" => Ok(UpdateOp::Prepend),
+        "to_lower" => Ok(UpdateOp::ToLower),
+        "to_upper" => Ok(UpdateOp::ToUpper),
+        "trim" => Ok(UpdateOp::Trim),
+        other => Err(json_error(format!("'{}' is not an update operator", other))),
+    }
+}
+
+impl JsonCodec for Update {
+    fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("attribute", Json::string(self.attribute.as_str())),
+            ("op", Json::string(update_op_name(self.operator))),
+            ("value", self.value.to_json()),
+            ("expression", match &self.expression {
+                Some(expression) => Json::String(expression.to_string()),
+                None => Json::Null,
+            }),
+        ])
+    }
+
+    fn from_json(json: &Json) -> Result<Self, EzError> {
+        // Optional so updates written before computed values existed still parse. A bare column name reads that column
+        let expression = match json.get("expression") {
+            Ok(Json::Null) | Err(_) => None,
+            Ok(text) => {
+                let text = text.as_str()?;
+                Some(Expression::parse(text)?.unwrap_or(Expression::Column(KeyString::from_input(text)?)))
+            },
+        };
+        Ok(Update {
+            attribute: json.get("attribute")?.as_keystring()?,
+            operator: update_op_from_name(json.get("op")?.as_str()?)?,
+            value: DbValue::from_json(json.get("value")?)?,
+            expression,
+        })
+    }
+}
+
+impl JsonCodec for Statistic {
+    fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("column", Json::string(self.column.as_str())),
+            ("actions", Json::Array(self.actions.iter().map(|action| Json::String(action.to_string())).collect())),
+        ])
+    }
+
+    fn from_json(json: &Json) -> Result<Self, EzError> {
+        let mut actions = BTreeSet::new();
+        for action in json.get("actions")?.as_array()? {
+            actions.insert(match action.as_str()? {
+                "SUM" => StatOp::SUM,
+                "MEAN" => StatOp::MEAN,
+                "MEDIAN" => StatOp::MEDIAN,
+                "MODE" => StatOp::MODE,
+                "STDEV" => StatOp::STDEV,
+                "MIN" => StatOp::MIN,
+                "MAX" => StatOp::MAX,
+                "COUNT" => StatOp::COUNT,
+                other => return Err(json_error(format!("'{}' is not one of SUM, MEAN, MEDIAN, MODE, STDEV, MIN, MAX or COUNT", other))),
+            });
+        }
+        Ok(Statistic{column: json.get("column")?.as_keystring()?, actions})
+    }
+}
+
+impl JsonCodec for Alteration {
+    /// {"alteration": "add_column", "column": "stock,i-N", "default"}, {"alteration": "drop_column", "column"},
+    /// {"alteration": "rename_column", "from", "to"} or {"alteration": "change_type", "column", "type"}.
+    fn to_json(&self) -> Json {
+        match self {
+            Alteration::AddColumn { column, default } => Json::object(vec![
+                ("alteration", Json::string("add_column")),
+                ("column", Json::String(column.to_string())),
+                ("default", default.to_json()),
+            ]),
+            Alteration::DropColumn { column } => Json::object(vec![("alteration", Json::string("drop_column")), ("column", Json::string(column.as_str()))]),
+            Alteration::RenameColumn { from, to } => Json::object(vec![
+                ("alteration", Json::string("rename_column")),
+                ("from", Json::string(from.as_str())),
+                ("to", Json::string(to.as_str())),
+            ]),
+            Alteration::ChangeType { column, kind } => Json::object(vec![
+                ("alteration", Json::string("change_type")),
+                ("column", Json::string(column.as_str())),
+                ("type", Json::string(kind.name())),
+            ]),
+        }
+    }
+
+    fn from_json(json: &Json) -> Result<Self, EzError> {
+        let alteration = match json.get("alteration")?.as_str()? {
+            "add_column" => Alteration::AddColumn {
+                column: parse_header_item(json.get("column")?.as_str()?)?,
+                default: DbValue::from_json(json.get("default")?)?,
+            },
+            "drop_column" => Alteration::DropColumn { column: json.get("column")?.as_keystring()? },
+            "rename_column" => Alteration::RenameColumn { from: json.get("from")?.as_keystring()?, to: json.get("to")?.as_keystring()? },
+            "change_type" => Alteration::ChangeType {
+                column: json.get("column")?.as_keystring()?,
+                kind: DbType::from_name(json.get("type")?.as_str()?)?,
+            },
+            other => return Err(json_error(format!("'{}' is not one of add_column, drop_column, rename_column or change_type", other))),
+        };
+        Ok(alteration)
+    }
+}
+
+impl JsonCodec for ColumnTable {
+    /// {"name", "created_by", "columns": [{"name", "type", "key", "values"}]} with columns in name order.
+    /// Enum columns also have "options", the allowed values, and their "values" are positions in it.
+    /// Text columns with a collation other than binary have "collation".
+    fn to_json(&self) -> Json {
+        let mut columns = Vec::new();
+        for item in &self.header {
+            let values = match &self.columns[&item.name] {
+                DbColumn::Ints(col) => col.iter().map(Json::number).collect(),
+                DbColumn::Floats(col) => col.iter().map(|f| float_to_json(*f)).collect(),
+                DbColumn::Texts(col) => col.iter().map(|t| Json::string(t.as_str())).collect(),
+                DbColumn::Durations(col) => col.iter().map(Json::number).collect(),
+                DbColumn::LongTexts(col) => col.iter().map(Json::string).collect(),
+            };
+            let mut column = vec![
+                ("name", Json::string(item.name.as_str())),
+                ("type", Json::string(item.kind.name())),
+                ("key", Json::string(item.key.name())),
+                ("values", Json::Array(values)),
+            ];
+            if item.collation != Collation::Binary {
+                column.push(("collation", Json::string(item.collation.name())));
+            }
+            if item.kind == DbType::Enum {
+                column.push(("options", Json::Array(item.values.iter().map(|value| Json::string(value.as_str())).collect())));
+            }
+            columns.push(Json::object(column));
+        }
+        Json::object(vec![
+            ("name", Json::string(self.name.as_str())),
+            ("created_by", Json::string(self.metadata.created_by.as_str())),
+            ("columns", Json::Array(columns)),
+        ])
+    }
+
+    fn from_json(json: &Json) -> Result<Self, EzError> {
+        let mut header = BTreeSet::new();
+        let mut columns = BTreeMap::new();
+        let mut rows = None;
+        for column in json.get("columns")?.as_array()? {
+            let name = column.get("name")?.as_keystring()?;
+            let kind = DbType::from_name(column.get("type")?.as_str()?)?;
+            let key = TableKey::from_name(column.get("key")?.as_str()?)?;
+            let values = column.get("values")?.as_array()?;
+            let data = match kind {
+                DbType::Int => DbColumn::Ints(values.iter().map(Json::as_i32).collect::<Result<_, _>>()?),
+                DbType::Float => DbColumn::Floats(values.iter().map(Json::as_f32).collect::<Result<_, _>>()?),
+                DbType::Text => DbColumn::Texts(values.iter().map(Json::as_keystring).collect::<Result<_, _>>()?),
+                DbType::Duration => DbColumn::Durations(values.iter().map(Json::as_i64).collect::<Result<_, _>>()?),
+                DbType::LongText => DbColumn::LongTexts(values.iter().map(Json::as_str).collect::<Result<_, _>>()?),
+                DbType::Enum => DbColumn::Ints(values.iter().map(Json::as_i32).collect::<Result<_, _>>()?),
+            };
+            // Binary when missing
+            let collation = match column.get("collation") {
+                Ok(collation) => Collation::from_name(collation.as_str()?)?,
+                Err(_) => Collation::Binary,
+            };
+            if collation != Collation::Binary && kind != DbType::Text {
+                return Err(json_error(format!("Column '{}' is not a text column and can't have a collation", name)))
+            }
+            if kind == DbType::LongText && key == TableKey::Primary {
+                return Err(json_error(format!("Column '{}' is a long text column and can't be the primary key", name)))
+            }
+            if *rows.get_or_insert(values.len()) != values.len() {
+                return Err(json_error(format!("Column '{}' has {} values but the columns before it have {}", name, values.len(), rows.unwrap())))
+            }
+            if columns.insert(name, data).is_some() {
+                return Err(json_error(format!("Column '{}' appears twice", name)))
+            }
+            let item = match kind {
+                DbType::Enum => {
+                    let options = column.get("options")?.as_array()?.iter().map(Json::as_keystring).collect::<Result<_, _>>()?;
+                    let item = HeaderItem::new_enum(name, key, options).map_err(|e| json_error(e.text))?;
+                    if let DbColumn::Ints(col) = &columns[&name] {
+                        if let Some(bad) = col.iter().find(|index| item.enum_value(**index).is_err()) {
+                            return Err(json_error(format!("Column '{}' holds {} which is not one of its options", name, bad)))
+                        }
+                    }
+                    item
+                },
+                _ => HeaderItem{name, kind, key, values: Vec::new(), collation},
+            };
+            header.insert(item);
+        }
+        if header.iter().filter(|item| item.key == TableKey::Primary).count() != 1 {
+            return Err(json_error("A table needs exactly one primary key column".to_owned()))
+        }
+
+        Ok(ColumnTable {
+            name: json.get("name")?.as_keystring()?,
+            header,
+            columns,
+            metadata: Metadata::new(json.get("created_by")?.as_str()?),
+        })
+    }
+}
+
+fn list_to_json<T: JsonCodec>(items: &[T]) -> Json {
+    Json::Array(items.iter().map(|item| item.to_json()).collect())
+}
+
+fn list_from_json<T: JsonCodec>(json: &Json) -> Result<Vec<T>, EzError> {
+    json.as_array()?.iter().map(T::from_json).collect()
+}
+
+fn keystrings_to_json(items: &[KeyString]) -> Json {
+    Json::Array(items.iter().map(|item| Json::string(item.as_str())).collect())
+}
+
+impl JsonCodec for Query {
+    /// An object with a "query" field naming the variant and one field per field of the variant.
+    fn to_json(&self) -> Json {
+        let name = |s: &KeyString| Json::string(s.as_str());
+        match self {
+            Query::CREATE { table, engine } => Json::object(vec![("query", Json::string("CREATE")), ("table", table.to_json()), ("engine", Json::string(engine.name()))]),
+            Query::CREATE_FROM_SCHEMA { tables } => Json::object(vec![
+                ("query", Json::string("CREATE_FROM_SCHEMA")),
+                ("tables", Json::Array(tables.iter().map(ColumnTable::to_json).collect())),
+            ]),
+            Query::DROP { table_name } => Json::object(vec![("query", Json::string("DROP")), ("table_name", name(table_name))]),
+            Query::SELECT { table_name, primary_keys, columns, conditions, distinct, limit } => Json::object(vec![
+                ("query", Json::string("SELECT")),
+                ("table_name", name(table_name)),
+                ("primary_keys", primary_keys.to_json()),
+                ("columns", keystrings_to_json(columns)),
+                ("conditions", list_to_json(conditions)),
+                ("distinct", Json::Bool(*distinct)),
+                ("limit", match limit { Some(limit) => Json::number(limit), None => Json::Null }),
+            ]),
+            Query::LEFT_JOIN { left_table_name, right_table_name, match_columns, primary_keys } => Json::object(vec![
+                ("query", Json::string("LEFT_JOIN")),
+                ("left_table_name", name(left_table_name)),
+                ("right_table_name", name(right_table_name)),
+                ("match_columns", keystrings_to_json(&[match_columns.0, match_columns.1])),
+                ("primary_keys", primary_keys.to_json()),
+            ]),
+            Query::INNER_JOIN => Json::object(vec![("query", Json::string("INNER_JOIN"))]),
+            Query::RIGHT_JOIN => Json::object(vec![("query", Json::string("RIGHT_JOIN"))]),
+            Query::FULL_JOIN => Json::object(vec![("query", Json::string("FULL_JOIN"))]),
+            Query::UPDATE { table_name, primary_keys, conditions, updates, version } => Json::object(vec![
+                ("query", Json::string("UPDATE")),
+                ("table_name", name(table_name)),
+                ("primary_keys", primary_keys.to_json()),
+                ("conditions", list_to_json(conditions)),
+                ("updates", list_to_json(updates)),
+                ("version", match version { Some(version) => Json::number(version), None => Json::Null }),
+            ]),
+            Query::INSERT { table_name, inserts, on_conflict } => Json::object(vec![
+                ("query", Json::string("INSERT")),
+                ("table_name", name(table_name)),
+                ("inserts", inserts.to_json()),
+                ("on_conflict", Json::string(on_conflict.name())),
+            ]),
+            Query::DELETE { primary_keys, table_name, conditions } => Json::object(vec![
+                ("query", Json::string("DELETE")),
+                ("table_name", name(table_name)),
+                ("primary_keys", primary_keys.to_json()),
+                ("conditions", list_to_json(conditions)),
+            ]),
+            Query::SUMMARY { table_name, columns } => Json::object(vec![
+                ("query", Json::string("SUMMARY")),
+                ("table_name", name(table_name)),
+                ("columns", list_to_json(columns)),
+            ]),
+            Query::MULTI_SUMMARY { tables, columns } => Json::object(vec![
+                ("query", Json::string("MULTI_SUMMARY")),
+                ("tables", Json::Array(tables.iter().map(name).collect())),
+                ("columns", list_to_json(columns)),
+            ]),
+            Query::DEDUPLICATE { table_name } => Json::object(vec![("query", Json::string("DEDUPLICATE")), ("table_name", name(table_name))]),
+            Query::ALTER_TABLE { table_name, alteration } => Json::object(vec![
+                ("query", Json::string("ALTER_TABLE")),
+                ("table_name", name(table_name)),
+                ("alteration", alteration.to_json()),
+            ]),
+            Query::INFER_SCHEMA { table_name, sample } => Json::object(vec![
+                ("query", Json::string("INFER_SCHEMA")),
+                ("table_name", name(table_name)),
+                ("sample", Json::string(sample)),
+            ]),
+            Query::DESCRIBE { table_name } => Json::object(vec![("query", Json::string("DESCRIBE")), ("table_name", name(table_name))]),
+            Query::PREPARE { query } => Json::object(vec![("query", Json::string("PREPARE")), ("prepared", query.to_json())]),
+            Query::EXECUTE { handle, params } => Json::object(vec![
+                ("query", Json::string("EXECUTE")),
+                ("handle", Json::number(handle)),
+                ("params", list_to_json(params)),
+            ]),
+            Query::INTO { query, target } => Json::object(vec![
+                ("query", Json::string("INTO")),
+                (match target { IntoTarget::Table(_) => "into_table", IntoTarget::Value(_) => "into_value" }, name(&target.name())),
+                ("source", query.to_json()),
+            ]),
+            Query::EXPLAIN { query } => Json::object(vec![("query", Json::string("EXPLAIN")), ("explained", query.to_json())]),
+            Query::HELP => Json::object(vec![("query", Json::string("HELP"))]),
+        }
+    }
+
+    fn from_json(json: &Json) -> Result<Self, EzError> {
+        let table_name = || json.get("table_name")?.as_keystring();
+        let query = match json.get("query")?.as_str()? {
+            "CREATE" => Query::CREATE {
+                table: ColumnTable::from_json(json.get("table")?)?,
+                // Optional so CREATEs written before engines existed still parse
+                engine: match json.get("engine") {
+                    Ok(engine) => TableEngine::from_name(engine.as_str()?)?,
+                    Err(_) => TableEngine::Column,
+                },
+            },
+            "CREATE_FROM_SCHEMA" => Query::CREATE_FROM_SCHEMA {
+                tables: json.get("tables")?.as_array()?.iter().map(ColumnTable::from_json).collect::<Result<_, _>>()?,
+            },
+            "DROP" => Query::DROP { table_name: table_name()? },
+            "SELECT" => Query::SELECT {
+                table_name: table_name()?,
+                primary_keys: RangeOrListOrAll::from_json(json.get("primary_keys")?)?,
+                columns: json.get("columns")?.as_array()?.iter().map(Json::as_keystring).collect::<Result<_, _>>()?,
+                conditions: list_from_json(json.get("conditions")?)?,
+                // Optional so SELECTs written before distinct existed still parse
+                distinct: match json.get("distinct") {
+                    Ok(flag) => flag.as_bool()?,
+                    Err(_) => false,
+                },
+                limit: match json.get("limit") {
+                    Ok(Json::Null) | Err(_) => None,
+                    Ok(limit) => Some(limit.as_usize()?),
+                },
+            },
+            "LEFT_JOIN" => {
+                let match_columns = json.get("match_columns")?.as_array()?;
+                if match_columns.len() != 2 {
+                    return Err(json_error(format!("match_columns holds exactly two columns. Got {}", match_columns.len())))
+                }
+                Query::LEFT_JOIN {
+                    left_table_name: json.get("left_table_name")?.as_keystring()?,
+                    right_table_name: json.get("right_table_name")?.as_keystring()?,
+                    match_columns: (match_columns[0].as_keystring()?, match_columns[1].as_keystring()?),
+                    primary_keys: RangeOrListOrAll::from_json(json.get("primary_keys")?)?,
+                }
+            },
+            "INNER_JOIN" => Query::INNER_JOIN,
+            "RIGHT_JOIN" => Query::RIGHT_JOIN,
+            "FULL_JOIN" => Query::FULL_JOIN,
+            "UPDATE" => Query::UPDATE {
+                table_name: table_name()?,
+                primary_keys: RangeOrListOrAll::from_json(json.get("primary_keys")?)?,
+                conditions: list_from_json(json.get("conditions")?)?,
+                updates: list_from_json(json.get("updates")?)?,
+                version: match json.get("version") {
+                    Ok(Json::Null) | Err(_) => None,
+                    Ok(version) => Some(version.as_i64()?),
+                },
+            },
+            "INSERT" => Query::INSERT {
+                table_name: table_name()?,
+                inserts: ColumnTable::from_json(json.get("inserts")?)?,
+                // Optional so INSERTs written before conflict policies existed still parse
+                on_conflict: match json.get("on_conflict") {
+                    Ok(on_conflict) => OnConflict::from_name(on_conflict.as_str()?)?,
+                    Err(_) => OnConflict::Skip,
+                },
+            },
+            "DELETE" => Query::DELETE {
+                primary_keys: RangeOrListOrAll::from_json(json.get("primary_keys")?)?,
+                table_name: table_name()?,
+                conditions: list_from_json(json.get("conditions")?)?,
+            },
+            "SUMMARY" => Query::SUMMARY { table_name: table_name()?, columns: list_from_json(json.get("columns")?)? },
+            "MULTI_SUMMARY" => Query::MULTI_SUMMARY {
+                tables: json.get("tables")?.as_array()?.iter().map(Json::as_keystring).collect::<Result<_, _>>()?,
+                columns: list_from_json(json.get("columns")?)?,
+            },
+            "DEDUPLICATE" => Query::DEDUPLICATE { table_name: table_name()? },
+            "ALTER_TABLE" => Query::ALTER_TABLE { table_name: table_name()?, alteration: Alteration::from_json(json.get("alteration")?)? },
+            "INFER_SCHEMA" => Query::INFER_SCHEMA { table_name: table_name()?, sample: json.get("sample")?.as_str()?.to_owned() },
+            "DESCRIBE" => Query::DESCRIBE { table_name: table_name()? },
+            "PREPARE" => Query::PREPARE { query: Box::new(Query::from_json(json.get("prepared")?)?) },
+            "EXECUTE" => {
+                let handle = json.get("handle")?.as_i64()?;
+                if handle < 0 {
+                    return Err(json_error(format!("A handle can't be negative. Got {}", handle)))
+                }
+                Query::EXECUTE { handle: handle as u64, params: list_from_json(json.get("params")?)? }
+            },
+            "INTO" => {
+                let target = match (json.get("into_table"), json.get("into_value")) {
+                    (Ok(name), Err(_)) => IntoTarget::Table(name.as_keystring()?),
+                    (Err(_), Ok(key)) => IntoTarget::Value(key.as_keystring()?),
+                    _ => return Err(json_error("INTO needs exactly one of into_table and into_value".to_owned())),
+                };
+                let source = Query::from_json(json.get("source")?)?;
+                if !matches!(source, Query::SELECT{..} | Query::SUMMARY{..}) {
+                    return Err(json_error(format!("Only SELECT and SUMMARY can store their result with INTO. Got '{}'", source)))
+                }
+                Query::INTO { query: Box::new(source), target }
+            },
+            "EXPLAIN" => Query::EXPLAIN { query: Box::new(Query::from_json(json.get("explained")?)?) },
+            "HELP" => Query::HELP,
+            other => return Err(json_error(format!("'{}' is not a query", other))),
+        };
+        Ok(query)
+    }
+}
+
+/// A batch of queries is a JSON array of queries.
+pub fn queries_from_json(text: &str) -> Result<Vec<Query>, EzError> {
+    list_from_json(&Json::parse(text)?)
+}
+
+pub fn queries_to_json(queries: &[Query]) -> String {
+    list_to_json(queries).to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::testing_tools::random_query;
+    use crate::utilities::ksf;
+
+    use super::*;
+
+    #[test]
+    fn test_json_parse() {
+        let json = Json::parse(" {\"b\": [1, -2.5e3, true, null], \"a\": \"x\\n\\u00e9\\ud83d\\ude00\"} ").unwrap();
+        assert_eq!(json.get("a").unwrap().as_str().unwrap(), "x\né😀");
+        assert_eq!(json.to_string(), "{\"a\":\"x\\né😀\",\"b\":[1,-2.5e3,true,null]}");
+        assert!(Json::parse("{\"a\": 1,}").is_err());
+        assert!(Json::parse("[1] 2").is_err());
+        assert!(Json::parse("{\"a\": 1, \"a\": 2}").is_err());
+    }
+
+    #[test]
+    fn test_query_json_round_trip() {
+        for _ in 0..1000 {
+            let query = random_query();
+            let text = query.to_json_string();
+            let parsed = Query::from_json_str(&text).unwrap();
+            assert_eq!(query, parsed);
+            // The JSON form and the binary form describe the same query
+            assert_eq!(Query::from_binary(&query.to_binary()).unwrap(), parsed);
+            assert_eq!(Query::from_binary(&parsed.to_binary()).unwrap(), query);
+            // Canonical: encoding the parsed query gives the same text
+            assert_eq!(parsed.to_json_string(), text);
+        }
+    }
+
+    #[test]
+    fn test_handwritten_json_query() {
+        let text = r#"{
+            "query": "SELECT",
+            "table_name": "products",
+            "primary_keys": {"kind": "range", "start": "0", "stop": "100"},
+            "columns": ["id", "price"],
+            "conditions": [
+                {"attribute": "price", "op": "greater_than", "value": {"int": 500}},
+                "AND", "NOT",
+                {"attribute": "id", "op": "equals", "value": {"int": 7}}
+            ]
+        }"#;
+        let query = Query::from_json_str(text).unwrap();
+        let expected = Query::SELECT {
+            table_name: ksf("products"),
+            primary_keys: RangeOrListOrAll::Range(ksf("0"), ksf("100")),
+            columns: vec![ksf("id"), ksf("price")],
+            conditions: vec![
+                OpOrCond::Cond(Condition::new("price", TestOp::Greater, 500).unwrap()),
+                OpOrCond::Op(Operator::AND),
+                OpOrCond::Not,
+                OpOrCond::Cond(Condition::new("id", TestOp::Equals, 7).unwrap()),
+            ],
+            distinct: false,
+            limit: None,
+        };
+        assert_eq!(query, expected);
+
+        for query in [Query::INNER_JOIN, Query::RIGHT_JOIN, Query::FULL_JOIN, Query::HELP] {
+            assert_eq!(Query::from_json_str(&query.to_json_string()).unwrap(), query);
+        }
+        let batch = vec![expected, Query::DROP { table_name: ksf("products") }];
+        assert_eq!(queries_from_json(&queries_to_json(&batch)).unwrap(), batch);
+        assert!(Query::from_json_str(r#"{"query": "SELECT", "table_name": "products"}"#).is_err());
+    }
+}
@@ -1,6 +1,6 @@
 //#![allow(unused)]
 //#![allow(non_snake_case)]
-#![feature(portable_simd)]
+#![cfg_attr(feature = "simd", feature(portable_simd))]
 
 #[cfg(target_os="windows")]
 pub const PATH_SEP: char = '\\';
@@ -8,22 +8,76 @@ pub const PATH_SEP: char = '\\';
 #[cfg(target_os="linux")]
 pub const PATH_SEP: char = '/';
 
+#[cfg(target_os="macos")]
+pub const PATH_SEP: char = '/';
+
 
 // pub mod aes;
+#[cfg(feature = "crypto")]
 pub mod aes_temp_crypto;
 pub mod auth;
+#[cfg(feature = "client")]
 pub mod client_networking;
+#[cfg(feature = "client")]
+pub mod cli;
 pub mod compression;
+pub mod database;
 pub mod db_structure;
 pub mod disk_utilities;
 pub mod ezql;
+pub mod query_builder;
+#[cfg(all(feature = "server", target_os = "linux"))]
 pub mod handlers;
 pub mod logging;
 pub mod utilities;
+// Built on epoll and fork, which only Linux has. See the Platforms section of the README
+#[cfg(all(feature = "server", target_os = "linux"))]
 pub mod server_networking;
 pub mod bloom_filter;
 pub mod row_arena;
+pub mod row_mapping;
+pub mod row_table;
+#[cfg(all(feature = "http", target_os = "linux"))]
 pub mod http_interface;
 pub mod thread_pool;
 pub mod testing_tools;
-pub mod query_execution;
\ No newline at end of file
+pub mod query_execution;
+pub mod system_tables;
+pub mod maintenance;
+pub mod namespaces;
+pub mod snapshot;
+pub mod disk_monitor;
+pub mod admission;
+pub mod blob_store;
+pub mod paths;
+pub mod tagging;
+pub mod external_sort;
+pub mod self_test;
+pub mod frame_checksum;
+pub mod differential_testing;
+pub mod lock_monitor;
+pub mod cursors;
+pub mod sessions;
+pub mod protocol;
+pub mod json;
+pub mod shared_tables;
+#[cfg(feature = "crypto")]
+pub mod transport;
+pub mod prepared;
+pub mod alloc_stats;
+pub mod migrate;
+pub mod help;
+pub mod metrics;
+pub mod cancellation;
+pub mod backup;
+pub mod schema_file;
+pub mod config;
+pub mod trash;
+pub mod partitions;
+pub mod explain;
+#[cfg(all(feature = "server", target_os = "linux"))]
+pub mod daemon;
+pub mod shutdown;
+pub mod write_versions;
+#[cfg(all(feature = "stress", target_os = "linux"))]
+pub mod stress_testing;
\ No newline at end of file
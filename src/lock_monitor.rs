@@ -0,0 +1,358 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+use crate::db_structure::{ColumnTable, DbColumn};
+use crate::utilities::{ksf, ErrorTag, EzError, KeyString};
+
+
+pub const DEFAULT_LOCK_TIMEOUT_MS: u64 = 5000;
+
+static LOCK_TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_LOCK_TIMEOUT_MS);
+
+/// Sets how long a query waits for a table lock before giving up. 0 waits forever.
+pub fn set_lock_timeout_ms(ms: u64) {
+    LOCK_TIMEOUT_MS.store(ms, Ordering::Relaxed);
+}
+
+pub fn lock_timeout_ms() -> u64 {
+    LOCK_TIMEOUT_MS.load(Ordering::Relaxed)
+}
+
+/// Locks taken outside of a query batch, such as by maintenance, are attributed to this user.
+pub const INTERNAL_USER: &str = "internal";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    Read,
+    Write,
+}
+
+impl LockMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LockMode::Read => "read",
+            LockMode::Write => "write",
+        }
+    }
+}
+
+/// A table lock that is held or waited for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockEntry {
+    pub table: KeyString,
+    pub mode: LockMode,
+    /// 0 for locks taken outside of a query batch.
+    pub query_id: u64,
+    pub user: KeyString,
+    pub since: Instant,
+}
+
+impl LockEntry {
+    fn describe(&self) -> String {
+        format!("query {} of user '{}' ({} lock for {} ms)", self.query_id, self.user, self.mode.name(), self.since.elapsed().as_millis())
+    }
+}
+
+/// Tracks who holds and who waits for each table lock so a stalled query can say what it is stuck behind.
+/// Every query batch gets an id when it starts. Locks taken while the batch runs are recorded under that id
+/// and the user that sent it. Lock acquisition gives up after lock_timeout_ms() with an error naming the holders.
+pub struct LockMonitor {
+    next_id: AtomicU64,
+    /// The query batch each worker thread is running.
+    batches: Mutex<HashMap<ThreadId, (u64, KeyString)>>,
+    holders: Mutex<BTreeMap<u64, LockEntry>>,
+    waiters: Mutex<BTreeMap<u64, LockEntry>>,
+    /// Overrides lock_timeout_ms() for this monitor only.
+    timeout_ms: Option<u64>,
+}
+
+impl Default for LockMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LockMonitor {
+    pub fn new() -> LockMonitor {
+        LockMonitor {
+            next_id: AtomicU64::new(1),
+            batches: Mutex::new(HashMap::new()),
+            holders: Mutex::new(BTreeMap::new()),
+            waiters: Mutex::new(BTreeMap::new()),
+            timeout_ms: None,
+        }
+    }
+
+    /// A monitor that gives up on locks after `ms` whatever lock_timeout_ms() is set to. 0 waits forever.
+    pub fn with_timeout_ms(ms: u64) -> LockMonitor {
+        LockMonitor{timeout_ms: Some(ms), ..LockMonitor::new()}
+    }
+
+    /// Attributes the locks this thread takes to a new query id and the user until the guard is dropped.
+    pub fn begin_batch(&self, user: &str) -> BatchGuard<'_> {
+        let query_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let thread = std::thread::current().id();
+        self.batches.lock().unwrap().insert(thread, (query_id, KeyString::from(user)));
+        BatchGuard{monitor: self, thread, query_id}
+    }
+
+    fn current_batch(&self) -> (u64, KeyString) {
+        match self.batches.lock().unwrap().get(&std::thread::current().id()) {
+            Some(batch) => *batch,
+            None => (0, ksf(INTERNAL_USER)),
+        }
+    }
+
+    pub fn read_table<'a>(&'a self, table_name: KeyString, lock: &'a RwLock<Arc<ColumnTable>>) -> Result<TableRead<'a>, EzError> {
+        let (guard, entry) = self.acquire(table_name, LockMode::Read, || match lock.try_read() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(_)) => panic!("Lock on table '{}' is poisoned", table_name),
+        })?;
+        Ok(TableRead{guard, monitor: self, entry})
+    }
+
+    pub fn write_table<'a>(&'a self, table_name: KeyString, lock: &'a RwLock<Arc<ColumnTable>>) -> Result<TableWrite<'a>, EzError> {
+        let (guard, entry) = self.acquire(table_name, LockMode::Write, || match lock.try_write() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::WouldBlock) => None,
+            Err(TryLockError::Poisoned(_)) => panic!("Lock on table '{}' is poisoned", table_name),
+        })?;
+        Ok(TableWrite{guard, monitor: self, entry})
+    }
+
+    /// Polls the lock with a growing backoff until it is free or the timeout runs out.
+    /// Returns the guard and the id of its holder entry.
+    fn acquire<G>(&self, table: KeyString, mode: LockMode, mut try_lock: impl FnMut() -> Option<G>) -> Result<(G, u64), EzError> {
+        let (query_id, user) = self.current_batch();
+        let entry_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = LockEntry{table, mode, query_id, user, since: Instant::now()};
+
+        let guard = match try_lock() {
+            Some(guard) => guard,
+            None => {
+                self.waiters.lock().unwrap().insert(entry_id, entry);
+                let timeout = self.timeout_ms.unwrap_or_else(lock_timeout_ms);
+                let mut backoff = Duration::from_micros(100);
+                let guard = loop {
+                    if let Some(guard) = try_lock() {
+                        break Some(guard)
+                    }
+                    if timeout != 0 && entry.since.elapsed() >= Duration::from_millis(timeout) {
+                        break None
+                    }
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(Duration::from_millis(10));
+                };
+                self.waiters.lock().unwrap().remove(&entry_id);
+                match guard {
+                    Some(guard) => guard,
+                    None => return Err(self.timeout_error(&entry, timeout)),
+                }
+            },
+        };
+
+        self.holders.lock().unwrap().insert(entry_id, LockEntry{since: Instant::now(), ..entry});
+        Ok((guard, entry_id))
+    }
+
+    fn timeout_error(&self, entry: &LockEntry, timeout: u64) -> EzError {
+        let holders: Vec<String> = self.holders.lock().unwrap()
+            .values()
+            .filter(|holder| holder.table == entry.table)
+            .map(|holder| holder.describe())
+            .collect();
+        let holders = match holders.is_empty() {
+            // Some background work locks tables directly without going through the monitor
+            true => "an untracked holder".to_owned(),
+            false => holders.join(", "),
+        };
+        EzError{tag: ErrorTag::Unavailable, text: format!(
+            "Query {} timed out after {} ms waiting for a {} lock on table '{}'. It is held by {}",
+            entry.query_id, timeout, entry.mode.name(), entry.table, holders
+        )}
+    }
+
+    pub fn holders(&self) -> Vec<LockEntry> {
+        self.holders.lock().unwrap().values().copied().collect()
+    }
+
+    pub fn waiters(&self) -> Vec<LockEntry> {
+        self.waiters.lock().unwrap().values().copied().collect()
+    }
+
+    /// The ez_locks system table. One row per lock held or waited for, holders first.
+    pub fn to_table(&self) -> Result<ColumnTable, EzError> {
+        let mut ids = Vec::new();
+        let mut tables = Vec::new();
+        let mut modes = Vec::new();
+        let mut states = Vec::new();
+        let mut query_ids = Vec::new();
+        let mut users = Vec::new();
+        let mut millis = Vec::new();
+        let holders = self.holders();
+        let waiters = self.waiters();
+        let rows = holders.iter().map(|entry| (entry, "holding")).chain(waiters.iter().map(|entry| (entry, "waiting")));
+        for (id, (entry, state)) in rows.enumerate() {
+            ids.push(id as i32);
+            tables.push(entry.table);
+            modes.push(ksf(entry.mode.name()));
+            states.push(ksf(state));
+            query_ids.push(entry.query_id as i32);
+            users.push(entry.user);
+            millis.push(entry.since.elapsed().as_millis() as i32);
+        }
+
+        let mut table = ColumnTable::create_empty("ez_locks", "system");
+        table.add_column(ksf("id"), DbColumn::Ints(ids))?;
+        table.add_column(ksf("table_name"), DbColumn::Texts(tables))?;
+        table.add_column(ksf("mode"), DbColumn::Texts(modes))?;
+        table.add_column(ksf("state"), DbColumn::Texts(states))?;
+        table.add_column(ksf("query_id"), DbColumn::Ints(query_ids))?;
+        table.add_column(ksf("user"), DbColumn::Texts(users))?;
+        table.add_column(ksf("millis"), DbColumn::Ints(millis))?;
+
+        Ok(table)
+    }
+}
+
+/// Ends the batch started by LockMonitor::begin_batch() when dropped.
+pub struct BatchGuard<'a> {
+    monitor: &'a LockMonitor,
+    thread: ThreadId,
+    pub query_id: u64,
+}
+
+impl Drop for BatchGuard<'_> {
+    fn drop(&mut self) {
+        self.monitor.batches.lock().unwrap().remove(&self.thread);
+    }
+}
+
+/// A read lock on a table that is listed in ez_locks until it is dropped.
+pub struct TableRead<'a> {
+    guard: RwLockReadGuard<'a, Arc<ColumnTable>>,
+    monitor: &'a LockMonitor,
+    entry: u64,
+}
+
+impl TableRead<'_> {
+    /// Returns a handle to the table that outlives the lock without copying it.
+    /// The next write to the table copies it instead, so the handle never changes.
+    pub fn share(&self) -> Arc<ColumnTable> {
+        Arc::clone(&self.guard)
+    }
+}
+
+impl Deref for TableRead<'_> {
+    type Target = ColumnTable;
+
+    fn deref(&self) -> &ColumnTable {
+        &self.guard
+    }
+}
+
+impl Drop for TableRead<'_> {
+    fn drop(&mut self) {
+        self.monitor.holders.lock().unwrap().remove(&self.entry);
+    }
+}
+
+/// A write lock on a table that is listed in ez_locks until it is dropped.
+pub struct TableWrite<'a> {
+    guard: RwLockWriteGuard<'a, Arc<ColumnTable>>,
+    monitor: &'a LockMonitor,
+    entry: u64,
+}
+
+impl Deref for TableWrite<'_> {
+    type Target = ColumnTable;
+
+    fn deref(&self) -> &ColumnTable {
+        &self.guard
+    }
+}
+
+impl DerefMut for TableWrite<'_> {
+    fn deref_mut(&mut self) -> &mut ColumnTable {
+        // Only copies the table if a snapshot still shares it
+        Arc::make_mut(&mut self.guard)
+    }
+}
+
+impl Drop for TableWrite<'_> {
+    fn drop(&mut self) {
+        self.monitor.holders.lock().unwrap().remove(&self.entry);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::testing_tools::create_fixed_table;
+
+    use super::*;
+
+    #[test]
+    fn test_lock_timeout_names_holder() {
+        let monitor = LockMonitor::with_timeout_ms(200);
+        let lock = RwLock::new(Arc::new(create_fixed_table(4)));
+        let name = ksf("fixed_table");
+
+        let batch = monitor.begin_batch("bob");
+        let writer = monitor.write_table(name, &lock).unwrap();
+        assert_eq!(monitor.holders()[0].query_id, batch.query_id);
+        assert_eq!(monitor.holders()[0].user, ksf("bob"));
+
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                let _batch = monitor.begin_batch("alice");
+                monitor.read_table(name, &lock).map(|_| ())
+            });
+            // The reader shows up as a waiter while the writer holds the table
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while monitor.waiters().is_empty() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            let locks = monitor.to_table().unwrap();
+            assert_eq!(locks.get_column_text(&ksf("state")).unwrap(), &vec![ksf("holding"), ksf("waiting")]);
+            assert_eq!(locks.get_column_text(&ksf("user")).unwrap(), &vec![ksf("bob"), ksf("alice")]);
+
+            let error = waiter.join().unwrap().unwrap_err();
+            assert_eq!(error.tag, ErrorTag::Unavailable);
+            assert!(error.text.contains(&format!("query {} of user 'bob'", batch.query_id)));
+        });
+
+        drop(writer);
+        assert!(monitor.holders().is_empty());
+        assert!(monitor.waiters().is_empty());
+        assert_eq!(monitor.read_table(name, &lock).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_shared_table_is_copied_on_write() {
+        let monitor = LockMonitor::new();
+        let lock = RwLock::new(Arc::new(create_fixed_table(4)));
+        let name = ksf("fixed_table");
+
+        // Sharing doesn't copy the table
+        let shared = monitor.read_table(name, &lock).unwrap().share();
+        assert!(Arc::ptr_eq(&shared, &lock.read().unwrap()));
+
+        // The first write copies it and the shared table stays as it was
+        monitor.write_table(name, &lock).unwrap().name = ksf("renamed");
+        assert!(!Arc::ptr_eq(&shared, &lock.read().unwrap()));
+        assert_eq!(shared.name, name);
+        assert_eq!(monitor.read_table(name, &lock).unwrap().name, ksf("renamed"));
+
+        // Once nothing shares it, writes happen in place
+        drop(shared);
+        let before = Arc::as_ptr(&lock.read().unwrap());
+        monitor.write_table(name, &lock).unwrap().name = name;
+        assert_eq!(Arc::as_ptr(&lock.read().unwrap()), before);
+    }
+}
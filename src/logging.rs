@@ -2,14 +2,15 @@ use std::{collections::BTreeMap, fmt::Display, fs::{File, OpenOptions}, io::{Rea
 
 use crate::{db_structure::ColumnTable, utilities::{get_precise_time, print_sep_list, u64_from_le_slice, KeyString}};
 
-#[allow(unused)]
-use crate::PATH_SEP;
+use crate::paths::log_file;
 
 
 pub struct Entry {
     count: u64,
     user: KeyString,
     client_address: KeyString,
+    /// The tag of the connection or batch that made the change. See tagging.rs
+    tag: KeyString,
     query: String,
     before_snap: BTreeMap<KeyString, ColumnTable>,
     after_snap: BTreeMap<KeyString, ColumnTable>,
@@ -21,13 +22,14 @@ impl Display for Entry {
         println!("calling: Entry::fmt()");
 
         let mut printer = format!(
-            "{}{} from {} made {} at {}\n\nBefore change:\n",
+            "{}{} from {} [{}] made {} at {}\n\nBefore change:\n",
             match self.finished {
                 true => "",
                 false => "UNFINISHED!!!",
             },
             self.user,
             self.client_address,
+            self.tag,
             self.query,
             self.count,
         );
@@ -57,6 +59,7 @@ impl Entry {
         binary.extend_from_slice(&self.count.to_le_bytes());
         binary.extend_from_slice(&self.user.raw());
         binary.extend_from_slice(&self.client_address.raw());
+        binary.extend_from_slice(self.tag.raw());
         binary.extend_from_slice(&self.query.len().to_le_bytes());        
         binary.extend_from_slice(&self.query.as_bytes());
         for (name, table) in &self.before_snap {
@@ -108,6 +111,9 @@ impl Entry {
         let client_address = KeyString::try_from(&slice[i..i+64]).expect(&format!("if reading a log entry from the binary fails, then there is a bug or the data is corrupted: Failure occured at {} at {} and {}", file!(), line!(), column!()));
         i += 64;
 
+        let tag = KeyString::try_from(&slice[i..i+64]).unwrap_or_else(|_| panic!("if reading a log entry from the binary fails, then there is a bug or the data is corrupted: Failure occured at {} at {} and {}", file!(), line!(), column!()));
+        i += 64;
+
         let query_len = u64_from_le_slice(&slice[i..i+8]);
         i += 8;
 
@@ -159,6 +165,7 @@ impl Entry {
             count,
             user,
             client_address,
+            tag,
             query,
             before_snap,
             after_snap,
@@ -193,7 +200,7 @@ impl Logger {
     pub fn read_log_file(timestamp_path: &str) -> Logger {
         println!("calling: Logger::read_log_file()");
 
-        let mut log_file = OpenOptions::new().read(true).append(true).open(&format!("EZconfig/log/{}", timestamp_path)).expect("Log file should exist before Logger is initialized");
+        let mut log_file = OpenOptions::new().read(true).append(true).open(log_file(timestamp_path)).expect("Log file should exist before Logger is initialized");
         let mut log = Vec::new();
         log_file.read_to_end(&mut log).expect("If reading the log file fails then we damn well better panic!");
         let mut entries = BTreeMap::new();
@@ -220,13 +227,14 @@ impl Logger {
         }
     }
 
-    pub fn start_log(&mut self, query: &str, user: KeyString, client_address: KeyString) -> u64 {
+    pub fn start_log(&mut self, query: &str, user: KeyString, client_address: KeyString, tag: KeyString) -> u64 {
         println!("calling: Logger::start_log()");
 
         let entry = Entry {
             count: self.counter.load(Ordering::SeqCst).wrapping_add(1),
             user,
             client_address,
+            tag,
             query: query.to_owned(),
             before_snap: BTreeMap::new(),
             after_snap: BTreeMap::new(),
@@ -274,7 +282,7 @@ impl Logger {
             binary.extend_from_slice(&entry_size.to_le_bytes());
             binary.extend_from_slice(&entry_binary);
         }
-        let mut log_file = File::create(format!("EZconfig/log/{}", get_precise_time())).unwrap();
+        let mut log_file = File::create(log_file(&get_precise_time().to_string())).unwrap();
         log_file.write_all(&binary).unwrap();
         self.entries = BTreeMap::new();
         self.counter.store(0, Ordering::SeqCst);
@@ -301,7 +309,7 @@ mod tests {
     //     // 18572054;flísalím;42
 
     //     let query_string = "INSERT(table_name: good_csv, value_columns: (vnr, heiti, magn), new_values: (0113446, harlech, 2500))".to_owned();
-    //     let hash = logger.start_log(&query_string, KeyString::from("test"), table.metadata.created_by);
+    //     let hash = logger.start_log(&query_string, KeyString::from("test"), table.metadata.created_by, ksf(UNTAGGED));
     //     logger.update_before_log(hash, &table);
     //     let query = parse_EZQL(&query_string).unwrap();
     //     match &query {
@@ -1,7 +1,13 @@
 //#![allow(unused)]
 //#![allow(non_snake_case)]
+// Only main() below uses the imports, and it is only built on Linux
+#![cfg_attr(not(target_os = "linux"), allow(unused_imports))]
 
 
+use EZDB::backup;
+use EZDB::config;
+#[cfg(target_os = "linux")]
+use EZDB::daemon;
 use EZDB::db_structure::ColumnTable;
 use EZDB::db_structure::DbValue;
 use EZDB::ezql::execute_select_query;
@@ -10,12 +16,25 @@ use EZDB::ezql::OpOrCond;
 use EZDB::ezql::Query;
 use EZDB::ezql::RangeOrListOrAll;
 use EZDB::ezql::TestOp;
+use EZDB::paths;
+use EZDB::self_test;
+#[cfg(target_os = "linux")]
 use EZDB::server_networking;
+use EZDB::database::Database;
+use EZDB::transport::ServerTransport;
 use EZDB::utilities;
 
+// The server is built on epoll and the daemon on fork, which only Linux has. See the Platforms section of the README
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("The EZDB server only runs on Linux. Run it under WSL on Windows and use the client library or ezdb-cli to connect to it");
+    std::process::exit(1);
+}
+
+#[cfg(target_os = "linux")]
 fn main() -> Result<(), utilities::EzError> {
 
-    let massive_table_binary = std::fs::read("test_files/massive_table.eztable").unwrap();
+    let massive_table_binary = std::fs::read(paths::test_file("massive_table.eztable")).unwrap();
         println!("HERE!");
         let massive_table = ColumnTable::from_binary("massive_table".into(), &massive_table_binary).unwrap();
         println!("HERE!");
@@ -29,10 +48,12 @@ fn main() -> Result<(), utilities::EzError> {
                 "oRMWqCfGSVjYydfSJeQnNgbPtqjQTaOTscYsxyy`NeeJVmU".into(),
             ],
             conditions: vec![
-                OpOrCond::Cond(Condition{attribute: "tqn[SNsonEhmBAbkTphVntSTPTqwyN]^EVnt".into(), op: TestOp::Greater, value: DbValue::Float(0.0)}),
-                OpOrCond::Cond(Condition{attribute: r"qlsCKiYAd_tko\PLNkoHwB`bUNlcTf_AryKdRKGmyo]ZixfsVNaELouL".into(), op: TestOp::Equals, value: DbValue::Text("Hella".into())}),
-                OpOrCond::Cond(Condition{attribute: "oRMWqCfGSVjYydfSJeQnNgbPtqjQTaOTscYsxyy`NeeJVmU".into(), op: TestOp::Greater, value: DbValue::Int(0)}),
+                OpOrCond::Cond(Condition{attribute: "tqn[SNsonEhmBAbkTphVntSTPTqwyN]^EVnt".into(), op: TestOp::Greater, value: DbValue::Float(0.0), other_column: None}),
+                OpOrCond::Cond(Condition{attribute: r"qlsCKiYAd_tko\PLNkoHwB`bUNlcTf_AryKdRKGmyo]ZixfsVNaELouL".into(), op: TestOp::Equals, value: DbValue::Text("Hella".into()), other_column: None}),
+                OpOrCond::Cond(Condition{attribute: "oRMWqCfGSVjYydfSJeQnNgbPtqjQTaOTscYsxyy`NeeJVmU".into(), op: TestOp::Greater, value: DbValue::Int(0), other_column: None}),
             ],
+            distinct: false,
+            limit: None,
         };
         println!("HERE!");
 
@@ -44,12 +65,64 @@ fn main() -> Result<(), utilities::EzError> {
     println!("calling: main()");
 
 
-    let args = std::env::args();
+    let args: Vec<String> = std::env::args().collect();
+
+    // The config file, then the EZDB_ variables, then the flags that set a setting. See config.rs
+    let config_path = args.iter()
+        .find_map(|arg| arg.strip_prefix("--config="))
+        .map_or_else(|| std::path::PathBuf::from(config::CONFIG_FILE), std::path::PathBuf::from);
+    let mut config = config::ServerConfig::load(&config_path)?;
+    config.apply_flags(&args)?;
+    config.apply();
+    config::log(config::LogLevel::Info, format_args!("Config:\n{}", config.to_string().trim_end()));
 
+    let mut run_self_test = false;
+    let mut detach = false;
+    let mut restore = None;
+
+    // The flags that do something rather than set something
     for arg in args {
-        println!("{}", arg);
+        config::log(config::LogLevel::Debug, &arg);
+        if arg == "--self-test" {
+            run_self_test = true;
+        }
+        // Detaches from the terminal, writes a pid file and sends all output to the log file
+        if arg == "--daemon" {
+            detach = true;
+        }
+        // Replaces the data directory with a backup archive before anything is loaded
+        if let Some(path) = arg.strip_prefix("--restore=") {
+            restore = Some(std::path::PathBuf::from(path));
+        }
+    }
+
+    if let Some(archive) = &restore {
+        let summary = backup::restore_backup(archive, &paths::config_dir())?;
+        print!("Restored {}", summary);
     }
 
+    // Checks the storage path end to end and exits without starting the server
+    if run_self_test {
+        let database = std::sync::Arc::new(Database::init()?);
+        let report = self_test::run_self_test(database);
+        println!("{}", report);
+        std::process::exit(if report.passed() { 0 } else { 1 });
+    }
+
+    // Has to happen before the server starts any threads. The pid file is removed when main returns
+    let mut pid_file = config.pid_file.clone();
+    if detach {
+        std::fs::create_dir_all(paths::config_dir())?;
+        daemon::daemonize(&config.log_file.clone().unwrap_or_else(paths::server_log_file))?;
+        pid_file.get_or_insert_with(paths::pid_file);
+    } else if let Some(path) = &config.log_file {
+        daemon::redirect_output(path)?;
+    }
+    let _pid_file = match &pid_file {
+        Some(path) => Some(daemon::PidFile::create(path)?),
+        None => None,
+    };
+
     // This stuff is for debugging purposes around simd
     #[cfg(target_feature="avx2")]
     unsafe fn p() {
@@ -65,7 +138,23 @@ fn main() -> Result<(), utilities::EzError> {
     // This stuff is for debugging purposes around simd
     unsafe { p() };
     
-    server_networking::run_server("127.0.0.1:3004")?;
+    // Clients connect with the Noise handshake unless the server is given a TLS certificate and key
+    let transport = match (config.tls_cert.clone(), config.tls_key.clone()) {
+        (None, None) => ServerTransport::Noise,
+        #[cfg(feature = "tls")]
+        (Some(cert), Some(key)) => ServerTransport::Tls(EZDB::transport::load_server_config(&cert, &key)?),
+        #[cfg(not(feature = "tls"))]
+        (Some(_), Some(_)) => {
+            println!("This build has no TLS support. Build with --features tls");
+            std::process::exit(1);
+        },
+        _ => {
+            println!("--tls-cert and --tls-key must be given together");
+            std::process::exit(1);
+        },
+    };
+
+    server_networking::run_server_with_transport(&config.listen_address, transport)?;
 
     Ok(())
 }
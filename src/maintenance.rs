@@ -0,0 +1,429 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::blob_store::referenced_blobs;
+use crate::config::{log, LogLevel};
+use crate::db_structure::{ColumnTable, DbColumn};
+use crate::database::Database;
+use crate::utilities::{get_current_time, ksf, u64_from_le_slice, ErrorTag, EzError, KeyString};
+use crate::paths::config_file;
+
+
+/// The file in the config directory that holds the tasks.
+pub const TASKS_FILE: &str = ".tasks";
+
+/// The size of a single serialized Task. id, kind, target, state, completed, total.
+pub const TASK_BINARY_SIZE: usize = 8 + 64 + 64 + 64 + 8 + 8;
+
+/// The kinds of long running maintenance jobs the server knows how to perform.
+/// Each kind is split into steps so that it can be paused or cancelled between steps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskKind {
+    /// Writes every table in the buffer pool to disk, one table per step.
+    FlushTables,
+    /// Deletes blobs no table cell refers to.
+    CollectBlobs,
+    /// Removes duplicate rows from the target table. See ColumnTable::deduplicate()
+    Deduplicate(KeyString),
+    /// Re-sorts the target table by primary key.
+    Sort(KeyString),
+}
+
+impl Display for TaskKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskKind::FlushTables => write!(f, "FLUSH_TABLES"),
+            TaskKind::CollectBlobs => write!(f, "COLLECT_BLOBS"),
+            TaskKind::Deduplicate(table_name) => write!(f, "DEDUPLICATE({})", table_name),
+            TaskKind::Sort(table_name) => write!(f, "SORT({})", table_name),
+        }
+    }
+}
+
+impl TaskKind {
+    pub fn name(&self) -> KeyString {
+        match self {
+            TaskKind::FlushTables => ksf("FLUSH_TABLES"),
+            TaskKind::CollectBlobs => ksf("COLLECT_BLOBS"),
+            TaskKind::Deduplicate(_) => ksf("DEDUPLICATE"),
+            TaskKind::Sort(_) => ksf("SORT"),
+        }
+    }
+
+    pub fn target(&self) -> KeyString {
+        match self {
+            TaskKind::FlushTables => KeyString::new(),
+            TaskKind::CollectBlobs => KeyString::new(),
+            TaskKind::Deduplicate(table_name) => *table_name,
+            TaskKind::Sort(table_name) => *table_name,
+        }
+    }
+
+    pub fn from_name(name: &KeyString, target: KeyString) -> Result<TaskKind, EzError> {
+        match name.as_str() {
+            "FLUSH_TABLES" => Ok(TaskKind::FlushTables),
+            "COLLECT_BLOBS" => Ok(TaskKind::CollectBlobs),
+            "DEDUPLICATE" => Ok(TaskKind::Deduplicate(target)),
+            "SORT" => Ok(TaskKind::Sort(target)),
+            other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unknown task kind: '{}'", other)}),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Paused,
+    Cancelled,
+    Finished,
+    Failed,
+}
+
+impl Display for TaskState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl TaskState {
+    pub fn name(&self) -> KeyString {
+        match self {
+            TaskState::Running => ksf("RUNNING"),
+            TaskState::Paused => ksf("PAUSED"),
+            TaskState::Cancelled => ksf("CANCELLED"),
+            TaskState::Finished => ksf("FINISHED"),
+            TaskState::Failed => ksf("FAILED"),
+        }
+    }
+
+    pub fn from_name(name: &KeyString) -> Result<TaskState, EzError> {
+        match name.as_str() {
+            "RUNNING" => Ok(TaskState::Running),
+            "PAUSED" => Ok(TaskState::Paused),
+            "CANCELLED" => Ok(TaskState::Cancelled),
+            "FINISHED" => Ok(TaskState::Finished),
+            "FAILED" => Ok(TaskState::Failed),
+            other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("Unknown task state: '{}'", other)}),
+        }
+    }
+
+    /// Whether the task can still change state
+    pub fn is_live(&self) -> bool {
+        matches!(self, TaskState::Running | TaskState::Paused)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Task {
+    pub id: u64,
+    pub kind: TaskKind,
+    pub state: TaskState,
+    pub completed: u64,
+    pub total: u64,
+}
+
+impl Task {
+    /// Progress of the task as a percentage between 0 and 100
+    pub fn progress(&self) -> f32 {
+        if self.total == 0 {
+            match self.state {
+                TaskState::Finished => 100.0,
+                _ => 0.0,
+            }
+        } else {
+            self.completed as f32 * 100.0 / self.total as f32
+        }
+    }
+
+    pub fn to_binary(&self) -> [u8; TASK_BINARY_SIZE] {
+        let mut binary = [0u8; TASK_BINARY_SIZE];
+        binary[0..8].copy_from_slice(&self.id.to_le_bytes());
+        binary[8..72].copy_from_slice(self.kind.name().raw());
+        binary[72..136].copy_from_slice(self.kind.target().raw());
+        binary[136..200].copy_from_slice(self.state.name().raw());
+        binary[200..208].copy_from_slice(&self.completed.to_le_bytes());
+        binary[208..216].copy_from_slice(&self.total.to_le_bytes());
+        binary
+    }
+
+    pub fn from_binary(binary: &[u8]) -> Result<Task, EzError> {
+        if binary.len() != TASK_BINARY_SIZE {
+            return Err(EzError{tag: ErrorTag::Deserialization, text: format!("A task must be exactly {} bytes. Received {} bytes", TASK_BINARY_SIZE, binary.len())})
+        }
+        let id = u64_from_le_slice(&binary[0..8]);
+        let kind_name = KeyString::try_from(&binary[8..72])?;
+        let target = KeyString::try_from(&binary[72..136])?;
+        let state = TaskState::from_name(&KeyString::try_from(&binary[136..200])?)?;
+        let completed = u64_from_le_slice(&binary[200..208]);
+        let total = u64_from_le_slice(&binary[208..216]);
+
+        Ok(Task {
+            id,
+            kind: TaskKind::from_name(&kind_name, target)?,
+            state,
+            completed,
+            total,
+        })
+    }
+}
+
+/// Keeps track of background maintenance tasks. Tasks are advanced one step at a time by
+/// perform_maintenance() so an admin can pause or cancel them between steps.
+/// Every state change is persisted so unfinished tasks pick up where they left off after a restart.
+pub struct TaskManager {
+    tasks: RwLock<BTreeMap<u64, Task>>,
+    next_id: AtomicU64,
+    path: Option<PathBuf>,
+}
+
+impl TaskManager {
+    /// A task manager that keeps its state in memory only. Used for tests.
+    pub fn new() -> TaskManager {
+        TaskManager {
+            tasks: RwLock::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+            path: None,
+        }
+    }
+
+    /// Loads the task manager state from the given file, creating an empty manager if the file doesn't exist.
+    pub fn load(path: &Path) -> Result<TaskManager, EzError> {
+        println!("calling: TaskManager::load()");
+
+        let mut tasks = BTreeMap::new();
+        if path.exists() {
+            let binary = std::fs::read(path)?;
+            for chunk in binary.chunks(TASK_BINARY_SIZE) {
+                let task = Task::from_binary(chunk)?;
+                tasks.insert(task.id, task);
+            }
+        }
+
+        let next_id = match tasks.keys().last() {
+            Some(id) => id + 1,
+            None => 0,
+        };
+
+        Ok(TaskManager {
+            tasks: RwLock::new(tasks),
+            next_id: AtomicU64::new(next_id),
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    pub fn default_path() -> PathBuf {
+        config_file(TASKS_FILE)
+    }
+
+    fn persist(&self) -> Result<(), EzError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(&self.to_binary())?;
+
+        Ok(())
+    }
+
+    /// Every task in the layout of the tasks file, which is what load() reads.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut binary = Vec::new();
+        for task in self.tasks.read().unwrap().values() {
+            binary.extend_from_slice(&task.to_binary());
+        }
+        binary
+    }
+
+    /// Registers a new task in the running state and returns its id.
+    pub fn start(&self, kind: TaskKind) -> Result<u64, EzError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.tasks.write().unwrap().insert(id, Task {
+            id,
+            kind,
+            state: TaskState::Running,
+            completed: 0,
+            total: 0,
+        });
+        self.persist()?;
+
+        Ok(id)
+    }
+
+    fn set_state(&self, id: u64, from: &[TaskState], to: TaskState) -> Result<(), EzError> {
+        {
+            let mut tasks = self.tasks.write().unwrap();
+            let task = match tasks.get_mut(&id) {
+                Some(task) => task,
+                None => return Err(EzError{tag: ErrorTag::Query, text: format!("No task with id: {}", id)}),
+            };
+            if !from.contains(&task.state) {
+                return Err(EzError{tag: ErrorTag::Query, text: format!("Task {} is {} and cannot become {}", id, task.state, to)});
+            }
+            task.state = to;
+        }
+        self.persist()
+    }
+
+    pub fn pause(&self, id: u64) -> Result<(), EzError> {
+        self.set_state(id, &[TaskState::Running], TaskState::Paused)
+    }
+
+    pub fn resume(&self, id: u64) -> Result<(), EzError> {
+        self.set_state(id, &[TaskState::Paused], TaskState::Running)
+    }
+
+    pub fn cancel(&self, id: u64) -> Result<(), EzError> {
+        self.set_state(id, &[TaskState::Running, TaskState::Paused], TaskState::Cancelled)
+    }
+
+    pub fn get(&self, id: u64) -> Option<Task> {
+        self.tasks.read().unwrap().get(&id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Task> {
+        self.tasks.read().unwrap().values().cloned().collect()
+    }
+
+    /// Advances the oldest running task by a single step. Returns false if there was nothing to do.
+    /// The task lock is not held while the step runs so pause and cancel requests are never blocked by a step.
+    pub fn run_step(&self, database: &Database) -> Result<bool, EzError> {
+
+        let mut task = match self.tasks.read().unwrap().values().find(|task| task.state == TaskState::Running) {
+            Some(task) => task.clone(),
+            None => return Ok(false),
+        };
+
+        let result = perform_task_step(&mut task, database);
+
+        {
+            let mut tasks = self.tasks.write().unwrap();
+            let current = match tasks.get_mut(&task.id) {
+                Some(current) => current,
+                None => return Ok(true),
+            };
+            current.completed = task.completed;
+            current.total = task.total;
+            match &result {
+                Ok(_) => if current.state == TaskState::Running && task.completed >= task.total {
+                    current.state = TaskState::Finished;
+                },
+                Err(_) => current.state = TaskState::Failed,
+            }
+        }
+        self.persist()?;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The tasks as a table, for the ez_tasks system table and the TASK admin commands.
+    pub fn to_table(&self) -> Result<ColumnTable, EzError> {
+        let tasks = self.list();
+
+        let mut table = ColumnTable::create_empty("ez_tasks", "system");
+        table.add_column(ksf("id"), DbColumn::Ints(tasks.iter().map(|t| t.id as i32).collect()))?;
+        table.add_column(ksf("kind"), DbColumn::Texts(tasks.iter().map(|t| t.kind.name()).collect()))?;
+        table.add_column(ksf("target"), DbColumn::Texts(tasks.iter().map(|t| t.kind.target()).collect()))?;
+        table.add_column(ksf("state"), DbColumn::Texts(tasks.iter().map(|t| t.state.name()).collect()))?;
+        table.add_column(ksf("progress"), DbColumn::Floats(tasks.iter().map(|t| t.progress()).collect()))?;
+
+        Ok(table)
+    }
+}
+
+impl Default for TaskManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Performs the next step of the given task and updates its progress counters.
+fn perform_task_step(task: &mut Task, database: &Database) -> Result<(), EzError> {
+    println!("calling: perform_task_step()");
+
+    match task.kind {
+        TaskKind::FlushTables => {
+            let tables = database.buffer_pool.tables.read().unwrap();
+            if task.total == 0 {
+                task.total = tables.len() as u64;
+            }
+            // Tables are flushed in name order so the completed counter doubles as a cursor.
+            if let Some((name, table)) = tables.iter().nth(task.completed as usize) {
+                database.buffer_pool.flush_table(name, &table.read().unwrap(), true)?;
+            }
+            task.completed = std::cmp::min(task.completed + 1, task.total);
+        },
+        TaskKind::CollectBlobs => {
+            task.total = 1;
+            let removed = database.blobs.collect_garbage(get_current_time(), || referenced_blobs(database))?;
+            log(LogLevel::Info, format_args!("Collected {} unreferenced blobs", removed));
+            task.completed = 1;
+        },
+        TaskKind::Deduplicate(table_name) => {
+            task.total = 1;
+            database.buffer_pool.ensure_loaded(&table_name)?;
+            let tables = database.buffer_pool.tables.read().unwrap();
+            let mut table = match tables.get(&table_name) {
+                Some(table) => table.write().unwrap(),
+                None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named: '{}'", table_name)}),
+            };
+            Arc::make_mut(&mut table).deduplicate();
+            database.buffer_pool.mark_table_changed(table_name);
+            task.completed = 1;
+        },
+        TaskKind::Sort(table_name) => {
+            task.total = 1;
+            database.buffer_pool.ensure_loaded(&table_name)?;
+            let tables = database.buffer_pool.tables.read().unwrap();
+            let mut table = match tables.get(&table_name) {
+                Some(table) => table.write().unwrap(),
+                None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named: '{}'", table_name)}),
+            };
+            Arc::make_mut(&mut table).sort();
+            database.buffer_pool.mark_table_changed(table_name);
+            task.completed = 1;
+        },
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_task_binary() {
+        let task = Task {
+            id: 7,
+            kind: TaskKind::Deduplicate(ksf("products")),
+            state: TaskState::Paused,
+            completed: 3,
+            total: 10,
+        };
+        let binary = task.to_binary();
+        assert_eq!(Task::from_binary(&binary).unwrap(), task);
+        assert_eq!(task.progress(), 30.0);
+    }
+
+    #[test]
+    fn test_task_state_transitions() {
+        let manager = TaskManager::new();
+        let id = manager.start(TaskKind::FlushTables).unwrap();
+        manager.pause(id).unwrap();
+        assert!(manager.pause(id).is_err());
+        manager.resume(id).unwrap();
+        manager.cancel(id).unwrap();
+        assert!(manager.resume(id).is_err());
+        assert_eq!(manager.get(id).unwrap().state, TaskState::Cancelled);
+    }
+}
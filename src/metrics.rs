@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use crate::database::Database;
+
+
+/// Upper bounds in microseconds of the buckets of the query latency histograms. Slower queries only count in +Inf.
+pub const LATENCY_BUCKETS_US: [u64; 8] = [100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 1_000_000];
+
+/// Running counters for one query type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct QueryTypeCounters {
+    pub queries: u64,
+    pub errors: u64,
+    pub total_latency_micros: u64,
+    /// Queries per bucket of LATENCY_BUCKETS_US, not cumulative. The last one counts the slower queries.
+    pub histogram: [u64; LATENCY_BUCKETS_US.len() + 1],
+}
+
+/// Counts queries by type and connections since the server started. Everything else in the metrics is read
+/// from the database when they are rendered. See render_metrics().
+pub struct Metrics {
+    counters: RwLock<BTreeMap<&'static str, QueryTypeCounters>>,
+    pub connections_accepted: AtomicU64,
+    pub open_connections: AtomicU64,
+    /// Requests dropped because the client disconnected before they finished.
+    pub cancelled_requests: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            counters: RwLock::new(BTreeMap::new()),
+            connections_accepted: AtomicU64::new(0),
+            open_connections: AtomicU64::new(0),
+            cancelled_requests: AtomicU64::new(0),
+        }
+    }
+
+    /// A batch is timed as a whole, so it is counted once under each query type it holds with the latency of the batch.
+    pub fn record_batch(&self, query_types: &[&'static str], latency_micros: u64, failed: bool) {
+        let mut types = query_types.to_vec();
+        types.sort();
+        types.dedup();
+        let bucket = LATENCY_BUCKETS_US.iter().position(|bound| latency_micros <= *bound).unwrap_or(LATENCY_BUCKETS_US.len());
+
+        let mut counters = self.counters.write().unwrap();
+        for query_type in types {
+            let entry = counters.entry(query_type).or_default();
+            entry.queries += 1;
+            entry.total_latency_micros += latency_micros;
+            entry.histogram[bucket] += 1;
+            if failed {
+                entry.errors += 1;
+            }
+        }
+    }
+
+    pub fn get_counters(&self, query_type: &str) -> QueryTypeCounters {
+        self.counters.read().unwrap().get(query_type).copied().unwrap_or_default()
+    }
+
+    pub fn connection_accepted(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn request_cancelled(&self) {
+        self.cancelled_requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Escapes a label value for the Prometheus text format.
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// The metrics in the Prometheus text exposition format, as answered to the METRICS message:
+/// query counts, errors and latency histograms by query type, buffer pool memory, the size of every loaded
+/// table, connections and thread pool utilization.
+pub fn render_metrics(database: &Database) -> String {
+
+    let mut out = String::new();
+    let counters = database.metrics.counters.read().unwrap().clone();
+
+    out.push_str("# HELP ezdb_queries_total Queries by type since the server started.\n# TYPE ezdb_queries_total counter\n");
+    for (query_type, counter) in &counters {
+        let _ = writeln!(out, "ezdb_queries_total{{type=\"{}\"}} {}", query_type, counter.queries);
+    }
+    out.push_str("# HELP ezdb_query_errors_total Queries that failed, by type.\n# TYPE ezdb_query_errors_total counter\n");
+    for (query_type, counter) in &counters {
+        let _ = writeln!(out, "ezdb_query_errors_total{{type=\"{}\"}} {}", query_type, counter.errors);
+    }
+    out.push_str("# HELP ezdb_query_latency_microseconds Latency of the batches holding each query type.\n# TYPE ezdb_query_latency_microseconds histogram\n");
+    for (query_type, counter) in &counters {
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS_US.iter().zip(counter.histogram.iter()) {
+            cumulative += count;
+            let _ = writeln!(out, "ezdb_query_latency_microseconds_bucket{{type=\"{}\",le=\"{}\"}} {}", query_type, bound, cumulative);
+        }
+        let _ = writeln!(out, "ezdb_query_latency_microseconds_bucket{{type=\"{}\",le=\"+Inf\"}} {}", query_type, counter.queries);
+        let _ = writeln!(out, "ezdb_query_latency_microseconds_sum{{type=\"{}\"}} {}", query_type, counter.total_latency_micros);
+        let _ = writeln!(out, "ezdb_query_latency_microseconds_count{{type=\"{}\"}} {}", query_type, counter.queries);
+    }
+
+    let mut table_bytes = Vec::new();
+    for (name, table) in database.buffer_pool.tables.read().unwrap().iter() {
+        let table = table.read().unwrap();
+        table_bytes.push((name.to_string(), table.len(), table.byte_size()));
+    }
+    let usage = database.buffer_pool.memory_usage();
+    out.push_str("# HELP ezdb_buffer_pool_bytes Bytes held in memory by loaded tables and values.\n# TYPE ezdb_buffer_pool_bytes gauge\n");
+    let _ = writeln!(out, "ezdb_buffer_pool_bytes{{kind=\"tables\"}} {}", usage.tables);
+    let _ = writeln!(out, "ezdb_buffer_pool_bytes{{kind=\"values\"}} {}", usage.values);
+    let _ = writeln!(out, "ezdb_buffer_pool_bytes{{kind=\"value_history\"}} {}", usage.value_history);
+    out.push_str("# HELP ezdb_buffer_pool_max_bytes The most the buffer pool may hold.\n# TYPE ezdb_buffer_pool_max_bytes gauge\n");
+    let _ = writeln!(out, "ezdb_buffer_pool_max_bytes {}", database.buffer_pool.max_size());
+    out.push_str("# HELP ezdb_tables_unloaded Tables unloaded for being idle.\n# TYPE ezdb_tables_unloaded gauge\n");
+    let _ = writeln!(out, "ezdb_tables_unloaded {}", database.buffer_pool.unloaded_tables.read().unwrap().len());
+
+    out.push_str("# HELP ezdb_table_rows Rows in each loaded table.\n# TYPE ezdb_table_rows gauge\n");
+    for (name, rows, _) in &table_bytes {
+        let _ = writeln!(out, "ezdb_table_rows{{table=\"{}\"}} {}", label(name), rows);
+    }
+    out.push_str("# HELP ezdb_table_bytes Bytes held by each loaded table.\n# TYPE ezdb_table_bytes gauge\n");
+    for (name, _, bytes) in &table_bytes {
+        let _ = writeln!(out, "ezdb_table_bytes{{table=\"{}\"}} {}", label(name), bytes);
+    }
+
+    out.push_str("# HELP ezdb_connections_open Connections the server is currently tracking.\n# TYPE ezdb_connections_open gauge\n");
+    let _ = writeln!(out, "ezdb_connections_open {}", database.metrics.open_connections.load(Ordering::Relaxed));
+    out.push_str("# HELP ezdb_connections_accepted_total Connections accepted since the server started.\n# TYPE ezdb_connections_accepted_total counter\n");
+    let _ = writeln!(out, "ezdb_connections_accepted_total {}", database.metrics.connections_accepted.load(Ordering::Relaxed));
+    out.push_str("# HELP ezdb_requests_cancelled_total Requests dropped because the client disconnected.\n# TYPE ezdb_requests_cancelled_total counter\n");
+    let _ = writeln!(out, "ezdb_requests_cancelled_total {}", database.metrics.cancelled_requests.load(Ordering::Relaxed));
+
+    out.push_str("# HELP ezdb_thread_pool_busy_workers Workers running a job.\n# TYPE ezdb_thread_pool_busy_workers gauge\n");
+    let _ = writeln!(out, "ezdb_thread_pool_busy_workers {}", database.pool.busy_workers.load(Ordering::Relaxed));
+    out.push_str("# HELP ezdb_thread_pool_queue_depth Jobs waiting for a worker.\n# TYPE ezdb_thread_pool_queue_depth gauge\n");
+    let _ = writeln!(out, "ezdb_thread_pool_queue_depth {}", database.pool.queue_depth.load(Ordering::Relaxed));
+    out.push_str("# HELP ezdb_thread_pool_priority_jobs_total Health checks and admin jobs that skipped the regular queue.\n# TYPE ezdb_thread_pool_priority_jobs_total counter\n");
+    let _ = writeln!(out, "ezdb_thread_pool_priority_jobs_total {}", database.pool.priority_jobs.load(Ordering::Relaxed));
+
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_metrics() {
+        let metrics = Metrics::new();
+        metrics.record_batch(&["SELECT", "SELECT", "SUMMARY"], 50, false);
+        metrics.record_batch(&["SELECT"], 2_000, true);
+        metrics.record_batch(&["SELECT"], 5_000_000, false);
+
+        let select = metrics.get_counters("SELECT");
+        assert_eq!(select.queries, 3);
+        assert_eq!(select.errors, 1);
+        assert_eq!(select.total_latency_micros, 5_002_050);
+        assert_eq!(select.histogram[0], 1);
+        assert_eq!(select.histogram[3], 1);
+        assert_eq!(select.histogram[LATENCY_BUCKETS_US.len()], 1);
+        assert_eq!(metrics.get_counters("SUMMARY").queries, 1);
+        assert_eq!(metrics.get_counters("DELETE"), QueryTypeCounters::default());
+
+        assert_eq!(label("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
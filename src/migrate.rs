@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use ezcbor::cbor::decode_cbor;
+
+use crate::auth::User;
+use crate::db_structure::{table_format, table_versions, ColumnTable, TABLE_FORMAT_VERSION, VERSIONED_COLUMN_TABLE_PREFIX};
+use crate::disk_utilities::{is_chunk_manifest, USERS_FILE};
+use crate::paths::{path_to_string, RAW_TABLES_DIR};
+use crate::utilities::{get_current_time, ErrorTag, EzError, KeyString};
+
+/// Backups of the files a migration rewrites go in a directory with this prefix and the time of the migration.
+pub const MIGRATION_BACKUP_PREFIX: &str = "migration_backup_";
+
+/// What a migration did, or would do on a dry run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MigrationReport {
+    /// Table files rewritten in the current format.
+    pub upgraded: Vec<String>,
+    /// Table files that were already current.
+    pub current: usize,
+    /// Table files written by a newer server. They are left alone.
+    pub newer: Vec<String>,
+    /// Files that could not be read or converted, with the reason.
+    pub failed: Vec<(String, String)>,
+    /// Users whose passwords are still unsalted. Their hashes can only be replaced when they log in next.
+    pub legacy_users: Vec<String>,
+    /// Where the original files were copied. None if nothing was rewritten.
+    pub backup_dir: Option<PathBuf>,
+    pub dry_run: bool,
+}
+
+impl Display for MigrationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.dry_run { "would be upgraded" } else { "upgraded" };
+        writeln!(f, "{} table file(s) {}", self.upgraded.len(), verb)?;
+        for name in &self.upgraded {
+            writeln!(f, "\t{}", name)?;
+        }
+        writeln!(f, "{} table file(s) already current", self.current)?;
+        if !self.newer.is_empty() {
+            writeln!(f, "{} table file(s) written by a newer server were left alone: {}", self.newer.len(), self.newer.join(", "))?;
+        }
+        for (name, reason) in &self.failed {
+            writeln!(f, "FAILED {}: {}", name, reason)?;
+        }
+        if !self.legacy_users.is_empty() {
+            writeln!(f, "{} user(s) have unsalted passwords and are upgraded at their next login: {}", self.legacy_users.len(), self.legacy_users.join(", "))?;
+        }
+        if let Some(dir) = &self.backup_dir {
+            writeln!(f, "Original files were copied to {}", path_to_string(dir))?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts the old format files of a data directory to the current format in place. The server must not be
+/// running. Every file is copied to a backup directory before it is replaced, and replaced through a temporary
+/// file so a crash leaves either the old or the new file. A dry run only reports what would be done.
+pub fn migrate_data_dir(data_dir: &Path, dry_run: bool) -> Result<MigrationReport, EzError> {
+    if !data_dir.is_dir() {
+        return Err(EzError{tag: ErrorTag::Io, text: format!("'{}' is not a data directory", path_to_string(data_dir))})
+    }
+
+    let mut report = MigrationReport{dry_run, ..Default::default()};
+    let backup_dir = data_dir.join(format!("{}{}", MIGRATION_BACKUP_PREFIX, get_current_time()));
+
+    let tables_dir = data_dir.join(RAW_TABLES_DIR);
+    if tables_dir.is_dir() {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(&tables_dir)?.map(|entry| entry.map(|e| e.path())).collect::<Result<_, _>>()?;
+        files.sort();
+        for path in files {
+            let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+            match migrate_table_file(&path, &name, data_dir, &backup_dir, dry_run) {
+                Ok(TableFileState::Upgraded) => report.upgraded.push(name),
+                Ok(TableFileState::Current) => report.current += 1,
+                Ok(TableFileState::Newer) => report.newer.push(name),
+                Err(e) => report.failed.push((name, e.to_string())),
+            }
+        }
+    }
+
+    let users_path = data_dir.join(USERS_FILE);
+    if users_path.exists() {
+        match decode_cbor::<BTreeMap<KeyString, User>>(&std::fs::read(&users_path)?) {
+            Ok(users) => report.legacy_users = users.into_iter()
+                .filter(|(_, user)| user.password.is_legacy())
+                .map(|(username, _)| username.to_string())
+                .collect(),
+            Err(e) => report.failed.push((USERS_FILE.to_owned(), EzError::from(e).to_string())),
+        }
+    }
+
+    if !report.upgraded.is_empty() && !dry_run {
+        report.backup_dir = Some(backup_dir);
+    }
+
+    Ok(report)
+}
+
+enum TableFileState {
+    Upgraded,
+    Current,
+    Newer,
+}
+
+fn migrate_table_file(path: &Path, name: &str, data_dir: &Path, backup_dir: &Path, dry_run: bool) -> Result<TableFileState, EzError> {
+    let binary = std::fs::read(path)?;
+    // Chunks are always written in the current format. Only the manifest is in raw_tables
+    if is_chunk_manifest(&binary) {
+        return Ok(TableFileState::Current)
+    }
+    if binary.len() < 64 {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Only {} bytes. Not a table file", binary.len())})
+    }
+    let format = match table_format(&binary[0..64]) {
+        Ok(format) => format,
+        // Newer servers can write formats this one can't read at all
+        Err(_) if binary[0..64].starts_with(VERSIONED_COLUMN_TABLE_PREFIX.as_bytes()) => return Ok(TableFileState::Newer),
+        Err(e) => return Err(e),
+    };
+    if format.version > TABLE_FORMAT_VERSION {
+        return Ok(TableFileState::Newer)
+    }
+    let table = ColumnTable::from_binary(Some(name), &binary)?;
+    // Files with the metadata only need a migration if they were written in a version too old for their columns,
+    // as tables with Duration or LongText columns once were. Checksums are added the next time the server writes the table
+    if format.has_metadata && format.version >= table_versions(&table, false).0 {
+        return Ok(TableFileState::Current)
+    }
+
+    let upgraded = table.to_disk_binary()?;
+    if ColumnTable::from_binary(Some(name), &upgraded)? != table {
+        return Err(EzError{tag: ErrorTag::Structure, text: "The converted table does not read back the same. Left unchanged".to_owned()})
+    }
+    if dry_run {
+        return Ok(TableFileState::Upgraded)
+    }
+
+    let backup_tables = backup_dir.join(RAW_TABLES_DIR);
+    std::fs::create_dir_all(&backup_tables)?;
+    std::fs::copy(path, backup_tables.join(name))?;
+
+    // The temporary file stays out of raw_tables so a crash never leaves it to be loaded as a table
+    let temp_path = data_dir.join(format!(".{}.migrate", name));
+    let mut file = std::fs::File::create(&temp_path)?;
+    file.write_all(&upgraded)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+
+    Ok(TableFileState::Upgraded)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_structure::{COLUMN_TABLE_MAGIC, LEGACY_COLUMN_TABLE_MAGIC, METADATA_BINARY_SIZE};
+    use crate::utilities::{ez_hash, ksf};
+    use crate::auth::PasswordHash;
+    use ezcbor::cbor::Cbor;
+
+    #[test]
+    fn test_migrate_data_dir() {
+        let data_dir = PathBuf::from("test_files").join("migration_test");
+        let _ = std::fs::remove_dir_all(&data_dir);
+        std::fs::create_dir_all(data_dir.join(RAW_TABLES_DIR)).unwrap();
+
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b", "old_table", "alice").unwrap();
+        let binary = table.to_binary();
+        let header_end = 144 + table.header.len() * 72;
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(ksf(LEGACY_COLUMN_TABLE_MAGIC).raw());
+        legacy.extend_from_slice(&binary[64..header_end]);
+        legacy.extend_from_slice(&binary[header_end + METADATA_BINARY_SIZE..]);
+        std::fs::write(data_dir.join(RAW_TABLES_DIR).join("old_table"), &legacy).unwrap();
+        std::fs::write(data_dir.join(RAW_TABLES_DIR).join("new_table"), table.to_disk_binary().unwrap()).unwrap();
+        std::fs::write(data_dir.join(RAW_TABLES_DIR).join("garbage"), b"not a table").unwrap();
+
+        let mut old_user = User::new("dave", "pass");
+        old_user.password = PasswordHash::from_bytes(&ez_hash(b"pass")).unwrap();
+        let users = BTreeMap::from([(ksf("dave"), old_user), (ksf("admin"), User::admin("admin", "admin"))]);
+        std::fs::write(data_dir.join(USERS_FILE), users.to_cbor_bytes()).unwrap();
+
+        let dry = migrate_data_dir(&data_dir, true).unwrap();
+        assert_eq!(dry.upgraded, vec!["old_table".to_owned()]);
+        assert_eq!(std::fs::read(data_dir.join(RAW_TABLES_DIR).join("old_table")).unwrap(), legacy);
+
+        let report = migrate_data_dir(&data_dir, false).unwrap();
+        assert_eq!(report.upgraded, vec!["old_table".to_owned()]);
+        assert_eq!(report.current, 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.legacy_users, vec!["dave".to_owned()]);
+        let backup = report.backup_dir.clone().unwrap();
+        assert_eq!(std::fs::read(backup.join(RAW_TABLES_DIR).join("old_table")).unwrap(), legacy);
+        let migrated = std::fs::read(data_dir.join(RAW_TABLES_DIR).join("old_table")).unwrap();
+        // Written the way the server writes table files, checksums included
+        assert_eq!(table_format(&migrated[0..64]).unwrap().version, table_versions(&table, true).0);
+        assert_eq!(ColumnTable::from_binary(Some("old_table"), &migrated).unwrap(), table);
+
+        // Running it again finds nothing to do
+        let again = migrate_data_dir(&data_dir, false).unwrap();
+        assert!(again.upgraded.is_empty() && again.backup_dir.is_none());
+        assert_eq!(again.current, 2);
+
+        // Duration columns were once written under the version 1 magic, which older servers would misread
+        let timed = ColumnTable::from_csv_string("id,i-P;wait,d-N\n1;2s", "timed_table", "alice").unwrap();
+        let mut mislabeled = timed.to_binary();
+        mislabeled[0..64].copy_from_slice(ksf(COLUMN_TABLE_MAGIC).raw());
+        mislabeled.truncate(mislabeled.len() - 8);
+        std::fs::write(data_dir.join(RAW_TABLES_DIR).join("timed_table"), &mislabeled).unwrap();
+        let report = migrate_data_dir(&data_dir, false).unwrap();
+        assert_eq!(report.upgraded, vec!["timed_table".to_owned()]);
+        let migrated = std::fs::read(data_dir.join(RAW_TABLES_DIR).join("timed_table")).unwrap();
+        assert_eq!(table_format(&migrated[0..64]).unwrap().version, 4);
+        assert_eq!(ColumnTable::from_binary(Some("timed_table"), &migrated).unwrap(), timed);
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::db_structure::{ColumnTable, DbColumn};
+use crate::ezql::Query;
+use crate::database::Database;
+use crate::utilities::{ksf, u64_from_le_slice, ErrorTag, EzError, KeyString};
+use crate::paths::config_file;
+
+
+/// Tables without a namespace prefix belong to this namespace.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// The file in the config directory that holds the quotas.
+pub const QUOTAS_FILE: &str = ".quotas";
+
+/// The size of a single serialized quota. namespace, max_bytes, max_tables
+pub const QUOTA_BINARY_SIZE: usize = 64 + 8 + 8;
+
+/// The namespace of a table is everything before the first '.' in its name.
+/// "shop.products" is in the namespace "shop" and "products" is in the default namespace.
+pub fn namespace_of(table_name: &KeyString) -> KeyString {
+    match table_name.as_str().split_once('.') {
+        Some((namespace, _)) if !namespace.is_empty() => KeyString::from(namespace),
+        _ => ksf(DEFAULT_NAMESPACE),
+    }
+}
+
+/// Resource limits for a namespace. A limit of 0 means unlimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct NamespaceQuota {
+    pub max_bytes: u64,
+    pub max_tables: u64,
+}
+
+/// Running counters for a namespace. Row counts and bytes are not stored here since they
+/// are always computed from the buffer pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct NamespaceCounters {
+    pub queries: u64,
+    pub total_latency_micros: u64,
+}
+
+/// A point in time view of a namespace's resource usage.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct NamespaceUsage {
+    pub tables: u64,
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+/// Tracks per namespace query counts and latency and holds per namespace quotas.
+pub struct NamespaceRegistry {
+    counters: RwLock<BTreeMap<KeyString, NamespaceCounters>>,
+    quotas: RwLock<BTreeMap<KeyString, NamespaceQuota>>,
+    path: Option<PathBuf>,
+}
+
+impl Default for NamespaceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NamespaceRegistry {
+    /// A registry that keeps its quotas in memory only. Used for tests.
+    pub fn new() -> NamespaceRegistry {
+        NamespaceRegistry {
+            counters: RwLock::new(BTreeMap::new()),
+            quotas: RwLock::new(BTreeMap::new()),
+            path: None,
+        }
+    }
+
+    /// Loads the quotas from the given file. Counters always start at zero.
+    pub fn load(path: &Path) -> Result<NamespaceRegistry, EzError> {
+        println!("calling: NamespaceRegistry::load()");
+
+        let mut quotas = BTreeMap::new();
+        if path.exists() {
+            let binary = std::fs::read(path)?;
+            for chunk in binary.chunks(QUOTA_BINARY_SIZE) {
+                if chunk.len() != QUOTA_BINARY_SIZE {
+                    return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Quota file '{}' is corrupted", path.display())})
+                }
+                let namespace = KeyString::try_from(&chunk[0..64])?;
+                let max_bytes = u64_from_le_slice(&chunk[64..72]);
+                let max_tables = u64_from_le_slice(&chunk[72..80]);
+                quotas.insert(namespace, NamespaceQuota{max_bytes, max_tables});
+            }
+        }
+
+        Ok(NamespaceRegistry {
+            counters: RwLock::new(BTreeMap::new()),
+            quotas: RwLock::new(quotas),
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    pub fn default_path() -> PathBuf {
+        config_file(QUOTAS_FILE)
+    }
+
+    fn persist(&self) -> Result<(), EzError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(&self.to_binary())?;
+
+        Ok(())
+    }
+
+    /// [namespace: 64][max_bytes: u64][max_tables: u64] for each quota. The layout of the quota file.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut binary = Vec::new();
+        for (namespace, quota) in self.quotas.read().unwrap().iter() {
+            binary.extend_from_slice(namespace.raw());
+            binary.extend_from_slice(&quota.max_bytes.to_le_bytes());
+            binary.extend_from_slice(&quota.max_tables.to_le_bytes());
+        }
+        binary
+    }
+
+    pub fn set_quota(&self, namespace: KeyString, quota: NamespaceQuota) -> Result<(), EzError> {
+        if quota == NamespaceQuota::default() {
+            self.quotas.write().unwrap().remove(&namespace);
+        } else {
+            self.quotas.write().unwrap().insert(namespace, quota);
+        }
+        self.persist()
+    }
+
+    pub fn get_quota(&self, namespace: &KeyString) -> NamespaceQuota {
+        self.quotas.read().unwrap().get(namespace).copied().unwrap_or_default()
+    }
+
+    /// Records that a query touching the given namespace took `latency_micros` to answer.
+    pub fn record_query(&self, namespace: KeyString, latency_micros: u64) {
+        let mut counters = self.counters.write().unwrap();
+        let entry = counters.entry(namespace).or_default();
+        entry.queries += 1;
+        entry.total_latency_micros += latency_micros;
+    }
+
+    pub fn get_counters(&self, namespace: &KeyString) -> NamespaceCounters {
+        self.counters.read().unwrap().get(namespace).copied().unwrap_or_default()
+    }
+}
+
+/// Computes the current usage of every namespace that has at least one table.
+pub fn namespace_usage(database: &Database) -> BTreeMap<KeyString, NamespaceUsage> {
+
+    let mut usage: BTreeMap<KeyString, NamespaceUsage> = BTreeMap::new();
+    for (name, table) in database.buffer_pool.tables.read().unwrap().iter() {
+        let table = table.read().unwrap();
+        let entry = usage.entry(namespace_of(name)).or_default();
+        entry.tables += 1;
+        entry.rows += table.len() as u64;
+        entry.bytes += table.byte_size() as u64;
+    }
+
+    usage
+}
+
+/// Checks whether a CREATE, CREATE_FROM_SCHEMA or INSERT would push its namespace over quota. Other queries always pass.
+/// A schema only counts the tables it would create, added up per namespace.
+pub fn check_quota(query: &Query, database: &Database) -> Result<(), EzError> {
+
+    let (table_name, added_tables, added_bytes) = match query {
+        Query::CREATE { table, .. } => (table.name, 1, table.byte_size() as u64),
+        // The rows join a table that is already counted, so only their values add to it
+        Query::INSERT { table_name, inserts, .. } => (*table_name, 0, inserts.columns.values().map(|column| column.byte_size() as u64).sum()),
+        Query::CREATE_FROM_SCHEMA { tables } => {
+            let mut added: BTreeMap<KeyString, (u64, u64)> = BTreeMap::new();
+            for table in tables.iter().filter(|table| !database.buffer_pool.table_exists(&table.name)) {
+                let entry = added.entry(namespace_of(&table.name)).or_default();
+                entry.0 += 1;
+                entry.1 += table.byte_size() as u64;
+            }
+            for (namespace, (added_tables, added_bytes)) in added {
+                check_namespace_quota(namespace, added_tables, added_bytes, database)?;
+            }
+            return Ok(())
+        },
+        _ => return Ok(()),
+    };
+
+    check_namespace_quota(namespace_of(&table_name), added_tables, added_bytes, database)
+}
+
+fn check_namespace_quota(namespace: KeyString, added_tables: u64, added_bytes: u64, database: &Database) -> Result<(), EzError> {
+
+    let quota = database.namespaces.get_quota(&namespace);
+    if quota == NamespaceQuota::default() {
+        return Ok(())
+    }
+
+    let usage = namespace_usage(database).get(&namespace).copied().unwrap_or_default();
+    if quota.max_tables != 0 && usage.tables + added_tables > quota.max_tables {
+        return Err(EzError{tag: ErrorTag::NoMoreBufferSpace, text: format!("Namespace '{}' is limited to {} tables", namespace, quota.max_tables)})
+    }
+    if quota.max_bytes != 0 && usage.bytes + added_bytes > quota.max_bytes {
+        return Err(EzError{tag: ErrorTag::NoMoreBufferSpace, text: format!("Namespace '{}' is limited to {} bytes. Currently using {}", namespace, quota.max_bytes, usage.bytes)})
+    }
+
+    Ok(())
+}
+
+/// The ez_namespaces system table. One row per namespace with tables, counters, or a quota.
+pub fn namespaces_table(database: &Database) -> Result<ColumnTable, EzError> {
+
+    let usage = namespace_usage(database);
+    let mut namespaces: Vec<KeyString> = usage.keys().copied().collect();
+    namespaces.extend(database.namespaces.counters.read().unwrap().keys());
+    namespaces.extend(database.namespaces.quotas.read().unwrap().keys());
+    namespaces.sort();
+    namespaces.dedup();
+
+    let mut tables = Vec::new();
+    let mut rows = Vec::new();
+    let mut bytes = Vec::new();
+    let mut queries = Vec::new();
+    let mut mean_latency = Vec::new();
+    let mut max_bytes = Vec::new();
+    let mut max_tables = Vec::new();
+    for namespace in &namespaces {
+        let current = usage.get(namespace).copied().unwrap_or_default();
+        let counters = database.namespaces.get_counters(namespace);
+        let quota = database.namespaces.get_quota(namespace);
+        tables.push(current.tables as i32);
+        rows.push(current.rows as i32);
+        bytes.push(current.bytes as i32);
+        queries.push(counters.queries as i32);
+        mean_latency.push(match counters.queries {
+            0 => 0.0,
+            n => counters.total_latency_micros as f32 / n as f32,
+        });
+        max_bytes.push(quota.max_bytes as i32);
+        max_tables.push(quota.max_tables as i32);
+    }
+
+    let mut output = ColumnTable::create_empty("ez_namespaces", "system");
+    output.add_column(ksf("namespace"), DbColumn::Texts(namespaces))?;
+    output.add_column(ksf("tables"), DbColumn::Ints(tables))?;
+    output.add_column(ksf("rows"), DbColumn::Ints(rows))?;
+    output.add_column(ksf("bytes"), DbColumn::Ints(bytes))?;
+    output.add_column(ksf("queries"), DbColumn::Ints(queries))?;
+    output.add_column(ksf("mean_latency_micros"), DbColumn::Floats(mean_latency))?;
+    output.add_column(ksf("max_bytes"), DbColumn::Ints(max_bytes))?;
+    output.add_column(ksf("max_tables"), DbColumn::Ints(max_tables))?;
+
+    Ok(output)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::ezql::execute_EZQL_queries;
+    use crate::testing_tools::test_database;
+
+    use super::*;
+
+    #[test]
+    fn test_namespace_of() {
+        assert_eq!(namespace_of(&ksf("shop.products")), ksf("shop"));
+        assert_eq!(namespace_of(&ksf("shop.products.archive")), ksf("shop"));
+        assert_eq!(namespace_of(&ksf("products")), ksf(DEFAULT_NAMESPACE));
+        assert_eq!(namespace_of(&ksf(".hidden")), ksf(DEFAULT_NAMESPACE));
+    }
+
+    #[test]
+    fn test_namespace_counters() {
+        let registry = NamespaceRegistry::new();
+        registry.record_query(ksf("shop"), 100);
+        registry.record_query(ksf("shop"), 300);
+        let counters = registry.get_counters(&ksf("shop"));
+        assert_eq!(counters.queries, 2);
+        assert_eq!(counters.total_latency_micros, 400);
+        assert_eq!(registry.get_counters(&ksf("other")), NamespaceCounters::default());
+    }
+
+    #[test]
+    fn test_insert_over_quota_is_rejected() {
+        let database = Arc::new(test_database());
+        let insert = |id: i32| -> Query {
+            format!("INSERT(table_name: fixed_table, value_columns: (ints, floats, texts), new_values: (({}, 1.5, new)))", id).parse().unwrap()
+        };
+        let used = namespace_usage(&database)[&ksf(DEFAULT_NAMESPACE)].bytes;
+        // Room for one more row of an int, a float and a text but not two
+        database.namespaces.set_quota(ksf(DEFAULT_NAMESPACE), NamespaceQuota{max_bytes: used + 100, max_tables: 0}).unwrap();
+
+        execute_EZQL_queries(vec![insert(100)], database.clone()).unwrap();
+        assert_eq!(database.buffer_pool.table_rows(&ksf("fixed_table")), Some(11));
+
+        let e = execute_EZQL_queries(vec![insert(101)], database.clone()).unwrap_err();
+        assert_eq!(e.tag, ErrorTag::NoMoreBufferSpace);
+        assert!(e.text.contains("is limited to"));
+        assert_eq!(database.buffer_pool.table_rows(&ksf("fixed_table")), Some(11));
+
+        // Lifting the quota lets the same insert through
+        database.namespaces.set_quota(ksf(DEFAULT_NAMESPACE), NamespaceQuota::default()).unwrap();
+        execute_EZQL_queries(vec![insert(101)], database.clone()).unwrap();
+        assert_eq!(database.buffer_pool.table_rows(&ksf("fixed_table")), Some(12));
+    }
+}
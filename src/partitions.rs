@@ -0,0 +1,260 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::database::Database;
+use crate::db_structure::{ColumnTable, DbColumn};
+use crate::ezql::{write_to_table, KeyList, Query, RangeOrListOrAll};
+use crate::utilities::{ksf, u64_from_le_slice, ErrorTag, EzError, KeyString};
+
+
+/// The file in the config directory that holds the partition map of every partitioned table.
+pub const PARTITIONS_FILE: &str = ".partitions";
+/// Goes between the name of a partitioned table and the number of a partition in the names of its partitions.
+pub const PARTITION_SEPARATOR: char = '#';
+
+/// How a partitioned table is split by primary key. Partition i holds the keys from bound i-1 up to but not
+/// including bound i, so there is one more partition than there are bounds. Each partition is an ordinary
+/// table in the buffer pool, named by partition_name(), and is loaded, unloaded and written on its own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartitionMap {
+    pub bounds: KeyList,
+}
+
+impl PartitionMap {
+    /// The bounds have to be one or more keys in ascending order.
+    pub fn new(bounds: KeyList) -> Result<PartitionMap, EzError> {
+        let ascending = match &bounds {
+            KeyList::Ints(keys) => keys.windows(2).all(|pair| pair[0] < pair[1]),
+            KeyList::Texts(keys) => keys.windows(2).all(|pair| pair[0] < pair[1]),
+        };
+        if bounds.is_empty() || !ascending {
+            return Err(EzError{tag: ErrorTag::Query, text: "Partition bounds have to be one or more keys in ascending order".to_owned()})
+        }
+        Ok(PartitionMap{bounds})
+    }
+
+    /// How many partitions the table is split into.
+    pub fn count(&self) -> usize {
+        self.bounds.len() + 1
+    }
+
+    /// The partition a key written as text falls in. None if the table has int keys and the key is not an int.
+    fn partition_of(&self, key: &KeyString) -> Option<usize> {
+        match &self.bounds {
+            KeyList::Ints(bounds) => key.to_i32_checked().ok().map(|key| bounds.partition_point(|bound| *bound <= key)),
+            KeyList::Texts(bounds) => Some(bounds.partition_point(|bound| bound <= key)),
+        }
+    }
+
+    /// The partitions that can hold rows with the given primary keys, in ascending order.
+    pub fn partitions_for(&self, keys: &RangeOrListOrAll) -> Vec<usize> {
+        let all = (0..self.count()).collect();
+        match keys {
+            RangeOrListOrAll::All => all,
+            RangeOrListOrAll::Range(start, stop) => match (self.partition_of(start), self.partition_of(stop)) {
+                (Some(first), Some(last)) => (first..=last.max(first)).collect(),
+                _ => all,
+            },
+            // Keys that are not ints match nothing in an int keyed table so they touch no partition
+            RangeOrListOrAll::List(keys) => keys.iter().filter_map(|key| self.partition_of(key)).collect::<BTreeSet<_>>().into_iter().collect(),
+            RangeOrListOrAll::Keys(keys) => match (&self.bounds, keys) {
+                (KeyList::Ints(bounds), KeyList::Ints(keys)) => keys.iter().map(|key| bounds.partition_point(|bound| bound <= key)).collect::<BTreeSet<_>>().into_iter().collect(),
+                (KeyList::Texts(bounds), KeyList::Texts(keys)) => keys.iter().map(|key| bounds.partition_point(|bound| bound <= key)).collect::<BTreeSet<_>>().into_iter().collect(),
+                // Fails the same way in every partition
+                _ => all,
+            },
+        }
+    }
+
+    /// Splits a table sorted by primary key into one table per partition, named after the partitions of the
+    /// table. Partitions without rows come out empty rather than being left out.
+    pub fn split(&self, table: &ColumnTable) -> Result<Vec<ColumnTable>, EzError> {
+        let mut cuts: Vec<usize> = match (&self.bounds, &table.columns[&table.get_primary_key_col_index()]) {
+            (KeyList::Ints(bounds), DbColumn::Ints(keys)) => bounds.iter().map(|bound| keys.partition_point(|key| key < bound)).collect(),
+            (KeyList::Texts(bounds), DbColumn::Texts(keys)) => bounds.iter().map(|bound| keys.partition_point(|key| key < bound)).collect(),
+            _ => return Err(EzError{tag: ErrorTag::Structure, text: format!("The partition bounds of '{}' are not of the type of its primary key", table.name)}),
+        };
+        cuts.push(table.len());
+
+        let mut parts = Vec::with_capacity(cuts.len());
+        let mut start = 0;
+        for (index, stop) in cuts.into_iter().enumerate() {
+            let mut part = table.create_subtable_from_index_range(start, stop);
+            part.name = partition_name(&table.name, index)?;
+            part.metadata = table.metadata.clone();
+            parts.push(part);
+            start = stop;
+        }
+        Ok(parts)
+    }
+
+    /// The partitions a query reads or writes. Only SELECT, UPDATE, DELETE and INSERT can be narrowed down.
+    pub fn touched_by(&self, query: &Query) -> Vec<usize> {
+        match query {
+            Query::SELECT { primary_keys, .. } | Query::UPDATE { primary_keys, .. } | Query::DELETE { primary_keys, .. } => self.partitions_for(primary_keys),
+            Query::INSERT { inserts, .. } => match KeyList::from_column(inserts.columns[&inserts.get_primary_key_col_index()].clone()) {
+                Ok(keys) => self.partitions_for(&RangeOrListOrAll::Keys(keys)),
+                Err(_) => (0..self.count()).collect(),
+            },
+            _ => (0..self.count()).collect(),
+        }
+    }
+
+    /// The lowest key of each partition as text. Empty for the first partition, which has no lower bound.
+    pub fn lower_bounds(&self) -> Vec<KeyString> {
+        let mut lower = vec![KeyString::new()];
+        match &self.bounds {
+            KeyList::Ints(bounds) => lower.extend(bounds.iter().map(|bound| KeyString::from(bound.to_string().as_str()))),
+            KeyList::Texts(bounds) => lower.extend(bounds.iter().copied()),
+        }
+        lower
+    }
+}
+
+/// The name of partition `index` of a table. Fails if the table name is too long to add the partition number to.
+pub fn partition_name(table_name: &KeyString, index: usize) -> Result<KeyString, EzError> {
+    KeyString::from_str_checked(&format!("{}{}{}", table_name, PARTITION_SEPARATOR, index))
+}
+
+/// The names of the partitions of a table, in partition order.
+pub fn partition_names(table_name: &KeyString, partitions: &[usize]) -> Result<Vec<KeyString>, EzError> {
+    partitions.iter().map(|index| partition_name(table_name, *index)).collect()
+}
+
+/// The table a partition belongs to. None if the name is not that of a partition of one of the partitioned tables.
+pub fn partitioned_table_of(name: &KeyString, partitioned: &BTreeMap<KeyString, PartitionMap>) -> Option<KeyString> {
+    let (table_name, index) = name.as_str().rsplit_once(PARTITION_SEPARATOR)?;
+    let table_name = KeyString::from(table_name);
+    match partitioned.contains_key(&table_name) && index.parse::<usize>().is_ok() {
+        true => Some(table_name),
+        false => None,
+    }
+}
+
+/// [tables: u64]{[table name: 64][key list length: u64][key list]}. See KeyList::write_binary().
+pub fn partitions_to_binary(partitions: &BTreeMap<KeyString, PartitionMap>) -> Vec<u8> {
+    let mut binary = Vec::new();
+    binary.extend_from_slice(&(partitions.len() as u64).to_le_bytes());
+    for (table_name, map) in partitions {
+        let mut bounds = Vec::new();
+        map.bounds.write_binary(&mut bounds);
+        binary.extend_from_slice(table_name.raw());
+        binary.extend_from_slice(&(bounds.len() as u64).to_le_bytes());
+        binary.extend_from_slice(&bounds);
+    }
+    binary
+}
+
+/// An empty binary is no partitions, so a missing partitions file can be read as an empty one.
+pub fn partitions_from_binary(binary: &[u8]) -> Result<BTreeMap<KeyString, PartitionMap>, EzError> {
+    let corrupt = || EzError{tag: ErrorTag::Deserialization, text: "The partitions file is corrupt".to_owned()};
+    let mut partitions = BTreeMap::new();
+    if binary.is_empty() {
+        return Ok(partitions)
+    }
+    if binary.len() < 8 {
+        return Err(corrupt())
+    }
+    let count = u64_from_le_slice(&binary[0..8]);
+    let mut pointer = 8;
+    for _ in 0..count {
+        if binary.len() < pointer + 72 {
+            return Err(corrupt())
+        }
+        let table_name = KeyString::try_from(&binary[pointer..pointer + 64])?;
+        let len = u64_from_le_slice(&binary[pointer + 64..pointer + 72]) as usize;
+        pointer += 72;
+        let bounds = binary.get(pointer..pointer.saturating_add(len)).ok_or_else(corrupt)?;
+        partitions.insert(table_name, PartitionMap::new(KeyList::from_binary(bounds)?)?);
+        pointer += len;
+    }
+    if pointer != binary.len() {
+        return Err(corrupt())
+    }
+    Ok(partitions)
+}
+
+/// Runs a write query on a partitioned table by running it on each partition it touches. Inserted rows are
+/// split between the partitions their keys fall in. Each partition is written on its own, so a failure in
+/// one partition leaves the partitions before it written. Returns the rows affected across all partitions.
+pub fn write_to_partitions(query: Query, map: &PartitionMap, database: &Database) -> Result<u64, EzError> {
+    let table_name = query.get_table_name();
+    let mut affected = 0;
+    match query {
+        Query::INSERT { mut inserts, on_conflict, .. } => {
+            inserts.name = table_name;
+            inserts.sort();
+            for part in map.split(&inserts)?.into_iter().filter(|part| part.len() > 0) {
+                affected += write_to_table(Query::INSERT{table_name: part.name, inserts: part, on_conflict}, database)?;
+            }
+        },
+        Query::UPDATE { .. } | Query::DELETE { .. } | Query::DEDUPLICATE { .. } | Query::ALTER_TABLE { .. } => {
+            for partition in partition_names(&table_name, &map.touched_by(&query))? {
+                affected += write_to_table(retarget(&query, partition), database)?;
+            }
+        },
+        other => return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' can't run on the partitioned table '{}'", other.type_name(), table_name)}),
+    }
+    Ok(affected)
+}
+
+/// The query with its table replaced by one partition of it.
+fn retarget(query: &Query, partition: KeyString) -> Query {
+    let mut query = query.clone();
+    match &mut query {
+        Query::SELECT { table_name, .. } | Query::UPDATE { table_name, .. } | Query::DELETE { table_name, .. } | Query::INSERT { table_name, .. }
+            | Query::SUMMARY { table_name, .. } | Query::DEDUPLICATE { table_name } | Query::ALTER_TABLE { table_name, .. } => *table_name = partition,
+        _ => (),
+    }
+    query
+}
+
+/// The answer to the PARTITION_TABLE admin command. One row per partition with its name, the lowest key it
+/// holds and its row count.
+pub fn partitions_table(table_name: &KeyString, map: &PartitionMap, rows: &[usize]) -> Result<ColumnTable, EzError> {
+    let mut table = ColumnTable::create_empty("ez_partitions", "system");
+    table.add_column(ksf("partition"), DbColumn::Texts(partition_names(table_name, &(0..map.count()).collect::<Vec<_>>())?))?;
+    table.add_column(ksf("from_key"), DbColumn::Texts(map.lower_bounds()))?;
+    table.add_column(ksf("rows"), DbColumn::Ints(rows.iter().map(|rows| *rows as i32).collect()))?;
+    Ok(table)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_routing() {
+        let map = PartitionMap::new(KeyList::Ints(vec![10, 20])).unwrap();
+        assert_eq!(map.count(), 3);
+        assert_eq!(map.partitions_for(&RangeOrListOrAll::All), vec![0, 1, 2]);
+        assert_eq!(map.partitions_for(&RangeOrListOrAll::Range(ksf("12"), ksf("15"))), vec![1]);
+        assert_eq!(map.partitions_for(&RangeOrListOrAll::Range(ksf("5"), ksf("25"))), vec![0, 1, 2]);
+        assert_eq!(map.partitions_for(&RangeOrListOrAll::List(vec![ksf("25"), ksf("3"), ksf("x"), ksf("4")])), vec![0, 2]);
+        assert_eq!(map.partitions_for(&RangeOrListOrAll::Keys(KeyList::Ints(vec![10]))), vec![1]);
+        assert!(PartitionMap::new(KeyList::Ints(vec![20, 10])).is_err());
+        assert!(PartitionMap::new(KeyList::Ints(Vec::new())).is_err());
+
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n10;b\n15;c\n30;d", "orders", "test").unwrap();
+        let parts = map.split(&table).unwrap();
+        assert_eq!(parts.iter().map(|part| part.len()).collect::<Vec<_>>(), vec![1, 2, 1]);
+        assert_eq!(parts[1].name, ksf("orders#1"));
+        assert_eq!(parts[1].get_column_int(&ksf("id")).unwrap(), &vec![10, 15]);
+        assert!(PartitionMap::new(KeyList::Texts(vec![ksf("m")])).unwrap().split(&table).is_err());
+
+        let update: Query = "UPDATE(table_name: orders, primary_keys: (15, 16), updates: ((name = x)))".parse().unwrap();
+        assert_eq!(map.touched_by(&update), vec![1]);
+
+        let mut all = BTreeMap::new();
+        all.insert(ksf("orders"), map);
+        all.insert(ksf("people"), PartitionMap::new(KeyList::Texts(vec![ksf("h"), ksf("p")])).unwrap());
+        assert_eq!(partitions_from_binary(&partitions_to_binary(&all)).unwrap(), all);
+        assert!(partitions_from_binary(&[]).unwrap().is_empty());
+        assert!(partitions_from_binary(&partitions_to_binary(&all)[..40]).is_err());
+
+        assert_eq!(partitioned_table_of(&ksf("orders#1"), &all), Some(ksf("orders")));
+        assert_eq!(partitioned_table_of(&ksf("orders"), &all), None);
+        assert_eq!(partitioned_table_of(&ksf("orders#x"), &all), None);
+        assert_eq!(partitioned_table_of(&ksf("invoices#1"), &all), None);
+    }
+}
@@ -0,0 +1,175 @@
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use std::path::Component;
+
+use crate::utilities::{ErrorTag, EzError};
+
+
+/// Everything the server stores lives under this directory, relative to the working directory, unless
+/// data_dir is set. See config.rs
+pub const CONFIG_DIR: &str = "EZconfig";
+pub const RAW_TABLES_DIR: &str = "raw_tables";
+pub const RAW_VALUES_DIR: &str = "raw_values";
+pub const LOG_DIR: &str = "log";
+pub const TEST_FILES_DIR: &str = "test_files";
+pub const SORT_SPILL_DIR: &str = "sort_spill";
+pub const TABLE_CHUNKS_DIR: &str = "table_chunks";
+pub const TRASH_DIR: &str = "trash";
+pub const KEY_FILTERS_DIR: &str = "key_filters";
+
+/// The layout of the data directory. All paths are built with PathBuf::join so the separator is always
+/// the right one for the platform.
+///     EZconfig/
+///         raw_tables/<table name>
+///         table_chunks/<table name>/<chunk>.<generation>
+///         key_filters/<table name>
+///         trash/<table name>/table, chunks/, dropped_at
+///         raw_values/<key>
+///         log/<timestamp>
+///         sort_spill/<run>
+///         .users .tasks .quotas ...
+pub fn config_dir() -> PathBuf {
+    DATA_DIR.read().unwrap().clone().unwrap_or_else(|| PathBuf::from(CONFIG_DIR))
+}
+
+static DATA_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Moves the data directory. Only meant to be called before the database is initialized.
+pub fn set_data_dir(path: PathBuf) {
+    *DATA_DIR.write().unwrap() = Some(path);
+}
+
+pub fn config_file(name: &str) -> PathBuf {
+    config_dir().join(name)
+}
+
+pub fn raw_tables_dir() -> PathBuf {
+    config_dir().join(RAW_TABLES_DIR)
+}
+
+pub fn raw_values_dir() -> PathBuf {
+    config_dir().join(RAW_VALUES_DIR)
+}
+
+pub fn log_dir() -> PathBuf {
+    config_dir().join(LOG_DIR)
+}
+
+/// Temporary sorted runs of results too big to sort in memory. See external_sort.rs
+pub fn sort_spill_dir() -> PathBuf {
+    config_dir().join(SORT_SPILL_DIR)
+}
+
+/// Table names and KV keys become file names under the data directory, and PathBuf::join replaces the base
+/// with an absolute path and follows '..', so a name has to be a single plain file name.
+pub fn validate_name(name: &str) -> Result<(), EzError> {
+    let mut components = Path::new(name).components();
+    let plain = matches!((components.next(), components.next()), (Some(Component::Normal(_)), None));
+    if name.is_empty() || name.contains(['/', '\\', '\0']) || !plain {
+        return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' can't be used as a name. Names can't be empty, '.' or '..' or contain path separators", name)})
+    }
+    Ok(())
+}
+
+/// Where a table is written to disk.
+pub fn table_file(table_name: &str) -> PathBuf {
+    raw_tables_dir().join(table_name)
+}
+
+/// Where the chunks of a table stored in chunks live. The file in raw_tables is then only a manifest.
+pub fn table_chunks_dir(table_name: &str) -> PathBuf {
+    config_dir().join(TABLE_CHUNKS_DIR).join(table_name)
+}
+
+pub fn table_chunk_file(table_name: &str, index: usize, generation: u64) -> PathBuf {
+    chunk_file(&table_chunks_dir(table_name), index, generation)
+}
+
+/// A chunk in any directory of chunks, such as the one a dropped table keeps in the trash.
+pub fn chunk_file(chunks_dir: &Path, index: usize, generation: u64) -> PathBuf {
+    chunks_dir.join(format!("{}.{}", index, generation))
+}
+
+pub fn key_filters_dir() -> PathBuf {
+    config_dir().join(KEY_FILTERS_DIR)
+}
+
+/// Where the bloom filter over the primary keys of a table is written. See KeyFilter.
+pub fn key_filter_file(table_name: &str) -> PathBuf {
+    key_filters_dir().join(table_name)
+}
+
+/// Where dropped tables wait until they are restored or purged. See trash.rs
+pub fn trash_dir() -> PathBuf {
+    config_dir().join(TRASH_DIR)
+}
+
+pub fn trashed_table_dir(table_name: &str) -> PathBuf {
+    trash_dir().join(table_name)
+}
+
+/// Where a key value pair is written to disk.
+pub fn value_file(key: &str) -> PathBuf {
+    raw_values_dir().join(key)
+}
+
+/// Where the server writes its pid when started with --daemon, unless given --pid-file=.
+pub fn pid_file() -> PathBuf {
+    config_file("ezdb.pid")
+}
+
+/// Where a daemonized server sends everything it prints, unless given --log-file=.
+pub fn server_log_file() -> PathBuf {
+    config_file("ezdb.log")
+}
+
+pub fn log_file(timestamp: &str) -> PathBuf {
+    log_dir().join(timestamp)
+}
+
+/// Path of a file in the test_files directory. Only meant for tests and benchmarks.
+pub fn test_file(name: &str) -> PathBuf {
+    PathBuf::from(TEST_FILES_DIR).join(name)
+}
+
+/// Creates the data directory and its subdirectories if they are missing.
+pub fn create_data_dirs() -> Result<(), EzError> {
+    for dir in [raw_tables_dir(), raw_values_dir(), log_dir(), key_filters_dir()] {
+        std::fs::create_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// Lossy conversion for places that need a &str, such as error messages and the KeyString based APIs.
+pub fn path_to_string(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        for name in ["products", "user:1", "orders#0", "a.b", "..."] {
+            assert!(validate_name(name).is_ok(), "{name}");
+        }
+        for name in ["", ".", "..", "../users", "/etc/passwd", "a/b", "a\\b", "C:\\tables", "a\0b"] {
+            assert!(validate_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        let table = table_file("products");
+        assert!(table.starts_with(config_dir()));
+        assert_eq!(table.file_name().unwrap(), "products");
+        assert_eq!(table.parent().unwrap().file_name().unwrap(), RAW_TABLES_DIR);
+        assert_eq!(
+            path_to_string(&value_file("core1")),
+            format!("{CONFIG_DIR}{}{RAW_VALUES_DIR}{}core1", std::path::MAIN_SEPARATOR, std::path::MAIN_SEPARATOR)
+        );
+    }
+}
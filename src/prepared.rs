@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+
+use crate::db_structure::{format_duration, ColumnTable, DbColumn, DbValue};
+use crate::ezql::{OpOrCond, Query, RangeOrListOrAll};
+use crate::utilities::{ksf, ErrorTag, EzError, KeyString};
+
+
+/// A connection can't keep more prepared queries than this at once.
+pub const MAX_PREPARED_PER_CONNECTION: usize = 256;
+
+struct Prepared {
+    query: Query,
+    parameters: usize,
+}
+
+/// Queries that were sent once with PREPARE and are run again with EXECUTE and new parameter values, so hot
+/// queries don't have to be rebuilt and resent. Like cursors, the prepared queries of a connection live in its
+/// Session and go away with it.
+///
+/// Parameters are written $1, $2, ... in place of a primary key or the value of a condition or an update.
+/// EXECUTE must give a value for every parameter up to the highest one used.
+#[derive(Default)]
+pub struct PreparedQueries {
+    next_handle: u64,
+    queries: BTreeMap<u64, Prepared>,
+}
+
+impl PreparedQueries {
+    pub fn new() -> PreparedQueries {
+        PreparedQueries::default()
+    }
+
+    /// Registers the query. Returns its handle and how many parameters it takes.
+    pub fn prepare(&mut self, query: Query) -> Result<(u64, usize), EzError> {
+        let parameters = parameter_count(&query)?;
+        if self.queries.len() >= MAX_PREPARED_PER_CONNECTION {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("A connection can have at most {} prepared queries", MAX_PREPARED_PER_CONNECTION)})
+        }
+        self.next_handle += 1;
+        self.queries.insert(self.next_handle, Prepared{query, parameters});
+        Ok((self.next_handle, parameters))
+    }
+
+    /// The prepared query with the parameters filled in.
+    pub fn bind(&self, handle: u64, params: &[DbValue]) -> Result<Query, EzError> {
+        let prepared = match self.queries.get(&handle) {
+            Some(prepared) => prepared,
+            None => return Err(EzError{tag: ErrorTag::Query, text: format!("No prepared query with handle {}", handle)}),
+        };
+        if params.len() != prepared.parameters {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("Prepared query {} takes {} parameters but {} were given", handle, prepared.parameters, params.len())})
+        }
+        bind_parameters(&prepared.query, params)
+    }
+
+    /// Replaces every EXECUTE in the batch with the query it runs. Other queries are left alone.
+    pub fn bind_batch(&self, queries: Vec<Query>) -> Result<Vec<Query>, EzError> {
+        queries.into_iter().map(|query| match query {
+            Query::EXECUTE { handle, params } => self.bind(handle, &params),
+            other => Ok(other),
+        }).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queries.is_empty()
+    }
+}
+
+/// The answer to a batch of PREPARE queries. One row per query in the order they were sent.
+pub fn handles_to_table(handles: &[(u64, usize)]) -> Result<ColumnTable, EzError> {
+    let mut table = ColumnTable::create_empty("prepared", "system");
+    table.add_column(ksf("handle"), DbColumn::Ints(handles.iter().map(|(handle, _)| *handle as i32).collect()))?;
+    table.add_column(ksf("parameters"), DbColumn::Ints(handles.iter().map(|(_, parameters)| *parameters as i32).collect()))?;
+    Ok(table)
+}
+
+/// The parameter number of a $N placeholder. Numbering starts at 1.
+fn placeholder(s: &str) -> Option<usize> {
+    match s.strip_prefix('$')?.parse::<usize>() {
+        Ok(0) | Err(_) => None,
+        Ok(n) => Some(n),
+    }
+}
+
+/// A place in a query that a parameter can fill.
+enum Slot<'a> {
+    Key(&'a mut KeyString),
+    Value(&'a mut DbValue),
+}
+
+fn visit_slots(query: &mut Query, visit: &mut dyn FnMut(Slot) -> Result<(), EzError>) -> Result<(), EzError> {
+    let (primary_keys, conditions, updates) = match query {
+        Query::SELECT { primary_keys, conditions, .. } => (Some(primary_keys), Some(conditions), None),
+        Query::DELETE { primary_keys, conditions, .. } => (Some(primary_keys), Some(conditions), None),
+        Query::UPDATE { primary_keys, conditions, updates, .. } => (Some(primary_keys), Some(conditions), Some(updates)),
+        Query::LEFT_JOIN { primary_keys, .. } => (Some(primary_keys), None, None),
+        _ => (None, None, None),
+    };
+
+    match primary_keys {
+        Some(RangeOrListOrAll::Range(start, stop)) => {
+            visit(Slot::Key(start))?;
+            visit(Slot::Key(stop))?;
+        },
+        Some(RangeOrListOrAll::List(keys)) => {
+            for key in keys {
+                visit(Slot::Key(key))?;
+            }
+        },
+        // Typed keys are sent as they are. There is nothing in them to substitute
+        Some(RangeOrListOrAll::Keys(_)) | Some(RangeOrListOrAll::All) | None => (),
+    }
+    for condition in conditions.into_iter().flatten() {
+        if let OpOrCond::Cond(condition) = condition {
+            visit(Slot::Value(&mut condition.value))?;
+        }
+    }
+    for update in updates.into_iter().flatten() {
+        visit(Slot::Value(&mut update.value))?;
+    }
+    Ok(())
+}
+
+/// The highest parameter number in the query. Queries can't be prepared inside PREPARE or EXECUTE.
+pub fn parameter_count(query: &Query) -> Result<usize, EzError> {
+    if matches!(query, Query::PREPARE{..} | Query::EXECUTE{..}) {
+        return Err(EzError{tag: ErrorTag::Query, text: "PREPARE and EXECUTE can't be prepared".to_owned()})
+    }
+    let mut count = 0;
+    visit_slots(&mut query.clone(), &mut |slot| {
+        let n = match slot {
+            Slot::Key(key) => placeholder(key.as_str()),
+            Slot::Value(DbValue::Text(text)) => placeholder(text.as_str()),
+            Slot::Value(_) => None,
+        };
+        count = count.max(n.unwrap_or(0));
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+/// Fills every $N placeholder with params[N-1]. Primary keys take the text form of int and text parameters.
+pub fn bind_parameters(query: &Query, params: &[DbValue]) -> Result<Query, EzError> {
+    let param = |n: usize| match params.get(n - 1) {
+        Some(param) => Ok(param),
+        None => Err(EzError{tag: ErrorTag::Query, text: format!("No value was given for parameter ${}", n)}),
+    };
+
+    let mut bound = query.clone();
+    visit_slots(&mut bound, &mut |slot| {
+        match slot {
+            Slot::Key(key) => if let Some(n) = placeholder(key.as_str()) {
+                *key = match param(n)? {
+                    DbValue::Int(x) => ksf(&x.to_string()),
+                    DbValue::Text(x) => *x,
+                    DbValue::Float(_) => return Err(EzError{tag: ErrorTag::Query, text: format!("Parameter ${} is a float which can't be a primary key", n)}),
+                    DbValue::Duration(x) => return Err(EzError{tag: ErrorTag::Query, text: format!("Parameter ${} is the duration {} which can't be a primary key", n, format_duration(*x))}),
+                };
+            },
+            Slot::Value(value) => {
+                let n = match value {
+                    DbValue::Text(text) => placeholder(text.as_str()),
+                    _ => None,
+                };
+                if let Some(n) = n {
+                    *value = param(n)?.clone();
+                }
+            },
+        }
+        Ok(())
+    })?;
+    Ok(bound)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::ezql::TestOp;
+
+    use super::*;
+
+    #[test]
+    fn test_prepared_queries() {
+        let query: Query = "UPDATE(table_name: products, primary_keys: ($1, 7), conditions: ((price greater-than $2)), updates: ((stock -= $3)))".parse().unwrap();
+        assert_eq!(parameter_count(&query).unwrap(), 3);
+
+        let mut prepared = PreparedQueries::new();
+        let (handle, parameters) = prepared.prepare(query).unwrap();
+        assert_eq!(parameters, 3);
+
+        let bound = prepared.bind(handle, &[DbValue::Int(5), DbValue::Float(2.5), DbValue::Int(10)]).unwrap();
+        let expected: Query = "UPDATE(table_name: products, primary_keys: (5, 7), conditions: ((price greater-than 2.5)), updates: ((stock -= 10)))".parse().unwrap();
+        assert_eq!(bound, expected);
+
+        // Wrong number of parameters, an unknown handle, a float key
+        assert!(prepared.bind(handle, &[DbValue::Int(5)]).is_err());
+        assert!(prepared.bind(handle + 1, &[DbValue::Int(5), DbValue::Int(1), DbValue::Int(1)]).is_err());
+        assert!(prepared.bind(handle, &[DbValue::Float(5.0), DbValue::Int(1), DbValue::Int(1)]).is_err());
+
+        let batch = vec![Query::EXECUTE { handle, params: vec![DbValue::Int(1), DbValue::Int(2), DbValue::Int(3)] }, Query::new_select("products")];
+        let batch = prepared.bind_batch(batch).unwrap();
+        assert!(matches!(&batch[0], Query::UPDATE { conditions, .. } if conditions[0] == OpOrCond::Cond(crate::ezql::Condition::new("price", TestOp::Greater, 2).unwrap())));
+        assert_eq!(batch[1], Query::new_select("products"));
+
+        let execute: Query = "EXECUTE(handle: 3, params: (5, 2.5, abc))".parse().unwrap();
+        assert_eq!(execute, Query::EXECUTE { handle: 3, params: vec![DbValue::Int(5), DbValue::Float(2.5), DbValue::Text(ksf("abc"))] });
+        assert_eq!(Query::from_binary(&execute.to_binary()).unwrap(), execute);
+        let prepare: Query = "PREPARE(query: \"DELETE(table_name: products, primary_keys: $1..$2)\")".parse().unwrap();
+        assert_eq!(Query::from_binary(&prepare.to_binary()).unwrap(), prepare);
+
+        assert_eq!(prepared.len(), 1);
+        assert!(prepared.prepare(Query::PREPARE { query: Box::new(Query::new_select("products")) }).is_err());
+    }
+}
@@ -0,0 +1,622 @@
+use crate::db_structure::{ColumnTable, Value};
+use crate::ezql::{parse_kv_queries_from_binary, parse_queries_from_binary, queries_to_binary, KvQuery, Query};
+use crate::frame_checksum::FrameChecksum;
+use crate::snapshot::is_read_only_batch;
+use crate::utilities::{bytes_to_str, ksf, u64_from_le_slice, ErrorTag, EzError, KeyString};
+
+
+/// The usernames and passwords in the first frame are each padded to this many bytes.
+pub const CREDENTIAL_FIELD_LEN: usize = 512;
+
+/// The answer to requests that succeed without anything to return.
+pub const ACK: &[u8] = b"None.";
+
+/// Error responses start with this tag, padded to 64 bytes, followed by the binary EzError.
+pub const ERROR_RESPONSE: &str = "EZDB_ERROR";
+
+/// Admin commands that only report on the server. Every other admin command changes something.
+pub const READ_ONLY_ADMIN_COMMANDS: [&str; 5] = ["TASK_LIST", "ALLOC_STATS", "METRICS", "CONFIG", "TRASH_LIST"];
+
+/// The first frame a client sends. See EZNP_ez_networking_protocol.txt.
+/// [username: 512 bytes][password: 512 bytes][checksum: u8, only when one is asked for]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    pub checksum: FrameChecksum,
+}
+
+impl Credentials {
+    pub fn encode(&self) -> Result<Vec<u8>, EzError> {
+        if self.username.len() > CREDENTIAL_FIELD_LEN || self.password.len() > CREDENTIAL_FIELD_LEN {
+            return Err(EzError{ tag: ErrorTag::Authentication, text: "Username and password must each be less than 512 bytes".to_owned()})
+        }
+        let mut binary = vec![0u8; 2*CREDENTIAL_FIELD_LEN];
+        binary[0..self.username.len()].copy_from_slice(self.username.as_bytes());
+        binary[CREDENTIAL_FIELD_LEN..CREDENTIAL_FIELD_LEN + self.password.len()].copy_from_slice(self.password.as_bytes());
+        // The extra byte is only sent when asked for so servers that predate checksums still accept the frame
+        if self.checksum != FrameChecksum::None {
+            binary.push(self.checksum.to_byte());
+        }
+        Ok(binary)
+    }
+
+    pub fn decode(binary: &[u8]) -> Result<Credentials, EzError> {
+        if binary.len() < 2*CREDENTIAL_FIELD_LEN {
+            return Err(EzError{tag: ErrorTag::Authentication, text: format!("Credentials are {} bytes. Got {}", 2*CREDENTIAL_FIELD_LEN, binary.len())})
+        }
+        let field = |bytes: &[u8]| match bytes_to_str(bytes) {
+            Ok(s) => Ok(s.to_owned()),
+            Err(e) => Err(EzError{tag: ErrorTag::Utf8, text: e.to_string()}),
+        };
+        let checksum = match binary.get(2*CREDENTIAL_FIELD_LEN) {
+            Some(byte) => FrameChecksum::from_byte(*byte)?,
+            None => FrameChecksum::None,
+        };
+        Ok(Credentials {
+            username: field(&binary[0..CREDENTIAL_FIELD_LEN])?,
+            password: field(&binary[CREDENTIAL_FIELD_LEN..2*CREDENTIAL_FIELD_LEN])?,
+            checksum,
+        })
+    }
+}
+
+/// Every message a client can send after authenticating. A message is [type tag: 64 bytes][body].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    /// Answered even while the server is recovering.
+    Health,
+    /// [queries]
+    Query(Vec<Query>),
+    /// [tag: 64 bytes][queries]
+    TaggedQuery{tag: KeyString, queries: Vec<Query>},
+    /// [write version: u64][queries]. Answered like Query once the server has applied the version. See write_versions.rs
+    AtVersion{version: u64, queries: Vec<Query>},
+    /// [tag: 64 bytes]
+    Tag(KeyString),
+    /// [command: 64 bytes][arguments]. The layout of the arguments depends on the command.
+    Admin{command: KeyString, args: Vec<u8>},
+    /// [kv queries]
+    KvQuery(Vec<KvQuery>),
+    /// [kv queries]
+    KvBatch(Vec<KvQuery>),
+    /// [table name: 64 bytes][EZ binary table]
+    BulkLoad{table_name: KeyString, table: Vec<u8>},
+    /// [content]
+    BlobPut(Vec<u8>),
+    /// [blob reference: 64 bytes]
+    BlobGet(KeyString),
+    /// [queries]
+    OpenCursor(Vec<Query>),
+    /// [cursor id: u64][max rows: u64]
+    FetchPage{cursor_id: u64, max_rows: u64},
+    /// [cursor id: u64]
+    CloseCursor{cursor_id: u64},
+}
+
+impl Request {
+    pub fn type_tag(&self) -> &'static str {
+        match self {
+            Request::Health => "HEALTH",
+            Request::Query(_) => "QUERY",
+            Request::TaggedQuery{..} => "TAGGEDQUERY",
+            Request::AtVersion{..} => "ATVERSION",
+            Request::Tag(_) => "TAG",
+            Request::Admin{..} => "ADMIN",
+            Request::KvQuery(_) => "KVQUERY",
+            Request::KvBatch(_) => "KVBATCH",
+            Request::BulkLoad{..} => "BULKLOAD",
+            Request::BlobPut(_) => "BLOBPUT",
+            Request::BlobGet(_) => "BLOBGET",
+            Request::OpenCursor(_) => "CURSOR",
+            Request::FetchPage{..} => "FETCHPAGE",
+            Request::CloseCursor{..} => "CLOSECURSOR",
+        }
+    }
+
+    /// Whether answering the request leaves the tables, values, users and settings as they were.
+    /// Only these are answered while the server is recovering. See AdmissionController::admit().
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Request::Health | Request::Tag(_) | Request::BlobGet(_) | Request::FetchPage{..} | Request::CloseCursor{..} => true,
+            Request::Query(queries) | Request::TaggedQuery{queries, ..} | Request::AtVersion{queries, ..} | Request::OpenCursor(queries) => is_read_only_batch(queries),
+            Request::KvQuery(queries) => queries.iter().all(|query| matches!(query,
+                KvQuery::Read(_) | KvQuery::ReadVersion(..) | KvQuery::ReadMany(_) | KvQuery::Scan(..)
+            )),
+            Request::Admin{command, ..} => READ_ONLY_ADMIN_COMMANDS.contains(&command.as_str()),
+            Request::KvBatch(_) | Request::BulkLoad{..} | Request::BlobPut(_) => false,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut binary = Vec::with_capacity(128);
+        binary.extend_from_slice(ksf(self.type_tag()).raw());
+        match self {
+            Request::Health => (),
+            Request::Query(queries) | Request::OpenCursor(queries) => binary.extend_from_slice(&queries_to_binary(queries)),
+            Request::TaggedQuery{tag, queries} => {
+                binary.extend_from_slice(tag.raw());
+                binary.extend_from_slice(&queries_to_binary(queries));
+            },
+            Request::AtVersion{version, queries} => {
+                binary.extend_from_slice(&version.to_le_bytes());
+                binary.extend_from_slice(&queries_to_binary(queries));
+            },
+            Request::Tag(tag) => binary.extend_from_slice(tag.raw()),
+            Request::Admin{command, args} => {
+                binary.extend_from_slice(command.raw());
+                binary.extend_from_slice(args);
+            },
+            Request::KvQuery(queries) | Request::KvBatch(queries) => {
+                for query in queries {
+                    binary.extend_from_slice(&query.to_binary());
+                }
+            },
+            Request::BulkLoad{table_name, table} => {
+                binary.extend_from_slice(table_name.raw());
+                binary.extend_from_slice(table);
+            },
+            Request::BlobPut(content) => binary.extend_from_slice(content),
+            Request::BlobGet(reference) => binary.extend_from_slice(reference.raw()),
+            Request::FetchPage{cursor_id, max_rows} => {
+                binary.extend_from_slice(&cursor_id.to_le_bytes());
+                binary.extend_from_slice(&max_rows.to_le_bytes());
+            },
+            Request::CloseCursor{cursor_id} => binary.extend_from_slice(&cursor_id.to_le_bytes()),
+        }
+        binary
+    }
+
+    pub fn decode(binary: &[u8]) -> Result<Request, EzError> {
+        if binary.len() < 64 {
+            return Err(EzError{tag: ErrorTag::Instruction, text: format!("A message starts with a 64 byte type tag. Got {} bytes", binary.len())})
+        }
+        let type_tag = KeyString::try_from(&binary[0..64])?;
+        let body = &binary[64..];
+        let key_string = |what: &str| -> Result<KeyString, EzError> {
+            if body.len() < 64 {
+                return Err(EzError{tag: ErrorTag::Instruction, text: format!("{} message is missing its {}", type_tag, what)})
+            }
+            KeyString::try_from(&body[0..64])
+        };
+        let number = |index: usize, what: &str| -> Result<u64, EzError> {
+            if body.len() < 8*(index + 1) {
+                return Err(EzError{tag: ErrorTag::Instruction, text: format!("{} message is missing its {}", type_tag, what)})
+            }
+            Ok(u64_from_le_slice(&body[8*index..8*index + 8]))
+        };
+
+        match type_tag.as_str() {
+            "HEALTH" => Ok(Request::Health),
+            "QUERY" => Ok(Request::Query(parse_queries_from_binary(body)?)),
+            "TAGGEDQUERY" => Ok(Request::TaggedQuery{tag: key_string("tag")?, queries: parse_queries_from_binary(&body[64..])?}),
+            "ATVERSION" => Ok(Request::AtVersion{version: number(0, "write version")?, queries: parse_queries_from_binary(&body[8..])?}),
+            "TAG" => Ok(Request::Tag(key_string("tag")?)),
+            "ADMIN" => Ok(Request::Admin{command: key_string("command")?, args: body[64..].to_vec()}),
+            "KVQUERY" => Ok(Request::KvQuery(parse_kv_queries_from_binary(body)?)),
+            "KVBATCH" => Ok(Request::KvBatch(parse_kv_queries_from_binary(body)?)),
+            "BULKLOAD" => Ok(Request::BulkLoad{table_name: key_string("table name")?, table: body[64..].to_vec()}),
+            "BLOBPUT" => Ok(Request::BlobPut(body.to_vec())),
+            "BLOBGET" => Ok(Request::BlobGet(key_string("blob reference")?)),
+            "CURSOR" => Ok(Request::OpenCursor(parse_queries_from_binary(body)?)),
+            "FETCHPAGE" => Ok(Request::FetchPage{cursor_id: number(0, "cursor id")?, max_rows: number(1, "page size")?}),
+            "CLOSECURSOR" => Ok(Request::CloseCursor{cursor_id: number(0, "cursor id")?}),
+            other => Err(EzError{tag: ErrorTag::Instruction, text: format!("Unsupported message type '{}'", other)}),
+        }
+    }
+}
+
+pub fn encode_ack() -> Vec<u8> {
+    ACK.to_vec()
+}
+
+/// Anything but an ACK is an error.
+pub fn decode_ack(binary: &[u8]) -> Result<(), EzError> {
+    if let Some(e) = decode_error(binary) {
+        return Err(e)
+    }
+    match binary {
+        ACK => Ok(()),
+        other => Err(EzError{tag: ErrorTag::Query, text: String::from_utf8_lossy(other).to_string()}),
+    }
+}
+
+/// The answer to a request that failed. [ERROR_RESPONSE: 64 bytes][EzError]
+/// The tag survives the trip so clients can branch on it, for example on ErrorTag::NotFound.
+pub fn encode_error(error: &EzError) -> Vec<u8> {
+    let mut binary = Vec::new();
+    binary.extend_from_slice(ksf(ERROR_RESPONSE).raw());
+    binary.extend_from_slice(&error.to_binary());
+    binary
+}
+
+/// The error in a response, if the response is one. A response that is cut short comes back as a Deserialization error.
+pub fn decode_error(binary: &[u8]) -> Option<EzError> {
+    if binary.len() < 64 || binary[0..64] != *ksf(ERROR_RESPONSE).raw() {
+        return None
+    }
+    let error = &binary[64..];
+    let cut_short = EzError{tag: ErrorTag::Deserialization, text: "The error response is cut short".to_owned()};
+    if error.len() < 72 || ((error.len() - 72) as u64) < u64_from_le_slice(&error[64..72]) {
+        return Some(cut_short)
+    }
+    Some(EzError::from_binary(error).unwrap_or_else(|e| e))
+}
+
+/// Tables are answered in the EZ binary table format and are always named RESULT.
+pub fn decode_table(binary: &[u8]) -> Result<ColumnTable, EzError> {
+    if let Some(e) = decode_error(binary) {
+        return Err(e)
+    }
+    ColumnTable::from_binary(Some("RESULT"), binary)
+}
+
+/// What a read query came to. A query that matched nothing is not an error, a query that names a table, column
+/// or key that doesn't exist is NotFound, and every other failure is still an Err.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryOutcome {
+    /// The result has at least one row.
+    Rows(ColumnTable),
+    /// Everything the query named exists but no rows matched. The table still has the columns of the result.
+    Empty(ColumnTable),
+    /// The batch did not produce a table.
+    Done,
+    NotFound(EzError),
+}
+
+impl QueryOutcome {
+    /// Sorts the result of executing a batch into an outcome.
+    pub fn from_result(result: Result<Option<ColumnTable>, EzError>) -> Result<QueryOutcome, EzError> {
+        match result {
+            Ok(Some(table)) if table.len() == 0 => Ok(QueryOutcome::Empty(table)),
+            Ok(Some(table)) => Ok(QueryOutcome::Rows(table)),
+            Ok(None) => Ok(QueryOutcome::Done),
+            Err(e) if e.tag == ErrorTag::NotFound => Ok(QueryOutcome::NotFound(e)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The rows, empty or not. NotFound becomes an error.
+    pub fn into_table(self) -> Result<Option<ColumnTable>, EzError> {
+        match self {
+            QueryOutcome::Rows(table) | QueryOutcome::Empty(table) => Ok(Some(table)),
+            QueryOutcome::Done => Ok(None),
+            QueryOutcome::NotFound(e) => Err(e),
+        }
+    }
+}
+
+/// Reads the response to a query batch as an outcome.
+pub fn decode_query_outcome(binary: &[u8]) -> Result<QueryOutcome, EzError> {
+    let result = match decode_error(binary) {
+        Some(e) => Err(e),
+        None if binary == ACK => Ok(None),
+        None => decode_table(binary).map(Some),
+    };
+    QueryOutcome::from_result(result)
+}
+
+/// [cursor id: u64][rows in the result: u64]
+pub fn encode_cursor_opened(cursor_id: u64, rows: u64) -> Vec<u8> {
+    let mut binary = Vec::with_capacity(16);
+    binary.extend_from_slice(&cursor_id.to_le_bytes());
+    binary.extend_from_slice(&rows.to_le_bytes());
+    binary
+}
+
+pub fn decode_cursor_opened(binary: &[u8]) -> Result<(u64, u64), EzError> {
+    if let Some(e) = decode_error(binary) {
+        return Err(e)
+    }
+    if binary.len() != 16 {
+        return Err(EzError{tag: ErrorTag::Query, text: String::from_utf8_lossy(binary).to_string()})
+    }
+    Ok((u64_from_le_slice(&binary[0..8]), u64_from_le_slice(&binary[8..16])))
+}
+
+/// [rows left after this page: u64][page: EZ binary table]
+pub fn encode_page(page: &ColumnTable, remaining: u64) -> Vec<u8> {
+    let mut binary = Vec::new();
+    binary.extend_from_slice(&remaining.to_le_bytes());
+    binary.extend_from_slice(&page.to_binary());
+    binary
+}
+
+pub fn decode_page(binary: &[u8]) -> Result<(ColumnTable, u64), EzError> {
+    if binary.len() < 8 {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: format!("A page is at least 8 bytes. Got {}", binary.len())})
+    }
+    let remaining = u64_from_le_slice(&binary[0..8]);
+    let page = decode_table(&binary[8..])?;
+    Ok((page, remaining))
+}
+
+/// The blob reference is the 64 byte Text cell that links a row to the blob.
+pub fn encode_blob_ref(reference: &KeyString) -> Vec<u8> {
+    reference.raw().to_vec()
+}
+
+pub fn decode_blob_ref(binary: &[u8]) -> Result<KeyString, EzError> {
+    if binary.len() != 64 {
+        return Err(EzError{tag: ErrorTag::Query, text: String::from_utf8_lossy(binary).to_string()})
+    }
+    KeyString::try_from(binary)
+}
+
+/// [number of results: u64][length of each result: u64 per result][results]
+/// Each result is one of [VALUE: 64 bytes][key: 64 bytes][length: u64][value], [NONE: 64 bytes] or [ERROR: 64 bytes][EzError].
+pub fn encode_kv_results(query_results: &[Result<Option<Value>, EzError>]) -> Vec<u8> {
+    let mut binary = Vec::new();
+    binary.extend_from_slice(&query_results.len().to_le_bytes());
+    for _ in 0..query_results.len() {
+        binary.extend_from_slice(&[0u8;8]);
+    }
+    let mut offsets = Vec::new();
+
+    for result in query_results {
+        let mut temp = Vec::new();
+        match result {
+            Ok(Some(value)) => {
+                temp.extend_from_slice(ksf("VALUE").raw());
+                temp.extend_from_slice(value.name.raw());
+                temp.extend_from_slice(&value.body.len().to_le_bytes());
+                temp.extend_from_slice(&value.body);
+            },
+            Ok(None) => temp.extend_from_slice(ksf("NONE").raw()),
+            Err(e) => {
+                temp.extend_from_slice(ksf("ERROR").raw());
+                temp.extend_from_slice(&e.to_binary());
+            }
+        }
+        offsets.push(temp.len());
+        binary.extend_from_slice(&temp);
+    }
+
+    for (i, offset) in offsets.into_iter().enumerate() {
+        binary[8+8*i..8+8*i+8].copy_from_slice(&offset.to_le_bytes());
+    }
+    binary
+}
+
+pub fn decode_kv_results(binary: &[u8]) -> Result<Vec<Result<Option<Value>, EzError>>, EzError> {
+    let too_short = || EzError{tag: ErrorTag::Deserialization, text: "KV results are cut short".to_owned()};
+    if binary.len() < 8 {
+        return Err(too_short())
+    }
+    let number_of_responses = u64_from_le_slice(&binary[0..8]) as usize;
+    if (binary.len() - 8) / 8 < number_of_responses {
+        return Err(too_short())
+    }
+    let mut body = &binary[8+8*number_of_responses..];
+
+    let mut results = Vec::with_capacity(number_of_responses);
+    for i in 0..number_of_responses {
+        let len = u64_from_le_slice(&binary[8+8*i..8+8*i+8]) as usize;
+        if body.len() < len || len < 64 {
+            return Err(too_short())
+        }
+        let (current_blob, rest) = body.split_at(len);
+        body = rest;
+
+        let tag = KeyString::try_from(&current_blob[0..64])?;
+        match tag.as_str() {
+            "VALUE" => {
+                if current_blob.len() < 136 {
+                    return Err(too_short())
+                }
+                let name = KeyString::try_from(&current_blob[64..128])?;
+                let len = u64_from_le_slice(&current_blob[128..136]) as usize;
+                if current_blob.len() - 136 < len {
+                    return Err(too_short())
+                }
+                results.push(Ok(Some(Value {name, body: current_blob[136..136+len].to_vec()})));
+            },
+            "ERROR" => results.push(Err(EzError::from_binary(&current_blob[64..])?)),
+            "NONE" => results.push(Ok(None)),
+            other => {
+                results.push(Err(EzError{tag: ErrorTag::Query, text: format!("Incorrectly formatted response. '{}' is not a valid response type", other)}));
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::testing_tools::{create_fixed_table, random_ez_error, random_kv_query, random_query};
+
+    use super::*;
+
+    fn round_trip(request: Request) {
+        let binary = request.encode();
+        assert_eq!(KeyString::try_from(&binary[0..64]).unwrap().as_str(), request.type_tag());
+        assert_eq!(Request::decode(&binary).unwrap(), request);
+    }
+
+    #[test]
+    fn test_read_only_requests() {
+        let select = Query::new_select("fixed_table");
+        let drop = Query::DROP{table_name: ksf("fixed_table")};
+        assert!(Request::Health.is_read_only());
+        assert!(Request::Query(vec![select.clone()]).is_read_only());
+        assert!(!Request::Query(vec![select.clone(), drop.clone()]).is_read_only());
+        assert!(!Request::TaggedQuery{tag: ksf("tag"), queries: vec![drop.clone()]}.is_read_only());
+        assert!(Request::AtVersion{version: 4, queries: vec![select.clone()]}.is_read_only());
+        assert!(!Request::AtVersion{version: 4, queries: vec![drop]}.is_read_only());
+        assert!(Request::KvQuery(vec![KvQuery::Read(ksf("a")), KvQuery::ReadVersion(ksf("a"), 1)]).is_read_only());
+        assert!(!Request::KvQuery(vec![KvQuery::Read(ksf("a")), KvQuery::Rollback(ksf("a"), 1)]).is_read_only());
+        assert!(!Request::KvBatch(vec![KvQuery::Delete(ksf("a"))]).is_read_only());
+        assert!(Request::Admin{command: ksf("METRICS"), args: Vec::new()}.is_read_only());
+        for command in ["USER_ADD", "USER_REMOVE", "USER_PASSWORD", "TRANSFER_OWNERSHIP", "NAMESPACE_QUOTA", "CONFIG_SET", "UNDROP", "TASK_START"] {
+            assert!(!Request::Admin{command: ksf(command), args: Vec::new()}.is_read_only(), "{}", command);
+        }
+        assert!(!Request::BlobPut(vec![1]).is_read_only());
+    }
+
+    #[test]
+    fn test_request_round_trips() {
+        let queries: Vec<Query> = (0..5).map(|_| random_query()).collect();
+        let kv_queries: Vec<KvQuery> = (0..5).map(|_| random_kv_query()).collect();
+
+        round_trip(Request::Health);
+        round_trip(Request::Query(queries.clone()));
+        round_trip(Request::TaggedQuery{tag: ksf("nightly_job_42"), queries: queries.clone()});
+        round_trip(Request::AtVersion{version: 0, queries: queries.clone()});
+        round_trip(Request::AtVersion{version: u64::MAX, queries: queries.clone()});
+        round_trip(Request::Tag(ksf("billing")));
+        round_trip(Request::Tag(ksf("")));
+        round_trip(Request::Admin{command: ksf("TASK_LIST"), args: Vec::new()});
+        round_trip(Request::Admin{command: ksf("TASK_PAUSE"), args: 7u64.to_le_bytes().to_vec()});
+        round_trip(Request::KvQuery(kv_queries.clone()));
+        round_trip(Request::KvBatch(kv_queries));
+        round_trip(Request::BulkLoad{table_name: ksf("good_table"), table: create_fixed_table(10).to_binary()});
+        round_trip(Request::BlobPut(b"attachment".to_vec()));
+        round_trip(Request::BlobPut(Vec::new()));
+        round_trip(Request::BlobGet(ksf("blob:0123456789abcdef")));
+        round_trip(Request::OpenCursor(queries));
+        round_trip(Request::FetchPage{cursor_id: 3, max_rows: 0});
+        round_trip(Request::FetchPage{cursor_id: u64::MAX, max_rows: 10_000});
+        round_trip(Request::CloseCursor{cursor_id: 12});
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        assert!(Request::decode(&[0u8; 10]).is_err());
+        assert_eq!(Request::decode(ksf("NOT_A_MESSAGE").raw()).unwrap_err().tag, ErrorTag::Instruction);
+        assert!(Request::decode(ksf("TAG").raw()).is_err());
+        assert!(Request::decode(ksf("BLOBGET").raw()).is_err());
+        assert!(Request::decode(ksf("QUERY").raw()).is_err());
+        assert!(Request::decode(ksf("ATVERSION").raw()).is_err());
+
+        let mut fetch = Request::FetchPage{cursor_id: 3, max_rows: 10}.encode();
+        fetch.truncate(64 + 12);
+        assert!(Request::decode(&fetch).is_err());
+        let mut close = Request::CloseCursor{cursor_id: 3}.encode();
+        close.truncate(64 + 4);
+        assert!(Request::decode(&close).is_err());
+    }
+
+    #[test]
+    fn test_credentials() {
+        let credentials = Credentials{username: "admin".to_owned(), password: "hunter2".to_owned(), checksum: FrameChecksum::None};
+        let binary = credentials.encode().unwrap();
+        assert_eq!(binary.len(), 1024);
+        assert_eq!(Credentials::decode(&binary).unwrap(), credentials);
+
+        let credentials = Credentials{checksum: FrameChecksum::Crc32, ..credentials};
+        let binary = credentials.encode().unwrap();
+        assert_eq!(binary.len(), 1025);
+        assert_eq!(Credentials::decode(&binary).unwrap(), credentials);
+
+        let too_long = Credentials{username: "a".repeat(513), ..credentials};
+        assert_eq!(too_long.encode().unwrap_err().tag, ErrorTag::Authentication);
+        assert!(Credentials::decode(&binary[0..1000]).is_err());
+    }
+
+    #[test]
+    fn test_response_round_trips() {
+        assert_eq!(decode_ack(&encode_ack()), Ok(()));
+        assert_eq!(decode_ack(b"No such table").unwrap_err().text, "No such table");
+
+        assert_eq!(decode_cursor_opened(&encode_cursor_opened(4, 25)).unwrap(), (4, 25));
+        let too_many = EzError{tag: ErrorTag::Unavailable, text: "Too many cursors".to_owned()};
+        assert_eq!(decode_cursor_opened(&encode_error(&too_many)).unwrap_err(), too_many);
+        assert!(decode_cursor_opened(b"Not a cursor").is_err());
+
+        let table = create_fixed_table(20);
+        let (page, remaining) = decode_page(&encode_page(&table, 80)).unwrap();
+        assert_eq!(remaining, 80);
+        assert_eq!(page.get_column_int(&ksf("ints")).unwrap(), table.get_column_int(&ksf("ints")).unwrap());
+        assert!(decode_page(&[0u8; 4]).is_err());
+
+        assert_eq!(decode_table(&table.to_binary()).unwrap().len(), 20);
+
+        let reference = ksf("blob:0123456789abcdef");
+        assert_eq!(decode_blob_ref(&encode_blob_ref(&reference)).unwrap(), reference);
+        assert!(decode_blob_ref(b"Not allowed").is_err());
+    }
+
+    #[test]
+    fn test_error_responses() {
+        for _ in 0..20 {
+            let error = random_ez_error();
+            assert_eq!(decode_error(&encode_error(&error)), Some(error.clone()));
+            assert_eq!(decode_table(&encode_error(&error)).unwrap_err(), error);
+            assert_eq!(decode_ack(&encode_error(&error)).unwrap_err(), error);
+        }
+        assert_eq!(decode_error(&encode_ack()), None);
+        assert_eq!(decode_error(&create_fixed_table(2).to_binary()), None);
+
+        let mut cut = encode_error(&EzError{tag: ErrorTag::NotFound, text: "No table named 'nope'".to_owned()});
+        cut.truncate(cut.len() - 4);
+        assert_eq!(decode_error(&cut).unwrap().tag, ErrorTag::Deserialization);
+    }
+
+    #[test]
+    fn test_query_outcomes() {
+        let table = create_fixed_table(5);
+        let empty = table.subtable_from_indexes(&[], &ksf("empty"));
+
+        assert_eq!(decode_query_outcome(&table.to_binary()).unwrap(), QueryOutcome::Rows(decode_table(&table.to_binary()).unwrap()));
+        match decode_query_outcome(&empty.to_binary()).unwrap() {
+            QueryOutcome::Empty(result) => assert_eq!(result.header, table.header),
+            other => panic!("Expected Empty, got {:?}", other),
+        }
+        assert_eq!(decode_query_outcome(&encode_ack()).unwrap(), QueryOutcome::Done);
+
+        let missing = EzError{tag: ErrorTag::NotFound, text: "No table named 'nope'".to_owned()};
+        assert_eq!(decode_query_outcome(&encode_error(&missing)).unwrap(), QueryOutcome::NotFound(missing.clone()));
+        assert_eq!(QueryOutcome::NotFound(missing.clone()).into_table().unwrap_err(), missing);
+
+        let malformed = EzError{tag: ErrorTag::Query, text: "'bigger' is not a test".to_owned()};
+        assert_eq!(decode_query_outcome(&encode_error(&malformed)).unwrap_err(), malformed);
+    }
+
+    #[test]
+    fn test_kv_queries_serde() {
+        let results: Vec<Result<Option<Value>, EzError>> = vec![
+            Ok(
+                Some(Value{name: ksf("test2"), body: vec![8,7,6,5,4,3,2,1]}),
+            ),
+            Ok(
+                Some(Value{name: ksf("test2"), body: vec![0,0,0,0,0,0,0,0]}),
+            ),
+            Ok(
+                Some(Value{name: ksf("test1"), body: vec![1,2,3,4,5,6,7,8]}),
+            ),
+            Ok(
+                None,
+            ),
+            Err(
+                EzError{tag: ErrorTag::Query, text: "Test".to_owned()}
+            )
+
+        ];
+
+        let binary = encode_kv_results(&results);
+
+        let parsed = decode_kv_results(&binary).unwrap();
+
+        assert_eq!(results, parsed);
+    }
+
+    #[test]
+    fn test_kv_results_round_trip() {
+        let results = vec![
+            Ok(Some(Value{name: ksf("a"), body: vec![1,2,3,4,5]})),
+            Ok(Some(Value{name: ksf("empty"), body: Vec::new()})),
+            Ok(None),
+            Err(EzError{tag: ErrorTag::Query, text: "Test".to_owned()}),
+            Err(random_ez_error()),
+        ];
+        let binary = encode_kv_results(&results);
+        assert_eq!(decode_kv_results(&binary).unwrap(), results);
+        assert_eq!(decode_kv_results(&encode_kv_results(&[])).unwrap(), Vec::new());
+
+        for cut in [0, 7, 20, binary.len() - 1] {
+            assert!(decode_kv_results(&binary[0..cut]).is_err());
+        }
+    }
+}
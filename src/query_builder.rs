@@ -0,0 +1,417 @@
+//! Builds queries without writing out the OpOrCond list by hand:
+//!     Select::table("products")
+//!         .columns(["price", "stock"])
+//!         .filter(col("price").gt(500).and(col("stock").lt(1000)))
+//!         .build()?
+//! Names and text values are converted when build() is called, so every mistake is reported there.
+
+use std::ops::Not;
+
+use crate::db_structure::DbValue;
+use crate::ezql::{Condition, ConditionBranch, OpOrCond, Operator, Query, RangeOrListOrAll, TestOp};
+use crate::utilities::{EzError, KeyString};
+
+
+/// The start of a test on a column. See Filter.
+pub fn col(name: &str) -> Column {
+    Column{name: name.to_owned()}
+}
+
+/// A column named in a filter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+    name: String,
+}
+
+/// A value to test a column against. Text is checked when the query is built.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Literal(LiteralValue);
+
+#[derive(Clone, Debug, PartialEq)]
+enum LiteralValue {
+    Value(DbValue),
+    Text(String),
+}
+
+impl Literal {
+    fn to_value(&self) -> Result<DbValue, EzError> {
+        match &self.0 {
+            LiteralValue::Value(value) => Ok(value.clone()),
+            LiteralValue::Text(text) => Ok(DbValue::Text(KeyString::from_input(text)?)),
+        }
+    }
+}
+
+impl From<DbValue> for Literal {
+    fn from(value: DbValue) -> Self {
+        Literal(LiteralValue::Value(value))
+    }
+}
+
+impl From<i32> for Literal {
+    fn from(value: i32) -> Self {
+        Literal(LiteralValue::Value(DbValue::Int(value)))
+    }
+}
+
+impl From<f32> for Literal {
+    fn from(value: f32) -> Self {
+        Literal(LiteralValue::Value(DbValue::Float(value)))
+    }
+}
+
+impl From<KeyString> for Literal {
+    fn from(value: KeyString) -> Self {
+        Literal(LiteralValue::Value(DbValue::Text(value)))
+    }
+}
+
+impl From<&str> for Literal {
+    fn from(value: &str) -> Self {
+        Literal(LiteralValue::Text(value.to_owned()))
+    }
+}
+
+impl From<String> for Literal {
+    fn from(value: String) -> Self {
+        Literal(LiteralValue::Text(value))
+    }
+}
+
+impl Column {
+    /// Tests the column against a value with any of the tests in TestOp.
+    pub fn test(self, op: TestOp, value: impl Into<Literal>) -> Filter {
+        Filter::Test{column: self.name, op, value: value.into(), other_column: None}
+    }
+
+    /// Tests the column against another column of the same row.
+    pub fn test_column(self, op: TestOp, other: &str) -> Filter {
+        Filter::Test{column: self.name, op, value: DbValue::Int(0).into(), other_column: Some(other.to_owned())}
+    }
+
+    pub fn eq(self, value: impl Into<Literal>) -> Filter {
+        self.test(TestOp::Equals, value)
+    }
+
+    pub fn ne(self, value: impl Into<Literal>) -> Filter {
+        self.test(TestOp::NotEquals, value)
+    }
+
+    pub fn lt(self, value: impl Into<Literal>) -> Filter {
+        self.test(TestOp::Less, value)
+    }
+
+    pub fn gt(self, value: impl Into<Literal>) -> Filter {
+        self.test(TestOp::Greater, value)
+    }
+
+    pub fn starts_with(self, value: impl Into<Literal>) -> Filter {
+        self.test(TestOp::Starts, value)
+    }
+
+    pub fn ends_with(self, value: impl Into<Literal>) -> Filter {
+        self.test(TestOp::Ends, value)
+    }
+
+    pub fn contains(self, value: impl Into<Literal>) -> Filter {
+        self.test(TestOp::Contains, value)
+    }
+
+    /// '*' matches any run of characters and '?' any single one. The whole value has to match.
+    pub fn matches(self, pattern: impl Into<Literal>) -> Filter {
+        self.test(TestOp::Matches, pattern)
+    }
+
+    /// Needs a server built with the regex feature.
+    pub fn matches_regex(self, pattern: impl Into<Literal>) -> Filter {
+        self.test(TestOp::MatchesRegex, pattern)
+    }
+
+    /// Only float columns can be tested for NaN.
+    pub fn is_nan(self) -> Filter {
+        self.test(TestOp::IsNaN, 0)
+    }
+
+    /// The column holds one of the values.
+    pub fn is_in<T: Into<Literal>>(self, values: impl IntoIterator<Item = T>) -> Filter {
+        Filter::In{column: self.name, values: values.into_iter().map(Into::into).collect()}
+    }
+
+    /// Both ends are included.
+    pub fn between(self, low: impl Into<Literal>, high: impl Into<Literal>) -> Filter {
+        Filter::Between{column: self.name, low: low.into(), high: high.into()}
+    }
+}
+
+/// The conditions of a query as a tree. and() and or() group exactly as they are written, and `!` negates
+/// a whole filter, so `!col("a").eq(1).or(col("b").eq(2))` is NOT (a = 1 OR b = 2).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    Test{column: String, op: TestOp, value: Literal, other_column: Option<String>},
+    In{column: String, values: Vec<Literal>},
+    Between{column: String, low: Literal, high: Literal},
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn and(self, other: Filter) -> Filter {
+        match self {
+            Filter::And(mut filters) => {
+                filters.push(other);
+                Filter::And(filters)
+            },
+            filter => Filter::And(vec![filter, other]),
+        }
+    }
+
+    pub fn or(self, other: Filter) -> Filter {
+        match self {
+            Filter::Or(mut filters) => {
+                filters.push(other);
+                Filter::Or(filters)
+            },
+            filter => Filter::Or(vec![filter, other]),
+        }
+    }
+
+    /// The flat condition list queries carry. Parentheses are only added where precedence needs them.
+    pub fn to_conditions(&self) -> Result<Vec<OpOrCond>, EzError> {
+        let mut conditions = Vec::new();
+        self.flatten(&mut conditions)?;
+        // The tree is always well formed but the server parses it again, so a mistake here would only show up there
+        ConditionBranch::parse(&conditions)?;
+        Ok(conditions)
+    }
+
+    fn flatten(&self, conditions: &mut Vec<OpOrCond>) -> Result<(), EzError> {
+        match self {
+            Filter::Test{column, op, value, other_column} => {
+                let other_column = match other_column {
+                    Some(other) => Some(KeyString::from_input(other)?),
+                    None => None,
+                };
+                conditions.push(OpOrCond::Cond(Condition{attribute: KeyString::from_input(column)?, op: op.clone(), value: value.to_value()?, other_column}));
+            },
+            Filter::In{column, values} => {
+                let values = values.iter().map(Literal::to_value).collect::<Result<Vec<_>, _>>()?;
+                conditions.push(OpOrCond::Cond(Condition{attribute: KeyString::from_input(column)?, op: TestOp::In(Vec::new()).with_values(values)?, value: 0.into(), other_column: None}));
+            },
+            Filter::Between{column, low, high} => {
+                let op = TestOp::Between(low.to_value()?, high.to_value()?);
+                conditions.push(OpOrCond::Cond(Condition{attribute: KeyString::from_input(column)?, op, value: 0.into(), other_column: None}));
+            },
+            Filter::Not(filter) => {
+                conditions.push(OpOrCond::Not);
+                filter.flatten_grouped(conditions, !matches!(**filter, Filter::Test{..} | Filter::In{..} | Filter::Between{..} | Filter::Not(_)))?;
+            },
+            Filter::And(filters) => {
+                for (i, filter) in filters.iter().enumerate() {
+                    if i > 0 {
+                        conditions.push(OpOrCond::Op(Operator::AND));
+                    }
+                    filter.flatten_grouped(conditions, matches!(filter, Filter::Or(_)))?;
+                }
+            },
+            Filter::Or(filters) => {
+                for (i, filter) in filters.iter().enumerate() {
+                    if i > 0 {
+                        conditions.push(OpOrCond::Op(Operator::OR));
+                    }
+                    filter.flatten(conditions)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn flatten_grouped(&self, conditions: &mut Vec<OpOrCond>, grouped: bool) -> Result<(), EzError> {
+        if grouped {
+            conditions.push(OpOrCond::Open);
+        }
+        self.flatten(conditions)?;
+        if grouped {
+            conditions.push(OpOrCond::Close);
+        }
+        Ok(())
+    }
+}
+
+impl Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Filter {
+        Filter::Not(Box::new(self))
+    }
+}
+
+/// Builds a Query::SELECT. Every column is returned unless columns() is called.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Select {
+    table_name: String,
+    primary_keys: RangeOrListOrAll,
+    columns: Vec<String>,
+    filter: Option<Filter>,
+    distinct: bool,
+    limit: Option<usize>,
+}
+
+impl Select {
+    pub fn table(table_name: &str) -> Select {
+        Select {
+            table_name: table_name.to_owned(),
+            primary_keys: RangeOrListOrAll::All,
+            columns: Vec::new(),
+            filter: None,
+            distinct: false,
+            limit: None,
+        }
+    }
+
+    pub fn columns<S: Into<String>>(mut self, columns: impl IntoIterator<Item = S>) -> Select {
+        self.columns.extend(columns.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn keys(mut self, primary_keys: RangeOrListOrAll) -> Select {
+        self.primary_keys = primary_keys;
+        self
+    }
+
+    /// Calling filter() again ANDs the filters together.
+    pub fn filter(mut self, filter: Filter) -> Select {
+        self.filter = Some(match self.filter.take() {
+            Some(existing) => existing.and(filter),
+            None => filter,
+        });
+        self
+    }
+
+    pub fn distinct(mut self) -> Select {
+        self.distinct = true;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Select {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn build(self) -> Result<Query, EzError> {
+        let columns = match self.columns.is_empty() {
+            true => vec![KeyString::from("*")],
+            false => self.columns.iter().map(|column| KeyString::from_input(column)).collect::<Result<_, _>>()?,
+        };
+        Ok(Query::SELECT {
+            table_name: KeyString::from_input(&self.table_name)?,
+            primary_keys: self.primary_keys,
+            columns,
+            conditions: build_conditions(&self.filter)?,
+            distinct: self.distinct,
+            limit: self.limit,
+        })
+    }
+}
+
+/// Builds a Query::DELETE. Without keys() or filter() it deletes every row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delete {
+    table_name: String,
+    primary_keys: RangeOrListOrAll,
+    filter: Option<Filter>,
+}
+
+impl Delete {
+    pub fn table(table_name: &str) -> Delete {
+        Delete {
+            table_name: table_name.to_owned(),
+            primary_keys: RangeOrListOrAll::All,
+            filter: None,
+        }
+    }
+
+    pub fn keys(mut self, primary_keys: RangeOrListOrAll) -> Delete {
+        self.primary_keys = primary_keys;
+        self
+    }
+
+    /// Calling filter() again ANDs the filters together.
+    pub fn filter(mut self, filter: Filter) -> Delete {
+        self.filter = Some(match self.filter.take() {
+            Some(existing) => existing.and(filter),
+            None => filter,
+        });
+        self
+    }
+
+    pub fn build(self) -> Result<Query, EzError> {
+        Ok(Query::DELETE {
+            table_name: KeyString::from_input(&self.table_name)?,
+            primary_keys: self.primary_keys,
+            conditions: build_conditions(&self.filter)?,
+        })
+    }
+}
+
+fn build_conditions(filter: &Option<Filter>) -> Result<Vec<OpOrCond>, EzError> {
+    match filter {
+        Some(filter) => filter.to_conditions(),
+        None => Ok(Vec::new()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_structure::ColumnTable;
+    use crate::ezql::execute_select_query;
+    use crate::utilities::ksf;
+
+    #[test]
+    fn test_select_builder() {
+        let query = Select::table("products")
+            .columns(["price"])
+            .filter(col("price").gt(500).and(col("stock").lt(1000)))
+            .build()
+            .unwrap();
+        let by_hand = Query::SELECT {
+            table_name: ksf("products"),
+            primary_keys: RangeOrListOrAll::All,
+            columns: vec![ksf("price")],
+            conditions: vec![
+                OpOrCond::Cond(Condition{attribute: ksf("price"), op: TestOp::Greater, value: DbValue::Int(500), other_column: None}),
+                OpOrCond::Op(Operator::AND),
+                OpOrCond::Cond(Condition{attribute: ksf("stock"), op: TestOp::Less, value: DbValue::Int(1000), other_column: None}),
+            ],
+            distinct: false,
+            limit: None,
+        };
+        assert_eq!(query, by_hand);
+    }
+
+    #[test]
+    fn test_filter_grouping() {
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N;stock,i-N\n1;apple;5\n2;apricot;50\n3;banana;5\n4;cherry;500", "fruit", "test").unwrap();
+        let ids = |filter: Filter| {
+            let query = Select::table("fruit").columns(["id"]).filter(filter).build().unwrap();
+            execute_select_query(&query, &table).unwrap().unwrap().to_string()
+        };
+
+        // (name starts with a OR id = 4) AND stock < 100 needs its group to mean what it says
+        let grouped = col("name").starts_with("a").or(col("id").eq(4)).and(col("stock").lt(100));
+        assert_eq!(grouped.to_conditions().unwrap()[0], OpOrCond::Open);
+        assert_eq!(ids(grouped), ids(col("id").lt(3)));
+
+        let negated = !col("name").starts_with("a").or(col("stock").gt(100));
+        assert_eq!(ids(negated), ids(col("id").eq(3)));
+
+        assert_eq!(ids(col("name").is_in(["apple", "cherry"])), ids(col("id").eq(1).or(col("id").eq(4))));
+        assert_eq!(ids(col("stock").between(5, 50)), ids(col("id").lt(4)));
+        assert_eq!(ids(!col("id").between(2, 3)), ids(col("id").is_in([1, 4])));
+
+        let compared = Select::table("fruit").filter(col("id").test_column(TestOp::Less, "stock")).build().unwrap();
+        assert!(matches!(compared, Query::SELECT{conditions, ..} if conditions.len() == 1));
+    }
+}
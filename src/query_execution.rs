@@ -1,8 +1,13 @@
-use std::{collections::{BTreeMap, BTreeSet}, sync::Arc};
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(feature = "server")]
+use std::sync::Arc;
 
-use eznoise::Connection;
+#[cfg(feature = "server")]
+use crate::transport::Transport;
+#[cfg(feature = "server")]
+use crate::{database::Database, ezql::filter_keepers};
 
-use crate::{db_structure::{remove_indices, write_column_table_binary_header, ColumnTable, DbColumn, DbType, HeaderItem, TableKey}, ezql::{filter_keepers, OpOrCond, Operator, RangeOrListOrAll, Statistic, Test, TestOp, Update}, server_networking::Database, utilities::{ksf, ErrorTag, EzError, KeyString}};
+use crate::{db_structure::{Collation, ColumnTable, DbColumn, DbValue, HeaderItem, LongTexts, TableKey}, ezql::{check_column_comparison, check_test_type, ConditionBranch, KeyList, OpOrCond, PreparedTests, RangeOrListOrAll, Statistic, Update}, utilities::{ErrorTag, EzError, KeyString}};
 
 pub const BUFCAP: usize = 65535;
 
@@ -14,15 +19,17 @@ pub fn zero_slice(slice: &mut [u8]) {
     }
 }
 
+#[cfg(feature = "server")]
 pub struct StreamBuffer<'a> {
-    connection: &'a mut Connection,
+    connection: &'a mut Transport,
     end_pointer: usize,
     buffer: [u8;BUFCAP],
 }
 
+#[cfg(feature = "server")]
 impl <'a> StreamBuffer<'a> {
 
-    pub fn new(connection: &mut Connection) -> StreamBuffer {
+    pub fn new(connection: &mut Transport) -> StreamBuffer<'_> {
         let buffer = [0u8;BUFCAP];
         let end_pointer = 0;
         StreamBuffer {
@@ -33,7 +40,7 @@ impl <'a> StreamBuffer<'a> {
     }
 
     pub fn flush(&mut self) -> Result<(), EzError> {
-        let result = match self.connection.SEND_C2(&self.buffer[0..self.end_pointer]) {
+        let result = match self.connection.send_to_client(&self.buffer[0..self.end_pointer]) {
             Ok(_) => Ok(()),
             Err(_) => Err(EzError { tag: ErrorTag::Io, text: format!("Something went wrong while trying to flush StreamBuffer.") }),
         };
@@ -87,6 +94,9 @@ pub enum DbSlice<'a> {
     Ints(&'a [i32]),
     Texts(&'a [KeyString]),
     Floats(&'a [f32]),
+    Durations(&'a [i64]),
+    /// Rows start up to but not including end of the column.
+    LongTexts(&'a LongTexts, usize, usize),
 }
 
 impl<'a> DbSlice<'a> {
@@ -95,6 +105,19 @@ impl<'a> DbSlice<'a> {
             DbSlice::Ints(col) => col.len()*size_of::<i32>(),
             DbSlice::Texts(col) => col.len()*size_of::<KeyString>(),
             DbSlice::Floats(col) => col.len()*size_of::<f32>(),
+            DbSlice::Durations(col) => std::mem::size_of_val(*col),
+            DbSlice::LongTexts(col, start, end) => (*start..*end).map(|i| col.get(i).len() + size_of::<usize>()).sum(),
+        }
+    }
+
+    /// The value in the given row. Long texts don't fit in a DbValue and can't be read this way.
+    pub fn value_at(&self, index: usize) -> Result<DbValue, EzError> {
+        match self {
+            DbSlice::Ints(col) => Ok(DbValue::Int(col[index])),
+            DbSlice::Texts(col) => Ok(DbValue::Text(col[index])),
+            DbSlice::Floats(col) => Ok(DbValue::Float(col[index])),
+            DbSlice::Durations(col) => Ok(DbValue::Duration(col[index])),
+            DbSlice::LongTexts(..) => Err(EzError{tag: ErrorTag::Query, text: "Long text columns can't be compared against other columns".to_owned()}),
         }
     }
 
@@ -106,6 +129,8 @@ pub fn db_slice_from_column<'a>(column: &'a DbColumn, start: usize, end: usize)
         DbColumn::Ints(vec) => DbSlice::Ints(&vec[start..end]),
         DbColumn::Texts(vec) => DbSlice::Texts(&vec[start..end]),
         DbColumn::Floats(vec) => DbSlice::Floats(&vec[start..end]),
+        DbColumn::Durations(vec) => DbSlice::Durations(&vec[start..end]),
+        DbColumn::LongTexts(col) => DbSlice::LongTexts(col, start, end),
     }
 }
 
@@ -128,6 +153,10 @@ impl SubTable<'_> {
         unreachable!("There should always be a primary key")
     }
 
+    pub fn key_collation(&self) -> Collation {
+        self.header.iter().find(|item| item.key == TableKey::Primary).map_or(Collation::Binary, |item| item.collation)
+    }
+
     pub fn len(&self) -> usize {
         
 
@@ -136,6 +165,8 @@ impl SubTable<'_> {
                 DbSlice::Floats(col) => col.len(),
                 DbSlice::Ints(col) => col.len(),
                 DbSlice::Texts(col) => col.len(),
+                DbSlice::Durations(col) => col.len(),
+                DbSlice::LongTexts(_, start, end) => end - start,
             },
             None => 0,
         }
@@ -174,7 +205,7 @@ pub fn make_subtable<'a>(table: &'a ColumnTable, start_row: usize, end_row: usiz
                 let end = std::cmp::min(column.len(), end_row);
                 subtable_columns.insert(*name, db_slice_from_column(column, start_row, end));
             },
-            None => return Err(EzError { tag: ErrorTag::Query, text: format!("No column named '{}' in table '{}'", name, table.name) }),
+            None => return Err(EzError { tag: ErrorTag::NotFound, text: format!("No column named '{}' in table '{}'", name, table.name) }),
         };
     }
 
@@ -208,55 +239,28 @@ pub fn keys_to_indexes_subtable(table: &SubTable, keys: &RangeOrListOrAll) -> Re
                     indexes = (first..last).collect();
                 },
                 DbSlice::Texts(column) => {
-                    let first = match column.binary_search(start) {
+                    let collation = table.key_collation();
+                    let first = match column.binary_search_by(|probe| collation.compare(probe, start)) {
                         Ok(x) => x,
                         Err(x) => x,
                     };
-                    let last = match column.binary_search(stop) {
+                    let last = match column.binary_search_by(|probe| collation.compare(probe, stop)) {
                         Ok(x) => x,
                         Err(x) => x,
                     };
                     indexes = (first..last).collect();
                 },
-                DbSlice::Floats(_n) => {
-                    unreachable!("There should never be a float primary key")
+                DbSlice::Floats(_) | DbSlice::Durations(_) | DbSlice::LongTexts(..) => {
+                    unreachable!("There should never be a float, duration or long text primary key")
                 },
             }
         },
         RangeOrListOrAll::List(ref keys) => {
-            match table.columns[&table.get_primary_key_col_index()] {
-                DbSlice::Ints(column) => {
-                    if keys.len() > column.len() {
-                        return Err(EzError{tag: ErrorTag::Query, text: "There are more keys requested than there are indexes to get".to_owned()})
-                    }
-                    let mut keys = keys.clone();
-                    keys.sort();
-                    let mut key_index: usize = 0;
-                    for index in 0..keys.len() {
-                        if column[index] == keys[key_index].to_i32() {
-                            indexes.push(index);
-                            key_index += 1;
-                        }
-                    }
-                },
-                DbSlice::Floats(_) => {
-                    unreachable!("There should never be a float primary key")
-                },
-                DbSlice::Texts(column) => {
-                    if keys.len() > column.len() {
-                        return Err(EzError{tag: ErrorTag::Query, text: "There are more keys requested than there are indexes to get".to_owned()})
-                    }
-                    let mut keys = keys.clone();
-                    keys.sort();
-                    let mut key_index = 0;
-                    for index in 0..column.len() {
-                        if column[index] == keys[key_index] {
-                            indexes.push(index);
-                            key_index += 1;
-                        }
-                    }
-                },
-            }
+            let column = &table.columns[&table.get_primary_key_col_index()];
+            indexes = KeyList::from_keystrings(keys, column).indexes_in(column, table.key_collation())?;
+        },
+        RangeOrListOrAll::Keys(ref keys) => {
+            indexes = keys.indexes_in(&table.columns[&table.get_primary_key_col_index()], table.key_collation())?;
         },
         RangeOrListOrAll::All => indexes = (0..table.len()).collect(),
     };
@@ -273,122 +277,28 @@ pub fn filter_keepers_subtable(conditions: &Vec<OpOrCond>, primary_keys: &RangeO
     if conditions.is_empty() {
         return Ok(indexes);
     }
-    let mut keepers = Vec::<usize>::new();
-    let mut current_op = Operator::OR;
-    for condition in conditions.iter() {
-        match condition {
-            OpOrCond::Op(op) => current_op = *op,
-            OpOrCond::Cond(cond) => {
-                if !table.columns.contains_key(&cond.attribute) {
-                    return Err(EzError{tag: ErrorTag::Query, text: format!("table does not contain column {}", cond.attribute)})
-                }
-                let column = &table.columns[&cond.attribute];
-                if current_op == Operator::OR {
-                    for index in &indexes {
-                        match &cond.op {
-                            TestOp::Equals => {
-                                match column {
-                                    DbSlice::Ints(col) => if col[*index] == cond.value.to_i32() {keepers.push(*index)},
-                                    DbSlice::Floats(col) => if col[*index] == cond.value.to_f32() {keepers.push(*index)},
-                                    DbSlice::Texts(col) => if col[*index] == cond.value.to_keystring() {keepers.push(*index)},
-                                }
-                            },
-                            TestOp::NotEquals => {
-                                match column {
-                                    DbSlice::Ints(col) => if col[*index] != cond.value.to_i32() {keepers.push(*index)},
-                                    DbSlice::Floats(col) => if col[*index] != cond.value.to_f32() {keepers.push(*index)},
-                                    DbSlice::Texts(col) => if col[*index] != cond.value.to_keystring() {keepers.push(*index)},
-                                }
-                            },
-                            TestOp::Less => {
-                                match column {
-                                    DbSlice::Ints(col) => if col[*index] < cond.value.to_i32() {keepers.push(*index)},
-                                    DbSlice::Floats(col) => if col[*index] < cond.value.to_f32() {keepers.push(*index)},
-                                    DbSlice::Texts(col) => if col[*index] < cond.value.to_keystring() {keepers.push(*index)},
-                                }
-                            },
-                            TestOp::Greater => {
-                                match column {
-                                    DbSlice::Ints(col) => if col[*index] > cond.value.to_i32() {keepers.push(*index)},
-                                    DbSlice::Floats(col) => if col[*index] > cond.value.to_f32() {keepers.push(*index)},
-                                    DbSlice::Texts(col) => if col[*index] > cond.value.to_keystring() {keepers.push(*index)},
-                                }
-                            },
-                            TestOp::Starts => {
-                                match column {
-                                    DbSlice::Texts(col) => if col[*index].as_str().starts_with(cond.value.to_keystring().as_str()) {keepers.push(*index)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'starts_with' on text values".to_owned()}),
-                                }
-                            },
-                            TestOp::Ends => {
-                                match column {
-                                    DbSlice::Texts(col) => if col[*index].as_str().ends_with(cond.value.to_keystring().as_str()) {keepers.push(*index)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'ends_with' on text values".to_owned()}),
-                                }
-                            },
-                            TestOp::Contains => {
-                                match column {
-                                    DbSlice::Texts(col) => if col[*index].as_str().contains(cond.value.to_keystring().as_str()) {keepers.push(*index)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'contains' on text values".to_owned()}),
-                                }
-                            },
-                        }
-                    }
-                } else {
-                    let mut losers = Vec::new();
-                    for keeper in &keepers {
-                        match &cond.op {
-                            TestOp::Equals => {
-                                match column {
-                                    DbSlice::Ints(col) => if col[*keeper] == cond.value.to_i32() {losers.push(*keeper)},
-                                    DbSlice::Floats(col) => if col[*keeper] == cond.value.to_f32() {losers.push(*keeper)},
-                                    DbSlice::Texts(col) => if col[*keeper] == cond.value.to_keystring() {losers.push(*keeper)},
-                                }
-                            },
-                            TestOp::NotEquals => {
-                                match column {
-                                    DbSlice::Ints(col) => if col[*keeper] != cond.value.to_i32() {losers.push(*keeper)},
-                                    DbSlice::Floats(col) => if col[*keeper] != cond.value.to_f32() {losers.push(*keeper)},
-                                    DbSlice::Texts(col) => if col[*keeper] != cond.value.to_keystring() {losers.push(*keeper)},
-                                }
-                            },
-                            TestOp::Less => {
-                                match column {
-                                    DbSlice::Ints(col) => if col[*keeper] < cond.value.to_i32() {losers.push(*keeper)},
-                                    DbSlice::Floats(col) => if col[*keeper] < cond.value.to_f32() {losers.push(*keeper)},
-                                    DbSlice::Texts(col) => if col[*keeper] < cond.value.to_keystring() {losers.push(*keeper)},
-                                }
-                            },
-                            TestOp::Greater => {
-                                match column {
-                                    DbSlice::Ints(col) => if col[*keeper] > cond.value.to_i32() {losers.push(*keeper)},
-                                    DbSlice::Floats(col) => if col[*keeper] > cond.value.to_f32() {losers.push(*keeper)},
-                                    DbSlice::Texts(col) => if col[*keeper] > cond.value.to_keystring() {losers.push(*keeper)},
-                                }
-                            },
-                            TestOp::Starts => {
-                                match column {
-                                    DbSlice::Texts(col) => if col[*keeper].as_str().starts_with(cond.value.to_keystring().as_str()) {losers.push(*keeper)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'starts_with' on text values".to_owned()}),
-                                }
-                            },
-                            TestOp::Ends => {
-                                match column {
-                                    DbSlice::Texts(col) => if col[*keeper].as_str().ends_with(cond.value.to_keystring().as_str()) {losers.push(*keeper)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'ends_with' on text values".to_owned()}),
-                                }
-                            },
-                            TestOp::Contains => {
-                                match column {
-                                    DbSlice::Texts(col) => if col[*keeper].as_str().contains(cond.value.to_keystring().as_str()) {losers.push(*keeper)},
-                                    _ => return Err(EzError{tag: ErrorTag::Query, text: "Can only filter by 'contains' on text values".to_owned()}),
-                                }
-                            },
-                        }
-                    }
-                    remove_indices(&mut keepers, &losers);
+
+    for condition in conditions {
+        if let OpOrCond::Cond(cond) = condition {
+            match table.columns.get(&cond.attribute) {
+                Some(column) => check_test_type(cond, column)?,
+                None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("table does not contain column {}", cond.attribute)}),
+            }
+            if let Some(other) = &cond.other_column {
+                match table.columns.get(other) {
+                    Some(other_column) => check_column_comparison(cond, &table.columns[&cond.attribute], other_column)?,
+                    None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("table does not contain column {}", other)}),
                 }
-            },
+            }
+        }
+    }
+
+    let tree = ConditionBranch::parse(conditions)?;
+    let prepared = PreparedTests::new(&tree, &table.columns, &table.header)?;
+    let mut keepers = Vec::<usize>::new();
+    for index in indexes {
+        if tree.evaluate(&mut |cond| prepared.matches_row(cond, &table.columns, index))? {
+            keepers.push(index);
         }
     }
 
@@ -396,11 +306,12 @@ pub fn filter_keepers_subtable(conditions: &Vec<OpOrCond>, primary_keys: &RangeO
 }
 
 
+#[cfg(feature = "server")]
 pub fn execute_queries(queries: Vec<Query>, database: Arc<Database>, streambuffer: &mut StreamBuffer) -> Result<(), EzError> {
     
     for query in queries {
         match query {
-            Query::CREATE { table } => todo!(),
+            Query::CREATE { .. } => todo!(),
             Query::SELECT { table_name, primary_keys, columns, conditions } => {
                 if database.contains_table(table_name) {
                     let tables = database.buffer_pool.tables.read().unwrap();
@@ -432,7 +343,7 @@ pub fn execute_queries(queries: Vec<Query>, database: Arc<Database>, streambuffe
 }
 
 
-#[cfg(test)]
+#[cfg(all(test, feature = "server", feature = "client"))]
 mod tests {
     use crate::client_networking::make_connection;
 
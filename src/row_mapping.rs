@@ -0,0 +1,203 @@
+use crate::db_structure::{DbType, DbValue};
+use crate::utilities::{ErrorTag, EzError, KeyString};
+
+
+/// A Rust type that can be the field of a Row, and the column type it maps to.
+pub trait RowField: Sized {
+    const KIND: DbType;
+
+    fn to_value(&self) -> DbValue;
+
+    fn from_value(value: DbValue) -> Result<Self, EzError>;
+}
+
+fn wrong_kind(expected: DbType, value: &DbValue) -> EzError {
+    EzError{tag: ErrorTag::Deserialization, text: format!("Expected a {} value but got '{}'", expected.name(), value)}
+}
+
+impl RowField for i32 {
+    const KIND: DbType = DbType::Int;
+
+    fn to_value(&self) -> DbValue {
+        DbValue::Int(*self)
+    }
+
+    fn from_value(value: DbValue) -> Result<Self, EzError> {
+        match value {
+            DbValue::Int(x) => Ok(x),
+            other => Err(wrong_kind(Self::KIND, &other)),
+        }
+    }
+}
+
+impl RowField for f32 {
+    const KIND: DbType = DbType::Float;
+
+    fn to_value(&self) -> DbValue {
+        DbValue::Float(*self)
+    }
+
+    fn from_value(value: DbValue) -> Result<Self, EzError> {
+        match value {
+            DbValue::Float(x) => Ok(x),
+            other => Err(wrong_kind(Self::KIND, &other)),
+        }
+    }
+}
+
+/// Durations are nanoseconds.
+impl RowField for i64 {
+    const KIND: DbType = DbType::Duration;
+
+    fn to_value(&self) -> DbValue {
+        DbValue::Duration(*self)
+    }
+
+    fn from_value(value: DbValue) -> Result<Self, EzError> {
+        match value {
+            DbValue::Duration(x) => Ok(x),
+            other => Err(wrong_kind(Self::KIND, &other)),
+        }
+    }
+}
+
+impl RowField for KeyString {
+    const KIND: DbType = DbType::Text;
+
+    fn to_value(&self) -> DbValue {
+        DbValue::Text(*self)
+    }
+
+    fn from_value(value: DbValue) -> Result<Self, EzError> {
+        match value {
+            DbValue::Text(x) => Ok(x),
+            other => Err(wrong_kind(Self::KIND, &other)),
+        }
+    }
+}
+
+/// Text columns hold at most 64 bytes. Longer strings are truncated unless strict keystrings are on.
+/// See KeyString::from_input().
+impl RowField for String {
+    const KIND: DbType = DbType::Text;
+
+    fn to_value(&self) -> DbValue {
+        DbValue::Text(KeyString::from_str_lossy(self))
+    }
+
+    fn from_value(value: DbValue) -> Result<Self, EzError> {
+        match value {
+            DbValue::Text(x) => Ok(x.to_string()),
+            other => Err(wrong_kind(Self::KIND, &other)),
+        }
+    }
+}
+
+/// A struct that is stored as one row of a table, one field per column. Usually implemented with row_struct!
+/// rather than by hand. See ColumnTable::insert_rows() and ColumnTable::rows_as().
+pub trait Row: Sized {
+    /// The column each field maps to and its type, in field order.
+    fn columns() -> Vec<(KeyString, DbType)>;
+
+    /// The value of each field in the order of columns().
+    fn to_row(&self) -> Vec<DbValue>;
+
+    /// Builds the struct from one value per field in the order of columns().
+    fn from_row(values: &[DbValue]) -> Result<Self, EzError>;
+}
+
+/// Used by row_struct! to read the next field out of a row.
+pub fn next_field<'a, T: RowField>(values: &mut impl Iterator<Item = &'a DbValue>, field: &str) -> Result<T, EzError> {
+    match values.next() {
+        Some(value) => T::from_value(value.clone()).map_err(|e| EzError{tag: e.tag, text: format!("Field '{}': {}", field, e.text)}),
+        None => Err(EzError{tag: ErrorTag::Deserialization, text: format!("The row has no value for field '{}'", field)}),
+    }
+}
+
+/// Defines a struct and implements Row for it. Each field maps to the column of the same name and its type
+/// to a column type through RowField: i32 is Int, f32 is Float, i64 is Duration and KeyString or String is Text.
+///     EZDB::row_struct! {
+///         #[derive(Debug, PartialEq)]
+///         pub struct Product {
+///             pub id: i32,
+///             pub name: KeyString,
+///             pub price: f32,
+///         }
+///     }
+#[macro_export]
+macro_rules! row_struct {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field_vis:vis $field:ident : $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$field_meta])* $field_vis $field: $ty),*
+        }
+
+        impl $crate::row_mapping::Row for $name {
+            fn columns() -> Vec<($crate::utilities::KeyString, $crate::db_structure::DbType)> {
+                vec![$(($crate::utilities::KeyString::from(stringify!($field)), <$ty as $crate::row_mapping::RowField>::KIND)),*]
+            }
+
+            fn to_row(&self) -> Vec<$crate::db_structure::DbValue> {
+                vec![$($crate::row_mapping::RowField::to_value(&self.$field)),*]
+            }
+
+            fn from_row(values: &[$crate::db_structure::DbValue]) -> Result<Self, $crate::utilities::EzError> {
+                let mut values = values.iter();
+                Ok($name {
+                    $($field: $crate::row_mapping::next_field(&mut values, stringify!($field))?),*
+                })
+            }
+        }
+    };
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::db_structure::ColumnTable;
+    use crate::utilities::{ksf, KeyString};
+
+    crate::row_struct! {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Product {
+            id: i32,
+            name: KeyString,
+            price: f32,
+            shelf_life: i64,
+        }
+    }
+
+    crate::row_struct! {
+        #[derive(Debug)]
+        struct WrongProduct {
+            id: i32,
+            price: KeyString,
+        }
+    }
+
+    #[test]
+    fn test_row_mapping() {
+        let mut table = ColumnTable::from_csv_string("id,i-P;name,t-N;price,f-N;shelf_life,d-N\n2;pear;2.5;3d", "products", "test").unwrap();
+        let products = vec![
+            Product{id: 3, name: ksf("plum"), price: 1.0, shelf_life: 86_400_000_000_000},
+            Product{id: 1, name: ksf("apple"), price: 1.5, shelf_life: 0},
+        ];
+        table.insert_rows(&products).unwrap();
+        assert_eq!(table.len(), 3);
+
+        let rows: Vec<Product> = table.rows_as().unwrap();
+        assert_eq!(rows[0], products[1]);
+        assert_eq!(rows[1].name, ksf("pear"));
+        assert_eq!(rows[2], products[0]);
+
+        assert!(table.rows_as::<WrongProduct>().is_err());
+        let before = table.clone();
+        assert!(table.insert_rows(&[WrongProduct{id: 7, price: ksf("free")}]).is_err());
+        assert_eq!(table, before);
+    }
+}
@@ -0,0 +1,567 @@
+//! A row oriented table engine for write heavy tables. A ColumnTable keeps every column sorted by primary key so
+//! each INSERT shifts every column of the table. A RowTable stores each row as one fixed width record in a slot,
+//! finds rows through a hash index on the primary key and reuses the slots of deleted rows, so inserts and
+//! deletes only touch the rows they write. Scans test the conditions row by row instead of column by column.
+//!
+//! Tables are created with the engine they use. See TableEngine. In the database a row engine table keeps its
+//! inserted rows in a RowTable until something reads the table, then merges them into the stored table in one
+//! batch. See BufferPool::merge_row_buffer().
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::db_structure::{is_engine_column, Collation, ColumnTable, DbType, DbValue, HeaderItem, Metadata, TableKey};
+use crate::ezql::{condition_matches, encode_enums, execute_select_query, update_durations, update_f32, update_i32, update_keystrings, validate_query, Condition, ConditionBranch, KeyList, Query, RangeOrListOrAll};
+use crate::query_execution::DbSlice;
+use crate::utilities::{ErrorTag, EzError, KeyString};
+
+
+/// The file in the config directory that lists the tables that use the row engine.
+pub const ROW_ENGINE_FILE: &str = ".row_engine";
+/// How many rows the row buffer of a table holds before it is merged even if nothing reads the table.
+pub const ROW_BUFFER_MERGE_ROWS: usize = 65_536;
+
+/// How a table stores its rows. Chosen when the table is created.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TableEngine {
+    /// Sorted columns. Fastest to scan and summarize. See ColumnTable.
+    #[default]
+    Column,
+    /// Rows in slots with a hash index on the primary key. Cheapest to insert into. See RowTable.
+    Row,
+}
+
+impl TableEngine {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TableEngine::Column => "column",
+            TableEngine::Row => "row",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<TableEngine, EzError> {
+        match name {
+            "column" => Ok(TableEngine::Column),
+            "row" => Ok(TableEngine::Row),
+            other => Err(EzError{tag: ErrorTag::Query, text: format!("The engine is either column or row but found '{}'", other)}),
+        }
+    }
+
+    /// How the engine is written in the handles of a CREATE query. Clients that don't know about engines send 0.
+    pub fn to_handle(&self) -> u64 {
+        match self {
+            TableEngine::Column => 0,
+            TableEngine::Row => 1,
+        }
+    }
+
+    pub fn from_handle(handle: usize) -> Result<TableEngine, EzError> {
+        match handle {
+            0 => Ok(TableEngine::Column),
+            1 => Ok(TableEngine::Row),
+            other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("{} is not a table engine", other)}),
+        }
+    }
+}
+
+/// The names of the row engine tables as written to ROW_ENGINE_FILE. [name: 64] per table.
+pub fn row_engine_tables_to_binary(tables: &BTreeSet<KeyString>) -> Vec<u8> {
+    let mut binary = Vec::with_capacity(tables.len() * 64);
+    for name in tables {
+        binary.extend_from_slice(name.raw());
+    }
+    binary
+}
+
+pub fn row_engine_tables_from_binary(binary: &[u8]) -> Result<BTreeSet<KeyString>, EzError> {
+    if !binary.len().is_multiple_of(64) {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: format!("The row engine table list should be a multiple of 64 bytes but is {}", binary.len())})
+    }
+    binary.chunks(64).map(KeyString::try_from).collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum RowKey {
+    Int(i32),
+    Text(KeyString),
+}
+
+/// Where a column lives within a row.
+#[derive(Clone, Debug, PartialEq)]
+struct Field {
+    name: KeyString,
+    kind: DbType,
+    offset: usize,
+}
+
+impl Field {
+    fn width(&self) -> usize {
+        field_width(&self.kind)
+    }
+}
+
+fn field_width(kind: &DbType) -> usize {
+    match kind {
+        DbType::Int | DbType::Enum | DbType::Float => 4,
+        DbType::Duration => 8,
+        DbType::Text => 64,
+        DbType::LongText => unreachable!("RowTable::new() rejects long text columns"),
+    }
+}
+
+/// The value as a one row column so the column tests of ezql can be reused.
+fn single_row(value: &DbValue) -> DbSlice<'_> {
+    match value {
+        DbValue::Int(x) => DbSlice::Ints(std::slice::from_ref(x)),
+        DbValue::Float(x) => DbSlice::Floats(std::slice::from_ref(x)),
+        DbValue::Text(x) => DbSlice::Texts(std::slice::from_ref(x)),
+        DbValue::Duration(x) => DbSlice::Durations(std::slice::from_ref(x)),
+    }
+}
+
+/// A table stored one fixed width row per slot. Answers the same INSERT, UPDATE, DELETE and SELECT queries
+/// as a ColumnTable. Long text columns and the columns the engine keeps up to date itself (row ids, row
+/// timestamps and row versions) have no fixed width or need sorted rows, so tables with them can't use it.
+/// Neither can tables with a collated column since the hash index and the row tests compare bytes.
+#[derive(Clone, Debug)]
+pub struct RowTable {
+    pub name: KeyString,
+    pub header: BTreeSet<HeaderItem>,
+    pub metadata: Metadata,
+    fields: Vec<Field>,
+    /// The position of the primary key in fields.
+    key_field: usize,
+    row_size: usize,
+    slots: Vec<u8>,
+    live: Vec<bool>,
+    /// Slots of deleted rows, reused by the next inserts.
+    free: Vec<usize>,
+    index: HashMap<RowKey, usize>,
+}
+
+impl RowTable {
+    /// An empty table with the given header.
+    pub fn new(name: KeyString, header: &BTreeSet<HeaderItem>, created_by: &str) -> Result<RowTable, EzError> {
+        RowTable::check_header(header)?;
+
+        let mut fields = Vec::with_capacity(header.len());
+        let mut key_field = 0;
+        let mut row_size = 0;
+        for item in header {
+            if item.key == TableKey::Primary {
+                key_field = fields.len();
+            }
+            fields.push(Field{name: item.name, kind: item.kind, offset: row_size});
+            row_size += field_width(&item.kind);
+        }
+
+        Ok(RowTable {
+            name,
+            header: header.clone(),
+            metadata: Metadata::new(created_by),
+            fields,
+            key_field,
+            row_size,
+            slots: Vec::new(),
+            live: Vec::new(),
+            free: Vec::new(),
+            index: HashMap::new(),
+        })
+    }
+
+    /// Whether a table with this header can use the row engine.
+    pub fn check_header(header: &BTreeSet<HeaderItem>) -> Result<(), EzError> {
+        let mut keys = 0;
+        for item in header {
+            if item.kind == DbType::LongText {
+                return Err(EzError{tag: ErrorTag::Structure, text: format!("Long text column '{}' has no fixed width and can't be stored by the row engine", item.name)})
+            }
+            if is_engine_column(&item.name) {
+                return Err(EzError{tag: ErrorTag::Structure, text: format!("The row engine does not keep '{}' up to date. Use the column engine for tables with row ids, timestamps or versions", item.name)})
+            }
+            if item.collation != Collation::Binary {
+                return Err(EzError{tag: ErrorTag::Structure, text: format!("Column '{}' has the {} collation, which only the column engine follows", item.name, item.collation.name())})
+            }
+            if item.key == TableKey::Primary {
+                if !matches!(item.kind, DbType::Int | DbType::Text) {
+                    return Err(EzError{tag: ErrorTag::Structure, text: format!("Primary key '{}' has to be an int or text column", item.name)})
+                }
+                keys += 1;
+            }
+        }
+        if keys != 1 {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("A table needs exactly one primary key but this header has {}", keys)})
+        }
+        Ok(())
+    }
+
+    pub fn from_column_table(table: &ColumnTable) -> Result<RowTable, EzError> {
+        let mut rows = RowTable::new(table.name, &table.header, table.metadata.created_by.as_str())?;
+        rows.metadata = table.metadata.clone();
+        rows.insert(table)?;
+        Ok(rows)
+    }
+
+    /// The rows as a ColumnTable, sorted by primary key.
+    pub fn to_column_table(&self) -> ColumnTable {
+        self.rows_to_table(self.live_slots())
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Bytes held by the slots, counting the free ones.
+    pub fn byte_size(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Adds the rows whose primary key is not in the table yet. Like ColumnTable::insert() the rows that are
+    /// already there win. Returns how many rows were added.
+    pub fn insert(&mut self, inserts: &ColumnTable) -> Result<usize, EzError> {
+        if inserts.header != self.header {
+            return Err(EzError{tag: ErrorTag::Structure, text: "Headers don't match".to_owned()})
+        }
+
+        let mut columns = Vec::with_capacity(self.fields.len());
+        for field in &self.fields {
+            match inserts.columns.get(&field.name) {
+                Some(column) => columns.push(column),
+                None => return Err(EzError{tag: ErrorTag::Structure, text: format!("The inserted rows have no column '{}'", field.name)}),
+            }
+        }
+
+        let mut added = 0;
+        for row in 0..inserts.len() {
+            let key = match columns[self.key_field].value_at(row)? {
+                DbValue::Int(x) => RowKey::Int(x),
+                DbValue::Text(x) => RowKey::Text(x),
+                other => return Err(EzError{tag: ErrorTag::Structure, text: format!("'{}' can't be a primary key", other)}),
+            };
+            if self.index.contains_key(&key) {
+                continue
+            }
+            let slot = self.allocate();
+            for (field, column) in columns.iter().enumerate() {
+                self.write_value(slot, field, &column.value_at(row)?)?;
+            }
+            self.index.insert(key, slot);
+            added += 1;
+        }
+
+        Ok(added)
+    }
+
+    /// Runs a SELECT. The matching rows are found row by row and the columns, distinct and limit are then
+    /// applied to them as execute_select_query() does.
+    pub fn select(&self, query: &Query) -> Result<Option<ColumnTable>, EzError> {
+        let rest = match query {
+            Query::SELECT { table_name, columns, distinct, limit, .. } => Query::SELECT {
+                table_name: *table_name,
+                primary_keys: RangeOrListOrAll::All,
+                columns: columns.clone(),
+                conditions: Vec::new(),
+                distinct: *distinct,
+                limit: *limit,
+            },
+            other => return Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to RowTable::select().\nReceived query: {}", other)}),
+        };
+        let slots = self.matching_slots(&self.prepare(query.clone())?)?;
+        execute_select_query(&rest, &self.rows_to_table(slots))
+    }
+
+    /// Runs an UPDATE and returns how many rows it changed. Updates computed from other columns are not
+    /// supported and the primary key can't be changed since rows are found by it.
+    pub fn update(&mut self, query: Query) -> Result<usize, EzError> {
+        let updates = match &query {
+            Query::UPDATE { updates, .. } => updates.clone(),
+            other => return Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to RowTable::update().\nReceived query: {}", other)}),
+        };
+        if let Some(update) = updates.iter().find(|update| update.expression.is_some()) {
+            return Err(EzError{tag: ErrorTag::Unimplemented, text: format!("The row engine can't compute '{}' from other columns", update.attribute)})
+        }
+        if updates.iter().any(|update| update.attribute == self.fields[self.key_field].name) {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("The primary key of row engine table '{}' can't be updated", self.name)})
+        }
+
+        let query = self.prepare(query)?;
+        let updates = match &query {
+            Query::UPDATE { updates, .. } => updates.clone(),
+            _ => unreachable!("Checked above"),
+        };
+        let mut targets = Vec::with_capacity(updates.len());
+        for update in &updates {
+            targets.push(self.field(&update.attribute)?);
+        }
+
+        let slots = self.matching_slots(&query)?;
+        for &slot in &slots {
+            for (update, &field) in updates.iter().zip(&targets) {
+                let value = match self.read_value(slot, field)? {
+                    DbValue::Int(x) => {
+                        let mut cell = [x];
+                        update_i32(&[0], &mut cell, update.operator, &update.value)?;
+                        DbValue::Int(cell[0])
+                    },
+                    DbValue::Float(x) => {
+                        let mut cell = [x];
+                        update_f32(&[0], &mut cell, update.operator, &update.value)?;
+                        DbValue::Float(cell[0])
+                    },
+                    DbValue::Text(x) => {
+                        let mut cell = [x];
+                        update_keystrings(&[0], &mut cell, update.operator, &update.value)?;
+                        DbValue::Text(cell[0])
+                    },
+                    DbValue::Duration(x) => {
+                        let mut cell = [x];
+                        update_durations(&[0], &mut cell, update.operator, &update.value)?;
+                        DbValue::Duration(cell[0])
+                    },
+                };
+                self.write_value(slot, field, &value)?;
+            }
+        }
+
+        Ok(slots.len())
+    }
+
+    /// Runs a DELETE and returns how many rows it removed. The slots of the removed rows are reused.
+    pub fn delete(&mut self, query: Query) -> Result<usize, EzError> {
+        if !matches!(query, Query::DELETE{..}) {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("Wrong type of query passed to RowTable::delete().\nReceived query: {}", query)})
+        }
+        let slots = self.matching_slots(&self.prepare(query)?)?;
+        for &slot in &slots {
+            let key = self.key_at(slot)?;
+            self.index.remove(&key);
+            self.live[slot] = false;
+            self.free.push(slot);
+        }
+        Ok(slots.len())
+    }
+
+    /// An empty ColumnTable with the same header, for checking queries against.
+    fn shape(&self) -> ColumnTable {
+        ColumnTable::blank(&self.header, self.name, self.metadata.created_by.as_str())
+    }
+
+    fn live_slots(&self) -> Vec<usize> {
+        (0..self.live.len()).filter(|slot| self.live[*slot]).collect()
+    }
+
+    /// Checks the query against the header and encodes its enum values as the positions the rows store.
+    fn prepare(&self, mut query: Query) -> Result<Query, EzError> {
+        validate_query(&query, &self.shape())?;
+        encode_enums(&mut query, &self.header)?;
+        Ok(query)
+    }
+
+    /// The slots of the rows a prepared SELECT, UPDATE or DELETE applies to, in slot order.
+    fn matching_slots(&self, query: &Query) -> Result<Vec<usize>, EzError> {
+        let (primary_keys, conditions) = match query {
+            Query::SELECT { primary_keys, conditions, .. } => (primary_keys, conditions),
+            Query::UPDATE { primary_keys, conditions, .. } => (primary_keys, conditions),
+            Query::DELETE { primary_keys, conditions, .. } => (primary_keys, conditions),
+            other => return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' does not select rows", other)}),
+        };
+
+        let slots = self.slots_by_key(primary_keys)?;
+        if conditions.is_empty() {
+            return Ok(slots)
+        }
+        let tree = ConditionBranch::parse(conditions)?;
+        let mut matching = Vec::new();
+        for slot in slots {
+            if tree.evaluate(&mut |cond| self.test_condition(slot, cond))? {
+                matching.push(slot);
+            }
+        }
+        Ok(matching)
+    }
+
+    fn slots_by_key(&self, primary_keys: &RangeOrListOrAll) -> Result<Vec<usize>, EzError> {
+        let int_keys = self.fields[self.key_field].kind == DbType::Int;
+        let parse = |key: &KeyString| -> Result<RowKey, EzError> {
+            match int_keys {
+                true => match key.to_i32_checked() {
+                    Ok(x) => Ok(RowKey::Int(x)),
+                    Err(e) => Err(EzError{tag: ErrorTag::Query, text: format!("Invalid int: {e}")}),
+                },
+                false => Ok(RowKey::Text(*key)),
+            }
+        };
+
+        let slots = match primary_keys {
+            RangeOrListOrAll::All => self.live_slots(),
+            RangeOrListOrAll::List(keys) => {
+                let mut slots = Vec::new();
+                for key in keys {
+                    if let Some(slot) = self.index.get(&parse(key)?) {
+                        slots.push(*slot);
+                    }
+                }
+                slots
+            },
+            RangeOrListOrAll::Keys(KeyList::Ints(keys)) => keys.iter().filter_map(|key| self.index.get(&RowKey::Int(*key)).copied()).collect(),
+            RangeOrListOrAll::Keys(KeyList::Texts(keys)) => keys.iter().filter_map(|key| self.index.get(&RowKey::Text(*key)).copied()).collect(),
+            // Start is included and stop is not, as with key_range_span()
+            RangeOrListOrAll::Range(start, stop) => {
+                let (start, stop) = (parse(start)?, parse(stop)?);
+                let in_range = |key: &RowKey| match (key, &start, &stop) {
+                    (RowKey::Int(x), RowKey::Int(a), RowKey::Int(b)) => a <= x && x < b,
+                    (RowKey::Text(x), RowKey::Text(a), RowKey::Text(b)) => a <= x && x < b,
+                    _ => false,
+                };
+                self.index.iter().filter(|(key, _)| in_range(key)).map(|(_, slot)| *slot).collect()
+            },
+        };
+        let mut slots = slots;
+        slots.sort_unstable();
+        slots.dedup();
+        Ok(slots)
+    }
+
+    fn test_condition(&self, slot: usize, cond: &Condition) -> Result<bool, EzError> {
+        let resolved;
+        let cond = match &cond.other_column {
+            Some(other) => {
+                let other = self.read_value(slot, self.field(other)?)?;
+                resolved = cond.resolve(&single_row(&other), 0)?;
+                &resolved
+            },
+            None => cond,
+        };
+        let value = self.read_value(slot, self.field(&cond.attribute)?)?;
+        condition_matches(cond, &single_row(&value), 0)
+    }
+
+    fn field(&self, name: &KeyString) -> Result<usize, EzError> {
+        match self.fields.iter().position(|field| field.name == *name) {
+            Some(x) => Ok(x),
+            None => Err(EzError{tag: ErrorTag::NotFound, text: format!("Table does not contain column {}", name)}),
+        }
+    }
+
+    fn allocate(&mut self) -> usize {
+        match self.free.pop() {
+            Some(slot) => {
+                self.live[slot] = true;
+                slot
+            },
+            None => {
+                self.live.push(true);
+                self.slots.resize(self.slots.len() + self.row_size, 0);
+                self.live.len() - 1
+            },
+        }
+    }
+
+    fn cell(&self, slot: usize, field: usize) -> &[u8] {
+        let start = slot * self.row_size + self.fields[field].offset;
+        &self.slots[start..start + self.fields[field].width()]
+    }
+
+    fn key_at(&self, slot: usize) -> Result<RowKey, EzError> {
+        match self.read_value(slot, self.key_field)? {
+            DbValue::Int(x) => Ok(RowKey::Int(x)),
+            DbValue::Text(x) => Ok(RowKey::Text(x)),
+            other => Err(EzError{tag: ErrorTag::Structure, text: format!("'{}' can't be a primary key", other)}),
+        }
+    }
+
+    fn read_value(&self, slot: usize, field: usize) -> Result<DbValue, EzError> {
+        let cell = self.cell(slot, field);
+        match self.fields[field].kind {
+            DbType::Int | DbType::Enum => Ok(DbValue::Int(i32::from_le_bytes(cell.try_into().expect("Int cells are 4 bytes")))),
+            DbType::Float => Ok(DbValue::Float(f32::from_le_bytes(cell.try_into().expect("Float cells are 4 bytes")))),
+            DbType::Duration => Ok(DbValue::Duration(i64::from_le_bytes(cell.try_into().expect("Duration cells are 8 bytes")))),
+            DbType::Text => Ok(DbValue::Text(KeyString::try_from(cell)?)),
+            DbType::LongText => unreachable!("RowTable::new() rejects long text columns"),
+        }
+    }
+
+    fn write_value(&mut self, slot: usize, field: usize, value: &DbValue) -> Result<(), EzError> {
+        let start = slot * self.row_size + self.fields[field].offset;
+        let cell = &mut self.slots[start..start + self.fields[field].width()];
+        match (&self.fields[field].kind, value) {
+            (DbType::Int | DbType::Enum, DbValue::Int(x)) => cell.copy_from_slice(&x.to_le_bytes()),
+            (DbType::Float, DbValue::Float(x)) => cell.copy_from_slice(&x.to_le_bytes()),
+            (DbType::Duration, DbValue::Duration(x)) => cell.copy_from_slice(&x.to_le_bytes()),
+            (DbType::Text, DbValue::Text(x)) => cell.copy_from_slice(x.raw()),
+            (kind, value) => return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' does not fit in {} column '{}'", value, kind.name(), self.fields[field].name)}),
+        }
+        Ok(())
+    }
+
+    /// The given rows as a ColumnTable sorted by primary key.
+    fn rows_to_table(&self, slots: Vec<usize>) -> ColumnTable {
+        let mut table = ColumnTable::blank(&self.header, self.name, self.metadata.created_by.as_str());
+        table.metadata = self.metadata.clone();
+        for slot in slots {
+            for (field, item) in self.fields.iter().enumerate() {
+                let value = self.read_value(slot, field).expect("Every live slot was written whole");
+                table.columns.get_mut(&item.name).expect("The table was made from this header").push_value(value).expect("Cells are read back as the type of their column");
+            }
+        }
+        table.sort();
+        table
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ezql::{parse_EZQL, update_rows_at, delete_rows};
+
+    #[test]
+    fn test_row_table_matches_column_table() {
+        let csv = "id,i-P;name,t-N;price,f-N;stock,i-N\n3;plum;1.0;40\n1;apple;1.5;100\n2;pear;2.5;7";
+        let mut columns = ColumnTable::from_csv_string(csv, "fruit", "test").unwrap();
+        let mut rows = RowTable::from_column_table(&columns).unwrap();
+        assert_eq!(rows.to_column_table(), columns);
+
+        let more = ColumnTable::from_csv_string("id,i-P;name,t-N;price,f-N;stock,i-N\n2;fig;9.0;1\n5;kiwi;0.5;300", "fruit", "test").unwrap();
+        assert_eq!(rows.insert(&more).unwrap(), 1);
+        columns.insert(more).unwrap();
+        assert_eq!(rows.to_column_table(), columns);
+
+        let select = |text: &str| parse_EZQL(text).unwrap().remove(0);
+        for text in [
+            "SELECT(table_name: fruit, primary_keys: *, columns: *, conditions: ((stock greater 10) AND (price less 2.0)))",
+            "SELECT(table_name: fruit, primary_keys: (1, 5, 9), columns: (name), conditions: ())",
+            "SELECT(table_name: fruit, primary_keys: 2..5, columns: *, conditions: (NOT (name starts p)))",
+        ] {
+            let query = select(text);
+            assert_eq!(rows.select(&query).unwrap(), execute_select_query(&query, &columns).unwrap(), "{}", text);
+        }
+
+        let update = select("UPDATE(table_name: fruit, primary_keys: *, conditions: ((stock less 50)), updates: ((stock += 10), (name = cheap)))");
+        assert_eq!(rows.update(update.clone()).unwrap(), update_rows_at(update, &mut columns).unwrap().len());
+        assert_eq!(rows.to_column_table(), columns);
+
+        let delete = select("DELETE(table_name: fruit, primary_keys: *, conditions: ((price greater 1.2)))");
+        assert_eq!(rows.delete(delete.clone()).unwrap(), 2);
+        delete_rows(delete, &mut columns).unwrap();
+        assert_eq!(rows.to_column_table(), columns);
+
+        // The freed slots are reused
+        let size = rows.byte_size();
+        rows.insert(&ColumnTable::from_csv_string("id,i-P;name,t-N;price,f-N;stock,i-N\n8;lime;0.2;3", "fruit", "test").unwrap()).unwrap();
+        assert_eq!(rows.byte_size(), size);
+        assert_eq!(rows.len(), 3);
+
+        let key_update = select("UPDATE(table_name: fruit, primary_keys: *, conditions: (), updates: ((id = 4)))");
+        assert!(rows.update(key_update).is_err());
+        let long = ColumnTable::from_csv_string("id,i-P;notes,l-N\n1;hello", "notes", "test").unwrap();
+        assert!(RowTable::from_column_table(&long).is_err());
+
+        let create = select("CREATE(table_name: fruit, table: \"id,i-P;stock,i-N\n1;5\", engine: row)");
+        assert!(matches!(create, Query::CREATE{engine: TableEngine::Row, ..}));
+        assert_eq!(Query::from_binary(&create.to_binary()).unwrap(), create);
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::db_structure::{parse_csv_header, ColumnTable};
+use crate::utilities::{ErrorTag, EzError, KeyString};
+
+
+/// The tables a server should have, one per line. Each line is the table name, optional flags in
+/// parentheses, a colon and the table's EZ CSV header:
+///     # Lines starting with # are comments
+///     products: id,i-P;name,t-N;price,f-N
+///     orders (row_ids, row_timestamps, row_versions): id,i-P;product,i-F;status,e(open|paid)-N
+/// Loading a schema only creates the tables that are missing. Existing tables are never changed.
+pub fn parse_schema(text: &str) -> Result<Vec<ColumnTable>, EzError> {
+
+    let mut tables: Vec<ColumnTable> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let table = parse_schema_line(line).map_err(|e| EzError{tag: e.tag, text: format!("Schema line {}: {}", index + 1, e.text)})?;
+        if tables.iter().any(|other| other.name == table.name) {
+            return Err(EzError{tag: ErrorTag::Structure, text: format!("Schema line {}: table '{}' is defined twice", index + 1, table.name)})
+        }
+        tables.push(table);
+    }
+
+    Ok(tables)
+}
+
+fn parse_schema_line(line: &str) -> Result<ColumnTable, EzError> {
+
+    let (declaration, header) = match line.split_once(':') {
+        Some(split) => split,
+        None => return Err(EzError{tag: ErrorTag::Structure, text: format!("'{}' needs a colon between the table name and its header", line)}),
+    };
+
+    let (name, flags) = match declaration.split_once('(') {
+        Some((name, flags)) => match flags.trim_end().strip_suffix(')') {
+            Some(flags) => (name.trim(), flags),
+            None => return Err(EzError{tag: ErrorTag::Structure, text: format!("Unclosed flags in '{}'", declaration)}),
+        },
+        None => (declaration.trim(), ""),
+    };
+    if name.is_empty() {
+        return Err(EzError{tag: ErrorTag::Structure, text: "Every table needs a name".to_owned()})
+    }
+
+    let header: BTreeSet<_> = parse_csv_header(header.trim())?.into_iter().collect();
+    let mut table = ColumnTable::blank(&header, KeyString::from_str_checked(name)?, "schema");
+    for flag in flags.split(',').map(str::trim).filter(|flag| !flag.is_empty()) {
+        match flag {
+            "row_ids" => table.add_row_ids()?,
+            "row_timestamps" => table.add_row_timestamps()?,
+            "row_versions" => table.add_row_versions()?,
+            other => return Err(EzError{tag: ErrorTag::Structure, text: format!("Unknown table flag '{}'. Use row_ids, row_timestamps or row_versions", other)}),
+        }
+    }
+
+    Ok(table)
+}
+
+/// Reads and parses a schema file. See parse_schema() for the format.
+pub fn read_schema_file(path: &Path) -> Result<Vec<ColumnTable>, EzError> {
+    let text = std::fs::read_to_string(path)?;
+    parse_schema(&text)
+}
+
+/// The schema file given with --schema or the schema_file setting. Its missing tables are created once recovery has loaded the rest.
+static SCHEMA_FILE: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+pub fn set_schema_file(path: Option<PathBuf>) {
+    *SCHEMA_FILE.write().unwrap() = path;
+}
+
+pub fn schema_file() -> Option<PathBuf> {
+    SCHEMA_FILE.read().unwrap().clone()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_schema() {
+        let text = "# Shop\n\nproducts: id,i-P;name,t-N;price,f-N\norders (row_ids, row_timestamps): id,i-P;product,i-F\n";
+        let tables = parse_schema(text).unwrap();
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables[0].name.as_str(), "products");
+        assert_eq!(tables[0].header.len(), 3);
+        assert!(!tables[0].has_row_ids());
+        assert_eq!(tables[1].name.as_str(), "orders");
+        assert!(tables[1].has_row_ids());
+        assert!(tables[1].has_row_timestamps());
+        assert!(tables.iter().all(|table| table.len() == 0));
+
+        assert!(parse_schema("products id,i-P").is_err());
+        assert!(parse_schema("products (row_ids: id,i-P").is_err());
+        assert!(parse_schema("products (indexed): id,i-P").is_err());
+        assert!(parse_schema("products: name,t-N").is_err());
+        let e = parse_schema("a: id,i-P\na: id,i-P").unwrap_err();
+        assert!(e.text.contains("line 2"));
+    }
+}
@@ -0,0 +1,286 @@
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::db_structure::{ColumnTable, OnConflict, Value};
+use crate::ezql::{execute_EZQL_queries, execute_kv_queries, Condition, KvQuery, OpOrCond, Query, RangeOrListOrAll, StatOp, Statistic, TestOp, STATISTIC_ROWS};
+use crate::paths::{table_file, value_file};
+use crate::database::Database;
+use crate::protocol::QueryOutcome;
+use crate::row_table::TableEngine;
+use crate::utilities::{ksf, ErrorTag, EzError, KeyString};
+
+
+/// Everything the self test creates lives in this namespace so it can never touch user data.
+pub const SELF_TEST_NAMESPACE: &str = "ez_self_test";
+
+const PRODUCTS: &str = "ez_self_test.products";
+const WAREHOUSES: &str = "ez_self_test.warehouses";
+const TEST_VALUE: &str = "ez_self_test.value";
+
+/// The outcome of a single check.
+pub struct CheckResult {
+    pub name: &'static str,
+    pub micros: u128,
+    pub outcome: Result<(), EzError>,
+}
+
+/// What `--self-test` prints. The server exits non-zero unless every check passed.
+pub struct SelfTestReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl SelfTestReport {
+    pub fn failures(&self) -> usize {
+        self.checks.iter().filter(|check| check.outcome.is_err()).count()
+    }
+
+    pub fn passed(&self) -> bool {
+        self.failures() == 0
+    }
+}
+
+impl Display for SelfTestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "EZDB self test:")?;
+        for check in &self.checks {
+            match &check.outcome {
+                Ok(_) => writeln!(f, "  PASS  {:<16} {}us", check.name, check.micros)?,
+                Err(e) => writeln!(f, "  FAIL  {:<16} {}us\n        {}", check.name, check.micros, e)?,
+            }
+        }
+        write!(f, "{} of {} checks passed", self.checks.len() - self.failures(), self.checks.len())
+    }
+}
+
+fn failed(text: String) -> EzError {
+    EzError{tag: ErrorTag::Query, text}
+}
+
+fn expect_rows(table: Option<ColumnTable>, rows: usize) -> Result<ColumnTable, EzError> {
+    match table {
+        Some(table) if table.len() == rows => Ok(table),
+        Some(table) => Err(failed(format!("Expected {} rows but got {}", rows, table.len()))),
+        None => Err(failed(format!("Expected {} rows but got no table", rows))),
+    }
+}
+
+fn check_create(database: &Arc<Database>) -> Result<(), EzError> {
+    let products = ColumnTable::from_csv_string("id,i-P;name,t-N;stock,i-N;warehouse,t-N\n1;hammer;10;north\n2;saw;5;south\n3;drill;0;north", PRODUCTS, "self_test")?;
+    let warehouses = ColumnTable::from_csv_string("warehouse,t-P;city,t-N\nnorth;Akureyri\nsouth;Selfoss", WAREHOUSES, "self_test")?;
+    execute_EZQL_queries(vec![Query::CREATE{table: products, engine: TableEngine::Column}, Query::CREATE{table: warehouses, engine: TableEngine::Column}], database.clone())?;
+
+    if !database.contains_table(ksf(PRODUCTS)) || !database.contains_table(ksf(WAREHOUSES)) {
+        return Err(failed("Created tables are missing from the buffer pool".to_owned()))
+    }
+    Ok(())
+}
+
+fn check_insert(database: &Arc<Database>) -> Result<(), EzError> {
+    let inserts = ColumnTable::from_csv_string("id,i-P;name,t-N;stock,i-N;warehouse,t-N\n4;wrench;7;south", "inserts", "self_test")?;
+    execute_EZQL_queries(vec![Query::INSERT{table_name: ksf(PRODUCTS), inserts, on_conflict: OnConflict::Skip}], database.clone())?;
+    let select_all = Query::SELECT {
+        table_name: ksf(PRODUCTS),
+        primary_keys: RangeOrListOrAll::All,
+        columns: vec![ksf("*")],
+        conditions: Vec::new(),
+        distinct: false,
+        limit: None,
+    };
+    expect_rows(execute_EZQL_queries(vec![select_all], database.clone())?, 4)?;
+    Ok(())
+}
+
+fn check_select(database: &Arc<Database>) -> Result<(), EzError> {
+    let query = Query::SELECT {
+        table_name: ksf(PRODUCTS),
+        primary_keys: RangeOrListOrAll::All,
+        columns: vec![ksf("id"), ksf("stock")],
+        conditions: vec![OpOrCond::Cond(Condition::new("stock", TestOp::Greater, 6)?)],
+        distinct: false,
+        limit: None,
+    };
+    let result = expect_rows(execute_EZQL_queries(vec![query], database.clone())?, 2)?;
+    if result.get_column_int(&ksf("id"))? != &vec![1, 4] {
+        return Err(failed(format!("SELECT returned the wrong rows:\n{}", result)))
+    }
+    Ok(())
+}
+
+/// A query that matches nothing, one on a missing table and one on a missing column each come back as their own outcome.
+fn check_outcomes(database: &Arc<Database>) -> Result<(), EzError> {
+    let select = |table_name: &str, column: &str| -> Result<Query, EzError> {
+        Ok(Query::SELECT {
+            table_name: ksf(table_name),
+            primary_keys: RangeOrListOrAll::All,
+            columns: vec![ksf("*")],
+            conditions: vec![OpOrCond::Cond(Condition::new(column, TestOp::Greater, 1000)?)],
+            distinct: false,
+            limit: None,
+        })
+    };
+    let outcome = |query| QueryOutcome::from_result(execute_EZQL_queries(vec![query], database.clone()));
+
+    match outcome(select(PRODUCTS, "stock")?)? {
+        QueryOutcome::Empty(table) if table.header.len() == 4 => (),
+        other => return Err(failed(format!("A SELECT matching no rows should be Empty but was {:?}", other))),
+    }
+    for query in [select("ez_self_test.missing", "stock")?, select(PRODUCTS, "missing")?] {
+        match outcome(query)? {
+            QueryOutcome::NotFound(_) => (),
+            other => return Err(failed(format!("A SELECT on something missing should be NotFound but was {:?}", other))),
+        }
+    }
+    Ok(())
+}
+
+fn check_join(database: &Arc<Database>) -> Result<(), EzError> {
+    let query = Query::LEFT_JOIN {
+        left_table_name: ksf(PRODUCTS),
+        right_table_name: ksf(WAREHOUSES),
+        match_columns: (ksf("warehouse"), ksf("warehouse")),
+        primary_keys: RangeOrListOrAll::All,
+    };
+    let result = expect_rows(execute_EZQL_queries(vec![query], database.clone())?, 4)?;
+    if result.get_column_text(&ksf("city"))?[3] != ksf("Selfoss") {
+        return Err(failed(format!("LEFT_JOIN matched the wrong rows:\n{}", result)))
+    }
+    Ok(())
+}
+
+fn check_summary(database: &Arc<Database>) -> Result<(), EzError> {
+    let query = Query::SUMMARY {
+        table_name: ksf(PRODUCTS),
+        columns: vec![Statistic{column: ksf("stock"), actions: [StatOp::SUM].into_iter().collect()}],
+    };
+    let result = expect_rows(execute_EZQL_queries(vec![query], database.clone())?, STATISTIC_ROWS)?;
+    let sum = result.get_column_int(&ksf("stock"))?[0];
+    if sum != 22 {
+        return Err(failed(format!("SUM of stock should be 22 but was {}", sum)))
+    }
+    Ok(())
+}
+
+fn check_kv(database: &Arc<Database>) -> Result<(), EzError> {
+    let key = ksf(TEST_VALUE);
+    let queries = vec![
+        KvQuery::Create(key, vec![1, 2, 3]),
+        KvQuery::Update(key, vec![4, 5, 6]),
+        KvQuery::Read(key),
+    ];
+    let mut results = execute_kv_queries(queries, database.clone());
+    let read = match results.pop() {
+        Some(read) => read?,
+        None => return Err(failed("KV batch returned no results".to_owned())),
+    };
+    for result in results {
+        result?;
+    }
+    match read {
+        Some(value) if value.body == vec![4, 5, 6] => Ok(()),
+        other => Err(failed(format!("KV read returned {:?} instead of the updated value", other.map(|v| v.body)))),
+    }
+}
+
+/// Writes the table and value through the same files the maintenance thread uses and reads them back.
+fn check_backup_restore(database: &Arc<Database>) -> Result<(), EzError> {
+    let table = match database.buffer_pool.tables.read().unwrap().get(&ksf(PRODUCTS)) {
+        Some(table) => Arc::clone(&table.read().unwrap()),
+        None => return Err(failed(format!("No table named '{}'", PRODUCTS))),
+    };
+    let table_path = table_file(PRODUCTS);
+    std::fs::write(&table_path, table.to_binary())?;
+    let restored = ColumnTable::from_binary(Some(PRODUCTS), &std::fs::read(&table_path)?);
+    std::fs::remove_file(&table_path)?;
+    if restored? != *table {
+        return Err(failed("Restored table does not match the original".to_owned()))
+    }
+
+    let value = match database.buffer_pool.values.read().unwrap().get(&ksf(TEST_VALUE)) {
+        Some(value) => value.clone(),
+        None => return Err(failed(format!("No value named '{}'", TEST_VALUE))),
+    };
+    let value_path = value_file(TEST_VALUE);
+    std::fs::write(&value_path, value.write_to_binary())?;
+    let restored = Value::from_binary(TEST_VALUE, &std::fs::read(&value_path)?);
+    std::fs::remove_file(&value_path)?;
+    if restored?.body != value.body {
+        return Err(failed("Restored value does not match the original".to_owned()))
+    }
+    Ok(())
+}
+
+/// Removes everything in the self test namespace, including leftovers from an earlier run that crashed.
+fn cleanup(database: &Arc<Database>) -> Result<(), EzError> {
+    for name in [PRODUCTS, WAREHOUSES] {
+        if database.contains_table(ksf(name)) {
+            execute_EZQL_queries(vec![Query::DROP{table_name: ksf(name)}], database.clone())?;
+        }
+        database.buffer_pool.table_delete_list.write().unwrap().remove(&ksf(name));
+        database.buffer_pool.table_naughty_list.write().unwrap().remove(&ksf(name));
+        if table_file(name).exists() {
+            std::fs::remove_file(table_file(name))?;
+        }
+    }
+
+    let key = KeyString::from(TEST_VALUE);
+    if database.buffer_pool.values.read().unwrap().contains_key(&key) {
+        database.buffer_pool.remove_value(&key)?;
+    }
+    database.buffer_pool.value_delete_list.write().unwrap().remove(&key);
+    database.buffer_pool.value_naughty_list.write().unwrap().remove(&key);
+    if value_file(TEST_VALUE).exists() {
+        std::fs::remove_file(value_file(TEST_VALUE))?;
+    }
+
+    if database.contains_table(ksf(PRODUCTS)) || database.contains_table(ksf(WAREHOUSES)) {
+        return Err(failed("Scratch tables are still in the buffer pool".to_owned()))
+    }
+    Ok(())
+}
+
+/// One functional check of the self test.
+type Check = fn(&Arc<Database>) -> Result<(), EzError>;
+
+/// Runs every functional check against the storage directories of the given database.
+/// Checks run in order and later checks use what earlier ones created.
+pub fn run_self_test(database: Arc<Database>) -> SelfTestReport {
+    println!("calling: run_self_test()");
+
+    let checks: [(&'static str, Check); 10] = [
+        ("prepare", cleanup),
+        ("create", check_create),
+        ("insert", check_insert),
+        ("select", check_select),
+        ("outcomes", check_outcomes),
+        ("join", check_join),
+        ("summary", check_summary),
+        ("kv", check_kv),
+        ("backup_restore", check_backup_restore),
+        ("cleanup", cleanup),
+    ];
+
+    let mut results = Vec::with_capacity(checks.len());
+    for (name, check) in checks {
+        let start = Instant::now();
+        let outcome = check(&database);
+        results.push(CheckResult{name, micros: start.elapsed().as_micros(), outcome});
+    }
+
+    SelfTestReport{checks: results}
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test() {
+        let database = Arc::new(Database::init().unwrap());
+        let report = run_self_test(database.clone());
+        println!("{}", report);
+        assert!(report.passed());
+        assert!(!database.contains_table(ksf(PRODUCTS)));
+    }
+}
@@ -1,24 +1,42 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::os::fd::{AsFd, AsRawFd, FromRawFd};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
+use std::time::Duration;
 use std::str::{self};
 use std::convert::{TryFrom, From};
 
-use ezcbor::cbor::{decode_cbor, Cbor};
-use eznoise::{Connection, KeyPair};
+use eznoise::KeyPair;
 use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
 
-use crate::auth::{check_kv_permission, check_permission, user_has_permission, Permission, User};
-use crate::disk_utilities::{BufferPool, MAX_BUFFERPOOL_SIZE};
-use crate::ezql::{execute_EZQL_queries, execute_kv_queries, parse_kv_queries_from_binary, parse_queries_from_binary};
-use crate::logging::Logger;
+use crate::alloc_stats::{self, alloc_stats_table};
+use crate::auth::{add_user, change_password, check_kv_permission, check_ownership, check_permission, remove_user, user_has_permission, Permission, User};
+use crate::database::{interior_log, Database};
+use crate::disk_utilities::value_compaction_table;
+use crate::ezql::{KeyList, KvQuery, Query, execute_EZQL_queries, execute_kv_queries, execute_write_queries, expand_table_globs, is_write_batch};
+use crate::maintenance::TaskKind;
+use crate::metrics::render_metrics;
+use crate::backup::Backup;
+use crate::cancellation::check_cancelled;
+use crate::config::{log, set_listen_address, LogLevel, ServerConfig, RUNTIME_SETTINGS};
+use crate::shutdown::{flush_for_shutdown, install_signal_handlers, request_shutdown, shutdown_requested, SHUTDOWN_DRAIN_SECS};
+use crate::blob_store::{tables_referencing, BlobRef};
+use crate::namespaces::{check_quota, namespace_of, namespaces_table, NamespaceQuota};
 use crate::query_execution::StreamBuffer;
-use crate::thread_pool::{initialize_thread_pool, Job};
-use crate::utilities::{authenticate_client, KeyString, ksf, kv_query_results_to_binary, read_known_length, u64_from_le_slice, ErrorTag, EzError, Instruction};
-use crate::db_structure::Value;
-use crate::PATH_SEP;
+use crate::row_table::TableEngine;
+use crate::thread_pool::{initialize_thread_pool, thread_pool_size, Job, ThreadHandler};
+use crate::utilities::{authenticate_client, get_current_time, KeyString, ksf, u64_from_le_slice, ErrorTag, EzError, Instruction};
+use crate::db_structure::{check_nan_ingest, column_table_binary_len, ColumnTable, Metadata};
+use crate::protocol::{encode_ack, encode_blob_ref, encode_cursor_opened, encode_error, encode_kv_results, encode_page, Request};
+use crate::prepared::handles_to_table;
+use crate::tagging::resolve_tag;
+use crate::paths::raw_values_dir;
+use crate::partitions::{partition_names, partitions_table};
+use crate::system_tables::materialize_system_table;
+use crate::transport::{ServerTransport, Transport};
+use crate::trash::{list_trash, purge_trash, trash_table};
+use crate::write_versions::version_wait_ms;
 
 pub const INSTRUCTION_LENGTH: usize = 284;
 pub const CONFIG_FOLDER: &str = "EZconfig/";
@@ -53,60 +71,6 @@ impl StreamStatus {
     }
 }
 
-pub struct Database {
-    pub buffer_pool: BufferPool,
-    pub users: Arc<RwLock<BTreeMap<KeyString, RwLock<User>>>>,
-    pub logger: Logger,
-}
-
-impl Database {
-    pub fn init() -> Result<Database, EzError> {
-        println!("calling: Database::init()");
-
-        if !std::path::Path::new("EZconfig").is_dir() {
-            println!("config does not exist");
-            std::fs::create_dir("EZconfig").expect("Need IO access to initialize database");
-            std::fs::create_dir("EZconfig/raw_tables").expect("Need IO access to initialize database");
-            std::fs::create_dir("EZconfig/raw_values").expect("Need IO access to initialize database");
-            std::fs::create_dir("EZconfig/log").expect("Need IO access to initialize database");
-        } else {
-            println!("config folder exists");
-        }
-
-        let buffer_pool = BufferPool::empty(std::sync::atomic::AtomicU64::new(MAX_BUFFERPOOL_SIZE));
-        buffer_pool.init_tables(&format!("EZconfig{PATH_SEP}raw_tables"))?;
-        buffer_pool.init_values(&format!("EZconfig{PATH_SEP}raw_values"))?;
-        let path = &format!("EZconfig{PATH_SEP}.users");
-        let mut temp_users = BTreeMap::new();
-        if std::path::Path::new(path).exists() {
-            let temp = std::fs::read(path)?;
-            temp_users = decode_cbor(&temp)?;
-        } else {
-            let mut users_file = std::fs::File::create(path)?;
-            let admin = User::admin("admin", "admin");
-            temp_users.insert(KeyString::from("admin"), admin);
-            users_file.write_all(&temp_users.to_cbor_bytes())?;
-            
-        }
-        let mut users = BTreeMap::new();
-        for (key, value) in temp_users {
-            users.insert(key, RwLock::new(value));
-        }
-        
-        let database = Database {
-            buffer_pool: buffer_pool,
-            users: Arc::new(RwLock::new(users)),
-            logger: Logger::init(),
-        };
-
-        Ok(database)
-    }
-
-    pub fn contains_table(&self, table_name: KeyString) -> bool {
-        self.buffer_pool.tables.read().unwrap().contains_key(&table_name)
-    }
-}
-
 pub fn get_server_static_keys() -> KeyPair {
     KeyPair::random()
 }
@@ -115,16 +79,33 @@ pub fn get_server_static_keys() -> KeyPair {
 /// Also writes tables to disk in a super primitive way. Basically a separate thread writes all the tables to disk
 /// every 10 seconds. This will be improved but I would appreciate some advice here.
 pub fn run_server(address: &str) -> Result<(), EzError> {
+    run_server_with_transport(address, ServerTransport::Noise)
+}
+
+/// Runs the server with clients connecting over the given transport. See transport.rs
+pub fn run_server_with_transport(address: &str, transport: ServerTransport) -> Result<(), EzError> {
     println!("calling: run_server()");
     
-    println!("Initializing database");
+    log(LogLevel::Info, "Initializing database");
     let database = Arc::new(Database::init()?);
+
+    let recovery_db = database.clone();
+    std::thread::spawn(move || {
+        match recovery_db.recover() {
+            Ok(_) => (),
+            Err(e) => {
+                println!("Recovery failed. Server stays in recovery mode and only admits admins");
+                interior_log(e);
+            },
+        }
+    });
     
     let s = get_server_static_keys();
     
-    println!("Starting server...\n###########################");
+    log(LogLevel::Info, "Starting server...\n###########################");
 
-    println!("Binding to address: {address}");
+    log(LogLevel::Info, format_args!("Binding to address: {address} using the {} transport", transport.name()));
+    set_listen_address(address);
     let listener = match TcpListener::bind(address) {
         Ok(value) => value,
         Err(e) => {return Err(EzError{tag: ErrorTag::Io, text: e.kind().to_string()});},
@@ -144,9 +125,13 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
     let mut pending_jobs = HashMap::new();
     let mut read_buffer = [0u8;4096];
 
-    let thread_handler = initialize_thread_pool(8, database.clone());
+    let thread_handler = initialize_thread_pool(thread_pool_size(), database.clone());
+    install_signal_handlers()?;
     
     loop {
+        if shutdown_requested() {
+            break
+        }
         
         let number_of_events = match epoll.wait(&mut events, 5 as u8) {
             Ok(number) => number,
@@ -157,6 +142,7 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
         };
         
         let db_con = database.clone();
+        database.metrics.open_connections.store(stream_statuses.len() as u64, std::sync::atomic::Ordering::Relaxed);
 
         'events: for i in 0..number_of_events {
             if events[i].data() == listener.as_raw_fd() as u64 {
@@ -164,15 +150,31 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
                     Ok((n,m)) => (n, m),
                     Err(e) => return Err(EzError{tag: ErrorTag::Io, text: e.kind().to_string()}),
                 };
-                println!("Accepted connection from: {}", client_address);
+                log(LogLevel::Debug, format_args!("Accepted connection from: {}", client_address));
+                database.metrics.connection_accepted();
                 let key = stream.as_raw_fd() as u64;
+                database.sessions.begin(key);
                 
-                let handshakestate = Some(eznoise::ESTABLISH_CONNECTION_STEP_1(&mut stream, s.clone()).unwrap());
-                let handshakestate = Some(eznoise::ESTABLISH_CONNECTION_STEP_2(&mut stream, handshakestate.unwrap()).unwrap());
-                stream_statuses.insert(key, (StreamStatus::Handshake1, handshakestate));
-
-                epoll.add(stream.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, key)).unwrap();
-                unsigned_streams.insert(key, stream);
+                match &transport {
+                    ServerTransport::Noise => {
+                        let handshakestate = eznoise::ESTABLISH_CONNECTION_STEP_1(&mut stream, s.clone()).unwrap();
+                        let handshakestate = Some(eznoise::ESTABLISH_CONNECTION_STEP_2(&mut stream, handshakestate).unwrap());
+                        stream_statuses.insert(key, (StreamStatus::Handshake1, handshakestate));
+
+                        epoll.add(stream.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, key)).unwrap();
+                        unsigned_streams.insert(key, stream);
+                    },
+                    // The whole TLS handshake happens here so the connection goes straight to authentication
+                    #[cfg(feature = "tls")]
+                    ServerTransport::Tls(config) => match crate::transport::TlsConnection::accept(stream, config.clone()) {
+                        Ok(connection) => {
+                            epoll.add(connection.stream.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, key)).unwrap();
+                            virgin_connections.insert(key, Transport::Tls(Box::new(connection)));
+                            stream_statuses.insert(key, (StreamStatus::Handshake2, None));
+                        },
+                        Err(e) => interior_log(e),
+                    },
+                }
             } else {
                 let fd = events[i].data();
                 match stream_statuses.remove(&fd) {
@@ -181,20 +183,20 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
 
                         },
                         StreamStatus::Handshake1 => {
-                            println!("handshake1");
+                            log(LogLevel::Debug, "handshake1");
                             let stream = unsigned_streams.remove(&fd).unwrap();
                             let connection = eznoise::ESTABLISH_CONNECTION_STEP_3(stream, handshakestate.unwrap()).unwrap();
                             connection.stream.set_nonblocking(true)?;
                             if virgin_connections.contains_key(&fd) {
                                 todo!()
                             } else {
-                                virgin_connections.insert(fd, connection);
+                                virgin_connections.insert(fd, Transport::Noise(connection));
                             }
                             status.bump();
                             stream_statuses.insert(fd, (status, None));
                         },
                         StreamStatus::Handshake2 => {
-                            println!("handshake2");
+                            log(LogLevel::Debug, "handshake2");
                             let inner_db_con = db_con.clone();
                             let connection = virgin_connections.get_mut(&fd).unwrap();
                             match authenticate_client(connection, inner_db_con) {
@@ -211,13 +213,23 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
                             };
                         }
                         StreamStatus::Authenticated => {
-                            println!("Authenticated");
+                            log(LogLevel::Debug, "Authenticated");
                             let mut connection = match virgin_connections.remove(&fd) {
                                 Some(x) => x,
                                 None => panic!("Unexpectedly dropped authenticated client"),
                             };
+                            if !connection.reads_raw_frames() {
+                                match queue_whole_frame(connection, &thread_handler) {
+                                    Ok(_) => {
+                                        status.bump();
+                                        stream_statuses.insert(fd, (status, None));
+                                    },
+                                    Err(e) => interior_log(e),
+                                }
+                                continue 'events
+                            }
                             let mut expected_length_bytes = [0u8;8];
-                            match connection.stream.read_exact(&mut expected_length_bytes) {
+                            match connection.stream().read_exact(&mut expected_length_bytes) {
                                 Ok(_) => (),
                                 Err(e) => println!("Failed to receive command because: {}", e),
                             };
@@ -226,7 +238,13 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
                             let mut total_read = 0;
                             loop {
                                 let to_read = std::cmp::min(4096, expected_length - total_read);
-                                let bytes_received= match connection.stream.read(&mut read_buffer[..to_read]) {
+                                let bytes_received= match connection.stream().read(&mut read_buffer[..to_read]) {
+                                    // The client hung up partway through the message
+                                    Ok(0) if to_read > 0 => {
+                                        pending_jobs.remove(&fd);
+                                        drop(connection);
+                                        continue 'events
+                                    },
                                     Ok(x) => x,
                                     Err(e) => {
                                         match e.kind() {
@@ -248,7 +266,7 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
                             
                             println!("SANITY CHECK!: Expected: {}\t\tTotal_read: {}", expected_length, total_read);
                             if total_read == expected_length {
-                                thread_handler.push_job(Job{connection, data: pending_job});
+                                thread_handler.push_job(Job::new(connection, pending_job));
                                 status.bump();
                             } else {
                                 pending_jobs.insert(fd, (expected_length, total_read, pending_job));
@@ -257,7 +275,7 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
 
                             // match read_known_length(&mut connection.stream) {
                             //     Ok(data) => {
-                            //         thread_handler.push_job(Job{connection, data});
+                            //         thread_handler.push_job(Job::new(connection, data));
                             //     },
                             //     Err(e) => {
                             //         println!("Failed to receive command because: {}", e);
@@ -269,11 +287,26 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
 
                         },
                         StreamStatus::Veteran(_rounds) => {
-                            println!("Veteran");
+                            log(LogLevel::Debug, "Veteran");
                             let mut connection = match thread_handler.open_connections.lock().unwrap().remove(&fd) {
                                 Some(x) => x,
-                                None => panic!("Unexpectedly dropped authenticated client"),
+                                // A worker still holds the connection. If the client hung up the worker
+                                // notices, cancels the request and closes the connection
+                                None => {
+                                    stream_statuses.insert(fd, (status, None));
+                                    continue 'events
+                                },
                             };
+                            if !connection.reads_raw_frames() {
+                                match queue_whole_frame(connection, &thread_handler) {
+                                    Ok(_) => {
+                                        status.bump();
+                                        stream_statuses.insert(fd, (status, None));
+                                    },
+                                    Err(e) => interior_log(e),
+                                }
+                                continue 'events
+                            }
 
                             let (expected_length, mut total_read, mut pending_job) = match pending_jobs.remove(&fd) {
                                 Some(x) => x,
@@ -286,7 +319,13 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
 
                             loop {
                                 let to_read = std::cmp::min(4096, expected_length - total_read);
-                                let bytes_received= match connection.stream.read(&mut read_buffer[..to_read]) {
+                                let bytes_received= match connection.stream().read(&mut read_buffer[..to_read]) {
+                                    // The client hung up partway through the message
+                                    Ok(0) if to_read > 0 => {
+                                        pending_jobs.remove(&fd);
+                                        drop(connection);
+                                        continue 'events
+                                    },
                                     Ok(x) => x,
                                     Err(e) => {
                                         match e.kind() {
@@ -303,7 +342,7 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
                             }
 
                             if total_read == expected_length {
-                                thread_handler.push_job(Job{connection, data: pending_job});
+                                thread_handler.push_job(Job::new(connection, pending_job));
                                 status.bump();
                             } else {
                                 pending_jobs.insert(fd, (expected_length, total_read, pending_job));
@@ -311,7 +350,7 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
                             
                             // match read_known_length(&mut connection.stream) {
                             //     Ok(data) => {
-                            //         thread_handler.push_job(Job{connection, data});
+                            //         thread_handler.push_job(Job::new(connection, data));
                             //     },
                             //     Err(e) => {
                             //         println!("Failed to receive command because: {}", e);
@@ -329,105 +368,545 @@ pub fn run_server(address: &str) -> Result<(), EzError> {
 
     }
 
+    log(LogLevel::Info, "Shutting down. No new connections are accepted");
+    drop(listener);
+    let drained = thread_handler.drain(std::time::Duration::from_secs(SHUTDOWN_DRAIN_SECS));
+    let summary = flush_for_shutdown(&database, drained)?;
+    log(LogLevel::Info, summary);
+
+    Ok(())
+}
+
+/// Reads a frame of a transport the loop can't read in pieces, see Transport::reads_raw_frames(), and queues it.
+fn queue_whole_frame(mut connection: Transport, thread_handler: &ThreadHandler) -> Result<(), EzError> {
+    let data = connection.receive_from_client()?;
+    thread_handler.push_job(Job::new(connection, data));
+    Ok(())
+}
+
+/// Carries out a request from an authenticated client. The health check and admission are handled by the thread pool
+/// before this is called. See protocol::Request for the layout of every message.
+pub fn answer_request(request: Request, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+    match request {
+        Request::Health => db_ref.admission.to_table().map(|table| table.to_binary()),
+        Request::Query(queries) => answer_query(queries, connection, db_ref),
+        Request::TaggedQuery{tag, queries} => answer_tagged_query(tag, queries, connection, db_ref),
+        Request::AtVersion{version, queries} => answer_query_at_version(version, queries, connection, db_ref),
+        Request::Tag(tag) => answer_set_tag(tag, connection, db_ref),
+        Request::Admin{command, args} => perform_administration(command, &args, connection, db_ref),
+        Request::KvQuery(queries) => answer_kv_query(queries, connection, db_ref),
+        Request::KvBatch(queries) => answer_kv_batch(queries, connection, db_ref),
+        Request::BulkLoad{table_name, table} => answer_bulk_load(table_name, &table, connection, db_ref),
+        Request::BlobPut(content) => answer_blob_put(&content, connection, db_ref),
+        Request::BlobGet(reference) => answer_blob_get(reference, connection, db_ref),
+        Request::OpenCursor(queries) => answer_open_cursor(queries, connection, db_ref),
+        Request::FetchPage{cursor_id, max_rows} => answer_fetch_page(cursor_id, max_rows, connection, db_ref),
+        Request::CloseCursor{cursor_id} => answer_close_cursor(cursor_id, connection, db_ref),
+    }
+}
+
+pub fn answer_query(queries: Vec<Query>, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+
+    answer_query_with_tag(queries, None, connection, db_ref)
+}
+
+/// Same as answer_query() but the batch is attributed to the given tag instead of the connection tag.
+pub fn answer_tagged_query(tag: KeyString, queries: Vec<Query>, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+
+    answer_query_with_tag(queries, Some(tag), connection, db_ref)
+}
+
+/// Same as answer_query() but only once the server has applied the given write version. See write_versions.rs
+pub fn answer_query_at_version(version: u64, queries: Vec<Query>, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+
+    db_ref.versions.wait_for(version, Duration::from_millis(version_wait_ms()))?;
+    answer_query_with_tag(queries, None, connection, db_ref)
 }
 
-pub fn answer_query(binary: &[u8], connection: &mut Connection, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+fn answer_query_with_tag(queries: Vec<Query>, batch_tag: Option<KeyString>, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
 
     let mut streambuffer = StreamBuffer::new(connection);
 
-    let queries = parse_queries_from_binary(&binary)?;
+    if queries.iter().any(|query| matches!(query, Query::PREPARE{..})) {
+        return answer_prepare(queries, connection, &db_ref)
+    }
+    let connection_id = connection.connection_id();
+    let mut queries = db_ref.sessions.with(connection_id, |session| session.prepared.bind_batch(queries))?;
+    let tag = resolve_tag(db_ref.sessions.with(connection_id, |session| session.tag), batch_tag);
+    log(LogLevel::Debug, format_args!("Query batch from '{}' tagged '{}'", connection.peer(), tag));
+
+    expand_table_globs(&mut queries, &db_ref)?;
+    check_permission(&queries, connection.peer(), db_ref.users.clone())?;
+    check_ownership(&queries, connection.peer(), db_ref.users.clone(), |table_name| db_ref.buffer_pool.table_owner(table_name))?;
+
+    // Tables are credited to the user that created them, whatever the client wrote in the header
+    for query in queries.iter_mut() {
+        match query {
+            Query::CREATE{table, ..} => table.metadata = Metadata::new(connection.peer()),
+            Query::CREATE_FROM_SCHEMA{tables} => for table in tables.iter_mut() {
+                table.metadata = Metadata::new(connection.peer());
+            },
+            _ => (),
+        }
+    }
 
-    check_permission(&queries, connection.peer.as_str(), db_ref.users.clone())?;
-    let requested_table = match execute_EZQL_queries(queries, db_ref) {
-        Ok(res) => match res {
-            Some(table) => table.to_binary(),
-            None => "None.".as_bytes().to_vec(),
-        },
-        Err(e) => format!("ERROR -> Could not process query because of error: '{}'", e.to_string()).as_bytes().to_vec(),
+    let mut namespaces = Vec::new();
+    for query in &queries {
+        match query {
+            Query::INNER_JOIN | Query::RIGHT_JOIN | Query::FULL_JOIN => (),
+            other => namespaces.push(namespace_of(&other.get_table_name())),
+        }
+    }
+    namespaces.sort();
+    namespaces.dedup();
+    let query_types: Vec<&'static str> = queries.iter().map(Query::type_name).collect();
+
+    check_cancelled()?;
+    let start = std::time::Instant::now();
+    let batch = db_ref.locks.begin_batch(connection.peer());
+    let mut failed = false;
+    let requested_table = if is_write_batch(&queries) {
+        let ack = execute_write_queries(queries, db_ref.clone());
+        if let Some(e) = &ack.error {
+            failed = true;
+            println!("Query batch tagged '{}' failed: {}", tag, e);
+            // Nobody is left to read the ack
+            if e.tag == ErrorTag::Cancelled {
+                drop(batch);
+                db_ref.tags.record(tag, start.elapsed().as_micros() as u64, true);
+                return Err(e.clone())
+            }
+        }
+        ack.to_binary()
+    } else {
+        match execute_EZQL_queries(queries, db_ref.clone()) {
+            Ok(res) => match res {
+                Some(table) => table.to_binary(),
+                None => encode_ack(),
+            },
+            Err(e) if e.tag == ErrorTag::Cancelled => {
+                drop(batch);
+                println!("Query batch tagged '{}' was cancelled: {}", tag, e);
+                db_ref.tags.record(tag, start.elapsed().as_micros() as u64, true);
+                return Err(e)
+            },
+            Err(e) => {
+                failed = true;
+                println!("Query batch tagged '{}' failed: {}", tag, e);
+                encode_error(&e)
+            },
+        }
     };
+    drop(batch);
+    let latency = start.elapsed().as_micros() as u64;
+    db_ref.tags.record(tag, latency, failed);
+    db_ref.metrics.record_batch(&query_types, latency, failed);
+    for namespace in namespaces {
+        db_ref.namespaces.record_query(namespace, latency);
+    }
 
     Ok(requested_table)
 }
 
-pub fn answer_kv_query(binary: &[u8], connection: &mut Connection, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+/// Registers every query of a PREPARE batch for the connection. A batch that prepares can't do anything else.
+/// The response is a table with the handle and the number of parameters of each query in the order they were sent.
+fn answer_prepare(queries: Vec<Query>, connection: &mut Transport, db_ref: &Database) -> Result<Vec<u8>, EzError> {
+    println!("calling: answer_prepare()");
 
-    let queries = parse_kv_queries_from_binary(&binary)?;
+    let mut handles = Vec::with_capacity(queries.len());
+    for query in queries {
+        let query = match query {
+            Query::PREPARE { query } => *query,
+            _ => return Err(EzError{tag: ErrorTag::Query, text: "A batch that prepares queries can't contain other queries".to_owned()}),
+        };
+        check_permission(std::slice::from_ref(&query), connection.peer(), db_ref.users.clone())?;
+        check_ownership(std::slice::from_ref(&query), connection.peer(), db_ref.users.clone(), |table_name| db_ref.buffer_pool.table_owner(table_name))?;
+        handles.push(db_ref.sessions.with(connection.connection_id(), |session| session.prepared.prepare(query))?);
+    }
 
-    check_kv_permission(&queries, connection.peer.as_str(), db_ref.users.clone())?;
-    let query_results: Vec<Result<Option<crate::db_structure::Value>, EzError>> = execute_kv_queries(queries, db_ref);
+    Ok(handles_to_table(&handles)?.to_binary())
+}
 
-    let mut binary = kv_query_results_to_binary(&query_results);
-    
+pub fn answer_kv_query(queries: Vec<KvQuery>, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+
+    check_kv_permission(&queries, connection.peer(), db_ref.users.clone())?;
+    let tag = resolve_tag(db_ref.sessions.with(connection.connection_id(), |session| session.tag), None);
+    let query_types: Vec<&'static str> = queries.iter().map(KvQuery::type_name).collect();
+    let start = std::time::Instant::now();
+    let query_results: Vec<Result<Option<crate::db_structure::Value>, EzError>> = execute_kv_queries(queries, db_ref.clone());
+    let latency = start.elapsed().as_micros() as u64;
+    let failed = query_results.iter().any(|result| result.is_err());
+    db_ref.tags.record(tag, latency, failed);
+    db_ref.metrics.record_batch(&query_types, latency, failed);
 
-    Ok(binary)
+    Ok(encode_kv_results(&query_results))
 
 }
 
-pub fn perform_administration(binary: &[u8], db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
-    todo!()
+/// Applies a batch of KV creates, updates and deletes all together or not at all. The answer is the same
+/// frame as KVQUERY with one result per query. If the batch fails, every query carries the error which
+/// names the query that failed.
+pub fn answer_kv_batch(queries: Vec<KvQuery>, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+    println!("calling: answer_kv_batch()");
+
+    check_kv_permission(&queries, connection.peer(), db_ref.users.clone())?;
+    let tag = resolve_tag(db_ref.sessions.with(connection.connection_id(), |session| session.tag), None);
+    let start = std::time::Instant::now();
+    let query_results: Vec<Result<Option<crate::db_structure::Value>, EzError>> = match db_ref.buffer_pool.apply_value_batch(&queries) {
+        Ok(results) => results.into_iter().map(Ok).collect(),
+        Err(e) => queries.iter().map(|_| Err(e.clone())).collect(),
+    };
+    let latency = start.elapsed().as_micros() as u64;
+    let failed = query_results.iter().any(|result| result.is_err());
+    db_ref.tags.record(tag, latency, failed);
+    let query_types: Vec<&'static str> = queries.iter().map(KvQuery::type_name).collect();
+    db_ref.metrics.record_batch(&query_types, latency, failed);
+
+    Ok(encode_kv_results(&query_results))
 }
 
-pub fn perform_maintenance(db_ref: Arc<Database>) -> Result<(), EzError> {
+/// Loads a table straight from the EZ binary column layout, skipping csv entirely.
+/// The name in the message overrides the name in the binary.
+/// The table is validated and sorted before it is added to the buffer pool.
+pub fn answer_bulk_load(table_name: KeyString, table_binary: &[u8], connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+    println!("calling: answer_bulk_load()");
+
+    {
+        let users = db_ref.users.read().unwrap();
+        let allowed = match users.get(&KeyString::from(connection.peer())) {
+            Some(user) => {
+                let user = user.read().unwrap();
+                user.admin || user.can_upload
+            },
+            None => false,
+        };
+        if !allowed {
+            return Err(EzError{tag: ErrorTag::Authentication, text: format!("User '{}' does not have permission to upload tables", connection.peer())})
+        }
+    }
 
-    println!("Current tables:");
-    for table in db_ref.buffer_pool.tables.read().unwrap().keys() {
-        println!("{}", table);
+    let expected_len = column_table_binary_len(table_binary)?;
+    if expected_len != table_binary.len() {
+        return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Bulk load table should be {} bytes according to its header but is {} bytes", expected_len, table_binary.len())})
     }
-    println!("Background thread still running");
-    println!("{:?}", db_ref.buffer_pool.table_delete_list.read().unwrap());
-    for key in db_ref.buffer_pool.table_delete_list.read().unwrap().iter() {
-        println!("KEY: {}", key);
-        match std::fs::remove_file(format!("EZconfig{PATH_SEP}raw_tables{PATH_SEP}{}", key.as_str())) {
-            Ok(_) => (),
-            Err(e) => println!("LINE: {} - ERROR: {}", line!(), e),
-        }
-        
+
+    let mut table = ColumnTable::from_binary(Some(table_name.as_str()), table_binary)?;
+    table.validate_and_sort()?;
+    check_nan_ingest(&table)?;
+    table.metadata = Metadata::new(connection.peer());
+
+    let query = Query::CREATE{table, engine: TableEngine::Column};
+    check_quota(&query, &db_ref)?;
+    match query {
+        Query::CREATE{table, ..} => db_ref.buffer_pool.add_table(table)?,
+        _ => unreachable!("Constructed above"),
     }
-    println!("{:?}", db_ref.buffer_pool.table_delete_list.read().unwrap());
-    db_ref.buffer_pool.table_delete_list.write().unwrap().clear();
 
+    Ok(encode_ack())
+}
 
-    for key in db_ref.buffer_pool.value_delete_list.write().unwrap().iter() {
-        match std::fs::remove_file(format!("EZconfig{PATH_SEP}raw_values{PATH_SEP}{}", key.as_str())) {
-            Ok(_) => (),
-            Err(e) => println!("LINE: {} - ERROR: {}", line!(), e),
+/// Stores an attachment in the blob store. The whole message is the content.
+/// Responds with the Text cell value that links a row to the blob.
+pub fn answer_blob_put(binary: &[u8], connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+    println!("calling: answer_blob_put()");
+
+    {
+        let users = db_ref.users.read().unwrap();
+        let allowed = match users.get(&KeyString::from(connection.peer())) {
+            Some(user) => {
+                let user = user.read().unwrap();
+                user.admin || user.can_upload
+            },
+            None => false,
+        };
+        if !allowed {
+            return Err(EzError{tag: ErrorTag::Authentication, text: format!("User '{}' does not have permission to upload blobs", connection.peer())})
         }
     }
-    db_ref.buffer_pool.value_delete_list.write().unwrap().clear();
-
-    for (key, table_lock) in db_ref.buffer_pool.tables.read().unwrap().iter() {
-        println!("key: {}", key);
-        let mut table_naughty_list = db_ref.buffer_pool.table_naughty_list.write().unwrap();
-        if table_naughty_list.contains(key) {
-            let mut file = match std::fs::File::create(format!("EZconfig{PATH_SEP}raw_tables{PATH_SEP}{}", key.as_str())) {
-                Ok(file) => file,
-                Err(e) => {
-                    println!("LINE: {} - ERROR: {}", line!(), e);
-                    continue
-                },
-            };
-            file.write(&table_lock.read().unwrap().to_binary()).expect(&format!("Panic of line: {} of server_networking. The backup file could not be written.", line!()));
-            table_naughty_list.remove(key);
+
+    let blob = db_ref.blobs.put(binary)?;
+
+    Ok(encode_blob_ref(&blob.to_cell()))
+}
+
+/// Fetches an attachment by the reference stored in the table cell.
+/// The user needs the permission a SELECT would need on one of the tables that reference the blob.
+pub fn answer_blob_get(cell: KeyString, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+    println!("calling: answer_blob_get()");
+
+    let blob = match BlobRef::from_cell(&cell) {
+        Some(blob) => blob,
+        None => return Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is not a blob reference", cell)}),
+    };
+
+    let is_admin = db_ref.users.read().unwrap().get(&KeyString::from(connection.peer())).is_some_and(|user| user.read().unwrap().admin);
+    if !is_admin {
+        let allowed = tables_referencing(&blob, &db_ref)?.iter()
+            .any(|table_name| check_permission(&[Query::new_select(table_name.as_str())], connection.peer(), db_ref.users.clone()).is_ok());
+        if !allowed {
+            return Err(EzError{tag: ErrorTag::Authentication, text: format!("User '{}' can't read any table that references blob '{}'", connection.peer(), blob.id)})
         }
     }
-    
-    for (key, value) in db_ref.buffer_pool.values.read().unwrap().iter() {
-        let mut value_naughty_list = db_ref.buffer_pool.value_naughty_list.write().unwrap();
-        if value_naughty_list.contains(key) {
-            let mut file = std::fs::File::create(format!("EZconfig{PATH_SEP}raw_values{PATH_SEP}{}", key.as_str())).expect(&format!("Panic of line: {} of server_networking. The backup file could not be created.", line!()));
-            file.write(&value.write_to_binary()).expect(&format!("Panic of line: {} of server_networking. The backup file could not be written.", line!()));
-            value_naughty_list.remove(key);
+
+    db_ref.blobs.get(&blob)
+}
+
+/// Runs a batch like answer_query() but holds the resulting table on the server instead of sending it.
+/// The client reads it with FETCHPAGE. The response is [cursor id: u64][rows in the result: u64].
+pub fn answer_open_cursor(queries: Vec<Query>, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+    println!("calling: answer_open_cursor()");
+
+    let connection_id = connection.connection_id();
+    let mut queries = db_ref.sessions.with(connection_id, |session| session.prepared.bind_batch(queries))?;
+    expand_table_globs(&mut queries, &db_ref)?;
+    check_permission(&queries, connection.peer(), db_ref.users.clone())?;
+    check_ownership(&queries, connection.peer(), db_ref.users.clone(), |table_name| db_ref.buffer_pool.table_owner(table_name))?;
+    for query in queries.iter_mut() {
+        match query {
+            Query::CREATE{table, ..} => table.metadata = Metadata::new(connection.peer()),
+            Query::CREATE_FROM_SCHEMA{tables} => for table in tables.iter_mut() {
+                table.metadata = Metadata::new(connection.peer());
+            },
+            _ => (),
         }
     }
+    if is_write_batch(&queries) {
+        return Err(EzError{tag: ErrorTag::Query, text: "Cursors hold the table a batch returns. A batch of writes returns none".to_owned()})
+    }
 
-    Ok(())
+    let tag = resolve_tag(db_ref.sessions.with(connection_id, |session| session.tag), None);
+    let start = std::time::Instant::now();
+    let batch = db_ref.locks.begin_batch(connection.peer());
+    let result = execute_EZQL_queries(queries, db_ref.clone());
+    drop(batch);
+    db_ref.tags.record(tag, start.elapsed().as_micros() as u64, result.is_err());
+
+    let table = match result? {
+        Some(table) => table,
+        None => return Err(EzError{tag: ErrorTag::Query, text: "The batch did not return a table to page through".to_owned()}),
+    };
+    let (cursor_id, rows) = db_ref.sessions.with(connection_id, |session| session.cursors.open(table))?;
+
+    Ok(encode_cursor_opened(cursor_id, rows))
+}
+
+/// The next page of an open cursor, 0 max rows meaning the default page size.
+/// The response is [rows left after this page: u64][page: EZ binary table]. The cursor is closed after its last page.
+pub fn answer_fetch_page(cursor_id: u64, max_rows: u64, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+
+    let (page, remaining) = db_ref.sessions.with(connection.connection_id(), |session| session.cursors.fetch(cursor_id, max_rows))?;
+
+    Ok(encode_page(&page, remaining))
+}
+
+/// Drops an open cursor before all of its pages have been fetched.
+pub fn answer_close_cursor(cursor_id: u64, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+
+    db_ref.sessions.with(connection.connection_id(), |session| session.cursors.close(cursor_id))?;
+
+    Ok(encode_ack())
 }
 
-pub fn interior_log(e: EzError) {
-    println!("{}", e);
+/// Tags all following work on this connection, for example with the name of the calling service.
+/// An empty tag clears it.
+pub fn answer_set_tag(tag: KeyString, connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+    println!("calling: answer_set_tag()");
+
+    db_ref.sessions.with(connection.connection_id(), |session| session.tag = Some(tag).filter(|tag| !tag.as_str().is_empty()));
+
+    Ok(encode_ack())
 }
 
+/// Carries out an administrative command. Only admins may send these.
+/// The arguments of each command follow it in the message:
+///  - TASK_LIST
+///  - TASK_START [kind: 64 bytes][target table: 64 bytes]
+///  - TASK_PAUSE / TASK_RESUME / TASK_CANCEL [id: u64]
+///  - NAMESPACE_QUOTA [namespace: 64 bytes][max_bytes: u64][max_tables: u64] (0 means unlimited)
+///  - TRANSFER_OWNERSHIP [table: 64 bytes][new owner: 64 bytes]
+///  - USER_ADD [username: 64 bytes][admin: u8][password: the rest]
+///  - USER_REMOVE [username: 64 bytes]
+///  - USER_PASSWORD [username: 64 bytes][password: the rest]
+///  - METRICS
+///  - BACKUP
+///  - TRASH_LIST
+///  - UNDROP [table: 64 bytes]
+///  - PURGE_TRASH [table: 64 bytes] (no table purges the whole trash)
+///  - PARTITION_TABLE [table: 64 bytes][bounds: a key list, see KeyList::write_binary()]
+///  - CONFIG
+///  - CONFIG_SET [setting: 64 bytes][value: the rest]
+///  - SHUTDOWN
+///
+/// All task commands respond with the current task list as a table.
+/// NAMESPACE_QUOTA responds with the ez_namespaces system table and TRANSFER_OWNERSHIP with the ez_tables system table.
+/// The user commands save the users to disk and respond with the ez_users system table.
+/// METRICS responds with the Prometheus text from metrics::render_metrics() instead of a table.
+/// BACKUP responds with a backup archive of the whole database. See backup::Backup::take().
+/// The trash commands respond with what is left in the trash. See trash.rs
+/// PARTITION_TABLE responds with the partitions of the table and how many rows each holds. See partitions.rs
+/// The config commands respond with the settings the server runs with. Only the settings in RUNTIME_SETTINGS
+/// can be set. See config.rs
+/// SHUTDOWN responds with an ack and the server shuts down once the queries it is running finish. See shutdown.rs
+pub fn perform_administration(command: KeyString, args: &[u8], connection: &mut Transport, db_ref: Arc<Database>) -> Result<Vec<u8>, EzError> {
+    println!("calling: perform_administration()");
+
+    {
+        let users = db_ref.users.read().unwrap();
+        let is_admin = match users.get(&KeyString::from(connection.peer())) {
+            Some(user) => user.read().unwrap().admin,
+            None => false,
+        };
+        if !is_admin {
+            return Err(EzError{tag: ErrorTag::Authentication, text: format!("User '{}' is not an admin", connection.peer())})
+        }
+    }
+
+    let task_id = || -> Result<u64, EzError> {
+        if args.len() < 8 {
+            return Err(EzError{tag: ErrorTag::Instruction, text: format!("'{}' requires a task id", command)})
+        }
+        Ok(u64_from_le_slice(&args[0..8]))
+    };
 
+    match command.as_str() {
+        "TASK_LIST" => (),
+        "TASK_START" => {
+            if args.len() < 128 {
+                return Err(EzError{tag: ErrorTag::Instruction, text: "'TASK_START' requires a task kind and a target".to_owned()})
+            }
+            let kind = TaskKind::from_name(&KeyString::try_from(&args[0..64])?, KeyString::try_from(&args[64..128])?)?;
+            db_ref.tasks.start(kind)?;
+        },
+        "TASK_PAUSE" => db_ref.tasks.pause(task_id()?)?,
+        "TASK_RESUME" => db_ref.tasks.resume(task_id()?)?,
+        "TASK_CANCEL" => db_ref.tasks.cancel(task_id()?)?,
+        "NAMESPACE_QUOTA" => {
+            if args.len() < 80 {
+                return Err(EzError{tag: ErrorTag::Instruction, text: "'NAMESPACE_QUOTA' requires a namespace, max_bytes, and max_tables".to_owned()})
+            }
+            let namespace = KeyString::try_from(&args[0..64])?;
+            let quota = NamespaceQuota {
+                max_bytes: u64_from_le_slice(&args[64..72]),
+                max_tables: u64_from_le_slice(&args[72..80]),
+            };
+            db_ref.namespaces.set_quota(namespace, quota)?;
+            return Ok(namespaces_table(&db_ref)?.to_binary())
+        },
+        "TRANSFER_OWNERSHIP" => {
+            if args.len() < 128 {
+                return Err(EzError{tag: ErrorTag::Instruction, text: "'TRANSFER_OWNERSHIP' requires a table and the new owner".to_owned()})
+            }
+            let table_name = KeyString::try_from(&args[0..64])?;
+            let new_owner = KeyString::try_from(&args[64..128])?;
+            if !db_ref.users.read().unwrap().contains_key(&new_owner) {
+                return Err(EzError{tag: ErrorTag::NotFound, text: format!("No user named '{}'", new_owner)})
+            }
+            db_ref.buffer_pool.transfer_ownership(&table_name, new_owner)?;
+            return Ok(materialize_system_table(&ksf("ez_tables"), &db_ref)?.to_binary())
+        },
+        "USER_ADD" | "USER_REMOVE" | "USER_PASSWORD" => {
+            if args.len() < 64 {
+                return Err(EzError{tag: ErrorTag::Instruction, text: format!("'{}' requires a username", command)})
+            }
+            let username = KeyString::try_from(&args[0..64])?;
+            match command.as_str() {
+                "USER_ADD" => {
+                    if args.len() < 66 {
+                        return Err(EzError{tag: ErrorTag::Instruction, text: "'USER_ADD' requires a username, the admin flag and a password".to_owned()})
+                    }
+                    let password = str::from_utf8(&args[65..])?;
+                    let user = match args[64] {
+                        0 => User::new(username.as_str(), password),
+                        _ => User::admin(username.as_str(), password),
+                    };
+                    add_user(&db_ref.users, user)?;
+                },
+                "USER_REMOVE" => {
+                    remove_user(&db_ref.users, username.as_str())?;
+                },
+                _ => {
+                    if args.len() < 65 {
+                        return Err(EzError{tag: ErrorTag::Instruction, text: "'USER_PASSWORD' requires a username and a password".to_owned()})
+                    }
+                    change_password(&db_ref.users, username.as_str(), str::from_utf8(&args[64..])?)?;
+                },
+            }
+            db_ref.save_users()?;
+            return Ok(materialize_system_table(&ksf("ez_users"), &db_ref)?.to_binary())
+        },
+        "ALLOC_STATS" => return Ok(alloc_stats_table()?.to_binary()),
+        // Answers with the stats from before the reset
+        "ALLOC_STATS_RESET" => {
+            let table = alloc_stats_table()?;
+            alloc_stats::reset();
+            return Ok(table.to_binary())
+        },
+        "METRICS" => return Ok(render_metrics(&db_ref).into_bytes()),
+        "BACKUP" => return Ok(Backup::take(&db_ref)?.to_binary()),
+        "COMPACT_VALUES" => {
+            let (before, after) = db_ref.buffer_pool.compact_values(&raw_values_dir())?;
+            return Ok(value_compaction_table(&before, &after)?.to_binary())
+        },
+        "TRASH_LIST" | "UNDROP" | "PURGE_TRASH" => {
+            match command.as_str() {
+                "UNDROP" => {
+                    if args.len() < 64 {
+                        return Err(EzError{tag: ErrorTag::Instruction, text: "'UNDROP' requires a table".to_owned()})
+                    }
+                    db_ref.buffer_pool.undrop_table(KeyString::try_from(&args[0..64])?)?;
+                },
+                "PURGE_TRASH" => {
+                    let table_name = match args.len() >= 64 {
+                        true => Some(KeyString::try_from(&args[0..64])?),
+                        false => None,
+                    };
+                    purge_trash(table_name.as_ref())?;
+                },
+                _ => (),
+            }
+            return Ok(trash_table(&list_trash()?, get_current_time())?.to_binary())
+        },
+        "PARTITION_TABLE" => {
+            if args.len() < 64 {
+                return Err(EzError{tag: ErrorTag::Instruction, text: "'PARTITION_TABLE' requires a table and the partition bounds".to_owned()})
+            }
+            let table_name = KeyString::try_from(&args[0..64])?;
+            db_ref.buffer_pool.partition_table(table_name, KeyList::from_binary(&args[64..])?)?;
+            let map = match db_ref.buffer_pool.partition_map(&table_name) {
+                Some(map) => map,
+                None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("Table '{}' was not partitioned", table_name)}),
+            };
+            let rows = partition_names(&table_name, &(0..map.count()).collect::<Vec<_>>())?
+                .iter()
+                .map(|partition| db_ref.buffer_pool.table_rows(partition).unwrap_or(0))
+                .collect::<Vec<_>>();
+            return Ok(partitions_table(&table_name, &map, &rows)?.to_binary())
+        },
+        "SHUTDOWN" => {
+            log(LogLevel::Info, format_args!("Admin '{}' asked the server to shut down", connection.peer()));
+            request_shutdown();
+            return Ok(encode_ack())
+        },
+        "CONFIG" => return Ok(ServerConfig::current().to_table()?.to_binary()),
+        "CONFIG_SET" => {
+            if args.len() < 64 {
+                return Err(EzError{tag: ErrorTag::Instruction, text: "'CONFIG_SET' requires a setting and a value".to_owned()})
+            }
+            let setting = KeyString::try_from(&args[0..64])?;
+            let mut config = ServerConfig::current();
+            config.set(setting.as_str(), str::from_utf8(&args[64..])?.trim())?;
+            if !RUNTIME_SETTINGS.contains(&setting.as_str()) {
+                return Err(EzError{tag: ErrorTag::Instruction, text: format!("'{}' can only be changed in the config file and takes effect on restart", setting)})
+            }
+            config.apply();
+            db_ref.buffer_pool.set_max_size(config.buffer_pool_bytes);
+            return Ok(config.to_table()?.to_binary())
+        },
+        other => return Err(EzError{tag: ErrorTag::Instruction, text: format!("Admin command: '{}' is not valid", other)}),
+    }
+
+    Ok(db_ref.tasks.to_table()?.to_binary())
+}
 
 /// Parses the inctructions sent by the client. Will be rewritten soon to accomodate EZQL
 pub fn parse_instruction(
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::cursors::Cursors;
+use crate::frame_checksum::FrameChecksum;
+use crate::prepared::PreparedQueries;
+use crate::utilities::KeyString;
+
+
+/// Everything the server keeps for one connection between its requests.
+#[derive(Default)]
+pub struct Session {
+    /// Set with the TAG instruction. See tagging.rs
+    pub tag: Option<KeyString>,
+    /// Agreed on during authentication. See frame_checksum.rs
+    pub checksum: FrameChecksum,
+    pub cursors: Cursors,
+    pub prepared: PreparedQueries,
+}
+
+/// The sessions of the open connections, keyed by connection id. A connection id is the file descriptor
+/// of the socket, which the OS hands out again once a connection closes. So the listener starts a fresh
+/// session on every accept and nothing of an earlier connection carries over.
+pub struct Sessions {
+    sessions: Mutex<HashMap<u64, Session>>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces whatever was kept under the id with an empty session.
+    pub fn begin(&self, connection_id: u64) {
+        self.sessions.lock().unwrap().insert(connection_id, Session::default());
+    }
+
+    /// Runs f on the session of the connection. A connection that was never begun gets an empty one.
+    pub fn with<T>(&self, connection_id: u64, f: impl FnOnce(&mut Session) -> T) -> T {
+        let mut sessions = self.sessions.lock().unwrap();
+        f(sessions.entry(connection_id).or_default())
+    }
+
+    /// Drops the cursors that haven't been used for CURSOR_IDLE_SECS in every session. Returns how many were dropped.
+    pub fn expire_idle_cursors(&self, now: u64) -> usize {
+        self.sessions.lock().unwrap().values_mut().map(|session| session.cursors.expire_idle(now)).sum()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::testing_tools::create_fixed_table;
+    use crate::utilities::ksf;
+
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let sessions = Sessions::new();
+        sessions.with(7, |session| {
+            session.tag = Some(ksf("billing"));
+            session.checksum = FrameChecksum::Crc32;
+        });
+        let (cursor_id, _) = sessions.with(7, |session| session.cursors.open(create_fixed_table(5))).unwrap();
+
+        // Only the connection that opened the cursor can read it
+        assert!(sessions.with(8, |session| session.cursors.fetch(cursor_id, 10)).is_err());
+        assert_eq!(sessions.with(8, |session| session.tag), None);
+
+        // A new connection that gets the same fd starts clean
+        sessions.begin(7);
+        sessions.with(7, |session| {
+            assert_eq!(session.tag, None);
+            assert_eq!(session.checksum, FrameChecksum::None);
+            assert!(session.cursors.fetch(cursor_id, 10).is_err());
+            assert!(session.prepared.is_empty());
+        });
+    }
+}
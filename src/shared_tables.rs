@@ -0,0 +1,419 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::disk_utilities::{is_chunk_manifest, FileMapping};
+use crate::db_structure::{read_enum_values, table_format, Collation, ColumnTable, DbType, HeaderItem, LongTexts, Metadata, TableKey, METADATA_BINARY_SIZE};
+use crate::paths::{config_file, table_file, RAW_TABLES_DIR};
+use crate::utilities::{f32_from_le_slice, i32_from_le_slice, i64_from_le_slice, u64_from_le_slice, ErrorTag, EzError, KeyString};
+
+
+/// Coordinates the server with other processes that map table files read-only. It holds a single u64 epoch
+/// that is bumped every time a table file is replaced or removed. Writers hold an exclusive flock on it while
+/// they swap a file in, readers hold a shared flock while they map one, so a reader never maps a half written file.
+pub const TABLE_EPOCH_FILE: &str = ".table_epoch";
+
+fn lock_error(path: &Path, e: std::io::Error) -> EzError {
+    EzError{tag: ErrorTag::Io, text: format!("Could not lock '{}': {}", path.display(), e)}
+}
+
+fn read_epoch(file: &mut File) -> Result<u64, EzError> {
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut bytes)?;
+    match bytes.len() {
+        0 => Ok(0),
+        8 => Ok(u64_from_le_slice(&bytes)),
+        other => Err(EzError{tag: ErrorTag::Deserialization, text: format!("The table epoch file should be 8 bytes but is {}", other)}),
+    }
+}
+
+/// Runs the change to the table files under the exclusive epoch lock and bumps the epoch after it.
+fn with_epoch_bump(change: impl FnOnce() -> Result<(), EzError>) -> Result<u64, EzError> {
+    let path = config_file(TABLE_EPOCH_FILE);
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+    file.lock().map_err(|e| lock_error(&path, e))?;
+    change()?;
+    let epoch = read_epoch(&mut file)? + 1;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&epoch.to_le_bytes())?;
+    Ok(epoch)
+}
+
+/// Replaces the file of a table. The new content is written next to it and renamed into place so processes
+/// that have the old file mapped keep reading the old version until they remap.
+pub fn write_table_file(table_name: &str, binary: &[u8]) -> Result<(), EzError> {
+    let path = table_file(table_name);
+    // The temporary file stays out of raw_tables so it is never loaded as a table
+    let temp_path = config_file(&format!(".{}.tmp", table_name));
+    let mut file = File::create(&temp_path)?;
+    file.write_all(binary)?;
+    file.sync_all()?;
+    with_epoch_bump(|| Ok(std::fs::rename(&temp_path, &path)?))?;
+    Ok(())
+}
+
+pub fn remove_table_file(table_name: &str) -> Result<(), EzError> {
+    let path = table_file(table_name);
+    with_epoch_bump(|| Ok(std::fs::remove_file(&path)?))?;
+    Ok(())
+}
+
+/// The current epoch of the tables under the given config directory. 0 if no table file has been replaced yet.
+pub fn table_epoch(config_dir: &Path) -> Result<u64, EzError> {
+    let path = config_dir.join(TABLE_EPOCH_FILE);
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    file.lock_shared().map_err(|e| lock_error(&path, e))?;
+    read_epoch(&mut file)
+}
+
+/// The size of each value of the type. Long text values vary so their columns carry their own length.
+fn type_size(kind: DbType) -> Option<usize> {
+    match kind {
+        DbType::Int | DbType::Float | DbType::Enum => Some(4),
+        DbType::Text => Some(64),
+        DbType::Duration => Some(8),
+        DbType::LongText => None,
+    }
+}
+
+fn corrupt(text: &str) -> EzError {
+    EzError{tag: ErrorTag::Deserialization, text: format!("Mapped table is corrupt: {}", text)}
+}
+
+/// A table file mapped read-only into memory. Meant for analytics sidecars on the same host as the server
+/// that want to scan tables without going through the network protocol. Columns are read straight out
+/// of the mapping without copying the table.
+/// The mapping is a snapshot: the server replaces table files instead of writing into them. Use is_stale()
+/// to find out whether any table has changed since and open the table again to see the changes.
+/// Files written with compressed columns can't be read in place. They are decoded into memory when opened.
+pub struct MappedTable {
+    pub name: KeyString,
+    pub header: BTreeSet<HeaderItem>,
+    pub metadata: Metadata,
+    pub rows: usize,
+    /// The table epoch when the file was mapped.
+    pub epoch: u64,
+    config_dir: PathBuf,
+    /// Where the values of each column are in the mapping.
+    offsets: BTreeMap<KeyString, Range<usize>>,
+    mapping: FileMapping,
+    /// The uncompressed layout of a compressed file.
+    decoded: Option<Vec<u8>>,
+}
+
+impl MappedTable {
+    /// Maps the table from the raw_tables directory under config_dir, the EZconfig directory of the server.
+    pub fn open(config_dir: &Path, table_name: &str) -> Result<MappedTable, EzError> {
+        let epoch_path = config_dir.join(TABLE_EPOCH_FILE);
+        // Without an epoch file the server has never replaced a table file so there is nothing to wait for
+        let mut lock = match File::open(&epoch_path) {
+            Ok(file) => {
+                file.lock_shared().map_err(|e| lock_error(&epoch_path, e))?;
+                Some(file)
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let epoch = match &mut lock {
+            Some(lock) => read_epoch(lock)?,
+            None => 0,
+        };
+
+        let path = config_dir.join(RAW_TABLES_DIR).join(table_name);
+        let file = File::open(&path)?;
+        if file.metadata()?.len() == 0 {
+            return Err(corrupt("the file is empty"))
+        }
+        let mapping = FileMapping::open(&file, &path)?;
+        // Once mapped, the file can be replaced without affecting us
+        drop(lock);
+
+        let mut table = MappedTable {
+            name: KeyString::from(table_name),
+            header: BTreeSet::new(),
+            metadata: Metadata::new("unknown"),
+            rows: 0,
+            epoch,
+            config_dir: config_dir.to_path_buf(),
+            offsets: BTreeMap::new(),
+            mapping,
+            decoded: None,
+        };
+        // Dropping the half built table unmaps the file if the header is bad
+        table.read_header()?;
+        Ok(table)
+    }
+
+    /// Reads the layout written by write_column_table_binary_header() without copying any columns.
+    fn read_header(&mut self) -> Result<(), EzError> {
+        if is_chunk_manifest(self.bytes()) {
+            return Err(EzError{tag: ErrorTag::Unimplemented, text: format!(
+                "Table '{}' is stored in chunks and can't be mapped. Start the server with --table-chunk-rows=0 to keep it in one file", self.name
+            )})
+        }
+        if self.bytes().len() < 144 {
+            return Err(corrupt("the header is cut short"))
+        }
+        // Refuses files from formats this build can't read before looking any further
+        let format = table_format(&self.bytes()[0..64])?;
+        if format.compressed {
+            self.decoded = Some(ColumnTable::from_binary(None, self.bytes())?.to_binary());
+        }
+        let bytes = self.bytes();
+        let has_metadata = format.has_metadata;
+        let name = KeyString::try_from(&bytes[64..128])?;
+        let header_len = u64_from_le_slice(&bytes[128..136]) as usize;
+        let rows = u64_from_le_slice(&bytes[136..144]) as usize;
+
+        let names_start = header_len.checked_mul(8).and_then(|n| n.checked_add(144)).ok_or(corrupt("impossible header length"))?;
+        let mut pointer = header_len.checked_mul(64).and_then(|n| n.checked_add(names_start)).ok_or(corrupt("impossible header length"))?;
+        if bytes.len() < pointer {
+            return Err(corrupt("the header is cut short"))
+        }
+        let mut header = BTreeSet::new();
+        for i in 0..header_len {
+            let kind = match bytes[144 + i*8 + 3] {
+                b'i' => DbType::Int,
+                b'f' => DbType::Float,
+                b't' => DbType::Text,
+                b'd' => DbType::Duration,
+                b'l' => DbType::LongText,
+                b'e' => DbType::Enum,
+                _ => return Err(corrupt("unknown column type")),
+            };
+            let key = match bytes[144 + i*8 + 7] {
+                b'P' => TableKey::Primary,
+                b'N' => TableKey::None,
+                b'F' => TableKey::Foreign,
+                _ => return Err(corrupt("unknown key type")),
+            };
+            let name = KeyString::try_from(&bytes[names_start + i*64..names_start + (i+1)*64])?;
+            let collation = match bytes[144 + i*8 + 5] {
+                b'c' => Collation::CaseInsensitive,
+                _ => Collation::Binary,
+            };
+            header.insert(HeaderItem{name, kind, key, values: Vec::new(), collation});
+        }
+
+        let metadata = if has_metadata {
+            if bytes.len() < pointer + METADATA_BINARY_SIZE {
+                return Err(corrupt("the metadata is cut short"))
+            }
+            pointer += METADATA_BINARY_SIZE;
+            Metadata::from_binary(&bytes[pointer - METADATA_BINARY_SIZE..pointer])?
+        } else {
+            Metadata::new("unknown")
+        };
+
+        // Columns follow the header in column name order, which is also the order of the header items
+        let mut offsets = BTreeMap::new();
+        for item in &header {
+            let start = pointer;
+            let column_len = match type_size(item.kind) {
+                Some(size) => rows.checked_mul(size).ok_or(corrupt("impossible row count"))?,
+                None => LongTexts::binary_len(bytes.get(pointer..).ok_or(corrupt("the columns are cut short"))?, rows)?,
+            };
+            pointer = column_len.checked_add(pointer).ok_or(corrupt("impossible row count"))?;
+            offsets.insert(item.name, start..pointer);
+        }
+        if bytes.len() < pointer {
+            return Err(corrupt("the columns are cut short"))
+        }
+        if header.iter().any(|item| item.kind == DbType::Enum) {
+            let mut enums = match format.has_sections {
+                true => read_enum_values(&bytes[pointer..])?,
+                false => BTreeMap::new(),
+            };
+            header = header.into_iter()
+                .map(|item| match item.kind {
+                    DbType::Enum => enums.remove(&item.name).map(|values| HeaderItem{values, ..item}).ok_or(corrupt("an enum column has no values")),
+                    _ => Ok(item),
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        self.name = name;
+        self.header = header;
+        self.metadata = metadata;
+        self.rows = rows;
+        self.offsets = offsets;
+        Ok(())
+    }
+
+    /// The whole file, as written by ColumnTable::to_binary().
+    pub fn bytes(&self) -> &[u8] {
+        if let Some(decoded) = &self.decoded {
+            return decoded
+        }
+        self.mapping.bytes()
+    }
+
+    fn column(&self, name: &str, kind: DbType) -> Result<&[u8], EzError> {
+        let item = match self.header.iter().find(|item| item.name.as_str() == name) {
+            Some(item) => item,
+            None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("Table '{}' has no column '{}'", self.name, name)}),
+        };
+        if item.kind != kind {
+            return Err(EzError{tag: ErrorTag::Query, text: format!("Column '{}' holds {} values, not {}", name, item.kind.name(), kind.name())})
+        }
+        Ok(&self.bytes()[self.offsets[&item.name].clone()])
+    }
+
+    /// The raw little endian values of a column.
+    pub fn column_bytes(&self, name: &str) -> Result<&[u8], EzError> {
+        let kind = match self.header.iter().find(|item| item.name.as_str() == name) {
+            Some(item) => item.kind,
+            None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("Table '{}' has no column '{}'", self.name, name)}),
+        };
+        self.column(name, kind)
+    }
+
+    pub fn ints(&self, name: &str) -> Result<impl Iterator<Item = i32> + '_, EzError> {
+        Ok(self.column(name, DbType::Int)?.chunks_exact(4).map(i32_from_le_slice))
+    }
+
+    pub fn floats(&self, name: &str) -> Result<impl Iterator<Item = f32> + '_, EzError> {
+        Ok(self.column(name, DbType::Float)?.chunks_exact(4).map(f32_from_le_slice))
+    }
+
+    pub fn durations(&self, name: &str) -> Result<impl Iterator<Item = i64> + '_, EzError> {
+        Ok(self.column(name, DbType::Duration)?.chunks_exact(8).map(i64_from_le_slice))
+    }
+
+    /// An int column as a slice. Borrowed straight from the mapping when the column is aligned, which it is
+    /// unless a long text column comes before it, and copied otherwise.
+    pub fn int_slice(&self, name: &str) -> Result<Cow<'_, [i32]>, EzError> {
+        Ok(cast_or_decode(self.column(name, DbType::Int)?, i32_from_le_slice))
+    }
+
+    /// A float column as a slice. See int_slice().
+    pub fn float_slice(&self, name: &str) -> Result<Cow<'_, [f32]>, EzError> {
+        Ok(cast_or_decode(self.column(name, DbType::Float)?, f32_from_le_slice))
+    }
+
+    /// A duration column as a slice. See int_slice().
+    pub fn duration_slice(&self, name: &str) -> Result<Cow<'_, [i64]>, EzError> {
+        Ok(cast_or_decode(self.column(name, DbType::Duration)?, i64_from_le_slice))
+    }
+
+    /// The values of an enum column. Indexes outside the value set come out as empty strings.
+    pub fn enums(&self, name: &str) -> Result<impl Iterator<Item = &str> + '_, EzError> {
+        let column = self.column(name, DbType::Enum)?;
+        let item = self.header.iter().find(|item| item.name.as_str() == name).expect("column() found it");
+        Ok(column.chunks_exact(4).map(|chunk| {
+            usize::try_from(i32_from_le_slice(chunk)).ok().and_then(|index| item.values.get(index)).map_or("", |value| value.as_str())
+        }))
+    }
+
+    /// Text values borrowed from the mapping. Values that aren't valid UTF-8 come out as empty strings.
+    pub fn texts(&self, name: &str) -> Result<impl Iterator<Item = &str> + '_, EzError> {
+        Ok(self.column(name, DbType::Text)?.chunks_exact(64).map(|chunk| {
+            let end = chunk.iter().position(|byte| *byte == 0).unwrap_or(64);
+            std::str::from_utf8(&chunk[..end]).unwrap_or("")
+        }))
+    }
+
+    /// Long text values borrowed from the mapping. Checks the whole column when called.
+    pub fn long_texts(&self, name: &str) -> Result<impl Iterator<Item = &str> + '_, EzError> {
+        let column = self.column(name, DbType::LongText)?;
+        let data_start = 8 + self.rows * 8;
+        let data = match std::str::from_utf8(&column[data_start..]) {
+            Ok(data) => data,
+            Err(_) => return Err(corrupt("a long text column is not valid UTF-8")),
+        };
+        let ends: Vec<usize> = column[8..data_start].chunks_exact(8).map(|chunk| u64_from_le_slice(chunk) as usize).collect();
+        let mut start = 0;
+        for end in &ends {
+            if *end < start || *end > data.len() || !data.is_char_boundary(*end) {
+                return Err(corrupt("a long text column has a bad offset"))
+            }
+            start = *end;
+        }
+        let mut start = 0;
+        Ok(ends.into_iter().map(move |end| {
+            let value = &data[start..end];
+            start = end;
+            value
+        }))
+    }
+
+    /// Copies the mapped table into an ordinary ColumnTable.
+    pub fn to_column_table(&self) -> Result<ColumnTable, EzError> {
+        ColumnTable::from_binary(Some(self.name.as_str()), self.bytes())
+    }
+
+    /// Whether any table file has been replaced since this one was mapped.
+    pub fn is_stale(&self) -> Result<bool, EzError> {
+        Ok(table_epoch(&self.config_dir)? != self.epoch)
+    }
+}
+
+/// Reinterprets little endian column bytes as values in place if the platform is little endian and the bytes are
+/// aligned for T. Only for plain number types, where every bit pattern is a value.
+fn cast_or_decode<T: Copy>(bytes: &[u8], decode: fn(&[u8]) -> T) -> Cow<'_, [T]> {
+    if cfg!(target_endian = "little") {
+        let (head, values, tail) = unsafe { bytes.align_to::<T>() };
+        if head.is_empty() && tail.is_empty() {
+            return Cow::Borrowed(values)
+        }
+    }
+    Cow::Owned(bytes.chunks_exact(std::mem::size_of::<T>()).map(decode).collect())
+}
+
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use crate::paths::{config_dir, create_data_dirs};
+    use crate::testing_tools::create_fixed_table;
+    use crate::utilities::ksf;
+
+    use super::*;
+
+    #[test]
+    fn test_mapped_table() {
+        create_data_dirs().unwrap();
+        let name = "mapped_table_test";
+        let table = create_fixed_table(20);
+        write_table_file(name, &table.to_binary()).unwrap();
+
+        let mapped = MappedTable::open(&config_dir(), name).unwrap();
+        assert_eq!(mapped.rows, 20);
+        assert_eq!(mapped.ints("ints").unwrap().collect::<Vec<i32>>(), *table.get_column_int(&ksf("ints")).unwrap());
+        assert_eq!(&*mapped.int_slice("ints").unwrap(), table.get_column_int(&ksf("ints")).unwrap().as_slice());
+        assert_eq!(&*mapped.float_slice("floats").unwrap(), table.get_column_float(&ksf("floats")).unwrap().as_slice());
+        assert!(mapped.float_slice("ints").is_err());
+        if cfg!(target_endian = "little") {
+            assert!(matches!(mapped.int_slice("ints").unwrap(), Cow::Borrowed(_)));
+        }
+        assert_eq!(mapped.to_column_table().unwrap(), table);
+        assert!(mapped.texts("ints").is_err());
+        assert!(!mapped.is_stale().unwrap());
+
+        // The old mapping is still readable after the server replaces the file
+        write_table_file(name, &create_fixed_table(3).to_binary()).unwrap();
+        assert!(mapped.is_stale().unwrap());
+        assert_eq!(mapped.ints("ints").unwrap().count(), 20);
+        let remapped = MappedTable::open(&config_dir(), name).unwrap();
+        assert_eq!(remapped.rows, 3);
+        assert!(remapped.epoch > mapped.epoch);
+
+        remove_table_file(name).unwrap();
+        assert!(MappedTable::open(&config_dir(), name).is_err());
+        assert_eq!(mapped.ints("ints").unwrap().count(), 20);
+
+        // Compressed files are decoded when they are opened
+        write_table_file(name, &table.to_compressed_binary().unwrap()).unwrap();
+        let compressed = MappedTable::open(&config_dir(), name).unwrap();
+        assert_eq!(compressed.to_column_table().unwrap(), table);
+        assert_eq!(compressed.texts("texts").unwrap().count(), 20);
+        remove_table_file(name).unwrap();
+    }
+}
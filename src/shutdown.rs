@@ -0,0 +1,80 @@
+//! Stopping the server without losing what only lives in memory. SIGINT, SIGTERM or the SHUTDOWN admin command
+//! ask for a shutdown. The server then stops accepting connections, waits for the queries it is running to
+//! finish and writes every changed table and value before it exits. A second signal exits at once.
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(all(feature = "server", target_os = "linux"))]
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use crate::database::Database;
+use crate::disk_utilities::{VALUE_EXPIRY_FILE, VALUE_HISTORY_FILE};
+use crate::paths::config_file;
+#[cfg(all(feature = "server", target_os = "linux"))]
+use crate::utilities::ErrorTag;
+use crate::utilities::EzError;
+
+
+/// How long the server waits for running queries before it writes everything out regardless.
+pub const SHUTDOWN_DRAIN_SECS: u64 = 30;
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks the server to shut down. The main loop notices within one epoll timeout.
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+// Only an atomic store and _exit happen here since little else is safe in a signal handler
+#[cfg(all(feature = "server", target_os = "linux"))]
+extern "C" fn handle_signal(_signal: nix::libc::c_int) {
+    if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+        unsafe { nix::libc::_exit(1) };
+    }
+}
+
+/// Turns SIGINT and SIGTERM into a request to shut down.
+#[cfg(all(feature = "server", target_os = "linux"))]
+pub fn install_signal_handlers() -> Result<(), EzError> {
+    let action = SigAction::new(SigHandler::Handler(handle_signal), SaFlags::empty(), SigSet::empty());
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        // Safe since the handler only touches an atomic
+        unsafe { sigaction(signal, &action) }
+            .map_err(|e| EzError{tag: ErrorTag::Io, text: format!("Could not handle {}: {}", signal, e)})?;
+    }
+    Ok(())
+}
+
+/// What was written on the way out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShutdownSummary {
+    pub tables: usize,
+    pub values: usize,
+    /// Whether every query finished before SHUTDOWN_DRAIN_SECS ran out.
+    pub drained: bool,
+}
+
+impl Display for ShutdownSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.drained {
+            true => write!(f, "Shut down cleanly. Wrote {} tables and {} values", self.tables, self.values),
+            false => write!(f, "Shut down after giving up on running queries. Wrote {} tables and {} values", self.tables, self.values),
+        }
+    }
+}
+
+/// Writes everything the database holds that is not on disk yet: changed tables and values, the row buffers,
+/// the value expiry times and history and the users. Call once no more queries run.
+pub fn flush_for_shutdown(database: &Database, drained: bool) -> Result<ShutdownSummary, EzError> {
+    let (tables, values) = database.buffer_pool.flush_dirty()?;
+    database.buffer_pool.write_value_expiry(&config_file(VALUE_EXPIRY_FILE))?;
+    database.buffer_pool.write_value_history(&config_file(VALUE_HISTORY_FILE))?;
+    database.save_users()?;
+
+    Ok(ShutdownSummary{tables, values, drained})
+}
@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use crate::db_structure::ColumnTable;
+use crate::ezql::{execute_left_join_query, execute_select_query, execute_summary_query, Query};
+use crate::database::Database;
+use crate::cancellation::check_cancelled;
+use crate::partitions::partition_names;
+use crate::system_tables::{is_system_table, materialize_system_table};
+use crate::utilities::{ErrorTag, EzError, KeyString};
+
+
+/// A consistent view of every table a read only batch touches.
+/// All the read locks are taken together (in name order, so two snapshots can't deadlock) and every table
+/// is pinned before any lock is released. After that the batch runs without holding any locks and never sees a half finished write.
+/// Whole tables are shared with the buffer pool rather than copied: the pool keeps each table behind an Arc and a writer
+/// copies the table only if a snapshot still holds it (see TableWrite). Tables that are only summarized are copied
+/// with just the summarized columns, and partitions are copied into one table.
+pub struct Snapshot {
+    tables: BTreeMap<KeyString, Arc<ColumnTable>>,
+}
+
+impl Snapshot {
+    pub fn take(queries: &[Query], database: &Database) -> Result<Snapshot, EzError> {
+        // println!("calling: Snapshot::take()");
+
+        let needed = needed_columns(queries)?;
+
+        let mut snapshot = BTreeMap::new();
+        let (system_names, table_names): (Vec<KeyString>, Vec<KeyString>) = needed.keys().copied().partition(is_system_table);
+        let stored = stored_names(&table_names, queries, database)?;
+        {
+            let tables = database.buffer_pool.tables.read().unwrap();
+            // Partitions are locked in name order along with everything else
+            let lock_order: BTreeSet<KeyString> = stored.values().flatten().copied().collect();
+            let mut guards = BTreeMap::new();
+            for name in &lock_order {
+                match tables.get(name) {
+                    Some(table) => { guards.insert(*name, database.locks.read_table(*name, table)?); },
+                    None => return Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named: '{}'", name)}),
+                }
+            }
+            for (name, stored_names) in &stored {
+                // A whole table that is stored under its own name is shared instead of copied
+                if needed[name].is_none() && stored_names[..] == [*name] {
+                    let guard = &guards[name];
+                    guard.metadata.touch();
+                    snapshot.insert(*name, guard.share());
+                    continue
+                }
+                let mut copy: Option<ColumnTable> = None;
+                for stored_name in stored_names {
+                    let guard = &guards[stored_name];
+                    guard.metadata.touch();
+                    let part = match &needed[name] {
+                        Some(columns) => guard.copy_columns(columns),
+                        None => ColumnTable::clone(guard),
+                    };
+                    match &mut copy {
+                        Some(copy) => copy.extend_from_table(part)?,
+                        None => copy = Some(part),
+                    }
+                }
+                if let Some(mut copy) = copy {
+                    copy.name = *name;
+                    snapshot.insert(*name, Arc::new(copy));
+                }
+            }
+        }
+        for name in system_names {
+            snapshot.insert(name, Arc::new(materialize_system_table(&name, database)?));
+        }
+
+        Ok(Snapshot{tables: snapshot})
+    }
+
+    pub fn get(&self, table_name: &KeyString) -> Result<&ColumnTable, EzError> {
+        match self.tables.get(table_name) {
+            Some(table) => Ok(table.as_ref()),
+            None => Err(EzError{tag: ErrorTag::NotFound, text: format!("No table named: '{}' in snapshot", table_name)}),
+        }
+    }
+}
+
+/// The columns the batch reads from each table it touches, in name order so the locks are always taken in the same order.
+/// None means the whole table. Only SUMMARY reads just some of the columns.
+fn needed_columns(queries: &[Query]) -> Result<BTreeMap<KeyString, Option<BTreeSet<KeyString>>>, EzError> {
+    let mut needed: BTreeMap<KeyString, Option<BTreeSet<KeyString>>> = BTreeMap::new();
+    for query in queries {
+        match query {
+            Query::SELECT { table_name, .. } => {
+                needed.insert(*table_name, None);
+            },
+            Query::SUMMARY { table_name, columns } => {
+                if let Some(set) = needed.entry(*table_name).or_insert_with(|| Some(BTreeSet::new())) {
+                    set.extend(columns.iter().map(|stat| stat.column));
+                }
+            },
+            Query::LEFT_JOIN { left_table_name, right_table_name, .. } => {
+                needed.insert(*left_table_name, None);
+                needed.insert(*right_table_name, None);
+            },
+            other => return Err(EzError{tag: ErrorTag::Query, text: format!("Can't take a snapshot for a batch containing: {}", other)}),
+        }
+    }
+    Ok(needed)
+}
+
+/// The tables in the buffer pool that hold each table the batch reads. That is the table itself, or for a partitioned
+/// table the partitions any of the queries touch in key order, so the copies can be put back together in order.
+fn stored_names(table_names: &[KeyString], queries: &[Query], database: &Database) -> Result<BTreeMap<KeyString, Vec<KeyString>>, EzError> {
+    let mut stored = BTreeMap::new();
+    for name in table_names {
+        let names = match database.buffer_pool.partition_map(name) {
+            Some(map) => {
+                let mut touched: BTreeSet<usize> = queries.iter()
+                    .filter(|query| reads_table(query, name))
+                    .flat_map(|query| map.touched_by(query))
+                    .collect();
+                // A query that touches no partition still needs an empty table with the right columns
+                if touched.is_empty() {
+                    touched.insert(0);
+                }
+                partition_names(name, &touched.into_iter().collect::<Vec<_>>())?
+            },
+            None => vec![*name],
+        };
+        stored.insert(*name, names);
+    }
+    Ok(stored)
+}
+
+fn reads_table(query: &Query, table_name: &KeyString) -> bool {
+    match query {
+        Query::SELECT { table_name: name, .. } | Query::SUMMARY { table_name: name, .. } => name == table_name,
+        Query::LEFT_JOIN { left_table_name, right_table_name, .. } => left_table_name == table_name || right_table_name == table_name,
+        _ => false,
+    }
+}
+
+/// Whether every query in the batch only reads. Such batches run against a Snapshot.
+pub fn is_read_only_batch(queries: &[Query]) -> bool {
+    !queries.is_empty() && queries.iter().all(|query| matches!(query, Query::SELECT{..} | Query::SUMMARY{..} | Query::LEFT_JOIN{..}))
+}
+
+/// Executes a read only batch against a consistent snapshot of the tables it touches.
+/// Chaining works the same way as in execute_EZQL_queries(): each query runs on the result of the previous one if there is one.
+pub fn execute_snapshot_queries(queries: Vec<Query>, database: &Database) -> Result<Option<ColumnTable>, EzError> {
+    // println!("calling: execute_snapshot_queries()");
+
+    let snapshot = Snapshot::take(&queries, database)?;
+
+    let mut result_table: Option<ColumnTable> = None;
+    for query in queries {
+        check_cancelled()?;
+        match &query {
+            Query::SELECT { table_name, .. } => {
+                result_table = match &result_table {
+                    Some(table) => execute_select_query(&query, table)?,
+                    None => execute_select_query(&query, snapshot.get(table_name)?)?,
+                };
+            },
+            Query::SUMMARY { table_name, .. } => {
+                return match &result_table {
+                    Some(table) => execute_summary_query(&query, table),
+                    None => execute_summary_query(&query, snapshot.get(table_name)?),
+                };
+            },
+            Query::LEFT_JOIN { left_table_name, right_table_name, .. } => {
+                let right_table = snapshot.get(right_table_name)?;
+                result_table = match &result_table {
+                    Some(table) => execute_left_join_query(query.clone(), table, right_table)?,
+                    None => execute_left_join_query(query.clone(), snapshot.get(left_table_name)?, right_table)?,
+                };
+            },
+            other => return Err(EzError{tag: ErrorTag::Query, text: format!("Query is not read only: {}", other)}),
+        }
+    }
+
+    Ok(result_table)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::ezql::{RangeOrListOrAll, StatOp, Statistic};
+    use crate::testing_tools::create_fixed_table;
+    use crate::utilities::ksf;
+
+    use super::*;
+
+    #[test]
+    fn test_is_read_only_batch() {
+        let select = Query::new_select("fixed_table");
+        let drop = Query::DROP { table_name: ksf("fixed_table") };
+        assert!(is_read_only_batch(&[select.clone(), select.clone()]));
+        assert!(!is_read_only_batch(&[select, drop]));
+        assert!(!is_read_only_batch(&[]));
+    }
+
+    #[test]
+    fn test_snapshot_lookup() {
+        let table = create_fixed_table(10);
+        let mut snapshot = Snapshot{tables: BTreeMap::new()};
+        snapshot.tables.insert(table.name, Arc::new(table.clone()));
+
+        let query = Query::SELECT {
+            table_name: table.name,
+            primary_keys: RangeOrListOrAll::All,
+            columns: vec![ksf("*")],
+            conditions: Vec::new(),
+            distinct: false,
+            limit: None,
+        };
+        let result = execute_select_query(&query, snapshot.get(&table.name).unwrap()).unwrap().unwrap();
+        assert_eq!(result.len(), 10);
+        assert!(snapshot.get(&ksf("missing")).is_err());
+    }
+
+    #[test]
+    fn test_summary_snapshot_columns() {
+        let summary = |table: &str, column: &str| Query::SUMMARY {
+            table_name: ksf(table),
+            columns: vec![Statistic{column: ksf(column), actions: BTreeSet::from([StatOp::SUM])}],
+        };
+        let needed = needed_columns(&[summary("fixed_table", "ints"), summary("fixed_table", "floats")]).unwrap();
+        assert_eq!(needed[&ksf("fixed_table")], Some(BTreeSet::from([ksf("ints"), ksf("floats")])));
+
+        // A table that is also selected from is copied whole, whatever order the queries come in
+        let needed = needed_columns(&[summary("fixed_table", "ints"), Query::new_select("fixed_table")]).unwrap();
+        assert_eq!(needed[&ksf("fixed_table")], None);
+        let needed = needed_columns(&[Query::new_select("fixed_table"), summary("fixed_table", "ints")]).unwrap();
+        assert_eq!(needed[&ksf("fixed_table")], None);
+
+        let table = create_fixed_table(10);
+        let copy = table.copy_columns(&BTreeSet::from([ksf("ints"), ksf("no_such_column")]));
+        assert_eq!(copy.columns.len(), 1);
+        assert_eq!(copy.header.len(), 1);
+        let summary = summary("fixed_table", "ints");
+        assert_eq!(execute_summary_query(&summary, &copy).unwrap(), execute_summary_query(&summary, &table).unwrap());
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+use crate::transport::Transport;
+use rand::Rng;
+
+use crate::client_networking::{make_connection, send_kv_queries, send_query, send_write_queries};
+use crate::db_structure::{ColumnTable, DbValue, OnConflict};
+use crate::ezql::{Condition, KvQuery, OpOrCond, Query, RangeOrListOrAll, TestOp, Update, UpdateOp};
+use crate::row_table::TableEngine;
+use crate::server_networking::run_server;
+use crate::utilities::{get_current_time, ksf, ErrorTag, EzError, KeyString};
+
+
+/// Panics anywhere in the process, including the server threads, since the hook was installed.
+static PANICS: AtomicU64 = AtomicU64::new(0);
+static PANIC_HOOK: Once = Once::new();
+
+/// Workers insert their own rows starting at this id so they never collide with each other or the initial rows.
+const INSERT_ID_STRIDE: i32 = 1_000_000;
+
+const STRESS_HEADER: &str = "id,i-P;counter,i-N;owner,i-N";
+
+pub struct StressConfig {
+    pub address: String,
+    pub username: String,
+    pub password: String,
+    pub workers: usize,
+    pub operations_per_worker: usize,
+    /// Rows in the table the workers fight over. One extra "hot" row is updated by every worker.
+    pub rows: usize,
+}
+
+impl Default for StressConfig {
+    fn default() -> Self {
+        StressConfig {
+            address: "127.0.0.1:3011".to_owned(),
+            username: "admin".to_owned(),
+            password: "admin".to_owned(),
+            workers: 8,
+            operations_per_worker: 200,
+            rows: 100,
+        }
+    }
+}
+
+/// What a stress run did and every invariant it saw broken. A clean run has no violations and no failed operations.
+#[derive(Debug, Default)]
+pub struct StressReport {
+    pub reads: u64,
+    pub writes: u64,
+    pub schema_changes: u64,
+    pub kv_operations: u64,
+    pub failed_operations: u64,
+    pub panics: u64,
+    pub violations: Vec<String>,
+}
+
+impl StressReport {
+    pub fn is_clean(&self) -> bool {
+        self.violations.is_empty() && self.failed_operations == 0 && self.panics == 0
+    }
+
+    fn absorb(&mut self, other: StressReport) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.schema_changes += other.schema_changes;
+        self.kv_operations += other.kv_operations;
+        self.failed_operations += other.failed_operations;
+        self.violations.extend(other.violations);
+    }
+}
+
+/// What one worker expects the table to look like after it is done.
+#[derive(Default)]
+struct WorkerLedger {
+    /// Successful increments per owned row id
+    increments: BTreeMap<i32, i32>,
+    inserted: Vec<i32>,
+}
+
+fn install_panic_counter() {
+    PANIC_HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANICS.fetch_add(1, Ordering::SeqCst);
+            previous(info);
+        }));
+    });
+}
+
+/// Starts the server on a background thread and waits until it accepts connections.
+pub fn start_server_in_process(config: &StressConfig) -> Result<(), EzError> {
+
+    let address = config.address.clone();
+    let server = thread::spawn(move || run_server(&address));
+
+    for _ in 0..100 {
+        if server.is_finished() {
+            return match server.join() {
+                Ok(Err(e)) => Err(e),
+                _ => Err(EzError{tag: ErrorTag::Io, text: format!("Server on {} stopped during startup", config.address)}),
+            }
+        }
+        if make_connection(&config.address, &config.username, &config.password).is_ok() {
+            return Ok(())
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    Err(EzError{tag: ErrorTag::Io, text: format!("Server on {} did not accept connections within 10 seconds", config.address)})
+}
+
+fn send_write(connection: &mut Transport, query: &Query) -> Result<(), EzError> {
+    send_write_queries(connection, std::slice::from_ref(query))?.into_result().map(|_| ())
+}
+
+fn increment_row(table_name: KeyString, id: i32) -> Query {
+    Query::UPDATE {
+        table_name,
+        primary_keys: RangeOrListOrAll::All,
+        conditions: vec![OpOrCond::Cond(Condition{attribute: ksf("id"), op: TestOp::Equals, value: DbValue::Int(id), other_column: None})],
+        updates: vec![Update{attribute: ksf("counter"), operator: UpdateOp::PlusEquals, value: DbValue::Int(1), expression: None}],
+        version: None,
+    }
+}
+
+fn select_all(table_name: KeyString) -> Query {
+    Query::SELECT {
+        table_name,
+        primary_keys: RangeOrListOrAll::All,
+        columns: vec![ksf("*")],
+        conditions: Vec::new(),
+        distinct: false,
+        limit: None,
+    }
+}
+
+/// Primary keys must be strictly increasing after every write or binary searches on the table return garbage.
+fn check_sorted(table: &ColumnTable, violations: &mut Vec<String>) {
+    let ids = match table.get_column_int(&ksf("id")) {
+        Ok(ids) => ids,
+        Err(e) => {
+            violations.push(format!("Result has no id column: {}", e));
+            return
+        },
+    };
+    if let Some(window) = ids.windows(2).find(|pair| pair[0] >= pair[1]) {
+        violations.push(format!("Primary keys out of order: {} came before {}", window[0], window[1]));
+    }
+}
+
+fn run_worker(config: &StressConfig, table_name: KeyString, worker: usize, hot_increments: &AtomicU64) -> (StressReport, WorkerLedger) {
+
+    let mut report = StressReport::default();
+    let mut ledger = WorkerLedger::default();
+
+    let mut connection = match make_connection(&config.address, &config.username, &config.password) {
+        Ok(c) => c,
+        Err(e) => {
+            report.violations.push(format!("Worker {} could not connect: {}", worker, e));
+            return (report, ledger)
+        },
+    };
+
+    let mut rng = rand::thread_rng();
+    let owned: Vec<i32> = (0..config.rows as i32).filter(|id| *id as usize % config.workers == worker).collect();
+    let hot_row = config.rows as i32;
+    let scratch_table = ksf(&format!("{}_{}", table_name, worker));
+    let kv_key = ksf(&format!("{}_kv_{}", table_name, worker));
+    let mut scratch_exists = false;
+    let mut kv_exists = false;
+
+    for op in 0..config.operations_per_worker {
+        match rng.gen_range(0..10) {
+            0..=3 if !owned.is_empty() => {
+                let id = owned[rng.gen_range(0..owned.len())];
+                report.writes += 1;
+                match send_write(&mut connection, &increment_row(table_name, id)) {
+                    Ok(()) => *ledger.increments.entry(id).or_insert(0) += 1,
+                    Err(_) => report.failed_operations += 1,
+                }
+            },
+            4 => {
+                report.writes += 1;
+                match send_write(&mut connection, &increment_row(table_name, hot_row)) {
+                    Ok(()) => { hot_increments.fetch_add(1, Ordering::SeqCst); },
+                    Err(_) => report.failed_operations += 1,
+                }
+            },
+            5 | 6 => {
+                report.reads += 1;
+                match send_query(&mut connection, &select_all(table_name)) {
+                    Ok(table) => {
+                        check_sorted(&table, &mut report.violations);
+                        if table.len() < config.rows + 1 {
+                            report.violations.push(format!("Worker {} saw {} rows but the table started with {}", worker, table.len(), config.rows + 1));
+                        }
+                    },
+                    Err(_) => report.failed_operations += 1,
+                }
+            },
+            7 => {
+                let id = INSERT_ID_STRIDE * (worker as i32 + 1) + op as i32;
+                let csv = format!("{}\n{};0;{}", STRESS_HEADER, id, worker);
+                report.writes += 1;
+                let inserts = match ColumnTable::from_csv_string(&csv, "inserts", "stress") {
+                    Ok(t) => t,
+                    Err(e) => {
+                        report.violations.push(format!("Could not build insert: {}", e));
+                        continue
+                    },
+                };
+                match send_write(&mut connection, &Query::INSERT{table_name, inserts, on_conflict: OnConflict::Skip}) {
+                    Ok(()) => ledger.inserted.push(id),
+                    Err(_) => report.failed_operations += 1,
+                }
+            },
+            8 => {
+                report.schema_changes += 1;
+                let query = if scratch_exists {
+                    Query::DROP{table_name: scratch_table}
+                } else {
+                    match ColumnTable::from_csv_string(&format!("{}\n0;0;{}", STRESS_HEADER, worker), scratch_table.as_str(), "stress") {
+                        Ok(table) => Query::CREATE{table, engine: TableEngine::Column},
+                        Err(e) => {
+                            report.violations.push(format!("Could not build scratch table: {}", e));
+                            continue
+                        },
+                    }
+                };
+                match send_write(&mut connection, &query) {
+                    Ok(()) => scratch_exists = !scratch_exists,
+                    Err(_) => report.failed_operations += 1,
+                }
+            },
+            _ => {
+                report.kv_operations += 1;
+                let body = format!("worker {} op {}", worker, op).into_bytes();
+                let write = if kv_exists { KvQuery::Update(kv_key, body.clone()) } else { KvQuery::Create(kv_key, body.clone()) };
+                match send_kv_queries(&mut connection, &[write, KvQuery::Read(kv_key)]) {
+                    Ok(results) => match results.as_slice() {
+                        [Ok(_), Ok(Some(value))] => {
+                            kv_exists = true;
+                            if value.body != body {
+                                report.violations.push(format!("Worker {} read back a different value for '{}' than it just wrote", worker, kv_key));
+                            }
+                        },
+                        _ => report.failed_operations += 1,
+                    },
+                    Err(_) => report.failed_operations += 1,
+                }
+            },
+        }
+    }
+
+    if scratch_exists && send_write(&mut connection, &Query::DROP{table_name: scratch_table}).is_err() {
+        report.failed_operations += 1;
+    }
+    if kv_exists {
+        let _ = send_kv_queries(&mut connection, &[KvQuery::Delete(kv_key)]);
+    }
+
+    (report, ledger)
+}
+
+/// Hammers a running server with concurrent reads, writes, schema changes and KV operations,
+/// then checks that no update was lost, the primary keys are still sorted and nothing panicked.
+/// Every run uses fresh table names so runs against the same data directory don't interfere.
+pub fn run_stress_test(config: &StressConfig) -> Result<StressReport, EzError> {
+    println!("calling: run_stress_test()");
+
+    if config.workers == 0 {
+        return Err(EzError{tag: ErrorTag::Query, text: "A stress test needs at least one worker".to_owned()})
+    }
+    install_panic_counter();
+    let panics_before = PANICS.load(Ordering::SeqCst);
+
+    let table_name = ksf(&format!("stress_{}", get_current_time()));
+    let mut csv = STRESS_HEADER.to_owned();
+    for id in 0..=config.rows {
+        csv.push_str(&format!("\n{};0;{}", id, id % config.workers));
+    }
+    let table = ColumnTable::from_csv_string(&csv, table_name.as_str(), "stress")?;
+    let mut connection = make_connection(&config.address, &config.username, &config.password)?;
+    send_write(&mut connection, &Query::CREATE{table, engine: TableEngine::Column})?;
+
+    let hot_increments = AtomicU64::new(0);
+    let ledgers = Mutex::new(Vec::new());
+    let mut report = StressReport::default();
+
+    thread::scope(|scope| {
+        let mut handles = Vec::new();
+        for worker in 0..config.workers {
+            let (hot_increments, ledgers) = (&hot_increments, &ledgers);
+            handles.push(scope.spawn(move || {
+                let (report, ledger) = run_worker(config, table_name, worker, hot_increments);
+                ledgers.lock().unwrap().push(ledger);
+                report
+            }));
+        }
+        for (worker, handle) in handles.into_iter().enumerate() {
+            match handle.join() {
+                Ok(worker_report) => report.absorb(worker_report),
+                Err(_) => report.violations.push(format!("Worker {} panicked", worker)),
+            }
+        }
+    });
+
+    let final_table = send_query(&mut connection, &select_all(table_name))?;
+    check_sorted(&final_table, &mut report.violations);
+
+    let ids = final_table.get_column_int(&ksf("id"))?;
+    let counters = final_table.get_column_int(&ksf("counter"))?;
+    let counter_of = |id: i32| ids.binary_search(&id).ok().map(|index| counters[index]);
+
+    let mut expected_rows = config.rows + 1;
+    for ledger in ledgers.lock().unwrap().iter() {
+        for (id, increments) in &ledger.increments {
+            match counter_of(*id) {
+                Some(actual) if actual == *increments => (),
+                Some(actual) => report.violations.push(format!("Lost update on row {}: {} increments succeeded but the counter is {}", id, increments, actual)),
+                None => report.violations.push(format!("Row {} disappeared", id)),
+            }
+        }
+        for id in &ledger.inserted {
+            if counter_of(*id).is_none() {
+                report.violations.push(format!("Inserted row {} is missing", id));
+            }
+        }
+        expected_rows += ledger.inserted.len();
+    }
+
+    let hot_expected = hot_increments.load(Ordering::SeqCst) as i32;
+    match counter_of(config.rows as i32) {
+        Some(actual) if actual == hot_expected => (),
+        Some(actual) => report.violations.push(format!("Lost update on the hot row: {} increments succeeded but the counter is {}", hot_expected, actual)),
+        None => report.violations.push("The hot row disappeared".to_owned()),
+    }
+    if final_table.len() != expected_rows {
+        report.violations.push(format!("Expected {} rows at the end but found {}", expected_rows, final_table.len()));
+    }
+
+    send_write(&mut connection, &Query::DROP{table_name})?;
+
+    report.panics = PANICS.load(Ordering::SeqCst) - panics_before;
+
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_mixed_workload() {
+        let config = StressConfig::default();
+        start_server_in_process(&config).unwrap();
+        let report = run_stress_test(&config).unwrap();
+        println!("{:#?}", report);
+        assert!(report.is_clean());
+    }
+}
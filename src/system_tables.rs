@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+
+use sha2::{Digest, Sha256};
+
+use crate::db_structure::{ColumnTable, DbColumn, DbType, TableKey};
+use crate::namespaces::namespaces_table;
+use crate::database::Database;
+use crate::utilities::{ksf, u64_from_le_slice, ErrorTag, EzError, KeyString};
+
+
+/// The names of the system tables. These are never stored in the buffer pool but are generated on the fly
+/// from the buffer pool and the user list whenever they are queried.
+pub const SYSTEM_TABLES: [&str; 11] = ["ez_tables", "ez_columns", "ez_users", "ez_permissions", "ez_tasks", "ez_namespaces", "ez_disk", "ez_health", "ez_tags", "ez_locks", "ez_thread_pool"];
+
+/// System tables that any authenticated user may read. The rest expose user data and are admin only.
+pub const PUBLIC_SYSTEM_TABLES: [&str; 3] = ["ez_tables", "ez_columns", "ez_health"];
+
+pub fn is_system_table(table_name: &KeyString) -> bool {
+    SYSTEM_TABLES.contains(&table_name.as_str())
+}
+
+pub fn is_public_system_table(table_name: &KeyString) -> bool {
+    PUBLIC_SYSTEM_TABLES.contains(&table_name.as_str())
+}
+
+/// Builds the requested system table from the current state of the database.
+/// The returned table is a snapshot and changes to it are not reflected anywhere.
+pub fn materialize_system_table(table_name: &KeyString, database: &Database) -> Result<ColumnTable, EzError> {
+    // println!("calling: materialize_system_table()");
+
+    match table_name.as_str() {
+        "ez_tables" => ez_tables(database),
+        "ez_columns" => ez_columns(database),
+        "ez_users" => ez_users(database),
+        "ez_permissions" => ez_permissions(database),
+        "ez_tasks" => database.tasks.to_table(),
+        "ez_namespaces" => namespaces_table(database),
+        "ez_disk" => database.disk.to_table(),
+        "ez_health" => ez_health(database),
+        "ez_tags" => database.tags.to_table(),
+        "ez_locks" => database.locks.to_table(),
+        "ez_thread_pool" => database.pool.to_table(),
+        other => Err(EzError{tag: ErrorTag::Query, text: format!("'{}' is not a system table", other)}),
+    }
+}
+
+fn db_type_name(kind: &DbType) -> KeyString {
+    ksf(kind.name())
+}
+
+fn table_key_name(key: &TableKey) -> KeyString {
+    ksf(key.name())
+}
+
+/// Sizes and counters go in Ints columns. Ones too big for an i32 show as i32::MAX instead of wrapping around.
+fn int_cell(n: impl TryInto<i32>) -> i32 {
+    n.try_into().unwrap_or(i32::MAX)
+}
+
+/// The key of a row about a pair of objects, "first.second". Each name can take up a whole KeyString, so a pair
+/// that doesn't fit keeps what fits of the front and ends in a hash of the whole pair to stay unique.
+fn pair_id(first: &KeyString, second: &str) -> KeyString {
+    let id = format!("{}.{}", first, second);
+    if id.len() <= 64 {
+        return KeyString::from(id.as_str())
+    }
+    let hash = Sha256::digest(id.as_bytes());
+    let suffix = format!("~{:016x}", u64_from_le_slice(&hash[0..8]));
+    let mut end = 64 - suffix.len();
+    while !id.is_char_boundary(end) {
+        end -= 1;
+    }
+    KeyString::from(format!("{}{}", &id[..end], suffix).as_str())
+}
+
+/// One row per table: table_name, rows, columns, byte_size, created_by, last_access, times_accessed, loaded
+/// Tables that were unloaded for being idle are listed with loaded = 0.
+fn ez_tables(database: &Database) -> Result<ColumnTable, EzError> {
+
+    let mut names = Vec::new();
+    let mut rows = Vec::new();
+    let mut columns = Vec::new();
+    let mut sizes = Vec::new();
+    let mut creators = Vec::new();
+    let mut last_accesses = Vec::new();
+    let mut access_counts = Vec::new();
+    let mut loaded = Vec::new();
+
+    let tables = database.buffer_pool.tables.read().unwrap();
+    for (name, table) in tables.iter() {
+        let table = table.read().unwrap();
+        names.push(*name);
+        rows.push(int_cell(table.len()));
+        columns.push(int_cell(table.header.len()));
+        sizes.push(int_cell(table.byte_size()));
+        creators.push(table.metadata.created_by);
+        last_accesses.push(int_cell(table.metadata.last_access.load(Ordering::Relaxed)));
+        access_counts.push(int_cell(table.metadata.times_accessed.load(Ordering::Relaxed)));
+        loaded.push(1);
+    }
+    for (name, stub) in database.buffer_pool.unloaded_tables.read().unwrap().iter() {
+        names.push(*name);
+        rows.push(int_cell(stub.rows));
+        columns.push(int_cell(stub.header.len()));
+        sizes.push(int_cell(stub.byte_size));
+        creators.push(stub.metadata.created_by);
+        last_accesses.push(int_cell(stub.metadata.last_access.load(Ordering::Relaxed)));
+        access_counts.push(int_cell(stub.metadata.times_accessed.load(Ordering::Relaxed)));
+        loaded.push(0);
+    }
+
+    let mut output = ColumnTable::create_empty("ez_tables", "system");
+    output.add_column(ksf("table_name"), DbColumn::Texts(names))?;
+    output.add_column(ksf("rows"), DbColumn::Ints(rows))?;
+    output.add_column(ksf("columns"), DbColumn::Ints(columns))?;
+    output.add_column(ksf("byte_size"), DbColumn::Ints(sizes))?;
+    output.add_column(ksf("created_by"), DbColumn::Texts(creators))?;
+    output.add_column(ksf("last_access"), DbColumn::Ints(last_accesses))?;
+    output.add_column(ksf("times_accessed"), DbColumn::Ints(access_counts))?;
+    output.add_column(ksf("loaded"), DbColumn::Ints(loaded))?;
+    output.sort();
+
+    Ok(output)
+}
+
+/// The admission state plus how many times idle tables have been unloaded and reloaded since startup.
+fn ez_health(database: &Database) -> Result<ColumnTable, EzError> {
+
+    let mut output = database.admission.to_table()?;
+    output.add_column(ksf("table_unloads"), DbColumn::Ints(vec![int_cell(database.buffer_pool.table_unloads.load(Ordering::Relaxed))]))?;
+    output.add_column(ksf("table_reloads"), DbColumn::Ints(vec![int_cell(database.buffer_pool.table_reloads.load(Ordering::Relaxed))]))?;
+    output.add_column(ksf("frames_checked"), DbColumn::Ints(vec![int_cell(database.frames.frames_checked.load(Ordering::Relaxed))]))?;
+    output.add_column(ksf("corrupt_frames"), DbColumn::Ints(vec![int_cell(database.frames.corrupt_frames.load(Ordering::Relaxed))]))?;
+
+    Ok(output)
+}
+
+/// One row per column of every table. The key is "table_name.column_name", see pair_id()
+fn ez_columns(database: &Database) -> Result<ColumnTable, EzError> {
+
+    // Collecting into a BTreeMap keeps the primary key column sorted.
+    let mut rows: BTreeMap<KeyString, (KeyString, KeyString, KeyString, KeyString)> = BTreeMap::new();
+
+    let tables = database.buffer_pool.tables.read().unwrap();
+    for (name, table) in tables.iter() {
+        let table = table.read().unwrap();
+        for item in &table.header {
+            let id = pair_id(name, item.name.as_str());
+            rows.insert(id, (*name, item.name, db_type_name(&item.kind), table_key_name(&item.key)));
+        }
+    }
+
+    let mut ids = Vec::with_capacity(rows.len());
+    let mut table_names = Vec::with_capacity(rows.len());
+    let mut column_names = Vec::with_capacity(rows.len());
+    let mut kinds = Vec::with_capacity(rows.len());
+    let mut keys = Vec::with_capacity(rows.len());
+    for (id, (table_name, column_name, kind, key)) in rows {
+        ids.push(id);
+        table_names.push(table_name);
+        column_names.push(column_name);
+        kinds.push(kind);
+        keys.push(key);
+    }
+
+    let mut output = ColumnTable::create_empty("ez_columns", "system");
+    output.add_column(ksf("id"), DbColumn::Texts(ids))?;
+    output.add_column(ksf("table_name"), DbColumn::Texts(table_names))?;
+    output.add_column(ksf("column_name"), DbColumn::Texts(column_names))?;
+    output.add_column(ksf("kind"), DbColumn::Texts(kinds))?;
+    output.add_column(ksf("key"), DbColumn::Texts(keys))?;
+
+    Ok(output)
+}
+
+/// One row per user: username, admin, can_upload. Password hashes are never exposed.
+fn ez_users(database: &Database) -> Result<ColumnTable, EzError> {
+
+    let mut usernames = Vec::new();
+    let mut admins = Vec::new();
+    let mut uploaders = Vec::new();
+
+    let users = database.users.read().unwrap();
+    for (username, user) in users.iter() {
+        let user = user.read().unwrap();
+        usernames.push(*username);
+        admins.push(user.admin as i32);
+        uploaders.push(user.can_upload as i32);
+    }
+
+    let mut output = ColumnTable::create_empty("ez_users", "system");
+    output.add_column(ksf("username"), DbColumn::Texts(usernames))?;
+    output.add_column(ksf("admin"), DbColumn::Ints(admins))?;
+    output.add_column(ksf("can_upload"), DbColumn::Ints(uploaders))?;
+
+    Ok(output)
+}
+
+/// One row per (user, object) pair the user has any permission on. The key is "username.object", see pair_id()
+fn ez_permissions(database: &Database) -> Result<ColumnTable, EzError> {
+
+    let mut rows: BTreeMap<KeyString, (KeyString, KeyString, i32, i32)> = BTreeMap::new();
+
+    let users = database.users.read().unwrap();
+    for (username, user) in users.iter() {
+        let user = user.read().unwrap();
+        for object in user.can_read.iter().chain(user.can_write.iter()) {
+            let id = pair_id(username, object);
+            rows.insert(id, (
+                *username,
+                KeyString::from(object.as_str()),
+                user.can_read.contains(object) as i32,
+                user.can_write.contains(object) as i32,
+            ));
+        }
+    }
+
+    let mut ids = Vec::with_capacity(rows.len());
+    let mut usernames = Vec::with_capacity(rows.len());
+    let mut objects = Vec::with_capacity(rows.len());
+    let mut reads = Vec::with_capacity(rows.len());
+    let mut writes = Vec::with_capacity(rows.len());
+    for (id, (username, object, read, write)) in rows {
+        ids.push(id);
+        usernames.push(username);
+        objects.push(object);
+        reads.push(read);
+        writes.push(write);
+    }
+
+    let mut output = ColumnTable::create_empty("ez_permissions", "system");
+    output.add_column(ksf("id"), DbColumn::Texts(ids))?;
+    output.add_column(ksf("username"), DbColumn::Texts(usernames))?;
+    output.add_column(ksf("object"), DbColumn::Texts(objects))?;
+    output.add_column(ksf("can_read"), DbColumn::Ints(reads))?;
+    output.add_column(ksf("can_write"), DbColumn::Ints(writes))?;
+
+    Ok(output)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::metrics::render_metrics;
+    use crate::testing_tools::{create_fixed_table, test_database};
+    use crate::ezql::{execute_EZQL_queries, expand_table_globs, Query};
+
+    use super::*;
+
+    #[test]
+    fn test_system_tables() {
+        let database = test_database();
+
+        let tables = materialize_system_table(&ksf("ez_tables"), &database).unwrap();
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables.get_column_int(&ksf("rows")).unwrap()[0], 10);
+        assert_eq!(tables.get_column_text(&ksf("created_by")).unwrap()[0], ksf("test"));
+
+        let columns = materialize_system_table(&ksf("ez_columns"), &database).unwrap();
+        let fixed = database.buffer_pool.tables.read().unwrap();
+        let fixed = fixed.values().next().unwrap().read().unwrap();
+        assert_eq!(columns.len(), fixed.header.len());
+
+        let users = materialize_system_table(&ksf("ez_users"), &database).unwrap();
+        assert_eq!(users.get_column_text(&ksf("username")).unwrap(), &vec![ksf("admin"), ksf("bob")]);
+        assert_eq!(users.get_column_int(&ksf("admin")).unwrap(), &vec![1, 0]);
+
+        let permissions = materialize_system_table(&ksf("ez_permissions"), &database).unwrap();
+        assert_eq!(permissions.len(), 1);
+        assert_eq!(permissions.get_column_int(&ksf("can_write")).unwrap()[0], 0);
+
+        let locks = materialize_system_table(&ksf("ez_locks"), &database).unwrap();
+        assert_eq!(locks.len(), 0);
+
+        let pool = materialize_system_table(&ksf("ez_thread_pool"), &database).unwrap();
+        assert_eq!(pool.get_column_int(&ksf("value")).unwrap().iter().sum::<i32>(), 0);
+
+        assert!(materialize_system_table(&ksf("not_a_system_table"), &database).is_err());
+    }
+
+    #[test]
+    fn test_long_pair_ids() {
+        assert_eq!(pair_id(&ksf("fixed_table"), "ints"), ksf("fixed_table.ints"));
+        // Both names take up most of a KeyString and only differ at the end
+        let table = ksf(&"t".repeat(60));
+        let first = pair_id(&table, &format!("{}_a", "c".repeat(58)));
+        let second = pair_id(&table, &format!("{}_b", "c".repeat(58)));
+        assert_ne!(first, second);
+        assert!(first.as_str().starts_with(&"t".repeat(40)));
+        assert_eq!(first.as_str().len(), 64);
+        assert_eq!(int_cell(u64::MAX), i32::MAX);
+        assert_eq!(int_cell(5usize), 5);
+    }
+
+    #[test]
+    fn test_render_metrics() {
+        let database = test_database();
+        database.metrics.record_batch(&["SELECT"], 700, false);
+        database.metrics.connection_accepted();
+
+        let text = render_metrics(&database);
+        assert!(text.contains("ezdb_queries_total{type=\"SELECT\"} 1\n"));
+        assert!(text.contains("ezdb_query_latency_microseconds_bucket{type=\"SELECT\",le=\"500\"} 0\n"));
+        assert!(text.contains("ezdb_query_latency_microseconds_bucket{type=\"SELECT\",le=\"1000\"} 1\n"));
+        assert!(text.contains("ezdb_table_rows{table=\"fixed_table\"} 10\n"));
+        assert!(text.contains("ezdb_connections_accepted_total 1\n"));
+        // Every sample line is a name, optional labels and a number
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            assert!(line.rsplit(' ').next().unwrap().parse::<f64>().is_ok(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_multi_summary() {
+        let database = Arc::new(test_database());
+        let mut other = create_fixed_table(4);
+        other.name = ksf("fixed_other");
+        database.buffer_pool.add_table(other).unwrap();
+
+        let query: Query = "MULTI_SUMMARY(tables: (fixed_*), columns: ((ints, SUM, COUNT)))".parse().unwrap();
+        let result = execute_EZQL_queries(vec![query], database.clone()).unwrap().unwrap();
+        assert_eq!(result.len(), 18);
+        let tables = result.get_column_text(&ksf("Table")).unwrap();
+        assert_eq!((tables[0], tables[9]), (ksf("fixed_other"), ksf("fixed_table")));
+        let ints = result.get_column_int(&ksf("ints")).unwrap();
+        // SUM is the first row of each summary and COUNT the eighth
+        assert_eq!((ints[0], ints[7]), (6, 4));
+        assert_eq!((ints[9], ints[16]), (45, 10));
+
+        let mut batch = vec!["MULTI_SUMMARY(tables: (fixed_*), columns: ((SUM ints)))".parse::<Query>().unwrap()];
+        expand_table_globs(&mut batch, &database).unwrap();
+        assert!(matches!(&batch[0], Query::MULTI_SUMMARY{tables, ..} if *tables == vec![ksf("fixed_other"), ksf("fixed_table")]));
+
+        let no_match: Query = "MULTI_SUMMARY(tables: (nothing_*), columns: ((SUM ints)))".parse().unwrap();
+        assert_eq!(execute_EZQL_queries(vec![no_match], database.clone()).unwrap_err().tag, ErrorTag::NotFound);
+        let missing_column: Query = "MULTI_SUMMARY(tables: (fixed_*), columns: ((SUM nope)))".parse().unwrap();
+        assert!(execute_EZQL_queries(vec![missing_column], database).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use crate::db_structure::{ColumnTable, DbColumn};
+use crate::utilities::{ksf, EzError, KeyString};
+
+
+/// Work from connections that never set a tag is attributed to this tag.
+pub const UNTAGGED: &str = "untagged";
+
+/// Running counters for a tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct TagCounters {
+    pub queries: u64,
+    pub errors: u64,
+    pub total_latency_micros: u64,
+}
+
+/// Free form labels that clients attach to their work so operators can tell which upstream service
+/// or job is causing load. A tag can be set for a whole connection with the TAG instruction or for a
+/// single batch with TAGGEDQUERY. A batch tag takes precedence over the connection tag, which is kept in
+/// the Session of the connection.
+pub struct TagRegistry {
+    counters: RwLock<BTreeMap<KeyString, TagCounters>>,
+}
+
+impl Default for TagRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TagRegistry {
+    pub fn new() -> TagRegistry {
+        TagRegistry {
+            counters: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record(&self, tag: KeyString, latency_micros: u64, failed: bool) {
+        let mut counters = self.counters.write().unwrap();
+        let entry = counters.entry(tag).or_default();
+        entry.queries += 1;
+        entry.total_latency_micros += latency_micros;
+        if failed {
+            entry.errors += 1;
+        }
+    }
+
+    pub fn get_counters(&self, tag: &KeyString) -> TagCounters {
+        self.counters.read().unwrap().get(tag).copied().unwrap_or_default()
+    }
+
+    /// The ez_tags system table. One row per tag that has been used since the server started.
+    pub fn to_table(&self) -> Result<ColumnTable, EzError> {
+
+        let counters = self.counters.read().unwrap();
+        let mut tags = Vec::new();
+        let mut queries = Vec::new();
+        let mut errors = Vec::new();
+        let mut total_latency = Vec::new();
+        let mut mean_latency = Vec::new();
+        for (tag, counter) in counters.iter() {
+            tags.push(*tag);
+            queries.push(counter.queries as i32);
+            errors.push(counter.errors as i32);
+            total_latency.push(counter.total_latency_micros as i32);
+            mean_latency.push(match counter.queries {
+                0 => 0.0,
+                n => counter.total_latency_micros as f32 / n as f32,
+            });
+        }
+
+        let mut table = ColumnTable::create_empty("ez_tags", "system");
+        table.add_column(ksf("tag"), DbColumn::Texts(tags))?;
+        table.add_column(ksf("queries"), DbColumn::Ints(queries))?;
+        table.add_column(ksf("errors"), DbColumn::Ints(errors))?;
+        table.add_column(ksf("total_latency_micros"), DbColumn::Ints(total_latency))?;
+        table.add_column(ksf("mean_latency_micros"), DbColumn::Floats(mean_latency))?;
+
+        Ok(table)
+    }
+}
+
+/// The tag a piece of work is attributed to. The batch tag wins over the connection tag.
+pub fn resolve_tag(session_tag: Option<KeyString>, batch_tag: Option<KeyString>) -> KeyString {
+    match batch_tag.filter(|tag| !tag.as_str().is_empty()) {
+        Some(tag) => tag,
+        None => session_tag.unwrap_or(ksf(UNTAGGED)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tags() {
+        assert_eq!(resolve_tag(None, None), ksf(UNTAGGED));
+        assert_eq!(resolve_tag(Some(ksf("billing")), None), ksf("billing"));
+        assert_eq!(resolve_tag(Some(ksf("billing")), Some(ksf("nightly_job_42"))), ksf("nightly_job_42"));
+        assert_eq!(resolve_tag(Some(ksf("billing")), Some(KeyString::new())), ksf("billing"));
+
+        let registry = TagRegistry::new();
+
+        registry.record(ksf("billing"), 100, false);
+        registry.record(ksf("billing"), 300, true);
+        let counters = registry.get_counters(&ksf("billing"));
+        assert_eq!(counters, TagCounters{queries: 2, errors: 1, total_latency_micros: 400});
+        assert_eq!(registry.to_table().unwrap().len(), 1);
+    }
+}
@@ -1,8 +1,25 @@
-use std::{collections::{BTreeMap, BTreeSet}, sync::atomic::AtomicU64};
+use std::{collections::{BTreeMap, BTreeSet, HashSet}, path::PathBuf, sync::{atomic::AtomicU64, Arc, RwLock}};
 
 use rand::{distributions::Standard, prelude::Distribution, Rng};
 
-use crate::{db_structure::{ColumnTable, DbColumn, DbType, DbValue, HeaderItem, Metadata, TableKey}, ezql::{AltTest, Condition, KvQuery, OpOrCond, Operator, Query, RangeOrListOrAll, StatOp, Statistic, Test, TestOp, Update, UpdateOp}, utilities::{get_current_time, ksf, ErrorTag, EzError, KeyString}};
+use crate::{db_structure::{Collation, ColumnTable, DbColumn, DbType, DbValue, HeaderItem, LongTexts, Metadata, OnConflict, TableKey}, ezql::{execute_deduplicate_query, execute_delete_query, execute_insert_query, execute_select_query, execute_summary_query, execute_update_query, parse_EZQL, Alteration, AltTest, Condition, IntoTarget, KeyList, KvQuery, OpOrCond, Operator, Query, RangeOrListOrAll, StatOp, Statistic, Test, TestOp, Update, UpdateOp, ValueFilter}, paths::test_file, utilities::{get_current_time, ksf, ErrorTag, EzError, KeyString}};
+use crate::row_table::TableEngine;
+use crate::admission::AdmissionController;
+use crate::auth::User;
+use crate::blob_store::BlobStore;
+use crate::database::Database;
+use crate::disk_monitor::DiskMonitor;
+use crate::disk_utilities::{BufferPool, MAX_BUFFERPOOL_SIZE};
+use crate::frame_checksum::FrameChecks;
+use crate::lock_monitor::LockMonitor;
+use crate::logging::Logger;
+use crate::maintenance::TaskManager;
+use crate::metrics::Metrics;
+use crate::namespaces::NamespaceRegistry;
+use crate::sessions::Sessions;
+use crate::tagging::TagRegistry;
+use crate::thread_pool::PoolStats;
+use crate::write_versions::WriteVersions;
 
 
 fn random_vec<T>(max_length: usize) -> Vec<T>  where Standard: Distribution<T> {
@@ -61,15 +78,21 @@ pub fn random_column_table(max_cols: usize, max_rows: usize) -> ColumnTable {
     let mut header = BTreeSet::new();
     for _ in 0..num_columns {
         let name = random_keystring();
-        let kind: u8 = rng.gen_range(0..3);
+        let kind: u8 = rng.gen_range(0..6);
         let kind = match kind {
             0 => DbType::Int,
             1 => DbType::Text,
             2 => DbType::Float,
-            _ => unreachable!("Kind is a range from [0, 3)")
+            3 => DbType::Duration,
+            4 => DbType::LongText,
+            5 => DbType::Enum,
+            _ => unreachable!("Kind is a range from [0, 6)")
         };
         let key = TableKey::None;
-        header.insert(HeaderItem{name, kind, key});
+        match kind {
+            DbType::Enum => header.insert(HeaderItem::new_enum(name, key, vec![ksf("low"), ksf("medium"), ksf("high")]).unwrap()),
+            _ => header.insert(HeaderItem{name, kind, key, values: Vec::new(), collation: Collation::Binary}),
+        };
     }
     let name = random_keystring();
     let kind: u8 = rng.gen_range(0..2);
@@ -79,7 +102,7 @@ pub fn random_column_table(max_cols: usize, max_rows: usize) -> ColumnTable {
         _ => unreachable!("Kind is a range from [0, 3)")
     };
     let key = TableKey::Primary;
-    header.insert(HeaderItem{name, kind, key});
+    header.insert(HeaderItem{name, kind, key, values: Vec::new(), collation: Collation::Binary});
 
     let mut cols = BTreeMap::new();
 
@@ -108,6 +131,21 @@ pub fn random_column_table(max_cols: usize, max_rows: usize) -> ColumnTable {
                 }
                 cols.insert(name, DbColumn::Texts(col));
             },
+            DbType::Duration => {
+                let mut col: Vec<i64> = Vec::new();
+                for _ in 0..num_rows {
+                    col.push(rng.gen());
+                }
+                cols.insert(name, DbColumn::Durations(col));
+            },
+            DbType::LongText => {
+                let col: Vec<String> = (0..num_rows).map(|_| random_string(200)).collect();
+                cols.insert(name, DbColumn::LongTexts(col.iter().map(|s| s.as_str()).collect()));
+            },
+            DbType::Enum => {
+                let col: Vec<i32> = (0..num_rows).map(|_| rng.gen_range(0..item.values.len() as i32)).collect();
+                cols.insert(name, DbColumn::Ints(col));
+            },
         }
     }
 
@@ -115,6 +153,7 @@ pub fn random_column_table(max_cols: usize, max_rows: usize) -> ColumnTable {
         name,
         header,
         columns: cols,
+        metadata: random_metadata(),
     }
 
 }
@@ -122,7 +161,7 @@ pub fn random_column_table(max_cols: usize, max_rows: usize) -> ColumnTable {
 
 fn random_range_or_list_or_all() -> RangeOrListOrAll {
     let mut rng = rand::thread_rng();
-    let n = rng.gen_range(0..3);
+    let n = rng.gen_range(0..5);
     match n {
         0 => RangeOrListOrAll::All,
         1 => RangeOrListOrAll::Range(random_keystring(), random_keystring()),
@@ -133,6 +172,8 @@ fn random_range_or_list_or_all() -> RangeOrListOrAll {
             }
             RangeOrListOrAll::List(list)
         },
+        3 => RangeOrListOrAll::Keys(KeyList::Ints((0..rng.gen_range(1..1000)).map(|_| rng.gen()).collect())),
+        4 => RangeOrListOrAll::Keys(KeyList::Texts((0..rng.gen_range(1..1000)).map(|_| random_keystring()).collect())),
         _ => unreachable!("Range is limited")
     }
 }
@@ -140,10 +181,11 @@ fn random_range_or_list_or_all() -> RangeOrListOrAll {
 fn random_db_value() -> DbValue {
     let mut rng = rand::thread_rng();
 
-    match rng.gen_range(0..3) {
+    match rng.gen_range(0..4) {
         0 => DbValue::Int(rng.gen()),
         1 => DbValue::Float(rng.gen()),
         2 => DbValue::Text(random_keystring()),
+        3 => DbValue::Duration(rng.gen()),
         _ => unreachable!("Range is limited"),
     }
 }
@@ -186,7 +228,7 @@ fn random_test_op() -> TestOp {
 
     let mut rng = rand::thread_rng();
 
-    match rng.gen_range(0..7) {
+    match rng.gen_range(0..15) {
         0 => TestOp::Contains,
         1 => TestOp::Equals,
         2 => TestOp::NotEquals,
@@ -194,19 +236,58 @@ fn random_test_op() -> TestOp {
         4 => TestOp::Ends,
         5 => TestOp::Greater,
         6 => TestOp::Less,
+        7 => TestOp::NotStarts,
+        8 => TestOp::NotEnds,
+        9 => TestOp::NotContains,
+        10 => TestOp::IsNaN,
+        11 => TestOp::In((0..rng.gen_range(1..6)).map(|_| random_db_value()).collect()),
+        12 => TestOp::Between(random_db_value(), random_db_value()),
+        13 => TestOp::Matches,
+        14 => TestOp::MatchesRegex,
         _ => unreachable!("Range")
     }
     
 }
 
+fn random_limit() -> Option<usize> {
+    let mut rng = rand::thread_rng();
+    match rng.gen_bool(0.5) {
+        true => Some(rng.gen_range(0..1000)),
+        false => None,
+    }
+}
+
 fn random_conditions() -> Vec<OpOrCond> {
     let mut rng = rand::thread_rng();
 
     let mut output = Vec::new();
 
-    for i in 0..rng.gen_range(0..10)*2 + 1 {
+    let len = rng.gen_range(0..10)*2 + 1;
+    let grouped = len > 1 && rng.gen::<bool>();
+    for i in 0..len {
         if i % 2 == 0 {
-            output.push(OpOrCond::Cond(Condition{ attribute: random_keystring(), op: random_test_op(), value: random_db_value() }));
+            if rng.gen_range(0..4) == 0 {
+                output.push(OpOrCond::Not);
+            }
+            if grouped && i == 0 {
+                output.push(OpOrCond::Open);
+            }
+            let op = random_test_op();
+            // in and between hold their values so they take neither a value nor another column
+            let holds_values = !op.values().is_empty();
+            let other_column = match rng.gen_range(0..4) {
+                0 if !holds_values => Some(random_keystring()),
+                _ => None,
+            };
+            let value = match other_column {
+                Some(_) => 0.into(),
+                None if holds_values => 0.into(),
+                None => random_db_value(),
+            };
+            output.push(OpOrCond::Cond(Condition{ attribute: random_keystring(), op, value, other_column }));
+            if grouped && i == 2 {
+                output.push(OpOrCond::Close);
+            }
         } else {
             match rng.gen::<bool>() {
                 true => output.push(OpOrCond::Op(Operator::AND)),
@@ -225,17 +306,20 @@ fn random_updates(max_length: usize) -> Vec<Update> {
 
         let attribute = random_keystring();
         let value = random_db_value();
-        let operator = match rand::thread_rng().gen_range(0..6) {
+        let operator = match rand::thread_rng().gen_range(0..9) {
             0 => UpdateOp::Append,
             1 => UpdateOp::Assign,
             2 => UpdateOp::MinusEquals,
             3 => UpdateOp::PlusEquals,
             4 => UpdateOp::Prepend,
             5 => UpdateOp::TimesEquals,
+            6 => UpdateOp::ToLower,
+            7 => UpdateOp::ToUpper,
+            8 => UpdateOp::Trim,
             _ => unreachable!("range")
         };
     
-        updates.push(Update { attribute, operator, value });
+        updates.push(Update { attribute, operator, value, expression: None });
     }
 
     updates
@@ -252,12 +336,15 @@ fn random_statistics(max_length: usize, max_actions: usize) -> Vec<Statistic> {
         let mut actions = BTreeSet::new();
         for _ in 0..rand::thread_rng().gen_range(1..max_actions) {
 
-            let stat = match rand::thread_rng().gen_range(0..5) {
+            let stat = match rand::thread_rng().gen_range(0..8) {
                 0 => StatOp::SUM,
                 1 => StatOp::MEAN,
                 2 => StatOp::MEDIAN,
                 3 => StatOp::MODE,
                 4 => StatOp::STDEV,
+                5 => StatOp::MIN,
+                6 => StatOp::MAX,
+                7 => StatOp::COUNT,
                 _ => unreachable!("range")
             };
             actions.insert(stat);
@@ -299,19 +386,19 @@ pub fn random_query() -> Query {
     let updates = random_updates(1000);
     let alt_summaries = random_statistics(10, 3);
 
-    let query_type = rng.gen_range(0..8);
+    let query_type = rng.gen_range(0..19);
     match query_type {
         0 => {
-            Query::SELECT{ table_name, primary_keys, columns, conditions }
+            Query::SELECT{ table_name, primary_keys, columns, conditions, distinct: rng.gen_bool(0.5), limit: random_limit() }
         }
         1 => {
             Query::LEFT_JOIN { left_table_name: table_name, right_table_name, match_columns, primary_keys }
         }
         2 => {
-            Query::UPDATE { table_name, primary_keys, conditions, updates }
+            Query::UPDATE { table_name, primary_keys, conditions, updates, version: None }
         }
         3 => {
-            Query::INSERT { table_name, inserts: random_column_table(10, 100) }
+            Query::INSERT { table_name, inserts: random_column_table(10, 100), on_conflict: OnConflict::from_handle(rng.gen_range(0..3)).unwrap() }
         }
         4 => {
             Query::DELETE { primary_keys, table_name, conditions }
@@ -320,25 +407,390 @@ pub fn random_query() -> Query {
             Query::SUMMARY { table_name, columns: alt_summaries }
         },
         6 => {
-            Query::CREATE { table: random_column_table(10, 100) }
+            Query::CREATE { table: random_column_table(10, 100), engine: TableEngine::Column }
         }
         7 => {
             Query::DROP { table_name: random_keystring() }
         }
+        8 => {
+            Query::DEDUPLICATE { table_name: random_keystring() }
+        }
+        9 => {
+            Query::INFER_SCHEMA { table_name, sample: random_column_table(5, 10).to_string() }
+        }
+        10 => {
+            Query::DESCRIBE { table_name }
+        }
+        11 => {
+            let mut query = random_query();
+            while matches!(query, Query::PREPARE { .. } | Query::EXECUTE { .. }) {
+                query = random_query();
+            }
+            Query::PREPARE { query: Box::new(query) }
+        }
+        12 => {
+            Query::EXECUTE { handle: rng.gen_range(0..1_000_000), params: (0..rng.gen_range(0..10)).map(|_| random_db_value()).collect() }
+        }
+        13 => {
+            let query = match rng.gen_bool(0.5) {
+                true => Query::SELECT{ table_name, primary_keys, columns, conditions, distinct: rng.gen_bool(0.5), limit: random_limit() },
+                false => Query::SUMMARY { table_name, columns: alt_summaries },
+            };
+            let target = match rng.gen_bool(0.5) {
+                true => IntoTarget::Table(random_keystring()),
+                false => IntoTarget::Value(random_keystring()),
+            };
+            Query::INTO { query: Box::new(query), target }
+        }
+        14 => Query::HELP,
+        15 => {
+            Query::MULTI_SUMMARY { tables: (0..rng.gen_range(1..5)).map(|_| random_keystring()).collect(), columns: alt_summaries }
+        }
+        16 => {
+            Query::CREATE_FROM_SCHEMA { tables: (0..rng.gen_range(1..4)).map(|_| random_column_table(10, 100)).collect() }
+        }
+        17 => {
+            let alteration = match rng.gen_range(0..4) {
+                0 => Alteration::AddColumn { column: HeaderItem{name: random_keystring(), kind: DbType::Int, key: TableKey::None, values: Vec::new(), collation: Collation::Binary}, default: DbValue::Int(rng.gen()) },
+                1 => Alteration::DropColumn { column: random_keystring() },
+                2 => Alteration::RenameColumn { from: random_keystring(), to: random_keystring() },
+                _ => Alteration::ChangeType { column: random_keystring(), kind: DbType::Float },
+            };
+            Query::ALTER_TABLE { table_name, alteration }
+        }
+        18 => {
+            let mut query = random_query();
+            while matches!(query, Query::PREPARE { .. } | Query::EXECUTE { .. }) {
+                query = random_query();
+            }
+            Query::EXPLAIN { query: Box::new(query) }
+        }
         _ => unreachable!("range")
     }
 
 }
 
+/// Text values for random_realistic_table(). Shared words and stray whitespace give the text
+/// conditions and updates something to match and change.
+const WORDS: [&str; 12] = ["apple", "Banana", "cherry", " date ", "ELDER", "fig", "grape", "apple pie", "banana split", "kiwi", "lemon ", "Mango"];
+
+/// Primary keys of text tables are zero padded so their sort order matches the order they were made in.
+/// Text keys sort like the numbers they are made from, negative ones included, so a table stays sorted either way.
+fn text_key(n: i32) -> KeyString {
+    KeyString::from(format!("key{:05}", n + 10_000).as_str())
+}
+
+fn realistic_value(kind: &DbType) -> DbValue {
+    let mut rng = rand::thread_rng();
+    match kind {
+        DbType::Int => DbValue::Int(rng.gen_range(-1000..1000)),
+        // Quarters are exact in f32 so the engine and a reference implementation agree on every sum
+        DbType::Float => DbValue::Float(rng.gen_range(-400..400) as f32 * 0.25),
+        DbType::Text | DbType::LongText => DbValue::Text(ksf(WORDS[rng.gen_range(0..WORDS.len())])),
+        DbType::Duration => DbValue::Duration(rng.gen_range(0..3600i64) * 1_000_000_000),
+        DbType::Enum => unreachable!("Realistic tables have no enum columns"),
+    }
+}
+
+fn push_value(column: &mut DbColumn, value: DbValue) {
+    match (column, value) {
+        (DbColumn::Ints(col), DbValue::Int(x)) => col.push(x),
+        (DbColumn::Floats(col), DbValue::Float(x)) => col.push(x),
+        (DbColumn::Texts(col), DbValue::Text(x)) => col.push(x),
+        (DbColumn::Durations(col), DbValue::Duration(x)) => col.push(x),
+        (DbColumn::LongTexts(col), DbValue::Text(x)) => col.push(x.as_str()),
+        _ => unreachable!("Values are always made from the kind of the column"),
+    }
+}
+
+fn value_at(column: &DbColumn, index: usize) -> DbValue {
+    match column {
+        DbColumn::Ints(col) => DbValue::Int(col[index]),
+        DbColumn::Floats(col) => DbValue::Float(col[index]),
+        DbColumn::Texts(col) => DbValue::Text(col[index]),
+        DbColumn::Durations(col) => DbValue::Duration(col[index]),
+        DbColumn::LongTexts(col) => DbValue::Text(KeyString::from(col.get(index))),
+    }
+}
+
+/// A table that looks like real data, unlike random_column_table(). Column names are short, the primary key
+/// is sorted and unique, numbers stay in small ranges and text comes from a small vocabulary so random
+/// conditions match some rows but not all of them.
+pub fn random_realistic_table(max_cols: usize, max_rows: usize) -> ColumnTable {
+    let mut rng = rand::thread_rng();
+
+    let num_columns = rng.gen_range(1..max_cols.max(2));
+    let num_rows = rng.gen_range(1..max_rows.max(2));
+
+    let mut key = rng.gen_range(-100..100);
+    let mut keys = Vec::new();
+    for _ in 0..num_rows {
+        keys.push(key);
+        key += rng.gen_range(1..5);
+    }
+
+    let mut table = ColumnTable::create_empty("realistic", "test");
+    match rng.gen::<bool>() {
+        true => table.add_column(ksf("id"), DbColumn::Ints(keys)).unwrap(),
+        false => table.add_column(ksf("id"), DbColumn::Texts(keys.into_iter().map(text_key).collect())).unwrap(),
+    };
+
+    for i in 0..num_columns {
+        let kind = match rng.gen_range(0..4) {
+            0 => DbType::Int,
+            1 => DbType::Float,
+            2 => DbType::Text,
+            3 => DbType::Duration,
+            _ => unreachable!("range"),
+        };
+        let mut column = match kind {
+            DbType::Int => DbColumn::Ints(Vec::new()),
+            DbType::Float => DbColumn::Floats(Vec::new()),
+            DbType::Text => DbColumn::Texts(Vec::new()),
+            DbType::Duration => DbColumn::Durations(Vec::new()),
+            DbType::LongText => DbColumn::LongTexts(LongTexts::new()),
+            DbType::Enum => unreachable!("range"),
+        };
+        for _ in 0..num_rows {
+            push_value(&mut column, realistic_value(&kind));
+        }
+        table.add_column(KeyString::from(format!("c{}", i).as_str()), column).unwrap();
+    }
+
+    table
+}
+
+/// A value for the column that is often taken from one of its rows so equality tests match something.
+fn value_for_column(table: &ColumnTable, item: &HeaderItem) -> DbValue {
+    let mut rng = rand::thread_rng();
+    if table.len() > 0 && rng.gen::<bool>() {
+        value_at(&table.columns[&item.name], rng.gen_range(0..table.len()))
+    } else if item.key == TableKey::Primary {
+        match item.kind {
+            DbType::Int => DbValue::Int(rng.gen_range(-150..500)),
+            _ => DbValue::Text(text_key(rng.gen_range(-150..500))),
+        }
+    } else {
+        realistic_value(&item.kind)
+    }
+}
+
+/// Keys are All or a Range. Key lists are left out since keys_to_indexes() only finds lists that line up
+/// with the start of an Int keyed table.
+fn random_keys_for_table(table: &ColumnTable) -> RangeOrListOrAll {
+    let mut rng = rand::thread_rng();
+    if rng.gen_range(0..3) != 0 {
+        return RangeOrListOrAll::All
+    }
+    let pk = table.header.iter().find(|item| item.key == TableKey::Primary).unwrap();
+    let mut ends = [value_for_column(table, pk), value_for_column(table, pk)];
+    ends.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let [start, stop] = ends.map(|value| match value {
+        DbValue::Int(x) => KeyString::from(x.to_string().as_str()),
+        other => other.to_keystring(),
+    });
+    RangeOrListOrAll::Range(start, stop)
+}
+
+fn random_conditions_for_table(table: &ColumnTable) -> Vec<OpOrCond> {
+    let mut rng = rand::thread_rng();
+    let items: Vec<&HeaderItem> = table.header.iter().collect();
+
+    let mut output = Vec::new();
+    let count = rng.gen_range(0..4);
+    // Sometimes the first two conditions are grouped so precedence is overridden
+    let grouped = count > 2 && rng.gen_range(0..3) == 0;
+    for i in 0..count {
+        if i > 0 {
+            match rng.gen::<bool>() {
+                true => output.push(OpOrCond::Op(Operator::AND)),
+                false => output.push(OpOrCond::Op(Operator::OR)),
+            };
+        }
+        if rng.gen_range(0..4) == 0 {
+            output.push(OpOrCond::Not);
+        }
+        if grouped && i == 0 {
+            output.push(OpOrCond::Open);
+        }
+        let item = items[rng.gen_range(0..items.len())];
+        let op = match item.kind {
+            DbType::Text | DbType::LongText => random_test_op_for_text(),
+            _ => [TestOp::Equals, TestOp::NotEquals, TestOp::Less, TestOp::Greater][rng.gen_range(0..4)].clone(),
+        };
+        // Sometimes a test that holds values, drawn like the others so they match some rows
+        let op = match rng.gen_range(0..6) {
+            0 => TestOp::In((0..rng.gen_range(1..5)).map(|_| value_for_column(table, item)).collect()),
+            1 => TestOp::Between(value_for_column(table, item), value_for_column(table, item)),
+            _ => op,
+        };
+        let value = match op {
+            TestOp::Starts | TestOp::NotStarts | TestOp::Ends | TestOp::NotEnds | TestOp::Contains | TestOp::NotContains | TestOp::Matches => {
+                let word = WORDS[rng.gen_range(0..WORDS.len())];
+                let end = rng.gen_range(1..=word.len());
+                match op {
+                    TestOp::Ends | TestOp::NotEnds => DbValue::Text(ksf(&word[word.len() - end..])),
+                    TestOp::Matches => DbValue::Text(ksf(&format!("*{}?*", &word[..end - 1]))),
+                    _ => DbValue::Text(ksf(&word[..end])),
+                }
+            },
+            _ => value_for_column(table, item),
+        };
+        output.push(OpOrCond::Cond(Condition{attribute: item.name, op, value, other_column: None}));
+        if grouped && i == 1 {
+            output.push(OpOrCond::Close);
+        }
+    }
+
+    output
+}
+
+fn random_test_op_for_text() -> TestOp {
+    match rand::thread_rng().gen_range(0..11) {
+        0 => TestOp::Equals,
+        1 => TestOp::NotEquals,
+        2 => TestOp::Less,
+        3 => TestOp::Greater,
+        4 => TestOp::Starts,
+        5 => TestOp::Ends,
+        6 => TestOp::Contains,
+        7 => TestOp::NotStarts,
+        8 => TestOp::NotEnds,
+        9 => TestOp::NotContains,
+        10 => TestOp::Matches,
+        _ => unreachable!("range"),
+    }
+}
+
+/// Updates to distinct non key columns. Factors are kept small so repeated updates don't overflow.
+fn random_updates_for_table(table: &ColumnTable) -> Vec<Update> {
+    let mut rng = rand::thread_rng();
+
+    let mut updates = Vec::new();
+    for item in table.header.iter().filter(|item| item.key != TableKey::Primary) {
+        if !rng.gen::<bool>() {
+            continue
+        }
+        let (operator, value) = match item.kind {
+            DbType::Int => match rng.gen_range(0..4) {
+                0 => (UpdateOp::Assign, realistic_value(&item.kind)),
+                1 => (UpdateOp::PlusEquals, DbValue::Int(rng.gen_range(-100..100))),
+                2 => (UpdateOp::MinusEquals, DbValue::Int(rng.gen_range(-100..100))),
+                _ => (UpdateOp::TimesEquals, DbValue::Int(rng.gen_range(-1..=1))),
+            },
+            DbType::Float => match rng.gen_range(0..4) {
+                0 => (UpdateOp::Assign, realistic_value(&item.kind)),
+                1 => (UpdateOp::PlusEquals, realistic_value(&item.kind)),
+                2 => (UpdateOp::MinusEquals, realistic_value(&item.kind)),
+                _ => (UpdateOp::TimesEquals, DbValue::Float([-1.0, 0.5, 1.0][rng.gen_range(0..3)])),
+            },
+            DbType::Text | DbType::LongText => match rng.gen_range(0..6) {
+                0 => (UpdateOp::Assign, realistic_value(&item.kind)),
+                1 => (UpdateOp::Append, realistic_value(&item.kind)),
+                2 => (UpdateOp::Prepend, realistic_value(&item.kind)),
+                3 => (UpdateOp::ToLower, DbValue::Text(KeyString::new())),
+                4 => (UpdateOp::ToUpper, DbValue::Text(KeyString::new())),
+                _ => (UpdateOp::Trim, DbValue::Text(KeyString::new())),
+            },
+            DbType::Duration => match rng.gen_range(0..4) {
+                0 => (UpdateOp::Assign, realistic_value(&item.kind)),
+                1 => (UpdateOp::PlusEquals, realistic_value(&item.kind)),
+                2 => (UpdateOp::MinusEquals, realistic_value(&item.kind)),
+                _ => (UpdateOp::TimesEquals, DbValue::Int(rng.gen_range(0..3))),
+            },
+            DbType::Enum => unreachable!("Realistic tables have no enum columns"),
+        };
+        updates.push(Update{attribute: item.name, operator, value, expression: None});
+    }
+
+    updates
+}
+
+/// Rows with the header of the table and keys it doesn't have yet, sorted by key.
+fn random_inserts_for_table(table: &ColumnTable) -> ColumnTable {
+    let mut rng = rand::thread_rng();
+    let pk = table.get_primary_key_col_index();
+
+    let mut keys = BTreeSet::new();
+    for _ in 0..rng.gen_range(1..6) {
+        let n = rng.gen_range(-150..500);
+        let taken = match &table.columns[&pk] {
+            DbColumn::Ints(col) => col.binary_search(&n).is_ok(),
+            DbColumn::Texts(col) => col.binary_search(&text_key(n)).is_ok(),
+            _ => unreachable!("There should never be a float or duration primary key"),
+        };
+        if !taken {
+            keys.insert(n);
+        }
+    }
+
+    let mut inserts = ColumnTable::blank(&table.header, ksf("inserts"), "test");
+    for n in keys {
+        for item in &table.header {
+            let value = if item.name == pk {
+                match item.kind {
+                    DbType::Int => DbValue::Int(n),
+                    _ => DbValue::Text(text_key(n)),
+                }
+            } else {
+                realistic_value(&item.kind)
+            };
+            push_value(inserts.columns.get_mut(&item.name).unwrap(), value);
+        }
+    }
+
+    inserts
+}
+
+/// A SELECT, UPDATE, DELETE or INSERT that is valid against the table: every column exists, every value
+/// has the type of its column and every operator applies to it. Meant for differential testing, see
+/// differential_testing.rs.
+pub fn random_query_for_table(table: &ColumnTable) -> Query {
+    let mut rng = rand::thread_rng();
+
+    let table_name = table.name;
+    let primary_keys = random_keys_for_table(table);
+    let conditions = random_conditions_for_table(table);
+    match rng.gen_range(0..4) {
+        0 => {
+            let columns = if rng.gen::<bool>() {
+                vec![ksf("*")]
+            } else {
+                // Conditions and key ranges are tested on the selected columns so those have to be in it
+                let mut columns: BTreeSet<KeyString> = table.header.iter().map(|item| item.name).filter(|_| rng.gen::<bool>()).collect();
+                columns.insert(table.get_primary_key_col_index());
+                for condition in &conditions {
+                    if let OpOrCond::Cond(cond) = condition {
+                        columns.insert(cond.attribute);
+                    }
+                }
+                columns.into_iter().collect()
+            };
+            Query::SELECT{ table_name, primary_keys, columns, conditions, distinct: false, limit: None }
+        },
+        1 => Query::UPDATE{ table_name, primary_keys, conditions, updates: random_updates_for_table(table), version: None },
+        2 => Query::DELETE{ primary_keys, table_name, conditions },
+        _ => Query::INSERT{ table_name, inserts: random_inserts_for_table(table), on_conflict: OnConflict::Skip },
+    }
+}
+
+
 pub fn random_kv_query() -> KvQuery {
     let mut rng = rand::thread_rng();
 
-    let query_type = rng.gen_range(0..4);
+    let query_type = rng.gen_range(0..11);
     match query_type {
         0 => KvQuery::Create(random_keystring(), random_vec(100)),
         1 => KvQuery::Read(random_keystring()),
         2 => KvQuery::Update(random_keystring(), random_vec(100)),
         3 => KvQuery::Delete(random_keystring()),
+        4 => KvQuery::ReadVersion(random_keystring(), rng.gen_range(0..10)),
+        5 => KvQuery::Rollback(random_keystring(), rng.gen_range(1..10)),
+        6 => KvQuery::ReadMany((0..rng.gen_range(0..5)).map(|_| random_keystring()).collect()),
+        7 => KvQuery::WriteMany((0..rng.gen_range(0..5)).map(|_| (random_keystring(), random_vec(100))).collect()),
+        8 => KvQuery::CreateWithTtl(random_keystring(), random_vec(100), rng.gen_range(1..3600)),
+        9 => KvQuery::UpdateWithTtl(random_keystring(), random_vec(100), rng.gen_range(1..3600)),
+        10 => KvQuery::Scan(random_keystring(), ValueFilter{min_size: rng.gen_range(0..10), body_prefix: random_vec(5), ..ValueFilter::new()}),
         other => panic!()
     }
 }
@@ -360,9 +812,38 @@ pub fn create_fixed_table(n: usize) -> ColumnTable {
     table
 }
 
+/// A database that lives in memory only, holding create_fixed_table(10), an admin named "admin" and a user
+/// named "bob" who can only read fixed_table.
+pub fn test_database() -> Database {
+    let buffer_pool = BufferPool::empty(AtomicU64::new(MAX_BUFFERPOOL_SIZE));
+    buffer_pool.add_table(create_fixed_table(10)).unwrap();
+    let mut users = BTreeMap::new();
+    let mut user = User::new("bob", "bob");
+    user.can_read = HashSet::from(["fixed_table".to_owned()]);
+    users.insert(ksf("admin"), RwLock::new(User::admin("admin", "admin")));
+    users.insert(ksf("bob"), RwLock::new(user));
+    Database {
+        buffer_pool,
+        users: Arc::new(RwLock::new(users)),
+        logger: Logger::init(),
+        tasks: TaskManager::new(),
+        namespaces: NamespaceRegistry::new(),
+        disk: DiskMonitor::default(),
+        admission: AdmissionController::ready(),
+        blobs: BlobStore::new(BlobStore::default_path()),
+        tags: TagRegistry::new(),
+        frames: FrameChecks::new(),
+        locks: LockMonitor::new(),
+        sessions: Sessions::new(),
+        pool: PoolStats::new(),
+        metrics: Metrics::new(),
+        versions: WriteVersions::new(),
+    }
+}
+
 pub fn random_ez_error() -> EzError {
     let mut rng = rand::thread_rng();
-    let tag = match rng.gen_range(0..19) {
+    let tag = match rng.gen_range(0..23) {
         0 => ErrorTag::Utf8,
         1 => ErrorTag::Io,
         2 => ErrorTag::Instruction,
@@ -382,6 +863,10 @@ pub fn random_ez_error() -> EzError {
         16 => ErrorTag::Serialization,
         17 => ErrorTag::Deserialization,
         18 => ErrorTag::Structure,
+        19 => ErrorTag::Unavailable,
+        20 => ErrorTag::NotFound,
+        21 => ErrorTag::Cancelled,
+        22 => ErrorTag::Conflict,
         x => unreachable!()
     };
     let text = random_keystring().as_str().to_string();
@@ -390,6 +875,161 @@ pub fn random_ez_error() -> EzError {
 
 
 
+/// Where query result snapshots are kept, one file per snapshot under test_files. Commit them with the tests that use them.
+pub const SNAPSHOT_DIR: &str = "snapshots";
+
+/// Set this environment variable to any value to rewrite the snapshots a test run compares against instead of failing.
+pub const UPDATE_SNAPSHOTS_VAR: &str = "EZDB_UPDATE_SNAPSHOTS";
+
+/// Unchanged lines shown around each difference in a snapshot diff.
+const DIFF_CONTEXT: usize = 2;
+
+/// What comparing a result against its snapshot found.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotOutcome {
+    /// There was no snapshot, or updating was asked for, so this result was stored.
+    Recorded,
+    Matched,
+    /// The stored snapshot and this result differ. Holds the diff from the snapshot to the result.
+    Differs(String),
+}
+
+pub fn snapshot_path(name: &str) -> PathBuf {
+    test_file(SNAPSHOT_DIR).join(format!("{}.csv", name))
+}
+
+/// The query on the first line after a #, then the result as EZ CSV. Tables keep their rows in primary key order and
+/// their columns in name order so the same result always has the same text.
+pub fn snapshot_text(query: &str, result: &ColumnTable) -> String {
+    format!("# {}\n{}\n", query.trim(), result.to_string().trim_end())
+}
+
+/// Runs a chain of EZQL queries on a copy of the table. Like the executor, each query runs on the result of the one
+/// before it. Writes change the copy and it is the result of the write.
+pub fn run_on_table(query: &str, table: &ColumnTable) -> Result<ColumnTable, EzError> {
+
+    let mut result = table.clone();
+    for query in parse_EZQL(query)? {
+        let next = match query {
+            Query::SELECT { .. } => execute_select_query(&query, &result)?,
+            Query::SUMMARY { .. } => execute_summary_query(&query, &result)?,
+            Query::UPDATE { .. } => { execute_update_query(query, &mut result)?; continue },
+            Query::DELETE { .. } => { execute_delete_query(query, &mut result)?; continue },
+            Query::INSERT { .. } => { execute_insert_query(query, &mut result)?; continue },
+            Query::DEDUPLICATE { .. } => { execute_deduplicate_query(query, &mut result)?; continue },
+            other => return Err(EzError{tag: ErrorTag::Unimplemented, text: format!("Snapshots do not cover: {}", other)}),
+        };
+        result = match next {
+            Some(table) => table,
+            None => return Err(EzError{tag: ErrorTag::Query, text: "A query in the chain gave no result".to_owned()}),
+        };
+    }
+
+    Ok(result)
+}
+
+/// Compares the result against the snapshot with the given name. A missing snapshot is recorded from the result, as is
+/// every snapshot when UPDATE_SNAPSHOTS_VAR is set.
+pub fn check_snapshot(name: &str, query: &str, result: &ColumnTable) -> Result<SnapshotOutcome, EzError> {
+
+    let path = snapshot_path(name);
+    let actual = snapshot_text(query, result);
+    if std::env::var_os(UPDATE_SNAPSHOTS_VAR).is_some() || !path.exists() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, actual)?;
+        return Ok(SnapshotOutcome::Recorded)
+    }
+
+    let expected = std::fs::read_to_string(&path)?;
+    if expected == actual {
+        Ok(SnapshotOutcome::Matched)
+    } else {
+        Ok(SnapshotOutcome::Differs(line_diff(&expected, &actual)))
+    }
+}
+
+/// Runs the query on the table and panics with a diff if the result is not the one in the named snapshot.
+/// The first run records the snapshot. Rerun with EZDB_UPDATE_SNAPSHOTS=1 to accept a change in behavior.
+pub fn assert_query_snapshot(name: &str, query: &str, table: &ColumnTable) {
+
+    let result = match run_on_table(query, table) {
+        Ok(result) => result,
+        Err(e) => panic!("Snapshot '{}' could not run its query: {}", name, e),
+    };
+    match check_snapshot(name, query, &result) {
+        Ok(SnapshotOutcome::Recorded) => println!("Recorded snapshot {}", snapshot_path(name).display()),
+        Ok(SnapshotOutcome::Matched) => (),
+        Ok(SnapshotOutcome::Differs(diff)) => panic!(
+            "Result differs from snapshot {}. Set {} to accept it.\n{}",
+            snapshot_path(name).display(), UPDATE_SNAPSHOTS_VAR, diff
+        ),
+        Err(e) => panic!("Snapshot '{}' could not be read or written: {}", name, e),
+    }
+}
+
+/// The lines removed from `expected` marked with - and the lines added in `actual` marked with +, with a little
+/// unchanged context around them. Longer unchanged stretches are folded. Empty when nothing changed.
+pub fn line_diff(expected: &str, actual: &str) -> String {
+
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+
+    // lcs[i][j] is the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] { lcs[i+1][j+1] + 1 } else { lcs[i+1][j].max(lcs[i][j+1]) };
+        }
+    }
+
+    let mut lines: Vec<(char, &str)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i+1][j] >= lcs[i][j+1]) {
+            lines.push(('-', old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', new[j]));
+            j += 1;
+        }
+    }
+
+    if lines.iter().all(|(mark, _)| *mark == ' ') {
+        return String::new()
+    }
+
+    let near_change = |index: usize| {
+        let start = index.saturating_sub(DIFF_CONTEXT);
+        let end = (index + DIFF_CONTEXT + 1).min(lines.len());
+        lines[start..end].iter().any(|(mark, _)| *mark != ' ')
+    };
+    let mut diff = String::new();
+    let mut folded = 0;
+    for (index, (mark, line)) in lines.iter().enumerate() {
+        if *mark == ' ' && !near_change(index) {
+            folded += 1;
+            continue
+        }
+        if folded > 0 {
+            diff.push_str(&format!("  ... {} unchanged lines\n", folded));
+            folded = 0;
+        }
+        diff.push_str(&format!("{} {}\n", mark, line));
+    }
+    if folded > 0 {
+        diff.push_str(&format!("  ... {} unchanged lines\n", folded));
+    }
+
+    diff
+}
+
+
 #[cfg(test)]
 mod tests {
 
@@ -475,6 +1115,39 @@ mod tests {
         println!("{}", table);
     }
 
+    #[test]
+    fn test_line_diff() {
+        assert_eq!(line_diff("a\nb\nc", "a\nb\nc"), "");
+        assert_eq!(line_diff("a\nb\nc", "a\nx\nc"), "  a\n- b\n+ x\n  c\n");
+
+        let old: String = (0..20).map(|i| format!("{}\n", i)).collect();
+        let new = old.replace("10\n", "ten\n");
+        let diff = line_diff(&old, &new);
+        assert!(diff.starts_with("  ... 8 unchanged lines\n  8\n  9\n- 10\n+ ten\n  11\n  12\n"));
+        assert!(diff.ends_with("  ... 7 unchanged lines\n"));
+    }
+
+    #[test]
+    fn test_query_snapshots() {
+        let name = "test_query_snapshots_scratch";
+        let _ = std::fs::remove_file(snapshot_path(name));
+        let table = create_fixed_table(5);
+        let query = "SELECT(table_name: fixed_table, columns: (ints, texts), conditions: ((ints greater_than 2)))";
+
+        let result = run_on_table(query, &table).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(check_snapshot(name, query, &result).unwrap(), SnapshotOutcome::Recorded);
+        assert_eq!(check_snapshot(name, query, &result).unwrap(), SnapshotOutcome::Matched);
+        assert_query_snapshot(name, query, &table);
+
+        let changed = run_on_table("DELETE(table_name: fixed_table, primary_keys: (3))", &result).unwrap();
+        match check_snapshot(name, query, &changed).unwrap() {
+            SnapshotOutcome::Differs(diff) => assert!(diff.contains("- 3;") && !diff.contains("- 4;")),
+            other => panic!("Expected a difference but got {:?}", other),
+        }
+        std::fs::remove_file(snapshot_path(name)).unwrap();
+    }
+
     #[test]
     fn test_random_kv_query() {
         for _ in 0..100 {
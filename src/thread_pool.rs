@@ -1,43 +1,437 @@
-use std::{collections::{HashMap, VecDeque}, net::TcpStream, os::fd::AsRawFd, sync::{Arc, Condvar, Mutex}};
+use std::{collections::VecDeque, ops::Range, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc, Condvar, Mutex, OnceLock}, time::{Duration, Instant}};
+#[cfg(all(feature = "server", target_os = "linux"))]
+use std::collections::HashMap;
 
 
-use crate::{query_execution::StreamBuffer, server_networking::{answer_kv_query, answer_query, interior_log, perform_administration, perform_maintenance, Database}, utilities::{ksf, CsPair, KeyString}};
+use crate::{db_structure::{ColumnTable, DbColumn}, utilities::{ksf, EzError}};
+#[cfg(all(feature = "server", target_os = "linux"))]
+use crate::{cancellation::{check_cancelled, watch_connection}, database::{perform_maintenance, Database}, frame_checksum::seal_frame, protocol::{encode_error, Request}, server_networking::answer_request, transport::Transport, utilities::ErrorTag};
+#[cfg(all(feature = "server", target_os = "linux"))]
+use crate::config::{log, LogLevel};
+#[cfg(all(feature = "server", target_os = "linux"))]
+use std::os::fd::AsRawFd;
 
 
+pub const DEFAULT_QUEUE_WARNING_DEPTH: u64 = 64;
+
+static QUEUE_WARNING_DEPTH: AtomicU64 = AtomicU64::new(DEFAULT_QUEUE_WARNING_DEPTH);
+
+/// Sets how many jobs may wait in the queue before a warning is logged. 0 turns the warning off.
+pub fn set_queue_warning_depth(depth: u64) {
+    QUEUE_WARNING_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+pub fn queue_warning_depth() -> u64 {
+    QUEUE_WARNING_DEPTH.load(Ordering::Relaxed)
+}
+
+/// How long the queue has to stay deeper than queue_warning_depth() before a warning is logged.
+/// Also the least time between two warnings.
+pub const QUEUE_WARNING_SECS: u64 = 10;
+
+/// Workers that only take jobs from the priority lane, on top of the regular ones. See JobQueue.
+pub const PRIORITY_WORKERS: usize = 1;
+
+pub const DEFAULT_THREAD_POOL_SIZE: usize = 8;
+
+static THREAD_POOL_SIZE: AtomicU64 = AtomicU64::new(DEFAULT_THREAD_POOL_SIZE as u64);
+
+/// Sets how many regular workers the server starts with. Read once when the server starts.
+pub fn set_thread_pool_size(size: usize) {
+    THREAD_POOL_SIZE.store(size as u64, Ordering::Relaxed);
+}
+
+pub fn thread_pool_size() -> usize {
+    THREAD_POOL_SIZE.load(Ordering::Relaxed) as usize
+}
+
+/// Scans of fewer rows than this run on the thread answering the query. Below it handing ranges to the scan pool costs
+/// more than it saves.
+pub const DEFAULT_PARALLEL_SCAN_ROWS: u64 = 200_000;
+
+static PARALLEL_SCAN_ROWS: AtomicU64 = AtomicU64::new(DEFAULT_PARALLEL_SCAN_ROWS);
+
+/// Sets how many rows a filter or summary has to cover before it is split between threads. 0 splits every scan.
+pub fn set_parallel_scan_rows(rows: u64) {
+    PARALLEL_SCAN_ROWS.store(rows, Ordering::Relaxed);
+}
+
+pub fn parallel_scan_rows() -> u64 {
+    PARALLEL_SCAN_ROWS.load(Ordering::Relaxed)
+}
+
+/// Runs scan over 0..len split into one range per core and returns the results in range order.
+/// See scan_in_chunks_with().
+pub fn scan_in_chunks<T: Send>(len: usize, scan: impl Fn(Range<usize>) -> T + Sync) -> Vec<T> {
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+    scan_in_chunks_with(len, parallel_scan_rows() as usize, workers, scan)
+}
+
+/// Runs scan over 0..len split into up to `workers` ranges and returns the results in range order. Scans shorter
+/// than min_rows run whole on the calling thread. The ranges are shared out to the scan pool, see scan_pool(),
+/// and the calling thread works through them as well, so a query never waits for threads that are busy with
+/// other queries or that may be waiting on it. A panic in scan is raised again on the calling thread.
+pub fn scan_in_chunks_with<T: Send>(len: usize, min_rows: usize, workers: usize, scan: impl Fn(Range<usize>) -> T + Sync) -> Vec<T> {
+    if len < min_rows.max(1) || workers < 2 {
+        return vec![scan(0..len)]
+    }
+    let chunk = len.div_ceil(workers);
+    let ranges: Vec<Range<usize>> = (0..len).step_by(chunk).map(|start| start..(start + chunk).min(len)).collect();
+    let state = Arc::new(ChunkedScan {
+        scan: &scan,
+        results: Mutex::new((0..ranges.len()).map(|_| None).collect()),
+        ranges,
+        next: AtomicUsize::new(0),
+        finished: Mutex::new(0),
+        all_finished: Condvar::new(),
+    });
+
+    let pool = scan_pool();
+    for _ in 1..state.ranges.len() {
+        let helper = Arc::clone(&state);
+        let task: Box<dyn FnOnce() + Send + '_> = Box::new(move || helper.run_chunks());
+        // Safe since every range is finished before this function returns and a task that runs later finds
+        // no range left to claim, so it never calls scan or touches a result once they are gone
+        pool.push(unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + '_>, ScanTask>(task) });
+    }
+    state.run_chunks();
+    state.wait();
+
+    let results = std::mem::take(&mut *state.results.lock().unwrap());
+    results.into_iter()
+        .map(|result| match result.expect("Every range of a scan is finished before it returns") {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        })
+        .collect()
+}
+
+/// The ranges of one scan and what came of them. Whoever claims a range next runs it.
+struct ChunkedScan<'a, T> {
+    scan: &'a (dyn Fn(Range<usize>) -> T + Sync),
+    ranges: Vec<Range<usize>>,
+    next: AtomicUsize,
+    results: Mutex<Vec<Option<std::thread::Result<T>>>>,
+    finished: Mutex<usize>,
+    all_finished: Condvar,
+}
+
+impl<T> ChunkedScan<'_, T> {
+    fn run_chunks(&self) {
+        loop {
+            let index = self.next.fetch_add(1, Ordering::Relaxed);
+            if index >= self.ranges.len() {
+                return
+            }
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| (self.scan)(self.ranges[index].clone())));
+            self.results.lock().unwrap()[index] = Some(result);
+            let mut finished = self.finished.lock().unwrap();
+            *finished += 1;
+            if *finished == self.ranges.len() {
+                self.all_finished.notify_all();
+            }
+        }
+    }
+
+    fn wait(&self) {
+        let mut finished = self.finished.lock().unwrap();
+        while *finished < self.ranges.len() {
+            finished = self.all_finished.wait(finished).unwrap();
+        }
+    }
+}
+
+type ScanTask = Box<dyn FnOnce() + Send + 'static>;
+
+/// Threads that help with the ranges of parallel scans. Separate from the workers that answer requests so
+/// a scan never waits for a request, and shared by every query so scans don't start threads of their own.
+pub struct ScanPool {
+    tasks: Mutex<VecDeque<ScanTask>>,
+    task_added: Condvar,
+    threads: usize,
+}
+
+impl ScanPool {
+    fn push(&self, task: ScanTask) {
+        self.tasks.lock().unwrap().push_back(task);
+        self.task_added.notify_one();
+    }
+
+    fn work(&self) {
+        loop {
+            let task = {
+                let mut tasks = self.tasks.lock().unwrap();
+                loop {
+                    match tasks.pop_front() {
+                        Some(task) => break task,
+                        None => tasks = self.task_added.wait(tasks).unwrap(),
+                    }
+                }
+            };
+            task();
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+}
+
+/// The scan pool, started on first use with one thread less than there are cores since the thread that
+/// starts a scan works on it too.
+pub fn scan_pool() -> &'static ScanPool {
+    static POOL: OnceLock<&'static ScanPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get()).saturating_sub(1).max(1);
+        let pool: &'static ScanPool = Box::leak(Box::new(ScanPool{tasks: Mutex::new(VecDeque::new()), task_added: Condvar::new(), threads}));
+        for index in 0..threads {
+            std::thread::Builder::new()
+                .name(format!("ezdb-scan-{}", index))
+                .spawn(move || pool.work())
+                .expect("Could not start a scan thread");
+        }
+        pool
+    })
+}
+
+/// Upper bounds in microseconds of the buckets of the queue wait histogram. A last bucket counts the slower jobs.
+pub const WAIT_BUCKETS_US: [u64; 6] = [100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+#[cfg(all(feature = "server", target_os = "linux"))]
 pub struct Job {
-    pub connection: eznoise::Connection,
+    pub connection: Transport,
+    /// The decrypted message. It still ends with the frame checksum if the connection uses one.
     pub data: Vec<u8>,
+    pub queued_at: Instant,
 }
 
+#[cfg(all(feature = "server", target_os = "linux"))]
+impl Job {
+    /// Decrypts the frame right away so the lane can be picked from its type tag. Frames of a connection are
+    /// still decrypted in the order they arrive since the connection travels with its job.
+    pub fn new(mut connection: Transport, frame: Vec<u8>) -> Job {
+        let data = match connection.open_frame(&frame) {
+            Ok(x) => x,
+            Err(_) => {
+                println!("Could not decrypt job data");
+
+                ksf("Couldn't decrypt").raw().to_vec()
+            },
+        };
+        Job { connection, data, queued_at: Instant::now() }
+    }
+
+    /// Health checks and everything admins send skip the regular jobs so operators can reach an overloaded server.
+    pub fn is_priority(&self, db_ref: &Database) -> bool {
+        self.data.starts_with(ksf("HEALTH").raw()) || db_ref.is_admin(self.connection.peer())
+    }
+}
+
+/// The jobs waiting for a worker, in two lanes. Every worker takes priority jobs first and the reserved
+/// workers take nothing else, so a priority job only ever waits behind other priority jobs.
+#[cfg(all(feature = "server", target_os = "linux"))]
+#[derive(Default)]
+pub struct JobQueue {
+    priority: VecDeque<Job>,
+    regular: VecDeque<Job>,
+}
 
+#[cfg(all(feature = "server", target_os = "linux"))]
+impl JobQueue {
+    pub fn push(&mut self, job: Job, priority: bool) {
+        match priority {
+            true => self.priority.push_back(job),
+            false => self.regular.push_back(job),
+        }
+    }
+
+    pub fn pop(&mut self, reserved: bool) -> Option<Job> {
+        match self.priority.pop_front() {
+            Some(job) => Some(job),
+            None if reserved => None,
+            None => self.regular.pop_front(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.priority.len() + self.regular.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+
+#[cfg(all(feature = "server", target_os = "linux"))]
 pub struct ThreadHandler {
     pub jobs_condvar: Arc<Condvar>,
-    pub job_queue: Arc<Mutex<VecDeque<Job>>>,
-    pub open_connections: Arc<Mutex<HashMap<u64, eznoise::Connection>>>,
+    /// Wakes the reserved workers. See PRIORITY_WORKERS.
+    pub priority_condvar: Arc<Condvar>,
+    pub job_queue: Arc<Mutex<JobQueue>>,
+    pub open_connections: Arc<Mutex<HashMap<u64, Transport>>>,
+    db_ref: Arc<Database>,
 }
 
+#[cfg(all(feature = "server", target_os = "linux"))]
 impl ThreadHandler {
     pub fn push_job(&self, job: Job) {
-        self.job_queue.lock().unwrap().push_back(job);
+        let priority = job.is_priority(&self.db_ref);
+        let mut queue = self.job_queue.lock().unwrap();
+        queue.push(job, priority);
+        let depth = queue.len() as u64;
+        drop(queue);
+        if let Some(warning) = self.db_ref.pool.queue_changed(depth, Instant::now()) {
+            log(LogLevel::Warn, format_args!("WARNING: {}", warning));
+        }
+        // Whichever is free first takes it, a reserved worker or a regular one
+        if priority {
+            self.db_ref.pool.priority_jobs.fetch_add(1, Ordering::Relaxed);
+            self.priority_condvar.notify_one();
+        }
         self.jobs_condvar.notify_one();
     }
 
+    /// Waits until no job is queued or running, for at most `timeout`. Returns whether that happened. The pool
+    /// has to look idle twice in a row since a worker counts itself busy just after taking a job off the queue.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        let mut idle_before = false;
+        while start.elapsed() < timeout {
+            let idle = self.job_queue.lock().unwrap().is_empty() && self.db_ref.pool.busy_workers.load(Ordering::Relaxed) == 0;
+            if idle && idle_before {
+                return true
+            }
+            idle_before = idle;
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+}
+
+/// Live utilization of the worker threads. Listed in the ez_thread_pool system table.
+pub struct PoolStats {
+    pub workers: AtomicU64,
+    pub busy_workers: AtomicU64,
+    pub queue_depth: AtomicU64,
+    pub jobs_completed: AtomicU64,
+    /// Jobs that went in the priority lane. See JobQueue.
+    pub priority_jobs: AtomicU64,
+    /// How long jobs waited in the queue before a worker picked them up. See WAIT_BUCKETS_US.
+    wait_histogram: [AtomicU64; WAIT_BUCKETS_US.len() + 1],
+    deep_since: Mutex<Option<Instant>>,
+    last_warning: Mutex<Option<Instant>>,
 }
 
+impl Default for PoolStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PoolStats {
+    pub fn new() -> PoolStats {
+        PoolStats {
+            workers: AtomicU64::new(0),
+            busy_workers: AtomicU64::new(0),
+            queue_depth: AtomicU64::new(0),
+            jobs_completed: AtomicU64::new(0),
+            priority_jobs: AtomicU64::new(0),
+            wait_histogram: std::array::from_fn(|_| AtomicU64::new(0)),
+            deep_since: Mutex::new(None),
+            last_warning: Mutex::new(None),
+        }
+    }
+
+    pub fn record_wait(&self, wait: Duration) {
+        let micros = wait.as_micros() as u64;
+        let bucket = WAIT_BUCKETS_US.iter().position(|bound| micros < *bound).unwrap_or(WAIT_BUCKETS_US.len());
+        self.wait_histogram[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn wait_histogram(&self) -> Vec<u64> {
+        self.wait_histogram.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect()
+    }
+
+    /// Records the new queue depth. Returns a warning when the queue has been deeper than
+    /// queue_warning_depth() for QUEUE_WARNING_SECS and no warning was given in that time.
+    pub fn queue_changed(&self, depth: u64, now: Instant) -> Option<String> {
+        self.queue_depth.store(depth, Ordering::Relaxed);
+
+        let threshold = queue_warning_depth();
+        let mut deep_since = self.deep_since.lock().unwrap();
+        if threshold == 0 || depth <= threshold {
+            *deep_since = None;
+            return None
+        }
+        let deep_for = now.saturating_duration_since(*deep_since.get_or_insert(now));
+        let interval = Duration::from_secs(QUEUE_WARNING_SECS);
+        if deep_for < interval {
+            return None
+        }
+        let mut last_warning = self.last_warning.lock().unwrap();
+        if last_warning.is_some_and(|last| now.saturating_duration_since(last) < interval) {
+            return None
+        }
+        *last_warning = Some(now);
+
+        Some(format!(
+            "The job queue has held more than {} jobs for {} s. {} jobs are waiting and {} of {} workers are busy",
+            threshold, deep_for.as_secs(), depth, self.busy_workers.load(Ordering::Relaxed), self.workers.load(Ordering::Relaxed)
+        ))
+    }
+
+    /// The ez_thread_pool system table. One row per metric: the worker and queue counts, then one row
+    /// per wait histogram bucket named after its upper bound.
+    pub fn to_table(&self) -> Result<ColumnTable, EzError> {
+        let mut metrics = vec![
+            (ksf("workers"), self.workers.load(Ordering::Relaxed)),
+            (ksf("busy_workers"), self.busy_workers.load(Ordering::Relaxed)),
+            (ksf("queue_depth"), self.queue_depth.load(Ordering::Relaxed)),
+            (ksf("jobs_completed"), self.jobs_completed.load(Ordering::Relaxed)),
+            (ksf("priority_jobs"), self.priority_jobs.load(Ordering::Relaxed)),
+        ];
+        for (i, count) in self.wait_histogram().into_iter().enumerate() {
+            let name = match WAIT_BUCKETS_US.get(i) {
+                Some(bound) => format!("wait_under_{}us", bound),
+                None => format!("wait_over_{}us", WAIT_BUCKETS_US[WAIT_BUCKETS_US.len() - 1]),
+            };
+            metrics.push((ksf(&name), count));
+        }
+
+        let mut table = ColumnTable::create_empty("ez_thread_pool", "system");
+        table.add_column(ksf("metric"), DbColumn::Texts(metrics.iter().map(|(name, _)| *name).collect()))?;
+        table.add_column(ksf("value"), DbColumn::Ints(metrics.iter().map(|(_, value)| *value as i32).collect()))?;
+
+        Ok(table)
+    }
+}
+
+/// Starts number_of_threads regular workers and PRIORITY_WORKERS reserved ones.
+#[cfg(all(feature = "server", target_os = "linux"))]
 pub fn initialize_thread_pool(number_of_threads: usize, db_ref: Arc<Database>) -> ThreadHandler {
 
-    let job_queue: Arc<Mutex<VecDeque<Job>>> = Arc::new(Mutex::new(VecDeque::new()));
+    let job_queue: Arc<Mutex<JobQueue>> = Arc::new(Mutex::new(JobQueue::default()));
 
     let open_connections = Arc::new(Mutex::new(HashMap::new()));
 
     let jobs_queue_condvar = Arc::new(Condvar::new());
+    let priority_condvar = Arc::new(Condvar::new());
+
+    db_ref.pool.workers.store((number_of_threads + PRIORITY_WORKERS) as u64, Ordering::Relaxed);
     
-    for i in 0..number_of_threads {
+    for i in 0..number_of_threads + PRIORITY_WORKERS {
+        let reserved = i >= number_of_threads;
+
         let jobs = job_queue.clone();
 
         let open_connections_clone = open_connections.clone();
 
-        let jobs_condvar = jobs_queue_condvar.clone();
+        let jobs_condvar = match reserved {
+            true => priority_condvar.clone(),
+            false => jobs_queue_condvar.clone(),
+        };
 
         let thread_db_ref = db_ref.clone();
         std::thread::spawn(move || {
@@ -46,40 +440,54 @@ pub fn initialize_thread_pool(number_of_threads: usize, db_ref: Arc<Database>) -
                 let loop_db_ref = thread_db_ref.clone();
 
                 let mut job_lock = jobs.lock().unwrap();
-                let job = job_lock.pop_front();
+                let job = job_lock.pop(reserved);
                 match job {
                     Some(mut job) => {
+                        let depth = job_lock.len() as u64;
                         drop(job_lock);
-                        let data = match job.connection.c1.DecryptWithAd(&[], &job.data) {
-                            Ok(x) => x,
-                            Err(_) => {
-                                println!("Could not decrypt job data");
-
-                                ksf("Couldn't decrypt").raw().to_vec()
-                            },
+                        loop_db_ref.pool.queue_changed(depth, Instant::now());
+                        loop_db_ref.pool.record_wait(job.queued_at.elapsed());
+                        loop_db_ref.pool.busy_workers.fetch_add(1, Ordering::Relaxed);
+                        let data = std::mem::take(&mut job.data);
+                        // Checked before anything is parsed so a corrupt frame is never acted on
+                        let connection_id = job.connection.connection_id();
+                        let checksum = loop_db_ref.sessions.with(connection_id, |session| session.checksum);
+                        let (data, frame_error) = match loop_db_ref.frames.open(checksum, data) {
+                            Ok(data) => (data, None),
+                            Err(e) => (ksf("CORRUPT").raw().to_vec(), Some(e)),
                         };
-                        println!("data: {:?}", &data[64..]);
-                        let result = match KeyString::try_from(&data[0..64]) {
-                            Ok(s) => match s.as_str() {
-                                "QUERY" => answer_query(&data[64..], &mut job.connection, loop_db_ref),
-                                "ADMIN" => perform_administration(&data[64..], loop_db_ref),
-                                "KVQUERY" => answer_kv_query(&data[64..], &mut job.connection, loop_db_ref),
-                                action => {
-                                    println!("Asked to perform unsupported action: '{}'", action);
-
-                                    Ok(s.raw().to_vec())
-                                }
-                            },
-                            Err(e) => {
-                                println!("Could not parse first 64 bytes as a KeyString");
+                        println!("data: {:?}", data.get(64..).unwrap_or_default());
+                        // Query execution checks the socket between queries and gives up if the client is gone
+                        let watch = watch_connection(job.connection.stream().as_raw_fd());
+                        let result = match (frame_error, check_cancelled()) {
+                            (Some(e), _) => Err(e),
+                            (None, Err(e)) => Err(e),
+                            (None, Ok(())) => match decode_request(&data) {
+                                Ok(Request::Health) => answer_request(Request::Health, &mut job.connection, loop_db_ref),
+                                // Everything except the health check and admin reads is turned away while the server is recovering
+                                Ok(request) => match loop_db_ref.admit(job.connection.peer(), &request) {
+                                    Ok(()) => answer_request(request, &mut job.connection, loop_db_ref),
+                                    Err(e) => Err(e),
+                                },
+                                Err(e) => {
+                                    println!("Could not decode the request: {}", e);
 
-                                Err(e)
-                                
+                                    Err(e)
+                                },
                             },
                         };
+                        drop(watch);
                         match result {
+                            // The connection is dropped instead of handed back, which closes it and its locks are already released
+                            Err(e) if e.tag == ErrorTag::Cancelled => {
+                                println!("Request from '{}' was cancelled: {}", job.connection.peer(), e);
+                                thread_db_ref.metrics.request_cancelled();
+                                thread_db_ref.pool.busy_workers.fetch_sub(1, Ordering::Relaxed);
+                                thread_db_ref.pool.jobs_completed.fetch_add(1, Ordering::Relaxed);
+                                continue
+                            },
                             Ok(r) => {
-                                match job.connection.SEND_C2(&r) {
+                                match job.connection.send_to_client(&seal_frame(r, checksum)) {
                                     Ok(_) => (),
                                     Err(_) => println!("Noise Error line {}, column {}", line!(), column!()),
                                 };
@@ -88,18 +496,27 @@ pub fn initialize_thread_pool(number_of_threads: usize, db_ref: Arc<Database>) -
                             Err(e) => {
                                 println!("Encountered an error while trying to carry out action");
 
-                                match job.connection.SEND_C2(&format!("Encountered an error while trying to carry out action.\n Error: '{}'", e).as_bytes()) {
+                                match job.connection.send_to_client(&seal_frame(encode_error(&e), checksum)) {
                                     Ok(_) => (),
                                     Err(_) => println!("Noise Error line {}, column {}", line!(), column!()),
                                 };
                             },
                         };
-                        open_connections_clone.lock().unwrap().insert(job.connection.stream.as_raw_fd() as u64, job.connection);
+                        open_connections_clone.lock().unwrap().insert(job.connection.connection_id(), job.connection);
+                        thread_db_ref.pool.busy_workers.fetch_sub(1, Ordering::Relaxed);
+                        thread_db_ref.pool.jobs_completed.fetch_add(1, Ordering::Relaxed);
                         
                     },
                     None => {
-                        perform_maintenance(loop_db_ref).unwrap();
-                        job_lock = jobs_condvar.wait(job_lock).unwrap();
+                        // Maintenance can take a while and the reserved workers have to stay free
+                        if !reserved {
+                            drop(job_lock);
+                            perform_maintenance(loop_db_ref).unwrap();
+                            job_lock = jobs.lock().unwrap();
+                        }
+                        if job_lock.is_empty() || reserved {
+                            job_lock = jobs_condvar.wait(job_lock).unwrap();
+                        }
                     },
                 }
                 
@@ -110,14 +527,25 @@ pub fn initialize_thread_pool(number_of_threads: usize, db_ref: Arc<Database>) -
 
     ThreadHandler {
         jobs_condvar: jobs_queue_condvar,
+        priority_condvar,
         job_queue: job_queue,
         open_connections,
-
+        db_ref,
     }
 
 }
 
 
+/// Decoders return errors for malformed input but a panic that slips through still only fails the one
+/// request. The client gets an error instead of the worker dying with the connection.
+#[cfg(all(feature = "server", target_os = "linux"))]
+fn decode_request(data: &[u8]) -> Result<Request, EzError> {
+    match std::panic::catch_unwind(|| Request::decode(data)) {
+        Ok(result) => result,
+        Err(_) => Err(EzError{tag: ErrorTag::Deserialization, text: format!("Could not decode a request of {} bytes", data.len())}),
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -125,6 +553,68 @@ mod tests {
 
     use super::*;
 
-    
+    #[test]
+    fn test_scan_in_chunks() {
+        let values: Vec<usize> = (0..1000).collect();
+        let sums = scan_in_chunks_with(values.len(), 0, 4, |range| values[range].iter().sum::<usize>());
+        assert_eq!(sums.len(), 4);
+        assert_eq!(sums.iter().sum::<usize>(), 499_500);
+        // Ranges come back in order
+        let firsts = scan_in_chunks_with(values.len(), 0, 3, |range| range.start);
+        assert_eq!(firsts, vec![0, 334, 668]);
+
+        assert_eq!(scan_in_chunks_with(values.len(), 5000, 4, |range| range), vec![0..1000]);
+        assert_eq!(scan_in_chunks_with(0, 0, 4, |range| range), vec![0..0]);
+    }
+
+    #[test]
+    fn test_scans_share_the_scan_pool() {
+        let values: Vec<usize> = (0..1000).collect();
+
+        // Many scans at once run on the pool and the threads that started them, never on threads of their own
+        let ids: Vec<std::thread::ThreadId> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8).map(|_| scope.spawn(|| scan_in_chunks_with(values.len(), 0, 16, |_| std::thread::current().id()))).collect();
+            handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+        });
+        assert_eq!(ids.len(), 8 * 16);
+        let distinct: std::collections::HashSet<_> = ids.into_iter().collect();
+        assert!(distinct.len() <= scan_pool().threads() + 8, "{} threads ran the scans", distinct.len());
+
+        // A panic reaches the caller and the pool keeps working
+        let panicked = std::panic::catch_unwind(|| scan_in_chunks_with(values.len(), 0, 4, |range| assert!(range.start != 250)));
+        assert!(panicked.is_err());
+        assert_eq!(scan_in_chunks_with(values.len(), 0, 4, |range| values[range].iter().sum::<usize>()).iter().sum::<usize>(), 499_500);
+    }
+
+    #[test]
+    fn test_pool_stats() {
+        let stats = PoolStats::new();
+        stats.workers.store(8, Ordering::Relaxed);
+        stats.record_wait(Duration::from_micros(50));
+        stats.record_wait(Duration::from_millis(5));
+        stats.record_wait(Duration::from_secs(60));
+        assert_eq!(stats.wait_histogram(), vec![1, 0, 1, 0, 0, 0, 1]);
+
+        let start = Instant::now();
+        let deep = queue_warning_depth() + 1;
+        assert!(stats.queue_changed(deep, start).is_none());
+        assert!(stats.queue_changed(deep, start + Duration::from_secs(QUEUE_WARNING_SECS - 1)).is_none());
+        let warning = stats.queue_changed(deep, start + Duration::from_secs(QUEUE_WARNING_SECS)).unwrap();
+        assert!(warning.contains("0 of 8 workers"));
+        // Not repeated right away while the queue stays deep
+        assert!(stats.queue_changed(deep, start + Duration::from_secs(QUEUE_WARNING_SECS + 1)).is_none());
+        // A shallow queue resets the clock
+        assert!(stats.queue_changed(1, start + Duration::from_secs(3 * QUEUE_WARNING_SECS)).is_none());
+        assert!(stats.queue_changed(deep, start + Duration::from_secs(3 * QUEUE_WARNING_SECS)).is_none());
+
+        let table = stats.to_table().unwrap();
+        assert_eq!(table.len(), 5 + WAIT_BUCKETS_US.len() + 1);
+        let metrics = table.get_column_text(&ksf("metric")).unwrap();
+        let values = table.get_column_int(&ksf("value")).unwrap();
+        let value = |name: &str| values[metrics.iter().position(|m| *m == ksf(name)).unwrap()];
+        assert_eq!(value("workers"), 8);
+        assert_eq!(value("queue_depth"), deep as i32);
+        assert_eq!(value("wait_over_10000000us"), 1);
+    }
 
 }
\ No newline at end of file
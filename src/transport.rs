@@ -0,0 +1,305 @@
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::fd::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::io::AsRawSocket;
+
+use crate::utilities::{ErrorTag, EzError};
+
+#[cfg(feature = "tls")]
+pub use tls::{load_client_config, load_server_config, TlsConnection};
+
+
+/// The encrypted channel between a client and the server. The instruction and query protocol on top of it
+/// is the same for every transport. Each message is sent and received as a whole frame.
+pub enum Transport {
+    /// The Noise handshake from eznoise. The default.
+    Noise(eznoise::Connection),
+    /// Standard TLS through rustls for deployments that need it for compliance. Boxed since the rustls
+    /// connection state is much larger than the Noise one.
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsConnection>),
+}
+
+/// The transport the server accepts connections with. Chosen at startup.
+#[derive(Clone)]
+pub enum ServerTransport {
+    Noise,
+    #[cfg(feature = "tls")]
+    Tls(std::sync::Arc<rustls::ServerConfig>),
+}
+
+impl ServerTransport {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerTransport::Noise => "noise",
+            #[cfg(feature = "tls")]
+            ServerTransport::Tls(_) => "tls",
+        }
+    }
+}
+
+impl From<eznoise::Connection> for Transport {
+    fn from(connection: eznoise::Connection) -> Self {
+        Transport::Noise(connection)
+    }
+}
+
+impl Transport {
+    pub fn stream(&self) -> &TcpStream {
+        match self {
+            Transport::Noise(connection) => &connection.stream,
+            #[cfg(feature = "tls")]
+            Transport::Tls(connection) => &connection.stream,
+        }
+    }
+
+    /// The file descriptor of the socket, or the socket handle on Windows. Per connection state on the server is keyed by it.
+    #[cfg(unix)]
+    pub fn connection_id(&self) -> u64 {
+        self.stream().as_raw_fd() as u64
+    }
+
+    #[cfg(windows)]
+    pub fn connection_id(&self) -> u64 {
+        self.stream().as_raw_socket()
+    }
+
+    /// The user that authenticated on this connection. Empty until authentication.
+    pub fn peer(&self) -> &str {
+        match self {
+            Transport::Noise(connection) => &connection.peer,
+            #[cfg(feature = "tls")]
+            Transport::Tls(connection) => &connection.peer,
+        }
+    }
+
+    pub fn set_peer(&mut self, peer: &str) {
+        match self {
+            Transport::Noise(connection) => connection.peer = peer.to_owned(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(connection) => connection.peer = peer.to_owned(),
+        }
+    }
+
+    /// Whether the server loop reads the frames of this connection off the socket itself and hands the
+    /// ciphertext to open_frame(). TLS records can't be split up that way so TLS frames are read whole.
+    pub fn reads_raw_frames(&self) -> bool {
+        match self {
+            Transport::Noise(_) => true,
+            #[cfg(feature = "tls")]
+            Transport::Tls(_) => false,
+        }
+    }
+
+    /// Decrypts a frame that the server loop read off the socket.
+    pub fn open_frame(&mut self, frame: &[u8]) -> Result<Vec<u8>, EzError> {
+        match self {
+            Transport::Noise(connection) => connection.c1.DecryptWithAd(&[], frame)
+                .map_err(|_| EzError{tag: ErrorTag::Crypto, text: "Could not decrypt frame".to_owned()}),
+            #[cfg(feature = "tls")]
+            Transport::Tls(_) => Ok(frame.to_vec()),
+        }
+    }
+
+    /// Server side.
+    pub fn send_to_client(&mut self, message: &[u8]) -> Result<(), EzError> {
+        match self {
+            Transport::Noise(connection) => {
+                connection.SEND_C2(message)?;
+                Ok(())
+            },
+            #[cfg(feature = "tls")]
+            Transport::Tls(connection) => connection.send(message),
+        }
+    }
+
+    /// Server side.
+    pub fn receive_from_client(&mut self) -> Result<Vec<u8>, EzError> {
+        match self {
+            Transport::Noise(connection) => Ok(connection.RECEIVE_C1()?),
+            #[cfg(feature = "tls")]
+            Transport::Tls(connection) => connection.receive(),
+        }
+    }
+
+    /// Client side.
+    pub fn send_to_server(&mut self, message: &[u8]) -> Result<(), EzError> {
+        match self {
+            Transport::Noise(connection) => {
+                connection.SEND_C1(message)?;
+                Ok(())
+            },
+            #[cfg(feature = "tls")]
+            Transport::Tls(connection) => connection.send(message),
+        }
+    }
+
+    /// Client side.
+    pub fn receive_from_server(&mut self) -> Result<Vec<u8>, EzError> {
+        match self {
+            Transport::Noise(connection) => Ok(connection.RECEIVE_C2()?),
+            #[cfg(feature = "tls")]
+            Transport::Tls(connection) => connection.receive(),
+        }
+    }
+}
+
+
+#[cfg(feature = "tls")]
+mod tls {
+    use std::fs::File;
+    use std::io::{BufReader, ErrorKind, Read, Write};
+    use std::net::TcpStream;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use rustls::pki_types::ServerName;
+
+    use crate::utilities::{u64_from_le_slice, ErrorTag, EzError};
+
+    fn tls_error(e: rustls::Error) -> EzError {
+        EzError{tag: ErrorTag::Crypto, text: format!("TLS error: {}", e)}
+    }
+
+    /// Reads the certificate chain and private key PEM files the server presents to clients.
+    pub fn load_server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<rustls::ServerConfig>, EzError> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?)).collect::<Result<Vec<_>, _>>()?;
+        let key = match rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))? {
+            Some(key) => key,
+            None => return Err(EzError{tag: ErrorTag::Crypto, text: format!("No private key in '{}'", key_path.display())}),
+        };
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(tls_error)?;
+        Ok(Arc::new(config))
+    }
+
+    /// Trusts the certificate authorities in the PEM file. Use it when the server certificate isn't signed
+    /// by a public authority.
+    pub fn load_client_config(ca_path: &Path) -> Result<Arc<rustls::ClientConfig>, EzError> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca_path)?)) {
+            roots.add(cert?).map_err(tls_error)?;
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Arc::new(config))
+    }
+
+    /// A TLS session over a blocking TcpStream. Frames are [length: u64][message] inside the TLS stream.
+    pub struct TlsConnection {
+        pub stream: TcpStream,
+        pub peer: String,
+        tls: rustls::Connection,
+    }
+
+    impl TlsConnection {
+        /// Runs the server side of the handshake.
+        pub fn accept(stream: TcpStream, config: Arc<rustls::ServerConfig>) -> Result<TlsConnection, EzError> {
+            let tls = rustls::ServerConnection::new(config).map_err(tls_error)?;
+            TlsConnection{stream, peer: String::new(), tls: tls.into()}.handshake()
+        }
+
+        /// Runs the client side of the handshake. The server certificate must be valid for server_name.
+        pub fn connect(address: &str, server_name: &str, config: Arc<rustls::ClientConfig>) -> Result<TlsConnection, EzError> {
+            let name = match ServerName::try_from(server_name.to_owned()) {
+                Ok(name) => name,
+                Err(_) => return Err(EzError{tag: ErrorTag::Crypto, text: format!("'{}' is not a valid server name", server_name)}),
+            };
+            let tls = rustls::ClientConnection::new(config, name).map_err(tls_error)?;
+            let stream = TcpStream::connect(address)?;
+            TlsConnection{stream, peer: String::new(), tls: tls.into()}.handshake()
+        }
+
+        fn handshake(mut self) -> Result<TlsConnection, EzError> {
+            self.stream.set_nonblocking(false)?;
+            while self.tls.is_handshaking() {
+                self.tls.complete_io(&mut self.stream)?;
+            }
+            Ok(self)
+        }
+
+        pub fn send(&mut self, message: &[u8]) -> Result<(), EzError> {
+            self.tls.writer().write_all(&(message.len() as u64).to_le_bytes())?;
+            self.tls.writer().write_all(message)?;
+            while self.tls.wants_write() {
+                self.tls.write_tls(&mut self.stream)?;
+            }
+            Ok(())
+        }
+
+        pub fn receive(&mut self) -> Result<Vec<u8>, EzError> {
+            // The server loop polls sockets without blocking but a frame is always read whole
+            self.stream.set_nonblocking(false)?;
+            let mut length = [0u8; 8];
+            self.read_plaintext(&mut length)?;
+            let mut message = vec![0u8; u64_from_le_slice(&length) as usize];
+            self.read_plaintext(&mut message)?;
+            Ok(message)
+        }
+
+        fn read_plaintext(&mut self, buffer: &mut [u8]) -> Result<(), EzError> {
+            let mut filled = 0;
+            while filled < buffer.len() {
+                match self.tls.reader().read(&mut buffer[filled..]) {
+                    Ok(0) => return Err(EzError{tag: ErrorTag::Io, text: "TLS connection was closed".to_owned()}),
+                    Ok(read) => filled += read,
+                    // Nothing decrypted yet. Read more records off the socket
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        let (read, _) = self.tls.complete_io(&mut self.stream)?;
+                        if read == 0 && !self.tls.wants_write() {
+                            return Err(EzError{tag: ErrorTag::Io, text: "TLS connection was closed".to_owned()})
+                        }
+                    },
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use std::net::TcpListener;
+
+    use crate::paths::test_file;
+
+    use super::*;
+
+    #[test]
+    fn test_tls_frames() {
+        let server_config = load_server_config(&test_file("tls").join("server.pem"), &test_file("tls").join("server_key.pem")).unwrap();
+        let client_config = load_client_config(&test_file("tls").join("ca.pem")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut connection = Transport::Tls(Box::new(TlsConnection::accept(stream, server_config).unwrap()));
+            let message = connection.receive_from_client().unwrap();
+            connection.send_to_client(&message.repeat(2)).unwrap();
+        });
+
+        let mut connection = Transport::Tls(Box::new(TlsConnection::connect(&address, "localhost", client_config.clone()).unwrap()));
+        let big = vec![7u8; 100_000];
+        connection.send_to_server(&big).unwrap();
+        assert_eq!(connection.receive_from_server().unwrap(), big.repeat(2));
+        server.join().unwrap();
+
+        // A certificate for another name is refused
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server_config = load_server_config(&test_file("tls").join("server.pem"), &test_file("tls").join("server_key.pem")).unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            assert!(TlsConnection::accept(stream, server_config).is_err());
+        });
+        assert!(TlsConnection::connect(&address, "example.com", client_config).is_err());
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+use std::fs::read_dir;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::db_structure::{ColumnTable, DbColumn};
+use crate::disk_utilities::read_table_at;
+use crate::paths::{table_chunks_dir, table_file, trash_dir, trashed_table_dir};
+use crate::utilities::{ksf, ErrorTag, EzError, KeyString};
+
+pub const DEFAULT_TRASH_RETENTION_SECS: u64 = 7 * 24 * 60 * 60;
+/// The names of the files a dropped table keeps in its directory in the trash.
+pub const TRASHED_TABLE_FILE: &str = "table";
+pub const TRASHED_CHUNKS_DIR: &str = "chunks";
+pub const DROPPED_AT_FILE: &str = "dropped_at";
+
+static TRASH_RETENTION_SECS: AtomicU64 = AtomicU64::new(DEFAULT_TRASH_RETENTION_SECS);
+
+/// Sets how many seconds a dropped table stays in the trash before maintenance deletes it for good.
+/// 0 deletes dropped tables on the next maintenance pass.
+pub fn set_trash_retention_secs(secs: u64) {
+    TRASH_RETENTION_SECS.store(secs, Ordering::Relaxed);
+}
+
+pub fn trash_retention_secs() -> u64 {
+    TRASH_RETENTION_SECS.load(Ordering::Relaxed)
+}
+
+/// A dropped table waiting in the trash.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrashedTable {
+    pub name: KeyString,
+    /// Seconds since the epoch.
+    pub dropped_at: u64,
+    /// The size of its files on disk, chunks included.
+    pub bytes: u64,
+}
+
+/// Moves the files of a table into the trash. A table of the same name that was dropped before is deleted
+/// for good, so only the latest drop of a name can be undone.
+pub fn move_to_trash(table_name: &str, now: u64) -> Result<(), EzError> {
+
+    let dir = trashed_table_dir(table_name);
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    std::fs::create_dir_all(&dir)?;
+
+    std::fs::rename(table_file(table_name), dir.join(TRASHED_TABLE_FILE))?;
+    let chunks_dir = table_chunks_dir(table_name);
+    if chunks_dir.exists() {
+        std::fs::rename(&chunks_dir, dir.join(TRASHED_CHUNKS_DIR))?;
+    }
+    // Written last so a drop that fails halfway is never mistaken for a finished one by purge_expired_trash()
+    std::fs::write(dir.join(DROPPED_AT_FILE), now.to_string())?;
+
+    Ok(())
+}
+
+/// Moves the files of a dropped table back to where the buffer pool loads tables from.
+/// Fails if the table is not in the trash or a table of the same name has been written since.
+pub fn restore_from_trash(table_name: &str) -> Result<(), EzError> {
+
+    let dir = trashed_table_dir(table_name);
+    if !dir.join(TRASHED_TABLE_FILE).exists() {
+        return Err(EzError{tag: ErrorTag::NotFound, text: format!("No dropped table named '{}' in the trash", table_name)})
+    }
+    if table_file(table_name).exists() {
+        return Err(EzError{tag: ErrorTag::Structure, text: format!("A table named '{}' exists. Drop it before restoring the old one", table_name)})
+    }
+
+    let chunks = dir.join(TRASHED_CHUNKS_DIR);
+    if chunks.exists() {
+        let target = table_chunks_dir(table_name);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(&chunks, &target)?;
+    }
+    std::fs::rename(dir.join(TRASHED_TABLE_FILE), table_file(table_name))?;
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+/// Reads a dropped table from the trash without restoring it. None if the drop never finished moving its file.
+pub fn read_trashed_table(table_name: &str) -> Result<Option<ColumnTable>, EzError> {
+    let dir = trashed_table_dir(table_name);
+    let path = dir.join(TRASHED_TABLE_FILE);
+    if !path.exists() {
+        return Ok(None)
+    }
+    Ok(Some(read_table_at(table_name, &path, &dir.join(TRASHED_CHUNKS_DIR))?))
+}
+
+/// Every table in the trash in name order.
+pub fn list_trash() -> Result<Vec<TrashedTable>, EzError> {
+
+    let dir = trash_dir();
+    if !dir.exists() {
+        return Ok(Vec::new())
+    }
+
+    let mut trashed = Vec::new();
+    for entry in read_dir(&dir)? {
+        let entry = entry?;
+        let name = KeyString::from_str_checked(&entry.file_name().to_string_lossy())?;
+        let dropped_at = match std::fs::read_to_string(entry.path().join(DROPPED_AT_FILE)) {
+            Ok(text) => text.trim().parse::<u64>().map_err(|_| EzError{tag: ErrorTag::Deserialization, text: format!("Unreadable drop time '{}' for table '{}' in the trash", text, name)})?,
+            // Left behind by a drop that didn't finish. Counts as dropped long ago so it is purged first
+            Err(_) => 0,
+        };
+        trashed.push(TrashedTable{name, dropped_at, bytes: dir_size(&entry.path())?});
+    }
+    trashed.sort_by_key(|table| table.name);
+
+    Ok(trashed)
+}
+
+/// Deletes one table from the trash for good, or every table if no name is given. Returns the names deleted.
+pub fn purge_trash(table_name: Option<&KeyString>) -> Result<Vec<KeyString>, EzError> {
+
+    let mut purged = Vec::new();
+    for trashed in list_trash()? {
+        if table_name.is_some_and(|name| *name != trashed.name) {
+            continue
+        }
+        std::fs::remove_dir_all(trashed_table_dir(trashed.name.as_str()))?;
+        purged.push(trashed.name);
+    }
+    if let Some(name) = table_name {
+        if purged.is_empty() {
+            return Err(EzError{tag: ErrorTag::NotFound, text: format!("No dropped table named '{}' in the trash", name)})
+        }
+    }
+
+    Ok(purged)
+}
+
+/// Deletes the tables that were dropped more than retention_secs ago. Returns the names deleted.
+pub fn purge_expired_trash(now: u64, retention_secs: u64) -> Result<Vec<KeyString>, EzError> {
+
+    let mut purged = Vec::new();
+    for trashed in list_trash()? {
+        if trashed.dropped_at.saturating_add(retention_secs) <= now {
+            std::fs::remove_dir_all(trashed_table_dir(trashed.name.as_str()))?;
+            purged.push(trashed.name);
+        }
+    }
+
+    Ok(purged)
+}
+
+/// The answer to the trash admin commands. One row per dropped table with when it was dropped,
+/// how many seconds it has left before it is purged and its size on disk.
+pub fn trash_table(trashed: &[TrashedTable], now: u64) -> Result<ColumnTable, EzError> {
+    let retention = trash_retention_secs();
+    let mut table = ColumnTable::create_empty("ez_trash", "system");
+    table.add_column(ksf("table_name"), DbColumn::Texts(trashed.iter().map(|t| t.name).collect()))?;
+    table.add_column(ksf("dropped_at"), DbColumn::Durations(trashed.iter().map(|t| t.dropped_at as i64 * 1_000_000_000).collect()))?;
+    table.add_column(ksf("expires_in"), DbColumn::Ints(trashed.iter().map(|t| (t.dropped_at + retention).saturating_sub(now) as i32).collect()))?;
+    table.add_column(ksf("bytes"), DbColumn::Ints(trashed.iter().map(|t| t.bytes as i32).collect()))?;
+    Ok(table)
+}
+
+fn dir_size(path: &Path) -> Result<u64, EzError> {
+    let mut size = 0;
+    for entry in read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += match metadata.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => metadata.len(),
+        };
+    }
+    Ok(size)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_utilities::{read_table_file, write_table_chunks};
+
+    #[test]
+    fn test_trash_roundtrip() {
+        crate::paths::create_data_dirs().unwrap();
+        let name = "trash_roundtrip_test";
+        let table = ColumnTable::from_csv_string("id,i-P;name,t-N\n1;a\n2;b\n3;c", name, "test").unwrap();
+        write_table_chunks(name, &table, None, 2).unwrap();
+
+        move_to_trash(name, 1_000).unwrap();
+        assert!(!table_file(name).exists());
+        assert!(!table_chunks_dir(name).exists());
+        let trashed = list_trash().unwrap();
+        let entry = trashed.iter().find(|t| t.name.as_str() == name).unwrap();
+        assert_eq!(entry.dropped_at, 1_000);
+        assert!(entry.bytes > 0);
+        assert_eq!(read_trashed_table(name).unwrap(), Some(table.clone()));
+
+        restore_from_trash(name).unwrap();
+        assert_eq!(read_table_file(name).unwrap(), table);
+        assert!(restore_from_trash(name).is_err());
+
+        move_to_trash(name, 1_000).unwrap();
+        assert!(purge_expired_trash(1_050, 100).unwrap().iter().all(|purged| purged.as_str() != name));
+        assert!(purge_expired_trash(1_100, 100).unwrap().contains(&ksf(name)));
+        assert!(!trashed_table_dir(name).exists());
+        assert!(purge_trash(Some(&ksf(name))).is_err());
+        assert_eq!(read_trashed_table(name).unwrap(), None);
+    }
+}
@@ -1,26 +1,42 @@
+#[cfg(target_arch = "x86_64")]
 use std::arch::asm;
 use std::fmt::Display;
+#[cfg(feature = "simd")]
 use std::simd;
 use std::io::{ErrorKind, Read};
 use std::net::TcpStream;
 use std::num::{ParseFloatError, ParseIntError};
-use std::simd::num::SimdInt;
+#[cfg(feature = "simd")]
+use std::simd::cmp::SimdOrd;
+#[cfg(feature = "simd")]
+use std::simd::num::{SimdFloat, SimdInt};
 use std::str::{self, Utf8Error};
 use std::string::FromUtf8Error;
+#[cfg(feature = "server")]
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{usize, fmt};
 
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
 use std::arch::x86_64;
 
 use ezcbor::cbor::{byteslice_from_cbor, byteslice_to_cbor, Cbor, CborError};
+#[cfg(feature = "crypto")]
 use eznoise::CipherState;
 use fnv::FnvHashMap;
+#[cfg(feature = "crypto")]
 use aes_gcm::aead;
 use sha2::{Sha256, Digest};
 
 use crate::auth::AuthenticationError;
-use crate::db_structure::Value;
-use crate::server_networking::Database;
+#[cfg(feature = "server")]
+use crate::auth::authenticate_user;
+#[cfg(feature = "server")]
+use crate::database::Database;
+#[cfg(feature = "server")]
+use crate::protocol::Credentials;
+#[cfg(feature = "server")]
+use crate::transport::Transport;
 
 
 pub const INSTRUCTION_BUFFER: usize = 1024;
@@ -48,6 +64,14 @@ pub enum ErrorTag {
     Serialization,
     Deserialization,
     Structure,
+    Unavailable,
+    /// A table, column, key or user that the request names does not exist.
+    NotFound,
+    /// The client disconnected before the request finished so the rest of it was not carried out.
+    Cancelled,
+    /// A conditional write found the rows changed since the client last read them, or an INSERT that refuses
+    /// conflicts found a key that is already in the table. Nothing was written.
+    Conflict,
 }
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Ord)]
@@ -79,6 +103,10 @@ impl EzError {
             ErrorTag::Serialization => binary.extend_from_slice(ksf("Serialization").raw()),
             ErrorTag::Deserialization => binary.extend_from_slice(ksf("Deserialization").raw()),
             ErrorTag::Structure => binary.extend_from_slice(ksf("Structure").raw()),
+            ErrorTag::Unavailable => binary.extend_from_slice(ksf("Unavailable").raw()),
+            ErrorTag::NotFound => binary.extend_from_slice(ksf("NotFound").raw()),
+            ErrorTag::Cancelled => binary.extend_from_slice(ksf("Cancelled").raw()),
+            ErrorTag::Conflict => binary.extend_from_slice(ksf("Conflict").raw()),
         };
 
         binary.extend_from_slice(&self.text.len().to_le_bytes());
@@ -88,7 +116,7 @@ impl EzError {
     }
 
     pub fn from_binary(binary: &[u8]) -> Result<EzError, EzError> {
-        let tag = KeyString::try_from(&binary[0..64])?;
+        let tag = KeyString::try_from(checked_slice(binary, 0, 64, "Error")?)?;
         let tag = match tag.as_str() {
             "Utf8" => ErrorTag::Utf8,
             "Io" => ErrorTag::Io,
@@ -109,10 +137,14 @@ impl EzError {
             "Serialization" => ErrorTag::Serialization,
             "Deserialization" => ErrorTag::Deserialization,
             "Structure" => ErrorTag::Structure,
+            "Unavailable" => ErrorTag::Unavailable,
+            "NotFound" => ErrorTag::NotFound,
+            "Cancelled" => ErrorTag::Cancelled,
+            "Conflict" => ErrorTag::Conflict,
             other => return Err(EzError{tag: ErrorTag::Unimplemented, text: format!("No error type called '{}'", other)})
         };
-        let len = u64_from_le_slice(&binary[64..72]) as usize;
-        let text = String::from_utf8(binary[72..72+len].to_vec())?;
+        let len = u64_from_le_slice(checked_slice(binary, 64, 8, "Error")?) as usize;
+        let text = String::from_utf8(checked_slice(binary, 72, len, "Error")?.to_vec())?;
 
         Ok(EzError{tag, text})
     }
@@ -141,6 +173,10 @@ impl Display for EzError {
             ErrorTag::Serialization => disp.push_str("Serialization"),
             ErrorTag::Deserialization => disp.push_str("Deserialization"),
             ErrorTag::Structure => disp.push_str("Structure"),
+            ErrorTag::Unavailable => disp.push_str("Unavailable"),
+            ErrorTag::NotFound => disp.push_str("NotFound"),
+            ErrorTag::Cancelled => disp.push_str("Cancelled"),
+            ErrorTag::Conflict => disp.push_str("Conflict"),
         };
         disp.push_str("\nError text:\n");
         disp.push_str(&self.text);
@@ -181,6 +217,7 @@ impl From<AuthenticationError> for EzError {
     }
 }
 
+#[cfg(feature = "crypto")]
 impl From<aead::Error> for EzError {
     fn from(e: aead::Error) -> Self {
         let tag = ErrorTag::Crypto;
@@ -224,6 +261,7 @@ impl From<FromUtf8Error> for EzError {
     }
 }
 
+#[cfg(feature = "crypto")]
 impl From<eznoise::NoiseError> for EzError {
     fn from(_e: eznoise::NoiseError) -> Self {
         let tag = ErrorTag::Io;
@@ -233,6 +271,19 @@ impl From<eznoise::NoiseError> for EzError {
 }
 
 
+/// When strict KeyString mode is on, input longer than 64 bytes is rejected with an error in all parsing paths
+/// (csv, query text, protocol) instead of being silently truncated. Off by default.
+static STRICT_KEYSTRINGS: AtomicBool = AtomicBool::new(false);
+
+/// Turns strict KeyString mode on or off for the whole process.
+pub fn set_strict_keystrings(strict: bool) {
+    STRICT_KEYSTRINGS.store(strict, Ordering::Relaxed);
+}
+
+pub fn strict_keystrings() -> bool {
+    STRICT_KEYSTRINGS.load(Ordering::Relaxed)
+}
+
 #[repr(align(8))]
 #[derive(Clone, Copy, Hash, PartialEq)]
 pub struct KeyString {
@@ -249,8 +300,7 @@ impl fmt::Debug for KeyString {
 
 impl fmt::Display for KeyString {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let text = std::str::from_utf8(&self.inner).expect(&format!("A KeyString should always be valid utf8.\nThe KeyString that was just attempted to Display was:\n{:x?}", self.inner));
-        write!(f, "{}", text)
+        write!(f, "{}", self.as_str())
     }   
 }
 
@@ -261,25 +311,10 @@ impl Default for KeyString {
 }
 
 /// Turns a &str into a KeyString. If the &str has more than 64 bytes, the last bytes will be cut.
+/// This ignores strict mode. Use KeyString::from_input() when parsing user input.
 impl From<&str> for KeyString {
     fn from(s: &str) -> Self {
-
-        let mut inner = [0u8;64];
-
-        let mut min = std::cmp::min(s.len(), 64);
-        inner[0..min].copy_from_slice(&s.as_bytes()[0..min]);
-
-        loop {
-            if min == 0 {break}
-            match std::str::from_utf8(&inner[0..min]) {
-                Ok(_) => break,
-                Err(_) => min -= 1,
-            }
-        }
-
-        KeyString {
-            inner
-        }
+        KeyString::from_str_lossy(s)
     }
 }
 
@@ -287,6 +322,10 @@ impl TryFrom<&[u8]> for KeyString {
     type Error = EzError;
 
     fn try_from(s: &[u8]) -> Result<Self, Self::Error> {
+        if s.len() > 64 && strict_keystrings() {
+            return Err(EzError{tag: ErrorTag::OversizedData, text: format!("KeyString can be at most 64 bytes. Received {} bytes", s.len())})
+        }
+
         let mut inner = [0u8;64];
 
         let min = std::cmp::min(s.len(), 64);
@@ -329,7 +368,10 @@ impl Cbor for KeyString {
             Ok(t) => t,
             Err(_) => return Err(CborError::Unexpected(format!("Error originated in KeyString implementation")))
         };
-        Ok((KeyString::from(text.as_str()), bytes_read))
+        match KeyString::from_input(text.as_str()) {
+            Ok(key) => Ok((key, bytes_read)),
+            Err(e) => Err(CborError::Unexpected(e.text)),
+        }
     }
 }
 
@@ -341,6 +383,48 @@ impl KeyString {
         }
     }
 
+    /// Turns a &str into a KeyString, cutting it at the last char boundary before 64 bytes.
+    /// Always truncates, regardless of strict mode.
+    pub fn from_str_lossy(s: &str) -> Self {
+
+        let mut inner = [0u8;64];
+
+        let mut min = std::cmp::min(s.len(), 64);
+        inner[0..min].copy_from_slice(&s.as_bytes()[0..min]);
+
+        loop {
+            if min == 0 {break}
+            match std::str::from_utf8(&inner[0..min]) {
+                Ok(_) => break,
+                Err(_) => min -= 1,
+            }
+        }
+
+        KeyString {
+            inner
+        }
+    }
+
+    /// Turns a &str into a KeyString, returning an error if it doesn't fit in 64 bytes.
+    /// Always strict, regardless of strict mode.
+    pub fn from_str_checked(s: &str) -> Result<Self, EzError> {
+        if s.len() > 64 {
+            return Err(EzError{tag: ErrorTag::OversizedData, text: format!("KeyString can be at most 64 bytes. '{}' is {} bytes", s, s.len())})
+        }
+
+        Ok(KeyString::from_str_lossy(s))
+    }
+
+    /// Turns user input into a KeyString. Errors on over-length input in strict mode and truncates otherwise.
+    /// All parsing paths (csv, query text, protocol) should go through this.
+    pub fn from_input(s: &str) -> Result<Self, EzError> {
+        if strict_keystrings() {
+            KeyString::from_str_checked(s)
+        } else {
+            Ok(KeyString::from_str_lossy(s))
+        }
+    }
+
     pub fn len(&self) -> usize {
         let mut output = 0;
         for byte in self.inner {
@@ -375,6 +459,13 @@ impl KeyString {
         &self.inner
     }
 
+    /// The same text with ASCII letters in lower case. Lowering ASCII never changes the length so it always fits.
+    pub fn to_ascii_lowercase(&self) -> KeyString {
+        let mut inner = self.inner;
+        inner.make_ascii_lowercase();
+        KeyString{inner}
+    }
+
     /// These functions may panic and should only be called if you are certain that the KeyString contains a valid number
     pub fn to_i32(&self) -> i32 {
         self.as_str().parse::<i32>().unwrap()
@@ -385,6 +476,39 @@ impl KeyString {
         self.as_str().parse::<f32>().unwrap()
     }
 
+    /// Lowercases the KeyString. ASCII text is transformed in place on the raw bytes.
+    /// Non ASCII text goes through String and is truncated if the result no longer fits in 64 bytes.
+    pub fn to_lowercase(&self) -> KeyString {
+        if self.inner.is_ascii() {
+            let mut output = *self;
+            output.inner.make_ascii_lowercase();
+            output
+        } else {
+            KeyString::from_str_lossy(&self.as_str().to_lowercase())
+        }
+    }
+
+    /// Uppercases the KeyString. See to_lowercase()
+    pub fn to_uppercase(&self) -> KeyString {
+        if self.inner.is_ascii() {
+            let mut output = *self;
+            output.inner.make_ascii_uppercase();
+            output
+        } else {
+            KeyString::from_str_lossy(&self.as_str().to_uppercase())
+        }
+    }
+
+    /// Removes leading and trailing whitespace
+    pub fn trim(&self) -> KeyString {
+        let trimmed = self.as_str().trim();
+        if trimmed.len() == self.len() {
+            *self
+        } else {
+            KeyString::from_str_lossy(trimmed)
+        }
+    }
+
     pub fn to_i32_checked(&self) -> Result<i32, ParseIntError> {
         self.as_str().parse::<i32>()
     }
@@ -610,6 +734,9 @@ pub enum Instruction {
     NewUser,
     MetaListTables,
     MetaListKeyValues,
+    OpenCursor,
+    FetchPage,
+    CloseCursor,
 }
 
 impl Display for Instruction {
@@ -622,6 +749,9 @@ impl Display for Instruction {
             Instruction::NewUser => write!(f, "NewUser()"),
             Instruction::MetaListTables => write!(f, "MetaListTables"),
             Instruction::MetaListKeyValues => write!(f, "MetaListKeyValues"),
+            Instruction::OpenCursor => write!(f, "OpenCursor()"),
+            Instruction::FetchPage => write!(f, "FetchPage()"),
+            Instruction::CloseCursor => write!(f, "CloseCursor()"),
         }
     }
 }
@@ -656,21 +786,24 @@ impl From<Utf8Error> for InstructionError {
     }
 }
 
+#[cfg(feature = "crypto")]
 pub struct SocketSide {
     pub stream: TcpStream,
     pub work_status: Option<CsPair>,
 }
 
+#[cfg(feature = "crypto")]
 pub struct CsPair {
     pub c1: CipherState,
     pub c2: CipherState,
 }
 
 /// THe server side of the Connection exchange
-pub fn perform_handshake_and_authenticate(s: eznoise::KeyPair, stream: TcpStream, db_ref: Arc<Database>) -> Result<eznoise::Connection, EzError> {
+#[cfg(feature = "server")]
+pub fn perform_handshake_and_authenticate(s: eznoise::KeyPair, stream: TcpStream, db_ref: Arc<Database>) -> Result<Transport, EzError> {
     
-    let mut connection = eznoise::ESTABLISH_CONNECTION(stream, s.clone())?;
-    let auth_buffer = connection.RECEIVE_C1()?;
+    let mut connection = Transport::Noise(eznoise::ESTABLISH_CONNECTION(stream, s.clone())?);
+    let auth_buffer = connection.receive_from_client()?;
 
     println!("About to parse auth_string");
     let username = match bytes_to_str(&auth_buffer[0..512]) {
@@ -680,24 +813,11 @@ pub fn perform_handshake_and_authenticate(s: eznoise::KeyPair, stream: TcpStream
             return Err(EzError{tag: ErrorTag::Utf8, text: e.to_string()});
         }
     };
-    let password = &auth_buffer[512..];
-    let password = ez_hash(bytes_to_str(password).unwrap().as_bytes());
+    let password = bytes_to_str(&auth_buffer[512..])?;
     println!("About to verify username and password");
 
-    let users_lock = db_ref.users.read().unwrap();
-    if !users_lock.contains_key(&KeyString::from(username)) {
-        println!("printing keys..");
-
-        for key in users_lock.keys() {
-            println!("key: '{}'", key);
-        }
-        println!("Username:\n\t'{}'\n...is wrong", username);
-        return Err(EzError{tag: ErrorTag::Authentication, text: format!("Username: '{}' does not exist", username)});
-    } else if db_ref.users.read().unwrap()[&KeyString::from(username)].read().unwrap().password != password {
-        // println!("thread_users_lock[username].password: {:?}", user_lock.password);
-        // println!("password: {:?}", password);
-        // println!("Password hash:\n\t{:?}\n...is wrong", password);
-        return Err(EzError{tag: ErrorTag::Authentication, text: "Wrong password.".to_owned()});
+    if authenticate_user(&db_ref.users, username, password)? {
+        db_ref.save_users()?;
     }
     Ok(
         connection
@@ -705,36 +825,21 @@ pub fn perform_handshake_and_authenticate(s: eznoise::KeyPair, stream: TcpStream
 
 }
 
-pub fn authenticate_client(connection: &mut eznoise::Connection, db_ref: Arc<Database>) -> Result<(), EzError> {
-    let auth_buffer = connection.RECEIVE_C1()?;
+#[cfg(feature = "server")]
+pub fn authenticate_client(connection: &mut Transport, db_ref: Arc<Database>) -> Result<(), EzError> {
+    let auth_buffer = connection.receive_from_client()?;
 
     println!("About to parse auth_string");
-    let username = match bytes_to_str(&auth_buffer[0..512]) {
-        Ok(s) => s,
-        Err(e) => {
-            println!("failed to read auth_string from bytes because: {}", e);
-            return Err(EzError{tag: ErrorTag::Utf8, text: e.to_string()});
-        }
-    };
-    connection.peer = username.to_string();
-    let password = &auth_buffer[512..];
-    let password = ez_hash(bytes_to_str(password).unwrap().as_bytes());
+    let Credentials{username, password, checksum} = Credentials::decode(&auth_buffer)?;
+    let username = username.as_str();
+    connection.set_peer(username);
     println!("About to verify username and password");
 
-    let users_lock = db_ref.users.read().unwrap();
-    println!("taken MUTEX on users");
-    if !users_lock.contains_key(&KeyString::from(username)) {
-        println!("printing keys..");
-
-        for key in users_lock.keys() {
-            println!("key: '{}'", key);
-        }
-        println!("Username:\n\t'{}'\n...is wrong", username);
-        return Err(EzError{tag: ErrorTag::Authentication, text: format!("Username: '{}' does not exist", username)});
-    } else if db_ref.users.read().unwrap()[&KeyString::from(username)].read().unwrap().password != password {
-        println!("password: {:?}", password);
-        return Err(EzError{tag: ErrorTag::Authentication, text: "Wrong password.".to_owned()});
+    // Users from before salted hashes are rehashed on their first login and saved right away
+    if authenticate_user(&db_ref.users, username, password.as_str())? {
+        db_ref.save_users()?;
     }
+    db_ref.sessions.with(connection.connection_id(), |session| session.checksum = checksum);
     Ok(())
 }
 
@@ -811,6 +916,7 @@ pub fn get_precise_time() -> u128 {
 }
 
 /// Count cycles for benchmarking
+#[cfg(target_arch = "x86_64")]
 #[inline(always)]
 pub fn rdtsc() -> u64 {
     
@@ -824,6 +930,14 @@ pub fn rdtsc() -> u64 {
     ((hi as u64) << 32) | (lo as u64)
 }
 
+/// Other architectures have no cycle counter to read so this counts nanoseconds of a monotonic clock instead.
+#[cfg(not(target_arch = "x86_64"))]
+#[inline(always)]
+pub fn rdtsc() -> u64 {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START.get_or_init(std::time::Instant::now).elapsed().as_nanos() as u64
+}
+
 /// Incredibly convoluted way to print the current date. Copied from StackOverflow
 pub fn time_print(s: &str, cycles: u64) {
     
@@ -942,6 +1056,16 @@ pub fn i32_from_le_slice(slice: &[u8]) -> i32 {
     i32::from_le_bytes(l)
 }
 
+/// The len bytes of binary starting at from, or a Deserialization error saying what was cut short.
+/// Lengths and offsets read out of a binary come from clients and files, so they go through this
+/// before they are used to index anything.
+pub fn checked_slice<'a>(binary: &'a [u8], from: usize, len: usize, what: &str) -> Result<&'a [u8], EzError> {
+    match from.checked_add(len).and_then(|to| binary.get(from..to)) {
+        Some(slice) => Ok(slice),
+        None => Err(EzError{tag: ErrorTag::Deserialization, text: format!("{} is cut short. Needed {} bytes at offset {} of {}", what, len, from, binary.len())}),
+    }
+}
+
 /// Creates a u32 from a &[u8] of length 4. Panics if len is different than 4.
 #[inline]
 pub fn u32_from_le_slice(slice: &[u8]) -> u32 {
@@ -960,6 +1084,15 @@ pub fn u64_from_le_slice(slice: &[u8]) -> u64 {
     u64::from_le_bytes(l)
 }
 
+/// Creates a i64 from a &[u8] of length 8. Panics if len is different than 8.
+#[inline]
+pub fn i64_from_le_slice(slice: &[u8]) -> i64 {
+
+    assert!(slice.len() == 8);
+    let l: [u8;8] = [ slice[0], slice[1], slice[2], slice[3], slice[4], slice[5], slice[6], slice[7] ];
+    i64::from_le_bytes(l)
+}
+
 /// Creates a u32 from a &[u8] of length 4. Panics if len is different than 4.
 #[inline]
 pub fn f32_from_le_slice(slice: &[u8]) -> f32 {   
@@ -995,6 +1128,66 @@ where T: Display  {
     printer
 }
 
+/// Whether the text matches the glob pattern. '*' matches any run of characters and '?' any single one.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last '*' was and how much of the text it had taken when we last backtracked to it
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            },
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+#[cfg(feature = "regex")]
+pub use regex::Regex;
+
+/// Stands in for regex::Regex without the regex feature. No pattern compiles so there is never one to match with.
+#[cfg(not(feature = "regex"))]
+#[derive(Debug)]
+pub enum Regex {}
+
+#[cfg(not(feature = "regex"))]
+impl Regex {
+    pub fn is_match(&self, _text: &str) -> bool {
+        match *self {}
+    }
+}
+
+/// Compiles a regex for the matches_regex test. It matches anywhere in the text unless anchored with ^ and $.
+#[cfg(feature = "regex")]
+pub fn compile_regex(pattern: &str) -> Result<Regex, EzError> {
+    Regex::new(pattern).map_err(|e| EzError{tag: ErrorTag::Query, text: format!("'{}' is not a valid regex: {}", pattern, e)})
+}
+
+#[cfg(not(feature = "regex"))]
+pub fn compile_regex(_pattern: &str) -> Result<Regex, EzError> {
+    Err(EzError{tag: ErrorTag::Query, text: "This server was built without the regex feature so 'matches_regex' can't be used".to_owned()})
+}
+
 
 #[inline]
 pub fn chunk3_vec<T>(list: &[T]) -> Option<[&T;3]> {
@@ -1016,6 +1209,7 @@ pub fn chunk3_vec<T>(list: &[T]) -> Option<[&T;3]> {
     Some([one, two, three])
 }
 
+#[cfg(feature = "simd")]
 #[inline]
 pub fn sum_i32_slice(slice: &[i32]) -> i32 {
 
@@ -1047,6 +1241,13 @@ pub fn sum_i32_slice(slice: &[i32]) -> i32 {
     sum
 }
 
+#[cfg(not(feature = "simd"))]
+#[inline]
+pub fn sum_i32_slice(slice: &[i32]) -> i32 {
+    slice.iter().fold(0, |acc: i32, x| acc.saturating_add(*x))
+}
+
+#[cfg(feature = "simd")]
 #[inline]
 pub fn sum_f32_slice(slice: &[f32]) -> f32 {
 
@@ -1081,6 +1282,14 @@ pub fn sum_f32_slice(slice: &[f32]) -> f32 {
     sum
 }
 
+#[cfg(not(feature = "simd"))]
+#[inline]
+pub fn sum_f32_slice(slice: &[f32]) -> f32 {
+    slice.iter().sum()
+}
+
+/// Sum with SSE intrinsics, kept to benchmark against the std::simd version.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
 pub unsafe fn raw_sum_f32_slice(slice: &[f32]) -> f32 {
 
     let mut suma = x86_64::_mm_setzero_ps();
@@ -1089,10 +1298,10 @@ pub unsafe fn raw_sum_f32_slice(slice: &[f32]) -> f32 {
     let mut sumd = x86_64::_mm_setzero_ps();
     let mut i = 0;
     while i + 15 < slice.len() {
-        suma = x86_64::_mm_add_ps(suma, x86_64::_mm_load_ps(slice[i..i+4].as_ptr()));
-        sumb = x86_64::_mm_add_ps(sumb, x86_64::_mm_load_ps(slice[i+4..i+8].as_ptr()));
-        sumc = x86_64::_mm_add_ps(sumc, x86_64::_mm_load_ps(slice[i+8..i+12].as_ptr()));
-        sumd = x86_64::_mm_add_ps(sumd, x86_64::_mm_load_ps(slice[i+12..i+16].as_ptr()));
+        suma = x86_64::_mm_add_ps(suma, x86_64::_mm_loadu_ps(slice[i..i+4].as_ptr()));
+        sumb = x86_64::_mm_add_ps(sumb, x86_64::_mm_loadu_ps(slice[i+4..i+8].as_ptr()));
+        sumc = x86_64::_mm_add_ps(sumc, x86_64::_mm_loadu_ps(slice[i+8..i+12].as_ptr()));
+        sumd = x86_64::_mm_add_ps(sumd, x86_64::_mm_loadu_ps(slice[i+12..i+16].as_ptr()));
         i += 16;
     }
 
@@ -1121,6 +1330,16 @@ pub unsafe fn raw_sum_f32_slice(slice: &[f32]) -> f32 {
     sum
 }
 
+/// Plain sum where the SSE intrinsics aren't available.
+///
+/// # Safety
+/// Always safe to call. It is only unsafe to match the x86_64 version.
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+pub unsafe fn raw_sum_f32_slice(slice: &[f32]) -> f32 {
+    slice.iter().sum()
+}
+
+#[cfg(feature = "simd")]
 #[inline]
 pub fn mean_i32_slice(slice: &[i32]) -> f32 {
 
@@ -1151,6 +1370,12 @@ pub fn mean_i32_slice(slice: &[i32]) -> f32 {
     sum / slice.len() as f32
 }
 
+#[cfg(not(feature = "simd"))]
+#[inline]
+pub fn mean_i32_slice(slice: &[i32]) -> f32 {
+    slice.iter().map(|x| *x as f32).sum::<f32>() / slice.len() as f32
+}
+
 #[inline]
 pub fn mean_f32_slice(slice: &[f32]) -> f32 {
 
@@ -1205,6 +1430,7 @@ pub fn mode_string_slice(slice: &[KeyString]) -> KeyString {
 }
 
 
+#[cfg(feature = "simd")]
 #[inline]
 pub fn stdev_i32_slice(slice: &[i32]) -> f32 {
 
@@ -1249,6 +1475,15 @@ pub fn stdev_i32_slice(slice: &[i32]) -> f32 {
 
 }
 
+#[cfg(not(feature = "simd"))]
+#[inline]
+pub fn stdev_i32_slice(slice: &[i32]) -> f32 {
+    let mean = mean_i32_slice(slice);
+    let variance: f32 = slice.iter().map(|x| (*x as f32 - mean) * (*x as f32 - mean)).sum();
+    (variance/slice.len() as f32).sqrt()
+}
+
+#[cfg(feature = "simd")]
 #[inline]
 pub fn stdev_f32_slice(slice: &[f32]) -> f32 {
 
@@ -1292,6 +1527,14 @@ pub fn stdev_f32_slice(slice: &[f32]) -> f32 {
     (variance/slice.len() as f32).sqrt()
 }
 
+#[cfg(not(feature = "simd"))]
+#[inline]
+pub fn stdev_f32_slice(slice: &[f32]) -> f32 {
+    let mean = mean_f32_slice(slice);
+    let variance: f32 = slice.iter().map(|x| (x - mean) * (x - mean)).sum();
+    (variance/slice.len() as f32).sqrt()
+}
+
 #[inline]
 fn partition<T: Copy + PartialOrd>(data: &[T]) -> (Vec<T>, T, Vec<T>) {
 
@@ -1355,132 +1598,219 @@ pub fn median_f32_slice(data: &[f32]) -> f32 {
     }
 }
 
+/// The smallest value or i32::MAX for an empty slice.
+#[cfg(feature = "simd")]
 #[inline]
-pub fn bytes_from_strings(strings: &[&str]) -> Vec<u8> {
+pub fn min_i32_slice(slice: &[i32]) -> i32 {
 
-    let mut v = Vec::with_capacity(strings.len()*64);
-    for string in strings {
-        v.extend_from_slice(KeyString::from(*string).raw());
+    let mut mina = simd::i32x4::splat(i32::MAX);
+    let mut minb = simd::i32x4::splat(i32::MAX);
+    let mut i = 0;
+    while i + 7 < slice.len() {
+        mina = mina.simd_min(simd::i32x4::from_slice(&slice[i..i+4]));
+        minb = minb.simd_min(simd::i32x4::from_slice(&slice[i+4..i+8]));
+        i += 8;
     }
 
-    v
+    let mut min = mina.simd_min(minb).reduce_min();
+    while i < slice.len() {
+        min = min.min(slice[i]);
+        i += 1;
+    }
+
+    min
 }
 
-/// Helper function that parses a response from instruction_send_and_confirm().
+#[cfg(not(feature = "simd"))]
 #[inline]
-pub fn parse_response(response: &str, username: &str, table_name: &str) -> Result<(), EzError> {
+pub fn min_i32_slice(slice: &[i32]) -> i32 {
+    slice.iter().copied().fold(i32::MAX, i32::min)
+}
 
-    if response == "OK" {
-        Ok(())
-    } else if response == "IU" {
-        Err(EzError{tag: ErrorTag::ParseResponse, text: format!("Username: {}, is invalid", username)})
-    } else if response == "IP" {
-        Err(EzError{tag: ErrorTag::ParseResponse, text: "Password is invalid".to_owned()})
-    } else if response == ("NT") {
-        Err(EzError{tag: ErrorTag::ParseResponse, text: format!("No such table as {}", table_name)})
-    } else {
-        panic!("Need to handle error: {}", response);
+/// The largest value or i32::MIN for an empty slice.
+#[cfg(feature = "simd")]
+#[inline]
+pub fn max_i32_slice(slice: &[i32]) -> i32 {
+
+    let mut maxa = simd::i32x4::splat(i32::MIN);
+    let mut maxb = simd::i32x4::splat(i32::MIN);
+    let mut i = 0;
+    while i + 7 < slice.len() {
+        maxa = maxa.simd_max(simd::i32x4::from_slice(&slice[i..i+4]));
+        maxb = maxb.simd_max(simd::i32x4::from_slice(&slice[i+4..i+8]));
+        i += 8;
+    }
+
+    let mut max = maxa.simd_max(maxb).reduce_max();
+    while i < slice.len() {
+        max = max.max(slice[i]);
+        i += 1;
     }
 
+    max
 }
 
-pub fn kv_query_results_to_binary(query_results: &Vec<Result<Option<Value>, EzError>>) -> Vec<u8> {
-    let mut binary = Vec::new();
-    binary.extend_from_slice(&query_results.len().to_le_bytes());
-    for _ in 0..query_results.len() {
-        binary.extend_from_slice(&[0u8;8]);
+#[cfg(not(feature = "simd"))]
+#[inline]
+pub fn max_i32_slice(slice: &[i32]) -> i32 {
+    slice.iter().copied().fold(i32::MIN, i32::max)
+}
+
+/// The smallest value or infinity for an empty slice. NaNs are skipped.
+#[cfg(feature = "simd")]
+#[inline]
+pub fn min_f32_slice(slice: &[f32]) -> f32 {
+
+    let mut mina = simd::f32x4::splat(f32::INFINITY);
+    let mut minb = simd::f32x4::splat(f32::INFINITY);
+    let mut i = 0;
+    while i + 7 < slice.len() {
+        mina = mina.simd_min(simd::f32x4::from_slice(&slice[i..i+4]));
+        minb = minb.simd_min(simd::f32x4::from_slice(&slice[i+4..i+8]));
+        i += 8;
     }
-    let mut offsets = Vec::new();
 
-    for result in query_results {
-        dbg!(&result);
-        match result {
-            Ok(x) => match x {
-                Some(value) => {
-                    let len = value.body.len();
-                    let mut temp = Vec::new();
-                    temp.extend_from_slice(ksf("VALUE").raw());
-                    temp.extend_from_slice(value.name.raw());
-                    temp.extend_from_slice(&len.to_le_bytes());
-                    temp.extend_from_slice(&value.body);
-                    offsets.push(temp.len());
-                    binary.extend_from_slice(&temp);
-                },
-                None => {
-                    let mut temp = Vec::new();
-                    temp.extend_from_slice(ksf("NONE").raw());
-                    offsets.push(temp.len());
-                    binary.extend_from_slice(&temp);
-                }
-            },
-            Err(e) => {
-                let mut temp = Vec::new();
-                temp.extend_from_slice(ksf("ERROR").raw());
-                temp.extend_from_slice(&e.to_binary());
-                offsets.push(temp.len());
-                binary.extend_from_slice(&temp);
-            }
-        }
+    let mut min = mina.simd_min(minb).reduce_min();
+    while i < slice.len() {
+        min = min.min(slice[i]);
+        i += 1;
     }
 
+    min
+}
+
+#[cfg(not(feature = "simd"))]
+#[inline]
+pub fn min_f32_slice(slice: &[f32]) -> f32 {
+    slice.iter().copied().fold(f32::INFINITY, f32::min)
+}
+
+/// The largest value or negative infinity for an empty slice. NaNs are skipped.
+#[cfg(feature = "simd")]
+#[inline]
+pub fn max_f32_slice(slice: &[f32]) -> f32 {
+
+    let mut maxa = simd::f32x4::splat(f32::NEG_INFINITY);
+    let mut maxb = simd::f32x4::splat(f32::NEG_INFINITY);
     let mut i = 0;
-    for offset in offsets {
-        binary[8+i..8+i+8].copy_from_slice(&offset.to_le_bytes());
+    while i + 7 < slice.len() {
+        maxa = maxa.simd_max(simd::f32x4::from_slice(&slice[i..i+4]));
+        maxb = maxb.simd_max(simd::f32x4::from_slice(&slice[i+4..i+8]));
         i += 8;
     }
-    binary
+
+    let mut max = maxa.simd_max(maxb).reduce_max();
+    while i < slice.len() {
+        max = max.max(slice[i]);
+        i += 1;
+    }
+
+    max
+}
+
+#[cfg(not(feature = "simd"))]
+#[inline]
+pub fn max_f32_slice(slice: &[f32]) -> f32 {
+    slice.iter().copied().fold(f32::NEG_INFINITY, f32::max)
+}
+
+/// Sums durations. Saturates instead of overflowing like sum_i32_slice()
+#[inline]
+pub fn sum_i64_slice(slice: &[i64]) -> i64 {
+
+    slice.iter().fold(0i64, |acc, x| acc.saturating_add(*x))
+}
+
+#[inline]
+pub fn mean_i64_slice(slice: &[i64]) -> f64 {
+
+    slice.iter().map(|x| *x as f64).sum::<f64>() / slice.len() as f64
+}
+
+#[inline]
+pub fn median_i64_slice(data: &[i64]) -> f64 {
+
+    match data.len() {
+        even if even % 2 == 0 => {
+            let fst_med = select(data, (even / 2) - 1);
+            let snd_med = select(data, even / 2);
+
+            (fst_med as f64 + snd_med as f64) / 2.0
+        },
+        odd => select(data, odd / 2) as f64
+    }
 }
 
-pub fn kv_query_results_from_binary(binary: &[u8]) -> Result<Vec<Result<Option<Value>, EzError>>, EzError> {
-    let number_of_responses = u64_from_le_slice(&binary[0..8]) as usize;
-    let mut offsets = Vec::new();
-    let mut last = 0;
-    for i in 0..number_of_responses {
-        let offset = u64_from_le_slice(&binary[8+8*i..8+8*i+8]) as usize;
-        offsets.push(last + offset);
-        last += offset;
+#[inline]
+pub fn mode_i64_slice(slice: &[i64]) -> i64 {
+
+    let mut map = FnvHashMap::default();
+    for item in slice {
+        map
+        .entry(item)
+        .and_modify(|n| *n += 1)
+        .or_insert(1);
     }
 
-    println!("offsets: {:?}", offsets);
-    
-    let body = &binary[8+8*offsets.len()..];
-    
-    let mut results = Vec::new();
-    for i in 0..offsets.len() {
-        println!("i: {}", i);
-        let current_blob: &[u8];
-        if i == 0 {
-            current_blob = &body[0..offsets[i]];
-        } else {
-            current_blob = &body[offsets[i-1]..offsets[i]];
+    let mut max = 0;
+    let mut result = 0;
+    for (key, value) in map {
+        if value > max {
+            max = value;
+            result = *key;
         }
+    }
+    result
+}
 
-        let tag = KeyString::try_from(&current_blob[0..64]).unwrap();
-        match tag.as_str() {
-            "VALUE" => {
-                let name = KeyString::try_from(&current_blob[64..128])?;
-                let len = u64_from_le_slice(&current_blob[128..136]) as usize;
-                let value = current_blob[136..136+len].to_vec();
-                let value = Value {name, body: value};
-                results.push(Ok(Some(value)));
-            },
-            "ERROR" => {
-                let error = EzError::from_binary(&current_blob[64..])?;
-                results.push(Err(error));
-            } ,
-            "NONE"  => {
-                results.push(Ok(None));
-            },
-            other => {
-                results.push(Err(EzError{tag: ErrorTag::Query, text: format!("Incorrectly formatted response. '{}' is not a valid response type", other)}));
-            }
-        }
+#[inline]
+pub fn min_i64_slice(slice: &[i64]) -> i64 {
+
+    slice.iter().copied().min().unwrap_or(i64::MAX)
+}
+
+#[inline]
+pub fn max_i64_slice(slice: &[i64]) -> i64 {
+
+    slice.iter().copied().max().unwrap_or(i64::MIN)
+}
 
+#[inline]
+pub fn stdev_i64_slice(slice: &[i64]) -> f64 {
+
+    let mean = mean_i64_slice(slice);
+    let variance = slice.iter().map(|x| (*x as f64 - mean) * (*x as f64 - mean)).sum::<f64>() / slice.len() as f64;
+    variance.sqrt()
+}
+
+#[inline]
+pub fn bytes_from_strings(strings: &[&str]) -> Vec<u8> {
+
+    let mut v = Vec::with_capacity(strings.len()*64);
+    for string in strings {
+        v.extend_from_slice(KeyString::from(*string).raw());
     }
 
-    Ok(results)
+    v
 }
 
+/// Helper function that parses a response from instruction_send_and_confirm().
+#[inline]
+pub fn parse_response(response: &str, username: &str, table_name: &str) -> Result<(), EzError> {
+
+    if response == "OK" {
+        Ok(())
+    } else if response == "IU" {
+        Err(EzError{tag: ErrorTag::ParseResponse, text: format!("Username: {}, is invalid", username)})
+    } else if response == "IP" {
+        Err(EzError{tag: ErrorTag::ParseResponse, text: "Password is invalid".to_owned()})
+    } else if response == ("NT") {
+        Err(EzError{tag: ErrorTag::ParseResponse, text: format!("No such table as {}", table_name)})
+    } else {
+        panic!("Need to handle error: {}", response);
+    }
+
+}
 
 #[cfg(test)]
 mod tests {
@@ -1489,31 +1819,32 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_kv_queries_serde() {
-        let results: Vec<Result<Option<Value>, EzError>> = vec![
-            Ok(
-                Some(Value{name: ksf("test2"), body: vec![8,7,6,5,4,3,2,1]}),
-            ),
-            Ok(
-                Some(Value{name: ksf("test2"), body: vec![0,0,0,0,0,0,0,0]}),
-            ),
-            Ok(
-                Some(Value{name: ksf("test1"), body: vec![1,2,3,4,5,6,7,8]}),
-            ),
-            Ok(
-                None,
-            ),
-            Err(
-                EzError{tag: ErrorTag::Query, text: "Test".to_owned()}
-            )
-
-        ];
-
-        let binary = kv_query_results_to_binary(&results);
+    fn test_glob_matches() {
+        assert!(glob_matches("sales.*", "sales.2024"));
+        assert!(glob_matches("sales.*", "sales."));
+        assert!(!glob_matches("sales.*", "sales"));
+        assert!(glob_matches("*_daily", "orders_daily"));
+        assert!(glob_matches("a*b*c", "aXXbYYbc"));
+        assert!(!glob_matches("a*b*c", "aXXbYY"));
+        assert!(glob_matches("t?ble", "table"));
+        assert!(!glob_matches("t?ble", "tble"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("products", "products"));
+        assert!(!glob_matches("products", "products2"));
+        assert!(is_glob("sales.*") && is_glob("t?ble") && !is_glob("sales"));
+    }
 
-        let parsed = kv_query_results_from_binary(&binary).unwrap();
+    #[test]
+    fn test_keystring_checked_and_lossy() {
+        let long = "a".repeat(65);
+        assert_eq!(KeyString::from_str_lossy(&long).len(), 64);
+        assert_eq!(KeyString::from_str_checked(&long).unwrap_err().tag, ErrorTag::OversizedData);
+        assert_eq!(KeyString::from_str_checked("short").unwrap(), ksf("short"));
 
-        assert_eq!(results, parsed);
+        // Truncation never splits a multi-byte character
+        let multibyte = "þ".repeat(33);
+        assert_eq!(KeyString::from_str_lossy(&multibyte).len(), 64);
+        assert!(KeyString::from_str_checked(&multibyte).is_err());
     }
 
     #[test]
@@ -1582,6 +1913,24 @@ mod tests {
         assert!(sum == 18.0);
     }
 
+    #[test]
+    fn test_min_max_slices() {
+        let ints: Vec<i32> = (0..37).map(|i| (i * 17) % 23 - 11).collect();
+        assert_eq!(min_i32_slice(&ints), *ints.iter().min().unwrap());
+        assert_eq!(max_i32_slice(&ints), *ints.iter().max().unwrap());
+        assert_eq!(min_i32_slice(&[4]), 4);
+        assert_eq!(max_i32_slice(&[]), i32::MIN);
+
+        let floats: Vec<f32> = ints.iter().map(|i| *i as f32 * 0.5).collect();
+        assert_eq!(min_f32_slice(&floats), -5.5);
+        assert_eq!(max_f32_slice(&floats), 5.5);
+        assert_eq!(min_f32_slice(&[1.0, f32::NAN, -2.0]), -2.0);
+        assert_eq!(min_f32_slice(&[]), f32::INFINITY);
+
+        assert_eq!(min_i64_slice(&[5, -3, 9]), -3);
+        assert_eq!(max_i64_slice(&[5, -3, 9]), 9);
+    }
+
     #[test]
     fn test_ez_error_serde() {
         for _ in 0..100 {
@@ -0,0 +1,177 @@
+//! Read your writes for reads sent to a different server than the writes.
+//! Every write batch answered with a WriteAck advances the write version of the server that ran it, and the ack carries
+//! the version the batch made. A client keeps the highest version it was given (see client_networking::ReadYourWrites)
+//! and sends it along with its reads as a Request::AtVersion. A server that has not applied that version yet waits for it
+//! for up to version_wait_ms() and then turns the read away, so the client can send it to the server it wrote to instead.
+//! There is no replication yet so a server is always caught up with the writes it ran itself. Waiting only comes into it
+//! once replicas apply the writes of a primary. Versions are reserved in the .write_version file ahead of being handed
+//! out, so a restarted server carries on above every version a client could be holding.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::paths::config_file;
+use crate::utilities::{u64_from_le_slice, ErrorTag, EzError};
+
+
+pub const DEFAULT_VERSION_WAIT_MS: u64 = 1000;
+
+pub const WRITE_VERSION_FILE: &str = ".write_version";
+
+/// How many versions are reserved each time the file is written, so it isn't written for every batch.
+/// A restart skips whatever was left of the reservation.
+pub const VERSION_RESERVATION: u64 = 1024;
+
+static VERSION_WAIT_MS: AtomicU64 = AtomicU64::new(DEFAULT_VERSION_WAIT_MS);
+
+/// How long a read waits for the write version it was sent with before it is turned away.
+pub fn set_version_wait_ms(ms: u64) {
+    VERSION_WAIT_MS.store(ms, Ordering::Relaxed);
+}
+
+pub fn version_wait_ms() -> u64 {
+    VERSION_WAIT_MS.load(Ordering::Relaxed)
+}
+
+struct VersionState {
+    applied: u64,
+    /// The highest version written to the file. Versions above it are only handed out after reserving more.
+    reserved: u64,
+}
+
+/// The write version this server has applied.
+pub struct WriteVersions {
+    state: Mutex<VersionState>,
+    advanced: Condvar,
+    path: Option<PathBuf>,
+}
+
+impl Default for WriteVersions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WriteVersions {
+    /// Versions that are never written to disk. Only meant for tests.
+    pub fn new() -> WriteVersions {
+        WriteVersions {
+            state: Mutex::new(VersionState{applied: 0, reserved: u64::MAX}),
+            advanced: Condvar::new(),
+            path: None,
+        }
+    }
+
+    /// Carries on from the last reservation in the file, or from 0 if there is no file yet.
+    pub fn load(path: &Path) -> Result<WriteVersions, EzError> {
+        println!("calling: WriteVersions::load()");
+
+        let mut reserved = 0;
+        if path.exists() {
+            let binary = std::fs::read(path)?;
+            if binary.len() != 8 {
+                return Err(EzError{tag: ErrorTag::Deserialization, text: format!("Write version file '{}' is corrupted", path.display())})
+            }
+            reserved = u64_from_le_slice(&binary);
+        }
+
+        Ok(WriteVersions {
+            state: Mutex::new(VersionState{applied: reserved, reserved}),
+            advanced: Condvar::new(),
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    pub fn default_path() -> PathBuf {
+        config_file(WRITE_VERSION_FILE)
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.state.lock().unwrap().applied
+    }
+
+    /// Records that another write batch was applied and returns its version. Fails without advancing if more
+    /// versions had to be reserved and the file couldn't be written.
+    pub fn advance(&self) -> Result<u64, EzError> {
+        let mut state = self.state.lock().unwrap();
+        if state.applied == state.reserved {
+            let reserved = state.reserved + VERSION_RESERVATION;
+            if let Some(path) = &self.path {
+                std::fs::write(path, reserved.to_le_bytes())?;
+            }
+            state.reserved = reserved;
+        }
+        state.applied += 1;
+        self.advanced.notify_all();
+        Ok(state.applied)
+    }
+
+    /// Waits until `version` has been applied, for at most `timeout`. Returns the version applied by then.
+    pub fn wait_for(&self, version: u64, timeout: Duration) -> Result<u64, EzError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while state.applied < version {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(EzError{
+                    tag: ErrorTag::Unavailable,
+                    text: format!("This server has applied writes up to version {} but the read needs version {}. Read from the server the writes went to", state.applied, version),
+                })
+            }
+            state = self.advanced.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        Ok(state.applied)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wait_for_version() {
+        let versions = WriteVersions::new();
+        assert_eq!(versions.wait_for(0, Duration::ZERO).unwrap(), 0);
+        assert_eq!(versions.advance().unwrap(), 1);
+        assert_eq!(versions.wait_for(1, Duration::ZERO).unwrap(), 1);
+
+        let e = versions.wait_for(3, Duration::from_millis(20)).unwrap_err();
+        assert_eq!(e.tag, ErrorTag::Unavailable);
+        assert!(e.text.contains("up to version 1 but the read needs version 3"));
+
+        // A read waiting for a version is let through as soon as it is applied
+        std::thread::scope(|scope| {
+            let reader = scope.spawn(|| versions.wait_for(3, Duration::from_secs(10)));
+            versions.advance().unwrap();
+            versions.advance().unwrap();
+            assert_eq!(reader.join().unwrap().unwrap(), 3);
+        });
+    }
+
+    #[test]
+    fn test_versions_survive_restart() {
+        let path = std::env::temp_dir().join(format!("ezdb_write_version_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let versions = WriteVersions::load(&path).unwrap();
+        assert_eq!(versions.applied(), 0);
+        assert_eq!(versions.advance().unwrap(), 1);
+        assert_eq!(versions.advance().unwrap(), 2);
+
+        // The restarted server starts above everything it could have handed out before
+        let restarted = WriteVersions::load(&path).unwrap();
+        assert_eq!(restarted.applied(), VERSION_RESERVATION);
+        assert_eq!(restarted.wait_for(2, Duration::ZERO).unwrap(), VERSION_RESERVATION);
+        for _ in 0..VERSION_RESERVATION + 1 {
+            restarted.advance().unwrap();
+        }
+        assert_eq!(WriteVersions::load(&path).unwrap().applied(), 3 * VERSION_RESERVATION);
+
+        std::fs::write(&path, [1, 2, 3]).unwrap();
+        assert!(WriteVersions::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}